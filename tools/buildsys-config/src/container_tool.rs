@@ -0,0 +1,94 @@
+//! The container tool which runs builds and SDK containers, chosen by setting
+//! `TWOLITER_CONTAINER_TOOL` to `docker`, the default, `podman` or `finch`. Each is called by the
+//! name of its command, with the same arguments, since podman and finch accept docker's.
+//!
+//! Twoliter and buildsys both read the variable, so it is parsed here, and a tool which is not
+//! supported is an error in both rather than quietly replaced by docker.
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// The environment variable naming the container tool.
+pub const CONTAINER_TOOL_ENV: &str = "TWOLITER_CONTAINER_TOOL";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContainerTool {
+    #[default]
+    Docker,
+    Podman,
+    Finch,
+}
+
+impl ContainerTool {
+    /// The container tool named by `TWOLITER_CONTAINER_TOOL`, or docker if it is unset.
+    pub fn from_env() -> Result<Self, UnsupportedContainerTool> {
+        match std::env::var(CONTAINER_TOOL_ENV) {
+            Ok(name) if !name.is_empty() => name.parse(),
+            _ => Ok(Self::Docker),
+        }
+    }
+
+    /// The command which runs the tool.
+    pub fn command(&self) -> &'static str {
+        match self {
+            Self::Docker => "docker",
+            Self::Podman => "podman",
+            Self::Finch => "finch",
+        }
+    }
+
+    /// The arguments `run` needs for containers which write files owned by the caller to mounted
+    /// directories. Rootless podman maps the caller to root in containers, so the caller's own id
+    /// is kept instead.
+    pub fn run_args(&self) -> &'static [&'static str] {
+        match self {
+            Self::Podman => &["--userns=keep-id"],
+            Self::Docker | Self::Finch => &[],
+        }
+    }
+
+    /// Whether `build` is docker's buildkit frontend, with buildx builders and options such as
+    /// `--no-cache-filter`.
+    pub fn has_buildx(&self) -> bool {
+        matches!(self, Self::Docker)
+    }
+}
+
+impl FromStr for ContainerTool {
+    type Err = UnsupportedContainerTool;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "docker" => Ok(Self::Docker),
+            "podman" => Ok(Self::Podman),
+            "finch" => Ok(Self::Finch),
+            _ => Err(UnsupportedContainerTool {
+                name: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl Display for ContainerTool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.command())
+    }
+}
+
+/// `TWOLITER_CONTAINER_TOOL` names a tool which is not supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedContainerTool {
+    name: String,
+}
+
+impl Display for UnsupportedContainerTool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Unsupported container tool '{}' in {CONTAINER_TOOL_ENV}, expected 'docker', 'podman' \
+            or 'finch'",
+            self.name
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedContainerTool {}
@@ -1,2 +1,9 @@
+pub mod container_tool;
+
 pub const EXTERNAL_KIT_DIRECTORY: &str = "build/external-kits";
 pub const EXTERNAL_KIT_METADATA: &str = "build/external-kits/external-kit-metadata.json";
+
+/// The prefix of the files in the build state directory where buildsys records failed package
+/// builds, one file per architecture with one JSON object per line, so that twoliter can
+/// summarize every failure once cargo finishes.
+pub const FAILURE_LOG_PREFIX: &str = "failed-packages";
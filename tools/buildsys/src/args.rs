@@ -15,7 +15,7 @@ use url::Url;
 /// variable changes. The build type is represented with bit flags so that we can easily list
 /// multiple build types for a single variable. See `[BuildType]` and `[rerun_for_envs]` below to
 /// see how this list is used.
-const REBUILD_VARS: [(&str, u8); 14] = [
+const REBUILD_VARS: [(&str, u8); 15] = [
     ("BUILDSYS_ARCH", PACKAGE | KIT | VARIANT),
     ("BUILDSYS_CACERTS_BUNDLE_OVERRIDE", VARIANT),
     ("BUILDSYS_KITS_DIR", KIT),
@@ -27,6 +27,7 @@ const REBUILD_VARS: [(&str, u8); 14] = [
     ("BUILDSYS_PRETTY_NAME", VARIANT),
     ("BUILDSYS_ROOT_DIR", PACKAGE | KIT | VARIANT),
     ("BUILDSYS_STATE_DIR", PACKAGE | KIT | VARIANT),
+    ("BUILDSYS_STEPS_KEY", PACKAGE | KIT | VARIANT),
     ("BUILDSYS_VERSION_BUILD", KIT | VARIANT),
     ("BUILDSYS_VERSION_IMAGE", KIT | VARIANT),
     ("TLPRIVATE_SDK_IMAGE", PACKAGE | KIT | VARIANT),
@@ -45,15 +46,19 @@ pub(crate) enum Command {
     BuildKit(Box<BuildKitArgs>),
     BuildVariant(Box<BuildVariantArgs>),
     RepackVariant(Box<RepackVariantArgs>),
+    SourceOffer(Box<SourceOfferArgs>),
 }
 
 impl Command {
-    pub(crate) fn build_type(&self) -> BuildType {
+    /// The type of build performed by the command, or `None` for commands which do not build
+    /// anything and so are not run from a Cargo build script.
+    pub(crate) fn build_type(&self) -> Option<BuildType> {
         match self {
-            Command::BuildPackage(_) => BuildType::Package,
-            Command::BuildKit(_) => BuildType::Kit,
-            Command::BuildVariant(_) => BuildType::Variant,
-            Command::RepackVariant(_) => BuildType::Repack,
+            Command::BuildPackage(_) => Some(BuildType::Package),
+            Command::BuildKit(_) => Some(BuildType::Kit),
+            Command::BuildVariant(_) => Some(BuildType::Variant),
+            Command::RepackVariant(_) => Some(BuildType::Repack),
+            Command::SourceOffer(_) => None,
         }
     }
 }
@@ -85,6 +90,15 @@ pub(crate) struct Common {
     #[arg(long, env = "TWOLITER_TOOLS_DIR")]
     pub(crate) tools_dir: PathBuf,
 
+    /// The project root as the docker daemon sees it, when buildsys runs in a container which
+    /// mounts the project from the daemon's host.
+    #[arg(long, env = "BUILDSYS_HOST_ROOT_DIR")]
+    pub(crate) host_root_dir: Option<PathBuf>,
+
+    /// Copy the project root into a volume for each build, for docker daemons which cannot see it.
+    #[arg(long, env = "BUILDSYS_BYPASS_COPY")]
+    pub(crate) bypass_copy: bool,
+
     /// cicd_hack is used to suppress builds from running after all the cargo-related metadata is
     /// emitted. This allows cargo to create a fresh crate, and assumes that the corresponding
     /// build artifacts are already present. It is intended for use in a CI/CD scenario where some
@@ -92,6 +106,20 @@ pub(crate) struct Common {
     /// build failures that are difficult to troubleshoot.
     #[arg(long, env = "BUILDSYS_CICD_HACK")]
     pub(crate) cicd_hack: bool,
+
+    /// The niceness, from 0 to 19, to run the commands of builds at, so that builds on a shared
+    /// host yield to interactive work.
+    #[arg(long, env = "BUILDSYS_NICE", value_parser = clap::value_parser!(u8).range(0..=19))]
+    pub(crate) nice: Option<u8>,
+
+    /// How many CPUs each build's commands may keep busy, such as the jobs of `make`.
+    #[arg(long, env = "BUILDSYS_CPU_QUOTA")]
+    pub(crate) cpu_quota: Option<f64>,
+
+    /// The cgroup, such as a systemd slice, to run build containers under, whose limits the host
+    /// enforces.
+    #[arg(long, env = "BUILDSYS_CGROUP_PARENT")]
+    pub(crate) cgroup_parent: Option<String>,
 }
 
 /// Build RPMs from a spec file and sources.
@@ -121,6 +149,25 @@ pub(crate) struct BuildPackageArgs {
     #[arg(long, env = "BUILDSYS_UPSTREAM_SOURCE_FALLBACK")]
     pub(crate) upstream_source_fallback: String,
 
+    /// A file of regular expressions, one per line, matching the output of package builds that
+    /// failed for flaky reasons and are worth retrying.
+    #[arg(long, env = "BUILDSYS_FLAKY_PATTERNS_FILE")]
+    pub(crate) flaky_patterns_file: Option<PathBuf>,
+
+    /// How many times to retry a package build that failed with output matching a flaky pattern.
+    #[arg(long, env = "BUILDSYS_FLAKY_RETRIES", default_value_t = 2)]
+    pub(crate) flaky_retries: u16,
+
+    /// An endpoint to notify with a JSON summary, naming the package's owner, when the package
+    /// fails to build.
+    #[arg(long, env = "BUILDSYS_FAILURE_WEBHOOK_URL")]
+    pub(crate) failure_webhook_url: Option<Url>,
+
+    /// A private directory holding the project's secrets, one file per secret, from which the
+    /// package's declared secrets are passed to its build.
+    #[arg(long, env = "BUILDSYS_SECRETS_DIR")]
+    pub(crate) secrets_dir: Option<PathBuf>,
+
     #[command(flatten)]
     pub(crate) common: Common,
 }
@@ -170,6 +217,12 @@ pub(crate) struct BuildVariantArgs {
     #[arg(long, env = "BUILDSYS_IMAGES_DIR")]
     pub(crate) image_dir: PathBuf,
 
+    /// The docker buildx builder to assemble the images with, instead of the docker daemon's own.
+    /// It must share the host's network so that builds can reach the sockets they stream files
+    /// through.
+    #[arg(long, env = "BUILDSYS_BUILDER")]
+    pub(crate) builder: Option<String>,
+
     #[command(flatten)]
     pub(crate) common: Common,
 }
@@ -189,6 +242,27 @@ pub(crate) struct RepackVariantArgs {
     #[arg(long, env = "BUILDSYS_IMAGES_DIR")]
     pub(crate) image_dir: PathBuf,
 
+    /// The docker buildx builder to assemble the images with, instead of the docker daemon's own.
+    /// It must share the host's network so that builds can reach the sockets they stream files
+    /// through.
+    #[arg(long, env = "BUILDSYS_BUILDER")]
+    pub(crate) builder: Option<String>,
+
+    #[command(flatten)]
+    pub(crate) common: Common,
+}
+
+/// Collect the upstream sources of a kit's packages into a source offer archive.
+#[derive(Debug, Parser)]
+pub(crate) struct SourceOfferArgs {
+    /// The directory where source offer archives go, e.g. build/source-offers
+    #[arg(long, env = "BUILDSYS_SOURCE_OFFERS_DIR")]
+    pub(crate) source_offers_dir: PathBuf,
+
+    /// Version number for the workspace
+    #[arg(long, env = "BUILDSYS_VERSION_IMAGE")]
+    pub(crate) version_image: String,
+
     #[command(flatten)]
     pub(crate) common: Common,
 }
@@ -5,31 +5,36 @@ the repository's top-level Dockerfile.
 
 */
 pub(crate) mod error;
+mod flaky;
+mod secrets;
+mod template;
 
-use crate::args::{BuildKitArgs, BuildPackageArgs, BuildVariantArgs, RepackVariantArgs};
+use crate::args::{BuildKitArgs, BuildPackageArgs, BuildVariantArgs, Common, RepackVariantArgs};
 use bottlerocket_variant::Variant;
 use buildsys::manifest::{
     ExternalKitMetadataView, ImageFeature, ImageFormat, ImageLayout, Manifest, PartitionPlan,
     SupportedArch,
 };
 use buildsys::BuildType;
+use buildsys_config::container_tool::ContainerTool;
 use buildsys_config::EXTERNAL_KIT_METADATA;
 use duct::cmd;
 use error::Result;
+use flaky::FlakyRetry;
 use lazy_static::lazy_static;
 use nonzero_ext::nonzero;
 use pipesys::server::Server as PipesysServer;
 use rand::Rng;
 use regex::Regex;
 use sha2::{Digest, Sha512};
-use snafu::{ensure, OptionExt, ResultExt};
-use std::collections::HashSet;
+use snafu::{OptionExt, ResultExt};
+use std::collections::{BTreeMap, HashSet};
 use std::env;
 use std::fs::{self, read_dir, File};
 use std::num::NonZeroU16;
-use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Output;
+use template::TemplateContext;
 use walkdir::{DirEntry, WalkDir};
 
 /*
@@ -91,12 +96,34 @@ lazy_static! {
 
 static DOCKER_BUILD_MAX_ATTEMPTS: NonZeroU16 = nonzero!(10u16);
 
+// The kit image config label recording the SDK and external kits the kit was built with, as
+// locked by Twoliter.lock.
+const LOCKED_IMAGES_LABEL: &str = "dev.bottlerocket.twoliter.lock";
+
 // Expected UID for privileged and unprivileged processes inside the build container.
 const ROOT_UID: u32 = 0;
 lazy_static! {
-    static ref BUILDER_UID: u32 = std::fs::metadata("/proc/self/comm")
+    static ref BUILDER_UID: u32 = builder_uid();
+}
+
+// The tool which runs builds, named by `TWOLITER_CONTAINER_TOOL`: docker, podman or finch, which
+// accept docker's arguments.
+fn container_tool() -> Result<ContainerTool> {
+    ContainerTool::from_env().context(error::ContainerToolSnafu)
+}
+
+#[cfg(unix)]
+fn builder_uid() -> u32 {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata("/proc/self/comm")
         .map(|m| m.uid())
-        .expect("Failed to obtain current UID");
+        .expect("Failed to obtain current UID")
+}
+
+// Builds only run on Linux hosts, so other hosts only need buildsys to compile.
+#[cfg(not(unix))]
+fn builder_uid() -> u32 {
+    ROOT_UID
 }
 
 enum OutputCleanup {
@@ -104,6 +131,70 @@ enum OutputCleanup {
     None,
 }
 
+/// How the project root is made available to the container which serves it to builds.
+enum BypassMount {
+    /// Bind mount the project root from this path, where the docker daemon sees it.
+    Bind(PathBuf),
+    /// Copy the project root into a volume, for docker daemons which cannot see it.
+    Copy,
+}
+
+impl BypassMount {
+    fn new(common: &Common) -> Self {
+        if common.bypass_copy {
+            BypassMount::Copy
+        } else {
+            BypassMount::Bind(
+                common
+                    .host_root_dir
+                    .clone()
+                    .unwrap_or_else(|| common.root_dir.clone()),
+            )
+        }
+    }
+}
+
+/// How much of a shared host the commands of a build may take.
+struct BuildPriority {
+    nice: Option<u8>,
+    /// How many CPUs the build's commands may keep busy, rounded up.
+    cpus: Option<u32>,
+    cgroup_parent: Option<String>,
+}
+
+impl BuildPriority {
+    fn new(common: &Common) -> Self {
+        Self {
+            nice: common.nice,
+            cpus: common
+                .cpu_quota
+                .filter(|quota| *quota > 0.0)
+                .map(|quota| (quota.ceil() as u32).max(1)),
+            cgroup_parent: common.cgroup_parent.clone(),
+        }
+    }
+
+    /// The arguments of `docker build` which apply the priority. The niceness and CPUs are passed
+    /// as build arguments, which the Dockerfile applies to the commands it runs, since builds run
+    /// under the builder's daemon rather than under buildsys.
+    fn docker_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        args.build_arg(
+            "NICE",
+            self.nice.map(|nice| nice.to_string()).unwrap_or_default(),
+        );
+        args.build_arg(
+            "BUILD_NCPUS",
+            self.cpus.map(|cpus| cpus.to_string()).unwrap_or_default(),
+        );
+        if let Some(cgroup_parent) = &self.cgroup_parent {
+            args.push("--cgroup-parent".to_string());
+            args.push(cgroup_parent.clone());
+        }
+        args
+    }
+}
+
 struct CommonBuildArgs {
     arch: SupportedArch,
     sdk: String,
@@ -111,6 +202,8 @@ struct CommonBuildArgs {
     token: String,
     cleanup: OutputCleanup,
     output_socket: String,
+    bypass: BypassMount,
+    priority: BuildPriority,
 }
 
 impl CommonBuildArgs {
@@ -119,6 +212,8 @@ impl CommonBuildArgs {
         sdk: String,
         arch: SupportedArch,
         cleanup: OutputCleanup,
+        bypass: BypassMount,
+        priority: BuildPriority,
     ) -> Self {
         let token = token(&root);
 
@@ -136,6 +231,8 @@ impl CommonBuildArgs {
             token,
             cleanup,
             output_socket,
+            bypass,
+            priority,
         }
     }
 }
@@ -159,6 +256,8 @@ impl KitBuildArgs {
         args.build_arg("EXTERNAL_KIT_METADATA", &self.external_kit_metadata);
         args.build_arg("VENDOR", &self.vendor);
         args.build_arg("LOCAL_KIT_DEPENDENCIES", self.local_kits.join(" "));
+        args.build_arg("KIT_LABELS", &self.labels);
+        args.build_arg("KIT_ANNOTATIONS", &self.annotations);
         args
     }
 }
@@ -171,6 +270,10 @@ struct KitBuildArgs {
     vendor: String,
     version_build: String,
     version_id: String,
+    /// Extra image config labels, as a JSON object.
+    labels: String,
+    /// Extra image manifest annotations, as a JSON object.
+    annotations: String,
 }
 
 impl crate::builder::PackageBuildArgs {
@@ -211,6 +314,8 @@ struct VariantBuildArgs {
     variant_runtime: String,
     version_build: String,
     version_image: String,
+    /// The SDK and external kits locked by Twoliter.lock, as a JSON object.
+    locked_images: String,
 }
 
 impl VariantBuildArgs {
@@ -226,6 +331,7 @@ impl VariantBuildArgs {
         args.build_arg("IMAGE_NAME", &self.name);
         args.build_arg("KERNEL_PARAMETERS", &self.kernel_parameters);
         args.build_arg("KIT_DEPENDENCIES", self.kit_dependencies.join(" "));
+        args.build_arg("LOCKED_IMAGES", &self.locked_images);
         args.build_arg(
             "EXTERNAL_KIT_DEPENDENCIES",
             self.external_kit_dependencies.join(" "),
@@ -323,14 +429,37 @@ pub(crate) struct DockerBuild {
     common_build_args: CommonBuildArgs,
     target_build_args: TargetBuildArgs,
     secrets_args: Vec<String>,
+    flaky_retry: Option<FlakyRetry>,
+    /// The docker buildx builder to build with, or `None` for the docker daemon's own.
+    builder: Option<String>,
 }
 
 impl DockerBuild {
     /// Create a new `DockerBuild` that can build a package.
     pub(crate) fn new_package(args: BuildPackageArgs, manifest: &Manifest) -> Result<Self> {
+        let bypass = BypassMount::new(&args.common);
+        let priority = BuildPriority::new(&args.common);
         let package = manifest.info().package_name();
         let per_package_dir = format!("{}/{}", args.packages_dir.display(), package).into();
         let old_package_dir = format!("{}", args.packages_dir.display()).into();
+        let flaky_retry = args
+            .flaky_patterns_file
+            .as_ref()
+            .map(|patterns_file| {
+                FlakyRetry::new(
+                    patterns_file,
+                    args.flaky_retries,
+                    &args.common.state_dir,
+                    package,
+                    args.common.arch,
+                )
+            })
+            .transpose()?;
+        let secrets_args = secrets::package_secrets_args(
+            package,
+            manifest.package_secrets(),
+            args.secrets_dir.as_deref(),
+        )?;
 
         Ok(Self {
             dockerfile: args.common.tools_dir.join("build.Dockerfile"),
@@ -353,6 +482,8 @@ impl DockerBuild {
                 args.common.sdk_image,
                 args.common.arch,
                 OutputCleanup::BeforeBuild,
+                bypass,
+                priority,
             ),
             target_build_args: TargetBuildArgs::Package(PackageBuildArgs {
                 package: package.to_string(),
@@ -364,13 +495,33 @@ impl DockerBuild {
                 version_build: args.version_build,
                 version_build_timestamp: args.version_build_timestamp,
             }),
-            secrets_args: Vec::new(),
+            secrets_args,
+            flaky_retry,
+            builder: None,
         })
     }
 
     pub(crate) fn new_kit(args: BuildKitArgs, manifest: &Manifest) -> Result<Self> {
+        let bypass = BypassMount::new(&args.common);
+        let priority = BuildPriority::new(&args.common);
         let kit = manifest.info().kit_name();
         let per_kit_dir = args.kits_dir.join(kit);
+        let vendor = manifest.info().kit_vendor().context(error::GraphSnafu)?;
+
+        let template_context = TemplateContext::new()
+            .with("kit", kit)
+            .with("vendor", &vendor)
+            .with("arch", args.common.arch.to_string())
+            .with("version", &args.version_image)
+            .with("build-id", &args.version_build);
+        let mut labels = template_context.render_map(manifest.info().kit_labels())?;
+        labels.insert(
+            LOCKED_IMAGES_LABEL.to_string(),
+            locked_images(
+                &ExternalKitMetadataView::load(&args.common.root_dir).context(error::GraphSnafu)?,
+            )?,
+        );
+        let annotations = template_context.render_map(manifest.info().kit_annotations())?;
 
         Ok(Self {
             dockerfile: args.common.tools_dir.join("build.Dockerfile"),
@@ -393,22 +544,30 @@ impl DockerBuild {
                 args.common.sdk_image,
                 args.common.arch,
                 OutputCleanup::BeforeBuild,
+                bypass,
+                priority,
             ),
             target_build_args: TargetBuildArgs::Kit(KitBuildArgs {
                 kit: kit.to_string(),
-                vendor: manifest.info().kit_vendor().context(error::GraphSnafu)?,
+                vendor,
                 local_kits: manifest.kit_dependencies().context(error::GraphSnafu)?,
                 external_kit_metadata: EXTERNAL_KIT_METADATA.into(),
                 package_dependencies: manifest.package_dependencies().context(error::GraphSnafu)?,
                 version_build: args.version_build,
                 version_id: args.version_image,
+                labels: json_object(&labels)?,
+                annotations: json_object(&annotations)?,
             }),
             secrets_args: Vec::new(),
+            flaky_retry: None,
+            builder: None,
         })
     }
 
     /// Create a new `DockerBuild` that can build a variant image.
     pub(crate) fn new_variant(args: BuildVariantArgs, manifest: &Manifest) -> Result<Self> {
+        let bypass = BypassMount::new(&args.common);
+        let priority = BuildPriority::new(&args.common);
         let image_layout = manifest.info().image_layout().cloned().unwrap_or_default();
         let ImageLayout {
             os_image_size_gib,
@@ -427,6 +586,8 @@ impl DockerBuild {
         let variant_runtime = v.runtime().into();
         let variant_family = v.family().into();
         let variant_flavor = v.variant_flavor().unwrap_or("").into();
+        let external_kit_metadata =
+            ExternalKitMetadataView::load(&args.common.root_dir).context(error::GraphSnafu)?;
 
         Ok(Self {
             dockerfile: args.common.tools_dir.join("build.Dockerfile"),
@@ -447,13 +608,13 @@ impl DockerBuild {
                 args.common.sdk_image,
                 args.common.arch,
                 OutputCleanup::BeforeBuild,
+                bypass,
+                priority,
             ),
             target_build_args: TargetBuildArgs::Variant(VariantBuildArgs {
                 package_dependencies: manifest.package_dependencies().context(error::GraphSnafu)?,
                 kit_dependencies: manifest.kit_dependencies().context(error::GraphSnafu)?,
-                external_kit_dependencies: ExternalKitMetadataView::load(args.common.root_dir)
-                    .context(error::GraphSnafu)?
-                    .list(),
+                external_kit_dependencies: external_kit_metadata.list(),
                 data_image_publish_size_gib,
                 data_image_size_gib: data_image_size_gib.to_string(),
                 image_features: manifest.info().image_features().unwrap_or_default(),
@@ -461,6 +622,7 @@ impl DockerBuild {
                     Some(ImageFormat::Raw) | None => "raw",
                     Some(ImageFormat::Qcow2) => "qcow2",
                     Some(ImageFormat::Vmdk) => "vmdk",
+                    Some(ImageFormat::Oci) => "oci",
                 }
                 .to_string(),
                 kernel_parameters: manifest
@@ -491,13 +653,18 @@ impl DockerBuild {
                 variant_runtime,
                 version_build: args.version_build,
                 version_image: args.version_image,
+                locked_images: locked_images(&external_kit_metadata)?,
             }),
             secrets_args: secrets_args()?,
+            flaky_retry: None,
+            builder: args.builder,
         })
     }
 
     /// Create a new `DockerBuild` that can repackage a variant image.
     pub(crate) fn repack_variant(args: RepackVariantArgs, manifest: &Manifest) -> Result<Self> {
+        let bypass = BypassMount::new(&args.common);
+        let priority = BuildPriority::new(&args.common);
         let image_layout = manifest.info().image_layout().cloned().unwrap_or_default();
         let ImageLayout {
             os_image_size_gib,
@@ -530,6 +697,8 @@ impl DockerBuild {
                 args.common.sdk_image,
                 args.common.arch,
                 OutputCleanup::None,
+                bypass,
+                priority,
             ),
             target_build_args: TargetBuildArgs::Repack(RepackVariantBuildArgs {
                 data_image_publish_size_gib,
@@ -539,6 +708,7 @@ impl DockerBuild {
                     Some(ImageFormat::Raw) | None => "raw",
                     Some(ImageFormat::Qcow2) => "qcow2",
                     Some(ImageFormat::Vmdk) => "vmdk",
+                    Some(ImageFormat::Oci) => "oci",
                 }
                 .to_string(),
                 name: args.name,
@@ -554,6 +724,8 @@ impl DockerBuild {
                 version_image: args.version_image,
             }),
             secrets_args: secrets_args()?,
+            flaky_retry: None,
+            builder: args.builder,
         })
     }
 
@@ -570,6 +742,14 @@ impl DockerBuild {
             &self.state_dir,
         )?;
 
+        // Have the build write its outputs into a directory unique to this invocation, so that
+        // concurrent builds which share the state directory never see each other's artifacts.
+        let staging_dir = create_staging_dir(
+            &self.common_build_args.arch.to_string(),
+            &self.common_build_args.nocache,
+            &self.state_dir,
+        )?;
+
         // Clean up any previous outputs we have tracked.
         match self.common_build_args.cleanup {
             OutputCleanup::BeforeBuild => {
@@ -584,7 +764,6 @@ impl DockerBuild {
             --tag {tag} \
             --network host \
             --file {dockerfile} \
-            --no-cache-filter rpmbuild,kitbuild,repobuild,imgbuild,migrationbuild,kmodkitbuild,imgrepack \
             --build-arg BYPASS_SOCKET={tag}-bypass \
             --build-arg BUILDER_UID={uid}",
             context = self.context.display(),
@@ -595,9 +774,56 @@ impl DockerBuild {
         )
         .split_string();
 
+        // Only docker's buildkit frontend can skip the cache for some stages, so other tools build
+        // every stage afresh.
+        if container_tool()?.has_buildx() {
+            build.extend([
+                "--no-cache-filter".to_string(),
+                "rpmbuild,kitbuild,repobuild,imgbuild,migrationbuild,kmodkitbuild,imgrepack"
+                    .to_string(),
+            ]);
+        } else {
+            build.push("--no-cache".to_string());
+        }
+
         build.extend(self.build_args());
         build.extend(self.secrets_args.clone());
 
+        // Build with the chosen builder instead of the docker daemon's own. The builder needs the
+        // host's network for the sockets used by the build, and the image is loaded into the
+        // daemon so that it can be cleaned up like any other.
+        if let Some(builder) = &self.builder {
+            build.splice(
+                0..1,
+                [
+                    "buildx".to_string(),
+                    "build".to_string(),
+                    "--builder".to_string(),
+                    builder.clone(),
+                    "--allow".to_string(),
+                    "network.host".to_string(),
+                    "--load".to_string(),
+                ],
+            );
+        }
+
+        // Mount the project's root where the docker daemon can see it: either bind mounted from
+        // the daemon's view of the path, or copied into a volume when the daemon can't see it.
+        let bypass_volume = format!("{}-bypass", self.tag);
+        let (bypass_mounts, pipesys) = match &self.common_build_args.bypass {
+            BypassMount::Bind(root) => (
+                format!(
+                    "-v {root}:/bypass:ro -v {root}/build/tools/pipesys:/usr/local/bin/pipesys:ro",
+                    root = root.display()
+                ),
+                "pipesys",
+            ),
+            BypassMount::Copy => (
+                format!("-v {bypass_volume}:/bypass:ro"),
+                "/bypass/build/tools/pipesys",
+            ),
+        };
+
         // Run a container with the project's root as a read-only volume mount, so that pipesys can
         // serve a read-only file descriptor that's safe to pass into builds.
         let run_bypass = format!(
@@ -608,12 +834,10 @@ impl DockerBuild {
             --net host \
             --pid host \
             -u {uid} \
-            -v {root}:/bypass:ro \
-            -v {root}/build/tools/pipesys:/usr/local/bin/pipesys:ro \
+            {bypass_mounts} \
             {sdk} \
-            pipesys serve --socket {tag}-bypass --client-uid {uid} --path /bypass",
+            {pipesys} serve --socket {tag}-bypass --client-uid {uid} --path /bypass",
             tag = self.tag,
-            root = self.root_dir.display(),
             sdk = self.common_build_args.sdk,
             uid = ROOT_UID,
         )
@@ -628,11 +852,15 @@ impl DockerBuild {
         // Clean up the stopped bypass container if it exists.
         let _ = docker(&rm_bypass, Retry::No);
 
+        if let BypassMount::Copy = self.common_build_args.bypass {
+            copy_to_volume(&self.root_dir, &bypass_volume, &self.common_build_args.sdk)?;
+        }
+
         let runtime = tokio::runtime::Runtime::new().context(error::AsyncRuntimeSnafu)?;
 
         // Spawn a background task to share the file descriptors for the output directory.
         let output_socket = self.common_build_args.output_socket.clone();
-        let output_dir = marker_dir.clone();
+        let output_dir = staging_dir.clone();
         runtime.spawn(async move {
             PipesysServer::for_path(output_socket, ROOT_UID, &output_dir)
                 .serve()
@@ -657,23 +885,35 @@ impl DockerBuild {
                     &*UNEXPECTED_EOF_ERROR,
                     &*CREATEREPO_C_READ_HEADER_ERROR,
                 ],
+                flaky: self.flaky_retry.as_ref(),
             },
         );
 
-        // Clean up our bypass container.
+        // Clean up our bypass container, and the copy of the project root it served.
         let _ = docker(&rm_bypass, Retry::No);
+        if let BypassMount::Copy = self.common_build_args.bypass {
+            let _ = docker(
+                &format!("volume rm --force {bypass_volume}").split_string(),
+                Retry::No,
+            );
+        }
 
         // Stop the runtime and the background threads.
         runtime.shutdown_background();
 
-        // Check whether the build succeeded before continuing.
-        build_result?;
+        // Check whether the build succeeded before continuing, discarding any partial outputs.
+        if let Err(e) = build_result {
+            let _ = fs::remove_dir_all(&staging_dir);
+            return Err(e);
+        }
 
         // Clean up our image now that we're done.
         docker(&rm_image, Retry::No)?;
 
         // Copy artifacts to the expected directory and write markers to track them.
-        copy_build_files(&marker_dir, &self.artifacts_dirs[0])?;
+        move_build_files(&staging_dir, &marker_dir, &self.artifacts_dirs[0])?;
+        fs::remove_dir_all(&staging_dir)
+            .context(error::DirectoryRemoveSnafu { path: &staging_dir })?;
 
         Ok(())
     }
@@ -691,6 +931,7 @@ impl DockerBuild {
         args.build_arg("NOCACHE", &self.common_build_args.nocache);
         args.build_arg("TOKEN", &self.common_build_args.token);
         args.build_arg("OUTPUT_SOCKET", &self.common_build_args.output_socket);
+        args.extend(self.common_build_args.priority.docker_args());
 
         // Skip some build checks:
         // - InvalidDefaultArgInFrom warns about the SDK argument, which is always set
@@ -710,14 +951,24 @@ impl DockerBuild {
 fn docker(args: &[String], retry: Retry) -> Result<Output> {
     let mut max_attempts: u16 = 1;
     let mut retry_messages: &[&Regex] = &[];
-    if let Retry::Yes { attempts, messages } = retry {
+    let mut flaky_retry = None;
+    if let Retry::Yes {
+        attempts,
+        messages,
+        flaky,
+    } = retry
+    {
         max_attempts = attempts.into();
         retry_messages = messages;
+        flaky_retry = flaky;
     }
 
     let mut attempt = 1;
+    // The flaky patterns which caused a retry, in order.
+    let mut flakes = Vec::new();
+    let tool = container_tool()?;
     loop {
-        let output = cmd("docker", args)
+        let output = cmd(tool.command(), args)
             .stderr_to_stdout()
             .stdout_capture()
             .unchecked()
@@ -727,17 +978,40 @@ fn docker(args: &[String], retry: Retry) -> Result<Output> {
         let stdout = String::from_utf8_lossy(&output.stdout);
         println!("{}", &stdout);
         if output.status.success() {
+            if let Some(flaky) = flaky_retry.filter(|_| !flakes.is_empty()) {
+                flaky.record(&flakes, true)?;
+            }
             return Ok(output);
         }
 
-        ensure!(
-            retry_messages.iter().any(|m| m.is_match(&stdout)) && attempt < max_attempts,
-            error::DockerExecutionSnafu {
-                args: &args.join(" ")
+        if retry_messages.iter().any(|m| m.is_match(&stdout)) && attempt < max_attempts {
+            attempt += 1;
+            continue;
+        }
+
+        if let Some(flaky) = flaky_retry {
+            match flaky.matching(&stdout) {
+                Some(pattern) if flakes.len() < usize::from(flaky.retries()) => {
+                    println!(
+                        "cargo:warning=Retrying build after flaky failure matching '{}'",
+                        pattern
+                    );
+                    flakes.push(pattern.to_string());
+                    continue;
+                }
+                Some(pattern) => {
+                    flakes.push(pattern.to_string());
+                    flaky.record(&flakes, false)?;
+                }
+                None if !flakes.is_empty() => flaky.record(&flakes, false)?,
+                None => (),
             }
-        );
+        }
 
-        attempt += 1;
+        return error::DockerExecutionSnafu {
+            args: &args.join(" "),
+        }
+        .fail();
     }
 }
 
@@ -748,6 +1022,9 @@ enum Retry<'a> {
     Yes {
         attempts: NonZeroU16,
         messages: &'a [&'static Regex],
+        /// Retries for failures the project has identified as flaky, tracked separately from
+        /// the known Docker failures in `messages`.
+        flaky: Option<&'a FlakyRetry>,
     },
 }
 
@@ -756,6 +1033,33 @@ enum Retry<'a> {
 /// Add secrets that might be needed for builds. Since most builds won't use
 /// them, they are not automatically tracked for changes. If necessary, builds
 /// can emit the relevant cargo directives for tracking in their build script.
+/// Copies the project root into the volume `volume`, through a container that is created only to
+/// mount it and never started.
+fn copy_to_volume(root: &Path, volume: &str, image: &str) -> Result<()> {
+    let container = format!("{volume}-copy");
+    let rm_container = format!("rm --force {container}").split_string();
+    let _ = docker(&rm_container, Retry::No);
+    let _ = docker(
+        &format!("volume rm --force {volume}").split_string(),
+        Retry::No,
+    );
+
+    docker(
+        &format!("container create --name {container} -v {volume}:/bypass {image}").split_string(),
+        Retry::No,
+    )?;
+    let copied = docker(
+        &[
+            "cp".to_string(),
+            format!("{}/.", root.display()),
+            format!("{container}:/bypass"),
+        ],
+        Retry::No,
+    );
+    let _ = docker(&rm_container, Retry::No);
+    copied.map(|_| ())
+}
+
 fn secrets_args() -> Result<Vec<String>> {
     let mut args = Vec::new();
     let sbkeys_var = "BUILDSYS_SBKEYS_PROFILE_DIR";
@@ -832,39 +1136,56 @@ fn create_marker_dir(
     Ok(path)
 }
 
+/// Create a directory for the outputs of a single build invocation, named for its unique token.
+fn create_staging_dir(arch: &str, invocation: &str, state_dir: &Path) -> Result<PathBuf> {
+    let path = [
+        &state_dir.display().to_string(),
+        arch,
+        "staging",
+        invocation,
+    ]
+    .iter()
+    .collect();
+
+    fs::create_dir_all(&path).context(error::DirectoryCreateSnafu { path: &path })?;
+
+    Ok(path)
+}
+
 const MARKER_EXTENSION: &str = ".buildsys_marker";
 
-/// Copy build artifacts to the output directory.
-/// Before we copy each file, we create a corresponding marker file to record its existence.
-fn copy_build_files<P>(build_dir: P, output_dir: P) -> Result<()>
+/// Move build artifacts from the staging directory to the output directory.
+/// Before we move each file, we create a corresponding marker file to record its existence.
+fn move_build_files<P>(staging_dir: P, marker_dir: P, output_dir: P) -> Result<()>
 where
     P: AsRef<Path>,
 {
-    fn has_artifacts(entry: &DirEntry) -> bool {
-        let is_dir = entry.path().is_dir();
-        let is_file = entry.file_type().is_file();
-        let is_not_marker = is_file
-            && entry
-                .file_name()
-                .to_str()
-                .map(|s| !s.ends_with(MARKER_EXTENSION))
-                .unwrap_or(false);
-        let is_symlink = entry.file_type().is_symlink();
-        is_dir || is_not_marker || is_symlink
+    // Markers live in a separate directory, so everything in the staging directory is an artifact.
+    fn is_artifact(_: &DirEntry) -> bool {
+        true
     }
 
-    for artifact_file in find_files(&build_dir, has_artifacts) {
-        let mut marker_file = artifact_file.clone().into_os_string();
+    for artifact_file in find_files(&staging_dir, is_artifact) {
+        let relative_path =
+            artifact_file
+                .strip_prefix(&staging_dir)
+                .context(error::StripPathPrefixSnafu {
+                    path: &artifact_file,
+                    prefix: staging_dir.as_ref(),
+                })?;
+
+        let mut marker_file = marker_dir.as_ref().join(relative_path).into_os_string();
         marker_file.push(MARKER_EXTENSION);
+        let marker_file = PathBuf::from(marker_file);
+        let marker_parent = marker_file
+            .parent()
+            .context(error::BadDirectorySnafu { path: &marker_file })?;
+        fs::create_dir_all(marker_parent).context(error::DirectoryCreateSnafu {
+            path: marker_parent,
+        })?;
         File::create(&marker_file).context(error::FileCreateSnafu { path: &marker_file })?;
 
-        let mut output_file: PathBuf = output_dir.as_ref().into();
-        output_file.push(artifact_file.strip_prefix(&build_dir).context(
-            error::StripPathPrefixSnafu {
-                path: &marker_file,
-                prefix: build_dir.as_ref(),
-            },
-        )?);
+        let output_file = output_dir.as_ref().join(relative_path);
 
         let parent_dir = output_file
             .parent()
@@ -1066,3 +1387,43 @@ fn filename(p: impl AsRef<Path>) -> String {
         .to_string_lossy()
         .to_string()
 }
+
+/// Helper to pass the SDK and external kits locked by Twoliter.lock to the build as a JSON object.
+fn locked_images(external_kit_metadata: &ExternalKitMetadataView) -> Result<String> {
+    serde_json::to_string(external_kit_metadata).context(error::LockedImagesSerializeSnafu)
+}
+
+/// Helper to pass a map of labels or annotations to the build as a JSON object.
+fn json_object(map: &BTreeMap<String, String>) -> Result<String> {
+    serde_json::to_string(map).context(error::KitMetadataSerializeSnafu)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_staged_outputs_are_tracked_and_cleaned() {
+        let state = TempDir::new().unwrap();
+        let output = TempDir::new().unwrap();
+        let marker_dir =
+            create_marker_dir(&BuildType::Package, "foo", "x86_64", state.path()).unwrap();
+        let staging_dir = create_staging_dir("x86_64", "1234", state.path()).unwrap();
+        fs::create_dir_all(staging_dir.join("sub")).unwrap();
+        fs::write(staging_dir.join("sub").join("foo.rpm"), "rpm").unwrap();
+
+        move_build_files(&staging_dir, &marker_dir, &output.path().to_path_buf()).unwrap();
+        let output_file = output.path().join("sub").join("foo.rpm");
+        assert!(output_file.is_file());
+        assert!(!staging_dir.join("sub").join("foo.rpm").exists());
+        assert!(marker_dir
+            .join("sub")
+            .join(format!("foo.rpm{MARKER_EXTENSION}"))
+            .is_file());
+
+        clean_build_files(&marker_dir, &[output.path().to_path_buf()]).unwrap();
+        assert!(!output_file.exists());
+        assert!(!marker_dir.join("sub").exists());
+    }
+}
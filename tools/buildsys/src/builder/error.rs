@@ -19,6 +19,11 @@ pub(crate) enum Error {
     #[snafu(display("Failed to start command: {}", source))]
     CommandStart { source: std::io::Error },
 
+    #[snafu(display("{}", source))]
+    ContainerTool {
+        source: buildsys_config::container_tool::UnsupportedContainerTool,
+    },
+
     #[snafu(display("Failed to execute command: 'docker {}'", args))]
     DockerExecution { args: String },
 
@@ -58,6 +63,12 @@ pub(crate) enum Error {
         source: std::io::Error,
     },
 
+    #[snafu(display("Failed to read file '{}': {}", path.display(), source))]
+    FileRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
     #[snafu(display("Failed to remove file '{}': {}", path.display(), source))]
     FileRemove {
         path: PathBuf,
@@ -71,15 +82,49 @@ pub(crate) enum Error {
         source: std::io::Error,
     },
 
+    #[snafu(display("Failed to write file '{}': {}", path.display(), source))]
+    FileWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to serialize flaky build record: {}", source))]
+    FlakeSerialize { source: serde_json::Error },
+
+    #[snafu(display("Invalid flaky build pattern in '{}': {}", path.display(), source))]
+    FlakyPattern { path: PathBuf, source: regex::Error },
+
     #[snafu(display("Failed to create build arguments due to a dependency error: {source}"))]
     Graph { source: buildsys::manifest::Error },
 
+    #[snafu(display("Invalid kit label or annotation '{}': {}", template, reason))]
+    KitMetadataTemplate { template: String, reason: String },
+
+    #[snafu(display("Failed to serialize kit labels or annotations: {}", source))]
+    KitMetadataSerialize { source: serde_json::Error },
+
+    #[snafu(display("Failed to serialize the images locked by Twoliter.lock: {}", source))]
+    LockedImagesSerialize { source: serde_json::Error },
+
     #[snafu(display("Missing environment variable '{}'", var))]
     Environment {
         var: String,
         source: std::env::VarError,
     },
 
+    #[snafu(display(
+        "Package '{}' needs secret '{}', which is not declared in the `secret` section of Twoliter.toml",
+        package,
+        name
+    ))]
+    SecretMissing { package: String, name: String },
+
+    #[snafu(display(
+        "Package '{}' needs secrets, but no secrets were provided through BUILDSYS_SECRETS_DIR",
+        package
+    ))]
+    SecretsDirMissing { package: String },
+
     #[snafu(display("Failed to strip prefix '{}' from path '{}': {}", prefix.display(), path.display(), source))]
     StripPathPrefix {
         path: PathBuf,
@@ -0,0 +1,126 @@
+/*!
+Retries package builds that fail with output matching one of the project's flaky patterns, such as
+a network hiccup or a compiler that segfaulted, and records each flaky build so that flakes can be
+tracked across runs.
+
+Patterns are read from the file named by `BUILDSYS_FLAKY_PATTERNS_FILE`, one regular expression
+per line. Blank lines and lines starting with `#` are ignored.
+
+*/
+use super::error::{self, Result};
+use buildsys::manifest::SupportedArch;
+use regex::Regex;
+use serde::Serialize;
+use snafu::ResultExt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The file in the state directory where flaky builds are recorded, one JSON object per line.
+const FLAKE_LOG: &str = "flaky-builds.jsonl";
+
+/// How to retry a package build that fails for a reason the project considers flaky.
+pub(super) struct FlakyRetry {
+    patterns: Vec<Regex>,
+    retries: u16,
+    log_path: PathBuf,
+    package: String,
+    arch: SupportedArch,
+}
+
+impl FlakyRetry {
+    pub(super) fn new(
+        patterns_file: &Path,
+        retries: u16,
+        state_dir: &Path,
+        package: &str,
+        arch: SupportedArch,
+    ) -> Result<Self> {
+        let contents = fs::read_to_string(patterns_file).context(error::FileReadSnafu {
+            path: patterns_file,
+        })?;
+        let patterns = parse_patterns(&contents).context(error::FlakyPatternSnafu {
+            path: patterns_file,
+        })?;
+        Ok(Self {
+            patterns,
+            retries,
+            log_path: state_dir.join(FLAKE_LOG),
+            package: package.to_string(),
+            arch,
+        })
+    }
+
+    /// The number of times a build may be retried after a flaky failure.
+    pub(super) fn retries(&self) -> u16 {
+        self.retries
+    }
+
+    /// The first flaky pattern that matches the output of a failed build, if any.
+    pub(super) fn matching(&self, output: &str) -> Option<&Regex> {
+        self.patterns.iter().find(|p| p.is_match(output))
+    }
+
+    /// Appends a record of a build that hit flaky failures to the flake log.
+    pub(super) fn record(&self, patterns: &[String], recovered: bool) -> Result<()> {
+        let flake = Flake {
+            package: &self.package,
+            arch: self.arch.to_string(),
+            patterns,
+            retries: patterns.len(),
+            recovered,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        };
+        let mut line = serde_json::to_string(&flake).context(error::FlakeSerializeSnafu)?;
+        line.push('\n');
+
+        // Package builds run in parallel, so write each record with a single append.
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log_path)
+            .and_then(|mut f| f.write_all(line.as_bytes()))
+            .context(error::FileWriteSnafu {
+                path: &self.log_path,
+            })
+    }
+}
+
+#[derive(Serialize)]
+struct Flake<'a> {
+    package: &'a str,
+    arch: String,
+    patterns: &'a [String],
+    retries: usize,
+    recovered: bool,
+    timestamp: u64,
+}
+
+fn parse_patterns(contents: &str) -> std::result::Result<Vec<Regex>, regex::Error> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Regex::new)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_patterns() {
+        let patterns = parse_patterns(
+            "# network hiccups\nConnection reset by peer\n\n(?m)^.*internal compiler error: Segmentation fault\n",
+        )
+        .unwrap();
+        assert_eq!(patterns.len(), 2);
+        assert!(patterns[1].is_match("gcc: internal compiler error: Segmentation fault signal"));
+        assert!(parse_patterns("(unclosed").is_err());
+    }
+}
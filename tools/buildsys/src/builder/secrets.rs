@@ -0,0 +1,118 @@
+/*!
+Passes the secrets a package declares in `package.metadata.build-package.secrets` to its build,
+such as a token for a private crate registry or Go module proxy.
+
+Twoliter resolves the project's secrets before the build and writes each one, named as in
+`Twoliter.toml`, to the private directory named by `BUILDSYS_SECRETS_DIR`. The secrets a package
+declares are gathered into a file of shell variable assignments that is handed to the build as a
+BuildKit secret, which is mounted only while `rpmbuild` runs. Secrets are therefore never written
+to an image layer, are not part of any cache key, and are never passed as build arguments that
+would show up in logs or image history.
+
+A secret named `cargo-registry-token` is seen by the build as `CARGO_REGISTRY_TOKEN`.
+
+*/
+use super::error::{self, Result};
+use super::BuildSecret;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// The id of the BuildKit secret which holds a package's secrets.
+const PACKAGE_SECRETS_ID: &str = "package-secrets";
+
+/// The directory within the secrets directory where each package's file of secrets is written.
+/// Secret names cannot contain a `.`, so it never collides with a secret.
+const PACKAGE_SECRETS_DIR: &str = ".packages";
+
+/// Returns the `--secret` argument which passes the secrets declared by `package` to its build,
+/// if it declares any.
+pub(super) fn package_secrets_args(
+    package: &str,
+    secrets: &[String],
+    secrets_dir: Option<&Path>,
+) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    if secrets.is_empty() {
+        return Ok(args);
+    }
+    let secrets_dir = secrets_dir.context(error::SecretsDirMissingSnafu { package })?;
+
+    let mut contents = String::new();
+    for name in secrets {
+        let path = secrets_dir.join(name);
+        ensure!(
+            path.is_file(),
+            error::SecretMissingSnafu {
+                package,
+                name: name.as_str(),
+            }
+        );
+        let value = fs::read_to_string(&path).context(error::FileReadSnafu { path: &path })?;
+        contents.push_str(&format!(
+            "export {}={}\n",
+            variable_name(name),
+            shell_quote(&value)
+        ));
+    }
+
+    let dir = secrets_dir.join(PACKAGE_SECRETS_DIR);
+    fs::create_dir_all(&dir).context(error::DirectoryCreateSnafu { path: &dir })?;
+    let path = dir.join(format!("{package}.env"));
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .context(error::FileCreateSnafu { path: &path })?;
+    file.write_all(contents.as_bytes())
+        .context(error::FileWriteSnafu { path: &path })?;
+
+    args.build_secret("file", PACKAGE_SECRETS_ID, &path.to_string_lossy());
+    Ok(args)
+}
+
+/// The name of the environment variable which holds a secret during the build.
+fn variable_name(name: &str) -> String {
+    name.replace('-', "_").to_uppercase()
+}
+
+/// Quotes `value` for a POSIX shell, so that it is assigned as is.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_package_secrets_args() {
+        assert!(package_secrets_args("pkg", &[], None).unwrap().is_empty());
+        assert!(package_secrets_args("pkg", &["token".to_string()], None).is_err());
+
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("cargo-registry-token"), "it's-a-secret").unwrap();
+        let secrets = ["cargo-registry-token".to_string()];
+        let args = package_secrets_args("pkg", &secrets, Some(dir.path())).unwrap();
+        let path = dir.path().join(".packages/pkg.env");
+        assert_eq!(
+            args,
+            [
+                "--secret".to_string(),
+                format!("type=file,id=package-secrets,src={}", path.display())
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "export CARGO_REGISTRY_TOKEN='it'\\''s-a-secret'\n"
+        );
+
+        let missing = ["other-token".to_string()];
+        assert!(package_secrets_args("pkg", &missing, Some(dir.path())).is_err());
+    }
+}
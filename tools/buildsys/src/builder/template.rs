@@ -0,0 +1,118 @@
+/*!
+Renders the extra labels and annotations that a kit declares in its manifest. Values may refer to
+the build context with `{{name}}`, or to an environment variable with `{{env.NAME}}`.
+
+*/
+use super::error::{self, Result};
+use snafu::OptionExt;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+
+const ENV_PREFIX: &str = "env.";
+
+/// The values available to templates, e.g. `kit` or `build-id`.
+pub(super) struct TemplateContext {
+    values: HashMap<&'static str, String>,
+}
+
+impl TemplateContext {
+    pub(super) fn new() -> Self {
+        Self {
+            values: HashMap::new(),
+        }
+    }
+
+    pub(super) fn with(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.values.insert(name, value.into());
+        self
+    }
+
+    /// Renders each value in `map`, dropping entries which render to an empty string.
+    pub(super) fn render_map(
+        &self,
+        map: Option<&BTreeMap<String, String>>,
+    ) -> Result<BTreeMap<String, String>> {
+        let mut rendered = BTreeMap::new();
+        for (key, template) in map.into_iter().flatten() {
+            let value = self.render(template)?;
+            if !value.is_empty() {
+                rendered.insert(key.clone(), value);
+            }
+        }
+        Ok(rendered)
+    }
+
+    fn render(&self, template: &str) -> Result<String> {
+        let mut output = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            output.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find("}}")
+                .context(error::KitMetadataTemplateSnafu {
+                    template,
+                    reason: "unterminated '{{'",
+                })?;
+            let name = rest[start + 2..start + end].trim();
+            output.push_str(&self.lookup(template, name)?);
+            rest = &rest[start + end + 2..];
+        }
+        output.push_str(rest);
+        Ok(output)
+    }
+
+    fn lookup(&self, template: &str, name: &str) -> Result<String> {
+        if let Some(var) = name.strip_prefix(ENV_PREFIX) {
+            // The rendered value depends on this variable, so the kit must be rebuilt if it
+            // changes.
+            println!("cargo:rerun-if-env-changed={var}");
+            return Ok(env::var(var).unwrap_or_default());
+        }
+        self.values
+            .get(name)
+            .cloned()
+            .context(error::KitMetadataTemplateSnafu {
+                template,
+                reason: format!("unknown variable '{name}'"),
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn context() -> TemplateContext {
+        TemplateContext::new()
+            .with("kit", "core-kit")
+            .with("build-id", "abc123")
+    }
+
+    #[test]
+    fn test_render_context_values() {
+        let rendered = context().render("{{kit}}@{{ build-id }}").unwrap();
+        assert_eq!(rendered, "core-kit@abc123");
+    }
+
+    #[test]
+    fn test_render_unknown_variable() {
+        assert!(context().render("{{nope}}").is_err());
+        assert!(context().render("{{kit").is_err());
+    }
+
+    #[test]
+    fn test_render_map_drops_empty_values() {
+        let map = BTreeMap::from([
+            ("a".to_string(), "{{kit}}".to_string()),
+            (
+                "b".to_string(),
+                "{{env.BUILDSYS_TEST_UNSET_TEMPLATE_VAR}}".to_string(),
+            ),
+        ]);
+        let rendered = context().render_map(Some(&map)).unwrap();
+        assert_eq!(
+            rendered,
+            BTreeMap::from([("a".to_string(), "core-kit".to_string())])
+        );
+    }
+}
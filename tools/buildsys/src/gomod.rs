@@ -23,7 +23,6 @@ use error::Result;
 use filetime::{set_file_mtime, FileTime};
 use snafu::{ensure, OptionExt, ResultExt};
 use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::{env, fs};
 
@@ -137,8 +136,12 @@ impl GoMod {
         {
             let mut script_file = fs::File::create(&script_path)
                 .context(error::CreateFileSnafu { path: &script_path })?;
-            fs::set_permissions(&script_path, fs::Permissions::from_mode(0o777))
-                .context(error::SetFilePermissionsSnafu { path: &script_path })?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&script_path, fs::Permissions::from_mode(0o777))
+                    .context(error::SetFilePermissionsSnafu { path: &script_path })?;
+            }
             script_file
                 .write_all(script_contents.as_bytes())
                 .context(error::WriteFileSnafu { path: &script_path })?;
@@ -13,23 +13,30 @@ mod builder;
 mod cache;
 mod gomod;
 mod project;
+mod source_offer;
 mod spec;
 
 use crate::args::{
     BuildKitArgs, BuildPackageArgs, BuildVariantArgs, Buildsys, Command, RepackVariantArgs,
+    SourceOfferArgs,
 };
 use crate::builder::DockerBuild;
 use buildsys::manifest::{BundleModule, Manifest, ManifestInfo, SupportedArch};
-use buildsys_config::EXTERNAL_KIT_METADATA;
+use buildsys_config::{EXTERNAL_KIT_METADATA, FAILURE_LOG_PREFIX};
 use cache::LookasideCache;
 use clap::Parser;
 use filetime::FileTime;
 use gomod::GoMod;
 use project::ProjectInfo;
 use snafu::{ensure, ResultExt};
+use source_offer::SourceOffer;
 use spec::SpecInfo;
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::time::{Duration, Instant};
+use url::Url;
 
 mod error {
     use snafu::Snafu;
@@ -61,11 +68,23 @@ mod error {
             source: super::project::error::Error,
         },
 
+        #[snafu(display("{source}"))]
+        SourceOffer {
+            source: super::source_offer::error::Error,
+        },
+
         #[snafu(display("{source}"))]
         BuildAttempt {
             source: super::builder::error::Error,
         },
 
+        #[snafu(display("Failed to build package '{package}' (owned by {owner}): {source}"))]
+        OwnedPackageBuildAttempt {
+            package: String,
+            owner: String,
+            source: super::builder::error::Error,
+        },
+
         #[snafu(display("Unable to instantiate the builder: {source}"))]
         BuilderInstantiation {
             source: crate::builder::error::Error,
@@ -107,12 +126,15 @@ fn main() {
 }
 
 fn run(args: Buildsys) -> Result<()> {
-    args::rerun_for_envs(args.command.build_type());
+    if let Some(build_type) = args.command.build_type() {
+        args::rerun_for_envs(build_type);
+    }
     match args.command {
         Command::BuildPackage(args) => build_package(*args),
         Command::BuildKit(args) => build_kit(*args),
         Command::BuildVariant(args) => build_variant(*args),
         Command::RepackVariant(args) => repack_variant(*args),
+        Command::SourceOffer(args) => source_offer(*args),
     }
 }
 
@@ -201,10 +223,109 @@ fn build_package(args: BuildPackageArgs) -> Result<()> {
         return Ok(());
     }
 
-    DockerBuild::new_package(args, &manifest)
+    let owner = manifest.info().package_owner().map(str::to_string);
+    let arch = args.common.arch;
+    let failure_webhook_url = args.failure_webhook_url.clone();
+    let state_dir = args.common.state_dir.clone();
+    let started = Instant::now();
+    let result = DockerBuild::new_package(args, &manifest)
         .context(error::BuilderInstantiationSnafu)?
-        .build()
-        .context(error::BuildAttemptSnafu)
+        .build();
+
+    let Err(e) = result else {
+        record_package_timing(&state_dir, package, arch, started.elapsed());
+        return Ok(());
+    };
+    record_package_failure(&state_dir, package, owner.as_deref(), arch, &e);
+    if let Some(url) = failure_webhook_url {
+        report_package_failure(&url, package, owner.as_deref(), arch, &e);
+    }
+    match owner {
+        Some(owner) => Err(e).context(error::OwnedPackageBuildAttemptSnafu { package, owner }),
+        None => Err(e).context(error::BuildAttemptSnafu),
+    }
+}
+
+/// Record a failed package build in the state directory. Like the webhook, failing to record it
+/// is only a warning.
+fn record_package_failure(
+    state_dir: &Path,
+    package: &str,
+    owner: Option<&str>,
+    arch: SupportedArch,
+    error: &builder::error::Error,
+) {
+    let line = serde_json::json!({
+        "package": package,
+        "owner": owner,
+        "arch": arch.to_string(),
+        "error": error.to_string(),
+    });
+    let path = state_dir.join(format!("{FAILURE_LOG_PREFIX}-{arch}.jsonl"));
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut f| f.write_all(format!("{line}\n").as_bytes()));
+    if let Err(e) = written {
+        println!(
+            "cargo:warning=Failed to record build failure of '{package}' in '{}': {e}",
+            path.display()
+        );
+    }
+}
+
+/// The prefix of the files in the state directory where the durations of successful package
+/// builds are recorded, one file per architecture with one JSON object per line, so that twoliter
+/// can estimate how long later builds will take.
+const TIMING_LOG_PREFIX: &str = "package-timings";
+
+/// Record how long a package took to build in the state directory. Failing to record it is only a
+/// warning.
+fn record_package_timing(state_dir: &Path, package: &str, arch: SupportedArch, took: Duration) {
+    let line = serde_json::json!({
+        "package": package,
+        "arch": arch.to_string(),
+        "seconds": took.as_secs_f64(),
+    });
+    let path = state_dir.join(format!("{TIMING_LOG_PREFIX}-{arch}.jsonl"));
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut f| f.write_all(format!("{line}\n").as_bytes()));
+    if let Err(e) = written {
+        println!(
+            "cargo:warning=Failed to record build time of '{package}' in '{}': {e}",
+            path.display()
+        );
+    }
+}
+
+/// Post a summary of a failed package build to `url`. Failing to deliver the summary is only a
+/// warning, since the build failure itself is what needs to be surfaced.
+fn report_package_failure(
+    url: &Url,
+    package: &str,
+    owner: Option<&str>,
+    arch: SupportedArch,
+    error: &builder::error::Error,
+) {
+    let summary = serde_json::json!({
+        "package": package,
+        "owner": owner,
+        "arch": arch.to_string(),
+        "error": error.to_string(),
+    });
+    let response = reqwest::blocking::Client::new()
+        .post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(summary.to_string())
+        .send()
+        .and_then(|response| response.error_for_status());
+    if let Err(e) = response {
+        println!("cargo:warning=Failed to report build failure of '{package}' to {url}: {e}");
+    }
 }
 
 fn build_kit(args: BuildKitArgs) -> Result<()> {
@@ -278,6 +399,31 @@ fn repack_variant(args: RepackVariantArgs) -> Result<()> {
         .context(error::BuildAttemptSnafu)
 }
 
+fn source_offer(args: SourceOfferArgs) -> Result<()> {
+    let manifest = Manifest::new(
+        args.common.cargo_manifest_dir.join("Cargo.toml"),
+        &args.common.cargo_metadata_path,
+    )
+    .context(error::ManifestParseSnafu)?;
+
+    let kit = manifest.info().kit_name();
+    let package_dirs = manifest
+        .package_dependency_dirs()
+        .context(error::ManifestParseSnafu)?;
+    let offer = SourceOffer::collect(kit, &args.version_image, &package_dirs)
+        .context(error::SourceOfferSnafu)?;
+
+    let archive_path = args.source_offers_dir.join(format!(
+        "{kit}-v{version}-sources.tar.gz",
+        version = args.version_image
+    ));
+    offer
+        .write_archive(&archive_path)
+        .context(error::SourceOfferSnafu)?;
+    println!("Wrote source offer to {}", archive_path.display());
+    Ok(())
+}
+
 /// Ensure that the current arch is supported by the current variant
 fn check_arch_support(manifest: &ManifestInfo, arch: SupportedArch) {
     if let Some(supported_arches) = manifest.supported_arches() {
@@ -87,6 +87,25 @@ to indicate a good URL for checking whether the software has had a new release.
 releases-url = "https://www.example.com/releases"
 ```
 
+`owner` names the team responsible for the package. It does not affect the
+build, but when the package fails to build the error names the owner, and the
+failure is reported to `BUILDSYS_FAILURE_WEBHOOK_URL` if that is set, so that
+failures in kits shared by several teams reach the right people.
+```ignore
+[package.metadata.build-package]
+owner = "networking-team"
+```
+
+`secrets` names secrets from the `secret` section of `Twoliter.toml` that the
+package needs to build, such as a token for a private crate registry. Each is
+set as an environment variable while `rpmbuild` runs, named for the secret in
+upper case with `-` replaced by `_`, and is never written to the image layers,
+build arguments or logs of the build.
+```ignore
+[package.metadata.build-package]
+secrets = ["cargo-registry-token"]
+```
+
 ## Metadata for kits
 
 When building a kit, it is necessary to include a `package.metadata.build-kit` key even though there
@@ -106,6 +125,24 @@ another-kit = { path = "../../kits/another-kit" }
 some-package = { path = "../../packages/some-package" }
 ```
 
+`labels` and `annotations` are optional maps of extra labels to add to the kit's image config, and
+annotations to add to its image manifest, so that provenance such as the source revision or the CI
+run that built the kit travels with the published image. Values may refer to the build context with
+`{{kit}}`, `{{vendor}}`, `{{arch}}`, `{{version}}` and `{{build-id}}`, or to an environment
+variable with `{{env.NAME}}`. Entries which render to an empty string, for example because the
+environment variable is unset, are left out.
+```ignore
+[package.metadata.build-kit]
+vendor = "my-vendor"
+
+[package.metadata.build-kit.labels]
+"org.opencontainers.image.revision" = "{{build-id}}"
+"com.example.compliance" = "reviewed"
+
+[package.metadata.build-kit.annotations]
+"com.example.ci.run-url" = "{{env.CI_RUN_URL}}"
+```
+
 ## Metadata for variants
 
 `included-packages` is a list of packages that should be included in a variant.
@@ -121,6 +158,14 @@ This can be `raw` (the default), `vmdk`, or `qcow2`.
 image-format = "vmdk"
 ```
 
+It can also be `oci`, to build the variant as a bootable container image rather
+than a disk image, for deployments which install or update the OS from a
+container image, such as ostree or bootc. The image is an OCI archive with the
+root filesystem in a single layer, and the boot partition's files under `/boot`,
+and `cargo make publish-image` pushes it to a vendor's registry. The image is
+unlabeled for SELinux, so the tool which deploys it labels its files, and the
+`image-layout` only applies to the boot and root images built beside it.
+
 `image-layout` is the desired layout for the built images.
 
 `os-image-size-gib` is the desired size of the "os" disk image in GiB.
@@ -250,7 +295,7 @@ use guppy::{CargoMetadata, PackageId};
 use serde::{Deserialize, Serialize};
 use snafu::{OptionExt, ResultExt, Snafu};
 use std::cmp::max;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fmt::{self, Display};
 use std::fs;
@@ -295,6 +340,39 @@ impl Manifest {
     /// gives a list of all the packages that are required when we are build a package, or all of the
     /// packages that should be included when building a kit.
     pub fn package_dependencies(&self) -> Result<Vec<String>> {
+        let name = self.info().manifest_name();
+        let mut packages: Vec<String> = self
+            .package_dependency_metadata()?
+            .iter()
+            .filter_map(|pkg_metadata| filter_map_to_name(name, pkg_metadata))
+            .collect();
+
+        // Sort so that this function has consistent, dependable output regardless of graph internals.
+        packages.sort();
+        Ok(packages)
+    }
+
+    /// List the directories of all packages that are package dependencies, following the same
+    /// rules as `package_dependencies`. Each directory contains the package's `Cargo.toml` and spec
+    /// file.
+    pub fn package_dependency_dirs(&self) -> Result<Vec<PathBuf>> {
+        let name = self.info().manifest_name();
+        let mut dirs: Vec<PathBuf> = self
+            .package_dependency_metadata()?
+            .iter()
+            .filter(|pkg_metadata| pkg_metadata.name() != name)
+            .filter_map(|pkg_metadata| {
+                pkg_metadata
+                    .manifest_path()
+                    .parent()
+                    .map(|dir| dir.as_std_path().to_path_buf())
+            })
+            .collect();
+        dirs.sort();
+        Ok(dirs)
+    }
+
+    fn package_dependency_metadata(&self) -> Result<Vec<PackageMetadata<'_>>> {
         let name = self.info().manifest_name();
         let manifest_type = self.info().build_type()?;
         let id = find_id(name, &self.graph, manifest_type)
@@ -308,14 +386,7 @@ impl Manifest {
             let to = link.to();
             is_valid_dep(name, &link) && is_manifest_type(&to, BuildType::Package)
         });
-        let mut packages: Vec<String> = package_set
-            .packages(DependencyDirection::Forward)
-            .filter_map(|pkg_metadata| filter_map_to_name(name, &pkg_metadata))
-            .collect();
-
-        // Sort so that this function has consistent, dependable output regardless of graph internals.
-        packages.sort();
-        Ok(packages)
+        Ok(package_set.packages(DependencyDirection::Forward).collect())
     }
 
     /// List all kits needed for the build.
@@ -344,8 +415,9 @@ impl Manifest {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct ExternalKitMetadataView {
+    sdk: ImageView,
     #[serde(rename = "kit")]
     kits: Vec<ImageView>,
 }
@@ -376,10 +448,15 @@ impl ExternalKitMetadataView {
     }
 }
 
-#[derive(Deserialize, Debug)]
+/// An image locked by Twoliter.lock. The digest is Twoliter's digest of the image's manifests,
+/// as recorded in the lock.
+#[derive(Deserialize, Serialize, Debug)]
 struct ImageView {
     name: String,
+    version: String,
     vendor: String,
+    source: String,
+    digest: String,
 }
 
 /// The nested structures here are somewhat complex, but they make it trivial
@@ -424,6 +501,18 @@ impl ManifestInfo {
             .unwrap_or_else(|| self.manifest_name())
     }
 
+    /// Convenience method to return the team that owns the package, if any.
+    pub fn package_owner(&self) -> Option<&str> {
+        self.build_package().and_then(|b| b.owner.as_deref())
+    }
+
+    /// Convenience method to return the names of the secrets the package needs to build.
+    pub fn package_secrets(&self) -> &[String] {
+        self.build_package()
+            .and_then(|b| b.secrets.as_deref())
+            .unwrap_or_default()
+    }
+
     /// Convenience method to return the kit name. If the manifest has an override in the
     /// `package.metadata.build-kit.kit-name` key, it is returned, otherwise the Cargo manifest name
     /// is returned from `package.name`.
@@ -444,6 +533,16 @@ impl ManifestInfo {
             .clone())
     }
 
+    /// Convenience method to return the extra labels to add to the kit's image config.
+    pub fn kit_labels(&self) -> Option<&BTreeMap<String, String>> {
+        self.build_kit().and_then(|b| b.labels.as_ref())
+    }
+
+    /// Convenience method to return the extra annotations to add to the kit's image manifest.
+    pub fn kit_annotations(&self) -> Option<&BTreeMap<String, String>> {
+        self.build_kit().and_then(|b| b.annotations.as_ref())
+    }
+
     /// Convenience method to find whether the package is sensitive to variant changes.
     pub fn variant_sensitive(&self) -> Option<&VariantSensitivity> {
         self.build_package()
@@ -632,6 +731,8 @@ pub struct BuildPackage {
     pub source_groups: Option<Vec<PathBuf>>,
     pub variant_sensitive: Option<VariantSensitivity>,
     pub package_features: Option<Vec<ImageFeature>>,
+    pub owner: Option<String>,
+    pub secrets: Option<Vec<String>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -640,6 +741,8 @@ pub struct BuildPackage {
 pub struct BuildKit {
     pub kit_name: Option<String>,
     pub vendor: String,
+    pub labels: Option<BTreeMap<String, String>>,
+    pub annotations: Option<BTreeMap<String, String>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -677,6 +780,7 @@ pub enum ImageFormat {
     Qcow2,
     Raw,
     Vmdk,
+    Oci,
 }
 
 #[derive(Deserialize, Debug, Copy, Clone)]
@@ -945,6 +1049,21 @@ mod test {
         assert_eq!(package_list, expected);
     }
 
+    #[test]
+    fn test_package_dirs_extra_3_kit() {
+        let manifest_path = cargo_manifest("extra-3-kit");
+        let temp_dir = TempDir::new().unwrap();
+        let cargo_metadata_path = cargo_metadata_path(&temp_dir);
+        let manifest = Manifest::new(manifest_path, cargo_metadata_path).unwrap();
+        let dirs = manifest.package_dependency_dirs().unwrap();
+        let expected = vec![
+            cargo_manifest("pkg-e").parent().unwrap().to_path_buf(),
+            cargo_manifest("pkg-f").parent().unwrap().to_path_buf(),
+            cargo_manifest("pkg-g").parent().unwrap().to_path_buf(),
+        ];
+        assert_eq!(dirs, expected);
+    }
+
     #[test]
     fn test_kit_dependencies_pkg_e() {
         let manifest_path = cargo_manifest("pkg-e");
@@ -975,4 +1094,52 @@ mod test {
         ];
         assert_eq!(kit_list, expected);
     }
+
+    #[test]
+    fn test_package_owner() {
+        let temp_dir = TempDir::new().unwrap();
+        let manifest_path = temp_dir.path().join("Cargo.toml");
+        fs::write(
+            &manifest_path,
+            r#"
+[package]
+name = "pkg-owned"
+
+[package.metadata.build-package]
+owner = "networking-team"
+"#,
+        )
+        .unwrap();
+        let info = ManifestInfo::new(&manifest_path).unwrap();
+        assert_eq!(info.package_owner(), Some("networking-team"));
+    }
+
+    #[test]
+    fn test_external_kit_metadata_locked_images() {
+        let temp_dir = TempDir::new().unwrap();
+        let metadata_file = temp_dir.path().join(EXTERNAL_KIT_METADATA);
+        fs::create_dir_all(metadata_file.parent().unwrap()).unwrap();
+        fs::write(
+            &metadata_file,
+            r#"{
+  "sdk": {"name": "bottlerocket-sdk", "version": "0.50.0", "vendor": "bottlerocket",
+          "source": "public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0", "digest": "c2Rr"},
+  "kit": [{"name": "core-kit", "version": "2.0.0", "vendor": "bottlerocket",
+           "source": "public.ecr.aws/bottlerocket/core-kit:v2.0.0", "digest": "a2l0",
+           "resolved-from": "mirror.example.com/bottlerocket/core-kit:v2.0.0"}]
+}"#,
+        )
+        .unwrap();
+        let metadata = ExternalKitMetadataView::load(temp_dir.path()).unwrap();
+        assert_eq!(metadata.list(), ["bottlerocket/core-kit"]);
+
+        let locked: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&metadata).unwrap()).unwrap();
+        assert_eq!(locked["sdk"]["digest"], "c2Rr");
+        assert_eq!(
+            locked["kit"][0]["source"],
+            "public.ecr.aws/bottlerocket/core-kit:v2.0.0"
+        );
+        assert_eq!(locked["kit"][0]["digest"], "a2l0");
+    }
 }
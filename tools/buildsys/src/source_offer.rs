@@ -0,0 +1,310 @@
+/*!
+This module collects the upstream sources used to build the packages in a kit into a single
+"source offer" archive, so that the corresponding sources for a shipped image can be distributed
+alongside it.
+
+For every package the kit depends on, the archive contains the spec file, each `Source` and
+`Patch` it declares, every `external-files` entry from the package manifest, and any archives of
+vendored dependencies produced by `bundle-modules`. An `index.json` at the root of the archive
+lists each file with its SHA-512 hash and, for external files, the upstream URL.
+
+External files are only present after they have been fetched, so the kit must be built first.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use crate::spec::SpecInfo;
+use buildsys::manifest::{ExternalFile, ManifestInfo};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use sha2::{Digest, Sha512};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const INDEX_FILE: &str = "index.json";
+
+/// The role a file plays in a package build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SourceKind {
+    Spec,
+    Source,
+    Patch,
+    ExternalFile,
+    Bundled,
+}
+
+/// A single file included in the source offer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct SourceFile {
+    /// The path of the file within the archive, e.g. `kernel/kernel.spec`.
+    pub(crate) path: PathBuf,
+    pub(crate) kind: SourceKind,
+    pub(crate) sha512: String,
+    /// The upstream location of the file, for external files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) url: Option<String>,
+    #[serde(skip)]
+    local_path: PathBuf,
+}
+
+/// The sources used to build a single package.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct PackageSources {
+    pub(crate) name: String,
+    pub(crate) files: Vec<SourceFile>,
+}
+
+impl PackageSources {
+    /// Finds the sources of the package whose `Cargo.toml` and spec file live in `package_dir`.
+    pub(crate) fn collect(package_dir: impl AsRef<Path>) -> Result<Self> {
+        let package_dir = package_dir.as_ref();
+        let manifest =
+            ManifestInfo::new(package_dir.join("Cargo.toml")).context(error::ManifestParseSnafu)?;
+        let name = manifest.package_name().to_string();
+
+        // Keyed by file name so that a spec `Source` which refers to an external file is only
+        // listed once, as the external file.
+        let mut files = BTreeMap::new();
+        let spec = PathBuf::from(format!("{name}.spec"));
+        let info = SpecInfo::new(package_dir.join(&spec)).context(error::SpecParseSnafu)?;
+        files.insert(spec, (SourceKind::Spec, None));
+        for source in info.sources {
+            files.insert(source, (SourceKind::Source, None));
+        }
+        for patch in info.patches {
+            files.insert(patch, (SourceKind::Patch, None));
+        }
+        for external_file in manifest.external_files().into_iter().flatten() {
+            let local_name = external_file_name(external_file)?;
+            if external_file.bundle_modules.is_some() {
+                let bundled = external_file.bundle_output_path.clone().unwrap_or_else(|| {
+                    PathBuf::from(format!("bundled-{}", local_name.to_string_lossy()))
+                });
+                files.insert(bundled, (SourceKind::Bundled, None));
+            }
+            files.insert(
+                local_name,
+                (SourceKind::ExternalFile, Some(external_file.url.clone())),
+            );
+        }
+
+        let files = files
+            .into_iter()
+            .map(|(file_name, (kind, url))| {
+                let local_path = package_dir.join(&file_name);
+                ensure!(
+                    local_path.is_file(),
+                    error::MissingSourceSnafu {
+                        package: &name,
+                        path: &local_path,
+                    }
+                );
+                Ok(SourceFile {
+                    path: Path::new(&name).join(file_name),
+                    kind,
+                    sha512: sha512(&local_path)?,
+                    url,
+                    local_path,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { name, files })
+    }
+}
+
+/// The contents of `index.json` in a source offer archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct SourceOffer {
+    pub(crate) kit: String,
+    pub(crate) version: String,
+    pub(crate) packages: Vec<PackageSources>,
+}
+
+impl SourceOffer {
+    /// Collects the sources for each of the given package directories.
+    pub(crate) fn collect<P: AsRef<Path>>(
+        kit: impl Into<String>,
+        version: impl Into<String>,
+        package_dirs: &[P],
+    ) -> Result<Self> {
+        let packages = package_dirs
+            .iter()
+            .map(PackageSources::collect)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            kit: kit.into(),
+            version: version.into(),
+            packages,
+        })
+    }
+
+    /// Writes a gzipped tarball containing `index.json` followed by every collected file.
+    pub(crate) fn write_archive(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(error::DirectoryCreateSnafu { path: parent })?;
+        }
+        let file = File::create(path).context(error::ArchiveCreateSnafu { path })?;
+        let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        archive.mode(tar::HeaderMode::Deterministic);
+
+        let index = serde_json::to_vec_pretty(self).context(error::IndexSerializeSnafu)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(index.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive
+            .append_data(&mut header, INDEX_FILE, index.as_slice())
+            .context(error::ArchiveAppendSnafu { path: INDEX_FILE })?;
+
+        for file in self.packages.iter().flat_map(|p| p.files.iter()) {
+            archive
+                .append_path_with_name(&file.local_path, &file.path)
+                .context(error::ArchiveAppendSnafu {
+                    path: &file.local_path,
+                })?;
+        }
+
+        archive
+            .into_inner()
+            .and_then(|encoder| encoder.finish())
+            .context(error::ArchiveCreateSnafu { path })?;
+        Ok(())
+    }
+}
+
+/// The local file name of an external file, taken from `path` or the last segment of the URL.
+fn external_file_name(external_file: &ExternalFile) -> Result<PathBuf> {
+    if let Some(path) = &external_file.path {
+        return Ok(path.clone());
+    }
+    let url = &external_file.url;
+    let name = url::Url::parse(url)
+        .ok()
+        .and_then(|parsed| {
+            parsed
+                .path_segments()
+                .and_then(|mut segments| segments.next_back().map(str::to_string))
+        })
+        .filter(|name| !name.is_empty())
+        .context(error::ExternalFileUrlSnafu { url })?;
+    Ok(name.into())
+}
+
+fn sha512(path: &Path) -> Result<String> {
+    let mut f = File::open(path).context(error::SourceReadSnafu { path })?;
+    let mut d = Sha512::new();
+    io::copy(&mut f, &mut d).context(error::SourceReadSnafu { path })?;
+    Ok(hex::encode(d.finalize()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::collections::HashSet;
+    use tempfile::TempDir;
+
+    const MANIFEST: &str = r#"
+[package]
+name = "pkg-foo"
+version = "0.1.0"
+
+[package.metadata.build-package]
+package-name = "foo"
+
+[[package.metadata.build-package.external-files]]
+url = "https://example.com/releases/foo-1.0.tar.gz"
+sha512 = "abcdef"
+"#;
+
+    const SPEC: &str = "Name: %{_cross_os}foo
+Source0: https://example.com/releases/foo-%{version}.tar.gz
+Source0: foo-1.0.tar.gz
+Source100: foo.service
+Patch0001: 0001-fix.patch
+";
+
+    fn package_dir() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("Cargo.toml"), MANIFEST).unwrap();
+        fs::write(dir.path().join("foo.spec"), SPEC).unwrap();
+        fs::write(dir.path().join("foo-1.0.tar.gz"), "upstream").unwrap();
+        fs::write(dir.path().join("foo.service"), "[Unit]").unwrap();
+        fs::write(dir.path().join("0001-fix.patch"), "diff").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_collect_package_sources() {
+        let dir = package_dir();
+        let sources = PackageSources::collect(dir.path()).unwrap();
+        assert_eq!(sources.name, "foo");
+
+        let kinds = sources
+            .files
+            .iter()
+            .map(|f| (f.path.to_str().unwrap(), f.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ("foo/0001-fix.patch", SourceKind::Patch),
+                ("foo/foo-1.0.tar.gz", SourceKind::ExternalFile),
+                ("foo/foo.service", SourceKind::Source),
+                ("foo/foo.spec", SourceKind::Spec),
+            ]
+        );
+        let external = &sources.files[1];
+        assert_eq!(
+            external.url.as_deref(),
+            Some("https://example.com/releases/foo-1.0.tar.gz")
+        );
+        assert_eq!(
+            external.sha512,
+            sha512(&dir.path().join("foo-1.0.tar.gz")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_missing_source() {
+        let dir = package_dir();
+        fs::remove_file(dir.path().join("foo-1.0.tar.gz")).unwrap();
+        let err = PackageSources::collect(dir.path()).unwrap_err();
+        assert!(matches!(err, error::Error::MissingSource { .. }));
+    }
+
+    #[test]
+    fn test_write_archive() {
+        let dir = package_dir();
+        let out = TempDir::new().unwrap();
+        let archive_path = out.path().join("offers").join("foo-kit-sources.tar.gz");
+        let offer = SourceOffer::collect("foo-kit", "1.0.0", &[dir.path()]).unwrap();
+        offer.write_archive(&archive_path).unwrap();
+
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(&archive_path).unwrap()));
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().display().to_string())
+            .collect::<HashSet<_>>();
+        let expected = [
+            "index.json",
+            "foo/0001-fix.patch",
+            "foo/foo-1.0.tar.gz",
+            "foo/foo.service",
+            "foo/foo.spec",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect::<HashSet<_>>();
+        assert_eq!(entries, expected);
+    }
+}
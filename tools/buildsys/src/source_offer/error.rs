@@ -0,0 +1,40 @@
+use snafu::Snafu;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("{source}"))]
+    ManifestParse { source: buildsys::manifest::Error },
+
+    #[snafu(display("{source}"))]
+    SpecParse { source: crate::spec::error::Error },
+
+    #[snafu(display("Bad external file url '{}'", url))]
+    ExternalFileUrl { url: String },
+
+    #[snafu(display(
+        "Source file '{}' for package '{}' is missing, build the kit to fetch it first",
+        path.display(),
+        package
+    ))]
+    MissingSource { package: String, path: PathBuf },
+
+    #[snafu(display("Failed to read source file '{}': {}", path.display(), source))]
+    SourceRead { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to create directory '{}': {}", path.display(), source))]
+    DirectoryCreate { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to create archive '{}': {}", path.display(), source))]
+    ArchiveCreate { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to add '{}' to source archive: {}", path.display(), source))]
+    ArchiveAppend { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to serialize source archive index: {}", source))]
+    IndexSerialize { source: serde_json::Error },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -31,6 +31,12 @@ import (
 )
 import (
 	"bytes"
+	"crypto/x509"
+	"encoding/json"
+	"fmt"
+	"os/exec"
+	"path/filepath"
+	"strings"
 )
 
 func init() {
@@ -81,8 +87,154 @@ var (
 	azureKeychain  authn.Keychain = authn.NewKeychainFromHelper(credhelper.NewACRCredentialsHelper())
 )
 
+// The flags, given before any other argument, which carry the settings for reaching registries.
+// The environment of the process is fixed when it starts, so the flags are how the settings reach
+// calls made from a process which configures them later.
+const (
+	// The credentials configured for registries, as JSON.
+	registryAuthFlag = "--registry-auth="
+	// A file of PEM certificates which registries are trusted to present, besides the system's CAs.
+	caBundleFlag = "--ca-bundle="
+	// An environment variable to set, as KEY=VALUE, such as HTTPS_PROXY.
+	envFlag = "--env="
+)
+
+// The settings for reaching registries, from the flags given before any other argument.
+type settings struct {
+	keychain configuredKeychain
+	caBundle string
+}
+
+// Where to find the credentials for a registry: the environment variables holding its username
+// and password, or a docker credential helper. The credentials themselves are never passed.
+type registryAuth struct {
+	CredentialHelper string `json:"credential-helper"`
+	UsernameEnv      string `json:"username-env"`
+	PasswordEnv      string `json:"password-env"`
+}
+
+// A keychain of the credentials configured for registries, by registry host.
+type configuredKeychain map[string]registryAuth
+
+func (k configuredKeychain) Resolve(target authn.Resource) (authn.Authenticator, error) {
+	auth, ok := k[target.RegistryStr()]
+	if !ok {
+		return authn.Anonymous, nil
+	}
+	if auth.CredentialHelper != "" {
+		return authn.NewKeychainFromHelper(execHelper(auth.CredentialHelper)).Resolve(target)
+	}
+	username, password := os.Getenv(auth.UsernameEnv), os.Getenv(auth.PasswordEnv)
+	if username == "" || password == "" {
+		return nil, fmt.Errorf("credentials for %s are configured in %s and %s, which are not both set",
+			target.RegistryStr(), auth.UsernameEnv, auth.PasswordEnv)
+	}
+	return authn.FromConfig(authn.AuthConfig{Username: username, Password: password}), nil
+}
+
+// A docker credential helper, run as `docker-credential-<name> get`.
+type execHelper string
+
+func (h execHelper) Get(serverURL string) (string, string, error) {
+	cmd := exec.Command("docker-credential-"+string(h), "get")
+	cmd.Stdin = strings.NewReader(serverURL)
+	out, err := cmd.Output()
+	if err != nil {
+		return "", "", fmt.Errorf("credential helper %s failed for %s: %w", h, serverURL, err)
+	}
+	var creds struct {
+		Username string
+		Secret   string
+	}
+	if err := json.Unmarshal(out, &creds); err != nil {
+		return "", "", fmt.Errorf("credential helper %s returned invalid credentials: %w", h, err)
+	}
+	return creds.Username, creds.Secret, nil
+}
+
+// Takes the settings from the front of args, setting the environment variables given there.
+func parseSettings(args []string) (settings, []string, error) {
+	s := settings{keychain: configuredKeychain{}}
+	for len(args) > 0 {
+		switch arg := args[0]; {
+		case strings.HasPrefix(arg, registryAuthFlag):
+			if err := json.Unmarshal([]byte(strings.TrimPrefix(arg, registryAuthFlag)), &s.keychain); err != nil {
+				return s, nil, fmt.Errorf("invalid registry credentials: %w", err)
+			}
+		case strings.HasPrefix(arg, caBundleFlag):
+			s.caBundle = strings.TrimPrefix(arg, caBundleFlag)
+		case strings.HasPrefix(arg, envFlag):
+			key, value, ok := strings.Cut(strings.TrimPrefix(arg, envFlag), "=")
+			if !ok {
+				return s, nil, fmt.Errorf("invalid environment variable %q", arg)
+			}
+			os.Setenv(key, value)
+		default:
+			return s, args, nil
+		}
+		args = args[1:]
+	}
+	return s, args, nil
+}
+
+// The CA bundle whose certificates have been added to the trusted CAs.
+var trustedCABundle string
+
+// Adds the certificates in caBundle to the CAs trusted for registries. crane replaces the TLS
+// configuration of its transport, so they are added where Go reads the system's CAs from, when it
+// first needs them: the directories named by SSL_CERT_DIR, which add to the system's CA file
+// rather than replacing it as SSL_CERT_FILE would.
+func trustCABundle(caBundle string) error {
+	if caBundle == trustedCABundle {
+		return nil
+	}
+	pem, err := os.ReadFile(caBundle)
+	if err != nil {
+		return fmt.Errorf("failed to read CA bundle: %w", err)
+	}
+	if !x509.NewCertPool().AppendCertsFromPEM(pem) {
+		return fmt.Errorf("no certificates found in CA bundle %s", caBundle)
+	}
+	// The bundle is copied to a directory of its own, so that no other file is trusted with it.
+	dir, err := os.MkdirTemp("", "krane-ca-bundle-")
+	if err != nil {
+		return fmt.Errorf("failed to create directory for CA bundle: %w", err)
+	}
+	if err := os.WriteFile(filepath.Join(dir, "ca-bundle.pem"), pem, 0o644); err != nil {
+		return fmt.Errorf("failed to copy CA bundle: %w", err)
+	}
+	dirs := os.Getenv("SSL_CERT_DIR")
+	if dirs == "" {
+		// The directories Go reads on Linux when SSL_CERT_DIR is unset.
+		dirs = "/etc/ssl/certs:/etc/pki/tls/certs"
+	}
+	os.Setenv("SSL_CERT_DIR", dir+":"+dirs)
+	trustedCABundle = caBundle
+	return nil
+}
+
 func kraneMain(args []string, inherited bool, outBuffer *bytes.Buffer, errBuffer *bytes.Buffer) uint {
+	fail := func(err error) uint {
+		if inherited {
+			fmt.Fprintln(os.Stderr, err)
+		} else {
+			fmt.Fprintln(errBuffer, err)
+		}
+		return 1
+	}
+	configured, args, err := parseSettings(args)
+	if err != nil {
+		return fail(err)
+	}
+	if configured.caBundle != "" {
+		if err := trustCABundle(configured.caBundle); err != nil {
+			return fail(err)
+		}
+	}
+
+	// Credentials configured for a registry come before any the host has.
 	keychain := authn.NewMultiKeychain(
+		configured.keychain,
 		authn.DefaultKeychain,
 		google.Keychain,
 		github.Keychain,
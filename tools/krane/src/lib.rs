@@ -1,12 +1,33 @@
 //! Rust interface to the golang FFI bindings for krane.
 use anyhow::{ensure, Context, Result};
 use std::ffi::{c_char, CStr, CString};
-use std::os::unix::process::ExitStatusExt;
 use std::process::ExitStatus;
 use std::ptr;
 
+pub mod settings;
+
 pub type KraneError = anyhow::Error;
 
+/// The environment variable holding the credentials configured for registries, as a JSON object
+/// keyed by registry host. Each names the environment variables which hold the registry's
+/// username and password, as `username-env` and `password-env`, or a docker credential helper,
+/// as `credential-helper`, so that the variable holds no secrets itself.
+pub const REGISTRY_AUTH_ENV: &str = "TWOLITER_REGISTRY_AUTH";
+
+/// The environment variable naming a file of PEM certificates, such as the CA of a proxy which
+/// intercepts TLS, which registries are trusted to present in addition to the system's CAs.
+pub const CA_BUNDLE_ENV: &str = "TWOLITER_CA_BUNDLE";
+
+/// The proxy variables which krane reads, in both the cases Go accepts.
+const PROXY_ENV_VARS: [&str; 6] = [
+    "HTTPS_PROXY",
+    "HTTP_PROXY",
+    "NO_PROXY",
+    "https_proxy",
+    "http_proxy",
+    "no_proxy",
+];
+
 /// Calls the go-containerregistry `krane` binary.
 ///
 /// The function is a thin wrapper around a statically compiled version of the binary which is
@@ -41,7 +62,7 @@ pub fn call_krane(args: &[impl AsRef<str>]) -> Result<std::process::Output> {
     Ok(std::process::Output {
         stdout,
         stderr,
-        status: ExitStatus::from_raw(status_code),
+        status: exit_status(status_code),
     })
 }
 
@@ -59,13 +80,42 @@ pub fn call_krane_inherited_io(args: &[impl AsRef<str>]) -> Result<ExitStatus> {
 
     let status_code = unsafe { extern_krane::krane_inherited_io(argc, argv.as_mut_ptr()) };
 
-    Ok(ExitStatus::from_raw(status_code))
+    Ok(exit_status(status_code))
+}
+
+/// Converts krane's exit code into the platform's `ExitStatus`.
+#[cfg(unix)]
+fn exit_status(status_code: i32) -> ExitStatus {
+    std::os::unix::process::ExitStatusExt::from_raw(status_code)
 }
 
+/// Converts krane's exit code into the platform's `ExitStatus`.
+#[cfg(windows)]
+fn exit_status(status_code: i32) -> ExitStatus {
+    std::os::windows::process::ExitStatusExt::from_raw(status_code as u32)
+}
+
+/// The arguments for krane, after the settings for reaching registries: the registry credentials
+/// and CA bundle if any are configured, and the proxy variables. Krane's environment is fixed when
+/// the process starts, so these are read through `settings` and passed as its first arguments.
 fn c_args(args: &[impl AsRef<str>]) -> Result<Vec<CString>> {
-    args.iter()
+    let var = |name: &str| settings::var(name).ok().filter(|value| !value.is_empty());
+    let settings = var(REGISTRY_AUTH_ENV)
+        .map(|auth| format!("--registry-auth={auth}"))
+        .into_iter()
+        .chain(var(CA_BUNDLE_ENV).map(|path| format!("--ca-bundle={path}")))
+        .chain(
+            PROXY_ENV_VARS
+                .iter()
+                .filter_map(|name| Some(format!("--env={name}={}", var(name)?))),
+        )
+        .collect::<Vec<_>>();
+    settings
+        .iter()
+        .map(String::as_str)
+        .chain(args.iter().map(AsRef::as_ref))
         .map(|arg| {
-            CString::new(arg.as_ref().as_bytes())
+            CString::new(arg.as_bytes())
                 .map_err(|_| anyhow::Error::msg("krane args contained illegal null byte"))
         })
         .collect::<Result<Vec<_>>>()
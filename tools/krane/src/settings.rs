@@ -0,0 +1,91 @@
+//! Settings which a program passes to its registry clients and to the tools it runs, in place of
+//! environment variables it would otherwise set in its own environment.
+//!
+//! Changing a process's environment is not safe once other threads may be reading it, as they do
+//! in an async runtime, so settings are kept here instead. Reads made through this module see the
+//! settings ahead of the environment, and the program passes them to each child process it starts.
+use std::collections::BTreeMap;
+use std::env::VarError;
+use std::ffi::OsString;
+use std::sync::{PoisonError, RwLock};
+
+/// The settings made so far, by variable name. A variable which is set to `None` is unset, whatever
+/// the environment holds.
+static SETTINGS: RwLock<BTreeMap<String, Option<String>>> = RwLock::new(BTreeMap::new());
+
+/// Sets the variable `key` to `value`.
+pub fn set(key: impl Into<String>, value: impl Into<String>) {
+    write(key.into(), Some(value.into()));
+}
+
+/// Unsets the variable `key`, so that it reads as not set even when the environment sets it.
+pub fn unset(key: impl Into<String>) {
+    write(key.into(), None);
+}
+
+/// Reads the variable `key`, as `std::env::var` does.
+pub fn var(key: impl AsRef<str>) -> Result<String, VarError> {
+    let key = key.as_ref();
+    match read(key) {
+        Some(Some(value)) => Ok(value),
+        Some(None) => Err(VarError::NotPresent),
+        None => std::env::var(key),
+    }
+}
+
+/// Reads the variable `key`, as `std::env::var_os` does.
+pub fn var_os(key: impl AsRef<str>) -> Option<OsString> {
+    let key = key.as_ref();
+    match read(key) {
+        Some(value) => value.map(OsString::from),
+        None => std::env::var_os(key),
+    }
+}
+
+/// The variables of the environment with the settings applied, as `std::env::vars` lists them.
+pub fn vars() -> Vec<(String, String)> {
+    let mut vars = std::env::vars().collect::<BTreeMap<_, _>>();
+    for (key, value) in overrides() {
+        match value {
+            Some(value) => vars.insert(key, value),
+            None => vars.remove(&key),
+        };
+    }
+    vars.into_iter().collect()
+}
+
+/// The settings made so far, which a child process must be started with: each variable to set, or
+/// to remove when its value is `None`.
+pub fn overrides() -> Vec<(String, Option<String>)> {
+    SETTINGS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// Applies the settings to a `command` which is yet to start.
+pub fn apply(command: &mut std::process::Command) {
+    for (key, value) in overrides() {
+        match value {
+            Some(value) => command.env(key, value),
+            None => command.env_remove(key),
+        };
+    }
+}
+
+fn read(key: &str) -> Option<Option<String>> {
+    SETTINGS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(key)
+        .cloned()
+}
+
+fn write(key: String, value: Option<String>) {
+    SETTINGS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(key, value);
+}
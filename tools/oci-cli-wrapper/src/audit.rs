@@ -0,0 +1,166 @@
+//! An append-only log of the changes made to container registries, for change control of release
+//! tooling.
+//!
+//! When `TWOLITER_AUDIT_LOG` names a file, every push, tag, mutation, deletion and signature made
+//! through an [`ImageTool`](crate::ImageTool) is appended to it as a line of JSON, recording when
+//! it happened, who made it from which host, what it targeted, the digest the target was left
+//! pointing at, and whether it succeeded. Attempts which fail are recorded too. The user is taken
+//! from `TWOLITER_AUDIT_USER`, e.g. the identity a CI job acts for, or else from `USER`.
+//!
+//! When `TWOLITER_AUDIT_SINK` is set, each entry is also written to the standard input of that
+//! shell command, e.g. `curl --fail --data-binary @- https://audit.example.com/twoliter`, to send
+//! it to a remote collector. The local log is the record of truth: an entry which cannot be
+//! written to it fails the operation's caller, while a failing sink is only logged.
+use crate::error::{self, Result};
+use krane_static::settings;
+use log::warn;
+use serde::Serialize;
+use snafu::ResultExt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// The environment variable naming the audit log.
+pub const AUDIT_LOG_ENV: &str = "TWOLITER_AUDIT_LOG";
+
+/// The environment variable holding a command which each entry is also sent to.
+pub const AUDIT_SINK_ENV: &str = "TWOLITER_AUDIT_SINK";
+
+/// The environment variable naming who the changes are made by, overriding `USER`.
+pub const AUDIT_USER_ENV: &str = "TWOLITER_AUDIT_USER";
+
+/// A kind of change to a registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    /// Pushing an image.
+    Push,
+    /// Pushing a manifest list.
+    PushIndex,
+    /// Pointing a tag at an image.
+    Tag,
+    /// Changing the annotations or labels of an image, which re-pushes it.
+    Mutate,
+    /// Deleting an image or tag.
+    Delete,
+    /// Signing an image.
+    Sign,
+}
+
+/// One change to a registry.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuditEntry {
+    /// When the change was made, in RFC 3339 format.
+    pub time: String,
+    pub user: String,
+    pub host: String,
+    pub operation: Operation,
+    /// The image or tag that was changed.
+    pub target: String,
+    /// The image the change was made from, e.g. the image a tag was pointed at.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The digest the target pointed at after the change.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    /// An entry for `operation` on `target`, made now by the current user.
+    pub fn new(operation: Operation, target: impl Into<String>) -> Self {
+        Self {
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            user: settings::var(AUDIT_USER_ENV)
+                .or_else(|_| settings::var("USER"))
+                .or_else(|_| settings::var("USERNAME"))
+                .unwrap_or_else(|_| "unknown".to_string()),
+            host: hostname(),
+            operation,
+            target: target.into(),
+            source: None,
+            digest: None,
+            success: true,
+            error: None,
+        }
+    }
+}
+
+/// Where changes to registries are recorded.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    sink: Option<String>,
+}
+
+impl AuditLog {
+    /// The audit log configured by `TWOLITER_AUDIT_LOG`, if any.
+    pub fn from_env() -> Option<Self> {
+        let path = settings::var_os(AUDIT_LOG_ENV).filter(|path| !path.is_empty())?;
+        Some(Self {
+            path: PathBuf::from(path),
+            sink: settings::var(AUDIT_SINK_ENV)
+                .ok()
+                .filter(|sink| !sink.trim().is_empty()),
+        })
+    }
+
+    /// Appends `entry` to the log, and sends it to the sink.
+    pub async fn record(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry).context(error::AuditSerializeSnafu)?;
+        line.push(b'\n');
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).context(error::AuditWriteSnafu { path: parent })?;
+        }
+        // Opening in append mode writes each line in one piece, even with several writers.
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o640);
+        options
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&line))
+            .context(error::AuditWriteSnafu { path: &self.path })?;
+
+        if let Some(sink) = &self.sink {
+            if let Err(e) = send(sink, &line).await {
+                warn!("Failed to send audit entry to '{sink}': {e}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `line` to the standard input of the shell command `sink`.
+async fn send(sink: &str, line: &[u8]) -> std::io::Result<()> {
+    let mut command = Command::new("sh");
+    settings::apply(command.as_std_mut());
+    let mut child = command
+        .args(["-c", sink])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(line).await?;
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("exited with {status}")));
+    }
+    Ok(())
+}
+
+fn hostname() -> String {
+    settings::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
@@ -0,0 +1,87 @@
+//! The container tool which runs builds and SDK containers, chosen by setting
+//! `TWOLITER_CONTAINER_TOOL` to `docker`, the default, `podman` or `finch`. Each is called by the
+//! name of its command, with the same arguments, since podman and finch accept docker's. The tool
+//! is parsed by `buildsys_config`, so that buildsys reads the variable the same way.
+//!
+//! Registries are reached without the container tool, but with its credentials. The in-process
+//! registry client uses those podman keeps in its `auth.json`, or finch in `~/.finch/config.json`,
+//! before docker's. `krane` reads podman's when there is no docker configuration, and finch's when
+//! `DOCKER_CONFIG` is set to `~/.finch`.
+use base64::Engine;
+use krane_static::settings;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+pub use buildsys_config::container_tool::{
+    ContainerTool, UnsupportedContainerTool, CONTAINER_TOOL_ENV,
+};
+
+/// The names Docker Hub goes by in credential files.
+const DOCKER_HUB: [&str; 3] = ["docker.io", "index.docker.io", "registry-1.docker.io"];
+
+/// The file where `tool` keeps registry credentials, if not in docker's configuration and if it
+/// exists.
+fn auth_file(tool: ContainerTool) -> Option<PathBuf> {
+    let home = settings::var_os("HOME").map(PathBuf::from);
+    let candidates = match tool {
+        ContainerTool::Docker => Vec::new(),
+        ContainerTool::Podman => vec![
+            settings::var_os("REGISTRY_AUTH_FILE").map(PathBuf::from),
+            settings::var_os("XDG_RUNTIME_DIR")
+                .map(|dir| PathBuf::from(dir).join("containers/auth.json")),
+            home.as_ref()
+                .map(|home| home.join(".config/containers/auth.json")),
+        ],
+        ContainerTool::Finch => vec![home.map(|home| home.join(".finch/config.json"))],
+    };
+    candidates.into_iter().flatten().find(|path| path.is_file())
+}
+
+/// The username and password `tool` keeps for `registry`, if any.
+pub fn credentials(tool: ContainerTool, registry: &str) -> Option<(String, String)> {
+    let path = auth_file(tool)?;
+    let auth_file = std::fs::read(&path)
+        .ok()
+        .and_then(|auth_file| serde_json::from_slice::<AuthFile>(&auth_file).ok());
+    let Some(auth_file) = auth_file else {
+        log::debug!("Ignoring '{}', which cannot be read", path.display());
+        return None;
+    };
+    let auth = auth_file
+        .auths
+        .iter()
+        .find(|(key, _)| same_registry(key, registry))
+        .and_then(|(_, entry)| entry.auth.as_deref())?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(auth.trim())
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Whether `key`, the registry of an entry in a credential file, names `registry`. Keys may be
+/// URLs, e.g. `https://index.docker.io/v1/`.
+fn same_registry(key: &str, registry: &str) -> bool {
+    let host = key
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    host == registry || (DOCKER_HUB.contains(&host) && DOCKER_HUB.contains(&registry))
+}
+
+/// The registry credentials in a podman or finch credential file.
+#[derive(Debug, Default, Deserialize)]
+struct AuthFile {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AuthEntry {
+    /// The username and password, separated by a colon, in base64.
+    auth: Option<String>,
+}
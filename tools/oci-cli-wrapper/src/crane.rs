@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::fs::File;
 use std::path::Path;
@@ -10,7 +11,7 @@ use tempfile::TempDir;
 
 use crate::{error, ConfigView, DockerArchitecture, ImageToolImpl, ImageView, Result};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CraneCLI;
 
 impl CraneCLI {
@@ -117,6 +118,41 @@ impl ImageToolImpl for CraneCLI {
         .await
     }
 
+    async fn get_digest(&self, uri: &str) -> Result<String> {
+        let bytes = Self::output(
+            &["digest", uri],
+            &format!("failed to fetch digest of {}", uri),
+        )
+        .await?;
+        Ok(String::from_utf8_lossy(&bytes).trim().to_string())
+    }
+
+    async fn tag(&self, uri: &str, tag: &str) -> Result<()> {
+        Self::call(
+            &["tag", uri, tag],
+            &format!("failed to tag image {} as {}", uri, tag),
+        )
+        .await
+    }
+
+    async fn list_repositories(&self, registry: &str) -> Result<Vec<String>> {
+        let bytes = Self::output(
+            &["catalog", registry],
+            &format!("failed to list repositories in {}", registry),
+        )
+        .await?;
+        Ok(lines(&bytes))
+    }
+
+    async fn list_tags(&self, repository: &str) -> Result<Vec<String>> {
+        let bytes = Self::output(
+            &["ls", repository],
+            &format!("failed to list tags of {}", repository),
+        )
+        .await?;
+        Ok(lines(&bytes))
+    }
+
     async fn get_config(&self, uri: &str) -> Result<ConfigView> {
         let bytes = Self::output(
             &["config", uri],
@@ -125,7 +161,7 @@ impl ImageToolImpl for CraneCLI {
         .await?;
         let image_view: ImageView =
             serde_json::from_slice(bytes.as_slice()).context(error::ConfigDeserializeSnafu)?;
-        Ok(image_view.config)
+        Ok(image_view.into())
     }
 
     async fn push_oci_archive(&self, path: &Path, uri: &str) -> Result<()> {
@@ -144,6 +180,26 @@ impl ImageToolImpl for CraneCLI {
         .await
     }
 
+    async fn mutate(
+        &self,
+        uri: &str,
+        annotations: &BTreeMap<String, String>,
+        labels: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        let annotations = annotations
+            .iter()
+            .flat_map(|(key, value)| ["--annotation".to_string(), format!("{key}={value}")]);
+        let labels = labels
+            .iter()
+            .flat_map(|(key, value)| ["--label".to_string(), format!("{key}={value}")]);
+        let mut args = vec!["mutate".to_string(), uri.to_string()];
+        args.extend(annotations);
+        args.extend(labels);
+        args.extend(["--tag".to_string(), uri.to_string()]);
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        Self::call(&args, &format!("failed to mutate image {}", uri)).await
+    }
+
     async fn push_multi_platform_manifest(
         &self,
         platform_images: Vec<(DockerArchitecture, String)>,
@@ -166,3 +222,13 @@ impl ImageToolImpl for CraneCLI {
         .await
     }
 }
+
+/// Splits the output of a listing command into its non-empty lines.
+fn lines(bytes: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
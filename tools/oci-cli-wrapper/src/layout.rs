@@ -0,0 +1,487 @@
+//! Serves images from a directory of OCI image layouts instead of a registry, for hosts which
+//! cannot reach one.
+//!
+//! Each repository is an OCI image layout at `<registry>/<repository>` within the directory, with
+//! its tags given by the `org.opencontainers.image.ref.name` annotation of the entries in its
+//! `index.json`. This is how `skopeo` writes them, so a directory can be populated with e.g.
+//!
+//! ```text
+//! skopeo copy --all docker://public.ecr.aws/bottlerocket/bottlerocket-core-kit:v2.0.0 \
+//!     oci:oci-dir/public.ecr.aws/bottlerocket/bottlerocket-core-kit:v2.0.0
+//! ```
+//!
+//! Images are only ever read as a registry: anything which would change one fails. `twoliter
+//! vendor` writes images to a directory with [`OciLayoutDir::store`].
+use crate::error::{self, Result};
+use crate::{ConfigView, DockerArchitecture, ImageToolImpl, ImageView};
+use async_trait::async_trait;
+use krane_static::settings;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// The environment variable naming a directory of OCI image layouts to serve images from.
+pub const OCI_DIR_ENV: &str = "TWOLITER_OCI_DIR";
+
+/// The annotation naming the tag of an entry in an OCI layout's `index.json`.
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+const OCI_LAYOUT_FILE: &str = "oci-layout";
+const INDEX_FILE: &str = "index.json";
+const INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+
+/// The directory of OCI layouts named by `TWOLITER_OCI_DIR`, if it is set.
+pub fn oci_dir_from_env() -> Option<PathBuf> {
+    settings::var_os(OCI_DIR_ENV)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Descriptor {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) media_type: Option<String>,
+    pub(crate) digest: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) size: Option<u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndexView {
+    manifests: Vec<Descriptor>,
+}
+
+/// The descriptors a manifest or manifest list refers to.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ReferencesView {
+    #[serde(default)]
+    pub(crate) media_type: Option<String>,
+    #[serde(default)]
+    pub(crate) config: Option<Descriptor>,
+    #[serde(default)]
+    pub(crate) layers: Vec<Descriptor>,
+    #[serde(default)]
+    pub(crate) manifests: Vec<Descriptor>,
+}
+
+/// What a uri refers to within a repository.
+#[derive(Debug, PartialEq, Eq)]
+enum Reference<'a> {
+    Tag(&'a str),
+    Digest(&'a str),
+}
+
+/// Splits `uri` into its repository, including the registry, and its tag or digest.
+fn parse_uri(uri: &str) -> (&str, Reference<'_>) {
+    if let Some((repository, digest)) = uri.split_once('@') {
+        // A tag alongside the digest is ignored, as registries do.
+        let name_start = repository.rfind('/').map_or(0, |slash| slash + 1);
+        let repository = match repository[name_start..].rfind(':') {
+            Some(colon) => &repository[..name_start + colon],
+            None => repository,
+        };
+        return (repository, Reference::Digest(digest));
+    }
+    // A colon after the last slash separates the tag, while one before it is a registry port.
+    let name_start = uri.rfind('/').map_or(0, |slash| slash + 1);
+    match uri[name_start..].rfind(':') {
+        Some(colon) => (
+            &uri[..name_start + colon],
+            Reference::Tag(&uri[name_start + colon + 1..]),
+        ),
+        None => (uri, Reference::Tag("latest")),
+    }
+}
+
+/// Writes the index of the OCI layout at `layout`, listing `manifests`, and marks the directory as
+/// a layout. The blobs must already be in place.
+pub(crate) fn write_index(layout: &Path, manifests: &[Descriptor]) -> Result<()> {
+    let index = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": INDEX_MEDIA_TYPE,
+        "manifests": manifests,
+    });
+    let index_path = layout.join(INDEX_FILE);
+    std::fs::write(&index_path, index.to_string())
+        .context(error::LayoutWriteSnafu { path: &index_path })?;
+    let layout_path = layout.join(OCI_LAYOUT_FILE);
+    std::fs::write(&layout_path, r#"{"imageLayoutVersion":"1.0.0"}"#)
+        .context(error::LayoutWriteSnafu { path: &layout_path })
+}
+
+/// A directory of OCI image layouts, one for each repository.
+#[derive(Debug)]
+pub struct OciLayoutDir {
+    root: PathBuf,
+}
+
+impl OciLayoutDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// The layout holding `repository`.
+    fn layout(&self, repository: &str) -> PathBuf {
+        self.root.join(repository)
+    }
+
+    fn read(uri: &str, path: &Path) -> Result<Vec<u8>> {
+        ensure!(
+            path.exists(),
+            error::LayoutMissingSnafu {
+                uri,
+                path: path.to_path_buf()
+            }
+        );
+        std::fs::read(path).context(error::LayoutReadSnafu { path })
+    }
+
+    fn read_json<T: for<'de> Deserialize<'de>>(uri: &str, path: &Path) -> Result<T> {
+        serde_json::from_slice(&Self::read(uri, path)?)
+            .context(error::LayoutDeserializeSnafu { path })
+    }
+
+    /// The path of the blob with `digest` in `layout`.
+    pub(crate) fn blob_path(layout: &Path, digest: &str) -> Result<PathBuf> {
+        let (algorithm, encoded) = digest
+            .split_once(':')
+            .context(error::InvalidDigestSnafu { digest })?;
+        ensure!(
+            [algorithm, encoded]
+                .iter()
+                .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric())),
+            error::InvalidDigestSnafu { digest }
+        );
+        Ok(layout.join("blobs").join(algorithm).join(encoded))
+    }
+
+    /// Finds the layout and digest of the manifest or manifest list at `uri`.
+    fn resolve(&self, uri: &str) -> Result<(PathBuf, String)> {
+        let (repository, reference) = parse_uri(uri);
+        let layout = self.layout(repository);
+        let digest = match reference {
+            Reference::Digest(digest) => digest.to_string(),
+            Reference::Tag(tag) => {
+                let index: IndexView = Self::read_json(uri, &layout.join(INDEX_FILE))?;
+                index
+                    .manifests
+                    .into_iter()
+                    .find(|descriptor| {
+                        descriptor
+                            .annotations
+                            .get(REF_NAME_ANNOTATION)
+                            .is_some_and(|name| name == tag)
+                    })
+                    .context(error::LayoutMissingSnafu {
+                        uri,
+                        path: layout.join(INDEX_FILE),
+                    })?
+                    .digest
+            }
+        };
+        Ok((layout, digest))
+    }
+
+    /// Copies the blob with `digest` from `layout` into `target`.
+    fn copy_blob(uri: &str, layout: &Path, target: &Path, digest: &str) -> Result<()> {
+        let source = Self::blob_path(layout, digest)?;
+        ensure!(
+            source.exists(),
+            error::LayoutMissingSnafu { uri, path: &source }
+        );
+        let destination = Self::blob_path(target, digest)?;
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent).context(error::LayoutWriteSnafu { path: parent })?;
+        }
+        std::fs::copy(&source, &destination)
+            .context(error::LayoutWriteSnafu { path: &destination })?;
+        Ok(())
+    }
+
+    /// Copies the manifest or manifest list with `digest`, and every blob it refers to, from
+    /// `layout` into `target`. Returns the size of the manifest and its media type, if it declares
+    /// one.
+    fn copy_manifest(
+        uri: &str,
+        layout: &Path,
+        target: &Path,
+        digest: &str,
+    ) -> Result<(u64, Option<String>)> {
+        let path = Self::blob_path(layout, digest)?;
+        let bytes = Self::read(uri, &path)?;
+        let references: ReferencesView =
+            serde_json::from_slice(&bytes).context(error::LayoutDeserializeSnafu { path })?;
+        Self::copy_blob(uri, layout, target, digest)?;
+        for descriptor in references.config.iter().chain(references.layers.iter()) {
+            Self::copy_blob(uri, layout, target, &descriptor.digest)?;
+        }
+        for descriptor in &references.manifests {
+            Self::copy_manifest(uri, layout, target, &descriptor.digest)?;
+        }
+        Ok((bytes.len() as u64, references.media_type))
+    }
+
+    /// Writes an OCI layout to `path` holding only the image at `uri`.
+    fn pull(&self, path: &Path, uri: &str) -> Result<()> {
+        let (layout, digest) = self.resolve(uri)?;
+        let (size, media_type) = Self::copy_manifest(uri, &layout, path, &digest)?;
+        write_index(
+            path,
+            &[Descriptor {
+                media_type,
+                digest,
+                size: Some(size),
+                annotations: BTreeMap::new(),
+            }],
+        )
+    }
+
+    /// Checks that the blob with `digest` in `layout` exists and has not changed.
+    fn verify_blob(uri: &str, layout: &Path, digest: &str) -> Result<()> {
+        let path = Self::blob_path(layout, digest)?;
+        ensure!(
+            path.exists(),
+            error::LayoutMissingSnafu { uri, path: &path }
+        );
+        let (algorithm, encoded) = digest
+            .split_once(':')
+            .context(error::InvalidDigestSnafu { digest })?;
+        ensure!(algorithm == "sha256", error::InvalidDigestSnafu { digest });
+        let mut file = File::open(&path).context(error::LayoutReadSnafu { path: &path })?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher).context(error::LayoutReadSnafu { path: &path })?;
+        ensure!(
+            format!("{:x}", hasher.finalize()) == encoded,
+            error::LayoutCorruptSnafu { uri, path }
+        );
+        Ok(())
+    }
+
+    /// Checks the manifest or manifest list with `digest` in `layout`, and every blob it refers
+    /// to, against their digests.
+    fn verify_manifest(uri: &str, layout: &Path, digest: &str) -> Result<()> {
+        Self::verify_blob(uri, layout, digest)?;
+        let path = Self::blob_path(layout, digest)?;
+        let references: ReferencesView = Self::read_json(uri, &path)?;
+        for descriptor in references.config.iter().chain(references.layers.iter()) {
+            Self::verify_blob(uri, layout, &descriptor.digest)?;
+        }
+        for descriptor in &references.manifests {
+            Self::verify_manifest(uri, layout, &descriptor.digest)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the digests of the manifest or manifest list with `digest` in `layout`, and of every
+    /// blob it refers to, to `found`. Manifests which cannot be read are skipped.
+    fn reachable(layout: &Path, digest: &str, found: &mut BTreeSet<String>) {
+        found.insert(digest.to_string());
+        let Ok(path) = Self::blob_path(layout, digest) else {
+            return;
+        };
+        let Some(references) = std::fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<ReferencesView>(&bytes).ok())
+        else {
+            return;
+        };
+        for descriptor in references.config.iter().chain(references.layers.iter()) {
+            found.insert(descriptor.digest.clone());
+        }
+        for descriptor in &references.manifests {
+            Self::reachable(layout, &descriptor.digest, found);
+        }
+    }
+
+    /// Removes the blobs in `layout` which none of `manifests` refer to.
+    fn prune(layout: &Path, manifests: &[Descriptor]) -> Result<()> {
+        let mut found = BTreeSet::new();
+        for descriptor in manifests {
+            Self::reachable(layout, &descriptor.digest, &mut found);
+        }
+        let blobs = layout.join("blobs");
+        let algorithms =
+            std::fs::read_dir(&blobs).context(error::LayoutReadSnafu { path: &blobs })?;
+        for algorithm in algorithms {
+            let algorithm = algorithm.context(error::LayoutReadSnafu { path: &blobs })?;
+            let dir = algorithm.path();
+            let entries = std::fs::read_dir(&dir).context(error::LayoutReadSnafu { path: &dir })?;
+            for entry in entries {
+                let path = entry.context(error::LayoutReadSnafu { path: &dir })?.path();
+                let digest = format!(
+                    "{}:{}",
+                    algorithm.file_name().to_string_lossy(),
+                    path.file_name().unwrap_or_default().to_string_lossy()
+                );
+                if !found.contains(&digest) {
+                    std::fs::remove_file(&path).context(error::LayoutWriteSnafu { path: &path })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the image at `uri` is complete and that none of its blobs have changed, and
+    /// returns its manifest or manifest list.
+    pub fn verify(&self, uri: &str) -> Result<Vec<u8>> {
+        let (layout, digest) = self.resolve(uri)?;
+        Self::verify_manifest(uri, &layout, &digest)?;
+        Self::read(uri, &Self::blob_path(&layout, &digest)?)
+    }
+
+    /// Copies the manifest or manifest list with `digest` in the repository of `uri` in `source`,
+    /// and every blob it refers to, into this directory, tagged with the tag of `uri`. An image
+    /// which had the same tag is replaced, and blobs which no image in the repository refers to
+    /// any longer are removed.
+    pub fn store(&self, source: &OciLayoutDir, uri: &str, digest: &str) -> Result<()> {
+        let (repository, reference) = parse_uri(uri);
+        let Reference::Tag(tag) = reference else {
+            return error::LayoutUntaggedSnafu { uri }.fail();
+        };
+        let layout = self.layout(repository);
+        let (size, media_type) =
+            Self::copy_manifest(uri, &source.layout(repository), &layout, digest)?;
+
+        let index_path = layout.join(INDEX_FILE);
+        let mut manifests = if index_path.exists() {
+            Self::read_json::<IndexView>(uri, &index_path)?.manifests
+        } else {
+            Vec::new()
+        };
+        manifests.retain(|descriptor| {
+            descriptor
+                .annotations
+                .get(REF_NAME_ANNOTATION)
+                .map(String::as_str)
+                != Some(tag)
+        });
+        manifests.push(Descriptor {
+            media_type,
+            digest: digest.to_string(),
+            size: Some(size),
+            annotations: BTreeMap::from([(REF_NAME_ANNOTATION.to_string(), tag.to_string())]),
+        });
+        write_index(&layout, &manifests)?;
+        Self::prune(&layout, &manifests)
+    }
+
+    /// The repositories below `dir`, named relative to `base`.
+    fn repositories(base: &Path, dir: &Path, repositories: &mut Vec<String>) -> Result<()> {
+        if dir.join(INDEX_FILE).is_file() {
+            if let Ok(relative) = dir.strip_prefix(base) {
+                repositories.push(relative.to_string_lossy().to_string());
+            }
+            return Ok(());
+        }
+        let entries = std::fs::read_dir(dir).context(error::LayoutReadSnafu { path: dir })?;
+        for entry in entries {
+            let entry = entry.context(error::LayoutReadSnafu { path: dir })?;
+            if entry.path().is_dir() {
+                Self::repositories(base, &entry.path(), repositories)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ImageToolImpl for OciLayoutDir {
+    async fn pull_oci_image(&self, path: &Path, uri: &str) -> Result<()> {
+        // Copying the layers can take a while, so it is kept off the async runtime's threads.
+        let layout_dir = Self::new(self.root.clone());
+        let (path, uri) = (path.to_path_buf(), uri.to_string());
+        tokio::task::spawn_blocking(move || layout_dir.pull(&path, &uri))
+            .await
+            .context(error::ForkSnafu)?
+    }
+
+    async fn get_config(&self, uri: &str) -> Result<ConfigView> {
+        let (layout, digest) = self.resolve(uri)?;
+        let manifest: ReferencesView = Self::read_json(uri, &Self::blob_path(&layout, &digest)?)?;
+        let config = manifest.config.context(error::LayoutMissingSnafu {
+            uri,
+            path: Self::blob_path(&layout, &digest)?,
+        })?;
+        let image_view: ImageView =
+            Self::read_json(uri, &Self::blob_path(&layout, &config.digest)?)?;
+        Ok(image_view.into())
+    }
+
+    async fn get_manifest(&self, uri: &str) -> Result<Vec<u8>> {
+        let (layout, digest) = self.resolve(uri)?;
+        Self::read(uri, &Self::blob_path(&layout, &digest)?)
+    }
+
+    async fn get_manifest_with_digest(&self, uri: &str) -> Result<(Vec<u8>, String)> {
+        let (layout, digest) = self.resolve(uri)?;
+        let manifest = Self::read(uri, &Self::blob_path(&layout, &digest)?)?;
+        Ok((manifest, digest))
+    }
+
+    async fn get_digest(&self, uri: &str) -> Result<String> {
+        let (layout, digest) = self.resolve(uri)?;
+        let path = Self::blob_path(&layout, &digest)?;
+        ensure!(path.exists(), error::LayoutMissingSnafu { uri, path });
+        Ok(digest)
+    }
+
+    async fn tag(&self, _uri: &str, _tag: &str) -> Result<()> {
+        error::OfflineSnafu { operation: "tag" }.fail()
+    }
+
+    async fn list_repositories(&self, registry: &str) -> Result<Vec<String>> {
+        let base = self.root.join(registry);
+        let mut repositories = Vec::new();
+        if base.is_dir() {
+            Self::repositories(&base, &base, &mut repositories)?;
+        }
+        repositories.sort();
+        Ok(repositories)
+    }
+
+    async fn list_tags(&self, repository: &str) -> Result<Vec<String>> {
+        let layout = self.layout(repository);
+        let index: IndexView = Self::read_json(repository, &layout.join(INDEX_FILE))?;
+        let mut tags = index
+            .manifests
+            .into_iter()
+            .filter_map(|mut descriptor| descriptor.annotations.remove(REF_NAME_ANNOTATION))
+            .collect::<Vec<_>>();
+        tags.sort();
+        Ok(tags)
+    }
+
+    async fn push_oci_archive(&self, _path: &Path, _uri: &str) -> Result<()> {
+        error::OfflineSnafu { operation: "push" }.fail()
+    }
+
+    async fn mutate(
+        &self,
+        _uri: &str,
+        _annotations: &BTreeMap<String, String>,
+        _labels: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        error::OfflineSnafu {
+            operation: "mutate",
+        }
+        .fail()
+    }
+
+    async fn push_multi_platform_manifest(
+        &self,
+        _platform_images: Vec<(DockerArchitecture, String)>,
+        _uri: &str,
+    ) -> Result<()> {
+        error::OfflineSnafu {
+            operation: "push a manifest list",
+        }
+        .fail()
+    }
+}
@@ -11,21 +11,45 @@
 //!     crane. The image needs to be pulled locally in order for docker to inspect the manifest and extract
 //!     metadata. In addition, in order to operate with OCI image format, the containerd-snapshotter
 //!     feature has to be enabled in the docker daemon
+//!
+//! Images can also be read from a registry in-process, without krane, by setting
+//! `TWOLITER_IMAGE_TOOL=native`. See [`registry`].
+//!
+//! Builds and SDK containers run with docker, or with podman or finch when
+//! `TWOLITER_CONTAINER_TOOL` names one. See [`container_tool`].
+//!
+//! Credentials for private registries can be configured in `TWOLITER_REGISTRY_AUTH`, ahead of
+//! those the host has. See [`registry_auth`].
+//!
+//! Images can also be read from a local directory of OCI image layouts instead of a registry, for
+//! hosts which cannot reach one. See [`layout`].
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::{collections::HashMap, path::Path};
 
 use async_trait::async_trait;
+use audit::{AuditEntry, AuditLog, Operation};
 use crane::CraneCLI;
+use krane_static::settings;
+use layout::{oci_dir_from_env, OciLayoutDir};
 use olpc_cjson::CanonicalFormatter;
+use registry::{RegistryClient, IMAGE_TOOL_ENV};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use snafu::ResultExt;
 
+pub mod audit;
+pub mod container_tool;
 mod crane;
+pub mod layout;
+pub mod registry;
+pub mod registry_auth;
 
 #[derive(Debug, Clone)]
 pub struct ImageTool {
     image_tool_impl: Arc<dyn ImageToolImpl>,
+    audit: Option<Arc<AuditLog>>,
 }
 
 impl ImageTool {
@@ -34,8 +58,46 @@ impl ImageTool {
         Self::new(Arc::new(CraneCLI))
     }
 
+    /// Creates a new `ImageTool` which reads images from the OCI layouts in `root` rather than a
+    /// registry.
+    pub fn oci_layout_dir(root: impl Into<PathBuf>) -> Self {
+        Self::new(Arc::new(OciLayoutDir::new(root)))
+    }
+
+    /// Creates a new `ImageTool` which reads images from registries with an in-process client,
+    /// and changes them with a statically linked `krane`.
+    pub fn native() -> Self {
+        Self::new(Arc::new(RegistryClient::new()))
+    }
+
+    /// Creates the `ImageTool` for reaching registries chosen by `TWOLITER_IMAGE_TOOL`: `native`
+    /// for the in-process client, or else a statically linked `krane`.
+    pub fn registry() -> Self {
+        match settings::var(IMAGE_TOOL_ENV).as_deref() {
+            Ok("native") => Self::native(),
+            Ok("krane") | Ok("") | Err(_) => Self::krane(),
+            Ok(name) => {
+                log::warn!("{}", error::Error::Unsupported { name: name.into() });
+                Self::krane()
+            }
+        }
+    }
+
+    /// Creates the `ImageTool` for reading images: one which reads the OCI layouts in the
+    /// directory named by `TWOLITER_OCI_DIR` if it is set, or else the one chosen by
+    /// `TWOLITER_IMAGE_TOOL`.
+    pub fn from_env() -> Self {
+        match oci_dir_from_env() {
+            Some(root) => Self::oci_layout_dir(root),
+            None => Self::registry(),
+        }
+    }
+
     pub fn new(image_tool_impl: Arc<dyn ImageToolImpl>) -> Self {
-        Self { image_tool_impl }
+        Self {
+            image_tool_impl,
+            audit: AuditLog::from_env().map(Arc::new),
+        }
     }
 
     /// Pull an image archive to disk
@@ -51,25 +113,56 @@ impl ImageTool {
     /// Fetch the manifest
     pub async fn get_manifest(&self, uri: &str) -> Result<Vec<u8>> {
         let manifest_bytes = self.image_tool_impl.get_manifest(uri).await?;
-        let manifest_object: serde_json::Value =
-            serde_json::from_slice(&manifest_bytes).context(error::ManifestDeserializeSnafu)?;
+        canonicalize_manifest(&manifest_bytes)
+    }
 
-        let mut canonicalized_manifest = Vec::new();
-        let mut ser = serde_json::Serializer::with_formatter(
-            &mut canonicalized_manifest,
-            CanonicalFormatter::new(),
-        );
+    /// Fetch the manifest along with its registry digest, in a single request. The digest is of
+    /// the bytes the registry serves, which the canonicalized manifest need not match.
+    pub async fn get_manifest_with_digest(&self, uri: &str) -> Result<(Vec<u8>, String)> {
+        let (manifest_bytes, digest) = self.image_tool_impl.get_manifest_with_digest(uri).await?;
+        Ok((canonicalize_manifest(&manifest_bytes)?, digest))
+    }
 
-        manifest_object
-            .serialize(&mut ser)
-            .context(error::ManifestCanonicalizeSnafu)?;
+    /// Fetch the digest of the manifest or manifest list at a uri
+    pub async fn get_digest(&self, uri: &str) -> Result<String> {
+        self.image_tool_impl.get_digest(uri).await
+    }
 
-        Ok(canonicalized_manifest)
+    /// Add a tag to the image at a uri, in the same repository
+    pub async fn tag(&self, uri: &str, tag: &str) -> Result<()> {
+        let result = self.image_tool_impl.tag(uri, tag).await;
+        self.record_mutation(Operation::Tag, &tag_target(uri, tag), Some(uri), result)
+            .await
+    }
+
+    /// List the repositories in a registry, for registries which support listing them
+    pub async fn list_repositories(&self, registry: &str) -> Result<Vec<String>> {
+        self.image_tool_impl.list_repositories(registry).await
+    }
+
+    /// List the tags of a repository
+    pub async fn list_tags(&self, repository: &str) -> Result<Vec<String>> {
+        self.image_tool_impl.list_tags(repository).await
     }
 
     /// Push a single-arch image in oci archive format
     pub async fn push_oci_archive(&self, path: &Path, uri: &str) -> Result<()> {
-        self.image_tool_impl.push_oci_archive(path, uri).await
+        let result = self.image_tool_impl.push_oci_archive(path, uri).await;
+        self.record_mutation(Operation::Push, uri, None, result)
+            .await
+    }
+
+    /// Add annotations to the manifest and labels to the config of an image in the registry,
+    /// re-pushing it to the same tag
+    pub async fn mutate(
+        &self,
+        uri: &str,
+        annotations: &BTreeMap<String, String>,
+        labels: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        let result = self.image_tool_impl.mutate(uri, annotations, labels).await;
+        self.record_mutation(Operation::Mutate, uri, None, result)
+            .await
     }
 
     /// Push the multi-arch kit manifest list
@@ -78,10 +171,71 @@ impl ImageTool {
         platform_images: Vec<(DockerArchitecture, String)>,
         uri: &str,
     ) -> Result<()> {
-        self.image_tool_impl
+        let result = self
+            .image_tool_impl
             .push_multi_platform_manifest(platform_images, uri)
+            .await;
+        self.record_mutation(Operation::PushIndex, uri, None, result)
             .await
     }
+
+    /// Records the `result` of a change to the registry in the audit log, if there is one. Tools
+    /// which change the registry without going through `ImageTool`, e.g. to sign images, call this
+    /// so that their changes are recorded too. The error of a failed change is returned before
+    /// any error writing the log.
+    pub async fn record_mutation(
+        &self,
+        operation: Operation,
+        target: &str,
+        source: Option<&str>,
+        result: Result<()>,
+    ) -> Result<()> {
+        let Some(audit) = &self.audit else {
+            return result;
+        };
+        let mut entry = AuditEntry::new(operation, target);
+        entry.source = source.map(str::to_string);
+        match &result {
+            Ok(()) => entry.digest = self.image_tool_impl.get_digest(target).await.ok(),
+            Err(e) => {
+                entry.success = false;
+                entry.error = Some(e.to_string());
+            }
+        }
+        let recorded = audit.record(&entry).await;
+        result.and(recorded)
+    }
+}
+
+/// Re-serializes a manifest in canonical JSON, so that it can be compared byte for byte.
+fn canonicalize_manifest(manifest_bytes: &[u8]) -> Result<Vec<u8>> {
+    let manifest_object: serde_json::Value =
+        serde_json::from_slice(manifest_bytes).context(error::ManifestDeserializeSnafu)?;
+
+    let mut canonicalized_manifest = Vec::new();
+    let mut ser = serde_json::Serializer::with_formatter(
+        &mut canonicalized_manifest,
+        CanonicalFormatter::new(),
+    );
+
+    manifest_object
+        .serialize(&mut ser)
+        .context(error::ManifestCanonicalizeSnafu)?;
+
+    Ok(canonicalized_manifest)
+}
+
+/// The uri of `tag` in the repository of the image at `uri`, which may be given by tag or digest.
+fn tag_target(uri: &str, tag: &str) -> String {
+    let repository = match uri.split_once('@') {
+        Some((repository, _)) => repository,
+        // A colon after the last slash separates the tag, while one before it is a registry port.
+        None => match uri.rfind(':') {
+            Some(colon) if colon > uri.rfind('/').unwrap_or(0) => &uri[..colon],
+            _ => uri,
+        },
+    };
+    format!("{repository}:{tag}")
 }
 
 #[async_trait]
@@ -92,8 +246,29 @@ pub trait ImageToolImpl: std::fmt::Debug + Send + Sync + 'static {
     async fn get_config(&self, uri: &str) -> Result<ConfigView>;
     /// Fetch the manifest
     async fn get_manifest(&self, uri: &str) -> Result<Vec<u8>>;
+    /// Fetch the manifest along with the digest of its bytes, as `sha256:<hex>`
+    async fn get_manifest_with_digest(&self, uri: &str) -> Result<(Vec<u8>, String)> {
+        let manifest = self.get_manifest(uri).await?;
+        let digest = format!("sha256:{:x}", Sha256::digest(&manifest));
+        Ok((manifest, digest))
+    }
+    /// Fetch the digest of the manifest or manifest list
+    async fn get_digest(&self, uri: &str) -> Result<String>;
+    /// Add a tag to an image in the same repository
+    async fn tag(&self, uri: &str, tag: &str) -> Result<()>;
+    /// List the repositories in a registry
+    async fn list_repositories(&self, registry: &str) -> Result<Vec<String>>;
+    /// List the tags of a repository
+    async fn list_tags(&self, repository: &str) -> Result<Vec<String>>;
     /// Push a single-arch image in oci archive format
     async fn push_oci_archive(&self, path: &Path, uri: &str) -> Result<()>;
+    /// Add annotations and labels to an image in the registry, re-pushing it to the same tag
+    async fn mutate(
+        &self,
+        uri: &str,
+        annotations: &BTreeMap<String, String>,
+        labels: &BTreeMap<String, String>,
+    ) -> Result<()>;
     /// Push the multi-arch kit manifest list
     async fn push_multi_platform_manifest(
         &self,
@@ -136,12 +311,26 @@ impl Display for DockerArchitecture {
 #[serde(rename_all = "snake_case")]
 struct ImageView {
     config: ConfigView,
+    #[serde(default)]
+    architecture: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct ConfigView {
     pub labels: HashMap<String, String>,
+    /// The architecture the image was built for, e.g. `amd64`, from the top level of its config.
+    #[serde(skip)]
+    pub architecture: Option<String>,
+}
+
+impl From<ImageView> for ConfigView {
+    fn from(image_view: ImageView) -> Self {
+        Self {
+            architecture: image_view.architecture,
+            ..image_view.config
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, error::Error>;
@@ -164,6 +353,15 @@ pub mod error {
         #[snafu(display("Failed to deserialize image config: {source}"))]
         ConfigDeserialize { source: serde_json::Error },
 
+        #[snafu(display("Failed to serialize audit log entry: {source}"))]
+        AuditSerialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to write audit log '{}': {source}", path.display()))]
+        AuditWrite {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
         #[snafu(display("Failed to create temporary directory for crane push: {source}"))]
         CraneTemp { source: std::io::Error },
 
@@ -179,12 +377,51 @@ pub mod error {
         #[snafu(display("invalid architecture '{value}'"))]
         InvalidArchitecture { value: String },
 
+        #[snafu(display("invalid digest '{digest}'"))]
+        InvalidDigest { digest: String },
+
+        #[snafu(display("invalid image reference '{uri}': {message}"))]
+        InvalidReference { uri: String, message: String },
+
+        #[snafu(display("'{uri}' in the OCI layout directory is corrupt: '{}' does not match its digest", path.display()))]
+        LayoutCorrupt { uri: String, path: PathBuf },
+
+        #[snafu(display("Failed to parse '{}' in OCI layout: {source}", path.display()))]
+        LayoutDeserialize {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("'{uri}' is not in the OCI layout directory: '{}' is missing", path.display()))]
+        LayoutMissing { uri: String, path: PathBuf },
+
+        #[snafu(display("Failed to read '{}' in OCI layout: {source}", path.display()))]
+        LayoutRead {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Cannot store '{uri}' in an OCI layout directory without a tag"))]
+        LayoutUntagged { uri: String },
+
+        #[snafu(display("Failed to write '{}': {source}", path.display()))]
+        LayoutWrite {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
         #[snafu(display("Failed to deserialize image manifest: {source}"))]
         ManifestDeserialize { source: serde_json::Error },
 
         #[snafu(display("Failed to canonicalize image manifest: {source}"))]
         ManifestCanonicalize { source: serde_json::Error },
 
+        #[snafu(display("'{uri}' is a manifest list, which has no image config"))]
+        NotAnImage { uri: String },
+
+        #[snafu(display("Cannot {operation} when reading images from an OCI layout directory"))]
+        Offline { operation: String },
+
         #[snafu(display("Failed to run operation with image tool: {message}\n command: {} {}", program.display(), args.join(" ")))]
         OperationFailed {
             message: String,
@@ -192,6 +429,14 @@ pub mod error {
             args: Vec<String>,
         },
 
+        #[snafu(display("Failed to {operation} '{uri}': {source}"))]
+        Registry {
+            operation: String,
+            uri: String,
+            #[snafu(source(from(oci_client::errors::OciDistributionError, Box::new)))]
+            source: Box<oci_client::errors::OciDistributionError>,
+        },
+
         #[snafu(display("Unsupported container image tool '{}'", name))]
         Unsupported { name: String },
     }
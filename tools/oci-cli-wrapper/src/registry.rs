@@ -0,0 +1,355 @@
+//! Reads images from a registry in-process with a pure-Rust OCI distribution client, rather than
+//! through `krane`. The connections to each registry are pooled for the life of the process, and
+//! failures carry the registry's own error, such as an unknown manifest or a denied request.
+//!
+//! Selected by setting `TWOLITER_IMAGE_TOOL=native`. Credentials are read from the docker
+//! configuration, including its credential helpers, as `krane` reads them, after any configured
+//! for the registry in `TWOLITER_REGISTRY_AUTH`. Changing a registry, and listing its
+//! repositories, which the distribution API leaves optional, still go through `krane`.
+//!
+//! Registries are reached through the proxies named by `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY`,
+//! and are trusted to present the certificates in the file named by `TWOLITER_CA_BUNDLE`, such as
+//! the CA of a proxy which intercepts TLS, as well as those of the system's CAs.
+use crate::container_tool::{self, ContainerTool};
+use crate::crane::CraneCLI;
+use crate::error::{self, Result};
+use crate::layout::{write_index, Descriptor, OciLayoutDir, ReferencesView};
+use crate::registry_auth;
+use crate::{ConfigView, DockerArchitecture, ImageToolImpl, ImageView};
+use async_trait::async_trait;
+use docker_credential::DockerCredential;
+use krane_static::settings;
+use oci_client::client::{Certificate, CertificateEncoding, ClientConfig, ClientProtocol};
+use oci_client::secrets::RegistryAuth;
+use oci_client::{Client, Reference};
+use snafu::{OptionExt, ResultExt};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock, PoisonError};
+use tokio::io::AsyncWriteExt;
+
+pub use krane_static::CA_BUNDLE_ENV;
+
+/// The environment variable choosing how registries are reached: `krane`, the default, or
+/// `native` for [`RegistryClient`].
+pub const IMAGE_TOOL_ENV: &str = "TWOLITER_IMAGE_TOOL";
+
+/// The manifests and manifest lists a registry may return.
+const ACCEPTED_MEDIA_TYPES: [&str; 4] = [
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// A client for each registry, shared by every [`RegistryClient`] so that connections and tokens
+/// are reused across calls.
+static CLIENTS: OnceLock<Mutex<HashMap<String, Client>>> = OnceLock::new();
+
+/// Reads images from registries with an in-process OCI distribution client.
+#[derive(Debug, Default)]
+pub struct RegistryClient {
+    /// Makes the changes to registries which the client does not.
+    krane: CraneCLI,
+}
+
+/// A reference to an image, with the client and credentials for its registry.
+struct Session {
+    client: Client,
+    reference: Reference,
+    auth: RegistryAuth,
+    uri: String,
+}
+
+impl RegistryClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connects to the registry of `uri`.
+    async fn session(uri: &str) -> Result<Session> {
+        let reference: Reference = uri.parse().map_err(|e| {
+            error::InvalidReferenceSnafu {
+                uri,
+                message: format!("{e}"),
+            }
+            .build()
+        })?;
+        let registry = reference.registry().to_string();
+        let client = client(&registry);
+        let auth = tokio::task::spawn_blocking(move || auth(&registry))
+            .await
+            .context(error::ForkSnafu)?;
+        Ok(Session {
+            client,
+            reference,
+            auth,
+            uri: uri.to_string(),
+        })
+    }
+}
+
+impl Session {
+    /// The same repository, at `digest`.
+    fn at(&self, digest: &str) -> Reference {
+        Reference::with_digest(
+            self.reference.registry().to_string(),
+            self.reference.repository().to_string(),
+            digest.to_string(),
+        )
+    }
+
+    /// Fetches the manifest or manifest list at `reference`, and its digest.
+    async fn manifest(&self, reference: &Reference) -> Result<(Vec<u8>, String)> {
+        let (manifest, digest) = self
+            .client
+            .pull_manifest_raw(reference, &self.auth, &ACCEPTED_MEDIA_TYPES)
+            .await
+            .context(error::RegistrySnafu {
+                operation: "fetch the manifest of",
+                uri: &self.uri,
+            })?;
+        Ok((manifest.to_vec(), digest))
+    }
+
+    /// Fetches the blob with `digest` into memory.
+    async fn blob(&self, digest: &str) -> Result<Vec<u8>> {
+        let mut blob = Vec::new();
+        self.client
+            .pull_blob(&self.reference, digest, &mut blob)
+            .await
+            .context(error::RegistrySnafu {
+                operation: format!("fetch blob {digest} of"),
+                uri: &self.uri,
+            })?;
+        Ok(blob)
+    }
+
+    /// Fetches the blob with `digest` into the OCI layout at `layout`, unless it is there already.
+    /// Blobs are written beside their final path and moved into place once complete, so a blob
+    /// that is present was fetched in full, and the client checks each against its digest.
+    async fn blob_to_layout(&self, layout: &Path, digest: &str) -> Result<()> {
+        let path = OciLayoutDir::blob_path(layout, digest)?;
+        if path.exists() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context(error::LayoutWriteSnafu { path: parent })?;
+        }
+        let partial = path.with_extension("partial");
+        let mut file = tokio::fs::File::create(&partial)
+            .await
+            .context(error::LayoutWriteSnafu { path: &partial })?;
+        self.client
+            .pull_blob(&self.reference, digest, &mut file)
+            .await
+            .context(error::RegistrySnafu {
+                operation: format!("pull blob {digest} of"),
+                uri: &self.uri,
+            })?;
+        file.flush()
+            .await
+            .context(error::LayoutWriteSnafu { path: &partial })?;
+        tokio::fs::rename(&partial, &path)
+            .await
+            .context(error::LayoutWriteSnafu { path: &path })
+    }
+}
+
+/// The client for `registry`, which is created on first use. Registries on the local host are
+/// reached over plain HTTP, as `krane` reaches them, and proxies are read through `settings`
+/// when the client is created.
+fn client(registry: &str) -> Client {
+    let mut clients = CLIENTS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    clients
+        .entry(registry.to_string())
+        .or_insert_with(|| {
+            let host = match registry.rsplit_once(':') {
+                Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+                _ => registry,
+            };
+            let protocol = match host {
+                "localhost" | "127.0.0.1" | "[::1]" => ClientProtocol::Http,
+                _ => ClientProtocol::Https,
+            };
+            Client::new(ClientConfig {
+                protocol,
+                extra_root_certificates: ca_bundle(),
+                https_proxy: proxy("HTTPS_PROXY"),
+                http_proxy: proxy("HTTP_PROXY"),
+                no_proxy: proxy("NO_PROXY"),
+                ..Default::default()
+            })
+        })
+        .clone()
+}
+
+/// The proxy setting `name`, in either the upper or the lower case.
+fn proxy(name: &str) -> Option<String> {
+    [name.to_string(), name.to_lowercase()]
+        .iter()
+        .find_map(|name| settings::var(name).ok().filter(|value| !value.is_empty()))
+}
+
+/// The certificates in the CA bundle named by `TWOLITER_CA_BUNDLE`, if it is set and readable.
+fn ca_bundle() -> Vec<Certificate> {
+    let Some(path) = settings::var_os(CA_BUNDLE_ENV).filter(|path| !path.is_empty()) else {
+        return Vec::new();
+    };
+    match std::fs::read(&path) {
+        Ok(data) => vec![Certificate {
+            encoding: CertificateEncoding::Pem,
+            data,
+        }],
+        Err(e) => {
+            log::warn!(
+                "Ignoring the CA bundle '{}', which cannot be read: {e}",
+                Path::new(&path).display()
+            );
+            Vec::new()
+        }
+    }
+}
+
+/// The credentials configured for `registry`, or else those kept by the container tool, or else
+/// those from the docker configuration, or anonymous access if it has none that the client can use.
+fn auth(registry: &str) -> RegistryAuth {
+    let credentials = registry_auth::credentials(registry)
+        .or_else(|| container_tool::credentials(ContainerTool::from_env().ok()?, registry));
+    if let Some((username, password)) = credentials {
+        return RegistryAuth::Basic(username, password);
+    }
+    match docker_credential::get_credential(registry) {
+        Ok(DockerCredential::UsernamePassword(username, password)) => {
+            RegistryAuth::Basic(username, password)
+        }
+        Ok(DockerCredential::IdentityToken(_)) => {
+            log::debug!("Ignoring the identity token for '{registry}', which cannot be used");
+            RegistryAuth::Anonymous
+        }
+        Err(e) => {
+            log::debug!("Reaching '{registry}' anonymously, with no credentials: {e}");
+            RegistryAuth::Anonymous
+        }
+    }
+}
+
+#[async_trait]
+impl ImageToolImpl for RegistryClient {
+    async fn pull_oci_image(&self, path: &Path, uri: &str) -> Result<()> {
+        let session = Self::session(uri).await?;
+        let (manifest, digest) = session.manifest(&session.reference).await?;
+        let references: ReferencesView =
+            serde_json::from_slice(&manifest).context(error::ManifestDeserializeSnafu)?;
+        let top = Descriptor {
+            media_type: references.media_type.clone(),
+            digest,
+            size: Some(manifest.len() as u64),
+            annotations: BTreeMap::new(),
+        };
+
+        // A manifest list is pulled with every manifest it lists.
+        let mut pending = vec![(top.digest.clone(), manifest)];
+        while let Some((digest, manifest)) = pending.pop() {
+            let references: ReferencesView =
+                serde_json::from_slice(&manifest).context(error::ManifestDeserializeSnafu)?;
+            for descriptor in references.config.iter().chain(&references.layers) {
+                session.blob_to_layout(path, &descriptor.digest).await?;
+            }
+            for descriptor in &references.manifests {
+                let (manifest, _) = session.manifest(&session.at(&descriptor.digest)).await?;
+                pending.push((descriptor.digest.clone(), manifest));
+            }
+            let manifest_path = OciLayoutDir::blob_path(path, &digest)?;
+            if let Some(parent) = manifest_path.parent() {
+                std::fs::create_dir_all(parent)
+                    .context(error::LayoutWriteSnafu { path: parent })?;
+            }
+            std::fs::write(&manifest_path, &manifest).context(error::LayoutWriteSnafu {
+                path: &manifest_path,
+            })?;
+        }
+        write_index(path, &[top])
+    }
+
+    async fn get_config(&self, uri: &str) -> Result<ConfigView> {
+        let session = Self::session(uri).await?;
+        let (manifest, _) = session.manifest(&session.reference).await?;
+        let references: ReferencesView =
+            serde_json::from_slice(&manifest).context(error::ManifestDeserializeSnafu)?;
+        let config = references.config.context(error::NotAnImageSnafu { uri })?;
+        let image_view: ImageView = serde_json::from_slice(&session.blob(&config.digest).await?)
+            .context(error::ConfigDeserializeSnafu)?;
+        Ok(image_view.into())
+    }
+
+    async fn get_manifest(&self, uri: &str) -> Result<Vec<u8>> {
+        let session = Self::session(uri).await?;
+        let (manifest, _) = session.manifest(&session.reference).await?;
+        Ok(manifest)
+    }
+
+    async fn get_manifest_with_digest(&self, uri: &str) -> Result<(Vec<u8>, String)> {
+        let session = Self::session(uri).await?;
+        session.manifest(&session.reference).await
+    }
+
+    async fn get_digest(&self, uri: &str) -> Result<String> {
+        let session = Self::session(uri).await?;
+        session
+            .client
+            .fetch_manifest_digest(&session.reference, &session.auth)
+            .await
+            .context(error::RegistrySnafu {
+                operation: "fetch the digest of",
+                uri,
+            })
+    }
+
+    async fn tag(&self, uri: &str, tag: &str) -> Result<()> {
+        self.krane.tag(uri, tag).await
+    }
+
+    async fn list_repositories(&self, registry: &str) -> Result<Vec<String>> {
+        self.krane.list_repositories(registry).await
+    }
+
+    async fn list_tags(&self, repository: &str) -> Result<Vec<String>> {
+        let session = Self::session(repository).await?;
+        let response = session
+            .client
+            .list_tags(&session.reference, &session.auth, None, None)
+            .await
+            .context(error::RegistrySnafu {
+                operation: "list the tags of",
+                uri: repository,
+            })?;
+        Ok(response.tags)
+    }
+
+    async fn push_oci_archive(&self, path: &Path, uri: &str) -> Result<()> {
+        self.krane.push_oci_archive(path, uri).await
+    }
+
+    async fn mutate(
+        &self,
+        uri: &str,
+        annotations: &BTreeMap<String, String>,
+        labels: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        self.krane.mutate(uri, annotations, labels).await
+    }
+
+    async fn push_multi_platform_manifest(
+        &self,
+        platform_images: Vec<(DockerArchitecture, String)>,
+        uri: &str,
+    ) -> Result<()> {
+        self.krane
+            .push_multi_platform_manifest(platform_images, uri)
+            .await
+    }
+}
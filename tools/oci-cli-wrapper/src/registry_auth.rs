@@ -0,0 +1,115 @@
+//! Credentials configured for registries, which take precedence over those of the container tool
+//! and the docker configuration. They are set in `TWOLITER_REGISTRY_AUTH` as a JSON object keyed by
+//! registry host, and name where to find each registry's credentials rather than holding them:
+//!
+//! ```json
+//! {
+//!   "123456789012.dkr.ecr.us-west-2.amazonaws.com": {"credential-helper": "ecr-login"},
+//!   "registry.example.com": {"username-env": "EXAMPLE_USER", "password-env": "EXAMPLE_TOKEN"}
+//! }
+//! ```
+//!
+//! `krane` reads them from its first argument, and the in-process registry client from
+//! [`krane_static::settings`], which falls back to the environment.
+use krane_static::settings;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+pub use krane_static::REGISTRY_AUTH_ENV;
+
+/// Where to find the credentials for a registry.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RegistryAuth {
+    /// The docker credential helper which provides the credentials, run as
+    /// `docker-credential-<name>`, e.g. `ecr-login`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential_helper: Option<String>,
+    /// The environment variable holding the username.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username_env: Option<String>,
+    /// The environment variable holding the password or token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_env: Option<String>,
+}
+
+/// The credentials configured for each registry host.
+pub fn configured() -> BTreeMap<String, RegistryAuth> {
+    let Ok(auth) = settings::var(REGISTRY_AUTH_ENV) else {
+        return BTreeMap::new();
+    };
+    serde_json::from_str(&auth).unwrap_or_else(|e| {
+        log::warn!("Ignoring the registry credentials in {REGISTRY_AUTH_ENV}: {e}");
+        BTreeMap::new()
+    })
+}
+
+/// The username and password configured for `registry`, if any.
+pub(crate) fn credentials(registry: &str) -> Option<(String, String)> {
+    let configured = configured();
+    let auth = configured.get(registry)?;
+    if let Some(helper) = &auth.credential_helper {
+        return run_helper(helper, registry);
+    }
+    let var = |name: &Option<String>| {
+        let name = name.as_deref()?;
+        let value = settings::var(name).ok().filter(|value| !value.is_empty());
+        if value.is_none() {
+            log::warn!(
+                "The credentials for '{registry}' are configured in {name}, which is not set"
+            );
+        }
+        value
+    };
+    Some((var(&auth.username_env)?, var(&auth.password_env)?))
+}
+
+/// Asks the docker credential helper `helper` for the credentials of `registry`.
+fn run_helper(helper: &str, registry: &str) -> Option<(String, String)> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct HelperCredentials {
+        username: String,
+        secret: String,
+    }
+
+    let program = format!("docker-credential-{helper}");
+    let mut command = Command::new(&program);
+    settings::apply(&mut command);
+    let output = command
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(registry.as_bytes())?;
+            }
+            child.wait_with_output()
+        });
+    match output {
+        Ok(output) if output.status.success() => {
+            match serde_json::from_slice::<HelperCredentials>(&output.stdout) {
+                Ok(credentials) => Some((credentials.username, credentials.secret)),
+                Err(e) => {
+                    log::warn!("'{program}' returned invalid credentials for '{registry}': {e}");
+                    None
+                }
+            }
+        }
+        Ok(output) => {
+            log::warn!(
+                "'{program}' failed for '{registry}': {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            log::warn!("Failed to run '{program}' for '{registry}': {e}");
+            None
+        }
+    }
+}
@@ -12,7 +12,6 @@ use simplelog::{Config as LogConfig, LevelFilter, SimpleLogger};
 use snafu::{ensure, OptionExt, ResultExt};
 use std::convert::TryFrom;
 use std::fs;
-use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
 use std::process::{self, Command};
 use tempfile::NamedTempFile;
@@ -161,11 +160,14 @@ fn run() -> Result<()> {
             );
 
             // Root role files don't need to be secret.
-            fs::set_permissions(&args.root_role_path, fs::Permissions::from_mode(0o644)).context(
-                error::SetModeSnafu {
-                    path: &args.root_role_path,
-                },
-            )?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&args.root_role_path, fs::Permissions::from_mode(0o644))
+                    .context(error::SetModeSnafu {
+                        path: &args.root_role_path,
+                    })?;
+            }
 
             Ok(())
         }
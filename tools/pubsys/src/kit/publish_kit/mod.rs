@@ -1,9 +1,14 @@
+mod state;
+
 use crate::Args;
 use clap::Parser;
 use log::{debug, info, trace};
 use oci_cli_wrapper::{DockerArchitecture, ImageTool};
 use pubsys_config::InfraConfig;
+use serde::Deserialize;
 use snafu::{ensure, OptionExt, ResultExt};
+use state::{PublishState, PushedImage};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 /// Takes a local kit built using buildsys and publishes it to a vendor specified in Infra.toml
@@ -28,10 +33,42 @@ pub(crate) struct PublishKitArgs {
     /// The build id of the kit that should be published
     #[arg(long)]
     build_id: String,
+
+    /// Annotations to add to each published kit image manifest and to the kit's manifest list, as
+    /// a JSON object
+    #[arg(long, default_value = "{}", value_parser = parse_json_map)]
+    annotations: BTreeMap<String, String>,
+
+    /// Labels to add to each published kit image config, as a JSON object
+    #[arg(long, default_value = "{}", value_parser = parse_json_map)]
+    labels: BTreeMap<String, String>,
+
+    /// Additional tags to point at the published kit, as a JSON array of objects with a `tag` and
+    /// whether it is `mutable`
+    #[arg(long, default_value = "[]", value_parser = parse_tags)]
+    tags: Vec<AdditionalTag>,
+}
+
+/// A tag to apply to the kit's manifest list besides its version.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct AdditionalTag {
+    tag: String,
+    /// Whether the tag may be moved from the image it points to. Immutable tags may only be applied
+    /// once.
+    #[serde(default)]
+    mutable: bool,
+}
+
+fn parse_tags(input: &str) -> Result<Vec<AdditionalTag>> {
+    serde_json::from_str(input).context(error::ParseTagsSnafu { input })
+}
+
+fn parse_json_map(input: &str) -> Result<BTreeMap<String, String>> {
+    serde_json::from_str(input).context(error::ParseJsonMapSnafu { input })
 }
 
 pub(crate) async fn run(args: &Args, publish_kit_args: &PublishKitArgs) -> Result<()> {
-    let image_tool = ImageTool::krane();
+    let image_tool = ImageTool::registry();
 
     // If a lock file exists, use that, otherwise use Infra.toml
     let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
@@ -75,6 +112,11 @@ async fn publish_kit(
         None => kit_name.to_string(),
     };
 
+    // A publish which was interrupted before it pushed the manifest list left a record of the
+    // images it pushed, which need not be pushed again.
+    let state_path = PublishState::path(kit_path, &kit_version, &build_id)?;
+    let mut state = PublishState::load(&state_path)?;
+
     let mut platform_images = Vec::new();
     for arch in ["aarch64", "x86_64"] {
         let docker_arch =
@@ -93,6 +135,29 @@ async fn publish_kit(
             vendor_registry_uri, repository_target, &kit_version, &build_id, arch
         );
 
+        let archive_size = path
+            .metadata()
+            .context(error::ReadArchiveSnafu { path: &path })?
+            .len();
+        let mut pushed = PushedImage {
+            archive_size,
+            annotations: publish_kit_args.annotations.clone(),
+            labels: publish_kit_args.labels.clone(),
+            digest: String::new(),
+        };
+        if let Some(previous) = state.pushed(&arch_specific_target_uri, &pushed) {
+            // The registry is checked in case the image was since overwritten or deleted.
+            let current = image_tool.get_digest(&arch_specific_target_uri).await.ok();
+            if current.as_ref() == Some(&previous.digest) {
+                info!(
+                    "Kit image for platform {} was already pushed to {}, skipping it",
+                    arch, &arch_specific_target_uri
+                );
+                platform_images.push((docker_arch, arch_specific_target_uri.clone()));
+                continue;
+            }
+        }
+
         info!(
             "Pushing kit image for platform {} to {}",
             arch, &arch_specific_target_uri
@@ -103,6 +168,27 @@ async fn publish_kit(
             .await
             .context(error::PublishKitSnafu)?;
 
+        if !publish_kit_args.annotations.is_empty() || !publish_kit_args.labels.is_empty() {
+            info!(
+                "Adding annotations and labels to kit image {}",
+                &arch_specific_target_uri
+            );
+            image_tool
+                .mutate(
+                    &arch_specific_target_uri,
+                    &publish_kit_args.annotations,
+                    &publish_kit_args.labels,
+                )
+                .await
+                .context(error::PublishKitSnafu)?;
+        }
+
+        pushed.digest = image_tool
+            .get_digest(&arch_specific_target_uri)
+            .await
+            .context(error::PublishKitSnafu)?;
+        state.record(&state_path, &arch_specific_target_uri, pushed)?;
+
         platform_images.push((docker_arch, arch_specific_target_uri.clone()));
     }
     ensure!(
@@ -122,8 +208,107 @@ async fn publish_kit(
         .await
         .context(error::PublishKitSnafu)?;
 
+    if !publish_kit_args.annotations.is_empty() {
+        // Consumers read the annotations from the manifest list they resolve the kit by, without
+        // fetching each image manifest. Only annotations can be added to a manifest list.
+        info!("Adding annotations to kit manifest list {}", &target_uri);
+        image_tool
+            .mutate(&target_uri, &publish_kit_args.annotations, &BTreeMap::new())
+            .await
+            .context(error::PublishKitSnafu)?;
+    }
+
     info!("Successfully published kit to {}", target_uri);
 
+    if !publish_kit_args.tags.is_empty() {
+        let repository_uri = format!("{}/{}", vendor_registry_uri, repository_target);
+        apply_tags(
+            image_tool,
+            &repository_uri,
+            &target_uri,
+            &publish_kit_args.tags,
+        )
+        .await?;
+    }
+
+    PublishState::clear(&state_path)?;
+    Ok(())
+}
+
+/// Points each of `tags` in `repository_uri` at the manifest list published to `target_uri`, then
+/// checks that every tag resolves to the same digest.
+async fn apply_tags(
+    image_tool: &ImageTool,
+    repository_uri: &str,
+    target_uri: &str,
+    tags: &[AdditionalTag],
+) -> Result<()> {
+    let digest = image_tool
+        .get_digest(target_uri)
+        .await
+        .context(error::PublishKitSnafu)?;
+    let digest_uri = format!("{}@{}", repository_uri, digest);
+
+    // Check every immutable tag before moving any tag, so that a conflict leaves the tags as they
+    // were.
+    let mut to_apply = Vec::new();
+    for tag in tags {
+        let tag_uri = format!("{}:{}", repository_uri, tag.tag);
+        // A tag which cannot be resolved is taken not to exist yet.
+        let existing = image_tool.get_digest(&tag_uri).await.ok();
+        match existing {
+            Some(existing) if existing == digest => {
+                debug!("Tag {} already points at {}", tag_uri, digest);
+            }
+            Some(existing) => {
+                ensure!(
+                    tag.mutable,
+                    error::ImmutableTagSnafu {
+                        tag: tag_uri,
+                        existing,
+                        digest: digest.clone(),
+                    }
+                );
+                to_apply.push(tag);
+            }
+            None => to_apply.push(tag),
+        }
+    }
+
+    for tag in to_apply {
+        info!("Tagging {} as {}", digest_uri, tag.tag);
+        image_tool
+            .tag(&digest_uri, &tag.tag)
+            .await
+            .context(error::PublishKitSnafu)?;
+    }
+
+    let tag_uris = std::iter::once(target_uri.to_string()).chain(
+        tags.iter()
+            .map(|tag| format!("{}:{}", repository_uri, tag.tag)),
+    );
+    for tag_uri in tag_uris {
+        let tagged = image_tool
+            .get_digest(&tag_uri)
+            .await
+            .context(error::PublishKitSnafu)?;
+        ensure!(
+            tagged == digest,
+            error::TagDigestMismatchSnafu {
+                tag: tag_uri,
+                tagged,
+                digest: digest.clone(),
+            }
+        );
+    }
+    info!(
+        "Tagged {} as {}",
+        digest_uri,
+        tags.iter()
+            .map(|tag| tag.tag.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
     Ok(())
 }
 
@@ -143,22 +328,79 @@ mod error {
             arch: String,
         },
 
+        #[snafu(display(
+            "Tag '{}' is immutable and already points at {}, not the published {}",
+            tag,
+            existing,
+            digest
+        ))]
+        ImmutableTag {
+            tag: String,
+            existing: String,
+            digest: String,
+        },
+
         #[snafu(display("Failed not get kit name from path {}", path.display()))]
         InvalidPath { path: PathBuf },
 
         #[snafu(display("No kit archive(s) exist at path {}", path.display()))]
         NoArchive { path: PathBuf },
 
+        #[snafu(display("Expected a JSON object of strings, got '{}': {}", input, source))]
+        ParseJsonMap {
+            input: String,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Expected a JSON array of tags, got '{}': {}", input, source))]
+        ParseTags {
+            input: String,
+            source: serde_json::Error,
+        },
+
         #[snafu(display("No vendors specified in Infra.toml, you must specify at least one"))]
         NoVendors,
 
+        #[snafu(display("Failed to read kit archive {}: {}", path.display(), source))]
+        ReadArchive {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to read publish state {}: {}", path.display(), source))]
+        ReadState {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to serialize publish state: {}", source))]
+        SerializeState { source: serde_json::Error },
+
         #[snafu(display("Could not publish kit: {}", source))]
         PublishKit {
             source: oci_cli_wrapper::error::Error,
         },
 
+        #[snafu(display(
+            "Tag '{}' points at {} instead of the published {}",
+            tag,
+            tagged,
+            digest
+        ))]
+        TagDigestMismatch {
+            tag: String,
+            tagged: String,
+            digest: String,
+        },
+
         #[snafu(display("Vendor '{}' not specified in Infra.toml", name))]
         VendorNotFound { name: String },
+
+        #[snafu(display("Failed to write publish state {}: {}", path.display(), source))]
+        WriteState {
+            path: PathBuf,
+            source: std::io::Error,
+        },
     }
 }
 
@@ -0,0 +1,143 @@
+//! Records the per-architecture kit images which a publish has pushed, so that a publish which is
+//! interrupted before it pushes the manifest list can be re-run without pushing them again.
+//!
+//! The state is kept beside the kit's directory, which may be recreated between runs, in a file
+//! named for the kit's version and build id. An image is only skipped when the registry still
+//! serves the digest recorded for it, and it was pushed from an archive of the same size with the
+//! same annotations and labels. The file is removed once the publish completes.
+use super::{error, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(super) struct PublishState {
+    /// The images pushed so far, keyed by the uri they were pushed to.
+    #[serde(default)]
+    images: BTreeMap<String, PushedImage>,
+}
+
+/// A per-architecture kit image which was pushed, and what it was pushed from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(super) struct PushedImage {
+    /// The size of the archive the image was pushed from.
+    pub(super) archive_size: u64,
+    pub(super) annotations: BTreeMap<String, String>,
+    pub(super) labels: BTreeMap<String, String>,
+    /// The digest of the image after its annotations and labels were added.
+    pub(super) digest: String,
+}
+
+impl PublishState {
+    /// The state file of publishing the kit at `kit_path` at `version` and `build_id`.
+    pub(super) fn path(kit_path: &Path, version: &str, build_id: &str) -> Result<PathBuf> {
+        let kit_name = kit_path
+            .file_name()
+            .context(error::InvalidPathSnafu { path: kit_path })?
+            .to_string_lossy();
+        Ok(kit_path.with_file_name(format!(
+            ".{kit_name}-{version}-{build_id}.publish-state.json"
+        )))
+    }
+
+    /// Reads the state left by an interrupted publish, if there is one. A state file which cannot
+    /// be parsed is ignored, so that the publish starts over.
+    pub(super) fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = std::fs::read_to_string(path).context(error::ReadStateSnafu { path })?;
+        Ok(serde_json::from_str(&data).unwrap_or_else(|e| {
+            debug!(
+                "Ignoring unreadable publish state '{}': {}",
+                path.display(),
+                e
+            );
+            Self::default()
+        }))
+    }
+
+    /// The image recorded as pushed to `uri`, if it matches `expected` in everything but its
+    /// digest.
+    pub(super) fn pushed(&self, uri: &str, expected: &PushedImage) -> Option<&PushedImage> {
+        self.images.get(uri).filter(|pushed| {
+            pushed.archive_size == expected.archive_size
+                && pushed.annotations == expected.annotations
+                && pushed.labels == expected.labels
+        })
+    }
+
+    /// Records `image` as pushed to `uri`, and writes the state to `path` so that it survives an
+    /// interruption.
+    pub(super) fn record(&mut self, path: &Path, uri: &str, image: PushedImage) -> Result<()> {
+        self.images.insert(uri.to_string(), image);
+        let data = serde_json::to_string_pretty(self).context(error::SerializeStateSnafu)?;
+        // Write the state beside its final path and rename it, so that it is never left half
+        // written.
+        let temp_path = path.with_extension("json.tmp");
+        std::fs::write(&temp_path, data).context(error::WriteStateSnafu { path: &temp_path })?;
+        std::fs::rename(&temp_path, path).context(error::WriteStateSnafu { path })
+    }
+
+    /// Removes the state once the publish has completed.
+    pub(super) fn clear(path: &Path) -> Result<()> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).context(error::WriteStateSnafu { path })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image(archive_size: u64, digest: &str) -> PushedImage {
+        PushedImage {
+            archive_size,
+            annotations: BTreeMap::from([("a".to_string(), "1".to_string())]),
+            labels: BTreeMap::new(),
+            digest: digest.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_publish_state() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let kit_path = dir.path().join("core-kit");
+        let path = PublishState::path(&kit_path, "v1.0.0", "abc").unwrap();
+        assert_eq!(
+            path,
+            dir.path().join(".core-kit-v1.0.0-abc.publish-state.json")
+        );
+        assert_eq!(PublishState::load(&path).unwrap(), PublishState::default());
+
+        let uri = "example.com/core-kit:v1.0.0-abc-x86_64";
+        let mut state = PublishState::default();
+        state.record(&path, uri, image(10, "sha256:1")).unwrap();
+        let state = PublishState::load(&path).unwrap();
+        assert_eq!(
+            state
+                .pushed(uri, &image(10, ""))
+                .map(|image| image.digest.as_str()),
+            Some("sha256:1")
+        );
+        assert!(state.pushed(uri, &image(11, "")).is_none());
+        assert!(state
+            .pushed("example.com/other:v1", &image(10, ""))
+            .is_none());
+
+        PublishState::clear(&path).unwrap();
+        assert!(!path.exists());
+        PublishState::clear(&path).unwrap();
+
+        std::fs::write(&path, "not json").unwrap();
+        assert_eq!(PublishState::load(&path).unwrap(), PublishState::default());
+    }
+}
@@ -11,6 +11,7 @@ Currently implemented:
 * setting SSM parameters based on built AMIs
 * promoting SSM parameters from versioned entries to named (e.g. 'latest')
 * validating SSM parameters by comparing the returned parameters in a region to a given list of parameters
+* pushing variants built as container images to a vendor's registry
 
 To be implemented:
 * high-level document describing pubsys usage with examples
@@ -24,6 +25,7 @@ Configuration comes from:
 
 mod aws;
 mod kit;
+mod oci;
 mod repo;
 mod vmware;
 
@@ -130,6 +132,11 @@ async fn run() -> Result<()> {
                 .await
                 .context(error::PublishKitSnafu)
         }
+        SubCommands::PublishImage(ref publish_image_args) => {
+            oci::publish_image::run(&args, publish_image_args)
+                .await
+                .context(error::PublishImageSnafu)
+        }
     }
 }
 
@@ -176,6 +183,7 @@ enum SubCommands {
     UploadOva(vmware::upload_ova::UploadArgs),
 
     PublishKit(kit::publish_kit::PublishKitArgs),
+    PublishImage(oci::publish_image::PublishImageArgs),
 }
 
 /// Parses a SemVer, stripping a leading 'v' if present
@@ -273,6 +281,11 @@ mod error {
         PublishKit {
             source: crate::kit::publish_kit::Error,
         },
+
+        #[snafu(display("Failed to publish image: {}", source))]
+        PublishImage {
+            source: crate::oci::publish_image::Error,
+        },
     }
 
     fn publish_ami_message(error: &crate::aws::publish_ami::Error) -> String {
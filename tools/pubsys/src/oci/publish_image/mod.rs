@@ -0,0 +1,143 @@
+use crate::Args;
+use clap::Parser;
+use log::{debug, info, trace};
+use oci_cli_wrapper::{DockerArchitecture, ImageTool};
+use pubsys_config::InfraConfig;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::path::PathBuf;
+
+/// Takes the container images of a variant built with `image-format = "oci"` and publishes them,
+/// as one multi-platform image, to a vendor specified in Infra.toml
+#[derive(Debug, Parser)]
+pub(crate) struct PublishImageArgs {
+    /// An image archive to publish and its architecture, as `<arch>=<path>`; may be repeated
+    #[arg(long = "archive", required = true, value_parser = parse_archive)]
+    archives: Vec<(String, PathBuf)>,
+
+    /// Vendor to publish the image to
+    #[arg(long)]
+    vendor: String,
+
+    /// The repository to push the image to, e.g. the variant's name
+    #[arg(long)]
+    repo: String,
+
+    /// The version of the image that should be published
+    #[arg(long)]
+    version: String,
+
+    /// The build id of the image that should be published
+    #[arg(long)]
+    build_id: String,
+}
+
+fn parse_archive(input: &str) -> Result<(String, PathBuf)> {
+    let (arch, path) = input
+        .split_once('=')
+        .context(error::ParseArchiveSnafu { input })?;
+    Ok((arch.to_string(), PathBuf::from(path)))
+}
+
+pub(crate) async fn run(args: &Args, publish_image_args: &PublishImageArgs) -> Result<()> {
+    let image_tool = ImageTool::registry();
+
+    // If a lock file exists, use that, otherwise use Infra.toml
+    let infra_config = InfraConfig::from_path_or_lock(&args.infra_config_path, false)
+        .context(error::ConfigSnafu)?;
+    trace!("Parsed infra config: {:?}", infra_config);
+
+    publish_image(infra_config, publish_image_args, &image_tool).await
+}
+
+async fn publish_image(
+    infra_config: InfraConfig,
+    publish_image_args: &PublishImageArgs,
+    image_tool: &ImageTool,
+) -> Result<()> {
+    // Fetch the vendor container registry uri
+    let vendor = infra_config
+        .vendor
+        .as_ref()
+        .context(error::NoVendorsSnafu)?
+        .get(&publish_image_args.vendor)
+        .context(error::VendorNotFoundSnafu {
+            name: publish_image_args.vendor.clone(),
+        })?;
+    let vendor_registry_uri = vendor.registry.clone();
+    debug!(
+        "Found vendor container registry at uri: {}",
+        vendor_registry_uri
+    );
+
+    let repository_uri = format!("{}/{}", vendor_registry_uri, publish_image_args.repo);
+    let version = &publish_image_args.version;
+    let build_id = &publish_image_args.build_id;
+
+    let mut platform_images = Vec::new();
+    for (arch, path) in &publish_image_args.archives {
+        let docker_arch = DockerArchitecture::try_from(arch.as_str())
+            .context(error::InvalidArchitectureSnafu { arch })?;
+        ensure!(path.is_file(), error::NoArchiveSnafu { path });
+
+        let arch_specific_target_uri = format!("{repository_uri}:{version}-{build_id}-{arch}");
+        info!(
+            "Pushing image for platform {} to {}",
+            arch, &arch_specific_target_uri
+        );
+        image_tool
+            .push_oci_archive(path, &arch_specific_target_uri)
+            .await
+            .context(error::PublishImageSnafu)?;
+
+        platform_images.push((docker_arch, arch_specific_target_uri));
+    }
+
+    let target_uri = format!("{repository_uri}:{version}");
+    info!("Pushing image to {}", &target_uri);
+    image_tool
+        .push_multi_platform_manifest(platform_images, &target_uri)
+        .await
+        .context(error::PublishImageSnafu)?;
+
+    info!("Successfully published image to {}", target_uri);
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(crate) enum Error {
+        #[snafu(display("Error reading config: {}", source))]
+        Config { source: pubsys_config::Error },
+
+        #[snafu(display("Could not convert {} to docker architecture: {}", arch, source))]
+        InvalidArchitecture {
+            source: oci_cli_wrapper::error::Error,
+            arch: String,
+        },
+
+        #[snafu(display("No image archive exists at path {}", path.display()))]
+        NoArchive { path: PathBuf },
+
+        #[snafu(display("No vendors specified in Infra.toml, you must specify at least one"))]
+        NoVendors,
+
+        #[snafu(display("Expected an archive as '<arch>=<path>', got '{}'", input))]
+        ParseArchive { input: String },
+
+        #[snafu(display("Could not publish image: {}", source))]
+        PublishImage {
+            source: oci_cli_wrapper::error::Error,
+        },
+
+        #[snafu(display("Vendor '{}' not specified in Infra.toml", name))]
+        VendorNotFound { name: String },
+    }
+}
+
+pub(crate) use error::Error;
+
+type Result<T> = std::result::Result<T, Error>;
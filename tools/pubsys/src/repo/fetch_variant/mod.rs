@@ -145,11 +145,13 @@ async fn fetch_variant(
         Some(ImageFormat::Raw) | None => "img.lz4",
         Some(ImageFormat::Qcow2) => "qcow2",
         Some(ImageFormat::Vmdk) => "ova",
+        Some(ImageFormat::Oci) => "oci.tar",
     };
 
     let targets = match image_format {
         // Since the OVA will contain all of the necessary VMDKs, the partition plan is irrelevant.
-        Some(ImageFormat::Vmdk) => {
+        // Container images have no data image either.
+        Some(ImageFormat::Vmdk) | Some(ImageFormat::Oci) => {
             vec![format!("{filename_prefix}.{image_ext}")]
         }
         _ => match image_layout.partition_plan {
@@ -2,6 +2,7 @@
 //! run specific `govc` commands.
 use duct::cmd;
 use log::trace;
+use oci_cli_wrapper::container_tool::ContainerTool;
 use pubsys_config::vmware::{Datacenter, DatacenterCreds};
 use snafu::ResultExt;
 use std::env;
@@ -115,7 +116,8 @@ fn docker_run(docker_env: &[&str], mount: Option<&[&str]>, command: &[&str]) ->
     args.push(&sdk);
     args.extend(command);
 
-    let output = cmd("docker", args)
+    let tool = ContainerTool::from_env().context(error::ContainerToolSnafu)?;
+    let output = cmd(tool.command(), args)
         .stderr_to_stdout()
         .stdout_capture()
         .unchecked()
@@ -163,6 +165,11 @@ pub(crate) mod error {
         #[snafu(display("Failed to start command: {}", source))]
         CommandStart { source: std::io::Error },
 
+        #[snafu(display("{}", source))]
+        ContainerTool {
+            source: oci_cli_wrapper::container_tool::UnsupportedContainerTool,
+        },
+
         #[snafu(display("Docker invocation failed: {}", output))]
         Docker { output: String },
 
@@ -167,7 +167,7 @@ pub(crate) async fn ec2_crd<'a>(
         .custom_user_data(
             bottlerocket_input
                 .crd_input
-                .encoded_userdata()?
+                .encoded_userdata(cluster_name)?
                 .map(|encoded_userdata| CustomUserData::Merge { encoded_userdata }),
         )
         .cluster_name_template(cluster_name, "clusterName")
@@ -300,7 +300,7 @@ pub(crate) async fn ec2_karpenter_crd<'a>(
         .custom_user_data(
             bottlerocket_input
                 .crd_input
-                .encoded_userdata()?
+                .encoded_userdata(cluster_name)?
                 .map(|encoded_userdata| CustomUserData::Merge { encoded_userdata }),
         )
         .cluster_name_template(cluster_name, "clusterName")
@@ -7,3 +7,8 @@ use base64::Engine;
 pub(crate) fn encode<T: AsRef<[u8]>>(input: T) -> String {
     GeneralPurpose::new(&STANDARD, GeneralPurposeConfig::default()).encode(input)
 }
+
+/// The decoding counterpart to `encode`.
+pub(crate) fn decode<T: AsRef<[u8]>>(input: T) -> Result<Vec<u8>, base64::DecodeError> {
+    GeneralPurpose::new(&STANDARD, GeneralPurposeConfig::default()).decode(input)
+}
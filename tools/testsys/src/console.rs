@@ -0,0 +1,115 @@
+use crate::base64;
+use crate::error::{self, Result};
+use aws_sdk_ec2::config::Region;
+use clap::Parser;
+use log::info;
+use regex::Regex;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::path::PathBuf;
+
+/// The kernel messages that indicate a failed boot unless other patterns are given.
+const DEFAULT_FORBIDDEN_PATTERNS: &[&str] = &["Kernel panic", "BUG: ", "Call Trace:"];
+
+/// Capture the serial console output of an EC2 instance, and check it for signs of a successful
+/// boot. This does not require a testsys cluster, so it can gate a quick boot test.
+#[derive(Debug, Parser)]
+pub(crate) struct Console {
+    /// The id of the instance whose console output should be captured.
+    #[clap(long)]
+    instance_id: String,
+
+    /// The region the instance was launched in.
+    #[clap(long, env = "TESTSYS_REGION")]
+    region: String,
+
+    /// Write the console output to this file.
+    #[clap(long)]
+    output: Option<PathBuf>,
+
+    /// A regex which must match the console output, e.g. a boot marker such as
+    /// "Reached target.*Multi-User System". May be given more than once.
+    #[clap(long = "expect")]
+    expect: Vec<Regex>,
+
+    /// A regex which must not match the console output. May be given more than once. Defaults to
+    /// common signs of a kernel panic or oops.
+    #[clap(long = "forbid")]
+    forbid: Vec<Regex>,
+
+    /// Request the most recent output rather than the buffered output, which is only supported for
+    /// instances on the Nitro system.
+    #[clap(long)]
+    latest: bool,
+}
+
+impl Console {
+    pub(crate) async fn run(self) -> Result<()> {
+        let config = aws_config::from_env()
+            .region(Region::new(self.region.clone()))
+            .load()
+            .await;
+        let ec2_client = aws_sdk_ec2::Client::new(&config);
+        let encoded = ec2_client
+            .get_console_output()
+            .instance_id(&self.instance_id)
+            .latest(self.latest)
+            .send()
+            .await
+            .map_err(|e| e.into_service_error())
+            .context(error::ConsoleOutputSnafu {
+                instance_id: &self.instance_id,
+            })?
+            .output
+            .context(error::MissingSnafu {
+                item: "output",
+                what: format!("console output of '{}'", self.instance_id),
+            })?;
+        let decoded = base64::decode(encoded).context(error::Base64DecodeSnafu {
+            what: format!("console output of '{}'", self.instance_id),
+        })?;
+        let console = String::from_utf8_lossy(&decoded);
+
+        if let Some(output) = &self.output {
+            std::fs::write(output, console.as_bytes())
+                .context(error::FileSnafu { path: output })?;
+            info!("Wrote console output to '{}'", output.display());
+        } else {
+            println!("{console}");
+        }
+
+        let forbid = if self.forbid.is_empty() {
+            DEFAULT_FORBIDDEN_PATTERNS
+                .iter()
+                .map(|pattern| Regex::new(&regex::escape(pattern)))
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .expect("default patterns are valid regexes")
+        } else {
+            self.forbid
+        };
+        let failures = check_console(&console, &self.expect, &forbid);
+        ensure!(
+            failures.is_empty(),
+            error::ConsoleAssertionSnafu {
+                instance_id: self.instance_id,
+                failures: failures.join("; "),
+            }
+        );
+        info!("Console output of '{}' passed all checks", self.instance_id);
+        Ok(())
+    }
+}
+
+/// Describes each pattern in `expect` that is missing from `console` and each pattern in
+/// `forbid` that is present.
+fn check_console(console: &str, expect: &[Regex], forbid: &[Regex]) -> Vec<String> {
+    let missing = expect
+        .iter()
+        .filter(|regex| !regex.is_match(console))
+        .map(|regex| format!("expected '{regex}' was not found"));
+    let present = forbid.iter().filter_map(|regex| {
+        regex
+            .find(console)
+            .map(|m| format!("forbidden '{regex}' was found: '{}'", m.as_str()))
+    });
+    missing.chain(present).collect()
+}
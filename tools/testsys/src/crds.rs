@@ -103,8 +103,10 @@ impl<'a> CrdInput<'a> {
             .collect())
     }
 
-    /// Use the provided userdata path to create the encoded userdata.
-    pub fn encoded_userdata(&self) -> Result<Option<String>> {
+    /// Use the provided userdata path to create the encoded userdata. The userdata is rendered as
+    /// a template with the same fields as custom CRDs, e.g. `{{cluster-name}}`, and must contain
+    /// only Bottlerocket settings so that a malformed fixture fails before any instance launches.
+    pub fn encoded_userdata(&self, cluster_name: &str) -> Result<Option<String>> {
         let userdata_path = match self.config.userdata.as_ref() {
             Some(userdata) => self.custom_userdata_file_path(userdata)?,
             None => return Ok(None),
@@ -113,10 +115,22 @@ impl<'a> CrdInput<'a> {
         info!("Using userdata at '{}'", userdata_path.display());
 
         let userdata = std::fs::read_to_string(&userdata_path).context(error::FileSnafu {
-            path: userdata_path,
+            path: &userdata_path,
         })?;
 
-        Ok(Some(base64::encode(userdata)))
+        let mut handlebars = Handlebars::new();
+        handlebars.set_strict_mode(true);
+        let rendered_userdata =
+            handlebars.render_template(&userdata, &self.config_fields(cluster_name))?;
+        if let Err(reason) = validate_userdata(&rendered_userdata) {
+            return error::InvalidUserdataSnafu {
+                path: userdata_path,
+                reason,
+            }
+            .fail();
+        }
+
+        Ok(Some(base64::encode(rendered_userdata)))
     }
 
     /// Find the userdata file for the test type
@@ -409,6 +423,17 @@ fn some_or_null(field: &Option<String>) -> String {
     field.to_owned().unwrap_or_else(|| "null".to_string())
 }
 
+/// Bottlerocket userdata is TOML in which every value lives under the `settings` table.
+fn validate_userdata(userdata: &str) -> std::result::Result<(), String> {
+    let table: toml::Table = toml::from_str(userdata).map_err(|e| e.to_string())?;
+    match table.keys().find(|key| *key != "settings") {
+        Some(key) => Err(format!(
+            "unexpected top-level key '{key}', only 'settings' is allowed"
+        )),
+        None => Ok(()),
+    }
+}
+
 /// The `CrdCreator` trait is used to create CRDs. Each variant family should have a `CrdCreator`
 /// that is responsible for creating the CRDs needed for testing.
 #[async_trait::async_trait]
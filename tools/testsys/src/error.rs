@@ -1,5 +1,6 @@
 use aws_sdk_ec2::error::SdkError;
 use aws_sdk_ec2::operation::describe_images::DescribeImagesError;
+use aws_sdk_ec2::operation::get_console_output::GetConsoleOutputError;
 use snafu::Snafu;
 use std::path::PathBuf;
 
@@ -10,12 +11,30 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     // `error` must be used instead of `source` because the build function returns
     // `std::error::Error` but not `std::error::Error + Sync + Send`.
+    #[snafu(display("Unable to decode {}: {}", what, source))]
+    Base64Decode {
+        what: String,
+        source: base64::DecodeError,
+    },
+
     #[snafu(display("Unable to build '{}': {}", what, source))]
     Build {
         what: String,
         source: Box<dyn std::error::Error + Sync + Send>,
     },
 
+    #[snafu(display("Console output of '{}' failed checks: {}", instance_id, failures))]
+    ConsoleAssertion {
+        instance_id: String,
+        failures: String,
+    },
+
+    #[snafu(display("Unable to get console output of '{}': {}", instance_id, source))]
+    ConsoleOutput {
+        instance_id: String,
+        source: GetConsoleOutputError,
+    },
+
     #[snafu(display("Unable to build datacenter credentials: {}", source))]
     CredsBuild {
         source: pubsys_config::vmware::Error,
@@ -52,6 +71,9 @@ pub enum Error {
     #[snafu(display("{}", what))]
     Invalid { what: String },
 
+    #[snafu(display("Invalid userdata '{}': {}", path.display(), reason))]
+    InvalidUserdata { path: PathBuf, reason: String },
+
     #[snafu(display("{}: {}", what, source))]
     IO {
         what: String,
@@ -1,4 +1,5 @@
 use clap::{Parser, Subcommand};
+use console::Console;
 use delete::Delete;
 use env_logger::Builder;
 use error::Result;
@@ -17,6 +18,7 @@ mod aws_ecs;
 mod aws_k8s;
 mod aws_resources;
 mod base64;
+mod console;
 mod crds;
 mod delete;
 mod error;
@@ -52,11 +54,17 @@ struct TestsysArgs {
 
 impl TestsysArgs {
     async fn run(self) -> Result<()> {
+        // Capturing the console of an instance does not involve the testsys cluster.
+        let command = match self.command {
+            Command::Console(console) => return console.run().await,
+            command => command,
+        };
         let client = match self.kubeconfig {
             Some(path) => TestManager::new_from_kubeconfig_path(&path).await?,
             None => TestManager::new().await?,
         };
-        match self.command {
+        match command {
+            Command::Console(_) => unreachable!("handled without a testsys client"),
             Command::Run(run) => run.run(client).await?,
             Command::Install(install) => install.run(client).await?,
             Command::Delete(delete) => delete.run(client).await?,
@@ -81,6 +89,7 @@ enum Command {
     RestartTest(RestartTest),
     Add(Add),
     Uninstall(Uninstall),
+    Console(Console),
 }
 
 #[tokio::main]
@@ -16,13 +16,14 @@ use pubsys_config::vmware::{
 use pubsys_config::InfraConfig;
 use serde::{Deserialize, Serialize};
 use serde_plain::{derive_display_from_serialize, derive_fromstr_from_deserialize};
-use snafu::{OptionExt, ResultExt};
+use snafu::{ensure, OptionExt, ResultExt};
 use std::fs::read_to_string;
 use std::path::PathBuf;
 use std::str::FromStr;
 use testsys_config::{GenericVariantConfig, ResourceAgentType, TestConfig};
 use testsys_model::test_manager::TestManager;
 use testsys_model::SecretName;
+use url::Url;
 
 /// Run a set of tests for a given arch and variant
 #[derive(Debug, Parser)]
@@ -112,6 +113,24 @@ pub(crate) struct Run {
     #[arg(long, env = "BUILDSYS_VERSION_IMAGE")]
     migration_target_version: Option<String>,
 
+    /// The base URL of the TUF metadata that migrations should use, e.g. the location a freshly
+    /// built repo has been uploaded to. Overrides `metadata_base_url` from Infra.toml.
+    #[arg(
+        long,
+        env = "TESTSYS_TUF_METADATA_BASE_URL",
+        requires = "tuf_targets_url"
+    )]
+    tuf_metadata_base_url: Option<Url>,
+
+    /// The URL of the TUF targets that migrations should use. Overrides `targets_url` from
+    /// Infra.toml.
+    #[arg(
+        long,
+        env = "TESTSYS_TUF_TARGETS_URL",
+        requires = "tuf_metadata_base_url"
+    )]
+    tuf_targets_url: Option<Url>,
+
     /// The template file that should be used for custom testing.
     #[arg(long = "template-file", short = 'f')]
     custom_crd_template: Option<PathBuf>,
@@ -229,7 +248,7 @@ impl Run {
         // If a lock file exists, use that, otherwise use Infra.toml or default
         let infra_config = InfraConfig::from_path_or_lock(&self.infra_config_path, true)?;
 
-        let repo_config = infra_config
+        let mut repo_config = infra_config
             .repo
             .unwrap_or_default()
             .remove(
@@ -239,6 +258,35 @@ impl Run {
                     .unwrap_or_else(|| "default".to_string()),
             )
             .unwrap_or_default();
+        if let (Some(metadata_base_url), Some(targets_url)) =
+            (self.tuf_metadata_base_url, self.tuf_targets_url)
+        {
+            info!(
+                "Using TUF metadata from '{}' and targets from '{}'",
+                metadata_base_url, targets_url
+            );
+            repo_config.metadata_base_url = Some(metadata_base_url);
+            repo_config.targets_url = Some(targets_url);
+        }
+
+        // A migration between identical versions would pass without exercising any migrations.
+        if matches!(
+            resolved_test_type,
+            TestType::Known(KnownTestType::Migration)
+        ) {
+            ensure!(
+                self.migration_starting_version.is_none()
+                    || self.migration_starting_version != self.migration_target_version,
+                error::InvalidSnafu {
+                    what: format!(
+                        "The migration starting version and target version are both '{}'",
+                        self.migration_starting_version
+                            .as_deref()
+                            .unwrap_or_default()
+                    ),
+                }
+            );
+        }
 
         let images = vec![
             Some(self.agent_images.into()),
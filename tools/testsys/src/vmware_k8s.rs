@@ -218,7 +218,7 @@ impl CrdCreator for VmwareK8sCreator {
             .custom_user_data(
                 bottlerocket_input
                     .crd_input
-                    .encoded_userdata()?
+                    .encoded_userdata(cluster_name)?
                     .map(|encoded_userdata| CustomUserData::Merge { encoded_userdata }),
             )
             .assume_role(bottlerocket_input.crd_input.config.agent_role.clone())
@@ -32,6 +32,7 @@ fn main() {
     paths.copy_file("docker-go");
     paths.copy_file("img2img");
     paths.copy_file("imghelper");
+    paths.copy_file("kitabi");
     paths.copy_file("partyplanner");
     paths.copy_file("rpm2img");
     paths.copy_file("rpm2kit");
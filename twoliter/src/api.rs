@@ -0,0 +1,248 @@
+//! Async entry points for resolving a project's kits, fetching them and extracting them, for code
+//! which drives twoliter from its own runtime rather than through the command line, such as a
+//! service reporting build preparation on a dashboard.
+//!
+//! Each call reports its progress to a callback, including how many bytes of each kit have been
+//! pulled and extracted, and stops early with an error once its [`CancellationToken`] is
+//! cancelled. Calls are cancel-safe: kit archives are pulled beside the cache and moved into place
+//! when complete, and extracted kits are only marked as such once unpacked, so a cancelled call
+//! leaves nothing behind that a later call would mistake for a finished result. Dropping a call's
+//! future is equally safe. Processes a call started, such as the `docker load` of the SDK, are
+//! killed when it is cancelled. Pulls made with krane run within this process and cannot be
+//! interrupted, so they finish in the background, and the blobs they download are kept for the
+//! next pull; with `TWOLITER_IMAGE_TOOL=native`, pulls stop as soon as the call is cancelled.
+use crate::host;
+use crate::output::{Output, OutputSchema, PROGRESS_SCHEMA};
+use crate::project::cache::CacheReport;
+use crate::project::{self, ExtractOptions, Locked, Project};
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tracing::{debug, info};
+
+/// A step completed by a call.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "event")]
+pub enum Progress {
+    /// The kits that the call will work on have been decided.
+    KitsResolved { kits: Vec<String> },
+    /// `bytes` of the `total` that `kit` transfers in `stage` have been transferred.
+    KitTransfer {
+        kit: String,
+        stage: TransferStage,
+        bytes: u64,
+        total: u64,
+    },
+    /// `kit`, the `done`th of `total` kits, has been pulled or extracted.
+    KitReady {
+        kit: String,
+        done: usize,
+        total: usize,
+    },
+    /// The project's SDK has been loaded into the docker daemon.
+    SdkReady { sdk: String },
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Progress::KitsResolved { kits } => write!(f, "Preparing {} kit(s)", kits.len()),
+            Progress::KitTransfer {
+                kit,
+                stage,
+                bytes,
+                total,
+            } => write!(f, "Kit '{kit}': {stage} {bytes} of {total} bytes"),
+            Progress::KitReady { kit, done, total } => {
+                write!(f, "[{done}/{total}] Kit '{kit}' is ready")
+            }
+            Progress::SdkReady { sdk } => write!(f, "SDK '{sdk}' is ready"),
+        }
+    }
+}
+
+impl Output for Progress {
+    const SCHEMA: OutputSchema = PROGRESS_SCHEMA;
+}
+
+/// What a kit's bytes are being transferred for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransferStage {
+    /// Pulling the kit's archive into the cache.
+    Pull,
+    /// Unpacking the kit's layers.
+    Extract,
+}
+
+impl Display for TransferStage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferStage::Pull => write!(f, "pull"),
+            TransferStage::Extract => write!(f, "extract"),
+        }
+    }
+}
+
+/// Receives the progress of a call.
+pub type ProgressFn = dyn Fn(&Progress) + Send + Sync;
+
+/// Logs each step, for callers with nowhere better to report progress.
+pub(crate) fn log_progress(progress: &Progress) {
+    match progress {
+        // Transfers are reported several times a second, which is too often to log.
+        Progress::KitTransfer { .. } => debug!("{progress}"),
+        _ => info!("{progress}"),
+    }
+}
+
+/// Signals calls sharing the token to stop. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Cancellation>,
+}
+
+#[derive(Debug, Default)]
+struct Cancellation {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels every call using this token, now or in the future.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes once the token is cancelled.
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Runs `work` until it completes or the token is cancelled, whichever happens first.
+    async fn run<T>(&self, work: impl Future<Output = Result<T>>) -> Result<T> {
+        if self.is_cancelled() {
+            bail!("cancelled");
+        }
+        tokio::select! {
+            result = work => result,
+            _ = self.cancelled() => bail!("cancelled"),
+        }
+    }
+}
+
+/// A project whose kits and SDK have been resolved against Twoliter.lock.
+#[derive(Debug)]
+pub struct ResolvedProject(Project<Locked>);
+
+impl ResolvedProject {
+    pub(crate) fn project(&self) -> &Project<Locked> {
+        &self.0
+    }
+}
+
+/// Loads the project at `project_path`, or found by searching from the current directory, and
+/// resolves its kits and SDK against Twoliter.lock.
+pub async fn resolve(
+    project_path: Option<PathBuf>,
+    cancel: &CancellationToken,
+) -> Result<ResolvedProject> {
+    cancel
+        .run(async {
+            let project = project::load_or_find_project(project_path).await?;
+            project.load_lock::<Locked>().await.map(ResolvedProject)
+        })
+        .await
+}
+
+/// Pulls the project's kits for `arch`, or only those needed by `variant`, into the kit archive
+/// cache without extracting them, and loads its SDK.
+pub async fn fetch(
+    project: &ResolvedProject,
+    arch: &str,
+    variant: Option<&str>,
+    cancel: &CancellationToken,
+    progress: &ProgressFn,
+) -> Result<CacheReport> {
+    let project = project.project();
+    cancel
+        .run(async {
+            let mut report = project.pull_kits(arch, variant, progress).await?;
+            report.extend(fetch_sdk(project, progress).await?);
+            Ok(report)
+        })
+        .await
+}
+
+/// Pulls and extracts the project's kits for `arch`, or only those needed by `variant`, up to
+/// `jobs` at once, and loads its SDK.
+pub async fn extract(
+    project: &ResolvedProject,
+    arch: &str,
+    variant: Option<&str>,
+    options: ExtractOptions,
+    jobs: usize,
+    cancel: &CancellationToken,
+    progress: &ProgressFn,
+) -> Result<CacheReport> {
+    let project = project.project();
+    cancel
+        .run(async {
+            let mut report = project
+                .fetch_kits(arch, variant, options, jobs, progress)
+                .await?;
+            report.extend(fetch_sdk(project, progress).await?);
+            Ok(report)
+        })
+        .await
+}
+
+async fn fetch_sdk(project: &Project<Locked>, progress: &ProgressFn) -> Result<CacheReport> {
+    // The SDK is only used by builds, so there is no need to load it where they cannot run.
+    if !host::BUILDS_SUPPORTED {
+        debug!("Skipping the SDK, since builds cannot run on this host");
+        return Ok(CacheReport::default());
+    }
+    let report = project.fetch_sdk().await?;
+    progress(&Progress::SdkReady {
+        sdk: project.sdk_image().project_image_uri().to_string(),
+    });
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancellation() {
+        let cancel = CancellationToken::new();
+        let pending = cancel.run(std::future::pending::<Result<()>>());
+        let canceller = cancel.clone();
+        let (result, ()) = tokio::join!(pending, async move { canceller.cancel() });
+        assert!(result.is_err());
+
+        let result = cancel.run(async { Ok(()) }).await;
+        assert!(result.is_err());
+    }
+}
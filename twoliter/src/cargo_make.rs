@@ -1,5 +1,6 @@
 use crate::common::{exec_log, BUILDSYS_OUTPUT_GENERATION_ID};
 use anyhow::{bail, Result};
+use krane_static::settings;
 use std::path::PathBuf;
 use tokio::process::Command;
 use tracing::trace;
@@ -138,18 +139,27 @@ impl CargoMake {
 
 fn build_system_env_vars() -> Result<Vec<String>> {
     let mut args = Vec::new();
-    for (key, val) in std::env::vars() {
+    for (key, val) in build_system_env()? {
+        trace!("Passing env var {} to cargo make", key);
+        args.push("-e".to_string());
+        args.push(format!("{}={}", key, val));
+    }
+    Ok(args)
+}
+
+/// The environment variables which need to be passed to `cargo make`, with their values.
+pub(crate) fn build_system_env() -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (key, val) in settings::vars() {
         if is_build_system_env(key.as_str()) {
-            trace!("Passing env var {} to cargo make", key);
-            args.push("-e".to_string());
-            args.push(format!("{}={}", key, val));
+            vars.push((key.clone(), val));
         }
 
         // To avoid confusion, environment variables whose values have been moved to
         // Twoliter.toml are expressly disallowed here.
         check_for_disallowed_var(&key)?;
     }
-    Ok(args)
+    Ok(vars)
 }
 
 /// A list of environment variables that don't conform to naming conventions but need to be passed
@@ -171,8 +181,12 @@ const ENV_VARS: &[&str] = &[
     "HTTP_PROXY",
     "MARK_OVA_AS_TEMPLATE",
     "NO_PROXY",
+    "PIP_INDEX_URL",
+    "PIP_TRUSTED_HOST",
     "RELEASE_START_TIME",
     "SSM_DATA_FILE_SUFFIX",
+    "TWOLITER_CA_BUNDLE",
+    "TWOLITER_CONTAINER_TOOL",
     "VMWARE_IMPORT_SPEC_PATH",
     "VMWARE_VM_NAME_DEFAULT",
     "http_proxy",
@@ -185,10 +199,11 @@ const DISALLOWED_ENV_VARS: &[&str] = &[
     "BUILDSYS_SDK_VERSION",
     "BUILDSYS_REGISTRY",
     "BUILDSYS_OUTPUT_GENERATION_ID",
+    "BUILDSYS_STEPS_KEY",
 ];
 
 /// Returns `true` if `key` is an environment variable that needs to be passed to `cargo make`.
-fn is_build_system_env(key: impl AsRef<str>) -> bool {
+pub(crate) fn is_build_system_env(key: impl AsRef<str>) -> bool {
     let key = key.as_ref();
     key.starts_with("BUILDSYS_")
         || key.starts_with("PUBLISH_")
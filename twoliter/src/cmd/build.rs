@@ -1,13 +1,26 @@
 use super::build_clean::BuildClean;
 use crate::cargo_make::CargoMake;
 use crate::common::fs;
-use crate::project::{self, Locked};
+use crate::container;
+use crate::docker::{BuilderTls, Docker};
+use crate::multi_arch;
+use crate::project::{
+    self, BuildOptions, BuildTarget, CpuQuota, Locked, PriorityConfig, STEPS_KEY_ENV,
+};
 use crate::tools::install_tools;
 use anyhow::{Context, Result};
+use buildsys_config::FAILURE_LOG_PREFIX;
 use clap::Parser;
-use std::path::PathBuf;
+use serde::Deserialize;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use tempfile::TempDir;
 
+/// Makes cargo log the reason each package's build script is rerun, e.g. a changed input file or
+/// environment variable. Packages and kits are built by these build scripts, so this explains
+/// every package that could not be reused from a previous build.
+const EXPLAIN_CACHE_CARGO_LOG: &str = "cargo::core::compiler::fingerprint=info";
+
 #[derive(Debug, Parser)]
 pub(crate) enum BuildCommand {
     Clean(BuildClean),
@@ -47,12 +60,51 @@ pub(crate) struct BuildKit {
     /// from the upstream URL found in a package's `Cargo.toml`.
     #[clap(long = "upstream-source-fallback")]
     pub(crate) upstream_source_fallback: bool,
+
+    /// Report whether the SDK could be reused from the local cache, and have cargo explain why
+    /// each package needed to be rebuilt.
+    #[clap(long = "explain-cache")]
+    pub(crate) explain_cache: bool,
+
+    #[clap(flatten)]
+    pub(crate) failure_mode: FailureMode,
+
+    #[clap(flatten)]
+    pub(crate) priority: PriorityOptions,
+
+    /// Build the kit for every architecture, each on the native builder named for it in the
+    /// `native-builder` section of Twoliter.toml, or else on this host, and check that the builds
+    /// can be published together.
+    #[clap(long = "multi-arch")]
+    pub(crate) multi_arch: bool,
+
+    /// Publish the kit to this vendor once every architecture is built.
+    #[clap(long = "publish", requires = "multi_arch")]
+    pub(crate) publish: Option<String>,
 }
 
 impl BuildKit {
     pub(super) async fn run(&self) -> Result<()> {
         let project = project::load_or_find_project(self.project_path.clone()).await?;
+        if self.multi_arch {
+            let args = std::env::args().skip(1).collect::<Vec<_>>();
+            return multi_arch::build_kit(&project, &self.kit, &args, self.publish.as_deref())
+                .await;
+        }
         let project = project.load_lock::<Locked>().await?;
+        project
+            .sparse_checkout(&[Path::new("kits").join(&self.kit)])
+            .await?;
+        let options = BuildOptions {
+            lookaside_cache: self.lookaside_cache.clone(),
+            upstream_source_fallback: self.upstream_source_fallback,
+            keep_going: self.failure_mode.keep_going(),
+            ..Default::default()
+        };
+        let target = BuildTarget::Kit(self.kit.clone());
+        project
+            .record_build_info(target.clone(), &self.arch, options)
+            .await?;
         let toolsdir = project.project_dir().join("build/tools");
         install_tools(&toolsdir).await?;
         let makefile_path = toolsdir.join("Makefile.toml");
@@ -60,11 +112,24 @@ impl BuildKit {
         let mut optional_envs = Vec::new();
 
         if let Some(lookaside_cache) = &self.lookaside_cache {
-            optional_envs.push(("BUILDSYS_LOOKASIDE_CACHE", lookaside_cache))
+            optional_envs.push(("BUILDSYS_LOOKASIDE_CACHE", lookaside_cache.to_string()))
         }
 
-        project.fetch_sdk().await?;
-        CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
+        optional_envs.extend(self.failure_mode.keep_going_env());
+        optional_envs.extend(container::build_env(project.project_dir()).await?);
+        project.apply_priority(&self.priority.config(), &mut optional_envs)?;
+        // Held until the build ends, when the secrets are removed.
+        let secrets = project.resolve_secrets().await?;
+        optional_envs.extend(secrets.as_ref().map(|secrets| secrets.env()));
+        optional_envs.extend(project.module_proxy_env(&project.default_cargo_home())?);
+
+        let sdk_report = project.fetch_sdk().await?;
+        optional_envs.extend(project.run_steps().await?.map(|key| (STEPS_KEY_ENV, key)));
+        if self.explain_cache {
+            println!("{sdk_report}");
+            optional_envs.push(("CARGO_LOG", EXPLAIN_CACHE_CARGO_LOG.to_string()));
+        }
+        let build = CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
             .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
             .env("BUILDSYS_ARCH", &self.arch)
             .env("BUILDSYS_KIT", &self.kit)
@@ -76,9 +141,13 @@ impl BuildKit {
             )
             .envs(optional_envs.into_iter())
             .makefile(makefile_path)
-            .project_dir(project.project_dir())
-            .exec("build-kit")
-            .await
+            .project_dir(project.project_dir());
+        let building =
+            with_failure_summary(project.project_dir(), &self.arch, build.exec("build-kit"));
+        project
+            .timed(&target.step_id(&self.arch), &self.arch, building)
+            .await?;
+        project.check_kit_budget(&self.kit, &self.arch).await
     }
 }
 
@@ -87,33 +156,61 @@ impl BuildKit {
 pub(crate) struct BuildVariant {
     /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
     #[clap(long = "project-path")]
-    project_path: Option<PathBuf>,
+    pub(crate) project_path: Option<PathBuf>,
 
     /// The architecture to build for.
     #[clap(long = "arch", default_value = "x86_64")]
-    arch: String,
+    pub(crate) arch: String,
 
     /// The variant to build.
-    variant: String,
+    pub(crate) variant: String,
 
     /// The URL to the lookaside cache where sources are stored to avoid pulling them from upstream.
     /// Defaults to https://cache.bottlerocket.aws
-    lookaside_cache: Option<String>,
+    pub(crate) lookaside_cache: Option<String>,
 
     /// If sources are not found in the lookaside cache, this flag will cause buildsys to pull them
     /// from the upstream URL found in a package's `Cargo.toml`.
     #[clap(long = "upstream-source-fallback")]
-    upstream_source_fallback: bool,
+    pub(crate) upstream_source_fallback: bool,
+
+    /// Report whether the SDK could be reused from the local cache, and have cargo explain why
+    /// each package needed to be rebuilt.
+    #[clap(long = "explain-cache")]
+    pub(crate) explain_cache: bool,
+
+    #[clap(flatten)]
+    pub(crate) failure_mode: FailureMode,
+
+    #[clap(flatten)]
+    pub(crate) priority: PriorityOptions,
+
+    #[clap(flatten)]
+    pub(crate) builder: BuilderOptions,
 
     /// Path to the Infra.toml file
     #[clap(long)]
-    infra_toml: Option<PathBuf>,
+    pub(crate) infra_toml: Option<PathBuf>,
 }
 
 impl BuildVariant {
     pub(super) async fn run(&self) -> Result<()> {
         let project = project::load_or_find_project(self.project_path.clone()).await?;
         let project = project.load_lock::<Locked>().await?;
+        project
+            .sparse_checkout(&[Path::new("variants").join(&self.variant)])
+            .await?;
+        let options = BuildOptions {
+            lookaside_cache: self.lookaside_cache.clone(),
+            upstream_source_fallback: self.upstream_source_fallback,
+            keep_going: self.failure_mode.keep_going(),
+            builder: self.builder.builder.clone(),
+            infra_toml: self.infra_toml.clone(),
+        };
+        let target = BuildTarget::Variant(self.variant.clone());
+        project
+            .record_build_info(target.clone(), &self.arch, options)
+            .await?;
         let toolsdir = project.project_dir().join("build/tools");
         install_tools(&toolsdir).await?;
         let makefile_path = toolsdir.join("Makefile.toml");
@@ -136,8 +233,22 @@ impl BuildVariant {
             ))
         }
 
-        project.fetch_sdk().await?;
-        CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
+        optional_envs.extend(self.failure_mode.keep_going_env());
+        optional_envs.extend(container::build_env(project.project_dir()).await?);
+        project.apply_priority(&self.priority.config(), &mut optional_envs)?;
+        // Held until the build ends, when the secrets are removed.
+        let secrets = project.resolve_secrets().await?;
+        optional_envs.extend(secrets.as_ref().map(|secrets| secrets.env()));
+        optional_envs.extend(project.module_proxy_env(&project.default_cargo_home())?);
+        optional_envs.extend(self.builder.builder_env().await?);
+
+        let sdk_report = project.fetch_sdk().await?;
+        optional_envs.extend(project.run_steps().await?.map(|key| (STEPS_KEY_ENV, key)));
+        if self.explain_cache {
+            println!("{sdk_report}");
+            optional_envs.push(("CARGO_LOG", EXPLAIN_CACHE_CARGO_LOG.to_string()));
+        }
+        let build = CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
             .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
             .env("BUILDSYS_ARCH", &self.arch)
             .env("BUILDSYS_VARIANT", &self.variant)
@@ -149,8 +260,205 @@ impl BuildVariant {
             )
             .envs(optional_envs.into_iter())
             .makefile(makefile_path)
-            .project_dir(project.project_dir())
-            .exec("build")
+            .project_dir(project.project_dir());
+        let building = with_failure_summary(project.project_dir(), &self.arch, build.exec("build"));
+        project
+            .timed(&target.step_id(&self.arch), &self.arch, building)
+            .await?;
+        project
+            .check_variant_budget(&self.variant, &self.arch)
             .await
     }
 }
+
+/// How a build reacts to a package that fails to build.
+#[derive(Debug, Default, clap::Args)]
+pub(crate) struct FailureMode {
+    /// Keep building every package which does not depend on a failed one, so that all failures
+    /// are surfaced in one run.
+    #[clap(long = "keep-going", overrides_with = "fail_fast")]
+    pub(crate) keep_going: bool,
+
+    /// Start no new package builds once one has failed. This is the default unless the
+    /// BUILDSYS_KEEP_GOING environment variable is set to 'true'.
+    #[clap(long = "fail-fast", overrides_with = "keep_going")]
+    pub(crate) fail_fast: bool,
+}
+
+impl FailureMode {
+    /// Whether to keep going after a package fails to build, if chosen on the command line.
+    pub(crate) fn keep_going(&self) -> Option<bool> {
+        (self.keep_going || self.fail_fast).then_some(self.keep_going)
+    }
+
+    /// The value of `BUILDSYS_KEEP_GOING` chosen on the command line, if any.
+    fn keep_going_env(&self) -> Option<(&'static str, String)> {
+        self.keep_going()
+            .map(|keep_going| ("BUILDSYS_KEEP_GOING", keep_going.to_string()))
+    }
+}
+
+/// How much of a shared host a build may take, overriding the `priority` section of
+/// Twoliter.toml.
+#[derive(Debug, Default, clap::Args)]
+pub(crate) struct PriorityOptions {
+    /// Run the build at this niceness, from 0 to 19, the lowest priority, so that it yields the
+    /// CPU and disk to interactive work.
+    #[clap(long = "nice", value_parser = clap::value_parser!(u8).range(0..=19))]
+    pub(crate) nice: Option<u8>,
+
+    /// How many CPUs the build may keep busy, e.g. 4 or 2.5, by limiting how many packages build
+    /// at once and how many jobs each runs.
+    #[clap(long = "cpu-quota")]
+    pub(crate) cpu_quota: Option<CpuQuota>,
+
+    /// The cgroup to run build containers under, e.g. a systemd slice whose CPU and I/O limits the
+    /// host enforces.
+    #[clap(long = "cgroup-parent")]
+    pub(crate) cgroup_parent: Option<String>,
+}
+
+impl PriorityOptions {
+    fn config(&self) -> PriorityConfig {
+        PriorityConfig {
+            nice: self.nice,
+            cpu_quota: self.cpu_quota,
+            cgroup_parent: self.cgroup_parent.clone(),
+        }
+    }
+}
+
+/// Where variant images are assembled.
+#[derive(Debug, Default, clap::Args)]
+pub(crate) struct BuilderOptions {
+    /// The docker buildx builder to assemble the variant's images with, instead of the docker
+    /// daemon's own. The builder must share the host's network, e.g. a buildkit daemon started
+    /// with host networking and the network.host entitlement, since builds stream files through
+    /// sockets on the host.
+    #[clap(long = "builder")]
+    pub(crate) builder: Option<String>,
+
+    /// The address of a remote buildkit daemon, e.g. tcp://buildkit.example.com:1234. The builder
+    /// is created for it, replacing any builder of the same name.
+    #[clap(long = "builder-endpoint", requires = "builder")]
+    pub(crate) endpoint: Option<String>,
+
+    /// The CA certificate to verify the remote buildkit daemon's certificate against.
+    #[clap(long = "builder-tls-ca", requires = "endpoint")]
+    pub(crate) tls_ca: Option<PathBuf>,
+
+    /// The client certificate to authenticate to the remote buildkit daemon with.
+    #[clap(long = "builder-tls-cert", requires_all = ["endpoint", "tls_key"])]
+    pub(crate) tls_cert: Option<PathBuf>,
+
+    /// The key of the client certificate.
+    #[clap(long = "builder-tls-key", requires_all = ["endpoint", "tls_cert"])]
+    pub(crate) tls_key: Option<PathBuf>,
+
+    /// The name to verify the remote buildkit daemon's certificate against, if not the
+    /// endpoint's host.
+    #[clap(long = "builder-tls-server-name", requires = "endpoint")]
+    pub(crate) tls_server_name: Option<String>,
+}
+
+impl BuilderOptions {
+    /// The value of `BUILDSYS_BUILDER` chosen on the command line, if any, creating the builder
+    /// first when an endpoint was given.
+    async fn builder_env(&self) -> Result<Option<(&'static str, String)>> {
+        let Some(builder) = &self.builder else {
+            return Ok(None);
+        };
+        if let Some(endpoint) = &self.endpoint {
+            let tls = BuilderTls {
+                ca: absolute(self.tls_ca.as_deref())?,
+                cert: absolute(self.tls_cert.as_deref())?,
+                key: absolute(self.tls_key.as_deref())?,
+                server_name: self.tls_server_name.clone(),
+            };
+            Docker::create_remote_builder(builder, endpoint, &tls).await?;
+        }
+        Ok(Some(("BUILDSYS_BUILDER", builder.clone())))
+    }
+}
+
+/// Resolves `path` so that it still refers to the same file when read by docker.
+fn absolute(path: Option<&Path>) -> Result<Option<PathBuf>> {
+    path.map(|path| {
+        std::fs::canonicalize(path).context(format!("Unable to find '{}'", path.display()))
+    })
+    .transpose()
+}
+
+/// A failed package build, as recorded by buildsys.
+#[derive(Debug, Deserialize)]
+struct FailedPackage {
+    package: String,
+    owner: Option<String>,
+    error: String,
+}
+
+/// Runs `build`, and if it fails, adds a summary of every package that failed to build for `arch`
+/// to the error.
+async fn with_failure_summary(
+    project_dir: &Path,
+    arch: &str,
+    build: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    let failure_log = project_dir
+        .join("build/state")
+        .join(format!("{FAILURE_LOG_PREFIX}-{arch}.jsonl"));
+    if failure_log.exists() {
+        fs::remove_file(&failure_log).await?;
+    }
+    let Err(e) = build.await else {
+        return Ok(());
+    };
+    let Ok(log) = fs::read_to_string(&failure_log).await else {
+        return Err(e);
+    };
+    match failure_summary(&log, arch) {
+        Some(summary) => Err(e.context(summary)),
+        None => Err(e),
+    }
+}
+
+/// Summarizes the failed package builds in `log`, or returns `None` if there were none.
+fn failure_summary(log: &str, arch: &str) -> Option<String> {
+    let failures = log
+        .lines()
+        .filter_map(|line| serde_json::from_str::<FailedPackage>(line).ok())
+        .collect::<Vec<_>>();
+    if failures.is_empty() {
+        return None;
+    }
+    let mut summary = format!("{} package(s) failed to build for {arch}:", failures.len());
+    for failure in failures {
+        let owner = failure
+            .owner
+            .map(|owner| format!(" (owner: {owner})"))
+            .unwrap_or_default();
+        let error = failure.error.lines().next().unwrap_or_default();
+        summary.push_str(&format!("\n  {}{owner}: {error}", failure.package));
+    }
+    Some(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_failure_summary() {
+        let log = concat!(
+            r#"{"package":"pkg-a","owner":"team-a","arch":"aarch64","error":"build failed"}"#,
+            "\n",
+            r#"{"package":"pkg-b","owner":null,"arch":"aarch64","error":"exit 1\nmore"}"#,
+            "\n",
+        );
+        assert_eq!(
+            failure_summary(log, "aarch64").unwrap(),
+            "2 package(s) failed to build for aarch64:\n  pkg-a (owner: team-a): build failed\n  pkg-b: exit 1"
+        );
+        assert_eq!(failure_summary("", "aarch64"), None);
+    }
+}
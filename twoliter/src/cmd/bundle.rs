@@ -0,0 +1,45 @@
+use crate::project::{self, Locked};
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+pub(crate) enum BundleCommand {
+    Runner(Runner),
+}
+
+impl BundleCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            BundleCommand::Runner(command) => command.run().await,
+        }
+    }
+}
+
+/// Build a container image that CI jobs can run the project's builds from. It holds this twoliter
+/// binary and its tools on top of the project's SDK, with the images it uses pinned by digest.
+#[derive(Debug, Parser)]
+pub(crate) struct Runner {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The tag to give the runner image.
+    #[clap(long = "tag")]
+    tag: String,
+
+    /// Write the image to this path as an OCI archive, rather than loading it into the docker
+    /// daemon. Requires docker buildx.
+    #[clap(long = "output")]
+    output: Option<PathBuf>,
+}
+
+impl Runner {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<Locked>().await?;
+        project
+            .bundle_runner(&self.tag, self.output.as_deref())
+            .await
+    }
+}
@@ -0,0 +1,94 @@
+use crate::output::{self, OutputFormat};
+use crate::project::shared_cache::{parse_age, GcPolicy, SharedCache};
+use crate::project::{self, cache::CacheStats, watch_kits, ByteSize};
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tracing::info;
+
+#[derive(Debug, Parser)]
+pub(crate) enum CacheCommand {
+    Stats(Stats),
+    Gc(Gc),
+    Watch(Watch),
+}
+
+impl CacheCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            CacheCommand::Stats(command) => command.run().await,
+            CacheCommand::Gc(command) => command.run().await,
+            CacheCommand::Watch(command) => command.run().await,
+        }
+    }
+}
+
+/// Summarize the kit archives and extracted kits cached in the build directory.
+#[derive(Debug, Parser)]
+pub(crate) struct Stats {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// How to print the summary.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+impl Stats {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let stats = CacheStats::collect(project.external_kits_dir()).await?;
+        output::print(self.format, &stats)
+    }
+}
+
+/// Remove blobs from the cache of image blobs shared by all projects, those used least recently
+/// first. The cache is at `$TWOLITER_CACHE_DIR`, or else under `$XDG_CACHE_HOME/twoliter`.
+#[derive(Debug, Parser)]
+pub(crate) struct Gc {
+    /// Remove blobs until the rest take up at most this much, e.g. `20GiB`.
+    #[clap(long = "max-size", required_unless_present = "max_age")]
+    max_size: Option<ByteSize>,
+
+    /// Remove blobs which have not been used for this long, e.g. `30d` or `12h`.
+    #[clap(long = "max-age", value_parser = parse_age)]
+    max_age: Option<Duration>,
+
+    /// How to print what was removed.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+impl Gc {
+    pub(super) async fn run(&self) -> Result<()> {
+        let shared_cache = SharedCache::configured()
+            .context("the shared cache is turned off, or its location is unknown")?;
+        let policy = GcPolicy {
+            max_size: self.max_size.map(|max_size| max_size.0),
+            max_age: self.max_age,
+        };
+        let report =
+            tokio::task::spawn_blocking(move || shared_cache.gc(&policy, SystemTime::now()))
+                .await
+                .context("failed to join the task collecting garbage")??;
+        output::print(self.format, &report)
+    }
+}
+
+/// Watch the kits extracted into the build directory until interrupted, and mark any kit whose
+/// files are changed as stale, so that the next fetch or build extracts it again.
+#[derive(Debug, Parser)]
+pub(crate) struct Watch {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+}
+
+impl Watch {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        watch_kits(&project.external_kits_dir()).await
+    }
+}
@@ -0,0 +1,57 @@
+use crate::common::fs::write;
+use crate::project;
+use anyhow::Result;
+use clap::Parser;
+use semver::Version;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Draft the changelog of a kit release from the packages that changed since a previously
+/// published version of the kit.
+#[derive(Debug, Parser)]
+pub(crate) struct Changelog {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The kit to draft the changelog of, which must have been built.
+    #[clap(long = "kit")]
+    kit: String,
+
+    /// The vendor which published the previous version of the kit.
+    #[clap(long = "vendor")]
+    vendor: String,
+
+    /// The previous version of the kit to compare with.
+    #[clap(long = "previous")]
+    previous: Version,
+
+    /// The architecture whose packages to compare.
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// Write the changelog to this file instead of printing it.
+    #[clap(long = "output")]
+    output: Option<PathBuf>,
+}
+
+impl Changelog {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let changelog = project
+            .changelog(&self.kit, &self.vendor, &self.arch, &self.previous)
+            .await?;
+        match &self.output {
+            Some(path) => {
+                write(path, changelog.to_string()).await?;
+                info!(
+                    "Wrote the changelog of '{}' to '{}'",
+                    self.kit,
+                    path.display()
+                );
+            }
+            None => print!("{changelog}"),
+        }
+        Ok(())
+    }
+}
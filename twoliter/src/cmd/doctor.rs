@@ -0,0 +1,83 @@
+use crate::container::ContainerEnvironment;
+use crate::docker::Docker;
+use crate::host::{self, DockerVm};
+use crate::preflight;
+use crate::telemetry::{self, TELEMETRY_ENDPOINT_ENV};
+use anyhow::{bail, Result};
+use clap::Parser;
+use krane_static::settings;
+use std::path::PathBuf;
+
+/// Check that builds can run in this environment, and report how they would be adjusted to it,
+/// e.g. when running inside a container or on a CI runner.
+#[derive(Debug, Parser)]
+pub(crate) struct Doctor {
+    /// The project directory whose builds to check. Defaults to the current directory.
+    #[clap(long = "project-dir")]
+    project_dir: Option<PathBuf>,
+}
+
+impl Doctor {
+    pub(super) async fn run(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        let project_dir = match &self.project_dir {
+            Some(project_dir) => project_dir.clone(),
+            None => std::env::current_dir()?,
+        };
+
+        println!("Host");
+        let wsl = if host::is_wsl() { " (WSL)" } else { "" };
+        println!("  {} {}{wsl}", std::env::consts::OS, std::env::consts::ARCH);
+        match Docker::tool() {
+            Ok(tool) => {
+                if let Some(vm) = DockerVm::detect().await {
+                    println!("  {tool} runs in {vm}");
+                }
+            }
+            Err(e) => problems.push(e.to_string()),
+        }
+        if let Err(e) = host::ensure_builds_supported(&project_dir).await {
+            problems.push(e.to_string());
+        }
+
+        println!("\nTools");
+        match preflight::check_environment().await {
+            Ok(()) => println!("  ok"),
+            Err(e) => {
+                println!("  {e}");
+                problems.push(e.to_string());
+            }
+        }
+
+        let environment = ContainerEnvironment::detect().await;
+        println!("\nContainer environment");
+        println!("{environment}");
+
+        let adjustments = environment.adjustments(&project_dir);
+        if !adjustments.is_empty() {
+            println!("\nAdjustments to builds");
+            for adjustment in &adjustments {
+                println!(
+                    "  {}={}: {}",
+                    adjustment.env, adjustment.value, adjustment.reason
+                );
+            }
+        }
+
+        println!("\nTelemetry");
+        match telemetry::endpoint(|key| settings::var(key).ok()) {
+            Some(endpoint) => println!("  on, sending anonymous usage events to '{endpoint}'"),
+            None => println!("  off, set {TELEMETRY_ENDPOINT_ENV} to opt in"),
+        }
+
+        problems.extend(environment.problems());
+        if !problems.is_empty() {
+            println!("\nProblems");
+            for problem in &problems {
+                println!("  - {problem}");
+            }
+            bail!("{} problem(s) found", problems.len());
+        }
+        Ok(())
+    }
+}
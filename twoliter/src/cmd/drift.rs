@@ -0,0 +1,49 @@
+use crate::output::{self, OutputFormat};
+use crate::project;
+use anyhow::{bail, Result};
+use clap::Parser;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+/// Compare the packages in a variant's images across architectures, and fail if any differ.
+#[derive(Debug, Parser)]
+pub(crate) struct Drift {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The variant whose images to compare.
+    #[clap(long = "variant")]
+    variant: String,
+
+    /// The architectures whose latest builds of the variant to compare.
+    #[clap(long = "arch", default_values = ["x86_64", "aarch64"])]
+    arches: Vec<String>,
+
+    /// A package that is expected to differ between architectures, such as a firmware package
+    /// which only exists for one of them.
+    #[clap(long = "allow")]
+    allow: Vec<String>,
+
+    /// How to print the report.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+impl Drift {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let allowed = self.allow.iter().cloned().collect::<BTreeSet<_>>();
+        let report = project
+            .drift_report(&self.variant, &self.arches, &allowed)
+            .await?;
+        output::print(self.format, &report)?;
+        if !report.divergences.is_empty() {
+            bail!(
+                "{} package(s) drifted between architectures",
+                report.divergences.len()
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use crate::output::{self, OutputFormat};
+use crate::project::{self, Setting, SettingSource};
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, Parser};
+use krane_static::settings;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Print the project's effective configuration after Twoliter.toml, the selected profile,
+/// environment variables and command-line flags are layered, with where each value came from.
+/// Credentials are redacted, so that the output can be attached to bug reports.
+#[derive(Debug, Parser)]
+pub(crate) struct Env {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// How to print the configuration.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+impl Env {
+    pub(super) async fn run(&self) -> Result<()> {
+        // Loading the project sets variables from it, which are told apart from the user's by
+        // comparing against the environment beforehand.
+        let original_env = settings::vars().into_iter().collect::<BTreeMap<_, _>>();
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let mut config = project.effective_config(&original_env).await?;
+        config.flags = global_flags()?;
+        output::print(self.format, &config)
+    }
+}
+
+/// The global flags which have a value, and whether it was given on the command line, taken from
+/// the environment, or defaulted.
+fn global_flags() -> Result<Vec<Setting>> {
+    let command = super::Args::command();
+    let matches = command
+        .clone()
+        .try_get_matches_from(std::env::args_os())
+        .context("failed to parse the command line")?;
+    let mut flags = Vec::new();
+    for arg in command.get_arguments() {
+        let (Some(long), Some(source)) =
+            (arg.get_long(), matches.value_source(arg.get_id().as_str()))
+        else {
+            continue;
+        };
+        let source = match source {
+            ValueSource::CommandLine => SettingSource::CommandLine,
+            ValueSource::EnvVariable => SettingSource::Environment,
+            _ => SettingSource::Default,
+        };
+        let value = matches
+            .get_raw(arg.get_id().as_str())
+            .into_iter()
+            .flatten()
+            .map(|value| value.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(",");
+        flags.push(Setting {
+            key: format!("--{long}"),
+            value,
+            source,
+        });
+    }
+    Ok(flags)
+}
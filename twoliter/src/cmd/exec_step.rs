@@ -0,0 +1,84 @@
+use crate::cmd::{Args, Subcommand};
+use crate::common::fs::read_to_string;
+use crate::output;
+use crate::project::{self, BuildPlan, Locked};
+use anyhow::{bail, ensure, Context, Result};
+use clap::Parser;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Run one step of a plan printed by `twoliter plan --format json`. The outputs of the steps it
+/// depends on must already be in the project.
+#[derive(Debug, Parser)]
+pub(crate) struct ExecStep {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The plan holding the step.
+    #[clap(long = "plan")]
+    plan: PathBuf,
+
+    /// The id of the step to run.
+    step: String,
+}
+
+impl ExecStep {
+    pub(super) async fn run(&self) -> Result<()> {
+        let plan: BuildPlan = output::from_json(&read_to_string(&self.plan).await?)
+            .context(format!("failed to read plan '{}'", self.plan.display()))?;
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<Locked>().await?;
+        ensure!(
+            project.lock_digest().await? == plan.lock_digest,
+            "Twoliter.lock has changed since the plan '{}' was made; make a new plan with \
+            `twoliter plan`",
+            self.plan.display()
+        );
+
+        let step = plan.step(&self.step).context(format!(
+            "the plan '{}' has no step '{}'",
+            self.plan.display(),
+            self.step
+        ))?;
+        let missing = step
+            .depends_on
+            .iter()
+            .filter_map(|id| plan.step(id))
+            .flat_map(|dependency| {
+                dependency
+                    .outputs
+                    .iter()
+                    .filter(|output| !project.project_dir().join(output).exists())
+                    .map(move |output| format!("{output} (from '{}')", dependency.id))
+            })
+            .collect::<Vec<_>>();
+        ensure!(
+            missing.is_empty(),
+            "step '{}' needs the outputs of the steps it depends on, which are missing:\n  - {}",
+            step.id,
+            missing.join("\n  - ")
+        );
+
+        // Steps are run from the project directory, so that their paths are the same in every job.
+        std::env::set_current_dir(project.project_dir()).context(format!(
+            "failed to change to the project directory '{}'",
+            project.project_dir().display()
+        ))?;
+        info!(
+            "Running step '{}': twoliter {}",
+            step.id,
+            step.command.join(" ")
+        );
+        let args = Args::try_parse_from(
+            std::iter::once("twoliter").chain(step.command.iter().map(String::as_str)),
+        )
+        .context(format!("step '{}' is not a twoliter command", step.id))?;
+        match args.subcommand {
+            Subcommand::Build(command) => command.run().await,
+            Subcommand::Fetch(command) => command.run().await,
+            Subcommand::Publish(command) => command.run().await,
+            _ => bail!("step '{}' is not a kind of step that plans have", step.id),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use crate::diagnostic::Code;
+use anyhow::Result;
+use clap::Parser;
+
+/// Explain an error code, such as E0203, and how to fix the problem it describes. Lists all error
+/// codes when no code is given.
+#[derive(Debug, Parser)]
+pub(crate) struct Explain {
+    /// The error code, e.g. E0203, or its name, e.g. missing-kit-metadata.
+    code: Option<Code>,
+}
+
+impl Explain {
+    pub(super) async fn run(&self) -> Result<()> {
+        match self.code {
+            Some(code) => println!("{code}\n\n{}", code.explanation()),
+            None => {
+                for code in Code::ALL {
+                    println!("{code}");
+                }
+            }
+        }
+        Ok(())
+    }
+}
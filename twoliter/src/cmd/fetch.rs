@@ -1,6 +1,10 @@
-use crate::project::{self, Locked};
-use anyhow::Result;
+use crate::api::{self, CancellationToken};
+use crate::progress::{ProgressMode, ProgressReporter};
+use crate::project::{default_extract_jobs, Attributes, ExtractOptions};
+use anyhow::{ensure, Context, Result};
 use clap::Parser;
+use krane_static::settings;
+use oci_cli_wrapper::layout::OCI_DIR_ENV;
 use std::path::PathBuf;
 
 #[derive(Debug, Parser)]
@@ -12,14 +16,101 @@ pub(crate) struct Fetch {
     /// Architecture of images to fetch
     #[clap(long = "arch", default_value = "x86_64")]
     pub(crate) arch: String,
+
+    /// Only fetch the kits needed to build this variant, rather than every kit in Twoliter.lock
+    #[clap(long = "variant")]
+    pub(crate) variant: Option<String>,
+
+    /// Only pull each kit's archive into the cache, leaving extraction to a later fetch or build.
+    /// Lets later steps run offline without spending time or disk space on extraction now.
+    #[clap(long = "no-extract")]
+    pub(crate) no_extract: bool,
+
+    /// How to apply the ownership, permissions and extended attributes recorded in each kit:
+    /// `normalize` them so extraction gives the same result as any user on any filesystem,
+    /// `preserve` them, which requires root, or normalize them but give files to `map:<uid>:<gid>`.
+    #[clap(long = "attributes", default_value_t, conflicts_with = "no_extract")]
+    pub(crate) attributes: Attributes,
+
+    /// How many kits to pull and extract at once. Defaults to the number of CPUs.
+    #[clap(
+        long,
+        short = 'j',
+        default_value_t = default_extract_jobs(),
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    pub(crate) jobs: usize,
+
+    /// Report whether each kit and the SDK could be reused from the local cache, and if not, why
+    #[clap(long = "explain-cache")]
+    pub(crate) explain_cache: bool,
+
+    /// How to show progress: `auto` draws progress bars when stderr is a terminal and logs
+    /// otherwise, `plain` always logs, and `json` prints each event as a line of JSON on stdout
+    #[clap(long = "progress", value_enum, default_value_t)]
+    pub(crate) progress: ProgressMode,
+
+    /// Read every image from the OCI layouts in `--oci-dir` rather than from registries, for hosts
+    /// which cannot reach them. Fails if any image in Twoliter.lock is missing from the directory
+    /// or differs from the digest locked.
+    #[clap(long = "offline", requires = "oci_dir")]
+    pub(crate) offline: bool,
+
+    /// A directory holding an OCI image layout for each repository at `<registry>/<repository>`,
+    /// as written by `twoliter vendor` or `skopeo copy --all docker://<image> oci:<dir>/<image>`.
+    #[clap(long = "oci-dir", requires = "offline")]
+    pub(crate) oci_dir: Option<PathBuf>,
 }
 
 impl Fetch {
     pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let project = project.load_lock::<Locked>().await?;
-        project.fetch_kits(self.arch.as_str()).await?;
-        project.fetch_sdk().await?;
+        if let Some(oci_dir) = self.oci_dir.as_ref().filter(|_| self.offline) {
+            ensure!(
+                oci_dir.is_dir(),
+                "the OCI layout directory '{}' does not exist",
+                oci_dir.display()
+            );
+            // Everything which reads images picks the directory up from the run's settings.
+            let oci_dir = oci_dir.to_str().context(format!(
+                "the OCI layout directory '{}' is not valid UTF-8",
+                oci_dir.display()
+            ))?;
+            settings::set(OCI_DIR_ENV, oci_dir);
+        }
+        let cancel = CancellationToken::new();
+        let resolved = api::resolve(self.project_path.clone(), &cancel).await?;
+        let project = resolved.project();
+        let (arch, variant) = (self.arch.as_str(), self.variant.as_deref());
+        let reporter = ProgressReporter::new(self.progress);
+        let fetching = async {
+            if self.no_extract {
+                api::fetch(&resolved, arch, variant, &cancel, &|progress| {
+                    reporter.report(progress)
+                })
+                .await
+            } else {
+                let options = ExtractOptions {
+                    attributes: self.attributes,
+                };
+                api::extract(
+                    &resolved,
+                    arch,
+                    variant,
+                    options,
+                    self.jobs,
+                    &cancel,
+                    &|progress| reporter.report(progress),
+                )
+                .await
+            }
+        };
+        let report = project
+            .timed(&format!("fetch:{arch}"), arch, fetching)
+            .await?;
+        reporter.finish();
+        if self.explain_cache {
+            println!("{report}");
+        }
         Ok(())
     }
 }
@@ -0,0 +1,95 @@
+use crate::import::LegacyImport;
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tracing::info;
+
+#[derive(Debug, Parser)]
+pub(crate) enum ImportCommand {
+    Legacy(Legacy),
+}
+
+impl ImportCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            ImportCommand::Legacy(command) => command.run().await,
+        }
+    }
+}
+
+/// Convert a checkout of a Bottlerocket build from before kits, with its packages and variants in
+/// their own workspaces, into a new twoliter project whose packages are built into kits.
+#[derive(Debug, Parser)]
+pub(crate) struct Legacy {
+    /// The checkout to convert, which is left as it is.
+    source: PathBuf,
+
+    /// Where to create the project.
+    #[clap(long = "output")]
+    output: PathBuf,
+
+    /// The vendor to publish the project's kits as.
+    #[clap(long = "vendor")]
+    vendor: String,
+
+    /// The registry the vendor publishes to.
+    #[clap(long = "registry")]
+    registry: String,
+
+    /// The kit to build the packages into.
+    #[clap(long = "kit", default_value = "core-kit")]
+    kit: String,
+
+    /// Build a package into another kit instead, given as `<package>=<kit>`, where the package is
+    /// named by its directory. May be given more than once.
+    #[clap(long = "package-kit", value_parser = parse_package_kit)]
+    package_kits: Vec<(String, String)>,
+
+    /// The version of the Bottlerocket SDK to build with, when it cannot be read from the checkout.
+    #[clap(long = "sdk-version")]
+    sdk_version: Option<String>,
+
+    /// The project's release version, when the checkout has no Release.toml to read it from.
+    #[clap(long = "release-version")]
+    release_version: Option<String>,
+}
+
+impl Legacy {
+    pub(super) async fn run(&self) -> Result<()> {
+        let report = LegacyImport {
+            source: self.source.clone(),
+            output: self.output.clone(),
+            vendor: self.vendor.clone(),
+            registry: self.registry.clone(),
+            kit: self.kit.clone(),
+            package_kits: self
+                .package_kits
+                .iter()
+                .cloned()
+                .collect::<BTreeMap<_, _>>(),
+            sdk_version: self.sdk_version.clone(),
+            release_version: self.release_version.clone(),
+        }
+        .run()
+        .await?;
+        for (kit, packages) in &report.kits {
+            info!("Kit '{kit}' builds {} package(s)", packages.len());
+        }
+        info!(
+            "Created a project with {} variant(s) in '{}'. Review the kits, then run \
+            `twoliter update` there to create Twoliter.lock.",
+            report.variants.len(),
+            self.output.display()
+        );
+        Ok(())
+    }
+}
+
+fn parse_package_kit(value: &str) -> Result<(String, String)> {
+    let (package, kit) = value
+        .split_once('=')
+        .filter(|(package, kit)| !package.is_empty() && !kit.is_empty())
+        .context(format!("'{value}' is not of the form <package>=<kit>"))?;
+    Ok((package.to_string(), kit.to_string()))
+}
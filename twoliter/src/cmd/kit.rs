@@ -0,0 +1,127 @@
+use crate::output::{self, OutputFormat};
+use crate::project::{self, assemble_kit, kit_consumers, ConsumerSources};
+use anyhow::{ensure, Result};
+use clap::Parser;
+use semver::Version;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+pub(crate) enum KitCommand {
+    Assemble(Assemble),
+    Consumers(Consumers),
+    Layers(Layers),
+}
+
+impl KitCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            KitCommand::Assemble(command) => command.run().await,
+            KitCommand::Consumers(command) => command.run().await,
+            KitCommand::Layers(command) => command.run().await,
+        }
+    }
+}
+
+/// Combine per-architecture kit images, such as those built and pushed natively on separate
+/// machines, into the kit's manifest list. The images must be builds of the same kit, one per
+/// architecture.
+#[derive(Debug, Parser)]
+pub(crate) struct Assemble {
+    /// A per-architecture image of the kit, e.g.
+    /// `public.ecr.aws/bottlerocket/my-kit:v1.0.0-abc-x86_64`. Pass once per architecture.
+    #[clap(long = "image", required = true)]
+    images: Vec<String>,
+
+    /// Where to push the manifest list, e.g. `public.ecr.aws/bottlerocket/my-kit:v1.0.0`.
+    #[clap(long = "target")]
+    target: String,
+
+    /// Check the images without pushing the manifest list.
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+
+    /// How to print the report.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+impl Assemble {
+    pub(super) async fn run(&self) -> Result<()> {
+        let report = assemble_kit(&self.images, &self.target, self.dry_run).await?;
+        output::print(self.format, &report)
+    }
+}
+
+/// List the published kits and the projects which depend on a kit, read from the metadata
+/// embedded in the kits and from the projects' Twoliter.lock.
+#[derive(Debug, Parser)]
+pub(crate) struct Consumers {
+    /// The name of the kit whose consumers to find.
+    #[clap(long = "kit")]
+    kit: String,
+
+    /// Only report consumers of this version of the kit.
+    #[clap(long = "version")]
+    version: Option<Version>,
+
+    /// A registry namespace whose kits to scan, e.g. `public.ecr.aws/bottlerocket`. The registry
+    /// must support listing its repositories.
+    #[clap(long = "namespace")]
+    namespaces: Vec<String>,
+
+    /// A repository whose kits to scan, e.g. `public.ecr.aws/bottlerocket/bottlerocket-core-kit`.
+    #[clap(long = "repository")]
+    repositories: Vec<String>,
+
+    /// A project to check, as the path to its Twoliter.toml or the directory holding it.
+    #[clap(long = "project")]
+    projects: Vec<PathBuf>,
+
+    /// How to print the report.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+impl Consumers {
+    pub(super) async fn run(&self) -> Result<()> {
+        let sources = ConsumerSources {
+            namespaces: self.namespaces.clone(),
+            repositories: self.repositories.clone(),
+            projects: self.projects.clone(),
+        };
+        ensure!(
+            !sources.namespaces.is_empty()
+                || !sources.repositories.is_empty()
+                || !sources.projects.is_empty(),
+            "nothing to scan; pass at least one --namespace, --repository or --project"
+        );
+        let report = kit_consumers(&self.kit, self.version.as_ref(), &sources).await?;
+        output::print(self.format, &report)
+    }
+}
+
+/// Report which packages land in each layer of the kits built and fetched by the project, which
+/// packages are duplicated across kits, and how the kits could be restructured so that registries
+/// and clients share more of their layers.
+#[derive(Debug, Parser)]
+pub(crate) struct Layers {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The architecture whose kits to report on.
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// How to print the report.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+impl Layers {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let report = project.layer_report(&self.arch)?;
+        output::print(self.format, &report)
+    }
+}
@@ -0,0 +1,70 @@
+use crate::output::{self, OutputFormat};
+use crate::project;
+use crate::project::lint::{self, Lint as LintName, LintLevel};
+use anyhow::{bail, Result};
+use clap::Parser;
+use std::path::PathBuf;
+
+/// Check Twoliter.toml and the project's kits and variants for likely mistakes.
+#[derive(Debug, Parser)]
+pub(crate) struct Lint {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Skip a lint, overriding Twoliter.toml.
+    #[clap(long = "allow")]
+    allow: Vec<LintName>,
+
+    /// Report a lint as a warning, overriding Twoliter.toml.
+    #[clap(long = "warn")]
+    warn: Vec<LintName>,
+
+    /// Report a lint as an error, overriding Twoliter.toml.
+    #[clap(long = "deny")]
+    deny: Vec<LintName>,
+
+    /// Rewrite kits referenced by version requirements in Twoliter.toml to the versions they
+    /// resolve to now, before linting.
+    #[clap(long = "fix")]
+    fix: bool,
+
+    /// How to print the findings.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+impl Lint {
+    pub(super) async fn run(&self) -> Result<()> {
+        let mut project = project::load_or_find_project(self.project_path.clone()).await?;
+        if self.fix {
+            let fixes = project.fix_mutable_references().await?;
+            if !fixes.is_empty() {
+                if self.format == OutputFormat::Text {
+                    println!("{}", fixes.join("\n"));
+                }
+                project = project::load_or_find_project(Some(project.filepath())).await?;
+            }
+        }
+        let mut config = project.lint_config().clone();
+        for (lints, level) in [
+            (&self.allow, LintLevel::Allow),
+            (&self.warn, LintLevel::Warn),
+            (&self.deny, LintLevel::Deny),
+        ] {
+            for lint in lints {
+                config.set(*lint, level);
+            }
+        }
+
+        let report = lint::lint(&project, &config).await?;
+        if self.format == OutputFormat::Json || !report.findings.is_empty() {
+            output::print(self.format, &report)?;
+        }
+        let errors = report.errors();
+        if errors > 0 {
+            bail!("{errors} lint error(s) found");
+        }
+        Ok(())
+    }
+}
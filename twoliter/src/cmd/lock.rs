@@ -0,0 +1,83 @@
+use crate::output::{self, OutputFormat};
+use crate::project::{self, DEFAULT_RESOLVE_JOBS};
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+use tracing::info;
+
+#[derive(Debug, Parser)]
+pub(crate) enum LockCommand {
+    Verify(VerifyLock),
+    Migrate(MigrateLock),
+}
+
+impl LockCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            LockCommand::Verify(command) => command.run().await,
+            LockCommand::Migrate(command) => command.run().await,
+        }
+    }
+}
+
+/// Check that registries still serve the images recorded in Twoliter.lock, without building
+/// anything. Fails if a registry serves a different image than the one locked, such as when a tag
+/// was moved, or if an image cannot be fetched from any of the places it was locked from.
+#[derive(Debug, Parser)]
+pub(crate) struct VerifyLock {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// How to print the result of each check.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// How many images to fetch from their registries at once.
+    #[clap(
+        long,
+        short = 'j',
+        default_value_t = DEFAULT_RESOLVE_JOBS,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    jobs: usize,
+}
+
+impl VerifyLock {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let check = project.check_lock(self.jobs).await?;
+        output::print(self.format, &check)?;
+        check.ensure_verified()
+    }
+}
+
+/// Rewrite a Twoliter.lock written by an older Twoliter in the current format, without changing
+/// what it locks. Other commands read an older lock as it is, and leave it alone. Fails if the
+/// lock no longer matches Twoliter.toml, which `twoliter update` fixes.
+#[derive(Debug, Parser)]
+pub(crate) struct MigrateLock {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// How many kits to resolve against their registries at once.
+    #[clap(
+        long,
+        short = 'j',
+        default_value_t = DEFAULT_RESOLVE_JOBS,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    jobs: usize,
+}
+
+impl MigrateLock {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        match project.migrate_lock(self.jobs).await? {
+            Some(version) => info!("Migrated Twoliter.lock from schema version {version}"),
+            None => info!("Twoliter.lock is already in the current format"),
+        }
+        Ok(())
+    }
+}
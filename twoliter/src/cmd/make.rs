@@ -1,9 +1,19 @@
-use crate::cargo_make::CargoMake;
-use crate::project::{self, Locked, SDKLocked, Unlocked};
+use crate::cargo_make::{build_system_env, CargoMake};
+use crate::common::exec_log;
+use crate::container;
+use crate::emulation::{self, shell_quote};
+use crate::host;
+use crate::project::{
+    self, ApprovalPolicy, ApprovalRequest, Locked, SDKLocked, Unlocked, PROMOTION_TASKS,
+};
 use crate::tools::install_tools;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
+use krane_static::settings;
+use std::collections::BTreeSet;
 use std::path::PathBuf;
+use tokio::process::Command;
+use tracing::info;
 
 // Most subcommands do not require kits and thus do not need to resolve and verify them against the
 // lockfile.
@@ -17,12 +27,21 @@ use std::path::PathBuf;
 const MUST_VALIDATE_KITS_TARGETS: &[&str] = &[
     "build-package",
     "build-kit",
+    "build-source-offer",
+    "check-kit-abi",
     "build-variant",
     "build-all",
     "build",
     "default",
 ];
 
+/// The Makefile which `twoliter make` runs, installed with the tools. It is read to find what a task
+/// does, rather than trusting its name.
+const MAKEFILE: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/embedded/Makefile.toml"
+));
+
 /// Run a cargo make command in Twoliter's build environment. Known Makefile.toml environment
 /// variables will be passed-through to the cargo make invocation.
 #[derive(Debug, Parser)]
@@ -43,6 +62,16 @@ pub(crate) struct Make {
     #[clap(long, env = "BUILDSYS_ARCH")]
     arch: String,
 
+    /// Register QEMU emulation with binfmt_misc if `arch` is foreign to this host and emulation is
+    /// not registered yet. This runs a privileged container.
+    #[clap(long, env = "TWOLITER_CONFIGURE_EMULATION")]
+    configure_emulation: bool,
+
+    /// A signed approval of the task, for tasks which publish a kit or promote a variant when
+    /// Twoliter.toml requires publishes to be approved. May be given more than once.
+    #[clap(long = "approval")]
+    approvals: Vec<PathBuf>,
+
     /// Cargo make task. E.g. the word "build" if we want to execute `cargo make build`.
     makefile_task: String,
 
@@ -54,6 +83,19 @@ pub(crate) struct Make {
 impl Make {
     pub(super) async fn run(&self) -> Result<()> {
         let project = project::load_or_find_project(self.project_path.clone()).await?;
+        self.check_approval(&project).await?;
+
+        // Hand the build to a native worker if one is configured and it can't run well here.
+        if self.is_build_task() && (!host::BUILDS_SUPPORTED || emulation::is_foreign(&self.arch)) {
+            if let Some(worker) = emulation::native_worker(&self.arch) {
+                return self.delegate(&worker, &project).await;
+            }
+        }
+        let toolsdir = project.project_dir().join("build/tools");
+        host::ensure_builds_supported(&toolsdir).await?;
+        if self.is_build_task() && emulation::is_foreign(&self.arch) {
+            emulation::ensure_emulation(&self.arch, self.configure_emulation).await?;
+        }
 
         let sdk_source = if self.can_skip_kit_verification(&project) {
             let project = project.load_lock::<SDKLocked>().await?;
@@ -67,19 +109,147 @@ impl Make {
         .project_image_uri()
         .to_string();
 
-        let toolsdir = project.project_dir().join("build/tools");
+        let mut container_envs = if self.is_build_task() {
+            container::build_env(project.project_dir()).await?
+        } else {
+            Vec::new()
+        };
+        // Held until the build ends, when the secrets are removed.
+        let secrets = if self.is_build_task() {
+            project.resolve_secrets().await?
+        } else {
+            None
+        };
+        container_envs.extend(secrets.as_ref().map(|secrets| secrets.env()));
+        container_envs.extend(project.module_proxy_env(&self.cargo_home)?);
+
         install_tools(&toolsdir).await?;
         let makefile_path = toolsdir.join("Makefile.toml");
         CargoMake::new(&sdk_source)?
             .env("CARGO_HOME", self.cargo_home.display().to_string())
             .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
             .env("BUILDSYS_VERSION_IMAGE", project.release_version())
+            .envs(container_envs.into_iter())
             .makefile(makefile_path)
             .project_dir(project.project_dir())
             .exec_with_args(&self.makefile_task, self.additional_args.clone())
             .await
     }
 
+    /// Checks that the task is approved, if it publishes a kit or promotes a variant and the project
+    /// requires that to be approved. What the task does is read from the pubsys operations it runs,
+    /// itself or through the tasks it depends on, and what is published from the same environment
+    /// variables that those operations read.
+    async fn check_approval(&self, project: &project::Project<Unlocked>) -> Result<()> {
+        for operation in pubsys_operations(MAKEFILE, &self.makefile_task)? {
+            if let Some((policy, request)) = self.approval_request(project, &operation).await? {
+                policy
+                    .check(&project.project_dir(), &request, &self.approvals)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The approval policy and request for the pubsys `operation`, if it publishes a kit or
+    /// promotes a variant and the project requires that to be approved.
+    async fn approval_request(
+        &self,
+        project: &project::Project<Unlocked>,
+        operation: &str,
+    ) -> Result<Option<(ApprovalPolicy, ApprovalRequest)>> {
+        let task = &self.makefile_task;
+        if PROMOTION_TASKS.contains(&operation) {
+            let Some(policy) = project.promotion_approval() else {
+                return Ok(None);
+            };
+            let variant = settings::var("BUILDSYS_VARIANT").context(format!(
+                "set BUILDSYS_VARIANT to the variant that '{task}' promotes"
+            ))?;
+            let target = settings::var("SSM_TARGET")
+                .ok()
+                .filter(|_| operation == "promote-ssm");
+            let request = ApprovalRequest::promote_variant(
+                &project.project_dir(),
+                project.release_version(),
+                operation,
+                &variant,
+                &self.arch,
+                settings::var("SSM_SOURCE").ok(),
+                target,
+            )
+            .await;
+            Ok(Some((policy.clone(), request)))
+        } else if operation == "publish-kit" {
+            let vendor = settings::var("PUBLISH_VENDOR").context(format!(
+                "set PUBLISH_VENDOR to the vendor that '{task}' publishes to"
+            ))?;
+            let Some(policy) = project.publish_metadata_for(&vendor).approval else {
+                return Ok(None);
+            };
+            let kit = settings::var("BUILDSYS_KIT").context(format!(
+                "set BUILDSYS_KIT to the kit that '{task}' publishes"
+            ))?;
+            let repository = settings::var("PUBLISH_KIT_REPO").unwrap_or(kit.clone());
+            let request = ApprovalRequest::publish_kit(
+                &project.project_dir(),
+                &kit,
+                project.release_version(),
+                &vendor,
+                &repository,
+            )
+            .await?;
+            Ok(Some((policy, request)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Whether the task builds packages, kits or variants for `arch`.
+    fn is_build_task(&self) -> bool {
+        MUST_VALIDATE_KITS_TARGETS.contains(&self.makefile_task.as_str())
+    }
+
+    /// Runs this command on the native `worker` instead, passing along the build system
+    /// environment. See the `emulation` module for what the worker needs.
+    async fn delegate(&self, worker: &str, project: &project::Project<Unlocked>) -> Result<()> {
+        info!(
+            "Delegating '{}' for '{}' to the native worker '{worker}'",
+            self.makefile_task, self.arch
+        );
+        exec_log(
+            Command::new("ssh").arg(worker).arg("--").args(
+                self.remote_args(project)?
+                    .iter()
+                    .map(|arg| shell_quote(arg)),
+            ),
+        )
+        .await
+    }
+
+    fn remote_args(&self, project: &project::Project<Unlocked>) -> Result<Vec<String>> {
+        let mut args = vec!["env".to_string()];
+        args.extend(
+            build_system_env()?
+                .into_iter()
+                .map(|(key, value)| format!("{key}={value}")),
+        );
+        args.extend([
+            "twoliter".to_string(),
+            "make".to_string(),
+            "--project-path".to_string(),
+            project.filepath().display().to_string(),
+            "--cargo-home".to_string(),
+            self.cargo_home.display().to_string(),
+            "--arch".to_string(),
+            self.arch.clone(),
+            self.makefile_task.clone(),
+            "--".to_string(),
+        ]);
+        args.extend(self.additional_args.iter().cloned());
+        Ok(args)
+    }
+
     fn can_skip_kit_verification(&self, project: &project::Project<Unlocked>) -> bool {
         let target_allows_kit_verification_skip =
             !MUST_VALIDATE_KITS_TARGETS.contains(&self.makefile_task.as_str());
@@ -89,12 +259,85 @@ impl Make {
     }
 }
 
+/// The pubsys operations which `task` in `makefile` runs, itself or through the tasks it depends on,
+/// extends, aliases or runs.
+fn pubsys_operations(makefile: &str, task: &str) -> Result<BTreeSet<String>> {
+    let makefile: toml::Table =
+        toml::from_str(makefile).context("failed to parse Makefile.toml")?;
+    let tasks = makefile
+        .get("tasks")
+        .and_then(toml::Value::as_table)
+        .context("Makefile.toml has no tasks")?;
+    let mut operations = BTreeSet::new();
+    let mut seen = BTreeSet::new();
+    let mut pending = vec![task.to_string()];
+    while let Some(name) = pending.pop() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+        let Some(task) = tasks.get(&name).and_then(toml::Value::as_table) else {
+            continue;
+        };
+        for key in ["alias", "extend", "dependencies", "run_task"] {
+            pending.extend(task.get(key).into_iter().flat_map(task_names));
+        }
+        let scripts = task.get("script").into_iter().flat_map(strings);
+        operations.extend(scripts.flat_map(|script| script_pubsys_operations(&script)));
+    }
+    Ok(operations)
+}
+
+/// The task names in a Makefile.toml value naming other tasks: a name, a list of names, or a table
+/// with the names under `name`, as `run_task` takes.
+fn task_names(value: &toml::Value) -> Vec<String> {
+    match value {
+        toml::Value::Table(table) => table.get("name").map(strings).unwrap_or_default(),
+        value => strings(value),
+    }
+}
+
+/// The strings in a Makefile.toml value which is a string or a list of strings.
+fn strings(value: &toml::Value) -> Vec<String> {
+    match value {
+        toml::Value::String(value) => vec![value.clone()],
+        toml::Value::Array(values) => values
+            .iter()
+            .filter_map(toml::Value::as_str)
+            .map(ToString::to_string)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The pubsys subcommands which a task script runs. The subcommand is the first bare word after
+/// `pubsys`, since pubsys' own options all take a value.
+fn script_pubsys_operations(script: &str) -> Vec<String> {
+    script
+        .replace("\\\n", " ")
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next()? != "pubsys" {
+                return None;
+            }
+            words
+                .find(|word| {
+                    !word.starts_with('-')
+                        && word
+                            .chars()
+                            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                })
+                .map(ToString::to_string)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use std::path::Path;
 
     use crate::cmd::update::Update;
-    use crate::project::VerificationTagger;
+    use crate::project::{VerificationTagger, DEFAULT_RESOLVE_JOBS};
 
     use super::*;
 
@@ -193,11 +436,64 @@ mod test {
         assert_eq!(args.additional_args[8], "--");
     }
 
+    #[test]
+    fn test_pubsys_operations() {
+        let operations = |task| pubsys_operations(MAKEFILE, task).unwrap();
+        assert_eq!(operations("ssm"), BTreeSet::from(["ssm".to_string()]));
+        assert_eq!(
+            operations("promote-ssm"),
+            BTreeSet::from(["promote-ssm".to_string()])
+        );
+        assert_eq!(
+            operations("publish-kit"),
+            BTreeSet::from(["publish-kit".to_string()])
+        );
+        assert!(operations("build").is_empty());
+        assert!(operations("no-such-task").is_empty());
+    }
+
+    #[test]
+    fn test_pubsys_operations_through_other_tasks() {
+        let makefile = r#"
+[tasks.release]
+dependencies = ["check"]
+run_task = { name = ["announce", "publish"] }
+
+[tasks.check]
+script = ["echo ok"]
+
+[tasks.publish]
+script = [
+'''
+pubsys \
+   --log-level "${PUBLISH_LOG_LEVEL}" \
+   \
+   promote-ssm \
+   --target "${SSM_TARGET}"
+'''
+]
+
+[tasks.default]
+alias = "release"
+"#;
+        assert_eq!(
+            pubsys_operations(makefile, "default").unwrap(),
+            BTreeSet::from(["promote-ssm".to_string()])
+        );
+    }
+
     const PROJECT: &str = "local-kit";
 
     async fn twoliter_update(project_path: &Path) {
         let command = Update {
             project_path: Some(project_path.to_path_buf()),
+            dry_run: false,
+            commit: false,
+            branch: None,
+            format: Default::default(),
+            jobs: DEFAULT_RESOLVE_JOBS,
+            kit: vec![],
+            sdk: false,
         };
         command.run().await.unwrap();
     }
@@ -252,8 +548,10 @@ mod test {
             project_path: Some(project_path),
             cargo_home: project_dir.to_owned(),
             arch: "x86_64".to_string(),
+            configure_emulation: false,
             makefile_task: target_name.to_string(),
             additional_args: Vec::new(),
+            approvals: Vec::new(),
         };
         make.can_skip_kit_verification(&project)
     }
@@ -1,21 +1,71 @@
 mod build;
 mod build_clean;
+mod bundle;
+mod cache;
+mod changelog;
 mod debug;
+mod doctor;
+mod drift;
+mod env;
+mod exec_step;
+mod explain;
 mod fetch;
+mod import;
+mod kit;
+mod lint;
+mod lock;
 mod make;
+mod new;
+mod plan;
+mod prepare;
+mod provenance;
+mod proxy;
 mod publish_kit;
+mod rebuild;
+mod resolve;
+mod schema;
+mod step;
+mod store;
 mod update;
+mod vendor;
+mod version;
 
 use self::build::BuildCommand;
+use self::bundle::BundleCommand;
+use self::cache::CacheCommand;
+use crate::cmd::changelog::Changelog;
 use crate::cmd::debug::DebugAction;
+use crate::cmd::doctor::Doctor;
+use crate::cmd::drift::Drift;
+use crate::cmd::env::Env;
+use crate::cmd::exec_step::ExecStep;
+use crate::cmd::explain::Explain;
 use crate::cmd::fetch::Fetch;
+use crate::cmd::import::ImportCommand;
+use crate::cmd::kit::KitCommand;
+use crate::cmd::lint::Lint;
+use crate::cmd::lock::LockCommand;
 use crate::cmd::make::Make;
+use crate::cmd::new::New;
+use crate::cmd::plan::Plan;
+use crate::cmd::prepare::Prepare;
+use crate::cmd::provenance::Provenance;
+use crate::cmd::proxy::ProxyCommand;
 use crate::cmd::publish_kit::PublishCommand;
+use crate::cmd::rebuild::Rebuild;
+use crate::cmd::resolve::Resolve;
+use crate::cmd::schema::SchemaCommand;
+use crate::cmd::step::StepCommand;
+use crate::cmd::store::StoreCommand;
 use crate::cmd::update::Update;
-use anyhow::Result;
-use clap::Parser;
+use crate::cmd::vendor::Vendor;
+use crate::cmd::version::VersionCommand;
+use crate::project::PROFILE_ENV;
+use crate::warnings;
+use anyhow::{bail, Result};
+use clap::{Parser, ValueEnum};
 use env_logger::Builder;
-use log::LevelFilter;
+use log::{warn, LevelFilter};
 
 const DEFAULT_LEVEL_FILTER: LevelFilter = LevelFilter::Info;
 
@@ -29,6 +79,22 @@ pub(crate) struct Args {
     #[clap(long = "log-level")]
     pub(crate) log_level: Option<LevelFilter>,
 
+    /// Fail the run when it raises warnings of the given kind, e.g. `--deny warnings` for strict
+    /// CI, or `--deny deprecations` to only fail on deprecated dependencies.
+    #[clap(long = "deny", value_enum, global = true)]
+    pub(crate) deny: Vec<Deny>,
+
+    /// Run the command on a remote Linux build host instead, given as an ssh destination. The
+    /// project is synced to the host, which must have twoliter installed, and the build outputs
+    /// are copied back.
+    #[clap(long = "remote", env = "TWOLITER_REMOTE", global = true)]
+    pub(crate) remote: Option<String>,
+
+    /// Apply the settings of this profile from the `profile` section of Twoliter.toml, e.g.
+    /// `--profile release` for locked-down release builds.
+    #[clap(long = "profile", env = PROFILE_ENV, global = true)]
+    pub(crate) profile: Option<String>,
+
     #[clap(subcommand)]
     pub(crate) subcommand: Subcommand,
 }
@@ -39,13 +105,94 @@ pub(crate) enum Subcommand {
     #[clap(subcommand)]
     Build(BuildCommand),
 
+    /// Package the project's build environment, such as a container image to run builds from.
+    #[clap(subcommand)]
+    Bundle(BundleCommand),
+
+    /// Inspect the artifacts Twoliter caches in the build directory.
+    #[clap(subcommand)]
+    Cache(CacheCommand),
+
+    /// Draft the changelog of a kit release.
+    Changelog(Changelog),
+
+    /// Check that builds can run in this environment, such as inside a CI container.
+    Doctor(Doctor),
+
+    /// Compare the packages in a variant's images across architectures.
+    Drift(Drift),
+
+    /// Print the effective configuration of the project, with where each value came from.
+    Env(Env),
+
+    /// Run one step of a plan printed by `twoliter plan`.
+    ExecStep(ExecStep),
+
+    /// Explain an error code and how to fix it.
+    Explain(Explain),
+
     Fetch(Fetch),
 
+    /// Convert other kinds of Bottlerocket builds into twoliter projects.
+    #[clap(subcommand)]
+    Import(ImportCommand),
+
+    /// Query kits published to registries.
+    #[clap(subcommand)]
+    Kit(KitCommand),
+
+    Lint(Lint),
+
+    /// Check Twoliter.lock.
+    #[clap(subcommand)]
+    Lock(LockCommand),
+
     Make(Make),
 
+    /// Create a new project from a template.
+    New(New),
+
+    /// Print the steps of a build so that a CI scheduler can run them as separate jobs.
+    Plan(Plan),
+
+    /// Fetch and extract everything needed to build a variant, without building it.
+    Prepare(Prepare),
+
+    /// Find the package, sources and patches that a file in an image was built from.
+    Provenance(Provenance),
+
+    /// Serve a caching proxy of the registries that kits and SDKs are pulled from.
+    #[clap(subcommand)]
+    Proxy(ProxyCommand),
+
+    /// Re-run a recorded kit or variant build in the environment it ran in.
+    Rebuild(Rebuild),
+
+    /// Resolve the project's dependencies without writing Twoliter.lock.
+    Resolve(Resolve),
+
+    /// Print the schemas of Twoliter's machine-readable outputs.
+    #[clap(subcommand)]
+    Schema(SchemaCommand),
+
+    /// Run the custom build steps declared in Twoliter.toml.
+    #[clap(subcommand)]
+    Step(StepCommand),
+
+    /// Manage the read-only store of kits and SDKs shared by the users of a build host.
+    #[clap(subcommand)]
+    Store(StoreCommand),
+
     /// Update Twoliter.lock
     Update(Update),
 
+    /// Copy every image in Twoliter.lock into a directory of OCI layouts for offline builds.
+    Vendor(Vendor),
+
+    /// Manage the versions of kits across releases.
+    #[clap(subcommand)]
+    Version(VersionCommand),
+
     /// Publish something, such as a Kit
     #[clap(subcommand)]
     Publish(PublishCommand),
@@ -55,13 +202,66 @@ pub(crate) enum Subcommand {
     Debug(DebugAction),
 }
 
+/// Diagnostics which can be promoted to errors with `--deny`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Deny {
+    /// Any warning.
+    Warnings,
+    /// Deprecation notices on the project's kits, SDK or vendors, and kits past their end of life.
+    Deprecations,
+}
+
 /// Entrypoint for the `twoliter` command line program.
 pub(super) async fn run(args: Args) -> Result<()> {
-    match args.subcommand {
+    let result = run_subcommand(args.subcommand).await;
+
+    let summary = warnings::summary();
+    if summary.total > 0 {
+        warn!("{summary}");
+    }
+    result?;
+    if args.deny.contains(&Deny::Warnings) && summary.total > 0 {
+        bail!("{summary} raised and warnings are denied by `--deny warnings`");
+    }
+    if args.deny.contains(&Deny::Deprecations) && summary.deprecations > 0 {
+        bail!(
+            "{} deprecation(s) found and deprecations are denied by `--deny deprecations`",
+            summary.deprecations
+        );
+    }
+    Ok(())
+}
+
+async fn run_subcommand(subcommand: Subcommand) -> Result<()> {
+    match subcommand {
         Subcommand::Build(build_command) => build_command.run().await,
+        Subcommand::Bundle(bundle_command) => bundle_command.run().await,
+        Subcommand::Cache(cache_command) => cache_command.run().await,
+        Subcommand::Changelog(changelog_args) => changelog_args.run().await,
+        Subcommand::Doctor(doctor_args) => doctor_args.run().await,
+        Subcommand::Drift(drift_args) => drift_args.run().await,
+        Subcommand::Env(env_args) => env_args.run().await,
+        Subcommand::ExecStep(exec_step_args) => exec_step_args.run().await,
+        Subcommand::Explain(explain_args) => explain_args.run().await,
         Subcommand::Fetch(fetch_args) => fetch_args.run().await,
+        Subcommand::Import(import_command) => import_command.run().await,
+        Subcommand::Kit(kit_command) => kit_command.run().await,
+        Subcommand::Lint(lint_args) => lint_args.run().await,
+        Subcommand::Lock(lock_command) => lock_command.run().await,
         Subcommand::Make(make_args) => make_args.run().await,
+        Subcommand::New(new_args) => new_args.run().await,
+        Subcommand::Plan(plan_args) => plan_args.run().await,
+        Subcommand::Prepare(prepare_args) => prepare_args.run().await,
+        Subcommand::Provenance(provenance_args) => provenance_args.run().await,
+        Subcommand::Proxy(proxy_command) => proxy_command.run().await,
+        Subcommand::Rebuild(rebuild_args) => rebuild_args.run().await,
+        Subcommand::Resolve(resolve_args) => resolve_args.run().await,
+        Subcommand::Schema(schema_command) => schema_command.run().await,
+        Subcommand::Step(step_command) => step_command.run().await,
+        Subcommand::Store(store_command) => store_command.run().await,
         Subcommand::Update(update_args) => update_args.run().await,
+        Subcommand::Vendor(vendor_args) => vendor_args.run().await,
+        Subcommand::Version(version_command) => version_command.run().await,
         Subcommand::Publish(publish_command) => publish_command.run().await,
         Subcommand::Debug(debug_action) => debug_action.run().await,
     }
@@ -93,6 +293,7 @@ pub(super) fn init_logger(level: Option<LevelFilter>) {
 mod test {
     use super::*;
     use crate::cmd::build::BuildKit;
+    use crate::project::{default_extract_jobs, DEFAULT_RESOLVE_JOBS};
     use async_walkdir::WalkDir;
     use futures::stream::StreamExt;
     use std::collections::HashSet;
@@ -134,6 +335,13 @@ mod test {
     async fn twoliter_update(project_path: &Path) {
         let command = Update {
             project_path: Some(project_path.to_path_buf()),
+            dry_run: false,
+            commit: false,
+            branch: None,
+            format: Default::default(),
+            jobs: DEFAULT_RESOLVE_JOBS,
+            kit: vec![],
+            sdk: false,
         };
         command.run().await.unwrap();
     }
@@ -142,6 +350,14 @@ mod test {
         let command = Fetch {
             project_path: Some(project_path.to_path_buf()),
             arch: arch.into(),
+            variant: None,
+            no_extract: false,
+            attributes: Default::default(),
+            jobs: default_extract_jobs(),
+            explain_cache: false,
+            progress: Default::default(),
+            offline: false,
+            oci_dir: None,
         };
         command.run().await.unwrap()
     }
@@ -163,6 +379,11 @@ mod test {
             kit: kit_name.to_string(),
             lookaside_cache: None,
             upstream_source_fallback: false,
+            explain_cache: false,
+            failure_mode: Default::default(),
+            priority: Default::default(),
+            multi_arch: false,
+            publish: None,
         };
 
         command.run().await.unwrap();
@@ -186,6 +407,11 @@ mod test {
             kit: kit_name.to_string(),
             lookaside_cache: None,
             upstream_source_fallback: false,
+            explain_cache: false,
+            failure_mode: Default::default(),
+            priority: Default::default(),
+            multi_arch: false,
+            publish: None,
         };
 
         command.run().await.unwrap();
@@ -210,6 +436,11 @@ mod test {
             kit: kit_name.to_string(),
             lookaside_cache: None,
             upstream_source_fallback: false,
+            explain_cache: false,
+            failure_mode: Default::default(),
+            priority: Default::default(),
+            multi_arch: false,
+            publish: None,
         };
 
         command.run().await.unwrap();
@@ -234,6 +465,11 @@ mod test {
             kit: kit_name.to_string(),
             lookaside_cache: None,
             upstream_source_fallback: false,
+            explain_cache: false,
+            failure_mode: Default::default(),
+            priority: Default::default(),
+            multi_arch: false,
+            publish: None,
         };
 
         command.run().await.unwrap();
@@ -248,4 +484,12 @@ mod test {
         )
         .await;
     }
+
+    #[test]
+    fn test_deny_after_subcommand() {
+        let args =
+            Args::try_parse_from(["twoliter", "build", "kit", "core-kit", "--deny", "warnings"])
+                .unwrap();
+        assert_eq!(args.deny, [Deny::Warnings]);
+    }
 }
@@ -0,0 +1,70 @@
+use crate::template::NewProject;
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Create a new project from a template.
+#[derive(Debug, Parser)]
+pub(crate) struct New {
+    /// Where to create the project. It must not exist, or be empty.
+    path: PathBuf,
+
+    /// The template to create the project from: the name of a template in the template registry,
+    /// `oci://<image>`, `git+<url>[#<rev>]`, or a directory.
+    #[clap(long = "template")]
+    template: String,
+
+    /// The vendor the project's kits are published as.
+    #[clap(long = "vendor")]
+    vendor: Option<String>,
+
+    /// The name of the project's kit.
+    #[clap(long = "kit-name")]
+    kit_name: Option<String>,
+
+    /// The registry the project's kits are published to.
+    #[clap(long = "registry")]
+    registry: Option<String>,
+
+    /// Sets a variable the template declares, given as `<name>=<value>`. May be given more than
+    /// once.
+    #[clap(long = "set", value_parser = parse_variable)]
+    set: Vec<(String, String)>,
+}
+
+impl New {
+    pub(super) async fn run(&self) -> Result<()> {
+        let mut values = self.set.iter().cloned().collect::<BTreeMap<_, _>>();
+        for (name, value) in [
+            ("vendor", &self.vendor),
+            ("kit-name", &self.kit_name),
+            ("registry", &self.registry),
+        ] {
+            if let Some(value) = value {
+                values.insert(name.to_string(), value.clone());
+            }
+        }
+        let written = NewProject {
+            template: self.template.clone(),
+            output: self.path.clone(),
+            values,
+        }
+        .run()
+        .await?;
+        info!(
+            "Created a project with {written} file(s) in '{}'",
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+fn parse_variable(value: &str) -> Result<(String, String)> {
+    let (name, value) = value
+        .split_once('=')
+        .filter(|(name, _)| !name.is_empty())
+        .context(format!("'{value}' is not of the form <name>=<value>"))?;
+    Ok((name.to_string(), value.to_string()))
+}
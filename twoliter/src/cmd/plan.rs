@@ -0,0 +1,50 @@
+use crate::output::{self, OutputFormat};
+use crate::project::{self, Locked, PlanRequest};
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+
+/// Print the steps of a build, and the steps each depends on, so that a CI scheduler can run them
+/// as separate jobs with `twoliter exec-step`.
+#[derive(Debug, Parser)]
+pub(crate) struct Plan {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The architectures to build for.
+    #[clap(long = "arch", default_value = "x86_64")]
+    arches: Vec<String>,
+
+    /// A kit of this project to build. Every kit and variant is built when none are given.
+    #[clap(long = "kit")]
+    kits: Vec<String>,
+
+    /// A variant of this project to build. Every kit and variant is built when none are given.
+    #[clap(long = "variant")]
+    variants: Vec<String>,
+
+    /// Publish the built kits to this vendor once they are built for every architecture.
+    #[clap(long = "publish-vendor")]
+    publish_vendor: Option<String>,
+
+    /// How to print the plan. Use `json` for a plan that `twoliter exec-step` can run.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+impl Plan {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<Locked>().await?;
+        let plan = project
+            .build_plan(&PlanRequest {
+                arches: self.arches.clone(),
+                kits: self.kits.clone(),
+                variants: self.variants.clone(),
+                publish_vendor: self.publish_vendor.clone(),
+            })
+            .await?;
+        output::print(self.format, &plan)
+    }
+}
@@ -0,0 +1,82 @@
+use crate::api::{self, CancellationToken};
+use crate::cargo_make::CargoMake;
+use crate::progress::{ProgressMode, ProgressReporter};
+use crate::project::{default_extract_jobs, ExtractOptions};
+use crate::tools::install_tools;
+use anyhow::Result;
+use clap::Parser;
+use std::path::{Path, PathBuf};
+
+/// Do all of the network and extraction work needed to build a variant, without building it.
+///
+/// This fetches and extracts the kits the variant needs, loads the SDK, installs Twoliter's tools,
+/// and fetches the project's Rust crates and vendored Go modules. Later builds of the variant can
+/// then run offline, apart from package sources missing from the sources directory, which are
+/// still fetched from the lookaside cache as packages are built.
+#[derive(Debug, Parser)]
+pub(crate) struct Prepare {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The architecture to prepare for.
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// The variant to prepare for.
+    #[clap(long = "variant")]
+    variant: String,
+
+    /// Report whether each kit and the SDK could be reused from the local cache, and if not, why.
+    #[clap(long = "explain-cache")]
+    explain_cache: bool,
+
+    /// How to show progress: `auto` draws progress bars when stderr is a terminal and logs
+    /// otherwise, `plain` always logs, and `json` prints each event as a line of JSON on stdout.
+    #[clap(long = "progress", value_enum, default_value_t)]
+    progress: ProgressMode,
+}
+
+impl Prepare {
+    pub(super) async fn run(&self) -> Result<()> {
+        let cancel = CancellationToken::new();
+        let resolved = api::resolve(self.project_path.clone(), &cancel).await?;
+        let project = resolved.project();
+        project
+            .sparse_checkout(&[Path::new("variants").join(&self.variant)])
+            .await?;
+        let reporter = ProgressReporter::new(self.progress);
+        let report = api::extract(
+            &resolved,
+            &self.arch,
+            Some(&self.variant),
+            ExtractOptions::default(),
+            default_extract_jobs(),
+            &cancel,
+            &|progress| reporter.report(progress),
+        )
+        .await?;
+        reporter.finish();
+        if self.explain_cache {
+            println!("{report}");
+        }
+
+        let toolsdir = project.project_dir().join("build/tools");
+        install_tools(&toolsdir).await?;
+        CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
+            .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
+            .env("BUILDSYS_ARCH", &self.arch)
+            .env("BUILDSYS_VARIANT", &self.variant)
+            .env("BUILDSYS_VERSION_IMAGE", project.release_version())
+            .env("GO_MODULES", project.find_go_modules().await?.join(" "))
+            .envs(
+                project
+                    .module_proxy_env(&project.default_cargo_home())?
+                    .into_iter(),
+            )
+            .makefile(toolsdir.join("Makefile.toml"))
+            .project_dir(project.project_dir())
+            .exec("fetch")
+            .await
+    }
+}
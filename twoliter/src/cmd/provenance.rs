@@ -0,0 +1,33 @@
+use crate::output::{self, OutputFormat};
+use crate::project;
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+
+/// Find the package, sources and patches that a file in an image was built from, read from the
+/// provenance records of the kits built and fetched by the project.
+#[derive(Debug, Parser)]
+pub(crate) struct Provenance {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The architecture whose kits to search.
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// How to print the report.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// The path of the file as installed in the image, e.g. `/usr/bin/apiclient`.
+    path: String,
+}
+
+impl Provenance {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let report = project.provenance(&self.arch, &self.path)?;
+        output::print(self.format, &report)
+    }
+}
@@ -0,0 +1,61 @@
+use crate::project::store::SystemStore;
+use crate::proxy::{RegistryProxy, DEFAULT_PROXY_CACHE, PROXY_CACHE_ENV, PROXY_TOKEN_ENV};
+use anyhow::Result;
+use clap::Parser;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+pub(crate) enum ProxyCommand {
+    Serve(Serve),
+}
+
+impl ProxyCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            ProxyCommand::Serve(command) => command.run().await,
+        }
+    }
+}
+
+/// Serve a caching, pull-through proxy of container registries, so that a team can pull kits and
+/// SDKs through one shared egress point. Images are named by their upstream registry, e.g. a
+/// vendor registry of `<proxy host>/public.ecr.aws` pulls through the proxy from `public.ecr.aws`.
+/// Clients log in to the proxy with its token as the password.
+#[derive(Debug, Parser)]
+pub(crate) struct Serve {
+    /// The address to listen on.
+    #[clap(long = "listen", default_value = "127.0.0.1:5000")]
+    listen: SocketAddr,
+
+    /// Where to cache pulled images.
+    #[clap(long = "cache-dir", env = PROXY_CACHE_ENV, default_value = DEFAULT_PROXY_CACHE)]
+    cache_dir: PathBuf,
+
+    /// An upstream registry to proxy, e.g. `public.ecr.aws`. May be given more than once; no other
+    /// registries are proxied.
+    #[clap(long = "upstream", required = true)]
+    upstreams: Vec<String>,
+
+    /// The token that clients must present to the proxy.
+    #[clap(long = "token", env = PROXY_TOKEN_ENV, hide_env_values = true)]
+    token: String,
+
+    /// Don't serve layers from the system store, even when it holds them.
+    #[clap(long = "no-store")]
+    no_store: bool,
+}
+
+impl Serve {
+    pub(super) async fn run(&self) -> Result<()> {
+        let store = (!self.no_store).then(SystemStore::find).flatten();
+        RegistryProxy::new(
+            &self.cache_dir,
+            store,
+            self.upstreams.iter().cloned(),
+            &self.token,
+        )
+        .serve(self.listen)
+        .await
+    }
+}
@@ -1,20 +1,31 @@
 use crate::cargo_make::CargoMake;
-use crate::project::{self, Locked};
+use crate::common::fs::read_to_string;
+use crate::project::{
+    self, ApprovalRequest, Locked, COMPATIBILITY_ANNOTATION, PROMOTION_TASKS,
+    RELEASE_NOTES_ANNOTATION,
+};
 use crate::tools::install_tools;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
 use std::path::PathBuf;
+use tracing::info;
 
 /// Group all publish commands
 #[derive(Debug, Parser)]
 pub(crate) enum PublishCommand {
     Kit(PublishKit),
+    Image(PublishImage),
+    Approve(ApproveKit),
+    ApprovePromotion(ApprovePromotion),
 }
 
 impl PublishCommand {
     pub(crate) async fn run(self) -> Result<()> {
         match self {
             PublishCommand::Kit(command) => command.run().await,
+            PublishCommand::Image(command) => command.run().await,
+            PublishCommand::Approve(command) => command.run().await,
+            PublishCommand::ApprovePromotion(command) => command.run().await,
         }
     }
 }
@@ -34,6 +45,16 @@ pub(crate) struct PublishKit {
 
     /// Publish kit image to a different repository than the kit's name
     kit_repo: Option<String>,
+
+    /// Attach the release notes in this file to the kit image, e.g. as drafted by
+    /// `twoliter changelog`
+    #[clap(long = "release-notes")]
+    release_notes: Option<PathBuf>,
+
+    /// A signed approval of this publish, made with `twoliter publish approve`, for vendors whose
+    /// publishes must be approved. May be given more than once.
+    #[clap(long = "approval")]
+    approvals: Vec<PathBuf>,
 }
 
 impl PublishKit {
@@ -41,24 +62,218 @@ impl PublishKit {
         let project = project::load_or_find_project(self.project_path.clone()).await?;
         let project = project.load_lock::<Locked>().await?;
 
-        let toolsdir = project.project_dir().join("build/tools");
-        install_tools(&toolsdir).await?;
-        let makefile_path = toolsdir.join("Makefile.toml");
-
         let publish_kit_repo = match &self.kit_repo {
             Some(kit_repo) => kit_repo,
             None => &self.kit_name,
         };
+        let mut publish_metadata = project.publish_metadata_for(&self.vendor);
+        if let Some(approval) = &publish_metadata.approval {
+            let request = ApprovalRequest::publish_kit(
+                &project.project_dir(),
+                &self.kit_name,
+                project.release_version(),
+                &self.vendor,
+                publish_kit_repo,
+            )
+            .await?;
+            approval
+                .check(&project.project_dir(), &request, &self.approvals)
+                .await?;
+        }
+
+        let toolsdir = project.project_dir().join("build/tools");
+        install_tools(&toolsdir).await?;
+        let makefile_path = toolsdir.join("Makefile.toml");
+
+        if let Some(release_notes) = &self.release_notes {
+            publish_metadata.annotations.insert(
+                RELEASE_NOTES_ANNOTATION.to_string(),
+                read_to_string(release_notes).await?,
+            );
+        }
+        if let Some(compatibility) = &publish_metadata.compatibility {
+            publish_metadata.annotations.insert(
+                COMPATIBILITY_ANNOTATION.to_string(),
+                serde_json::to_string(compatibility)
+                    .context("Unable to serialize kit compatibility matrix")?,
+            );
+        }
+        let annotations = serde_json::to_string(&publish_metadata.annotations)
+            .context("Unable to serialize publish annotations")?;
+        let labels = serde_json::to_string(&publish_metadata.labels)
+            .context("Unable to serialize publish labels")?;
+        let tags = publish_metadata
+            .tags
+            .iter()
+            .map(|tag| tag.expand(project.release_version()))
+            .collect::<Result<Vec<_>>>()?;
+        let tags = serde_json::to_string(&tags).context("Unable to serialize publish tags")?;
+        let compression = project.layer_compression_for(&self.vendor);
+        let kit_path = project
+            .compress_kit(&self.kit_name, &self.vendor, compression)
+            .await?;
+        project
+            .record_publish(&self.kit_name, &self.vendor, compression)
+            .await?;
         project.fetch_sdk().await?;
         CargoMake::new(project.sdk_image().project_image_uri().to_string().as_str())?
             .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
             .env("BUILDSYS_KIT", &self.kit_name)
             .env("BUILDSYS_VERSION_IMAGE", project.release_version())
             .env("PUBLISH_VENDOR", &self.vendor)
+            .env("PUBLISH_KIT_PATH", kit_path.display().to_string())
             .env("PUBLISH_KIT_REPO", publish_kit_repo)
+            .env("PUBLISH_KIT_ANNOTATIONS", annotations)
+            .env("PUBLISH_KIT_LABELS", labels)
+            .env("PUBLISH_KIT_TAGS", tags)
             .makefile(makefile_path)
             .project_dir(project.project_dir())
             .exec("publish-kit")
             .await
     }
 }
+
+/// Publish a variant built as a container image, with `image-format = "oci"`, to a container
+/// registry
+#[derive(Debug, Parser)]
+pub(crate) struct PublishImage {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Variant whose image to publish
+    variant: String,
+
+    /// Vendor to publish to
+    vendor: String,
+
+    /// Publish the image to a different repository than the variant's name
+    image_repo: Option<String>,
+}
+
+impl PublishImage {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<Locked>().await?;
+
+        let toolsdir = project.project_dir().join("build/tools");
+        install_tools(&toolsdir).await?;
+        let makefile_path = toolsdir.join("Makefile.toml");
+
+        project.fetch_sdk().await?;
+        let mut cargo_make =
+            CargoMake::new(project.sdk_image().project_image_uri().to_string().as_str())?
+                .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
+                .env("BUILDSYS_VARIANT", &self.variant)
+                .env("BUILDSYS_VERSION_IMAGE", project.release_version())
+                .env("PUBLISH_VENDOR", &self.vendor);
+        if let Some(image_repo) = &self.image_repo {
+            cargo_make = cargo_make.env("PUBLISH_IMAGE_REPO", image_repo);
+        }
+        cargo_make
+            .makefile(makefile_path)
+            .project_dir(project.project_dir())
+            .exec("publish-image")
+            .await
+    }
+}
+
+/// Sign an approval for publishing the current build of a kit to a vendor whose publishes must be
+/// approved
+#[derive(Debug, Parser)]
+pub(crate) struct ApproveKit {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Kit name to approve publishing
+    kit_name: String,
+
+    /// Vendor the kit will be published to
+    vendor: String,
+
+    /// Repository the kit will be published to, when it differs from the kit's name
+    kit_repo: Option<String>,
+
+    /// The ssh private key to sign the approval with
+    #[clap(long)]
+    key: PathBuf,
+
+    /// Where to write the approval
+    #[clap(long)]
+    output: PathBuf,
+}
+
+impl ApproveKit {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let request = ApprovalRequest::publish_kit(
+            &project.project_dir(),
+            &self.kit_name,
+            project.release_version(),
+            &self.vendor,
+            self.kit_repo.as_ref().unwrap_or(&self.kit_name),
+        )
+        .await?;
+        request.sign(&self.key, &self.output).await?;
+        info!("Wrote approval of {request} to '{}'", self.output.display());
+        Ok(())
+    }
+}
+
+/// Sign an approval for running a `twoliter make` task which publishes or promotes the SSM
+/// parameters of the current build of a variant
+#[derive(Debug, Parser)]
+pub(crate) struct ApprovePromotion {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The pubsys operation to approve, `ssm` or `promote-ssm`, whichever the task runs
+    #[clap(value_parser = clap::builder::PossibleValuesParser::new(PROMOTION_TASKS))]
+    task: String,
+
+    /// Variant to approve promoting
+    variant: String,
+
+    /// Architecture of the variant
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// The version or pointer the parameters are promoted to, as given to `promote-ssm` in
+    /// `SSM_TARGET`
+    #[clap(long = "target")]
+    target: Option<String>,
+
+    /// The build to promote, as given to `promote-ssm` in `SSM_SOURCE`. Defaults to the build of
+    /// the checked out commit
+    #[clap(long = "source")]
+    source: Option<String>,
+
+    /// The ssh private key to sign the approval with
+    #[clap(long)]
+    key: PathBuf,
+
+    /// Where to write the approval
+    #[clap(long)]
+    output: PathBuf,
+}
+
+impl ApprovePromotion {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let request = ApprovalRequest::promote_variant(
+            &project.project_dir(),
+            project.release_version(),
+            &self.task,
+            &self.variant,
+            &self.arch,
+            self.source.clone(),
+            self.target.clone(),
+        )
+        .await;
+        request.sign(&self.key, &self.output).await?;
+        info!("Wrote approval of {request} to '{}'", self.output.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use crate::cmd::build::{BuildKit, BuildVariant, BuilderOptions, FailureMode};
+use crate::project::{self, BuildInfo, BuildTarget, Locked};
+use crate::warnings;
+use anyhow::{bail, Result};
+use clap::Parser;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Re-run a build recorded in build/build-info, with the options and environment variables it
+/// was run with. Fails if the project's lock, commit or the twoliter version differ from the
+/// recorded build's, since the rebuild would not be the same build.
+#[derive(Debug, Parser)]
+pub(crate) struct Rebuild {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The build info describing the build to re-run.
+    #[clap(long = "from")]
+    from: PathBuf,
+
+    /// Rebuild even if what goes into the build differs from the recorded build.
+    #[clap(long = "force")]
+    force: bool,
+}
+
+impl Rebuild {
+    pub(super) async fn run(&self) -> Result<()> {
+        let info = BuildInfo::load(&self.from).await?;
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<Locked>().await?;
+
+        let drift = project.build_info_drift(&info).await;
+        for difference in &drift.environment {
+            warnings::warn(format!(
+                "Rebuilding in a different environment: {difference}"
+            ));
+        }
+        if !drift.inputs.is_empty() {
+            if !self.force {
+                bail!(
+                    "The build recorded in '{}' cannot be reproduced exactly:\n  - {}\nPass \
+                    --force to rebuild anyway",
+                    self.from.display(),
+                    drift.inputs.join("\n  - ")
+                );
+            }
+            for difference in &drift.inputs {
+                warnings::warn(format!("Rebuilding with different inputs: {difference}"));
+            }
+        }
+
+        info.apply_env();
+        let options = info.options;
+        let failure_mode = FailureMode {
+            keep_going: options.keep_going == Some(true),
+            fail_fast: options.keep_going == Some(false),
+        };
+        match info.target {
+            BuildTarget::Kit(kit) => {
+                info!("Rebuilding kit '{kit}' for '{}'", info.arch);
+                BuildKit {
+                    project_path: self.project_path.clone(),
+                    arch: info.arch,
+                    kit,
+                    lookaside_cache: options.lookaside_cache,
+                    upstream_source_fallback: options.upstream_source_fallback,
+                    explain_cache: false,
+                    failure_mode,
+                    priority: Default::default(),
+                    multi_arch: false,
+                    publish: None,
+                }
+                .run()
+                .await
+            }
+            BuildTarget::Variant(variant) => {
+                info!("Rebuilding variant '{variant}' for '{}'", info.arch);
+                BuildVariant {
+                    project_path: self.project_path.clone(),
+                    arch: info.arch,
+                    variant,
+                    lookaside_cache: options.lookaside_cache,
+                    upstream_source_fallback: options.upstream_source_fallback,
+                    explain_cache: false,
+                    failure_mode,
+                    priority: Default::default(),
+                    builder: BuilderOptions {
+                        builder: options.builder,
+                        ..Default::default()
+                    },
+                    infra_toml: options.infra_toml,
+                }
+                .run()
+                .await
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use crate::output::{self, OutputFormat};
+use crate::project::{self, UpdateScope, DEFAULT_RESOLVE_JOBS};
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Resolve the project's kits and SDK against their registries without writing Twoliter.lock,
+/// printing the changes that `twoliter update` would make.
+#[derive(Debug, Parser)]
+pub(crate) struct Resolve {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Only read the versions, architectures and dependencies of each image from its manifest
+    /// list and kit metadata, without computing digests or verifying signatures, and print them.
+    /// Fast enough for dashboards which poll many projects.
+    #[clap(long = "metadata-only")]
+    metadata_only: bool,
+
+    /// How to print what was resolved. The text format is not stable.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// How many kits to resolve against their registries at once.
+    #[clap(
+        long,
+        short = 'j',
+        default_value_t = DEFAULT_RESOLVE_JOBS,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    jobs: usize,
+}
+
+impl Resolve {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        if self.metadata_only {
+            let report = project.resolve_metadata(self.jobs).await?;
+            return output::print(self.format, &report);
+        }
+        let changes = project
+            .preview_lock_update(self.jobs, &UpdateScope::default())
+            .await?;
+        match self.format {
+            OutputFormat::Json => output::print(self.format, &changes)?,
+            OutputFormat::Text if changes.is_empty() => info!("Twoliter.lock is up to date"),
+            OutputFormat::Text => print!("{}", changes.detailed()),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use crate::output::SCHEMAS;
+use anyhow::{ensure, Context, Result};
+use clap::Parser;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Parser)]
+pub(crate) enum SchemaCommand {
+    Outputs(Outputs),
+}
+
+impl SchemaCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            SchemaCommand::Outputs(command) => command.run().await,
+        }
+    }
+}
+
+/// Print the JSON Schema of each `--format json` output, keyed by schema name.
+#[derive(Debug, Parser)]
+pub(crate) struct Outputs {
+    /// Only print the schema with this name.
+    #[clap(long = "name")]
+    name: Option<String>,
+}
+
+impl Outputs {
+    pub(super) async fn run(&self) -> Result<()> {
+        let schemas = SCHEMAS
+            .iter()
+            .filter(|schema| {
+                self.name
+                    .as_deref()
+                    .map_or(true, |name| name == schema.name)
+            })
+            .map(|schema| (schema.name.to_string(), schema.json_schema()))
+            .collect::<Map<_, _>>();
+        if let Some(name) = &self.name {
+            ensure!(!schemas.is_empty(), "no output schema named '{name}'");
+        }
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&Value::Object(schemas))
+                .context("failed to serialize output schemas")?
+        );
+        Ok(())
+    }
+}
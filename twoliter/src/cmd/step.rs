@@ -0,0 +1,44 @@
+use crate::host;
+use crate::project::{self, SDKLocked};
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+pub(crate) enum StepCommand {
+    Run(RunStep),
+}
+
+impl StepCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            StepCommand::Run(command) => command.run().await,
+        }
+    }
+}
+
+/// Run a custom build step from Twoliter.toml in the SDK, unless its inputs are unchanged.
+#[derive(Debug, Parser)]
+pub(crate) struct RunStep {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Run the step even if nothing it depends on has changed.
+    #[clap(long = "force")]
+    force: bool,
+
+    /// The name of the step to run.
+    name: String,
+}
+
+impl RunStep {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<SDKLocked>().await?;
+        host::ensure_containers_supported(&project.project_dir())?;
+        project.fetch_sdk().await?;
+        project.run_step(&self.name, self.force).await?;
+        Ok(())
+    }
+}
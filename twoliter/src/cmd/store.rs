@@ -0,0 +1,66 @@
+use crate::docker::Docker;
+use crate::project::store::SystemStore;
+use crate::project::{self, Locked};
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+pub(crate) enum StoreCommand {
+    Populate(Populate),
+}
+
+impl StoreCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            StoreCommand::Populate(command) => command.run().await,
+        }
+    }
+}
+
+/// Pull a project's kits and SDK into the read-only store shared by the users of a build host.
+/// This is meant to be run by the host's administrator.
+#[derive(Debug, Parser)]
+pub(crate) struct Populate {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The store to populate. Defaults to $TWOLITER_SYSTEM_STORE, or else /var/lib/twoliter/store.
+    #[clap(long = "store")]
+    store: Option<PathBuf>,
+
+    /// Architectures of kits to pull.
+    #[clap(long = "arch", default_values = ["x86_64", "aarch64"])]
+    arch: Vec<String>,
+
+    /// Platform of the SDK image to pull. Defaults to the platform of the docker daemon.
+    #[clap(long = "sdk-platform")]
+    sdk_platform: Option<String>,
+
+    /// Report which kits and SDK were already in the store.
+    #[clap(long = "explain-cache")]
+    explain_cache: bool,
+}
+
+impl Populate {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let project = project.load_lock::<Locked>().await?;
+        let store = match &self.store {
+            Some(root) => SystemStore::new(root),
+            None => SystemStore::configured(),
+        };
+        let platform = match &self.sdk_platform {
+            Some(platform) => platform.clone(),
+            None => Docker::host_platform().await?,
+        };
+        let report = project
+            .populate_store(&store, &self.arch, &platform)
+            .await?;
+        if self.explain_cache {
+            println!("{report}");
+        }
+        Ok(())
+    }
+}
@@ -1,19 +1,111 @@
-use crate::project;
+use crate::git::Git;
+use crate::output::{self, OutputFormat};
+use crate::project::{self, UpdateScope, DEFAULT_RESOLVE_JOBS};
 use anyhow::Result;
 use clap::Parser;
 use std::path::PathBuf;
+use tracing::info;
 
 #[derive(Debug, Parser)]
 pub(crate) struct Update {
     /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
     #[clap(long = "project-path")]
     pub(crate) project_path: Option<PathBuf>,
+
+    /// Print the changes that updating would make to Twoliter.lock, with the old and new digests
+    /// of each image, without writing it.
+    #[clap(long = "dry-run", conflicts_with = "commit")]
+    pub(crate) dry_run: bool,
+
+    /// Commit Twoliter.lock with a conventional commit message summarizing the version and digest
+    /// changes, along with the release notes published with each new image. Nothing is committed
+    /// if the lock is unchanged.
+    #[clap(long)]
+    pub(crate) commit: bool,
+
+    /// Create and switch to a new branch with this name before committing.
+    #[clap(long, requires = "commit")]
+    pub(crate) branch: Option<String>,
+
+    /// How to print the changes made to Twoliter.lock. The text format is logged.
+    #[clap(long = "format", value_enum, default_value_t)]
+    pub(crate) format: OutputFormat,
+
+    /// How many kits to resolve against their registries at once.
+    #[clap(
+        long,
+        short = 'j',
+        default_value_t = DEFAULT_RESOLVE_JOBS,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    pub(crate) jobs: usize,
+
+    /// Only update the lock of this kit, keeping the other entries of Twoliter.lock as they are
+    /// where they still lock the same version. May be given more than once.
+    #[clap(long = "kit")]
+    pub(crate) kit: Vec<String>,
+
+    /// Only update the lock of the SDK, or of the SDK as well as the kits given with `--kit`.
+    #[clap(long)]
+    pub(crate) sdk: bool,
 }
 
 impl Update {
     pub(super) async fn run(&self) -> Result<()> {
         let project = project::load_or_find_project(self.project_path.clone()).await?;
-        project.create_lock().await?;
+        let scope = UpdateScope {
+            kits: self.kit.clone(),
+            sdk: self.sdk,
+        };
+        if self.dry_run {
+            let changes = project.preview_lock_update(self.jobs, &scope).await?;
+            match self.format {
+                OutputFormat::Json => output::print(self.format, &changes)?,
+                OutputFormat::Text if changes.is_empty() => info!("Twoliter.lock is up to date"),
+                OutputFormat::Text => print!("{}", changes.detailed()),
+            }
+            return Ok(());
+        }
+        let orphans = project.orphaned_lock_entries().await?;
+        let (project, changes) = project.update_lock(self.jobs, &scope).await?;
+        if !orphans.is_empty() {
+            // Only the images the project still leads to are resolved or kept, so orphaned
+            // entries are dropped whatever the scope of the update.
+            info!(
+                "Pruned {} orphaned entries from Twoliter.lock:\n{}",
+                orphans.len(),
+                orphans
+                    .iter()
+                    .map(|image| format!("remove {image}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
+        if self.format == OutputFormat::Json {
+            output::print(self.format, &changes)?;
+        }
+        if changes.is_empty() {
+            info!("Twoliter.lock is up to date");
+            return Ok(());
+        }
+        info!("Updated Twoliter.lock:\n{changes}");
+
+        if !self.commit {
+            return Ok(());
+        }
+        let project_dir = project.project_dir();
+        if let Some(branch) = &self.branch {
+            info!("Creating branch '{branch}'");
+            Git::create_branch(&project_dir, branch).await?;
+        }
+        let release_notes = changes.release_notes().await;
+        Git::commit(
+            &project_dir,
+            &[project.lock_file_path()],
+            &changes.commit_message(&release_notes),
+        )
+        .await?;
+        info!("Committed changes to Twoliter.lock");
         Ok(())
     }
 }
@@ -0,0 +1,42 @@
+use crate::output::{self, OutputFormat};
+use crate::project::{self, DEFAULT_RESOLVE_JOBS};
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+
+/// Copy the SDK and every kit in Twoliter.lock, for all architectures, into a directory of OCI
+/// layouts, so that the project can be built offline with `twoliter fetch --offline --oci-dir` or
+/// archived. Images already in the directory are checked against Twoliter.lock and their digests,
+/// and only pulled again if they differ.
+#[derive(Debug, Parser)]
+pub(crate) struct Vendor {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The directory to write the images to, relative to the project directory unless absolute.
+    #[clap(long = "dir", default_value = "vendor")]
+    dir: PathBuf,
+
+    /// How to print what was done for each image.
+    #[clap(long = "format", value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// How many images to pull at once.
+    #[clap(
+        long,
+        short = 'j',
+        default_value_t = DEFAULT_RESOLVE_JOBS,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    jobs: usize,
+}
+
+impl Vendor {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let dir = project.project_dir().join(&self.dir);
+        let report = project.vendor_images(&dir, self.jobs).await?;
+        output::print(self.format, &report)
+    }
+}
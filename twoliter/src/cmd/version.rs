@@ -0,0 +1,70 @@
+use crate::project::{self, BumpLevel};
+use anyhow::Result;
+use clap::{ArgGroup, Parser};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+pub(crate) enum VersionCommand {
+    Bump(Bump),
+}
+
+impl VersionCommand {
+    pub(crate) async fn run(self) -> Result<()> {
+        match self {
+            VersionCommand::Bump(command) => command.run().await,
+        }
+    }
+}
+
+/// Bump the version of a kit, and the references to it in this and the given consuming projects.
+/// A kit built by this project is released at the project's release-version, which is shared by
+/// all of the project's kits.
+#[derive(Debug, Parser)]
+#[clap(group(ArgGroup::new("level").required(true).args(["major", "minor", "patch"])))]
+pub(crate) struct Bump {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// Increment the major version.
+    #[clap(long = "major")]
+    major: bool,
+
+    /// Increment the minor version.
+    #[clap(long = "minor")]
+    minor: bool,
+
+    /// Increment the patch version.
+    #[clap(long = "patch")]
+    patch: bool,
+
+    /// A project which depends on the kit and should move to the new version, as the path to its
+    /// Twoliter.toml or the directory holding it.
+    #[clap(long = "consumer")]
+    consumers: Vec<PathBuf>,
+
+    /// Commit the updated files and tag the commit as `<kit>-v<version>`.
+    #[clap(long = "tag")]
+    tag: bool,
+
+    /// The name of the kit to bump.
+    kit: String,
+}
+
+impl Bump {
+    pub(super) async fn run(&self) -> Result<()> {
+        let level = if self.major {
+            BumpLevel::Major
+        } else if self.minor {
+            BumpLevel::Minor
+        } else {
+            BumpLevel::Patch
+        };
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let bump = project
+            .bump_version(&self.kit, level, &self.consumers, self.tag)
+            .await?;
+        println!("{bump}");
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 use anyhow::{ensure, Context, Result};
+use krane_static::settings;
 use log::{self, LevelFilter};
 use tokio::process::Command;
 use tracing::{debug, instrument};
@@ -27,6 +28,10 @@ pub(crate) async fn exec_log(cmd: &mut Command) -> Result<()> {
 #[instrument(level = "trace")]
 pub(crate) async fn exec(cmd: &mut Command, quiet: bool) -> Result<Option<String>> {
     debug!("Running: {:?}", cmd);
+    // A command whose caller gave up on it, such as a cancelled fetch, should not keep running.
+    cmd.kill_on_drop(true);
+    // Settings made for the run are not in this process's environment, so they are passed on.
+    settings::apply(cmd.as_std_mut());
     Ok(if quiet {
         // For quiet levels of logging we capture stdout and stderr
         let output = cmd
@@ -160,7 +165,18 @@ pub(crate) mod fs {
 
     #[instrument(level = "trace", skip(path), fields(path = %path.as_ref().display()))]
     pub(crate) async fn remove_dir_all(path: impl AsRef<Path>) -> Result<()> {
-        match fs::remove_dir_all(path.as_ref()).await {
+        let result = fs::remove_dir_all(path.as_ref()).await;
+        // Windows refuses to remove read-only files, which unpacking a layer whose files lack write
+        // permission leaves behind.
+        #[cfg(windows)]
+        let result = match result {
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                clear_readonly(path.as_ref());
+                fs::remove_dir_all(path.as_ref()).await
+            }
+            result => result,
+        };
+        match result {
             Ok(_) => Ok(()),
             Err(e) => match e.kind() {
                 ErrorKind::NotFound => {
@@ -175,6 +191,26 @@ pub(crate) mod fs {
         }
     }
 
+    /// Makes the files under `path` writable, skipping any which cannot be.
+    #[cfg(windows)]
+    fn clear_readonly(path: &Path) {
+        let Ok(entries) = std::fs::read_dir(path) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                clear_readonly(&entry.path());
+            }
+            let mut permissions = metadata.permissions();
+            #[allow(clippy::permissions_set_readonly_false)]
+            permissions.set_readonly(false);
+            let _ = std::fs::set_permissions(entry.path(), permissions);
+        }
+    }
+
     #[instrument(
         level = "trace",
         skip_all,
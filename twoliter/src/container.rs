@@ -0,0 +1,465 @@
+//! Detects when twoliter runs inside a container or on a CI runner, and adjusts builds to suit.
+//!
+//! Builds run containers through the docker daemon, and bind mount the project into them by path.
+//! When twoliter itself runs in a container and talks to the host's daemon through its socket, the
+//! daemon resolves those paths on the host, where the project is usually somewhere else or nowhere
+//! at all. Twoliter looks up where the project is mounted from, so that buildsys can bind the host
+//! path instead, and otherwise has buildsys copy the project into a volume for each build. Builds
+//! also pass files over sockets in the docker host's network, so the container must share it.
+//!
+//! CPU quotas set on the container limit how many packages are built at once, and a `/tmp` held in
+//! memory is swapped for a directory in the build directory when the container's memory is
+//! limited. `twoliter doctor` reports what was detected and what would be adjusted.
+use crate::common::exec;
+use crate::common::fs::create_dir_all;
+use crate::docker::Docker;
+use anyhow::{bail, Result};
+use krane_static::settings;
+use oci_cli_wrapper::container_tool::ContainerTool;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Environment variables set by CI systems, and the names of the systems.
+const CI_ENV_VARS: &[(&str, &str)] = &[
+    ("GITHUB_ACTIONS", "GitHub Actions"),
+    ("GITLAB_CI", "GitLab CI"),
+    ("BUILDKITE", "Buildkite"),
+    ("CODEBUILD_BUILD_ID", "AWS CodeBuild"),
+    ("JENKINS_URL", "Jenkins"),
+    ("CIRCLECI", "CircleCI"),
+    ("CI", "CI"),
+];
+
+/// Where the docker daemon used by builds runs, relative to twoliter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Daemon {
+    /// On the same host as twoliter, which is not in a container.
+    Host,
+    /// Inside the same container as twoliter, i.e. docker-in-docker.
+    Nested,
+    /// On the host of the container twoliter runs in, reached through its socket.
+    Sibling {
+        /// The container twoliter runs in, if it could be found.
+        container: Option<String>,
+    },
+    /// The daemon's location could not be determined.
+    Unknown,
+}
+
+/// A mount of the container twoliter runs in, as reported by `docker inspect`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct Mount {
+    pub(crate) source: PathBuf,
+    pub(crate) destination: PathBuf,
+}
+
+/// What twoliter found out about the environment it runs in.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ContainerEnvironment {
+    pub(crate) in_container: bool,
+    /// The name of the CI system running twoliter, if any.
+    pub(crate) ci: Option<String>,
+    pub(crate) daemon: Daemon,
+    pub(crate) rootless: bool,
+    /// The mounts of the container twoliter runs in, when the daemon is its sibling.
+    pub(crate) mounts: Vec<Mount>,
+    /// Whether the container twoliter runs in shares the host's network, if known.
+    pub(crate) host_network: Option<bool>,
+    /// The number of CPUs the container may use, if limited.
+    pub(crate) cpu_limit: Option<f64>,
+    /// The memory the container may use in bytes, if limited.
+    pub(crate) memory_limit: Option<u64>,
+    pub(crate) tmp_is_tmpfs: bool,
+}
+
+/// A change made to builds to suit the environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Adjustment {
+    pub(crate) env: &'static str,
+    pub(crate) value: String,
+    pub(crate) reason: String,
+}
+
+impl ContainerEnvironment {
+    /// Inspects the environment twoliter runs in.
+    pub(crate) async fn detect() -> Self {
+        let in_container = Path::new("/.dockerenv").exists()
+            || Path::new("/run/.containerenv").exists()
+            || read("/proc/1/cgroup").is_some_and(|cgroup| {
+                ["docker", "kubepods", "containerd"]
+                    .iter()
+                    .any(|runtime| cgroup.contains(runtime))
+            });
+        let ci = ci_name(|key| settings::var(key).ok());
+        let info = docker_info().await;
+        let rootless = info
+            .as_ref()
+            .is_some_and(|info| info.security_options.iter().any(|o| o.contains("rootless")));
+
+        let hostname = read(HOSTNAME).map(|name| name.trim().to_string());
+        let mut mounts = Vec::new();
+        let mut host_network = None;
+        let daemon = match info {
+            _ if !in_container => Daemon::Host,
+            Some(info) if Some(info.name.trim()) == hostname.as_deref() => Daemon::Nested,
+            Some(_) => {
+                let container = read("/proc/self/mountinfo")
+                    .and_then(|mountinfo| container_id(&mountinfo))
+                    .or(hostname);
+                if let Some(inspect) = match &container {
+                    Some(container) => docker_inspect(container).await,
+                    None => None,
+                } {
+                    mounts = inspect.mounts;
+                    host_network = Some(inspect.host_config.network_mode == "host");
+                }
+                Daemon::Sibling { container }
+            }
+            None => Daemon::Unknown,
+        };
+
+        Self {
+            in_container,
+            ci,
+            daemon,
+            rootless,
+            mounts,
+            host_network,
+            cpu_limit: read(CPU_MAX).and_then(|max| parse_cpu_max(&max)),
+            memory_limit: read(MEMORY_MAX).and_then(|max| parse_memory_max(&max)),
+            tmp_is_tmpfs: read("/proc/mounts").is_some_and(|mounts| is_tmpfs(&mounts, "/tmp")),
+        }
+    }
+
+    /// The changes to make to builds of the project in `project_dir` in this environment.
+    pub(crate) fn adjustments(&self, project_dir: &Path) -> Vec<Adjustment> {
+        let mut adjustments = Vec::new();
+        if let Daemon::Sibling { .. } = self.daemon {
+            match host_path(&self.mounts, project_dir) {
+                Some(host_dir) => adjustments.push(Adjustment {
+                    env: "BUILDSYS_HOST_ROOT_DIR",
+                    value: host_dir.display().to_string(),
+                    reason: format!(
+                        "the docker daemon sees the project at '{}'",
+                        host_dir.display()
+                    ),
+                }),
+                None => adjustments.push(Adjustment {
+                    env: "BUILDSYS_BYPASS_COPY",
+                    value: "true".to_string(),
+                    reason: "the project is not on a volume shared with the docker daemon, so it \
+                        is copied into a volume for each build"
+                        .to_string(),
+                }),
+            }
+        }
+        if let Some(cpus) = self
+            .cpu_limit
+            .filter(|_| settings::var_os("CARGO_BUILD_JOBS").is_none())
+        {
+            adjustments.push(Adjustment {
+                env: "CARGO_BUILD_JOBS",
+                value: (cpus.ceil() as u64).max(1).to_string(),
+                reason: format!("the container is limited to {cpus} CPUs"),
+            });
+        }
+        if self.tmp_is_tmpfs && self.memory_limit.is_some() {
+            adjustments.push(Adjustment {
+                env: "TMPDIR",
+                value: project_dir.join("build/tmp").display().to_string(),
+                reason: "/tmp is held in memory, which counts against the container's memory \
+                    limit"
+                    .to_string(),
+            });
+        }
+        adjustments
+    }
+
+    /// The problems which would make builds fail in this environment.
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let (Daemon::Sibling { container }, Some(false)) = (&self.daemon, self.host_network) {
+            problems.push(format!(
+                "twoliter runs in container '{}', which does not share the docker host's \
+                network, but builds pass files over sockets in it; run the container with \
+                '--network host'",
+                container.as_deref().unwrap_or("unknown")
+            ));
+        }
+        problems
+    }
+}
+
+impl Display for ContainerEnvironment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+        writeln!(f, "  in a container: {}", yes_no(self.in_container))?;
+        writeln!(f, "  CI: {}", self.ci.as_deref().unwrap_or("none detected"))?;
+        let daemon = match &self.daemon {
+            Daemon::Host => "on this host".to_string(),
+            Daemon::Nested => "inside this container (docker-in-docker)".to_string(),
+            Daemon::Sibling { container } => format!(
+                "on the host of container '{}'",
+                container.as_deref().unwrap_or("unknown")
+            ),
+            Daemon::Unknown => "unknown".to_string(),
+        };
+        writeln!(f, "  docker daemon: {daemon}")?;
+        writeln!(f, "  rootless docker: {}", yes_no(self.rootless))?;
+        if let Some(host_network) = self.host_network {
+            writeln!(f, "  host network: {}", yes_no(host_network))?;
+        }
+        match self.cpu_limit {
+            Some(cpus) => writeln!(f, "  CPU limit: {cpus}")?,
+            None => writeln!(f, "  CPU limit: none")?,
+        }
+        match self.memory_limit {
+            Some(bytes) => writeln!(f, "  memory limit: {} MiB", bytes / (1024 * 1024))?,
+            None => writeln!(f, "  memory limit: none")?,
+        }
+        write!(f, "  /tmp in memory: {}", yes_no(self.tmp_is_tmpfs))
+    }
+}
+
+/// Detects the environment, fails on anything which would make builds of the project in
+/// `project_dir` fail, and returns the environment variables which adjust builds to it.
+pub(crate) async fn build_env(project_dir: &Path) -> Result<Vec<(&'static str, String)>> {
+    let environment = ContainerEnvironment::detect().await;
+    let problems = environment.problems();
+    if !problems.is_empty() {
+        bail!(
+            "Builds cannot run in this environment:\n  - {}\nRun 'twoliter doctor' for details",
+            problems.join("\n  - ")
+        );
+    }
+    let mut envs = Vec::new();
+    for adjustment in environment.adjustments(project_dir) {
+        info!(
+            "Setting {}={}, since {}",
+            adjustment.env, adjustment.value, adjustment.reason
+        );
+        if adjustment.env == "TMPDIR" {
+            create_dir_all(&adjustment.value).await?;
+        }
+        envs.push((adjustment.env, adjustment.value));
+    }
+    Ok(envs)
+}
+
+const HOSTNAME: &str = "/proc/sys/kernel/hostname";
+const CPU_MAX: &str = "/sys/fs/cgroup/cpu.max";
+const MEMORY_MAX: &str = "/sys/fs/cgroup/memory.max";
+
+fn read(path: &str) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerInfo {
+    name: String,
+    #[serde(default)]
+    security_options: Vec<String>,
+}
+
+/// The host of podman, which reports itself differently from docker's daemon.
+#[derive(Debug, Deserialize)]
+struct PodmanHost {
+    hostname: String,
+    security: PodmanSecurity,
+}
+
+#[derive(Debug, Deserialize)]
+struct PodmanSecurity {
+    rootless: bool,
+}
+
+async fn docker_info() -> Option<DockerInfo> {
+    let podman = Docker::tool().ok()? == ContainerTool::Podman;
+    let format = if podman {
+        "{{json .Host}}"
+    } else {
+        "{{json .}}"
+    };
+    let info = exec(
+        Docker::command().ok()?.args(["info", "--format", format]),
+        true,
+    )
+    .await
+    .ok()
+    .flatten()?;
+    if podman {
+        let host: PodmanHost = serde_json::from_str(&info).ok()?;
+        return Some(DockerInfo {
+            name: host.hostname,
+            security_options: host
+                .security
+                .rootless
+                .then(|| "name=rootless".to_string())
+                .into_iter()
+                .collect(),
+        });
+    }
+    serde_json::from_str(&info).ok()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerInspect {
+    #[serde(default)]
+    mounts: Vec<Mount>,
+    host_config: HostConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HostConfig {
+    network_mode: String,
+}
+
+async fn docker_inspect(container: &str) -> Option<DockerInspect> {
+    let inspect = exec(
+        Docker::command()
+            .ok()?
+            .args(["inspect", "--type", "container", "--format", "{{json .}}"])
+            .arg(container),
+        true,
+    )
+    .await
+    .ok()
+    .flatten()?;
+    serde_json::from_str(&inspect).ok()
+}
+
+/// The name of the CI system running twoliter, found through `var`.
+pub(crate) fn ci_name(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    CI_ENV_VARS
+        .iter()
+        .find(|(key, _)| var(key).is_some_and(|value| !value.is_empty() && value != "false"))
+        .map(|(_, name)| name.to_string())
+}
+
+/// Finds the id of the docker container in `/proc/self/mountinfo`, which mounts files such as
+/// `/etc/hostname` from the container's directory on the host.
+fn container_id(mountinfo: &str) -> Option<String> {
+    mountinfo.lines().find_map(|line| {
+        let (_, rest) = line.split_once("/containers/")?;
+        let id = rest.split('/').next()?;
+        (id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit())).then(|| id.to_string())
+    })
+}
+
+/// Parses cgroup v2's `cpu.max`, `<quota> <period>` or `max <period>`, into a number of CPUs.
+fn parse_cpu_max(cpu_max: &str) -> Option<f64> {
+    let (quota, period) = cpu_max.trim().split_once(' ')?;
+    let quota = quota.parse::<f64>().ok()?;
+    let period = period.parse::<f64>().ok().filter(|period| *period > 0.0)?;
+    Some(quota / period)
+}
+
+/// Parses cgroup v2's `memory.max`, a number of bytes or `max`.
+fn parse_memory_max(memory_max: &str) -> Option<u64> {
+    memory_max.trim().parse().ok()
+}
+
+/// Whether `mount_point` is a tmpfs according to the mount table `mounts`.
+fn is_tmpfs(mounts: &str, mount_point: &str) -> bool {
+    mounts.lines().any(|line| {
+        let mut fields = line.split_whitespace().skip(1);
+        fields.next() == Some(mount_point) && fields.next() == Some("tmpfs")
+    })
+}
+
+/// Where the docker host sees `path`, given the container's `mounts`.
+fn host_path(mounts: &[Mount], path: &Path) -> Option<PathBuf> {
+    mounts
+        .iter()
+        .filter(|mount| path.starts_with(&mount.destination))
+        .max_by_key(|mount| mount.destination.components().count())
+        .and_then(|mount| {
+            let relative = path.strip_prefix(&mount.destination).ok()?;
+            Some(mount.source.join(relative))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_container_id() {
+        let id = "0123456789abcdef".repeat(4);
+        let mountinfo = format!(
+            "100 90 8:1 /var/lib/docker/containers/{id}/hostname /etc/hostname rw - ext4 /dev/sda1 rw\n"
+        );
+        assert_eq!(container_id(&mountinfo), Some(id));
+        assert_eq!(
+            container_id("100 90 0:50 / / rw - overlay overlay rw\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_cgroup_limits() {
+        assert_eq!(parse_cpu_max("200000 100000\n"), Some(2.0));
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+        assert_eq!(parse_memory_max("4294967296\n"), Some(4294967296));
+        assert_eq!(parse_memory_max("max\n"), None);
+        let mounts = "overlay / overlay rw 0 0\ntmpfs /tmp tmpfs rw,nosuid 0 0\n";
+        assert!(is_tmpfs(mounts, "/tmp"));
+        assert!(!is_tmpfs(mounts, "/var/tmp"));
+    }
+
+    #[test]
+    fn test_ci_name() {
+        let var = |key: &str| (key == "GITLAB_CI" || key == "CI").then(|| "true".to_string());
+        assert_eq!(ci_name(var).as_deref(), Some("GitLab CI"));
+        assert_eq!(ci_name(|_| None), None);
+    }
+
+    #[test]
+    fn test_sibling_adjustments() {
+        let mounts = vec![
+            Mount {
+                source: PathBuf::from("/home/runner"),
+                destination: PathBuf::from("/work"),
+            },
+            Mount {
+                source: PathBuf::from("/srv/checkout"),
+                destination: PathBuf::from("/work/project"),
+            },
+        ];
+        assert_eq!(
+            host_path(&mounts, Path::new("/work/project/variants")),
+            Some(PathBuf::from("/srv/checkout/variants"))
+        );
+        assert_eq!(host_path(&mounts, Path::new("/src")), None);
+
+        let environment = ContainerEnvironment {
+            in_container: true,
+            ci: None,
+            daemon: Daemon::Sibling {
+                container: Some("runner".to_string()),
+            },
+            rootless: false,
+            mounts,
+            host_network: Some(false),
+            cpu_limit: None,
+            memory_limit: Some(1 << 30),
+            tmp_is_tmpfs: true,
+        };
+        let adjustments = environment.adjustments(Path::new("/src"));
+        assert_eq!(
+            adjustments
+                .iter()
+                .map(|adjustment| adjustment.env)
+                .collect::<Vec<_>>(),
+            ["BUILDSYS_BYPASS_COPY", "TMPDIR"]
+        );
+        assert_eq!(adjustments[1].value, "/src/build/tmp");
+        assert_eq!(environment.problems().len(), 1);
+    }
+}
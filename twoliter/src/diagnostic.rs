@@ -0,0 +1,191 @@
+//! Stable codes for the errors users most often run into, so that an error message can point to an
+//! extended explanation with `twoliter explain <CODE>`.
+use crate::messages::{self, msg};
+use anyhow::{anyhow, Error};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// A class of error with a stable code and an extended explanation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Code {
+    NoRegistryForImage,
+    MultipleKitVersions,
+    MultipleSdks,
+    NoSdk,
+    MissingLock,
+    StaleLock,
+    MissingKitMetadata,
+    UnsupportedKitMetadata,
+    LockDigestMismatch,
+    UnverifiedSignature,
+    UnrepresentablePath,
+    IncompatibleKit,
+    NewerLock,
+    ReleaseVersionMismatch,
+    PublishNotApproved,
+}
+
+impl Code {
+    pub(crate) const ALL: [Code; 15] = [
+        Code::NoRegistryForImage,
+        Code::MultipleKitVersions,
+        Code::MultipleSdks,
+        Code::NoSdk,
+        Code::MissingLock,
+        Code::StaleLock,
+        Code::MissingKitMetadata,
+        Code::UnsupportedKitMetadata,
+        Code::LockDigestMismatch,
+        Code::UnverifiedSignature,
+        Code::UnrepresentablePath,
+        Code::IncompatibleKit,
+        Code::NewerLock,
+        Code::ReleaseVersionMismatch,
+        Code::PublishNotApproved,
+    ];
+
+    /// The stable identifier for the error, e.g. `E0203`. Codes are grouped by area: `E01xx` for
+    /// project configuration, `E02xx` for the lockfile and kit images, and `E03xx` for release
+    /// metadata. Codes are never reused.
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Code::NoRegistryForImage => "E0101",
+            Code::MultipleKitVersions => "E0102",
+            Code::MultipleSdks => "E0103",
+            Code::NoSdk => "E0104",
+            Code::MissingLock => "E0201",
+            Code::StaleLock => "E0202",
+            Code::MissingKitMetadata => "E0203",
+            Code::UnsupportedKitMetadata => "E0204",
+            Code::LockDigestMismatch => "E0205",
+            Code::UnverifiedSignature => "E0206",
+            Code::UnrepresentablePath => "E0207",
+            Code::IncompatibleKit => "E0208",
+            Code::NewerLock => "E0209",
+            Code::ReleaseVersionMismatch => "E0301",
+            Code::PublishNotApproved => "E0302",
+        }
+    }
+
+    /// A short, human-readable name for the error.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Code::NoRegistryForImage => "no-registry-for-image",
+            Code::MultipleKitVersions => "multiple-kit-versions",
+            Code::MultipleSdks => "multiple-sdks",
+            Code::NoSdk => "no-sdk",
+            Code::MissingLock => "missing-lock",
+            Code::StaleLock => "stale-lock",
+            Code::MissingKitMetadata => "missing-kit-metadata",
+            Code::UnsupportedKitMetadata => "unsupported-kit-metadata",
+            Code::LockDigestMismatch => "lock-digest-mismatch",
+            Code::UnverifiedSignature => "unverified-signature",
+            Code::UnrepresentablePath => "unrepresentable-path",
+            Code::IncompatibleKit => "incompatible-kit",
+            Code::NewerLock => "newer-lock",
+            Code::ReleaseVersionMismatch => "release-version-mismatch",
+            Code::PublishNotApproved => "publish-not-approved",
+        }
+    }
+
+    /// An extended explanation of the error and how to fix it, from the message catalog.
+    pub(crate) fn explanation(&self) -> String {
+        messages::message(&format!("explain.{}", self.name()), &[])
+    }
+
+    /// Creates an error carrying this code, so that it is shown alongside `message`.
+    pub(crate) fn error(self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            code: self,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Code {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.code(), self.name())
+    }
+}
+
+impl FromStr for Code {
+    type Err = Error;
+
+    /// Accepts either the code, e.g. `E0203`, or the name, e.g. `missing-kit-metadata`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Code::ALL
+            .into_iter()
+            .find(|code| code.code().eq_ignore_ascii_case(s) || code.name() == s)
+            .ok_or_else(|| anyhow!("unknown diagnostic code '{s}'"))
+    }
+}
+
+/// An error with a diagnostic code, displayed with a pointer to `twoliter explain`.
+#[derive(Debug)]
+pub(crate) struct Diagnostic {
+    code: Code,
+    message: String,
+}
+
+impl Diagnostic {
+    pub(crate) fn code(&self) -> Code {
+        self.code
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let help = msg!(
+            "diagnostic.help",
+            code = self.code.code(),
+            message = self.message,
+        );
+        write!(f, "{help}")
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_codes_are_unique() {
+        let codes = Code::ALL.iter().map(Code::code).collect::<HashSet<_>>();
+        let names = Code::ALL.iter().map(Code::name).collect::<HashSet<_>>();
+        assert_eq!(codes.len(), Code::ALL.len());
+        assert_eq!(names.len(), Code::ALL.len());
+    }
+
+    #[test]
+    fn test_codes_are_explained() {
+        for code in Code::ALL {
+            assert!(
+                !code.explanation().starts_with("explain."),
+                "no explanation for {code}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_code() {
+        for code in Code::ALL {
+            assert_eq!(code.code().parse::<Code>().unwrap(), code);
+            assert_eq!(code.name().parse::<Code>().unwrap(), code);
+        }
+        assert_eq!("e0203".parse::<Code>().unwrap(), Code::MissingKitMetadata);
+        assert!("E9999".parse::<Code>().is_err());
+    }
+
+    #[test]
+    fn test_diagnostic_downcast() {
+        let err: Error = Code::StaleLock.error("lock is stale").into();
+        assert!(err.to_string().starts_with("[E0202] lock is stale"));
+        assert_eq!(
+            err.downcast_ref::<Diagnostic>().map(Diagnostic::code),
+            Some(Code::StaleLock)
+        );
+    }
+}
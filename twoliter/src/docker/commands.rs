@@ -1,30 +1,72 @@
 use crate::common::{exec, exec_log};
-use anyhow::{Context, Result};
+use crate::emulation::goarch;
+use anyhow::{ensure, Context, Result};
+use oci_cli_wrapper::container_tool::ContainerTool;
 use semver::Version;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tokio::process::Command;
 
 use super::ImageUri;
 
 pub(crate) struct Docker;
 
+/// The TLS configuration used to reach a remote buildkit daemon.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BuilderTls {
+    /// The CA certificate to verify the daemon's certificate against.
+    pub(crate) ca: Option<PathBuf>,
+    /// The client certificate to authenticate with.
+    pub(crate) cert: Option<PathBuf>,
+    /// The client certificate's key.
+    pub(crate) key: Option<PathBuf>,
+    /// The name to verify the daemon's certificate against, if not the endpoint's host.
+    pub(crate) server_name: Option<String>,
+}
+
+impl BuilderTls {
+    /// The `docker buildx create` driver options for this configuration.
+    fn driver_opts(&self) -> Vec<String> {
+        let paths = [
+            ("cacert", &self.ca),
+            ("cert", &self.cert),
+            ("key", &self.key),
+        ];
+        paths
+            .into_iter()
+            .filter_map(|(opt, path)| {
+                path.as_ref()
+                    .map(|path| format!("{opt}={}", path.display()))
+            })
+            .chain(
+                self.server_name
+                    .iter()
+                    .map(|name| format!("servername={name}")),
+            )
+            .collect()
+    }
+}
+
 impl Docker {
+    /// The container tool chosen by `TWOLITER_CONTAINER_TOOL`: docker, podman or finch, which all
+    /// accept docker's commands.
+    pub(crate) fn tool() -> Result<ContainerTool> {
+        Ok(ContainerTool::from_env()?)
+    }
+
+    /// A command which runs the container tool.
+    pub(crate) fn command() -> Result<Command> {
+        Ok(Command::new(Self::tool()?.command()))
+    }
+
     /// Loads an image tarball into the docker daemon from the given path
     pub(crate) async fn load(path: impl AsRef<Path>) -> Result<()> {
-        exec_log(
-            Command::new("docker")
-                .args(["load", "-i"])
-                .arg(path.as_ref()),
-        )
-        .await
+        exec_log(Self::command()?.args(["load", "-i"]).arg(path.as_ref())).await
     }
 
     /// Returns whether or not the docker daemon has cached an image with the given URI locally
     pub(crate) async fn image_is_cached(image_uri: &ImageUri) -> Result<bool> {
         let image_hash = exec(
-            Command::new("docker")
-                .args(["images", "-q"])
-                .arg(image_uri.uri()),
+            Self::command()?.args(["images", "-q"]).arg(image_uri.uri()),
             true,
         )
         .await
@@ -42,33 +84,111 @@ impl Docker {
         Ok(!image_hash.is_empty())
     }
 
+    /// Creates the buildx builder `name` for the remote buildkit daemon at `endpoint`, replacing any
+    /// builder of that name, and checks that the daemon can be reached.
+    pub(crate) async fn create_remote_builder(
+        name: &str,
+        endpoint: &str,
+        tls: &BuilderTls,
+    ) -> Result<()> {
+        let tool = Self::tool()?;
+        ensure!(
+            tool.has_buildx(),
+            "Remote builders need docker's buildx, which {tool} does not have"
+        );
+        let exists = exec(Self::command()?.args(["buildx", "inspect", name]), true)
+            .await
+            .is_ok();
+        if exists {
+            exec_log(Self::command()?.args(["buildx", "rm", name]))
+                .await
+                .context(format!("Failed to remove the existing builder '{name}'"))?;
+        }
+
+        let mut command = Self::command()?;
+        command.args([
+            "buildx",
+            "create",
+            "--name",
+            name,
+            "--driver",
+            "remote",
+            "--bootstrap",
+        ]);
+        let driver_opts = tls.driver_opts();
+        if !driver_opts.is_empty() {
+            command.arg("--driver-opt").arg(driver_opts.join(","));
+        }
+        exec_log(command.arg(endpoint)).await.context(format!(
+            "Failed to create builder '{name}' for '{endpoint}'"
+        ))
+    }
+
     /// Fetches the host platform in the form $OS/$GOARCH, e.g. linux/arm64
     pub(crate) async fn host_platform() -> Result<String> {
-        exec(
-            Command::new("docker").args(["version", "--format", "{{.Server.Os}}/{{.Server.Arch}}"]),
-            true,
-        )
-        .await
-        // Convert Result<Option<String>> to Option<String>
-        .ok()
-        .flatten()
-        .map(|s| s.trim().to_string())
-        .context("Failed to fetch host platform from docker")
+        let format = match Self::tool()? {
+            ContainerTool::Docker => "{{.Server.Os}}/{{.Server.Arch}}",
+            // Podman runs containers itself, rather than through a daemon.
+            ContainerTool::Podman => "{{.Client.OsArch}}",
+            // Finch runs containers in a Linux VM of the host's architecture, which macOS calls
+            // `arm64` on Apple silicon and Rust calls `aarch64`.
+            ContainerTool::Finch => {
+                return Ok(format!("linux/{}", goarch(std::env::consts::ARCH)));
+            }
+        };
+        exec(Self::command()?.args(["version", "--format", format]), true)
+            .await
+            // Convert Result<Option<String>> to Option<String>
+            .ok()
+            .flatten()
+            .map(|s| s.trim().to_string())
+            .context("Failed to fetch host platform from docker")
     }
 
-    /// Fetches the version of the docker daemon
+    /// Fetches the version of the docker daemon, or of podman or finch, which have none
     pub(crate) async fn server_version() -> Result<Version> {
-        let version_str = exec(
-            Command::new("docker").args(["version", "--format", "{{.Server.Version}}"]),
-            true,
-        )
-        .await
-        // Convert Result<Option<String>> to Option<String>
-        .ok()
-        .flatten()
-        .map(|s| s.trim().to_string())
-        .context("Failed to fetch docker version")?;
+        let mut command = Self::command()?;
+        match Self::tool()? {
+            ContainerTool::Docker => command.args(["version", "--format", "{{.Server.Version}}"]),
+            ContainerTool::Podman => command.args(["version", "--format", "{{.Client.Version}}"]),
+            // Finch prints e.g. `finch version v1.2.0`.
+            ContainerTool::Finch => command.arg("--version"),
+        };
+        let version_str = exec(&mut command, true)
+            .await
+            // Convert Result<Option<String>> to Option<String>
+            .ok()
+            .flatten()
+            .map(|s| s.trim().to_string())
+            .and_then(|s| s.split_whitespace().last().map(str::to_string))
+            .map(|s| s.trim_start_matches('v').to_string())
+            .context("Failed to fetch docker version")?;
 
         Version::parse(&version_str).context("Failed to parse docker version as semver")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builder_tls_driver_opts() {
+        assert!(BuilderTls::default().driver_opts().is_empty());
+        let tls = BuilderTls {
+            ca: Some(PathBuf::from("/certs/ca.pem")),
+            cert: Some(PathBuf::from("/certs/cert.pem")),
+            key: Some(PathBuf::from("/certs/key.pem")),
+            server_name: Some("buildkit.example.com".to_string()),
+        };
+        assert_eq!(
+            tls.driver_opts(),
+            [
+                "cacert=/certs/ca.pem",
+                "cert=/certs/cert.pem",
+                "key=/certs/key.pem",
+                "servername=buildkit.example.com",
+            ]
+        );
+    }
+}
@@ -10,7 +10,7 @@ pub(crate) struct ImageUri {
     pub(crate) registry: Option<String>,
     /// e.g. my-repo
     pub(crate) repo: String,
-    /// e.g. v0.31.0
+    /// e.g. v0.31.0, or a digest such as `sha256:...` for an image referenced by its digest
     pub(crate) tag: String,
 }
 
@@ -31,9 +31,11 @@ impl ImageUri {
 
     /// Returns the `ImageUri` for use with docker, e.g. `public.ecr.aws/myregistry/myrepo:v0.1.0`
     pub(crate) fn uri(&self) -> String {
+        // Tags cannot contain a colon, so one marks a digest, which is referenced with an '@'.
+        let separator = if self.tag.contains(':') { '@' } else { ':' };
         match &self.registry {
-            None => format!("{}:{}", self.repo, self.tag),
-            Some(registry) => format!("{}/{}:{}", registry, self.repo, self.tag),
+            None => format!("{}{separator}{}", self.repo, self.tag),
+            Some(registry) => format!("{}/{}{separator}{}", registry, self.repo, self.tag),
         }
     }
 }
@@ -58,6 +60,12 @@ fn image_uri_no_registry() {
     assert_eq!(expected, formatted);
 }
 
+#[test]
+fn image_uri_with_digest() {
+    let uri = ImageUri::new(Some("example.com".to_string()), "foo", "sha256:0123abcd");
+    assert_eq!(uri.uri(), "example.com/foo@sha256:0123abcd");
+}
+
 #[test]
 fn image_uri_with_registry() {
     let uri = ImageUri::new(Some("example.com/a/b/c".to_string()), "foo", "v1.2.3");
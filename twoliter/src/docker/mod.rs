@@ -2,4 +2,4 @@ mod commands;
 mod image;
 
 pub(crate) use self::image::ImageUri;
-pub(crate) use commands::Docker;
+pub(crate) use commands::{BuilderTls, Docker};
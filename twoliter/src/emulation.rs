@@ -0,0 +1,136 @@
+//! Handles builds whose target architecture differs from the host's, e.g. aarch64 builds on an
+//! x86_64 host.
+//!
+//! Packages are cross-compiled by the SDK, but some build steps run target binaries, which needs
+//! QEMU registered with the kernel's `binfmt_misc`. Twoliter checks for the registration before
+//! such builds and, when `--configure-emulation` is given, installs it with the `binfmt` image.
+//! Emulated steps are much slower than native ones, so builds can instead be delegated to a native
+//! worker named by `TWOLITER_NATIVE_WORKER_<ARCH>`, e.g. `TWOLITER_NATIVE_WORKER_AARCH64`. The
+//! worker is an `ssh` destination which must have twoliter installed, and see the project and the
+//! cargo home at the same paths as this host, e.g. through a shared filesystem.
+use crate::common::exec_log;
+use crate::docker::Docker;
+use crate::warnings;
+use anyhow::{ensure, Result};
+use krane_static::settings;
+use std::path::Path;
+use tracing::info;
+
+/// The prefix of the environment variables naming the native worker for an architecture.
+pub(crate) const NATIVE_WORKER_ENV_PREFIX: &str = "TWOLITER_NATIVE_WORKER_";
+
+/// The image which registers QEMU with `binfmt_misc`.
+const BINFMT_IMAGE: &str = "docker.io/tonistiigi/binfmt:qemu-v8.1.5";
+
+const BINFMT_MISC_DIR: &str = "/proc/sys/fs/binfmt_misc";
+
+/// Whether builds for `arch` cannot run natively on this host.
+pub(crate) fn is_foreign(arch: &str) -> bool {
+    arch != std::env::consts::ARCH
+}
+
+/// The native worker configured for `arch`, if any.
+pub(crate) fn native_worker(arch: &str) -> Option<String> {
+    settings::var(native_worker_env(arch))
+        .ok()
+        .filter(|worker| !worker.is_empty())
+}
+
+fn native_worker_env(arch: &str) -> String {
+    format!("{NATIVE_WORKER_ENV_PREFIX}{}", arch.to_uppercase())
+}
+
+/// Makes sure that binaries for the foreign `arch` can run on this host, registering QEMU if
+/// `configure` is set. Warns if emulation is left unavailable, and about its cost otherwise.
+pub(crate) async fn ensure_emulation(arch: &str, configure: bool) -> Result<()> {
+    let registered = emulation_registered(arch);
+    if !registered && configure {
+        register_emulation(arch).await?;
+        ensure!(
+            emulation_registered(arch),
+            "QEMU emulation for '{arch}' is still unavailable after installing it with '{BINFMT_IMAGE}'"
+        );
+    } else if !registered {
+        warnings::warn(format!(
+            "Building for '{arch}' on a '{host}' host, but QEMU emulation for '{arch}' is not \
+            registered with binfmt_misc. Build steps which run '{arch}' binaries will fail. Pass \
+            --configure-emulation to register it, or set {env} to build on a native worker.",
+            host = std::env::consts::ARCH,
+            env = native_worker_env(arch),
+        ));
+        return Ok(());
+    }
+    warnings::warn(format!(
+        "Building for '{arch}' on a '{host}' host. Build steps which run '{arch}' binaries are \
+        emulated with QEMU and may be many times slower. Set {env} to build on a native worker.",
+        host = std::env::consts::ARCH,
+        env = native_worker_env(arch),
+    ));
+    Ok(())
+}
+
+fn emulation_registered(arch: &str) -> bool {
+    std::fs::read_to_string(Path::new(BINFMT_MISC_DIR).join(format!("qemu-{arch}")))
+        .map(|status| handler_enabled(&status))
+        .unwrap_or(false)
+}
+
+/// Whether the `binfmt_misc` handler `status` says that the handler is in use.
+fn handler_enabled(status: &str) -> bool {
+    status.lines().next().map(str::trim) == Some("enabled")
+}
+
+async fn register_emulation(arch: &str) -> Result<()> {
+    info!("Registering QEMU emulation for '{arch}' with '{BINFMT_IMAGE}'");
+    exec_log(
+        Docker::command()?
+            .args(["run", "--privileged", "--rm", BINFMT_IMAGE, "--install"])
+            .arg(goarch(arch)),
+    )
+    .await
+}
+
+/// The name Docker and `binfmt` use for `arch`.
+pub(crate) fn goarch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        other => other,
+    }
+}
+
+/// Quotes `arg` so that the remote shell started by `ssh` passes it through unchanged.
+pub(crate) fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_handler_enabled() {
+        assert!(handler_enabled(
+            "enabled\ninterpreter /usr/bin/qemu-aarch64\nflags: POCF\n"
+        ));
+        assert!(!handler_enabled(
+            "disabled\ninterpreter /usr/bin/qemu-aarch64\n"
+        ));
+        assert!(!handler_enabled(""));
+    }
+
+    #[test]
+    fn test_native_worker_env() {
+        assert_eq!(
+            native_worker_env("aarch64"),
+            "TWOLITER_NATIVE_WORKER_AARCH64"
+        );
+        assert_eq!(goarch("x86_64"), "amd64");
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("build"), "'build'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+}
@@ -0,0 +1,108 @@
+//! Thin wrappers around the `git` CLI, used by commands which record their results in the
+//! project's repository.
+use crate::common::{exec, exec_log};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+pub(crate) struct Git;
+
+impl Git {
+    /// Creates and switches to a new branch in the repository containing `repo_dir`.
+    pub(crate) async fn create_branch(repo_dir: impl AsRef<Path>, branch: &str) -> Result<()> {
+        exec_log(
+            Command::new("git")
+                .arg("-C")
+                .arg(repo_dir.as_ref())
+                .args(["checkout", "-b", branch]),
+        )
+        .await
+    }
+
+    /// Commits the given paths, and only those paths, to the repository containing `repo_dir`.
+    /// Anything else that is staged is left staged.
+    pub(crate) async fn commit<P: AsRef<Path>>(
+        repo_dir: impl AsRef<Path>,
+        paths: &[P],
+        message: &str,
+    ) -> Result<()> {
+        let paths = paths.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+        exec_log(
+            Command::new("git")
+                .arg("-C")
+                .arg(repo_dir.as_ref())
+                .args(["add", "--"])
+                .args(&paths),
+        )
+        .await?;
+        exec_log(
+            Command::new("git")
+                .arg("-C")
+                .arg(repo_dir.as_ref())
+                .args(["commit", "--message", message, "--"])
+                .args(&paths),
+        )
+        .await
+    }
+
+    /// The root of the working tree containing `repo_dir`.
+    pub(crate) async fn toplevel(repo_dir: impl AsRef<Path>) -> Result<PathBuf> {
+        let output = Self::output(repo_dir, &["rev-parse", "--show-toplevel"]).await?;
+        Ok(PathBuf::from(output.trim()))
+    }
+
+    /// The id that the build system gives builds of the commit checked out in `repo_dir`: its
+    /// abbreviated hash, marked `-dirty` if there are uncommitted changes. Builds outside of git
+    /// repositories have the id `00000000`, as they do in the build system.
+    pub(crate) async fn build_id(repo_dir: impl AsRef<Path>) -> String {
+        Self::output(
+            repo_dir,
+            &["describe", "--always", "--dirty", "--exclude", "*"],
+        )
+        .await
+        .map_or("00000000".to_string(), |output| output.trim().to_string())
+    }
+
+    /// Whether the repository containing `repo_dir` is a sparse checkout. Directories outside of
+    /// git repositories are not.
+    pub(crate) async fn is_sparse(repo_dir: impl AsRef<Path>) -> bool {
+        Self::output(repo_dir, &["config", "--bool", "core.sparseCheckout"])
+            .await
+            .is_ok_and(|output| output.trim() == "true")
+    }
+
+    /// The patterns of the sparse checkout of the repository containing `repo_dir`.
+    pub(crate) async fn sparse_patterns(repo_dir: impl AsRef<Path>) -> Result<Vec<String>> {
+        let output = Self::output(repo_dir, &["sparse-checkout", "list"]).await?;
+        Ok(output.lines().map(str::to_string).collect())
+    }
+
+    /// Replaces the patterns of the sparse checkout of the repository containing `repo_dir`, which
+    /// checks out the files they match. In a partial clone, git fetches any of those files which
+    /// it does not have yet.
+    pub(crate) async fn set_sparse_patterns(
+        repo_dir: impl AsRef<Path>,
+        patterns: &[String],
+    ) -> Result<()> {
+        exec_log(
+            Command::new("git")
+                .arg("-C")
+                .arg(repo_dir.as_ref())
+                .args(["sparse-checkout", "set", "--no-cone", "--"])
+                .args(patterns),
+        )
+        .await
+    }
+
+    async fn output(repo_dir: impl AsRef<Path>, args: &[&str]) -> Result<String> {
+        exec(
+            Command::new("git")
+                .arg("-C")
+                .arg(repo_dir.as_ref())
+                .args(args),
+            true,
+        )
+        .await?
+        .context(format!("git {} produced no output", args.join(" ")))
+    }
+}
@@ -0,0 +1,214 @@
+//! What the host that twoliter runs on can do.
+//!
+//! Twoliter can resolve, fetch and extract kits on Linux, macOS and Windows hosts, so that a lock
+//! can be managed and kits inspected anywhere. Builds run Linux tools in containers which share
+//! sockets and file descriptors with the host, so they need a Linux host. On Windows, that can be a
+//! WSL2 distribution with Docker Desktop's WSL integration enabled for it. On macOS, `twoliter make`
+//! can hand builds to a Linux machine named by `TWOLITER_NATIVE_WORKER_<ARCH>`, such as the VM that
+//! Colima runs docker in.
+//!
+//! Custom build steps only mount files of the project into the SDK, so they can also run on macOS,
+//! in the Linux VM of Finch, Docker Desktop or Colima, each of which shares the user's home
+//! directory with the VM at the same path. The VM has the host's architecture, so an Apple silicon
+//! Mac runs the SDK for `aarch64`.
+use crate::common::exec;
+use crate::docker::Docker;
+use crate::emulation::NATIVE_WORKER_ENV_PREFIX;
+use crate::warnings;
+use anyhow::{bail, Result};
+use krane_static::settings;
+use std::fmt::{Display, Formatter};
+use std::path::{Component, Path, PathBuf};
+
+/// Whether builds can run on this host.
+pub(crate) const BUILDS_SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// Fails with advice on where to build instead when builds cannot run on this host, and warns
+/// when `build_dir` is somewhere builds will be slow.
+pub(crate) async fn ensure_builds_supported(build_dir: &Path) -> Result<()> {
+    if !BUILDS_SUPPORTED {
+        bail!(
+            "Builds need a Linux host, but twoliter is running on {}. {}",
+            std::env::consts::OS,
+            build_elsewhere().await
+        );
+    }
+    if is_wsl() && on_windows_drive(build_dir) {
+        warnings::warn(format!(
+            "Building in '{}', which is on a Windows drive. Builds in WSL read and write Windows \
+            drives much more slowly than the distribution's own filesystem, and file permissions \
+            may not be kept. Clone the project into the WSL filesystem, e.g. under your home \
+            directory, for faster builds.",
+            build_dir.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Fails when containers which mount `project_dir` cannot run on this host. On macOS, they run in
+/// a VM which only sees the user's home directory.
+pub(crate) fn ensure_containers_supported(project_dir: &Path) -> Result<()> {
+    if BUILDS_SUPPORTED {
+        return Ok(());
+    }
+    if cfg!(target_os = "macos") {
+        let home = settings::var_os("HOME").map(PathBuf::from);
+        if let Some(home) = home.filter(|home| !project_dir.starts_with(home)) {
+            bail!(
+                "The project in '{}' is outside your home directory '{}', which is all that the \
+                VM running {} shares with it. Move the project under your home directory.",
+                project_dir.display(),
+                home.display(),
+                Docker::tool()?
+            );
+        }
+        return Ok(());
+    }
+    bail!(
+        "Containers need a Linux or macOS host, but twoliter is running on {}. Run twoliter from a \
+        WSL2 distribution with Docker Desktop's WSL integration enabled for it.",
+        std::env::consts::OS
+    )
+}
+
+/// Advice on where to run builds that cannot run on this host.
+async fn build_elsewhere() -> String {
+    if cfg!(windows) {
+        return "Run twoliter from a WSL2 distribution with Docker Desktop's WSL integration \
+            enabled for it, or from a Linux machine."
+            .to_string();
+    }
+    let mut advice = format!(
+        "Run twoliter from a Linux machine, or run `twoliter make` with \
+        {NATIVE_WORKER_ENV_PREFIX}<ARCH> set to the ssh destination of a Linux machine which sees \
+        the project at the same path, to build there."
+    );
+    match DockerVm::detect().await {
+        Some(DockerVm::Colima { profile }) => advice.push_str(&format!(
+            " Colima's VM mounts your home directory at the same path, and `colima ssh-config \
+            --profile {profile}` prints an ssh destination for it."
+        )),
+        Some(DockerVm::Finch) => advice.push_str(
+            " Finch's VM mounts your home directory at the same path, but is not reachable with \
+            ssh, so it cannot be the worker.",
+        ),
+        Some(DockerVm::DockerDesktop) | None => (),
+    }
+    advice
+}
+
+/// The virtual machine which runs the docker daemon, or finch's containers, on a macOS or Windows
+/// host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DockerVm {
+    DockerDesktop,
+    /// A Colima VM, which belongs to a Colima profile.
+    Colima {
+        profile: String,
+    },
+    /// The Lima VM which Finch manages.
+    Finch,
+}
+
+impl DockerVm {
+    /// Asks the docker daemon which VM, if any, it is running in.
+    pub(crate) async fn detect() -> Option<Self> {
+        let info = exec(
+            Docker::command()
+                .ok()?
+                .args(["info", "--format", "{{.Name}}\t{{.OperatingSystem}}"]),
+            true,
+        )
+        .await
+        .ok()
+        .flatten()?;
+        Self::from_info(&info)
+    }
+
+    /// Reads the VM from the daemon's host name and operating system, separated by a tab.
+    fn from_info(info: &str) -> Option<Self> {
+        let (name, os) = info.trim().split_once('\t')?;
+        if os == "Docker Desktop" {
+            Some(DockerVm::DockerDesktop)
+        } else if name == "lima-finch" {
+            Some(DockerVm::Finch)
+        } else if name == "colima" {
+            Some(DockerVm::Colima {
+                profile: "default".to_string(),
+            })
+        } else {
+            name.strip_prefix("colima-")
+                .map(|profile| DockerVm::Colima {
+                    profile: profile.to_string(),
+                })
+        }
+    }
+}
+
+impl Display for DockerVm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DockerVm::DockerDesktop => write!(f, "Docker Desktop"),
+            DockerVm::Colima { profile } => write!(f, "Colima (profile '{profile}')"),
+            DockerVm::Finch => write!(f, "Finch"),
+        }
+    }
+}
+
+/// Whether twoliter is running in a WSL distribution.
+pub(crate) fn is_wsl() -> bool {
+    std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .is_ok_and(|release| release.to_lowercase().contains("microsoft"))
+}
+
+/// Whether `path` is on a Windows drive that WSL mounts, such as `/mnt/c`.
+pub(crate) fn on_windows_drive(path: &Path) -> bool {
+    let mut components = path.components();
+    matches!(
+        (components.next(), components.next(), components.next()),
+        (Some(Component::RootDir), Some(Component::Normal(mnt)), Some(Component::Normal(drive)))
+            if mnt == "mnt"
+                && drive.len() == 1
+                && drive.to_str().is_some_and(|drive| drive.chars().all(|c| c.is_ascii_alphabetic()))
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_on_windows_drive() {
+        assert!(on_windows_drive(Path::new("/mnt/c/Users/me/project")));
+        assert!(on_windows_drive(Path::new("/mnt/D")));
+        assert!(!on_windows_drive(Path::new("/mnt/data/project")));
+        assert!(!on_windows_drive(Path::new("/home/me/mnt/c")));
+        assert!(!on_windows_drive(Path::new("mnt/c")));
+    }
+
+    #[test]
+    fn test_docker_vm_from_info() {
+        assert_eq!(
+            DockerVm::from_info("docker-desktop\tDocker Desktop\n"),
+            Some(DockerVm::DockerDesktop)
+        );
+        assert_eq!(
+            DockerVm::from_info("colima\tUbuntu 24.04 LTS"),
+            Some(DockerVm::Colima {
+                profile: "default".to_string()
+            })
+        );
+        assert_eq!(
+            DockerVm::from_info("colima-arm\tUbuntu 24.04 LTS"),
+            Some(DockerVm::Colima {
+                profile: "arm".to_string()
+            })
+        );
+        assert_eq!(
+            DockerVm::from_info("lima-finch\tFedora Linux 41 (Cloud Edition)"),
+            Some(DockerVm::Finch)
+        );
+        assert_eq!(DockerVm::from_info("builder\tAmazon Linux 2023"), None);
+        assert_eq!(DockerVm::from_info(""), None);
+    }
+}
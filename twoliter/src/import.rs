@@ -0,0 +1,666 @@
+//! Converts a checkout of a Bottlerocket build from before kits, with its packages and variants in
+//! their own cargo workspaces, into a twoliter project, to ease the migration of downstream forks.
+//!
+//! The packages, variants and sources are copied into a new project directory. The packages are
+//! grouped into kits, all of them into one kit unless some are assigned to others, and each
+//! variant's build dependencies on packages are replaced with dependencies on the kits holding
+//! them. A `Twoliter.toml` is written with the release version from `Release.toml` and the SDK
+//! version the checkout built with, along with a cargo workspace for the whole project. The
+//! checkout itself is left as it was.
+use crate::common::fs::{copy, create_dir_all, read_to_string, write};
+use anyhow::{bail, ensure, Context, Result};
+use async_recursion::async_recursion;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
+use toml::Table;
+use tracing::{debug, info};
+
+/// The vendor of the Bottlerocket SDK.
+const SDK_VENDOR: &str = "bottlerocket";
+
+/// The registry the Bottlerocket SDK is published to.
+const SDK_REGISTRY: &str = "public.ecr.aws/bottlerocket";
+
+/// Entries of the checkout which are never copied, because they are build outputs or belong to
+/// the build system that twoliter replaces.
+const SKIPPED: &[&str] = &["target", "build", ".cargo", ".gomodcache"];
+
+const BUILD_RS: &str = r#"use std::process::{exit, Command};
+
+fn main() -> Result<(), std::io::Error> {
+    let ret = Command::new("buildsys").arg("{command}").status()?;
+    if !ret.success() {
+        exit(1);
+    }
+    Ok(())
+}
+"#;
+
+const LIB_RS: &str = r#"/*!
+
+This is an intentionally empty file that all of the {kind} `Cargo.toml` files can point to as their
+`lib.rs`. The build system uses `build.rs` to invoke `buildsys` but Cargo needs something to compile
+so we give it an empty `lib.rs` file.
+
+!*/
+"#;
+
+const GITIGNORE: &str = "/build/
+**/target/
+/.cargo/
+/.gomodcache/
+/keys/
+/roles/
+/sbkeys/
+Test.toml
+testsys.kubeconfig
+Infra.toml
+";
+
+/// How to convert a legacy checkout.
+#[derive(Debug, Clone)]
+pub(crate) struct LegacyImport {
+    /// The legacy checkout.
+    pub(crate) source: PathBuf,
+    /// Where to create the project, which must not exist or be empty.
+    pub(crate) output: PathBuf,
+    /// The vendor the project's kits are published by.
+    pub(crate) vendor: String,
+    /// The registry the vendor publishes to.
+    pub(crate) registry: String,
+    /// The kit holding every package not assigned to another kit.
+    pub(crate) kit: String,
+    /// The kits of the packages assigned to other kits, keyed by the package's directory.
+    pub(crate) package_kits: BTreeMap<String, String>,
+    /// The SDK version, if it should not be read from the checkout.
+    pub(crate) sdk_version: Option<String>,
+    /// The release version, if it should not be read from the checkout's `Release.toml`.
+    pub(crate) release_version: Option<String>,
+}
+
+/// A package of the checkout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Package {
+    /// The package's directory within `packages`.
+    dir: String,
+    /// The package's crate name.
+    name: String,
+}
+
+/// What was converted.
+#[derive(Debug, Default)]
+pub(crate) struct ImportReport {
+    /// The packages in each kit.
+    pub(crate) kits: BTreeMap<String, Vec<String>>,
+    pub(crate) variants: Vec<String>,
+}
+
+impl LegacyImport {
+    pub(crate) async fn run(&self) -> Result<ImportReport> {
+        let source = &self.source;
+        ensure!(
+            source.join("packages").is_dir() && source.join("variants").is_dir(),
+            "'{}' does not look like a Bottlerocket checkout, which has packages and variants \
+            directories",
+            source.display()
+        );
+        ensure!(
+            !source.join("Twoliter.toml").is_file() || !source.join("kits").exists(),
+            "'{}' is already a twoliter project with kits",
+            source.display()
+        );
+        if self.output.exists() {
+            ensure!(
+                self.output.read_dir()?.next().is_none(),
+                "'{}' already exists and is not empty",
+                self.output.display()
+            );
+        }
+        let release_version = match &self.release_version {
+            Some(version) => version.clone(),
+            None => release_version(source).await?,
+        };
+        let sdk_version = match &self.sdk_version {
+            Some(version) => version.clone(),
+            None => sdk_version(source).await?,
+        };
+
+        let packages = packages(&source.join("packages")).await?;
+        for package in self.package_kits.keys() {
+            ensure!(
+                packages.iter().any(|p| &p.dir == package),
+                "there is no package '{package}' to assign to a kit"
+            );
+        }
+        let mut report = ImportReport::default();
+        for package in &packages {
+            report
+                .kits
+                .entry(self.kit_of(package).to_string())
+                .or_default()
+                .push(package.dir.clone());
+        }
+
+        let output = &self.output;
+        create_dir_all(output).await?;
+        for dir in ["packages", "variants", "sources"] {
+            if source.join(dir).is_dir() {
+                info!("Copying {dir}");
+                copy_tree(&source.join(dir), &output.join(dir), true).await?;
+            }
+        }
+        for (kind, command) in [("package", "build-package"), ("variant", "build-variant")] {
+            let dir = output.join(format!("{kind}s"));
+            write_if_missing(
+                &dir.join("build.rs"),
+                &BUILD_RS.replace("{command}", command),
+            )
+            .await?;
+            write_if_missing(
+                &dir.join(format!("{kind}s.rs")),
+                &LIB_RS.replace("{kind}", kind),
+            )
+            .await?;
+        }
+
+        let kits_dir = output.join("kits");
+        create_dir_all(&kits_dir).await?;
+        write(
+            kits_dir.join("build.rs"),
+            BUILD_RS.replace("{command}", "build-kit"),
+        )
+        .await?;
+        write(kits_dir.join("kit.rs"), LIB_RS.replace("{kind}", "kit")).await?;
+        for kit in report.kits.keys() {
+            let members = packages.iter().filter(|p| self.kit_of(p) == kit.as_str());
+            create_dir_all(kits_dir.join(kit)).await?;
+            write(
+                kits_dir.join(kit).join("Cargo.toml"),
+                self.kit_manifest(kit, members),
+            )
+            .await?;
+        }
+
+        let variants = variants(&output.join("variants")).await?;
+        for variant in &variants {
+            let manifest_path = output.join("variants").join(variant).join("Cargo.toml");
+            let manifest = read_to_string(&manifest_path).await?;
+            let deps = self.variant_dependencies(&manifest, &packages)?;
+            write(&manifest_path, replace_build_dependencies(&manifest, &deps)).await?;
+            debug!("Converted variant '{variant}'");
+        }
+        report.variants = variants;
+
+        write(
+            output.join("Twoliter.toml"),
+            self.project_file(&release_version, &sdk_version),
+        )
+        .await?;
+        write(output.join("Cargo.toml"), workspace(&report, &packages)).await?;
+        write_if_missing(&output.join(".gitignore"), GITIGNORE).await?;
+        Ok(report)
+    }
+
+    fn kit_of<'a>(&'a self, package: &Package) -> &'a str {
+        self.package_kits
+            .get(&package.dir)
+            .map(String::as_str)
+            .unwrap_or(&self.kit)
+    }
+
+    fn kit_manifest<'a>(&self, kit: &str, packages: impl Iterator<Item = &'a Package>) -> String {
+        let mut manifest = format!(
+            r#"[package]
+name = "{kit}"
+version = "0.1.0"
+edition = "2021"
+publish = false
+build = "../build.rs"
+
+[package.metadata.build-kit]
+vendor = "{}"
+
+[lib]
+path = "../kit.rs"
+
+[build-dependencies]
+"#,
+            self.vendor
+        );
+        for package in packages {
+            manifest.push_str(&format!(
+                "{} = {{ path = \"../../packages/{}\" }}\n",
+                package.name, package.dir
+            ));
+        }
+        manifest
+    }
+
+    /// The build dependencies of the variant with the given `manifest`, with its dependencies on
+    /// `packages` replaced by dependencies on their kits.
+    fn variant_dependencies(
+        &self,
+        manifest: &str,
+        packages: &[Package],
+    ) -> Result<Vec<(String, String)>> {
+        let manifest: Table = toml::from_str(manifest).context("failed to parse variant")?;
+        let mut kits = BTreeSet::new();
+        let mut others = Vec::new();
+        let deps = manifest
+            .get("build-dependencies")
+            .and_then(|deps| deps.as_table())
+            .into_iter()
+            .flatten();
+        for (name, dep) in deps {
+            let Some(path) = dep.get("path").and_then(|path| path.as_str()) else {
+                bail!("build dependency '{name}' is not a path dependency");
+            };
+            match package_dir(Path::new(path)) {
+                Some(dir) => match packages.iter().find(|p| p.dir == dir) {
+                    Some(package) => {
+                        kits.insert(self.kit_of(package).to_string());
+                    }
+                    None => bail!("build dependency '{name}' refers to a missing package"),
+                },
+                None => others.push((name.clone(), path.to_string())),
+            }
+        }
+        Ok(kits
+            .into_iter()
+            .map(|kit| (kit.clone(), format!("../../kits/{kit}")))
+            .chain(others)
+            .collect())
+    }
+
+    fn project_file(&self, release_version: &str, sdk_version: &str) -> String {
+        let mut project = format!(
+            r#"schema-version = 1
+release-version = "{release_version}"
+
+[sdk]
+name = "bottlerocket-sdk"
+vendor = "{SDK_VENDOR}"
+version = "{sdk_version}"
+"#
+        );
+        if self.vendor != SDK_VENDOR {
+            project.push_str(&format!(
+                "\n[vendor.{SDK_VENDOR}]\nregistry = \"{SDK_REGISTRY}\"\n"
+            ));
+        }
+        project.push_str(&format!(
+            "\n[vendor.{}]\nregistry = \"{}\"\n",
+            self.vendor, self.registry
+        ));
+        project
+    }
+}
+
+/// The release version from the checkout's `Release.toml`.
+async fn release_version(source: &Path) -> Result<String> {
+    let path = source.join("Release.toml");
+    ensure!(
+        path.is_file(),
+        "'{}' has no Release.toml to read the release version from; give it with \
+        --release-version",
+        source.display()
+    );
+    let release: Table = toml::from_str(&read_to_string(&path).await?)
+        .context(format!("failed to parse '{}'", path.display()))?;
+    release
+        .get("version")
+        .and_then(|version| version.as_str())
+        .map(ToString::to_string)
+        .context(format!("'{}' has no version", path.display()))
+}
+
+/// The SDK version the checkout builds with, from its `Twoliter.toml` or else its `Makefile.toml`.
+async fn sdk_version(source: &Path) -> Result<String> {
+    let twoliter_toml = source.join("Twoliter.toml");
+    if twoliter_toml.is_file() {
+        let project: Table = toml::from_str(&read_to_string(&twoliter_toml).await?)
+            .context(format!("failed to parse '{}'", twoliter_toml.display()))?;
+        if let Some(version) = project
+            .get("sdk")
+            .and_then(|sdk| sdk.get("version"))
+            .and_then(|version| version.as_str())
+        {
+            return Ok(version.trim_start_matches('v').to_string());
+        }
+    }
+    let makefile = source.join("Makefile.toml");
+    if makefile.is_file() {
+        if let Some(version) = makefile_sdk_version(&read_to_string(&makefile).await?) {
+            return Ok(version);
+        }
+    }
+    bail!(
+        "unable to find the SDK version '{}' builds with; give it with --sdk-version",
+        source.display()
+    )
+}
+
+/// The SDK version set in a legacy `Makefile.toml`.
+fn makefile_sdk_version(makefile: &str) -> Option<String> {
+    let makefile: Table = toml::from_str(makefile).ok()?;
+    let version = makefile.get("env")?.get("BUILDSYS_SDK_VERSION")?.as_str()?;
+    Some(version.trim_start_matches('v').to_string())
+}
+
+/// The packages in the checkout's `packages` directory.
+async fn packages(dir: &Path) -> Result<Vec<Package>> {
+    let mut packages = Vec::new();
+    for name in crate_dirs(dir).await? {
+        let manifest_path = dir.join(&name).join("Cargo.toml");
+        let manifest: Table = toml::from_str(&read_to_string(&manifest_path).await?)
+            .context(format!("failed to parse '{}'", manifest_path.display()))?;
+        let crate_name = manifest
+            .get("package")
+            .and_then(|package| package.get("name"))
+            .and_then(|name| name.as_str())
+            .context(format!("'{}' has no package name", manifest_path.display()))?;
+        packages.push(Package {
+            dir: name,
+            name: crate_name.to_string(),
+        });
+    }
+    Ok(packages)
+}
+
+/// The variants in the project's `variants` directory.
+async fn variants(dir: &Path) -> Result<Vec<String>> {
+    let mut variants = Vec::new();
+    for name in crate_dirs(dir).await? {
+        let manifest = read_to_string(dir.join(&name).join("Cargo.toml")).await?;
+        if manifest.contains("build-variant") {
+            variants.push(name);
+        }
+    }
+    Ok(variants)
+}
+
+/// The names of the directories in `dir` which hold a crate, in order.
+async fn crate_dirs(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .context(format!("failed to read directory '{}'", dir.display()))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context(format!("failed to read directory '{}'", dir.display()))?
+    {
+        if entry.path().join("Cargo.toml").is_file() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Copies the directory `from` to `to`, leaving out build outputs. At the `top` of the copy, the
+/// checkout's workspace manifest and lock are left out as well, since the project has its own.
+#[async_recursion]
+async fn copy_tree(from: &Path, to: &Path, top: bool) -> Result<()> {
+    create_dir_all(to).await?;
+    let mut entries = tokio::fs::read_dir(from)
+        .await
+        .context(format!("failed to read directory '{}'", from.display()))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context(format!("failed to read directory '{}'", from.display()))?
+    {
+        let name = entry.file_name();
+        let name_str = name.to_string_lossy();
+        let is_workspace_file = top && matches!(name_str.as_ref(), "Cargo.toml" | "Cargo.lock");
+        if SKIPPED.contains(&name_str.as_ref()) || is_workspace_file {
+            continue;
+        }
+        let file_type = entry
+            .file_type()
+            .await
+            .context(format!("failed to inspect '{}'", entry.path().display()))?;
+        if file_type.is_dir() {
+            copy_tree(&entry.path(), &to.join(&name), false).await?;
+        } else if file_type.is_symlink() {
+            let target = tokio::fs::read_link(entry.path())
+                .await
+                .context(format!("failed to read link '{}'", entry.path().display()))?;
+            tokio::fs::symlink(&target, to.join(&name))
+                .await
+                .context(format!(
+                    "failed to create link '{}'",
+                    to.join(&name).display()
+                ))?;
+        } else {
+            copy(entry.path(), to.join(&name)).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn write_if_missing(path: &Path, contents: &str) -> Result<()> {
+    if !path.exists() {
+        write(path, contents).await?;
+    }
+    Ok(())
+}
+
+/// The directory within `packages` that the dependency path `path` of a variant refers to.
+fn package_dir(path: &Path) -> Option<String> {
+    let components = path
+        .components()
+        .filter(|component| !matches!(component, Component::CurDir | Component::ParentDir))
+        .collect::<Vec<_>>();
+    match components.as_slice() {
+        [Component::Normal(packages), Component::Normal(dir)] if *packages == "packages" => {
+            Some(dir.to_string_lossy().to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Replaces the `[build-dependencies]` of the cargo manifest `manifest` with `deps`, given as the
+/// name and path of each, keeping the rest of the manifest as it is.
+fn replace_build_dependencies(manifest: &str, deps: &[(String, String)]) -> String {
+    let mut replaced = String::with_capacity(manifest.len());
+    let mut in_section = false;
+    let mut found = false;
+    for line in manifest.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_section = trimmed == "[build-dependencies]";
+            if in_section {
+                found = true;
+                replaced.push_str(line);
+                for (name, path) in deps {
+                    replaced.push_str(&format!("{name} = {{ path = \"{path}\" }}\n"));
+                }
+                continue;
+            }
+        }
+        // Comments in the section are kept, since they often explain the dependencies.
+        if !in_section || trimmed.is_empty() || trimmed.starts_with('#') {
+            replaced.push_str(line);
+        }
+    }
+    if !found && !deps.is_empty() {
+        if !replaced.is_empty() && !replaced.ends_with('\n') {
+            replaced.push('\n');
+        }
+        replaced.push_str("\n[build-dependencies]\n");
+        for (name, path) in deps {
+            replaced.push_str(&format!("{name} = {{ path = \"{path}\" }}\n"));
+        }
+    }
+    replaced
+}
+
+/// The cargo workspace manifest of the project.
+fn workspace(report: &ImportReport, packages: &[Package]) -> String {
+    let members = report
+        .kits
+        .keys()
+        .map(|kit| format!("kits/{kit}"))
+        .chain(packages.iter().map(|p| format!("packages/{}", p.dir)))
+        .chain(report.variants.iter().map(|v| format!("variants/{v}")))
+        .map(|member| format!("    \"{member}\",\n"))
+        .collect::<String>();
+    format!("[workspace]\nresolver = \"2\"\nmembers = [\n{members}]\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const VARIANT: &str = r#"[package]
+name = "aws-dev"
+version = "0.1.0"
+edition = "2021"
+build = "../build.rs"
+
+[package.metadata.build-variant]
+included-packages = ["release", "my-agent"]
+
+[lib]
+path = "../variants.rs"
+
+[build-dependencies]
+# Packages included in the variant
+release = { path = "../../packages/release" }
+my-agent = { path = "../../packages/my-agent" }
+"#;
+
+    fn package(dir: &str, name: &str) -> Package {
+        Package {
+            dir: dir.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    fn import(package_kits: &[(&str, &str)]) -> LegacyImport {
+        LegacyImport {
+            source: PathBuf::new(),
+            output: PathBuf::new(),
+            vendor: "my-vendor".to_string(),
+            registry: "registry.example.com/my-vendor".to_string(),
+            kit: "core-kit".to_string(),
+            package_kits: package_kits
+                .iter()
+                .map(|(package, kit)| (package.to_string(), kit.to_string()))
+                .collect(),
+            sdk_version: None,
+            release_version: None,
+        }
+    }
+
+    #[test]
+    fn test_variant_dependencies() {
+        let packages = [
+            package("release", "release"),
+            package("my-agent", "my-agent"),
+        ];
+        let deps = import(&[("my-agent", "agent-kit")])
+            .variant_dependencies(VARIANT, &packages)
+            .unwrap();
+        assert_eq!(
+            deps,
+            [
+                ("agent-kit".to_string(), "../../kits/agent-kit".to_string()),
+                ("core-kit".to_string(), "../../kits/core-kit".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replace_build_dependencies() {
+        let deps = [("core-kit".to_string(), "../../kits/core-kit".to_string())];
+        let replaced = replace_build_dependencies(VARIANT, &deps);
+        assert!(replaced.starts_with("[package]\nname = \"aws-dev\""));
+        assert!(replaced.ends_with(
+            "[build-dependencies]\ncore-kit = { path = \"../../kits/core-kit\" }\n\
+            # Packages included in the variant\n"
+        ));
+        toml::from_str::<Table>(&replaced).unwrap();
+    }
+
+    #[test]
+    fn test_package_dir() {
+        assert_eq!(
+            package_dir(Path::new("../../packages/kernel-6.1")),
+            Some("kernel-6.1".to_string())
+        );
+        assert_eq!(package_dir(Path::new("../../sources/api")), None);
+    }
+
+    #[test]
+    fn test_makefile_sdk_version() {
+        let makefile = r#"
+[env]
+BUILDSYS_SDK_NAME = "bottlerocket"
+BUILDSYS_SDK_VERSION = "v0.34.1"
+"#;
+        assert_eq!(makefile_sdk_version(makefile), Some("0.34.1".to_string()));
+        assert_eq!(makefile_sdk_version("[env]\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_import() {
+        let source = tempfile::tempdir().unwrap();
+        let files = [
+            ("Release.toml", "version = \"1.19.2\"\n"),
+            (
+                "Makefile.toml",
+                "[env]\nBUILDSYS_SDK_VERSION = \"v0.34.1\"\n",
+            ),
+            (
+                "packages/Cargo.toml",
+                "[workspace]\nmembers = [\"release\"]\n",
+            ),
+            (
+                "packages/release/Cargo.toml",
+                "[package]\nname = \"release\"\nversion = \"0.1.0\"\n",
+            ),
+            ("packages/release/release.spec", "Name: release\n"),
+            (
+                "packages/my-agent/Cargo.toml",
+                "[package]\nname = \"my-agent\"\nversion = \"0.1.0\"\n",
+            ),
+            ("packages/my-agent/target/junk", ""),
+            ("variants/aws-dev/Cargo.toml", VARIANT),
+        ];
+        for (path, contents) in files {
+            let path = source.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        let output = tempfile::tempdir().unwrap();
+        let mut import = import(&[("my-agent", "agent-kit")]);
+        import.source = source.path().to_path_buf();
+        import.output = output.path().join("project");
+
+        let report = import.run().await.unwrap();
+        assert_eq!(report.kits["core-kit"], ["release"]);
+        assert_eq!(report.kits["agent-kit"], ["my-agent"]);
+        assert_eq!(report.variants, ["aws-dev"]);
+
+        let project = import.output;
+        let twoliter: Table =
+            toml::from_str(&std::fs::read_to_string(project.join("Twoliter.toml")).unwrap())
+                .unwrap();
+        assert_eq!(twoliter["release-version"].as_str(), Some("1.19.2"));
+        assert_eq!(twoliter["sdk"]["version"].as_str(), Some("0.34.1"));
+        assert!(!project.join("packages/Cargo.toml").exists());
+        assert!(!project.join("packages/my-agent/target").exists());
+        assert!(project.join("packages/release/release.spec").is_file());
+        let kit = std::fs::read_to_string(project.join("kits/agent-kit/Cargo.toml")).unwrap();
+        assert!(kit.contains("my-agent = { path = \"../../packages/my-agent\" }"));
+        let workspace: Table =
+            toml::from_str(&std::fs::read_to_string(project.join("Cargo.toml")).unwrap()).unwrap();
+        assert_eq!(
+            workspace["workspace"]["members"].as_array().unwrap().len(),
+            5
+        );
+    }
+}
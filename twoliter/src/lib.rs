@@ -0,0 +1,81 @@
+//! Twoliter builds custom variants of Bottlerocket, and the kits of packages they are made from.
+//!
+//! Besides the `twoliter` command line program, the library offers the entry points in [`api`] for
+//! resolving a project's kits, fetching them and extracting them, so that other programs can drive
+//! them from their own runtime.
+use crate::cmd::{init_logger, Args};
+use anyhow::Result;
+use clap::Parser;
+use krane_static::settings;
+
+pub mod api;
+mod cargo_make;
+pub(crate) mod cleanup;
+mod cmd;
+mod common;
+mod compatibility;
+mod container;
+mod diagnostic;
+mod docker;
+mod emulation;
+mod git;
+mod host;
+mod import;
+mod messages;
+mod multi_arch;
+mod output;
+mod preflight;
+mod progress;
+mod project;
+mod proxy;
+mod remote;
+mod schema_version;
+mod telemetry;
+mod template;
+/// Test code that should only be compiled when running tests.
+#[cfg(test)]
+mod test;
+mod tools;
+mod warnings;
+
+pub use crate::api::{extract, fetch, resolve, CancellationToken, ResolvedProject};
+pub use crate::project::cache::CacheReport;
+pub use crate::project::{Attributes, ExtractOptions};
+
+/// Runs the `twoliter` command line program with the arguments this process was started with.
+#[doc(hidden)]
+pub async fn run_cli() -> Result<()> {
+    let args = Args::parse();
+    init_logger(args.log_level);
+    // The project is loaded deep within each command, so the selected profile is passed to it,
+    // and to a remote host, in the run's settings.
+    if let Some(profile) = &args.profile {
+        settings::set(project::PROFILE_ENV, profile);
+    }
+    // The remote host checks its own environment, so there is nothing to set up here.
+    if let Some(destination) = &args.remote {
+        return remote::run(destination).await;
+    }
+    let telemetry = telemetry::Telemetry::start();
+    let result = run(args).await;
+    if let Some(telemetry) = telemetry {
+        telemetry.finish(&result).await;
+    }
+    result
+}
+
+async fn run(args: Args) -> Result<()> {
+    // The doctor reports on the environment, so it must run even where the checks would fail. The
+    // proxy only talks to registries, and imports and new projects only copy files, so they run
+    // where builds cannot.
+    if !matches!(
+        args.subcommand,
+        cmd::Subcommand::Doctor(_)
+            | cmd::Subcommand::Import(_)
+            | cmd::Subcommand::New(_)
+            | cmd::Subcommand::Proxy(_)
+    ) {
+        preflight::preflight().await?;
+    }
+    cmd::run(args).await
+}
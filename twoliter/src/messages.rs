@@ -0,0 +1,167 @@
+//! A catalog of user-facing messages, so that diagnostics can be translated without changing the
+//! code that emits them.
+//!
+//! The English catalog is built in. The locale is taken from `TWOLITER_LOCALE`, or else from the
+//! usual `LC_ALL`, `LC_MESSAGES` and `LANG` variables, and its catalog is loaded from
+//! `<locale>.toml` in the directory named by `TWOLITER_MESSAGES_DIR`. Messages missing from that
+//! catalog, or a catalog that cannot be loaded, fall back to English.
+use crate::warnings;
+use anyhow::{Context, Result};
+use krane_static::settings;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+use toml::{Table, Value};
+
+const DEFAULT_LOCALE: &str = "en";
+const DEFAULT_CATALOG: &str = include_str!("messages/en.toml");
+const LOCALE_VARS: [&str; 4] = ["TWOLITER_LOCALE", "LC_ALL", "LC_MESSAGES", "LANG"];
+const MESSAGES_DIR_VAR: &str = "TWOLITER_MESSAGES_DIR";
+
+lazy_static! {
+    static ref CATALOG: Catalog = Catalog::from_env();
+}
+
+/// Looks up the message `id` in the catalog for the selected locale and fills in its arguments.
+/// Usually called through the `msg!` macro.
+pub(crate) fn message(id: &str, args: &[(&str, &dyn Display)]) -> String {
+    CATALOG.render(id, args)
+}
+
+/// Renders a message from the catalog, e.g. `msg!("error.multiple-sdks", sdks = list)`.
+macro_rules! msg {
+    ($id:literal $(, $name:ident = $value:expr)* $(,)?) => {
+        $crate::messages::message(
+            $id,
+            &[$((stringify!($name), &$value as &dyn std::fmt::Display)),*],
+        )
+    };
+}
+pub(crate) use msg;
+
+/// Messages keyed by their dotted path in the catalog file, e.g. `error.no-sdk`.
+#[derive(Debug, Clone, Default)]
+struct Catalog {
+    messages: HashMap<String, String>,
+    fallback: HashMap<String, String>,
+}
+
+impl Catalog {
+    fn from_env() -> Self {
+        let locale = LOCALE_VARS
+            .iter()
+            .filter_map(|var| settings::var(var).ok())
+            .find_map(|value| parse_locale(&value));
+        let messages = match (locale, settings::var_os(MESSAGES_DIR_VAR)) {
+            (Some(locale), Some(dir)) if locale != DEFAULT_LOCALE => {
+                load_catalog(&Path::new(&dir).join(format!("{locale}.toml"))).unwrap_or_else(|e| {
+                    warnings::warn(format!("Using English messages: {e:#}"));
+                    HashMap::new()
+                })
+            }
+            _ => HashMap::new(),
+        };
+        Self::new(messages)
+    }
+
+    fn new(messages: HashMap<String, String>) -> Self {
+        Self {
+            messages,
+            fallback: parse_catalog(DEFAULT_CATALOG).expect("the built-in catalog is valid"),
+        }
+    }
+
+    fn render(&self, id: &str, args: &[(&str, &dyn Display)]) -> String {
+        let Some(template) = self.messages.get(id).or_else(|| self.fallback.get(id)) else {
+            // A missing message is a bug, but the arguments are still worth showing.
+            return std::iter::once(id.to_string())
+                .chain(args.iter().map(|(name, value)| format!("{name}={value}")))
+                .collect::<Vec<_>>()
+                .join(" ");
+        };
+        args.iter()
+            .fold(template.trim().to_string(), |text, (name, value)| {
+                text.replace(&format!("{{{name}}}"), &value.to_string())
+            })
+    }
+}
+
+/// Reduces a POSIX locale such as `de_DE.UTF-8` to its language, e.g. `de`.
+fn parse_locale(value: &str) -> Option<String> {
+    let language = value.split(['_', '.', '@']).next()?.to_lowercase();
+    match language.as_str() {
+        "" => None,
+        "c" | "posix" => Some(DEFAULT_LOCALE.to_string()),
+        _ => Some(language),
+    }
+}
+
+fn load_catalog(path: &Path) -> Result<HashMap<String, String>> {
+    let contents = std::fs::read_to_string(path).context(format!(
+        "failed to read message catalog '{}'",
+        path.display()
+    ))?;
+    parse_catalog(&contents).context(format!(
+        "failed to parse message catalog '{}'",
+        path.display()
+    ))
+}
+
+fn parse_catalog(contents: &str) -> Result<HashMap<String, String>> {
+    let table: Table = toml::from_str(contents)?;
+    let mut messages = HashMap::new();
+    flatten("", table, &mut messages);
+    Ok(messages)
+}
+
+fn flatten(prefix: &str, table: Table, messages: &mut HashMap<String, String>) {
+    for (key, value) in table {
+        let id = if prefix.is_empty() {
+            key
+        } else {
+            format!("{prefix}.{key}")
+        };
+        match value {
+            Value::String(message) => {
+                messages.insert(id, message);
+            }
+            Value::Table(table) => flatten(&id, table, messages),
+            _ => warnings::warn(format!("Ignoring message '{id}', which is not a string")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_locale() {
+        assert_eq!(parse_locale("de_DE.UTF-8").as_deref(), Some("de"));
+        assert_eq!(parse_locale("fr").as_deref(), Some("fr"));
+        assert_eq!(parse_locale("C.UTF-8").as_deref(), Some("en"));
+        assert_eq!(parse_locale(""), None);
+    }
+
+    #[test]
+    fn test_render_falls_back_to_english() {
+        let translated =
+            parse_catalog("[error]\nno-sdk = \"kein SDK gefunden\"\nmultiple-sdks = \"{sdks}!\"")
+                .unwrap();
+        let catalog = Catalog::new(translated);
+        assert_eq!(catalog.render("error.no-sdk", &[]), "kein SDK gefunden");
+        assert_eq!(
+            catalog.render("error.multiple-sdks", &[("sdks", &"a, b")]),
+            "a, b!"
+        );
+        assert_eq!(
+            catalog.render("error.missing-lock", &[]),
+            "Twoliter.lock does not exist, please run `twoliter update` first"
+        );
+        assert_eq!(
+            catalog.render("error.bogus", &[("x", &1)]),
+            "error.bogus x=1"
+        );
+    }
+}
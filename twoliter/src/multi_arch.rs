@@ -0,0 +1,152 @@
+//! Builds a kit for every architecture at once with `twoliter build kit --multi-arch`, each on the
+//! native builder that the `native-builder` section of `Twoliter.toml` names for it, so that no
+//! architecture is built under emulation.
+//!
+//! Each builder builds as with `twoliter --remote`, and copies its kit archive back into
+//! `build/kits`. Builders run side by side, while architectures without a builder are built on
+//! this host one after another. Once every build is done, the collected archives are checked to be
+//! builds of the same kit from the same commit, which `twoliter publish kit` then publishes as a
+//! single manifest list. With `--publish <vendor>` it is published straight away.
+use crate::common::exec;
+use crate::project::{check_kit_archives, Project, ProjectLock, KIT_ARCHES};
+use crate::remote::{self, forwarded_args};
+use anyhow::{Context, Result};
+use futures::future::try_join_all;
+use std::collections::BTreeMap;
+use tokio::process::Command;
+use tracing::info;
+
+/// The flags of `twoliter build kit` which choose what `--multi-arch` builds, and are dropped from
+/// the build of each architecture. Each is followed by a value, apart from `--multi-arch`.
+const MULTI_ARCH_FLAGS: &[&str] = &["--multi-arch", "--arch", "--publish"];
+
+/// Builds `kit` for every architecture on its native builder, with the arguments `args` this
+/// invocation of twoliter was given, and checks the kit archives that are collected. The kit is
+/// then published to `publish`, if given.
+pub(crate) async fn build_kit<L: ProjectLock>(
+    project: &Project<L>,
+    kit: &str,
+    args: &[String],
+    publish: Option<&str>,
+) -> Result<()> {
+    let project_dir = project.project_dir();
+    let twoliter = std::env::current_exe().context("failed to find the twoliter executable")?;
+    let mut hosts: BTreeMap<Option<&str>, Vec<&str>> = BTreeMap::new();
+    for arch in KIT_ARCHES {
+        hosts
+            .entry(project.native_builder(arch))
+            .or_default()
+            .push(*arch);
+    }
+
+    let builds = hosts.into_iter().map(|(destination, arches)| {
+        let twoliter = &twoliter;
+        async move {
+            for arch in arches {
+                let arch_args = arch_args(args, arch);
+                match destination {
+                    Some(destination) => {
+                        info!("Building kit '{kit}' for {arch} on '{destination}'");
+                        remote::run_args(&project.filepath(), destination, &arch_args)
+                            .await
+                            .context(format!(
+                                "failed to build kit '{kit}' for {arch} on '{destination}'"
+                            ))?;
+                    }
+                    None => {
+                        info!("Building kit '{kit}' for {arch} on this host");
+                        exec(Command::new(twoliter).args(&arch_args), false)
+                            .await
+                            .context(format!("failed to build kit '{kit}' for {arch}"))?;
+                    }
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+    });
+    try_join_all(builds).await?;
+
+    let kit_dir = project_dir.join("build/kits").join(kit);
+    let archives = check_kit_archives(&kit_dir, KIT_ARCHES).await?;
+    for archive in &archives {
+        info!("Collected '{}'", archive.display());
+    }
+
+    match publish {
+        Some(vendor) => {
+            info!("Publishing kit '{kit}' to '{vendor}'");
+            exec(
+                Command::new(&twoliter)
+                    .args(["publish", "kit", "--project-path"])
+                    .arg(project.filepath())
+                    .args([kit, vendor]),
+                false,
+            )
+            .await
+            .context(format!("failed to publish kit '{kit}' to '{vendor}'"))?;
+        }
+        None => info!(
+            "Built kit '{kit}' for {}; run `twoliter publish kit {kit} <vendor>` to publish it",
+            KIT_ARCHES.join(", ")
+        ),
+    }
+    Ok(())
+}
+
+/// The arguments to build one architecture with, which are those twoliter was run with, less the
+/// flags which choose what `--multi-arch` builds and `--remote`.
+fn arch_args(args: &[String], arch: &str) -> Vec<String> {
+    let mut per_arch = Vec::new();
+    let mut args = forwarded_args(args.iter().cloned()).into_iter();
+    while let Some(arg) = args.next() {
+        let (flag, has_value) = match arg.split_once('=') {
+            Some((flag, _)) => (flag.to_string(), true),
+            None => (arg.clone(), false),
+        };
+        if !MULTI_ARCH_FLAGS.contains(&flag.as_str()) {
+            per_arch.push(arg);
+        } else if !has_value && flag != "--multi-arch" {
+            args.next();
+        }
+    }
+    per_arch.extend(["--arch".to_string(), arch.to_string()]);
+    per_arch
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_arch_args() {
+        let given = args(&[
+            "build",
+            "kit",
+            "my-kit",
+            "--multi-arch",
+            "--arch",
+            "x86_64",
+            "--publish=my-vendor",
+            "--project-path",
+            "/home/me/kits/Twoliter.toml",
+            "--keep-going",
+        ]);
+        assert_eq!(
+            arch_args(&given, "aarch64"),
+            args(&[
+                "build",
+                "kit",
+                "my-kit",
+                "--project-path",
+                "/home/me/kits/Twoliter.toml",
+                "--keep-going",
+                "--arch",
+                "aarch64",
+            ])
+        );
+    }
+}
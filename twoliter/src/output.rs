@@ -0,0 +1,661 @@
+//! Machine-readable output for commands that support `--format json`.
+//!
+//! Each JSON document is wrapped in an envelope naming its schema and schema version:
+//!
+//! ```json
+//! { "schema": "lint", "schema-version": 1, "data": { ... } }
+//! ```
+//!
+//! Within a schema version, fields may be added but are never removed, renamed or given a
+//! different type, so consumers should ignore fields they do not recognize. Any other change
+//! increments the schema version. `twoliter schema outputs` prints the JSON Schema of every output.
+use anyhow::{ensure, Context, Result};
+use clap::ValueEnum;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt::Display;
+
+/// How a command should print its results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// Human-readable text, which may change between releases.
+    #[default]
+    Text,
+    /// JSON following a versioned schema.
+    Json,
+}
+
+/// Describes the JSON output of a command.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct OutputSchema {
+    pub(crate) name: &'static str,
+    pub(crate) version: u32,
+    /// Returns the JSON Schema of the envelope's `data`.
+    data: fn() -> Value,
+}
+
+impl OutputSchema {
+    /// The JSON Schema of the whole document, including the envelope.
+    pub(crate) fn json_schema(&self) -> Value {
+        json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "title": self.name,
+            "type": "object",
+            "required": ["schema", "schema-version", "data"],
+            "properties": {
+                "schema": { "const": self.name },
+                "schema-version": { "const": self.version },
+                "data": (self.data)(),
+            },
+        })
+    }
+}
+
+/// A command result which can be printed as text or as versioned JSON.
+pub(crate) trait Output: Serialize + Display {
+    const SCHEMA: OutputSchema;
+}
+
+/// Prints `output` to stdout in the requested format.
+pub(crate) fn print<T: Output>(format: OutputFormat, output: &T) -> Result<()> {
+    match format {
+        OutputFormat::Text => println!("{output}"),
+        OutputFormat::Json => println!("{}", to_json(output)?),
+    }
+    Ok(())
+}
+
+/// Prints `output` to stdout as JSON on a single line, for outputs which are streamed as they
+/// happen, such as progress events, and read a line at a time.
+pub(crate) fn print_line<T: Output>(output: &T) -> Result<()> {
+    let json = serde_json::to_string(&Envelope::of(output))
+        .context(format!("failed to serialize '{}' output", T::SCHEMA.name))?;
+    println!("{json}");
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Envelope<'a, T> {
+    schema: &'static str,
+    schema_version: u32,
+    data: &'a T,
+}
+
+impl<'a, T: Output> Envelope<'a, T> {
+    fn of(output: &'a T) -> Self {
+        Self {
+            schema: T::SCHEMA.name,
+            schema_version: T::SCHEMA.version,
+            data: output,
+        }
+    }
+}
+
+fn to_json<T: Output>(output: &T) -> Result<String> {
+    serde_json::to_string_pretty(&Envelope::of(output))
+        .context(format!("failed to serialize '{}' output", T::SCHEMA.name))
+}
+
+/// Reads an output that was printed as JSON, such as a plan that is read back to run its steps.
+/// Fails if the output follows a different schema or schema version.
+pub(crate) fn from_json<T: Output + DeserializeOwned>(json: &str) -> Result<T> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    struct Envelope<T> {
+        schema: String,
+        schema_version: u32,
+        data: T,
+    }
+
+    let envelope: Envelope<serde_json::Value> = serde_json::from_str(json)
+        .context(format!("failed to parse '{}' output", T::SCHEMA.name))?;
+    ensure!(
+        envelope.schema == T::SCHEMA.name && envelope.schema_version == T::SCHEMA.version,
+        "expected '{}' output at schema version {}, found '{}' at schema version {}",
+        T::SCHEMA.name,
+        T::SCHEMA.version,
+        envelope.schema,
+        envelope.schema_version
+    );
+    serde_json::from_value(envelope.data)
+        .context(format!("failed to parse '{}' output", T::SCHEMA.name))
+}
+
+/// The schemas of every command output, printed by `twoliter schema outputs`.
+pub(crate) const SCHEMAS: [OutputSchema; 15] = [
+    LINT_SCHEMA,
+    CACHE_STATS_SCHEMA,
+    CACHE_GC_SCHEMA,
+    LOCK_DIFF_SCHEMA,
+    LOCK_VERIFY_SCHEMA,
+    RESOLVE_METADATA_SCHEMA,
+    DRIFT_SCHEMA,
+    KIT_CONSUMERS_SCHEMA,
+    KIT_LAYERS_SCHEMA,
+    KIT_ASSEMBLE_SCHEMA,
+    PLAN_SCHEMA,
+    PROVENANCE_SCHEMA,
+    VENDOR_SCHEMA,
+    PROGRESS_SCHEMA,
+    ENV_SCHEMA,
+];
+
+pub(crate) const LINT_SCHEMA: OutputSchema = OutputSchema {
+    name: "lint",
+    version: 1,
+    data: || {
+        json!({
+            "type": "object",
+            "required": ["findings"],
+            "properties": {
+                "findings": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["level", "lint", "message"],
+                        "properties": {
+                            "level": { "enum": ["allow", "warn", "deny"] },
+                            "lint": { "type": "string" },
+                            "message": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        })
+    },
+};
+
+pub(crate) const CACHE_STATS_SCHEMA: OutputSchema = OutputSchema {
+    name: "cache-stats",
+    version: 1,
+    data: || {
+        json!({
+            "type": "object",
+            "required": ["archives", "extracted"],
+            "properties": {
+                "archives": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["digest", "size"],
+                        "properties": {
+                            "digest": { "type": "string" },
+                            "size": { "type": "integer", "minimum": 0 },
+                        },
+                    },
+                },
+                "extracted": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["path", "digest"],
+                        "properties": {
+                            "path": { "type": "string" },
+                            "digest": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        })
+    },
+};
+
+pub(crate) const CACHE_GC_SCHEMA: OutputSchema = OutputSchema {
+    name: "cache-gc",
+    version: 1,
+    data: || {
+        json!({
+            "type": "object",
+            "required": ["root", "removed", "removed-size", "freed", "kept", "kept-size"],
+            "properties": {
+                "root": { "type": "string" },
+                "removed": { "type": "integer", "minimum": 0 },
+                "removed-size": { "type": "integer", "minimum": 0 },
+                "freed": { "type": "integer", "minimum": 0 },
+                "kept": { "type": "integer", "minimum": 0 },
+                "kept-size": { "type": "integer", "minimum": 0 },
+            },
+        })
+    },
+};
+
+pub(crate) const LOCK_DIFF_SCHEMA: OutputSchema = OutputSchema {
+    name: "lock-diff",
+    version: 1,
+    data: || {
+        let image = json!({
+            "type": "object",
+            "required": ["name", "version", "vendor", "source", "digest"],
+            "properties": {
+                "name": { "type": "string" },
+                "version": { "type": "string" },
+                "vendor": { "type": "string" },
+                "source": { "type": "string" },
+                "digest": { "type": "string" },
+                "resolved-from": { "type": "string" },
+            },
+        });
+        json!({
+            "type": "object",
+            "required": ["changes"],
+            "properties": {
+                "changes": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["change"],
+                        "properties": {
+                            "change": { "enum": ["added", "removed", "updated"] },
+                            "old": image,
+                            "new": image,
+                        },
+                    },
+                },
+            },
+        })
+    },
+};
+
+pub(crate) const LOCK_VERIFY_SCHEMA: OutputSchema = OutputSchema {
+    name: "lock-verify",
+    version: 1,
+    data: || {
+        json!({
+            "type": "object",
+            "required": ["checks"],
+            "properties": {
+                "checks": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["image", "uri", "status", "locked"],
+                        "properties": {
+                            "image": { "type": "string" },
+                            "uri": { "type": "string" },
+                            "status": { "enum": ["match", "mismatch", "unreachable"] },
+                            "locked": { "type": "string" },
+                            "served": { "type": "string" },
+                            "error": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        })
+    },
+};
+
+pub(crate) const RESOLVE_METADATA_SCHEMA: OutputSchema = OutputSchema {
+    name: "resolve-metadata",
+    version: 1,
+    data: || {
+        let image = json!({
+            "type": "object",
+            "required": ["name", "version", "vendor", "uri", "architectures"],
+            "properties": {
+                "name": { "type": "string" },
+                "version": { "type": "string" },
+                "vendor": { "type": "string" },
+                "uri": { "type": "string" },
+                "architectures": { "type": "array", "items": { "type": "string" } },
+                "sdk": { "type": "string" },
+                "kits": { "type": "array", "items": { "type": "string" } },
+            },
+        });
+        json!({
+            "type": "object",
+            "required": ["sdk", "kits"],
+            "properties": {
+                "sdk": image,
+                "kits": { "type": "array", "items": image },
+            },
+        })
+    },
+};
+
+pub(crate) const DRIFT_SCHEMA: OutputSchema = OutputSchema {
+    name: "drift",
+    version: 1,
+    data: || {
+        json!({
+            "type": "object",
+            "required": ["variant", "arches", "divergences"],
+            "properties": {
+                "variant": { "type": "string" },
+                "arches": { "type": "array", "items": { "type": "string" } },
+                "divergences": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["package", "versions"],
+                        "properties": {
+                            "package": { "type": "string" },
+                            "versions": {
+                                "type": "object",
+                                "additionalProperties": { "type": ["string", "null"] },
+                            },
+                        },
+                    },
+                },
+            },
+        })
+    },
+};
+
+pub(crate) const KIT_CONSUMERS_SCHEMA: OutputSchema = OutputSchema {
+    name: "kit-consumers",
+    version: 1,
+    data: || {
+        json!({
+            "type": "object",
+            "required": ["kit", "version", "consumers"],
+            "properties": {
+                "kit": { "type": "string" },
+                "version": { "type": ["string", "null"] },
+                "consumers": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["consumer", "depends-on"],
+                        "properties": {
+                            "consumer": { "type": "string" },
+                            "depends-on": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        })
+    },
+};
+
+pub(crate) const KIT_ASSEMBLE_SCHEMA: OutputSchema = OutputSchema {
+    name: "kit-assemble",
+    version: 1,
+    data: || {
+        json!({
+            "type": "object",
+            "required": ["kit", "version", "target", "digest", "images"],
+            "properties": {
+                "kit": { "type": "string" },
+                "version": { "type": "string" },
+                "target": { "type": "string" },
+                "digest": { "type": ["string", "null"] },
+                "images": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["uri", "architecture", "digest"],
+                        "properties": {
+                            "uri": { "type": "string" },
+                            "architecture": { "type": "string" },
+                            "digest": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        })
+    },
+};
+
+pub(crate) const KIT_LAYERS_SCHEMA: OutputSchema = OutputSchema {
+    name: "kit-layers",
+    version: 1,
+    data: || {
+        json!({
+            "type": "object",
+            "required": ["arch", "kits", "duplicates", "advice"],
+            "properties": {
+                "arch": { "type": "string" },
+                "kits": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["kit", "layers"],
+                        "properties": {
+                            "kit": { "type": "string" },
+                            "layers": {
+                                "type": "array",
+                                "items": {
+                                    "type": "object",
+                                    "required": ["name", "size", "rpms"],
+                                    "properties": {
+                                        "name": { "type": "string" },
+                                        "size": { "type": "integer" },
+                                        "rpms": {
+                                            "type": "array",
+                                            "items": {
+                                                "type": "object",
+                                                "required": ["file", "size", "sha256"],
+                                                "properties": {
+                                                    "file": { "type": "string" },
+                                                    "size": { "type": "integer" },
+                                                    "sha256": { "type": "string" },
+                                                },
+                                            },
+                                        },
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+                "duplicates": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["file", "size", "kits"],
+                        "properties": {
+                            "file": { "type": "string" },
+                            "size": { "type": "integer" },
+                            "kits": { "type": "array", "items": { "type": "string" } },
+                        },
+                    },
+                },
+                "advice": { "type": "array", "items": { "type": "string" } },
+            },
+        })
+    },
+};
+
+pub(crate) const PLAN_SCHEMA: OutputSchema = OutputSchema {
+    name: "plan",
+    version: 1,
+    data: || {
+        let strings = json!({ "type": "array", "items": { "type": "string" } });
+        json!({
+            "type": "object",
+            "required": ["lock-digest", "steps"],
+            "properties": {
+                "lock-digest": { "type": "string" },
+                "steps": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["id", "command", "depends-on", "outputs"],
+                        "properties": {
+                            "id": { "type": "string" },
+                            "command": strings,
+                            "depends-on": strings,
+                            "outputs": strings,
+                            "estimate-seconds": { "type": "integer", "minimum": 0 },
+                        },
+                    },
+                },
+                "estimate-seconds": { "type": "integer", "minimum": 0 },
+            },
+        })
+    },
+};
+
+pub(crate) const PROVENANCE_SCHEMA: OutputSchema = OutputSchema {
+    name: "provenance",
+    version: 1,
+    data: || {
+        let files = json!({
+            "type": "array",
+            "items": {
+                "type": "object",
+                "required": ["name", "sha512"],
+                "properties": {
+                    "name": { "type": "string" },
+                    "sha512": { "type": "string" },
+                },
+            },
+        });
+        json!({
+            "type": "object",
+            "required": ["path", "arch", "providers", "unrecorded"],
+            "properties": {
+                "path": { "type": "string" },
+                "arch": { "type": "string" },
+                "providers": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["kit", "package", "rpm", "sources", "patches"],
+                        "properties": {
+                            "kit": { "type": "string" },
+                            "package": { "type": "string" },
+                            "rpm": { "type": "string" },
+                            "sources": files,
+                            "patches": files,
+                        },
+                    },
+                },
+                "unrecorded": { "type": "array", "items": { "type": "string" } },
+            },
+        })
+    },
+};
+
+pub(crate) const VENDOR_SCHEMA: OutputSchema = OutputSchema {
+    name: "vendor",
+    version: 1,
+    data: || {
+        json!({
+            "type": "object",
+            "required": ["dir", "images"],
+            "properties": {
+                "dir": { "type": "string" },
+                "images": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["image", "uri", "status"],
+                        "properties": {
+                            "image": { "type": "string" },
+                            "uri": { "type": "string" },
+                            "status": { "enum": ["up-to-date", "pulled", "replaced"] },
+                        },
+                    },
+                },
+            },
+        })
+    },
+};
+
+pub(crate) const PROGRESS_SCHEMA: OutputSchema = OutputSchema {
+    name: "progress",
+    version: 1,
+    data: || {
+        json!({
+            "type": "object",
+            "required": ["event"],
+            "properties": {
+                "event": { "enum": ["kits-resolved", "kit-transfer", "kit-ready", "sdk-ready"] },
+                "kits": { "type": "array", "items": { "type": "string" } },
+                "kit": { "type": "string" },
+                "stage": { "enum": ["pull", "extract"] },
+                "bytes": { "type": "integer", "minimum": 0 },
+                "done": { "type": "integer", "minimum": 0 },
+                "total": { "type": "integer", "minimum": 0 },
+                "sdk": { "type": "string" },
+            },
+        })
+    },
+};
+
+pub(crate) const ENV_SCHEMA: OutputSchema = OutputSchema {
+    name: "env",
+    version: 1,
+    data: || {
+        let settings = json!({
+            "type": "array",
+            "items": {
+                "type": "object",
+                "required": ["key", "value", "source"],
+                "properties": {
+                    "key": { "type": "string" },
+                    "value": { "type": "string" },
+                    "source": {
+                        "enum": [
+                            "command-line",
+                            "environment",
+                            "profile",
+                            "twoliter-toml",
+                            "user-config",
+                            "default",
+                        ],
+                    },
+                },
+            },
+        });
+        json!({
+            "type": "object",
+            "required": ["project-file", "profile", "flags", "project", "env"],
+            "properties": {
+                "project-file": { "type": "string" },
+                "profile": { "type": ["string", "null"] },
+                "flags": settings,
+                "project": settings,
+                "env": settings,
+            },
+        })
+    },
+};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+    use std::fmt::Formatter;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Example {
+        count: u32,
+    }
+
+    impl Display for Example {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{} things", self.count)
+        }
+    }
+
+    impl Output for Example {
+        const SCHEMA: OutputSchema = LINT_SCHEMA;
+    }
+
+    #[test]
+    fn test_envelope() {
+        let json: Value = serde_json::from_str(&to_json(&Example { count: 2 }).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            json!({ "schema": "lint", "schema-version": 1, "data": { "count": 2 } })
+        );
+    }
+
+    #[test]
+    fn test_from_json() {
+        let json = to_json(&Example { count: 2 }).unwrap();
+        assert_eq!(from_json::<Example>(&json).unwrap(), Example { count: 2 });
+        let other = json.replace("\"lint\"", "\"drift\"");
+        assert!(from_json::<Example>(&other).is_err());
+    }
+
+    #[test]
+    fn test_schema_names_are_unique() {
+        let names = SCHEMAS.iter().map(|s| s.name).collect::<HashSet<_>>();
+        assert_eq!(names.len(), SCHEMAS.len());
+    }
+}
@@ -2,13 +2,16 @@
 //! as any other "global" setup that must occur before the build process begins.
 use anyhow::{ensure, Result};
 use lazy_static::lazy_static;
+use oci_cli_wrapper::container_tool::ContainerTool;
 use semver::{Comparator, Op, Prerelease, VersionReq};
-use tracing::warn;
 use which::which_global;
 
 use crate::docker::Docker;
+use crate::host;
+use crate::warnings;
 
-const REQUIRED_TOOLS: &[&str] = &["docker", "gzip", "lz4"];
+/// The tools builds need besides the container tool.
+const REQUIRED_TOOLS: &[&str] = &["gzip", "lz4"];
 
 lazy_static! {
     // Twoliter relies on minimum Dockerfile syntax 1.4.3, which is shipped in Docker 23.0.0 by default
@@ -24,6 +27,19 @@ lazy_static! {
             }
         ].into()
     };
+
+    // Podman 4 is the first whose builds support the secret and cache mounts that builds use.
+    static ref MINIMUM_PODMAN_VERSION: VersionReq = VersionReq {
+        comparators: [
+            Comparator {
+                op: Op::GreaterEq,
+                major: 4,
+                minor: None,
+                patch: None,
+                pre: Prerelease::default(),
+            }
+        ].into()
+    };
 }
 
 /// Runs all common setup required for twoliter.
@@ -33,14 +49,21 @@ lazy_static! {
 pub(crate) async fn preflight() -> Result<()> {
     check_environment().await?;
     if let Err(e) = crate::cleanup::JANITOR.setup_signal_handler() {
-        warn!("Failed to register cleanup signal handler: {:?}", e);
-        warn!("Twoliter may leak resources if interrupted abruptly.");
+        warnings::warn(format!(
+            "Failed to register cleanup signal handler: {:?}",
+            e
+        ));
+        warnings::warn("Twoliter may leak resources if interrupted abruptly.");
     }
 
     Ok(())
 }
 
 pub(crate) async fn check_environment() -> Result<()> {
+    // The tools are only used by builds, which refuse to run on other hosts.
+    if !host::BUILDS_SUPPORTED {
+        return Ok(());
+    }
     check_for_required_tools()?;
     check_docker_version().await?;
 
@@ -48,7 +71,7 @@ pub(crate) async fn check_environment() -> Result<()> {
 }
 
 fn check_for_required_tools() -> Result<()> {
-    for tool in REQUIRED_TOOLS {
+    for tool in [Docker::tool()?.command()].iter().chain(REQUIRED_TOOLS) {
         ensure!(
             which_global(tool).is_ok(),
             "Failed to find required tool `{tool}` in PATH"
@@ -58,12 +81,19 @@ fn check_for_required_tools() -> Result<()> {
 }
 
 async fn check_docker_version() -> Result<()> {
+    let tool = Docker::tool()?;
+    let minimum = match tool {
+        ContainerTool::Docker => &*MINIMUM_DOCKER_VERSION,
+        ContainerTool::Podman => &*MINIMUM_PODMAN_VERSION,
+        // Finch does not report its version in a form that can be checked.
+        ContainerTool::Finch => return Ok(()),
+    };
     let docker_version = Docker::server_version().await?;
 
     ensure!(
-        MINIMUM_DOCKER_VERSION.matches(&docker_version),
-        "docker found in PATH does not meet the minimum version requirements for twoliter: {}",
-        MINIMUM_DOCKER_VERSION.to_string(),
+        minimum.matches(&docker_version),
+        "{tool} found in PATH does not meet the minimum version requirements for twoliter: {}",
+        minimum.to_string(),
     );
 
     Ok(())
@@ -82,4 +112,10 @@ mod test {
     fn test_docker_version_req(version: Version, is_ok: bool) {
         assert_eq!(MINIMUM_DOCKER_VERSION.matches(&version), is_ok)
     }
+
+    #[test_case(Version::parse("4.9.3").unwrap(), true; "4.9.3 passes")]
+    #[test_case(Version::parse("3.4.4").unwrap(), false; "3.4.4 fails")]
+    fn test_podman_version_req(version: Version, is_ok: bool) {
+        assert_eq!(MINIMUM_PODMAN_VERSION.matches(&version), is_ok)
+    }
 }
@@ -0,0 +1,205 @@
+//! Shows the progress of the calls in [`crate::api`] on the command line, as a progress bar for
+//! each kit being pulled or extracted under one for the whole fetch, as log lines, or as one JSON
+//! document per event for tools which drive twoliter.
+use crate::api::{log_progress, Progress, TransferStage};
+use crate::output;
+use crate::warnings;
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::sync::Mutex;
+
+/// The bar of a kit being pulled or extracted.
+const KIT_TEMPLATE: &str =
+    "{msg:32!} [{bar:30}] {bytes:>10}/{total_bytes:10} {binary_bytes_per_sec:>12} {eta:>4}";
+
+/// The bar of every kit together.
+const OVERALL_TEMPLATE: &str =
+    "{msg:32!} [{bar:30}] {bytes:>10}/{total_bytes:10} {elapsed:>12} {eta:>4}";
+
+/// How progress is shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ProgressMode {
+    /// Progress bars when stderr is a terminal, or else log lines.
+    #[default]
+    Auto,
+    /// Log lines, as when stderr is not a terminal.
+    Plain,
+    /// One JSON document per line on stdout, following the `progress` output schema.
+    Json,
+}
+
+/// Shows progress events in a [`ProgressMode`].
+pub(crate) enum ProgressReporter {
+    Bars(Mutex<Bars>),
+    Plain,
+    Json,
+}
+
+impl ProgressReporter {
+    pub(crate) fn new(mode: ProgressMode) -> Self {
+        match mode {
+            ProgressMode::Auto if std::io::stderr().is_terminal() => {
+                Self::Bars(Mutex::new(Bars::new()))
+            }
+            ProgressMode::Auto | ProgressMode::Plain => Self::Plain,
+            ProgressMode::Json => Self::Json,
+        }
+    }
+
+    pub(crate) fn report(&self, progress: &Progress) {
+        match self {
+            Self::Bars(bars) => match bars.lock() {
+                Ok(mut bars) => bars.report(progress),
+                Err(_) => log_progress(progress),
+            },
+            Self::Plain => log_progress(progress),
+            Self::Json => {
+                if let Err(e) = output::print_line(progress) {
+                    warnings::warn(format!("Failed to report progress: {e:#}"));
+                }
+            }
+        }
+    }
+
+    /// Clears the progress bars, if any, once the call has finished.
+    pub(crate) fn finish(&self) {
+        if let Self::Bars(bars) = self {
+            if let Ok(bars) = bars.lock() {
+                bars.overall.finish_and_clear();
+            }
+        }
+    }
+}
+
+/// The progress bars of a fetch.
+pub(crate) struct Bars {
+    multi: MultiProgress,
+    overall: ProgressBar,
+    kits: HashMap<String, ProgressBar>,
+    /// The bytes transferred and to transfer in each stage of each kit, for the overall bar.
+    transfers: HashMap<(String, TransferStage), (u64, u64)>,
+    /// How many kits are ready, of how many.
+    ready: (usize, usize),
+}
+
+impl Bars {
+    fn new() -> Self {
+        let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+        let overall = multi.add(ProgressBar::new(0).with_style(style(OVERALL_TEMPLATE)));
+        overall.set_message("Fetching kits");
+        Self {
+            multi,
+            overall,
+            kits: HashMap::new(),
+            transfers: HashMap::new(),
+            ready: (0, 0),
+        }
+    }
+
+    fn report(&mut self, progress: &Progress) {
+        match progress {
+            Progress::KitsResolved { kits } => {
+                self.ready = (0, kits.len());
+                self.update_overall();
+            }
+            Progress::KitTransfer {
+                kit,
+                stage,
+                bytes,
+                total,
+            } => {
+                let bar = self.kits.entry(kit.clone()).or_insert_with(|| {
+                    self.multi
+                        .add(ProgressBar::new(0).with_style(style(KIT_TEMPLATE)))
+                });
+                // A new stage starts the kit's bar over.
+                if self
+                    .transfers
+                    .insert((kit.clone(), *stage), (*bytes, *total))
+                    .is_none()
+                {
+                    bar.reset();
+                }
+                bar.set_message(format!("{kit} ({stage})"));
+                bar.set_length(*total);
+                bar.set_position(*bytes);
+                self.update_overall();
+            }
+            Progress::KitReady { kit, done, total } => {
+                if let Some(bar) = self.kits.remove(kit) {
+                    bar.finish_and_clear();
+                    self.multi.remove(&bar);
+                }
+                self.ready = (*done, *total);
+                self.update_overall();
+            }
+            Progress::SdkReady { .. } => {
+                let _ = self.multi.println(progress.to_string());
+            }
+        }
+    }
+
+    fn update_overall(&self) {
+        let (bytes, total) = overall(&self.transfers);
+        let (done, kits) = self.ready;
+        self.overall
+            .set_message(format!("Fetching kits [{done}/{kits}]"));
+        self.overall.set_length(total);
+        self.overall.set_position(bytes);
+    }
+}
+
+/// The bytes transferred and to transfer across every stage of every kit.
+fn overall(transfers: &HashMap<(String, TransferStage), (u64, u64)>) -> (u64, u64) {
+    transfers
+        .values()
+        .fold((0, 0), |(bytes, total), (stage_bytes, stage_total)| {
+            (bytes + stage_bytes, total + stage_total)
+        })
+}
+
+fn style(template: &str) -> ProgressStyle {
+    // The templates are constant, and checked by the tests below.
+    ProgressStyle::with_template(template)
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_templates() {
+        for template in [KIT_TEMPLATE, OVERALL_TEMPLATE] {
+            assert!(ProgressStyle::with_template(template).is_ok(), "{template}");
+        }
+    }
+
+    #[test]
+    fn test_overall() {
+        let mut bars = Bars::new();
+        bars.multi.set_draw_target(ProgressDrawTarget::hidden());
+        let transfer = |kit: &str, stage, bytes, total| Progress::KitTransfer {
+            kit: kit.to_string(),
+            stage,
+            bytes,
+            total,
+        };
+        bars.report(&transfer("core-kit", TransferStage::Pull, 10, 10));
+        bars.report(&transfer("core-kit", TransferStage::Extract, 5, 20));
+        bars.report(&transfer("extra-kit", TransferStage::Pull, 1, 4));
+        assert_eq!(overall(&bars.transfers), (16, 34));
+        assert_eq!(bars.kits.len(), 2);
+
+        bars.report(&Progress::KitReady {
+            kit: "core-kit".to_string(),
+            done: 1,
+            total: 2,
+        });
+        assert_eq!(bars.kits.len(), 1);
+        assert_eq!(bars.ready, (1, 2));
+    }
+}
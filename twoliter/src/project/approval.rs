@@ -0,0 +1,536 @@
+//! Requires publishes to some or all vendors to be approved by several people, so that no single
+//! operator can publish a production kit or promote a variant on their own.
+//!
+//! A vendor is protected by giving it an `approval` policy in the `publish` section of
+//! `Twoliter.toml`, or every vendor by giving the policy directly under `publish`:
+//!
+//! ```toml
+//! [publish.vendor.prod.approval]
+//! # How many different people must approve each publish.
+//! required = 2
+//! # An ssh allowed signers file listing who may approve, relative to the project.
+//! allowed-signers = "approvers/allowed_signers"
+//! # An approval service to ask, besides or instead of signed approvals.
+//! url = "https://approvals.example.com/v1/check"
+//! ```
+//!
+//! Approvers sign a statement naming the kit, its version, the vendor, the repository and the
+//! sha256 of each of the kit's per-arch archives with `twoliter publish approve`, which uses
+//! `ssh-keygen -Y sign`, and the signatures are passed to `twoliter publish kit` with
+//! `--approval`. An approval is therefore only good for the build that was approved.
+//!
+//! The shared policy also covers `twoliter make` tasks which publish or promote a variant's SSM
+//! parameters, or publish a kit, whatever the task is named: what a task does is read from the pubsys
+//! operations it runs. Promotion approvals name the operation, the variant, the architecture and the
+//! build id being promoted, and are signed with `twoliter publish approve-promotion`.
+//!
+//! The approval service is sent the same details as JSON, with the token in
+//! `TWOLITER_APPROVAL_TOKEN` if it is set, and responds with the `approvers` who approved the
+//! publish. Each approver is counted once, however many approvals they gave. Signed approvals are
+//! counted by the key that signed them, so a key listed for several principals in the allowed
+//! signers file still counts as one approver.
+use super::lock::hash_file;
+use crate::common::{exec, fs};
+use crate::diagnostic::Code;
+use crate::git::Git;
+use crate::messages::msg;
+use crate::warnings;
+use anyhow::{bail, ensure, Context, Result};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use krane_static::settings;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::{debug, info};
+
+/// The ssh signature namespace of approvals, so that signatures made for other purposes with the
+/// same keys are not accepted as approvals.
+const APPROVAL_NAMESPACE: &str = "twoliter-publish-approval";
+
+/// The environment variable holding a bearer token for the approval service.
+const APPROVAL_TOKEN_ENV: &str = "TWOLITER_APPROVAL_TOKEN";
+
+/// The pubsys operations which publish or promote a variant, and so must be approved when a
+/// `twoliter make` task runs them. Approvals name the operation rather than the task.
+pub(crate) const PROMOTION_TASKS: &[&str] = &["ssm", "promote-ssm"];
+
+/// Who must approve a publish before it proceeds.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct ApprovalPolicy {
+    /// How many different approvers must approve a publish.
+    required: usize,
+
+    /// An ssh allowed signers file listing the approvers whose signed approvals are accepted,
+    /// relative to the project.
+    #[serde(default)]
+    allowed_signers: Option<PathBuf>,
+
+    /// An approval service which is asked who approved a publish.
+    #[serde(default)]
+    url: Option<String>,
+}
+
+/// A publish to be approved.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "action")]
+pub(crate) enum ApprovalRequest {
+    /// Publishing a kit to a vendor.
+    #[serde(rename_all = "kebab-case")]
+    PublishKit {
+        kit: String,
+        version: String,
+        vendor: String,
+        repository: String,
+        /// The sha256 of each of the kit's per-arch archives, by file name.
+        archives: BTreeMap<String, String>,
+    },
+    /// Running a task which publishes or promotes a variant's SSM parameters.
+    #[serde(rename_all = "kebab-case")]
+    PromoteVariant {
+        task: String,
+        variant: String,
+        arch: String,
+        /// The build id of the variant being promoted: its version and the commit it was built
+        /// from, as in the names of its build artifacts.
+        build: String,
+        /// The version or pointer the parameters are promoted to, for `promote-ssm`.
+        target: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct ApprovalResponse {
+    #[serde(default)]
+    approvers: Vec<String>,
+}
+
+impl ApprovalRequest {
+    /// A request to publish the build of `kit` in `project_dir` to `repository` of `vendor`.
+    pub(crate) async fn publish_kit(
+        project_dir: &Path,
+        kit: &str,
+        version: &str,
+        vendor: &str,
+        repository: &str,
+    ) -> Result<Self> {
+        let kit_dir = project_dir.join("build/kits").join(kit);
+        let mut entries = tokio::fs::read_dir(&kit_dir).await.context(format!(
+            "no build of kit at '{}'; build it before approving its publish",
+            kit_dir.display()
+        ))?;
+        let mut archives = BTreeMap::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context(format!("failed to read '{}'", kit_dir.display()))?
+        {
+            let path = entry.path();
+            if path.extension().is_some_and(|extension| extension == "tar") {
+                let name = entry.file_name().to_string_lossy().to_string();
+                archives.insert(name, hash_file(&path)?);
+            }
+        }
+        ensure!(
+            !archives.is_empty(),
+            "no kit archives in '{}'; build the kit before approving its publish",
+            kit_dir.display()
+        );
+        Ok(Self::PublishKit {
+            kit: kit.to_string(),
+            version: version.to_string(),
+            vendor: vendor.to_string(),
+            repository: repository.to_string(),
+            archives,
+        })
+    }
+
+    /// A request to run `task`, which publishes or promotes the SSM parameters of `variant` for
+    /// `arch`, on the build `source`, or else the build of `version` from the commit checked out in
+    /// `project_dir`.
+    pub(crate) async fn promote_variant(
+        project_dir: &Path,
+        version: &str,
+        task: &str,
+        variant: &str,
+        arch: &str,
+        source: Option<String>,
+        target: Option<String>,
+    ) -> Self {
+        let build = match source {
+            Some(source) => source,
+            None => format!("{version}-{}", Git::build_id(project_dir).await),
+        };
+        Self::PromoteVariant {
+            task: task.to_string(),
+            variant: variant.to_string(),
+            arch: arch.to_string(),
+            build,
+            target,
+        }
+    }
+
+    /// The statement which approvers sign. It holds everything the approval service is sent, so
+    /// that a signed approval covers exactly the same publish.
+    fn statement(&self) -> Result<String> {
+        let request =
+            serde_json::to_string_pretty(self).context("failed to serialize the approval")?;
+        Ok(format!("{APPROVAL_NAMESPACE}\n{request}\n"))
+    }
+
+    /// Signs an approval of the publish with the ssh key at `key`, writing it to `output`.
+    pub(crate) async fn sign(&self, key: &Path, output: &Path) -> Result<()> {
+        let dir = tempfile::tempdir().context("failed to create a directory for the approval")?;
+        let statement = dir.path().join("approval");
+        fs::write(&statement, self.statement()?).await?;
+        exec(
+            Command::new("ssh-keygen")
+                .args(["-Y", "sign", "-n", APPROVAL_NAMESPACE, "-f"])
+                .arg(key)
+                .arg(&statement),
+            true,
+        )
+        .await
+        .context("failed to sign the approval with ssh-keygen")?;
+        fs::copy(dir.path().join("approval.sig"), output).await?;
+        Ok(())
+    }
+}
+
+impl Display for ApprovalRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApprovalRequest::PublishKit {
+                kit,
+                version,
+                vendor,
+                ..
+            } => write!(f, "publishing {kit} {version} to vendor '{vendor}'"),
+            ApprovalRequest::PromoteVariant {
+                task,
+                variant,
+                arch,
+                build,
+                ..
+            } => write!(f, "running '{task}' for {variant} {build} ({arch})"),
+        }
+    }
+}
+
+impl ApprovalPolicy {
+    /// Checks that the publish described by `request` is approved by enough approvers, counting
+    /// the signed `approvals` and the approval service's response.
+    pub(crate) async fn check(
+        &self,
+        project_dir: &Path,
+        request: &ApprovalRequest,
+        approvals: &[PathBuf],
+    ) -> Result<()> {
+        ensure!(
+            self.allowed_signers.is_some() || self.url.is_some(),
+            "the approval policy for {request} needs allowed-signers or a url"
+        );
+        let mut approvers = BTreeSet::new();
+        match &self.allowed_signers {
+            Some(allowed_signers) => {
+                let allowed_signers = project_dir.join(allowed_signers);
+                approvers.extend(verify_approvals(&allowed_signers, request, approvals).await?);
+            }
+            None => ensure!(
+                approvals.is_empty(),
+                "signed approvals were given, but the approval policy for {request} has no \
+                allowed-signers to check them against"
+            ),
+        }
+        if let Some(url) = &self.url {
+            approvers.extend(ask_service(url, request).await?);
+        }
+
+        ensure!(
+            approvers.len() >= self.required,
+            Code::PublishNotApproved.error(msg!(
+                "error.publish-not-approved",
+                request = request,
+                approved = approvers.len(),
+                required = self.required,
+            ))
+        );
+        info!(
+            "Approved {request} by {}",
+            approvers.into_iter().collect::<Vec<_>>().join(", ")
+        );
+        Ok(())
+    }
+}
+
+/// The approvers listed in `allowed_signers` whose signatures among `approvals` are valid for the
+/// publish described by `request`, one for each distinct key that signed a valid approval. Approvals
+/// which are invalid are skipped with a warning.
+async fn verify_approvals(
+    allowed_signers: &Path,
+    request: &ApprovalRequest,
+    approvals: &[PathBuf],
+) -> Result<BTreeSet<String>> {
+    let allowed = principals(&fs::read_to_string(allowed_signers).await?);
+    debug!(
+        "{} approvers are allowed by '{}'",
+        allowed.len(),
+        allowed_signers.display()
+    );
+    let dir = tempfile::tempdir().context("failed to create a directory for the approval")?;
+    let statement = dir.path().join("approval");
+    fs::write(&statement, request.statement()?).await?;
+
+    // The first principal verified for each signing key, by the key's fingerprint.
+    let mut approvers = BTreeMap::new();
+    for approval in approvals {
+        let key = match fs::read_to_string(approval)
+            .await
+            .and_then(|signature| signing_key(&signature))
+        {
+            Ok(key) => key,
+            Err(e) => {
+                warnings::warn(format!(
+                    "Ignoring approval '{}', which could not be read as an ssh signature: {e:#}",
+                    approval.display()
+                ));
+                continue;
+            }
+        };
+        if let Some(approver) = approvers.get(&key) {
+            debug!(
+                "Approval '{}' is signed by the same key as an earlier approval by '{approver}'",
+                approval.display()
+            );
+            continue;
+        }
+        let found = exec(
+            Command::new("ssh-keygen")
+                .args(["-Y", "find-principals", "-f"])
+                .arg(allowed_signers)
+                .arg("-s")
+                .arg(approval),
+            true,
+        )
+        .await;
+        let Ok(Some(found)) = found else {
+            warnings::warn(format!(
+                "Ignoring approval '{}', which was not signed by an allowed approver",
+                approval.display()
+            ));
+            continue;
+        };
+        for principal in found.lines().map(str::trim).filter(|p| !p.is_empty()) {
+            let stdin =
+                std::fs::File::open(&statement).context("failed to open the approval statement")?;
+            let verified = exec(
+                Command::new("ssh-keygen")
+                    .args(["-Y", "verify", "-n", APPROVAL_NAMESPACE, "-f"])
+                    .arg(allowed_signers)
+                    .args(["-I", principal, "-s"])
+                    .arg(approval)
+                    .stdin(stdin),
+                true,
+            )
+            .await;
+            match verified {
+                Ok(_) => {
+                    // Any other principals of the key are the same approver.
+                    approvers.insert(key, principal.to_string());
+                    break;
+                }
+                Err(e) => warnings::warn(format!(
+                    "Ignoring approval '{}', which does not approve this publish: {e}",
+                    approval.display()
+                )),
+            }
+        }
+    }
+    Ok(approvers.into_values().collect())
+}
+
+/// The fingerprint of the public key which made an armored ssh signature, in the format
+/// `ssh-keygen -l` prints it.
+fn signing_key(signature: &str) -> Result<String> {
+    let armored = signature
+        .trim()
+        .strip_prefix("-----BEGIN SSH SIGNATURE-----")
+        .and_then(|signature| signature.strip_suffix("-----END SSH SIGNATURE-----"))
+        .context("missing the ssh signature armor")?;
+    let blob = STANDARD
+        .decode(armored.split_whitespace().collect::<String>())
+        .context("failed to decode the ssh signature")?;
+    // The blob is the magic preamble, a version and then the public key as an ssh string.
+    let Some(rest) = blob.strip_prefix(b"SSHSIG") else {
+        bail!("missing the SSHSIG preamble");
+    };
+    let public_key = rest
+        .get(4..8)
+        .map(|len| u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+        .and_then(|len| rest.get(8..8 + len))
+        .context("the ssh signature is truncated")?;
+    Ok(format!(
+        "SHA256:{}",
+        STANDARD_NO_PAD.encode(Sha256::digest(public_key))
+    ))
+}
+
+/// Asks the approval service at `url` who approved the publish described by `request`.
+async fn ask_service(url: &str, request: &ApprovalRequest) -> Result<Vec<String>> {
+    let mut post = reqwest::Client::new().post(url).json(request);
+    if let Ok(token) = settings::var(APPROVAL_TOKEN_ENV) {
+        post = post.bearer_auth(token);
+    }
+    let response: ApprovalResponse = post
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context(format!("failed to ask the approval service '{url}'"))?
+        .json()
+        .await
+        .context(format!(
+            "failed to parse the response of the approval service '{url}'"
+        ))?;
+    Ok(response.approvers)
+}
+
+/// The principals listed in the contents of an ssh allowed signers file.
+fn principals(allowed_signers: &str) -> BTreeSet<String> {
+    allowed_signers
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_whitespace().next())
+        .flat_map(|principals| principals.split(','))
+        .map(ToString::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_principals() {
+        let allowed_signers = r#"
+# Release approvers
+alice@example.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAlice
+bob@example.com,robert@example.com namespaces="twoliter-publish-approval" ssh-ed25519 AAAAB
+alice@example.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAlice2
+"#;
+        assert_eq!(
+            principals(allowed_signers),
+            ["alice@example.com", "bob@example.com", "robert@example.com"]
+                .into_iter()
+                .map(ToString::to_string)
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_signing_key() {
+        let signature = r#"-----BEGIN SSH SIGNATURE-----
+U1NIU0lHAAAAAQAAADMAAAALc3NoLWVkMjU1MTkAAAAgS6OWLnRR4KEbNFzDcrIB0jJCp3
+UdHVoY+TIr3NZM05QAAAAEdGVzdAAAAAAAAAAGc2hhNTEyAAAAUwAAAAtzc2gtZWQyNTUx
+OQAAAEBA1I2JGDRfHff9EclnWHAFoqWMeLBroG4sepSEXUCGy6m2VyHxsuT4KkMqa7hy4m
+EjFIjgINneJXLzoljDhs4A
+-----END SSH SIGNATURE-----
+"#;
+        assert_eq!(
+            signing_key(signature).unwrap(),
+            "SHA256:60AI4pxxnEsI4RNko+6WPbtjp4hGzuR/U1wkrO77Nns"
+        );
+        assert!(signing_key("-----BEGIN SSH SIGNATURE-----\n-----END SSH SIGNATURE-----").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_with_several_principals() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("bob");
+        exec(
+            Command::new("ssh-keygen")
+                .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+                .arg(&key),
+            true,
+        )
+        .await
+        .unwrap();
+        let public_key = std::fs::read_to_string(key.with_extension("pub")).unwrap();
+        std::fs::write(
+            dir.path().join("allowed_signers"),
+            format!("bob@example.com,robert@example.com {public_key}"),
+        )
+        .unwrap();
+        let request = ApprovalRequest::PromoteVariant {
+            task: "ssm".to_string(),
+            variant: "aws-dev".to_string(),
+            arch: "x86_64".to_string(),
+            build: "1.0.0-abcdef".to_string(),
+            target: None,
+        };
+        let approvals = [dir.path().join("first.sig"), dir.path().join("second.sig")];
+        for approval in &approvals {
+            request.sign(&key, approval).await.unwrap();
+        }
+
+        let approvers = verify_approvals(&dir.path().join("allowed_signers"), &request, &approvals)
+            .await
+            .unwrap();
+        assert_eq!(approvers.len(), 1);
+        let policy = ApprovalPolicy {
+            required: 2,
+            allowed_signers: Some(PathBuf::from("allowed_signers")),
+            url: None,
+        };
+        assert!(policy
+            .check(dir.path(), &request, &approvals)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy: ApprovalPolicy = toml::from_str(
+            r#"
+required = 2
+allowed-signers = "approvers/allowed_signers"
+"#,
+        )
+        .unwrap();
+        assert_eq!(policy.required, 2);
+        assert!(policy.url.is_none());
+        assert!(toml::from_str::<ApprovalPolicy>("required = 2\napprovers = 3").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unapproved() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("allowed_signers"),
+            "alice@example.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAlice\n",
+        )
+        .unwrap();
+        let policy = ApprovalPolicy {
+            required: 1,
+            allowed_signers: Some(PathBuf::from("allowed_signers")),
+            url: None,
+        };
+        let request = ApprovalRequest::PublishKit {
+            kit: "core-kit".to_string(),
+            version: "1.0.0".to_string(),
+            vendor: "prod".to_string(),
+            repository: "core-kit".to_string(),
+            archives: BTreeMap::from([("core-kit-x86_64.tar".to_string(), "abc".to_string())]),
+        };
+        let err = policy.check(dir.path(), &request, &[]).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<crate::diagnostic::Diagnostic>()
+                .map(|diagnostic| diagnostic.code()),
+            Some(Code::PublishNotApproved)
+        );
+    }
+}
@@ -0,0 +1,481 @@
+//! Size budgets for variant images and kits, declared in the `budget` section of `Twoliter.toml`
+//! and checked after each build, so that growth is caught when it happens rather than when an
+//! image no longer fits its partitions.
+//!
+//! ```toml
+//! [budget.variant.aws-dev]
+//! # The OS image, and the images of its partitions.
+//! image = "1GiB"
+//! root = "600MiB"
+//! data = "20MiB"
+//!
+//! [budget.kit.my-kit]
+//! # Each package of the kit, which is published as a layer of its own, and the kit as a whole.
+//! layer = "200MiB"
+//! total = "1.5GiB"
+//! ```
+//!
+//! Images are measured as the files the build writes, so images which are written compressed are
+//! measured compressed. A build which exceeds a budget fails with a breakdown of the largest
+//! packages, to show where to trim.
+use super::lock::parse_rpm_name;
+use super::{Project, ProjectLock};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::info;
+
+/// How many of the largest packages a report lists.
+const LARGEST: usize = 10;
+
+/// The size budgets of the project's variants and kits.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct BudgetConfig {
+    #[serde(default)]
+    variant: BTreeMap<String, VariantBudget>,
+    #[serde(default)]
+    kit: BTreeMap<String, KitBudget>,
+}
+
+/// The largest each image a variant's build writes may be.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct VariantBudget {
+    image: Option<ByteSize>,
+    data: Option<ByteSize>,
+    boot: Option<ByteSize>,
+    root: Option<ByteSize>,
+    verity: Option<ByteSize>,
+}
+
+impl VariantBudget {
+    fn get(&self, image: &str) -> Option<ByteSize> {
+        match image {
+            "image" => self.image,
+            "data" => self.data,
+            "boot" => self.boot,
+            "root" => self.root,
+            "verity" => self.verity,
+            _ => None,
+        }
+    }
+}
+
+/// The largest a kit's layers, and the kit as a whole, may be.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct KitBudget {
+    layer: Option<ByteSize>,
+    total: Option<ByteSize>,
+}
+
+/// A number of bytes, written in `Twoliter.toml` as a number of bytes or with a unit, such as
+/// `"512MiB"` or `"1.5GB"`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(try_from = "RawByteSize")]
+pub(crate) struct ByteSize(pub(crate) u64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawByteSize {
+    Bytes(u64),
+    Text(String),
+}
+
+impl TryFrom<RawByteSize> for ByteSize {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawByteSize) -> Result<Self> {
+        match raw {
+            RawByteSize::Bytes(bytes) => Ok(Self(bytes)),
+            RawByteSize::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let multiplier: u64 = match unit.trim() {
+            "" | "B" => 1,
+            "K" | "KiB" => 1 << 10,
+            "M" | "MiB" => 1 << 20,
+            "G" | "GiB" => 1 << 30,
+            "KB" => 1000,
+            "MB" => 1000 * 1000,
+            "GB" => 1000 * 1000 * 1000,
+            unit => bail!("unknown unit '{unit}' in size '{s}'"),
+        };
+        let number: f64 = number
+            .parse()
+            .map_err(|_| anyhow!("'{s}' is not a size, such as '512MiB'"))?;
+        Ok(Self((number * multiplier as f64).round() as u64))
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let bytes = self.0 as f64;
+        let text = match self.0 {
+            size if size >= 1 << 30 => format!("{:.1} GiB", bytes / (1u64 << 30) as f64),
+            size if size >= 1 << 20 => format!("{:.1} MiB", bytes / (1u64 << 20) as f64),
+            size if size >= 1 << 10 => format!("{:.1} KiB", bytes / (1u64 << 10) as f64),
+            size => format!("{size} B"),
+        };
+        f.pad(&text)
+    }
+}
+
+/// The size of something a budget limits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Measurement {
+    name: String,
+    size: u64,
+    budget: Option<u64>,
+}
+
+impl Measurement {
+    fn over_budget(&self) -> bool {
+        self.budget.is_some_and(|budget| self.size > budget)
+    }
+}
+
+/// The sizes of a built variant or kit against its budgets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SizeReport {
+    target: String,
+    measurements: Vec<Measurement>,
+    /// The largest packages, largest first.
+    largest: Vec<(String, u64)>,
+}
+
+impl SizeReport {
+    fn over_budget(&self) -> Vec<&Measurement> {
+        self.measurements
+            .iter()
+            .filter(|measurement| measurement.over_budget())
+            .collect()
+    }
+
+    /// Fails with the report if anything is over its budget.
+    fn ensure_within_budget(&self) -> Result<()> {
+        let over = self.over_budget();
+        ensure!(
+            over.is_empty(),
+            "{self}\n{} exceeds its size budget for {}",
+            self.target,
+            over.iter()
+                .map(|measurement| measurement.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        info!("{} is within its size budget", self.target);
+        Ok(())
+    }
+}
+
+impl Display for SizeReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Sizes of {}:", self.target)?;
+        for measurement in &self.measurements {
+            write!(
+                f,
+                "\n  {:<40} {:>10}",
+                measurement.name,
+                ByteSize(measurement.size)
+            )?;
+            if let Some(budget) = measurement.budget {
+                write!(f, " of {}", ByteSize(budget))?;
+            }
+            if measurement.over_budget() {
+                write!(f, "  OVER BUDGET")?;
+            }
+        }
+        if !self.largest.is_empty() {
+            write!(f, "\nLargest packages:")?;
+            for (name, size) in &self.largest {
+                write!(f, "\n  {name:<40} {:>10}", ByteSize(*size))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// Checks the latest build of `variant` for `arch` against its budget, if it has one.
+    pub(crate) async fn check_variant_budget(&self, variant: &str, arch: &str) -> Result<()> {
+        let Some(budget) = self.budget.variant.get(variant) else {
+            return Ok(());
+        };
+        let images_dir = self
+            .project_dir
+            .join("build/images")
+            .join(format!("{arch}-{variant}"))
+            .join("latest");
+        ensure!(
+            images_dir.is_dir(),
+            "no build of variant '{variant}' for '{arch}' at '{}'",
+            images_dir.display()
+        );
+        let mut sizes = BTreeMap::<&str, u64>::new();
+        for (path, size) in files(&images_dir)? {
+            let name = path.file_name().and_then(|name| name.to_str());
+            if let Some(image) = name.and_then(image_kind) {
+                let largest = sizes.entry(image).or_default();
+                *largest = (*largest).max(size);
+            }
+        }
+        let measurements = sizes
+            .into_iter()
+            .map(|(image, size)| Measurement {
+                name: image.to_string(),
+                size,
+                budget: budget.get(image).map(|budget| budget.0),
+            })
+            .collect();
+
+        // The image's packages come from the kits, so the sizes of their RPMs are the best measure
+        // of what each package adds to the image.
+        let installed = self.installed_packages(variant, arch).await?;
+        let mut packages = BTreeMap::new();
+        for dir in [
+            self.project_dir.join("build/kits"),
+            self.external_kits_dir(),
+        ] {
+            packages.extend(package_sizes(&dir, arch)?);
+        }
+        packages.retain(|name, _| installed.contains_key(name));
+        SizeReport {
+            target: format!("variant '{variant}' for {arch}"),
+            measurements,
+            largest: largest(packages),
+        }
+        .ensure_within_budget()
+    }
+
+    /// Checks the latest build of `kit` for `arch` against its budget, if it has one.
+    pub(crate) async fn check_kit_budget(&self, kit: &str, arch: &str) -> Result<()> {
+        let Some(budget) = self.budget.kit.get(kit) else {
+            return Ok(());
+        };
+        let packages_dir = self
+            .project_dir
+            .join("build/kits")
+            .join(kit)
+            .join(arch)
+            .join("Packages");
+        ensure!(
+            packages_dir.is_dir(),
+            "no build of kit '{kit}' for '{arch}' at '{}'",
+            packages_dir.display()
+        );
+        let mut layers = BTreeMap::new();
+        for (path, size) in files(&packages_dir)? {
+            let layer = path
+                .strip_prefix(&packages_dir)
+                .ok()
+                .and_then(|path| path.components().next())
+                .map(|layer| format!("layer Packages/{}", layer.as_os_str().to_string_lossy()));
+            if let Some(layer) = layer {
+                *layers.entry(layer).or_default() += size;
+            }
+        }
+        let total = layers.values().sum();
+        let mut measurements = layers
+            .into_iter()
+            .map(|(name, size)| Measurement {
+                name,
+                size,
+                budget: budget.layer.map(|budget| budget.0),
+            })
+            .collect::<Vec<_>>();
+        measurements.push(Measurement {
+            name: "total".to_string(),
+            size: total,
+            budget: budget.total.map(|budget| budget.0),
+        });
+        SizeReport {
+            target: format!("kit '{kit}' for {arch}"),
+            measurements,
+            largest: largest(package_sizes(&packages_dir, arch)?),
+        }
+        .ensure_within_budget()
+    }
+}
+
+/// Which of a variant's images the file `name` is, from the names `rpm2img` gives them.
+fn image_kind(name: &str) -> Option<&'static str> {
+    let kind = if name.contains("-root.verity") {
+        "verity"
+    } else if name.contains("-root.") {
+        "root"
+    } else if name.contains("-boot.") {
+        "boot"
+    } else if name.contains("-data.") {
+        "data"
+    } else if name.contains(".img") || name.ends_with(".vmdk") || name.ends_with(".qcow2") {
+        "image"
+    } else {
+        return None;
+    };
+    Some(kind)
+}
+
+/// The regular files under `dir`, with their sizes. Links are left out, so that the images which
+/// `rpm2img` links to under friendlier names are only counted once.
+pub(super) fn files(dir: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .context(format!("failed to read directory '{}'", dir.display()))?;
+        for entry in entries {
+            let entry = entry.context(format!("failed to read directory '{}'", dir.display()))?;
+            let metadata = std::fs::symlink_metadata(entry.path())
+                .context(format!("failed to inspect '{}'", entry.path().display()))?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else if metadata.is_file() {
+                files.push((entry.path(), metadata.len()));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// The size of each package built for `arch` under `dir`, by name.
+fn package_sizes(dir: &Path, arch: &str) -> Result<BTreeMap<String, u64>> {
+    if !dir.is_dir() {
+        return Ok(BTreeMap::new());
+    }
+    Ok(files(dir)?
+        .into_iter()
+        .filter(|(path, _)| {
+            path.components()
+                .any(|component| component.as_os_str() == arch)
+        })
+        .filter_map(|(path, size)| {
+            let (name, _) = parse_rpm_name(path.file_name()?.to_str()?)?;
+            Some((name, size))
+        })
+        .collect())
+}
+
+/// The largest of `packages`, largest first.
+fn largest(packages: BTreeMap<String, u64>) -> Vec<(String, u64)> {
+    let mut packages = packages.into_iter().collect::<Vec<_>>();
+    packages.sort_by(|(_, left), (_, right)| right.cmp(left));
+    packages.truncate(LARGEST);
+    packages
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_byte_size() {
+        assert_eq!("512".parse::<ByteSize>().unwrap(), ByteSize(512));
+        assert_eq!("600MiB".parse::<ByteSize>().unwrap(), ByteSize(600 << 20));
+        assert_eq!("1.5 GiB".parse::<ByteSize>().unwrap(), ByteSize(3 << 29));
+        assert_eq!("2MB".parse::<ByteSize>().unwrap(), ByteSize(2_000_000));
+        assert!("2 parsecs".parse::<ByteSize>().is_err());
+        assert!("MiB".parse::<ByteSize>().is_err());
+        assert_eq!(ByteSize(3 << 29).to_string(), "1.5 GiB");
+        assert_eq!(ByteSize(100).to_string(), "100 B");
+    }
+
+    #[test]
+    fn test_budget_config() {
+        let config: BudgetConfig = toml::from_str(
+            r#"
+[variant.aws-dev]
+image = "1GiB"
+root = 629145600
+
+[kit.my-kit]
+layer = "200MiB"
+"#,
+        )
+        .unwrap();
+        let variant = &config.variant["aws-dev"];
+        assert_eq!(variant.get("image"), Some(ByteSize(1 << 30)));
+        assert_eq!(variant.get("root"), Some(ByteSize(600 << 20)));
+        assert_eq!(variant.get("data"), None);
+        assert_eq!(config.kit["my-kit"].layer, Some(ByteSize(200 << 20)));
+        assert!(toml::from_str::<BudgetConfig>("[variant.aws-dev]\nefi = \"1MiB\"").is_err());
+    }
+
+    #[test]
+    fn test_image_kind() {
+        let prefix = "bottlerocket-aws-dev-x86_64-1.20.0-abcdef";
+        assert_eq!(image_kind(&format!("{prefix}.img.lz4")), Some("image"));
+        assert_eq!(image_kind(&format!("{prefix}-data.img.lz4")), Some("data"));
+        assert_eq!(image_kind(&format!("{prefix}-boot.ext4.lz4")), Some("boot"));
+        assert_eq!(image_kind(&format!("{prefix}-root.ext4.lz4")), Some("root"));
+        assert_eq!(
+            image_kind(&format!("{prefix}-root.verity.lz4")),
+            Some("verity")
+        );
+        assert_eq!(image_kind("application-inventory.json"), None);
+    }
+
+    #[test]
+    fn test_package_sizes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for (arch, file, size) in [
+            ("x86_64", "bottlerocket-glibc-2.38-1.x86_64.rpm", 300),
+            ("x86_64", "bottlerocket-kernel-6.1-6.1.90-1.x86_64.rpm", 900),
+            ("aarch64", "bottlerocket-glibc-2.38-1.aarch64.rpm", 200),
+        ] {
+            let packages = dir.path().join(arch).join("Packages/pkg");
+            std::fs::create_dir_all(&packages).unwrap();
+            std::fs::write(packages.join(file), vec![0; size]).unwrap();
+        }
+        let sizes = package_sizes(dir.path(), "x86_64").unwrap();
+        assert_eq!(
+            largest(sizes),
+            [("kernel-6.1".to_string(), 900), ("glibc".to_string(), 300)]
+        );
+        assert!(package_sizes(&dir.path().join("missing"), "x86_64")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_report() {
+        let report = SizeReport {
+            target: "kit 'my-kit' for x86_64".to_string(),
+            measurements: vec![
+                Measurement {
+                    name: "layer Packages/kernel-6.1".to_string(),
+                    size: 300 << 20,
+                    budget: Some(200 << 20),
+                },
+                Measurement {
+                    name: "total".to_string(),
+                    size: 400 << 20,
+                    budget: None,
+                },
+            ],
+            largest: largest(BTreeMap::from([
+                ("glibc".to_string(), 10 << 20),
+                ("kernel-6.1".to_string(), 250 << 20),
+            ])),
+        };
+        assert_eq!(report.largest[0].0, "kernel-6.1");
+        let err = report.ensure_within_budget().unwrap_err().to_string();
+        assert!(err.contains("300.0 MiB of 200.0 MiB  OVER BUDGET"));
+        assert!(err.ends_with("exceeds its size budget for layer Packages/kernel-6.1"));
+    }
+}
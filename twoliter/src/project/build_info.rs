@@ -0,0 +1,412 @@
+//! Records the environment each build ran in, so that it can be reproduced with `twoliter rebuild`.
+//!
+//! Before building a kit or a variant, Twoliter writes a description of the build to
+//! `build/build-info/<kit|variant>-<name>-<arch>.json`: what was built and with which options, the
+//! project's commit, the SDK and kits locked by Twoliter.lock along with their digests, the versions
+//! of twoliter and of the tools it runs, the `BUILDSYS_` environment variables which were set, and
+//! the host's architecture and kernel. Environment variables whose names suggest they hold
+//! credentials are left out.
+//!
+//! When a kit is published, the compression of its layers is added to its build info, by vendor.
+//!
+//! A rebuild compares the recorded build with the project as it is now. Differences in what goes
+//! into the build, such as a changed lock or commit, mean the rebuild would not be exact, while
+//! differences in the tools or host are only worth knowing about.
+use super::compression::LayerCompression;
+use super::lock::LockedImage;
+use super::{Locked, Project};
+use crate::common::exec;
+use crate::common::fs::{create_dir_all, read_to_string, write};
+use crate::docker::Docker;
+use anyhow::{Context, Result};
+use krane_static::settings;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// Where build info is recorded, relative to the project directory.
+const BUILD_INFO_DIR: &str = "build/build-info";
+
+/// The environment variables recorded with a build are those with this prefix.
+const RECORDED_ENV_PREFIX: &str = "BUILDSYS_";
+
+/// Environment variables whose names contain one of these are not recorded.
+const SENSITIVE_ENV_MARKERS: &[&str] = &["TOKEN", "SECRET", "PASSWORD", "CREDENTIAL", "WEBHOOK"];
+
+/// A description of a build, and of the environment it ran in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BuildInfo {
+    pub(crate) target: BuildTarget,
+    pub(crate) arch: String,
+    pub(crate) release_version: String,
+    /// The project's commit, if it is a git repository.
+    pub(crate) commit: Option<String>,
+    pub(crate) options: BuildOptions,
+    pub(crate) sdk: LockedImage,
+    pub(crate) kits: Vec<LockedImage>,
+    /// The versions of twoliter and of the tools it runs, by tool.
+    pub(crate) tools: BTreeMap<String, String>,
+    pub(crate) env: BTreeMap<String, String>,
+    pub(crate) host: Host,
+    /// The compression of the kit's layers when it was published, by vendor.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) published: BTreeMap<String, LayerCompression>,
+}
+
+/// What a build built.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "type", content = "name")]
+pub(crate) enum BuildTarget {
+    Kit(String),
+    Variant(String),
+}
+
+/// The command line options a build was run with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BuildOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) lookaside_cache: Option<String>,
+    #[serde(default)]
+    pub(crate) upstream_source_fallback: bool,
+    /// Whether the build kept going after a package failed to build, if chosen on the command
+    /// line.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) keep_going: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) builder: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) infra_toml: Option<PathBuf>,
+}
+
+/// The host a build ran on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Host {
+    pub(crate) arch: String,
+    pub(crate) kernel: Option<String>,
+}
+
+/// How the project and host differ from those a build was recorded with.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct BuildInfoDrift {
+    /// Differences in what goes into the build, which keep a rebuild from being exact.
+    pub(crate) inputs: Vec<String>,
+    /// Differences in the tools and host running the build.
+    pub(crate) environment: Vec<String>,
+}
+
+impl BuildInfo {
+    /// Reads the build info recorded at `path`.
+    pub(crate) async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        serde_json::from_str(&read_to_string(path).await?)
+            .context(format!("Unable to parse build info '{}'", path.display()))
+    }
+
+    /// Sets the recorded environment variables for the rebuild, and unsets those which were not
+    /// set for the recorded build.
+    pub(crate) fn apply_env(&self) {
+        for (key, _) in recorded_env(settings::vars().into_iter()) {
+            if !self.env.contains_key(&key) {
+                settings::unset(key);
+            }
+        }
+        for (key, value) in &self.env {
+            settings::set(key, value);
+        }
+    }
+
+    fn file_name(&self) -> String {
+        let (kind, name) = match &self.target {
+            BuildTarget::Kit(name) => ("kit", name),
+            BuildTarget::Variant(name) => ("variant", name),
+        };
+        format!("{kind}-{name}-{}.json", self.arch)
+    }
+
+    /// Compares this recorded build with the `current` one.
+    fn drift(&self, current: &BuildInfo) -> BuildInfoDrift {
+        let mut drift = BuildInfoDrift::default();
+        let tool = |info: &BuildInfo, name: &str| {
+            info.tools
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_string())
+        };
+        if tool(self, "twoliter") != tool(current, "twoliter") {
+            drift.inputs.push(format!(
+                "twoliter {} was used, but this is twoliter {}",
+                tool(self, "twoliter"),
+                tool(current, "twoliter")
+            ));
+        }
+        if self.commit != current.commit {
+            drift.inputs.push(format!(
+                "the project was at commit {}, but is now at {}",
+                self.commit.as_deref().unwrap_or("unknown"),
+                current.commit.as_deref().unwrap_or("unknown")
+            ));
+        }
+        if self.release_version != current.release_version {
+            drift.inputs.push(format!(
+                "the release-version was {}, but is now {}",
+                self.release_version, current.release_version
+            ));
+        }
+        if self.sdk != current.sdk {
+            drift.inputs.push(format!(
+                "the SDK was locked to {} ({}), but is now locked to {} ({})",
+                self.sdk, self.sdk.digest, current.sdk, current.sdk.digest
+            ));
+        }
+        for kit in &self.kits {
+            if !current.kits.contains(kit) {
+                drift
+                    .inputs
+                    .push(format!("{kit} ({}) is no longer locked", kit.digest));
+            }
+        }
+        for kit in &current.kits {
+            if !self.kits.contains(kit) {
+                drift
+                    .inputs
+                    .push(format!("{kit} ({}) was not locked", kit.digest));
+            }
+        }
+
+        for (name, version) in &self.tools {
+            match current.tools.get(name) {
+                _ if name == "twoliter" => {}
+                Some(current_version) if current_version == version => {}
+                current_version => drift.environment.push(format!(
+                    "{name} {version} was used, but {name} is now {}",
+                    current_version.map_or("not found", String::as_str)
+                )),
+            }
+        }
+        if self.host.arch != current.host.arch {
+            drift.environment.push(format!(
+                "the build ran on an {} host, but this host is {}",
+                self.host.arch, current.host.arch
+            ));
+        }
+        if self.host.kernel != current.host.kernel {
+            drift.environment.push(format!(
+                "the build ran on kernel {}, but this host runs {}",
+                self.host.kernel.as_deref().unwrap_or("unknown"),
+                current.host.kernel.as_deref().unwrap_or("unknown")
+            ));
+        }
+        drift
+    }
+}
+
+impl Project<Locked> {
+    /// Records a build of `target` for `arch` with `options` in the build directory, returning
+    /// where it was written.
+    pub(crate) async fn record_build_info(
+        &self,
+        target: BuildTarget,
+        arch: &str,
+        options: BuildOptions,
+    ) -> Result<PathBuf> {
+        let info = self.describe_build(target, arch, options).await;
+        let dir = self.project_dir.join(BUILD_INFO_DIR);
+        create_dir_all(&dir).await?;
+        let path = dir.join(info.file_name());
+        let json = serde_json::to_string_pretty(&info).context("Unable to serialize build info")?;
+        write(&path, json).await?;
+        Ok(path)
+    }
+
+    /// Adds the `compression` that `kit` was published to `vendor` with to the kit's recorded
+    /// builds.
+    pub(crate) async fn record_publish(
+        &self,
+        kit: &str,
+        vendor: &str,
+        compression: LayerCompression,
+    ) -> Result<()> {
+        let dir = self.project_dir.join(BUILD_INFO_DIR);
+        let Ok(mut entries) = tokio::fs::read_dir(&dir).await else {
+            return Ok(());
+        };
+        let target = BuildTarget::Kit(kit.to_string());
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context(format!("Unable to read '{}'", dir.display()))?
+        {
+            let Ok(mut info) = BuildInfo::load(entry.path()).await else {
+                continue;
+            };
+            if info.target != target {
+                continue;
+            }
+            info.published.insert(vendor.to_string(), compression);
+            let json =
+                serde_json::to_string_pretty(&info).context("Unable to serialize build info")?;
+            write(entry.path(), json).await?;
+        }
+        Ok(())
+    }
+
+    /// Compares the recorded build `info` with what a build would use now.
+    pub(crate) async fn build_info_drift(&self, info: &BuildInfo) -> BuildInfoDrift {
+        let current = self
+            .describe_build(info.target.clone(), &info.arch, info.options.clone())
+            .await;
+        info.drift(&current)
+    }
+
+    async fn describe_build(
+        &self,
+        target: BuildTarget,
+        arch: &str,
+        options: BuildOptions,
+    ) -> BuildInfo {
+        let Locked(lock) = &self.lock;
+        BuildInfo {
+            target,
+            arch: arch.to_string(),
+            release_version: self.release_version.clone(),
+            commit: git_commit(&self.project_dir).await,
+            options,
+            sdk: lock.sdk.clone(),
+            kits: lock.kit.clone(),
+            tools: tool_versions().await,
+            env: recorded_env(settings::vars().into_iter()),
+            host: Host {
+                arch: std::env::consts::ARCH.to_string(),
+                kernel: tokio::fs::read_to_string("/proc/sys/kernel/osrelease")
+                    .await
+                    .ok()
+                    .map(|kernel| kernel.trim().to_string()),
+            },
+            published: BTreeMap::new(),
+        }
+    }
+}
+
+/// The commit checked out in `project_dir`, if it is in a git repository.
+async fn git_commit(project_dir: &Path) -> Option<String> {
+    let commit = exec(
+        Command::new("git")
+            .arg("-C")
+            .arg(project_dir)
+            .args(["rev-parse", "HEAD"]),
+        true,
+    )
+    .await
+    .ok()
+    .flatten()?;
+    Some(commit.trim().to_string())
+}
+
+/// The versions of twoliter and of the tools it runs which could be found.
+async fn tool_versions() -> BTreeMap<String, String> {
+    let mut tools = BTreeMap::new();
+    tools.insert(
+        "twoliter".to_string(),
+        env!("CARGO_PKG_VERSION").to_string(),
+    );
+    if let (Ok(tool), Ok(version)) = (Docker::tool(), Docker::server_version().await) {
+        tools.insert(tool.to_string(), version.to_string());
+    }
+    if let Ok(Some(version)) = exec(Command::new("cargo").arg("--version"), true).await {
+        tools.insert("cargo".to_string(), version.trim().to_string());
+    }
+    tools
+}
+
+/// The environment variables among `vars` which are recorded with a build.
+fn recorded_env(vars: impl Iterator<Item = (String, String)>) -> BTreeMap<String, String> {
+    vars.filter(|(key, _)| {
+        key.starts_with(RECORDED_ENV_PREFIX)
+            && !SENSITIVE_ENV_MARKERS
+                .iter()
+                .any(|marker| key.contains(marker))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::project::lock::test::locked_image;
+
+    fn build_info() -> BuildInfo {
+        BuildInfo {
+            target: BuildTarget::Variant("aws-dev".to_string()),
+            arch: "x86_64".to_string(),
+            release_version: "1.0.0".to_string(),
+            commit: Some("abc123".to_string()),
+            options: BuildOptions::default(),
+            sdk: locked_image("sdk", "1.0.0", "c2Rr"),
+            kits: vec![locked_image("core-kit", "1.0.0", "a2l0")],
+            tools: [("twoliter", "0.5.0"), ("docker", "27.0.0")]
+                .into_iter()
+                .map(|(tool, version)| (tool.to_string(), version.to_string()))
+                .collect(),
+            env: BTreeMap::new(),
+            host: Host {
+                arch: "x86_64".to_string(),
+                kernel: Some("6.1.0".to_string()),
+            },
+            published: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_build_info_round_trip() {
+        let info = build_info();
+        assert_eq!(info.file_name(), "variant-aws-dev-x86_64.json");
+        let json = serde_json::to_string(&info).unwrap();
+        assert!(json.contains(r#""target":{"type":"variant","name":"aws-dev"}"#));
+        assert_eq!(serde_json::from_str::<BuildInfo>(&json).unwrap(), info);
+    }
+
+    #[test]
+    fn test_build_info_drift() {
+        let recorded = build_info();
+        assert_eq!(recorded.drift(&recorded), BuildInfoDrift::default());
+
+        let mut current = build_info();
+        current.kits = vec![locked_image("core-kit", "1.0.0", "bmV3")];
+        current
+            .tools
+            .insert("docker".to_string(), "28.0.0".to_string());
+        let drift = recorded.drift(&current);
+        assert_eq!(drift.inputs.len(), 2);
+        assert!(drift.inputs[0].ends_with("(a2l0) is no longer locked"));
+        assert!(drift.inputs[1].ends_with("(bmV3) was not locked"));
+        assert_eq!(
+            drift.environment,
+            ["docker 27.0.0 was used, but docker is now 28.0.0"]
+        );
+    }
+
+    #[test]
+    fn test_recorded_env() {
+        let vars = [
+            ("BUILDSYS_LOOKASIDE_CACHE", "https://cache.example.com"),
+            (
+                "BUILDSYS_FAILURE_WEBHOOK_URL",
+                "https://hooks.example.com/secret",
+            ),
+            ("HOME", "/root"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        assert_eq!(
+            recorded_env(vars).into_iter().collect::<Vec<_>>(),
+            [(
+                "BUILDSYS_LOOKASIDE_CACHE".to_string(),
+                "https://cache.example.com".to_string()
+            )]
+        );
+    }
+}
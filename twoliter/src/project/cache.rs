@@ -0,0 +1,354 @@
+//! Tracks whether the artifacts that Twoliter keeps under the build directory could be reused,
+//! so that slow builds can be explained, and summarizes what is currently cached on disk.
+use crate::common::fs::read_to_string;
+use crate::output::{Output, OutputSchema, CACHE_STATS_SCHEMA};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// The directory within the external kits directory that holds pulled OCI archives.
+pub(crate) const KIT_ARCHIVE_CACHE_DIR: &str = "cache";
+
+/// The file recording which image digest was extracted into a kit directory.
+pub(crate) const EXTRACTED_DIGEST_FILE: &str = "digest";
+
+/// The file marking an extracted kit as changed since it was extracted, which holds the path of
+/// the first file that changed.
+pub(crate) const STALE_FILE: &str = ".stale";
+
+/// Why a cached artifact could not be reused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CacheMiss {
+    /// Nothing had been cached yet.
+    Missing,
+    /// Something was cached, but for a different input, e.g. an older digest of the same kit.
+    Changed { cached: String, wanted: String },
+    /// Something was cached, but it failed its integrity check and could not be trusted.
+    Tampered,
+    /// Something was cached, but `twoliter cache watch` saw it being changed afterwards.
+    Modified { path: String },
+}
+
+/// Whether a cached artifact could be reused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CacheStatus {
+    Hit,
+    Miss(CacheMiss),
+}
+
+impl CacheStatus {
+    pub(crate) fn is_hit(&self) -> bool {
+        matches!(self, CacheStatus::Hit)
+    }
+}
+
+impl Display for CacheStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheStatus::Hit => write!(f, "hit"),
+            CacheStatus::Miss(CacheMiss::Missing) => write!(f, "miss (not cached)"),
+            CacheStatus::Miss(CacheMiss::Changed { cached, wanted }) => {
+                write!(f, "miss (input changed: cached {cached}, wanted {wanted})")
+            }
+            CacheStatus::Miss(CacheMiss::Tampered) => write!(f, "miss (failed integrity check)"),
+            CacheStatus::Miss(CacheMiss::Modified { path }) => {
+                write!(f, "miss (modified after extraction: {path})")
+            }
+        }
+    }
+}
+
+/// The kind of artifact a cache lookup was for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CacheStage {
+    /// The SDK image in the local docker daemon.
+    SdkImage,
+    /// A kit's OCI archive, pulled from its registry.
+    KitArchive,
+    /// A kit's packages, extracted from its OCI archive.
+    KitExtraction,
+}
+
+impl Display for CacheStage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheStage::SdkImage => write!(f, "sdk image"),
+            CacheStage::KitArchive => write!(f, "kit archive"),
+            CacheStage::KitExtraction => write!(f, "kit extraction"),
+        }
+    }
+}
+
+/// The outcome of a single cache lookup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CacheEvent {
+    pub(crate) subject: String,
+    pub(crate) stage: CacheStage,
+    pub(crate) status: CacheStatus,
+}
+
+/// The outcomes of the cache lookups made while preparing a build, shown by `--explain-cache`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheReport {
+    events: Vec<CacheEvent>,
+}
+
+impl CacheReport {
+    pub(crate) fn record(
+        &mut self,
+        subject: impl Into<String>,
+        stage: CacheStage,
+        status: CacheStatus,
+    ) {
+        self.events.push(CacheEvent {
+            subject: subject.into(),
+            stage,
+            status,
+        });
+    }
+
+    pub(crate) fn extend(&mut self, other: CacheReport) {
+        self.events.extend(other.events);
+    }
+}
+
+impl Display for CacheReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for event in &self.events {
+            writeln!(
+                f,
+                "{:<16} {}: {}",
+                event.stage.to_string(),
+                event.subject,
+                event.status
+            )?;
+        }
+        let hits = self.events.iter().filter(|e| e.status.is_hit()).count();
+        write!(f, "{hits} hit(s), {} miss(es)", self.events.len() - hits)
+    }
+}
+
+/// A kit which has been extracted into the external kits directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct ExtractedKit {
+    /// The kit's directory relative to the external kits directory, e.g. `vendor/kit/x86_64`.
+    pub(crate) path: PathBuf,
+    pub(crate) digest: String,
+}
+
+/// An OCI archive held in the kit archive cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct CachedArchive {
+    pub(crate) digest: String,
+    pub(crate) size: u64,
+}
+
+/// A summary of the kits cached in the external kits directory, shown by `twoliter cache stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct CacheStats {
+    pub(crate) archives: Vec<CachedArchive>,
+    pub(crate) extracted: Vec<ExtractedKit>,
+}
+
+impl CacheStats {
+    /// Inspects the archives and extracted kits found under `external_kits_dir`.
+    pub(crate) async fn collect(external_kits_dir: impl AsRef<Path>) -> Result<Self> {
+        let external_kits_dir = external_kits_dir.as_ref();
+        let mut stats = Self::default();
+
+        for archive_dir in subdirs(&external_kits_dir.join(KIT_ARCHIVE_CACHE_DIR)).await? {
+            let name = archive_dir.file_name().unwrap_or_default();
+            // Skip pulls that were interrupted before their archive was moved into place.
+            if name.to_string_lossy().starts_with('.') {
+                continue;
+            }
+            stats.archives.push(CachedArchive {
+                // Archives are named for their digest, with ':' replaced to keep paths portable.
+                digest: name.to_string_lossy().replacen('-', ":", 1),
+                size: dir_size(&archive_dir).await?,
+            });
+        }
+
+        // Extracted kits are laid out as `<vendor>/<kit>/<arch>`.
+        for vendor_dir in subdirs(external_kits_dir).await? {
+            if vendor_dir.ends_with(KIT_ARCHIVE_CACHE_DIR) {
+                continue;
+            }
+            for kit_dir in subdirs(&vendor_dir).await? {
+                for arch_dir in subdirs(&kit_dir).await? {
+                    let digest_file = arch_dir.join(EXTRACTED_DIGEST_FILE);
+                    if !digest_file.is_file() {
+                        continue;
+                    }
+                    stats.extracted.push(ExtractedKit {
+                        path: arch_dir
+                            .strip_prefix(external_kits_dir)
+                            .unwrap_or(&arch_dir)
+                            .to_path_buf(),
+                        digest: read_to_string(&digest_file).await?.trim().to_string(),
+                    });
+                }
+            }
+        }
+
+        stats.archives.sort_by(|a, b| a.digest.cmp(&b.digest));
+        stats.extracted.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(stats)
+    }
+
+    /// Archives which no extracted kit was unpacked from, e.g. left behind by a kit update.
+    pub(crate) fn unreferenced_archives(&self) -> Vec<&CachedArchive> {
+        let referenced = self
+            .extracted
+            .iter()
+            .map(|kit| kit.digest.as_str())
+            .collect::<BTreeSet<_>>();
+        self.archives
+            .iter()
+            .filter(|archive| !referenced.contains(archive.digest.as_str()))
+            .collect()
+    }
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let total: u64 = self.archives.iter().map(|a| a.size).sum();
+        writeln!(f, "Kit archives: {} ({} bytes)", self.archives.len(), total)?;
+        for archive in &self.archives {
+            writeln!(f, "  {} ({} bytes)", archive.digest, archive.size)?;
+        }
+        writeln!(f, "Extracted kits: {}", self.extracted.len())?;
+        for kit in &self.extracted {
+            writeln!(f, "  {} ({})", kit.path.display(), kit.digest)?;
+        }
+        let unreferenced = self.unreferenced_archives();
+        write!(f, "Unreferenced kit archives: {}", unreferenced.len())?;
+        for archive in unreferenced {
+            write!(f, "\n  {}", archive.digest)?;
+        }
+        Ok(())
+    }
+}
+
+impl Output for CacheStats {
+    const SCHEMA: OutputSchema = CACHE_STATS_SCHEMA;
+}
+
+/// Lists the directories directly within `dir`, or nothing if `dir` does not exist.
+async fn subdirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    if !dir.is_dir() {
+        return Ok(dirs);
+    }
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .context(format!("failed to read directory '{}'", dir.display()))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context(format!("failed to read directory '{}'", dir.display()))?
+    {
+        if entry.path().is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+/// The total size of the files within `dir`.
+pub(crate) async fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .context(format!("failed to read directory '{}'", dir.display()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context(format!("failed to read directory '{}'", dir.display()))?
+        {
+            let metadata = entry
+                .metadata()
+                .await
+                .context(format!("failed to stat '{}'", entry.path().display()))?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else {
+                size += metadata.len();
+            }
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::fs::{create_dir_all, write};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_collect_stats() {
+        let dir = TempDir::new().unwrap();
+        let cache = dir.path().join(KIT_ARCHIVE_CACHE_DIR);
+        create_dir_all(cache.join("sha256-aaa/blobs"))
+            .await
+            .unwrap();
+        write(cache.join("sha256-aaa/blobs/layer"), "12345")
+            .await
+            .unwrap();
+        create_dir_all(cache.join("sha256-bbb")).await.unwrap();
+        write(cache.join("sha256-bbb/index.json"), "{}")
+            .await
+            .unwrap();
+
+        let kit = dir.path().join("vendor/core-kit/x86_64");
+        create_dir_all(&kit).await.unwrap();
+        write(kit.join(EXTRACTED_DIGEST_FILE), "sha256:aaa")
+            .await
+            .unwrap();
+
+        let stats = CacheStats::collect(dir.path()).await.unwrap();
+        assert_eq!(
+            stats.archives,
+            vec![
+                CachedArchive {
+                    digest: "sha256:aaa".to_string(),
+                    size: 5
+                },
+                CachedArchive {
+                    digest: "sha256:bbb".to_string(),
+                    size: 2
+                },
+            ]
+        );
+        assert_eq!(
+            stats.extracted,
+            vec![ExtractedKit {
+                path: PathBuf::from("vendor/core-kit/x86_64"),
+                digest: "sha256:aaa".to_string(),
+            }]
+        );
+        assert_eq!(stats.unreferenced_archives(), vec![&stats.archives[1]]);
+    }
+
+    #[test]
+    fn test_report_display() {
+        let mut report = CacheReport::default();
+        report.record("sdk", CacheStage::SdkImage, CacheStatus::Hit);
+        report.record(
+            "core-kit",
+            CacheStage::KitExtraction,
+            CacheStatus::Miss(CacheMiss::Changed {
+                cached: "sha256:aaa".to_string(),
+                wanted: "sha256:bbb".to_string(),
+            }),
+        );
+        let display = report.to_string();
+        assert!(display.contains("kit extraction   core-kit: miss (input changed"));
+        assert!(display.ends_with("1 hit(s), 1 miss(es)"));
+    }
+}
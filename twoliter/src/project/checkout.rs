@@ -0,0 +1,214 @@
+//! Widens sparse checkouts to the parts of a project that a build needs.
+//!
+//! Large projects can be cloned with `git clone --filter=blob:none --sparse` so that only the
+//! packages being worked on are checked out. Before building a kit or variant in such a checkout,
+//! twoliter adds the directories of the packages, kits and source groups it depends on to the
+//! checkout, and git fetches what it is missing. The checkout keeps every file at the top two levels
+//! of the project and every package's Cargo.toml, so that cargo can still load the whole workspace.
+//! Checkouts are only ever widened; `git sparse-checkout set` narrows them again.
+use super::{Project, ProjectLock};
+use crate::common::fs::read_to_string;
+use crate::git::Git;
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+use toml::Table;
+use tracing::{debug, info};
+
+/// Patterns for every file at the top two levels of the project, and every Cargo.toml one level
+/// below that.
+const BASE_PATTERNS: &[&str] = &["/*", "!/*/", "/*/*", "!/*/*/", "/*/*/Cargo.toml"];
+
+impl<L: ProjectLock> Project<L> {
+    /// Widens the sparse checkout, if the project is in one, to the directories that building the
+    /// `targets` needs. Targets are directories relative to the project, such as `kits/<name>`.
+    pub(crate) async fn sparse_checkout(&self, targets: &[PathBuf]) -> Result<()> {
+        let project_dir = self.project_dir();
+        if !Git::is_sparse(&project_dir).await {
+            return Ok(());
+        }
+        let toplevel = Git::toplevel(&project_dir).await?;
+        let prefix = project_dir
+            .canonicalize()
+            .context(format!(
+                "failed to resolve the project directory '{}'",
+                project_dir.display()
+            ))?
+            .strip_prefix(toplevel.canonicalize()?)
+            .context("the project is not inside its git working tree")?
+            .to_path_buf();
+
+        // The manifests are read to find what the targets need, so they are checked out first.
+        widen(&project_dir, &prefix, &BTreeSet::new()).await?;
+        let dirs = needed_dirs(&project_dir, targets).await?;
+        widen(&project_dir, &prefix, &dirs).await
+    }
+}
+
+/// Adds the base patterns and the patterns for `dirs` to the sparse checkout of the project in
+/// `project_dir`, which is at `prefix` in its repository.
+async fn widen(project_dir: &Path, prefix: &Path, dirs: &BTreeSet<PathBuf>) -> Result<()> {
+    let existing = Git::sparse_patterns(project_dir).await?;
+    let mut patterns = existing.clone();
+    for pattern in checkout_patterns(prefix, dirs) {
+        if !patterns.contains(&pattern) {
+            patterns.push(pattern);
+        }
+    }
+    if patterns.len() == existing.len() {
+        debug!("The sparse checkout already includes what the build needs");
+        return Ok(());
+    }
+    info!(
+        "Adding {} to the sparse checkout",
+        patterns[existing.len()..].join(" ")
+    );
+    Git::set_sparse_patterns(project_dir, &patterns).await
+}
+
+/// The sparse checkout patterns for the base of the project at `prefix` in its repository, and the
+/// project's `dirs`.
+fn checkout_patterns(prefix: &Path, dirs: &BTreeSet<PathBuf>) -> Vec<String> {
+    let prefix = prefix
+        .components()
+        .map(|component| format!("/{}", component.as_os_str().to_string_lossy()))
+        .collect::<String>();
+    let mut patterns = BASE_PATTERNS
+        .iter()
+        .map(|pattern| match pattern.strip_prefix('!') {
+            Some(pattern) => format!("!{prefix}{pattern}"),
+            None => format!("{prefix}{pattern}"),
+        })
+        .collect::<Vec<_>>();
+    patterns.extend(
+        dirs.iter()
+            .map(|dir| format!("{prefix}/{}/", dir.to_string_lossy())),
+    );
+    patterns
+}
+
+/// The directories of the project in `project_dir` that building the `targets` needs: the targets
+/// themselves, the packages and kits they depend on, and the source groups of those packages.
+async fn needed_dirs(project_dir: &Path, targets: &[PathBuf]) -> Result<BTreeSet<PathBuf>> {
+    let mut needed = BTreeSet::new();
+    let mut pending = targets.to_vec();
+    while let Some(dir) = pending.pop() {
+        if !needed.insert(dir.clone()) {
+            continue;
+        }
+        let manifest_path = project_dir.join(&dir).join("Cargo.toml");
+        if !manifest_path.is_file() {
+            continue;
+        }
+        let manifest: Table = toml::from_str(&read_to_string(&manifest_path).await?)
+            .context(format!("failed to parse '{}'", manifest_path.display()))?;
+
+        for table in ["build-dependencies", "dependencies"] {
+            let paths = manifest
+                .get(table)
+                .and_then(|deps| deps.as_table())
+                .into_iter()
+                .flat_map(|deps| deps.values())
+                .filter_map(|dep| dep.get("path").and_then(|path| path.as_str()));
+            // Dependencies outside of the project are left to the user.
+            pending.extend(paths.filter_map(|path| normalize(&dir.join(path))));
+        }
+
+        let groups = manifest
+            .get("package")
+            .and_then(|package| package.get("metadata"))
+            .and_then(|metadata| metadata.get("build-package"))
+            .and_then(|build| build.get("source-groups"))
+            .and_then(|groups| groups.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|group| group.as_str());
+        pending.extend(groups.filter_map(|group| normalize(&Path::new("sources").join(group))));
+    }
+    Ok(needed)
+}
+
+/// Resolves the `..` components of `path`, which is relative to the project, or returns `None`
+/// if it leads outside of the project.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::project_dir;
+
+    fn dirs(dirs: &[&str]) -> BTreeSet<PathBuf> {
+        dirs.iter().map(PathBuf::from).collect()
+    }
+
+    #[tokio::test]
+    async fn test_needed_dirs() {
+        let needed = needed_dirs(
+            &project_dir("local-kit"),
+            &[PathBuf::from("variants/hello-ootb")],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            needed,
+            dirs(&[
+                "kits/core-kit",
+                "kits/extra-1-kit",
+                "kits/extra-2-kit",
+                "kits/extra-3-kit",
+                "packages/pkg-a-1.27",
+                "packages/pkg-b",
+                "packages/pkg-c",
+                "packages/pkg-d",
+                "packages/pkg-e",
+                "packages/pkg-f",
+                "packages/pkg-g",
+                "variants/hello-ootb",
+            ])
+        );
+    }
+
+    #[test]
+    fn test_checkout_patterns() {
+        assert_eq!(
+            checkout_patterns(
+                Path::new("projects/custom"),
+                &dirs(&["kits/core-kit", "sources/api"])
+            ),
+            [
+                "/projects/custom/*",
+                "!/projects/custom/*/",
+                "/projects/custom/*/*",
+                "!/projects/custom/*/*/",
+                "/projects/custom/*/*/Cargo.toml",
+                "/projects/custom/kits/core-kit/",
+                "/projects/custom/sources/api/",
+            ]
+        );
+        assert_eq!(checkout_patterns(Path::new(""), &BTreeSet::new())[0], "/*");
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize(Path::new("kits/core-kit/../../packages/pkg-a")),
+            Some(PathBuf::from("packages/pkg-a"))
+        );
+        assert_eq!(normalize(Path::new("kits/../../elsewhere")), None);
+    }
+}
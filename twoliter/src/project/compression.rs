@@ -0,0 +1,441 @@
+//! Compression of a kit's layers when it is published, set by `layer-compression` in the `publish`
+//! section of `Twoliter.toml`, which a vendor may override:
+//!
+//! ```toml
+//! [publish]
+//! layer-compression = "gzip:6"
+//!
+//! [publish.vendor.my-dev-vendor]
+//! layer-compression = "gzip:1"
+//! ```
+//!
+//! Kits are built with uncompressed layers, so that a build does not depend on where it will be
+//! published. Publishing compresses them as `gzip` or `zstd`, at the given level or else that
+//! algorithm's default: higher levels take longer to publish but are quicker to pull. Kits
+//! compressed with zstd can only be fetched by Twoliter versions which read zstd layers.
+//!
+//! Without a setting, the level suits the vendor's registry: registries on the local host, which
+//! kits are pulled from quickly, get the fastest compression, and public registries, which a kit is
+//! pulled from far more often than it is published to, get the smallest. The compression a kit was
+//! published with is recorded in its build info, so that the published image can be reproduced.
+use super::lock::hash_file;
+use super::{Project, ProjectLock};
+use anyhow::{bail, ensure, Context, Result};
+use flate2::{Compression, GzBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{debug, info};
+
+/// The media type of the uncompressed layers which kits are built with.
+const UNCOMPRESSED_LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+
+/// Where the compressed archives of a kit are written to be published, relative to the project.
+const PUBLISH_DIR: &str = "build/publish";
+
+/// Registries which are pulled from by anyone, so are worth the smallest layers.
+const PUBLIC_REGISTRIES: &[&str] = &["public.ecr.aws", "docker.io", "ghcr.io", "quay.io"];
+
+/// An algorithm to compress layers with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Algorithm {
+    Gzip,
+    Zstd,
+}
+
+impl Algorithm {
+    /// The levels the algorithm accepts, and the level used when none is given.
+    fn levels(&self) -> (u32, u32, u32) {
+        match self {
+            Algorithm::Gzip => (1, 9, 6),
+            Algorithm::Zstd => (1, 22, 3),
+        }
+    }
+
+    fn media_type(&self) -> &'static str {
+        match self {
+            Algorithm::Gzip => "application/vnd.oci.image.layer.v1.tar+gzip",
+            Algorithm::Zstd => "application/vnd.oci.image.layer.v1.tar+zstd",
+        }
+    }
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Algorithm::Gzip => write!(f, "gzip"),
+            Algorithm::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// How to compress the layers of a kit, written as `<algorithm>[:<level>]`, e.g. `zstd:19`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct LayerCompression {
+    algorithm: Algorithm,
+    level: u32,
+}
+
+impl LayerCompression {
+    fn new(algorithm: Algorithm, level: Option<u32>) -> Result<Self> {
+        let (min, max, default) = algorithm.levels();
+        let level = level.unwrap_or(default);
+        ensure!(
+            (min..=max).contains(&level),
+            "{algorithm} compression level must be from {min} to {max}, not {level}"
+        );
+        Ok(Self { algorithm, level })
+    }
+
+    /// The compression suited to kits published to `registry`.
+    pub(crate) fn for_registry(registry: &str) -> Self {
+        let host = registry.split('/').next().unwrap_or_default();
+        let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+        let level = if matches!(host, "localhost" | "127.0.0.1") {
+            1
+        } else if PUBLIC_REGISTRIES.contains(&host) {
+            9
+        } else {
+            6
+        };
+        Self {
+            algorithm: Algorithm::Gzip,
+            level,
+        }
+    }
+
+    /// Compresses the layer blob at `src` into `dst`.
+    fn compress(&self, src: &Path, dst: &Path) -> Result<()> {
+        let mut reader = BufReader::new(
+            File::open(src).context(format!("failed to read layer '{}'", src.display()))?,
+        );
+        let writer = File::create(dst).context(format!("failed to create '{}'", dst.display()))?;
+        let result = match self.algorithm {
+            // The header records no modification time, so the same layer compresses the same.
+            Algorithm::Gzip => {
+                let mut encoder = GzBuilder::new().write(writer, Compression::new(self.level));
+                io::copy(&mut reader, &mut encoder).and_then(|_| encoder.finish().map(|_| ()))
+            }
+            Algorithm::Zstd => zstd::stream::copy_encode(reader, writer, self.level as i32),
+        };
+        result.context(format!(
+            "failed to compress layer '{}' with {self}",
+            src.display()
+        ))
+    }
+}
+
+impl Display for LayerCompression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.level)
+    }
+}
+
+impl FromStr for LayerCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (algorithm, level) = match s.split_once(':') {
+            Some((algorithm, level)) => (
+                algorithm,
+                Some(
+                    level
+                        .parse()
+                        .context(format!("compression level '{level}' is not a number"))?,
+                ),
+            ),
+            None => (s, None),
+        };
+        let algorithm = match algorithm {
+            "gzip" => Algorithm::Gzip,
+            "zstd" => Algorithm::Zstd,
+            _ => bail!("unknown layer compression '{algorithm}'; expected 'gzip' or 'zstd'"),
+        };
+        Self::new(algorithm, level)
+    }
+}
+
+impl TryFrom<String> for LayerCompression {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<LayerCompression> for String {
+    fn from(compression: LayerCompression) -> Self {
+        compression.to_string()
+    }
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// The compression of kits published to `vendor`: the one set for it in `Twoliter.toml`, or
+    /// else the one suited to its registry.
+    pub(crate) fn layer_compression_for(&self, vendor: &str) -> LayerCompression {
+        self.publish_metadata_for(vendor)
+            .layer_compression
+            .or_else(|| {
+                self.vendor
+                    .iter()
+                    .find(|(name, _)| name.as_ref() == vendor)
+                    .map(|(_, vendor)| LayerCompression::for_registry(&vendor.registry))
+            })
+            .unwrap_or_else(|| LayerCompression::for_registry(""))
+    }
+
+    /// Writes the archives of the built `kit` with their layers compressed to a directory of their
+    /// own for publishing to `vendor`, which is returned.
+    pub(crate) async fn compress_kit(
+        &self,
+        kit: &str,
+        vendor: &str,
+        compression: LayerCompression,
+    ) -> Result<PathBuf> {
+        let kit_dir = self.project_dir.join("build/kits").join(kit);
+        let publish_dir = self.project_dir.join(PUBLISH_DIR).join(vendor).join(kit);
+        info!("Compressing the layers of kit '{kit}' with {compression} for '{vendor}'");
+        let output = publish_dir.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            if output.exists() {
+                std::fs::remove_dir_all(&output)
+                    .context(format!("failed to remove '{}'", output.display()))?;
+            }
+            std::fs::create_dir_all(&output)
+                .context(format!("failed to create '{}'", output.display()))?;
+            let entries = std::fs::read_dir(&kit_dir).context(format!(
+                "no build of kit at '{}'; build it before publishing",
+                kit_dir.display()
+            ))?;
+            for entry in entries {
+                let path = entry
+                    .context(format!("failed to read '{}'", kit_dir.display()))?
+                    .path();
+                if path.extension().is_some_and(|extension| extension == "tar") {
+                    let name = path.file_name().context("kit archive has no name")?;
+                    compress_archive(&path, &output.join(name), compression)?;
+                }
+            }
+            Ok(())
+        })
+        .await
+        .context("failed to compress kit layers")??;
+        Ok(publish_dir)
+    }
+}
+
+/// Writes the OCI archive at `src` to `dst` with its uncompressed layers compressed.
+fn compress_archive(src: &Path, dst: &Path, compression: LayerCompression) -> Result<()> {
+    let temp_dir = tempfile::TempDir::new().context("failed to create a directory for a kit")?;
+    let layout = temp_dir.path();
+    tar::Archive::new(File::open(src).context(format!("failed to read '{}'", src.display()))?)
+        .unpack(layout)
+        .context(format!("failed to unpack '{}'", src.display()))?;
+
+    let index_path = layout.join("index.json");
+    let mut index = read_json(&index_path)?;
+    let descriptor = index
+        .pointer_mut("/manifests/0")
+        .context(format!("'{}' has no image manifest", src.display()))?;
+    let manifest_path = blob_path(layout, descriptor)?;
+    let mut manifest = read_json(&manifest_path)?;
+    let layers = manifest
+        .get_mut("layers")
+        .and_then(Value::as_array_mut)
+        .context(format!("the manifest of '{}' has no layers", src.display()))?;
+    for layer in layers {
+        if layer["mediaType"] != UNCOMPRESSED_LAYER_MEDIA_TYPE {
+            continue;
+        }
+        let uncompressed = blob_path(layout, layer)?;
+        let compressed = uncompressed.with_extension("compressed");
+        compression.compress(&uncompressed, &compressed)?;
+        let (digest, size) = store_blob(layout, &compressed)?;
+        debug!("Compressed layer '{}' to {digest}", uncompressed.display());
+        std::fs::remove_file(&uncompressed)
+            .context(format!("failed to remove '{}'", uncompressed.display()))?;
+        layer["mediaType"] = compression.algorithm.media_type().into();
+        layer["digest"] = digest.into();
+        layer["size"] = size.into();
+    }
+
+    // The image's config is left alone: its diff IDs are the digests of the uncompressed layers.
+    let manifest_bytes = serde_json::to_vec(&manifest).context("failed to serialize manifest")?;
+    std::fs::remove_file(&manifest_path)
+        .context(format!("failed to remove '{}'", manifest_path.display()))?;
+    let staged = layout.join("manifest.json");
+    std::fs::write(&staged, &manifest_bytes)
+        .context(format!("failed to write '{}'", staged.display()))?;
+    let (digest, size) = store_blob(layout, &staged)?;
+    descriptor["digest"] = digest.into();
+    descriptor["size"] = size.into();
+    std::fs::write(
+        &index_path,
+        serde_json::to_vec(&index).context("failed to serialize index")?,
+    )
+    .context(format!("failed to write '{}'", index_path.display()))?;
+
+    write_archive(layout, dst)
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    serde_json::from_slice(
+        &std::fs::read(path).context(format!("failed to read '{}'", path.display()))?,
+    )
+    .context(format!("failed to parse '{}'", path.display()))
+}
+
+/// The path of the blob which `descriptor` refers to within `layout`.
+fn blob_path(layout: &Path, descriptor: &Value) -> Result<PathBuf> {
+    let digest = descriptor["digest"]
+        .as_str()
+        .context("descriptor has no digest")?;
+    let encoded = digest
+        .strip_prefix("sha256:")
+        .context(format!("unsupported digest '{digest}'"))?;
+    Ok(layout.join("blobs/sha256").join(encoded))
+}
+
+/// Moves the file at `path` into the blobs of `layout`, returning its digest and size.
+fn store_blob(layout: &Path, path: &Path) -> Result<(String, u64)> {
+    let encoded = hash_file(path)?;
+    let size = std::fs::metadata(path)
+        .context(format!("failed to read '{}'", path.display()))?
+        .len();
+    let blob = layout.join("blobs/sha256").join(&encoded);
+    std::fs::rename(path, &blob).context(format!("failed to write '{}'", blob.display()))?;
+    Ok((format!("sha256:{encoded}"), size))
+}
+
+/// Writes `layout` as a tarball at `dst`, in a stable order and without timestamps, so that the
+/// same layout always makes the same archive.
+fn write_archive(layout: &Path, dst: &Path) -> Result<()> {
+    let mut blobs = std::fs::read_dir(layout.join("blobs/sha256"))
+        .context("failed to read the blobs of a kit")?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()
+        .context("failed to read the blobs of a kit")?;
+    blobs.sort();
+    let files = [layout.join("oci-layout"), layout.join("index.json")]
+        .into_iter()
+        .chain(blobs);
+
+    let mut builder = tar::Builder::new(
+        File::create(dst).context(format!("failed to create '{}'", dst.display()))?,
+    );
+    for path in files {
+        let name = path.strip_prefix(layout).unwrap_or(&path);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(
+            std::fs::metadata(&path)
+                .context(format!("failed to read '{}'", path.display()))?
+                .len(),
+        );
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        builder
+            .append_data(
+                &mut header,
+                name,
+                File::open(&path).context(format!("failed to read '{}'", path.display()))?,
+            )
+            .context(format!("failed to write '{}'", dst.display()))?;
+    }
+    builder
+        .into_inner()
+        .and_then(|mut file| file.flush())
+        .context(format!("failed to write '{}'", dst.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_compression() {
+        let parse = |s: &str| s.parse::<LayerCompression>().map(|c| c.to_string());
+        assert_eq!(parse("gzip").unwrap(), "gzip:6");
+        assert_eq!(parse("zstd").unwrap(), "zstd:3");
+        assert_eq!(parse("zstd:19").unwrap(), "zstd:19");
+        assert!(parse("gzip:10").is_err());
+        assert!(parse("zstd:0").is_err());
+        assert!(parse("xz:6").is_err());
+        assert!(parse("gzip:fast").is_err());
+    }
+
+    #[test]
+    fn test_for_registry() {
+        let level = |registry: &str| LayerCompression::for_registry(registry).level;
+        assert_eq!(level("localhost:5000"), 1);
+        assert_eq!(level("public.ecr.aws/bottlerocket"), 9);
+        assert_eq!(level("123456789012.dkr.ecr.us-west-2.amazonaws.com"), 6);
+    }
+
+    #[test]
+    fn test_compress_archive() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let layout = dir.path().join("layout");
+        let blobs = layout.join("blobs/sha256");
+        std::fs::create_dir_all(&blobs).unwrap();
+        let write_blob = |contents: &[u8]| {
+            let path = blobs.join("staged");
+            std::fs::write(&path, contents).unwrap();
+            let digest = hash_file(&path).unwrap();
+            std::fs::rename(&path, blobs.join(&digest)).unwrap();
+            (format!("sha256:{digest}"), contents.len())
+        };
+        let mut layer = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        layer
+            .append_data(&mut header, "repodata/repomd.xml", "data".as_bytes())
+            .unwrap();
+        let (layer, layer_size) = write_blob(&layer.into_inner().unwrap());
+        let (config, config_size) = write_blob(b"{}");
+        let manifest = json!({
+            "schemaVersion": 2,
+            "config": { "digest": config, "size": config_size },
+            "layers": [{ "mediaType": UNCOMPRESSED_LAYER_MEDIA_TYPE, "digest": layer, "size": layer_size }],
+        });
+        let (manifest, manifest_size) = write_blob(manifest.to_string().as_bytes());
+        std::fs::write(
+            layout.join("index.json"),
+            json!({ "manifests": [{ "digest": manifest, "size": manifest_size }] }).to_string(),
+        )
+        .unwrap();
+        std::fs::write(layout.join("oci-layout"), "{}").unwrap();
+        let src = dir.path().join("kit.tar");
+        write_archive(&layout, &src).unwrap();
+
+        let compression = "gzip:9".parse().unwrap();
+        let dst = dir.path().join("compressed.tar");
+        compress_archive(&src, &dst, compression).unwrap();
+        let again = dir.path().join("again.tar");
+        compress_archive(&src, &again, compression).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), std::fs::read(&again).unwrap());
+
+        let out = dir.path().join("out");
+        tar::Archive::new(File::open(&dst).unwrap())
+            .unpack(&out)
+            .unwrap();
+        let index = read_json(&out.join("index.json")).unwrap();
+        let manifest = read_json(&blob_path(&out, &index["manifests"][0]).unwrap()).unwrap();
+        let compressed = &manifest["layers"][0];
+        assert_eq!(compressed["mediaType"], Algorithm::Gzip.media_type());
+        assert_ne!(compressed["digest"], layer.as_str());
+        assert_eq!(manifest["config"]["digest"], config.as_str());
+        let blob = blob_path(&out, compressed).unwrap();
+        assert_eq!(
+            format!("sha256:{}", hash_file(&blob).unwrap()),
+            compressed["digest"].as_str().unwrap()
+        );
+        assert!(!out.join("blobs/sha256").join(&layer[7..]).exists());
+    }
+}
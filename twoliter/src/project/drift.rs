@@ -0,0 +1,208 @@
+//! Compares the packages installed in the images of a variant built for several architectures, run
+//! by `twoliter drift`.
+//!
+//! Each image build writes an `application-inventory.json` next to the image, listing the packages
+//! it installed. A package that is missing from one architecture's image, or that was installed at
+//! a different version, usually means that something went wrong in that architecture's build, such
+//! as a package which failed to build there and was silently left out.
+use super::{Project, ProjectLock};
+use crate::common::fs::read;
+use crate::output::{Output, OutputSchema, DRIFT_SCHEMA};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+/// The inventory written by `rpm2img` for each image.
+#[derive(Debug, Deserialize)]
+struct Inventory {
+    #[serde(rename = "Content")]
+    content: Vec<InventoryEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InventoryEntry {
+    name: String,
+    version: String,
+    release: String,
+    epoch: String,
+}
+
+impl InventoryEntry {
+    /// The package's `epoch:version-release`.
+    fn evr(&self) -> String {
+        format!("{}:{}-{}", self.epoch, self.version, self.release)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct DriftReport {
+    pub(crate) variant: String,
+    pub(crate) arches: Vec<String>,
+    pub(crate) divergences: Vec<Divergence>,
+}
+
+/// A package which is not installed at the same version for every architecture.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Divergence {
+    pub(crate) package: String,
+    /// The version installed for each architecture, or `None` where it is missing.
+    pub(crate) versions: BTreeMap<String, Option<String>>,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let versions = self
+            .versions
+            .iter()
+            .map(|(arch, version)| format!("{arch}: {}", version.as_deref().unwrap_or("missing")))
+            .collect::<Vec<_>>();
+        write!(f, "{} ({})", self.package, versions.join(", "))
+    }
+}
+
+impl Display for DriftReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.divergences.is_empty() {
+            return write!(
+                f,
+                "No drift between the '{}' images for {}",
+                self.variant,
+                self.arches.join(", ")
+            );
+        }
+        write!(
+            f,
+            "{} package(s) differ between the '{}' images for {}:",
+            self.divergences.len(),
+            self.variant,
+            self.arches.join(", ")
+        )?;
+        for divergence in &self.divergences {
+            write!(f, "\n  {divergence}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Output for DriftReport {
+    const SCHEMA: OutputSchema = DRIFT_SCHEMA;
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// The inventory written by the latest build of `variant` for `arch`.
+    fn inventory_path(&self, variant: &str, arch: &str) -> PathBuf {
+        self.project_dir
+            .join("build/images")
+            .join(format!("{arch}-{variant}"))
+            .join("latest/application-inventory.json")
+    }
+
+    /// The packages installed by the latest build of `variant` for `arch`, with their
+    /// `epoch:version-release`.
+    pub(super) async fn installed_packages(
+        &self,
+        variant: &str,
+        arch: &str,
+    ) -> Result<BTreeMap<String, String>> {
+        let path = self.inventory_path(variant, arch);
+        let bytes = read(&path).await.context(format!(
+            "no inventory for '{variant}' on '{arch}', has it been built?"
+        ))?;
+        let inventory: Inventory = serde_json::from_slice(&bytes)
+            .context(format!("failed to parse inventory '{}'", path.display()))?;
+        Ok(inventory
+            .content
+            .iter()
+            .map(|entry| (entry.name.clone(), entry.evr()))
+            .collect())
+    }
+
+    /// Compares the latest builds of `variant` for each of `arches`. Packages named in `allowed`
+    /// are expected to differ and are left out of the report.
+    pub(crate) async fn drift_report(
+        &self,
+        variant: &str,
+        arches: &[String],
+        allowed: &BTreeSet<String>,
+    ) -> Result<DriftReport> {
+        let mut inventories = BTreeMap::new();
+        for arch in arches {
+            let packages = self.installed_packages(variant, arch).await?;
+            inventories.insert(arch.clone(), packages);
+        }
+        Ok(DriftReport {
+            variant: variant.to_string(),
+            arches: arches.to_vec(),
+            divergences: divergences(&inventories, allowed),
+        })
+    }
+}
+
+/// Finds the packages whose versions, keyed by architecture, are not all the same.
+fn divergences(
+    inventories: &BTreeMap<String, BTreeMap<String, String>>,
+    allowed: &BTreeSet<String>,
+) -> Vec<Divergence> {
+    let packages = inventories
+        .values()
+        .flat_map(|packages| packages.keys())
+        .filter(|package| !allowed.contains(*package))
+        .collect::<BTreeSet<_>>();
+    packages
+        .into_iter()
+        .filter_map(|package| {
+            let versions = inventories
+                .iter()
+                .map(|(arch, packages)| (arch.clone(), packages.get(package).cloned()))
+                .collect::<BTreeMap<_, _>>();
+            let distinct = versions.values().collect::<BTreeSet<_>>();
+            (distinct.len() > 1).then(|| Divergence {
+                package: package.clone(),
+                versions,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_divergences() {
+        let inventory = |packages: &[(&str, &str)]| {
+            packages
+                .iter()
+                .map(|(name, evr)| (name.to_string(), evr.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let inventories = BTreeMap::from([
+            (
+                "aarch64".to_string(),
+                inventory(&[("kernel", "0:6.1-1"), ("shim", "0:15-1")]),
+            ),
+            (
+                "x86_64".to_string(),
+                inventory(&[
+                    ("kernel", "0:6.1-1"),
+                    ("shim", "0:15-2"),
+                    ("microcode", "0:1-1"),
+                ]),
+            ),
+        ]);
+
+        let found = divergences(&inventories, &BTreeSet::new());
+        assert_eq!(
+            found.iter().map(|d| d.package.as_str()).collect::<Vec<_>>(),
+            ["microcode", "shim"]
+        );
+        assert_eq!(found[0].versions["aarch64"], None);
+        assert_eq!(found[1].versions["x86_64"].as_deref(), Some("0:15-2"));
+
+        let allowed = BTreeSet::from(["microcode".to_string()]);
+        assert_eq!(divergences(&inventories, &allowed).len(), 1);
+    }
+}
@@ -1,4 +1,5 @@
 //! Contains abstractions representing image artifacts referred by a Project.
+use super::signature::SignaturePolicy;
 use super::ArtifactVendor;
 use crate::docker::ImageUri;
 use anyhow::{ensure, Result};
@@ -7,6 +8,7 @@ use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt::{Debug, Display, Formatter};
 use std::hash::Hash;
+use std::path::Path;
 use std::str::FromStr;
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -50,6 +52,28 @@ impl ProjectImage {
         self.vendor.vendor_name()
     }
 
+    /// The digest the image is pinned to, if any.
+    pub(crate) fn digest(&self) -> Option<&str> {
+        self.image.digest()
+    }
+
+    /// The same image, fetched by `digest` instead of by its version's tag.
+    pub(crate) fn pinned(&self, digest: &str) -> ProjectImage {
+        let mut pinned = self.clone();
+        pinned.image.digest = Some(digest.to_string());
+        pinned
+    }
+
+    /// How the image must be signed to be locked, if its vendor signs its images.
+    pub(crate) fn signature_policy(&self) -> Option<&SignaturePolicy> {
+        self.vendor.signature_policy()
+    }
+
+    /// The directory of local builds which `Twoliter.override` replaces the image with, if any.
+    pub(crate) fn local_path(&self) -> Option<&Path> {
+        self.vendor.local_path()
+    }
+
     /// Returns the URI for the original vendor.
     pub(crate) fn original_source_uri(&self) -> ImageUri {
         match &self.vendor {
@@ -61,6 +85,26 @@ impl ProjectImage {
         }
     }
 
+    /// Returns the image as served by each of its vendor's mirrors, in the order they are tried.
+    pub(crate) fn mirrors(&self) -> Vec<ProjectImage> {
+        self.vendor
+            .mirrors()
+            .into_iter()
+            .map(|vendor| ProjectImage {
+                image: self.image.clone(),
+                vendor,
+            })
+            .collect()
+    }
+
+    /// Returns every place the image can be fetched from, in the order they are tried: its
+    /// vendor's mirrors, and then the vendor's registry as a last resort.
+    pub(crate) fn sources(&self) -> Vec<ProjectImage> {
+        let mut sources = self.mirrors();
+        sources.push(self.clone());
+        sources
+    }
+
     /// Returns the image URI that the project will use for this image
     ///
     /// This could be different than the source_uri if overridden.
@@ -68,7 +112,10 @@ impl ProjectImage {
         ImageUri {
             registry: Some(self.vendor.registry().to_string()),
             repo: self.vendor.repo_for(&self.image).to_string(),
-            tag: format!("v{}", self.image.version()),
+            tag: self
+                .image
+                .digest()
+                .map_or_else(|| format!("v{}", self.image.version()), str::to_string),
         }
     }
 }
@@ -80,6 +127,11 @@ pub(crate) trait VendedArtifact: std::fmt::Debug {
     fn artifact_name(&self) -> &ValidIdentifier;
     fn vendor_name(&self) -> &ValidIdentifier;
     fn version(&self) -> &Version;
+
+    /// The digest the artifact is pinned to, if it is referenced by digest rather than by tag.
+    fn digest(&self) -> Option<&str> {
+        None
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -153,6 +205,15 @@ fn is_valid_id_char(c: char) -> bool {
 #[serde(rename_all = "kebab-case")]
 pub(crate) struct Vendor {
     pub registry: String,
+    /// Registries which serve the same images as `registry`, tried in order before it, for
+    /// networks which cannot reach the vendor's registry. The registry is only used when none of
+    /// them can be reached, and Twoliter.lock records it as the source of the vendor's images
+    /// either way.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<String>,
+    /// How the vendor's images must be signed to be locked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<SignaturePolicy>,
 }
 
 /// This represents a dependency on a container, primarily used for kits
@@ -162,6 +223,9 @@ pub(crate) struct Image {
     pub name: ValidIdentifier,
     pub version: Version,
     pub vendor: ValidIdentifier,
+    /// The digest the image is pinned to, which it is fetched by instead of its version's tag.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
 }
 
 impl Image {
@@ -170,6 +234,7 @@ impl Image {
             name: artifact.artifact_name().clone(),
             vendor: artifact.vendor_name().clone(),
             version: artifact.version().clone(),
+            digest: artifact.digest().map(str::to_string),
         }
     }
 }
@@ -192,4 +257,8 @@ impl VendedArtifact for Image {
     fn version(&self) -> &Version {
         &self.version
     }
+
+    fn digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
 }
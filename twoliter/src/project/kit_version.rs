@@ -0,0 +1,292 @@
+//! The version of a `[[kit]]` in `Twoliter.toml` is either exact, or a semver requirement which
+//! is resolved like a Cargo dependency:
+//!
+//! ```toml
+//! [[kit]]
+//! name = "bottlerocket-core-kit"
+//! version = "^2.1"
+//! vendor = "bottlerocket"
+//! ```
+//!
+//! A requirement is pinned to the version that Twoliter.lock has for the kit while that still
+//! matches it, so that builds use the locked kit. `twoliter update` pins it afresh to the highest
+//! release of the kit which matches, chosen among the `v<version>` tags of its repository, and
+//! locks that. A version such as `"2.1.0"` is exact, not a requirement, as it always has been.
+//!
+//! A kit with an exact version can also be pinned to the digest of its manifest list, for
+//! consumers which do not trust tags not to move:
+//!
+//! ```toml
+//! [[kit]]
+//! name = "bottlerocket-core-kit"
+//! version = "2.1.0"
+//! vendor = "bottlerocket"
+//! digest = "sha256:..."
+//! ```
+//!
+//! The kit is then fetched by its digest rather than by its version's tag, which is never looked
+//! up, and Twoliter.lock records the digest in the kit's source.
+use super::lock::Lock;
+use super::{Image, Project, Unlocked, UpdateScope, ValidIdentifier};
+use anyhow::{ensure, Context, Result};
+use oci_cli_wrapper::ImageTool;
+use semver::{Version, VersionReq};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use tracing::{debug, info};
+
+/// A kit the project depends on, as declared in `Twoliter.toml`.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct KitDependency {
+    pub(crate) name: ValidIdentifier,
+    pub(crate) version: KitVersion,
+    pub(crate) vendor: ValidIdentifier,
+    /// The digest of the kit's manifest list, which the kit is fetched by instead of its tag.
+    #[serde(default)]
+    pub(crate) digest: Option<String>,
+}
+
+/// The version of a kit dependency, either exact or a requirement to pin.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum KitVersion {
+    Exact(Version),
+    Requirement(VersionReq),
+}
+
+impl KitDependency {
+    /// The kit at `version`.
+    fn pinned(&self, version: Version) -> Image {
+        Image {
+            name: self.name.clone(),
+            version,
+            vendor: self.vendor.clone(),
+            digest: self.digest.clone(),
+        }
+    }
+
+    /// Checks that a digest is a sha256 digest, given with an exact version.
+    fn check_digest(&self) -> Result<()> {
+        let Some(digest) = &self.digest else {
+            return Ok(());
+        };
+        ensure!(
+            matches!(self.version, KitVersion::Exact(_)),
+            "kit '{}' is pinned to a digest, so its version must be exact rather than '{}'",
+            self.name,
+            self.version
+        );
+        ensure!(
+            digest
+                .strip_prefix("sha256:")
+                .is_some_and(|hex| hex.len() == 64
+                    && hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))),
+            "the digest of kit '{}' must be a sha256 digest such as 'sha256:<64 hex digits>', \
+            not '{digest}'",
+            self.name
+        );
+        Ok(())
+    }
+}
+
+impl Project<Unlocked> {
+    /// Pins the kits whose versions are requirements: to the version Twoliter.lock has for them
+    /// where it still matches, or else to the highest matching release in their registry. The
+    /// kits within `upgrade` are always pinned to the highest matching release.
+    pub(super) async fn pin_kits(&mut self, upgrade: Option<&UpdateScope>) -> Result<()> {
+        let needs_lock = self
+            .kit_dependencies
+            .iter()
+            .any(|kit| matches!(kit.version, KitVersion::Requirement(_)));
+        let lock = if needs_lock && self.lock_file_path().exists() {
+            Lock::current_lock_state(self)
+                .await
+                .map_err(|e| debug!("Unable to read Twoliter.lock to pin kits: {e:?}"))
+                .ok()
+        } else {
+            None
+        };
+
+        let image_tool = ImageTool::from_env();
+        let mut kits = Vec::with_capacity(self.kit_dependencies.len());
+        for kit in &self.kit_dependencies {
+            kit.check_digest()?;
+            let requirement = match &kit.version {
+                KitVersion::Exact(version) => {
+                    kits.push(kit.pinned(version.clone()));
+                    continue;
+                }
+                KitVersion::Requirement(requirement) => requirement,
+            };
+            let upgrade = upgrade.is_some_and(|scope| {
+                scope.is_everything() || scope.kits.iter().any(|name| name == kit.name.as_ref())
+            });
+            let locked = lock
+                .as_ref()
+                .filter(|_| !upgrade)
+                .and_then(|lock| {
+                    lock.kit.iter().find(|locked| {
+                        locked.name == kit.name
+                            && locked.vendor == kit.vendor
+                            && requirement.matches(&locked.version)
+                    })
+                })
+                .map(|locked| locked.version.clone());
+            let version = match locked {
+                Some(version) => {
+                    debug!(
+                        "Pinned kit '{}' {requirement} to locked {version}",
+                        kit.name
+                    );
+                    version
+                }
+                None => {
+                    let version = self.highest_release(&image_tool, kit, requirement).await?;
+                    info!("Pinned kit '{}' {requirement} to {version}", kit.name);
+                    version
+                }
+            };
+            kits.push(kit.pinned(version));
+        }
+        self.kit = kits;
+        Ok(())
+    }
+
+    /// Finds the highest release of `kit` in its registry which matches `requirement`.
+    async fn highest_release(
+        &self,
+        image_tool: &ImageTool,
+        kit: &KitDependency,
+        requirement: &VersionReq,
+    ) -> Result<Version> {
+        let uri = self
+            .as_project_image(&kit.pinned(Version::new(0, 0, 0)))?
+            .project_image_uri();
+        let repository = match &uri.registry {
+            Some(registry) => format!("{registry}/{}", uri.repo),
+            None => uri.repo.clone(),
+        };
+        let tags = image_tool
+            .list_tags(&repository)
+            .await
+            .context(format!("failed to list the releases of kit '{}'", kit.name))?;
+        highest_matching(&tags, requirement).context(format!(
+            "no release of kit '{}' in '{repository}' matches '{requirement}'",
+            kit.name
+        ))
+    }
+}
+
+/// The highest of the versions tagged `v<version>` in `tags` which matches `requirement`.
+fn highest_matching(tags: &[String], requirement: &VersionReq) -> Option<Version> {
+    tags.iter()
+        .filter_map(|tag| Version::parse(tag.strip_prefix('v')?).ok())
+        .filter(|version| requirement.matches(version))
+        .max()
+}
+
+impl Display for KitVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KitVersion::Exact(version) => write!(f, "{version}"),
+            KitVersion::Requirement(requirement) => write!(f, "{requirement}"),
+        }
+    }
+}
+
+impl Ord for KitVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.to_string().cmp(&other.to_string())
+    }
+}
+
+impl PartialOrd for KitVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'de> Deserialize<'de> for KitVersion {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let input = String::deserialize(deserializer)?;
+        if let Ok(version) = Version::parse(&input) {
+            return Ok(KitVersion::Exact(version));
+        }
+        VersionReq::parse(&input)
+            .map(KitVersion::Requirement)
+            .map_err(|_| {
+                D::Error::custom(format!(
+                    "'{input}' is neither a version such as '2.1.0' nor a version requirement \
+                    such as '^2.1'"
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kit_version() {
+        let version = |version: &str| {
+            toml::from_str::<KitDependency>(&format!(
+                "name = \"core-kit\"\nversion = \"{version}\"\nvendor = \"bottlerocket\""
+            ))
+            .map(|kit| kit.version)
+        };
+        assert_eq!(
+            version("2.1.0").unwrap(),
+            KitVersion::Exact(Version::new(2, 1, 0))
+        );
+        assert_eq!(
+            version("^2.1").unwrap(),
+            KitVersion::Requirement(VersionReq::parse("^2.1").unwrap())
+        );
+        assert!(version("latest").is_err());
+    }
+
+    #[test]
+    fn test_check_digest() {
+        let kit = |version: &str, digest: &str| {
+            toml::from_str::<KitDependency>(&format!(
+                "name = \"core-kit\"\nversion = \"{version}\"\nvendor = \"bottlerocket\"\n\
+                digest = \"{digest}\""
+            ))
+            .unwrap()
+        };
+        let digest = format!("sha256:{}", "0123456789abcdef".repeat(4));
+        kit("2.1.0", &digest).check_digest().unwrap();
+        assert_eq!(
+            kit("2.1.0", &digest).pinned(Version::new(2, 1, 0)).digest,
+            Some(digest.clone())
+        );
+        assert!(kit("^2.1", &digest).check_digest().is_err());
+        assert!(kit("2.1.0", "sha256:0123").check_digest().is_err());
+        assert!(kit("2.1.0", &digest.to_uppercase()).check_digest().is_err());
+    }
+
+    #[test]
+    fn test_highest_matching() {
+        let tags = [
+            "v2.0.0",
+            "v2.1.3",
+            "v2.10.0",
+            "v3.0.0",
+            "latest",
+            "v2.11.0-rc1",
+        ]
+        .map(String::from);
+        let highest =
+            |requirement| highest_matching(&tags, &VersionReq::parse(requirement).unwrap());
+        assert_eq!(highest("^2.1"), Some(Version::new(2, 10, 0)));
+        assert_eq!(highest("~2.1"), Some(Version::new(2, 1, 3)));
+        assert_eq!(highest(">=3"), Some(Version::new(3, 0, 0)));
+        assert_eq!(highest("^4"), None);
+    }
+}
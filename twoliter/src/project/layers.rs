@@ -0,0 +1,396 @@
+//! Reports how the packages of the project's kits are split into layers, run by
+//! `twoliter kit layers`.
+//!
+//! A kit is published with one layer for each directory under its `Packages`, holding the RPMs
+//! built from one package. Registries store and clients pull each distinct layer once, so an RPM
+//! which is built into several kits costs its size in each of them, and a large layer is uploaded
+//! and pulled again whenever any RPM in it changes. The report covers the kits built by the
+//! project and the external kits it fetched, and ends with advice on restructuring them.
+use super::budget::files;
+use super::lock::{hash_file, parse_rpm_name};
+use super::{Project, ProjectLock};
+use crate::output::{Output, OutputSchema, KIT_LAYERS_SCHEMA};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// The share of a kit above which a layer of several RPMs is worth splitting, in percent.
+const LARGE_LAYER_PERCENT: u64 = 50;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct LayerReport {
+    pub(crate) arch: String,
+    pub(crate) kits: Vec<KitLayers>,
+    /// RPMs with the same contents in more than one kit.
+    pub(crate) duplicates: Vec<Duplicate>,
+    pub(crate) advice: Vec<String>,
+}
+
+/// The layers of one kit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct KitLayers {
+    /// The kit's name, prefixed with its vendor for external kits.
+    pub(crate) kit: String,
+    pub(crate) layers: Vec<Layer>,
+}
+
+impl KitLayers {
+    fn size(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.size).sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Layer {
+    /// The layer's directory within the kit, e.g. `Packages/kernel-6.1`.
+    pub(crate) name: String,
+    pub(crate) size: u64,
+    pub(crate) rpms: Vec<Rpm>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Rpm {
+    pub(crate) file: String,
+    pub(crate) size: u64,
+    pub(crate) sha256: String,
+}
+
+/// An RPM which is built into more than one kit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Duplicate {
+    pub(crate) file: String,
+    pub(crate) size: u64,
+    pub(crate) kits: Vec<String>,
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// Reports the layers of every kit built or fetched for `arch`.
+    pub(crate) fn layer_report(&self, arch: &str) -> Result<LayerReport> {
+        let mut kits = Vec::new();
+        for (kit, dir) in kit_dirs(&self.project_dir.join("build/kits"), 1) {
+            kits.push(kit_layers(kit, &dir.join(arch))?);
+        }
+        for (kit, dir) in kit_dirs(&self.external_kits_dir(), 2) {
+            kits.push(kit_layers(kit, &dir.join(arch))?);
+        }
+        kits.retain(|kit| !kit.layers.is_empty());
+        let duplicates = duplicates(&kits);
+        let advice = advice(&kits, &duplicates);
+        Ok(LayerReport {
+            arch: arch.to_string(),
+            kits,
+            duplicates,
+            advice,
+        })
+    }
+}
+
+/// The directories `depth` levels below `dir`, named by their path relative to it. Kits built by
+/// the project are one level down, and external kits are two, below their vendor.
+pub(super) fn kit_dirs(dir: &Path, depth: usize) -> Vec<(String, PathBuf)> {
+    let mut dirs = vec![(String::new(), dir.to_path_buf())];
+    for _ in 0..depth {
+        let mut next = Vec::new();
+        for (name, dir) in dirs {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                if entry.path().is_dir() {
+                    let child = entry.file_name().to_string_lossy().to_string();
+                    let name = if name.is_empty() {
+                        child
+                    } else {
+                        format!("{name}/{child}")
+                    };
+                    next.push((name, entry.path()));
+                }
+            }
+        }
+        dirs = next;
+    }
+    dirs.sort();
+    dirs
+}
+
+/// The layers of the kit built for one architecture in `dir`.
+fn kit_layers(kit: String, dir: &Path) -> Result<KitLayers> {
+    let packages_dir = dir.join("Packages");
+    let mut layers = BTreeMap::<String, Layer>::new();
+    if packages_dir.is_dir() {
+        for (path, size) in files(&packages_dir)? {
+            let Ok(relative) = path.strip_prefix(&packages_dir) else {
+                continue;
+            };
+            let Some(package) = relative.components().next() else {
+                continue;
+            };
+            let name = format!("Packages/{}", package.as_os_str().to_string_lossy());
+            let layer = layers.entry(name.clone()).or_insert_with(|| Layer {
+                name,
+                size: 0,
+                rpms: Vec::new(),
+            });
+            layer.size += size;
+            let file = relative.file_name().unwrap_or_default().to_string_lossy();
+            if file.ends_with(".rpm") {
+                layer.rpms.push(Rpm {
+                    file: file.to_string(),
+                    size,
+                    sha256: hash_file(&path)?,
+                });
+            }
+        }
+    }
+    let mut layers = layers.into_values().collect::<Vec<_>>();
+    for layer in layers.iter_mut() {
+        layer.rpms.sort_by(|left, right| left.file.cmp(&right.file));
+    }
+    Ok(KitLayers { kit, layers })
+}
+
+/// The RPMs whose contents appear in more than one kit, largest first.
+fn duplicates(kits: &[KitLayers]) -> Vec<Duplicate> {
+    let mut by_hash = BTreeMap::<&str, Duplicate>::new();
+    for kit in kits {
+        for rpm in kit.layers.iter().flat_map(|layer| layer.rpms.iter()) {
+            let duplicate = by_hash.entry(&rpm.sha256).or_insert_with(|| Duplicate {
+                file: rpm.file.clone(),
+                size: rpm.size,
+                kits: Vec::new(),
+            });
+            if !duplicate.kits.contains(&kit.kit) {
+                duplicate.kits.push(kit.kit.clone());
+            }
+        }
+    }
+    let mut duplicates = by_hash
+        .into_values()
+        .filter(|duplicate| duplicate.kits.len() > 1)
+        .collect::<Vec<_>>();
+    duplicates.sort_by(|left, right| {
+        right
+            .size
+            .cmp(&left.size)
+            .then_with(|| left.file.cmp(&right.file))
+    });
+    duplicates
+}
+
+/// Suggestions for restructuring the kits so that registries and clients can share more layers.
+fn advice(kits: &[KitLayers], duplicates: &[Duplicate]) -> Vec<String> {
+    let mut advice = Vec::new();
+    for duplicate in duplicates {
+        advice.push(format!(
+            "'{}' ({} bytes) is built into {}; build it in one kit and have the others depend on \
+            that kit, so that it is stored and pulled once",
+            duplicate.file,
+            duplicate.size,
+            duplicate.kits.join(", ")
+        ));
+    }
+
+    // The same package at different versions can never share a layer.
+    let mut versions = BTreeMap::<String, BTreeMap<String, Vec<&str>>>::new();
+    for kit in kits {
+        for rpm in kit.layers.iter().flat_map(|layer| layer.rpms.iter()) {
+            if let Some((name, version)) = parse_rpm_name(&rpm.file) {
+                let kits = versions
+                    .entry(name)
+                    .or_default()
+                    .entry(version)
+                    .or_default();
+                if !kits.contains(&kit.kit.as_str()) {
+                    kits.push(&kit.kit);
+                }
+            }
+        }
+    }
+    for (name, versions) in versions.iter().filter(|(_, versions)| versions.len() > 1) {
+        let versions = versions
+            .iter()
+            .map(|(version, kits)| format!("{version} in {}", kits.join(", ")))
+            .collect::<Vec<_>>();
+        advice.push(format!(
+            "'{name}' is built at different versions ({}); aligning them lets the kits share it",
+            versions.join("; ")
+        ));
+    }
+
+    for kit in kits {
+        let total = kit.size();
+        for layer in &kit.layers {
+            if layer.rpms.len() > 1 && total > 0 && layer.size * 100 / total > LARGE_LAYER_PERCENT {
+                advice.push(format!(
+                    "'{}' holds {}% of kit '{}' in {} RPMs, and is published again whenever any \
+                    of them changes; consider moving subpackages which change on their own into \
+                    packages of their own",
+                    layer.name,
+                    layer.size * 100 / total,
+                    kit.kit,
+                    layer.rpms.len()
+                ));
+            }
+        }
+    }
+    advice
+}
+
+impl Display for LayerReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.kits.is_empty() {
+            return write!(f, "No kits have been built or fetched for {}", self.arch);
+        }
+        write!(f, "Kit layers for {}:", self.arch)?;
+        for kit in &self.kits {
+            write!(
+                f,
+                "\n{} ({} layers, {} bytes)",
+                kit.kit,
+                kit.layers.len(),
+                kit.size()
+            )?;
+            for layer in &kit.layers {
+                write!(f, "\n  {} ({} bytes)", layer.name, layer.size)?;
+                for rpm in &layer.rpms {
+                    write!(f, "\n    {} ({} bytes)", rpm.file, rpm.size)?;
+                }
+            }
+        }
+        if !self.duplicates.is_empty() {
+            write!(f, "\nDuplicated across kits:")?;
+            for duplicate in &self.duplicates {
+                write!(
+                    f,
+                    "\n  {} ({} bytes): {}",
+                    duplicate.file,
+                    duplicate.size,
+                    duplicate.kits.join(", ")
+                )?;
+            }
+        }
+        if !self.advice.is_empty() {
+            write!(f, "\nAdvice:")?;
+            for advice in &self.advice {
+                write!(f, "\n  - {advice}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Output for LayerReport {
+    const SCHEMA: OutputSchema = KIT_LAYERS_SCHEMA;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_rpm(dir: &Path, kit: &str, package: &str, file: &str, contents: &[u8]) {
+        let dir = dir.join(kit).join("x86_64/Packages").join(package);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(file), contents).unwrap();
+    }
+
+    #[test]
+    fn test_layers() {
+        let dir = TempDir::new().unwrap();
+        let kits = dir.path().join("build/kits");
+        write_rpm(
+            &kits,
+            "my-kit",
+            "glibc",
+            "bottlerocket-glibc-2.38-1.x86_64.rpm",
+            b"glibc",
+        );
+        write_rpm(
+            &kits,
+            "my-kit",
+            "app",
+            "bottlerocket-app-1.0-1.x86_64.rpm",
+            b"app",
+        );
+        write_rpm(
+            &kits,
+            "my-kit",
+            "app",
+            "bottlerocket-app-extra-1.0-1.x86_64.rpm",
+            b"app-extras",
+        );
+        write_rpm(
+            &kits,
+            "other-kit",
+            "glibc",
+            "bottlerocket-glibc-2.38-1.x86_64.rpm",
+            b"glibc",
+        );
+        write_rpm(
+            &kits,
+            "other-kit",
+            "zlib",
+            "bottlerocket-zlib-1.3-1.x86_64.rpm",
+            b"zlib-1.3",
+        );
+        write_rpm(
+            &kits,
+            "third-kit",
+            "zlib",
+            "bottlerocket-zlib-1.2-1.x86_64.rpm",
+            b"zlib-1.2",
+        );
+
+        let kits = kit_dirs(&kits, 1)
+            .into_iter()
+            .map(|(kit, dir)| kit_layers(kit, &dir.join("x86_64")).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kits.iter().map(|kit| kit.kit.as_str()).collect::<Vec<_>>(),
+            ["my-kit", "other-kit", "third-kit"]
+        );
+        let app = &kits[0].layers[0];
+        assert_eq!(app.name, "Packages/app");
+        assert_eq!(app.size, 13);
+        assert_eq!(app.rpms.len(), 2);
+
+        let duplicates = duplicates(&kits);
+        assert_eq!(
+            duplicates,
+            [Duplicate {
+                file: "bottlerocket-glibc-2.38-1.x86_64.rpm".to_string(),
+                size: 5,
+                kits: vec!["my-kit".to_string(), "other-kit".to_string()],
+            }]
+        );
+
+        let advice = advice(&kits, &duplicates);
+        assert_eq!(advice.len(), 3, "{advice:?}");
+        assert!(advice[0].starts_with("'bottlerocket-glibc-2.38-1.x86_64.rpm' (5 bytes)"));
+        assert!(advice[1].starts_with("'zlib' is built at different versions"));
+        assert!(advice[2].starts_with("'Packages/app' holds 72% of kit 'my-kit' in 2 RPMs"));
+    }
+
+    #[test]
+    fn test_external_kit_dirs() {
+        let dir = TempDir::new().unwrap();
+        write_rpm(
+            &dir.path().join("bottlerocket"),
+            "core-kit",
+            "glibc",
+            "bottlerocket-glibc-2.38-1.x86_64.rpm",
+            b"glibc",
+        );
+        let kits = kit_dirs(dir.path(), 2);
+        assert_eq!(kits.len(), 1);
+        assert_eq!(kits[0].0, "bottlerocket/core-kit");
+        assert!(kit_dirs(&dir.path().join("missing"), 2).is_empty());
+    }
+}
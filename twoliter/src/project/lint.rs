@@ -0,0 +1,412 @@
+//! Checks over `Twoliter.toml` and the project's kits and variants for likely mistakes, run by
+//! `twoliter lint`.
+//!
+//! Each lint has a default level, which can be changed in the `lint` section of `Twoliter.toml`,
+//! or for a single run on the command line. For example:
+//!
+//! ```toml
+//! [lint]
+//! unused-vendor = "deny"
+//! unreferenced-kit = "warn"
+//! ```
+//!
+//! Projects which must build from exactly what they reviewed can deny `mutable-reference`, which
+//! rejects kits referenced by version requirements. `twoliter lint --fix` rewrites those to the
+//! versions they currently resolve to.
+use super::kit_version::KitVersion;
+use super::release::set_kit_dependency;
+use super::{Project, ProjectLock};
+use crate::output::{Output, OutputSchema, LINT_SCHEMA};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use strum::{EnumIter, IntoEnumIterator};
+use toml::Table;
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Serialize,
+    Deserialize,
+    EnumIter,
+    ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Lint {
+    /// A vendor that neither the SDK nor any kit comes from.
+    UnusedVendor,
+    /// Several vendors that point at the same registry.
+    DuplicateVendorRegistry,
+    /// The same kit required at different versions, which can never resolve.
+    ConflictingKitVersions,
+    /// A kit in the project that no variant depends on, directly or through another kit.
+    UnreferencedKit,
+    /// An entry in Twoliter.lock that nothing in Twoliter.toml leads to any more.
+    OrphanedLockEntry,
+    /// A kit referenced by a version requirement, which can resolve to a different release on
+    /// every update, rather than by an exact version or digest.
+    MutableReference,
+}
+
+impl Lint {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedVendor => "unused-vendor",
+            Lint::DuplicateVendorRegistry => "duplicate-vendor-registry",
+            Lint::ConflictingKitVersions => "conflicting-kit-versions",
+            Lint::UnreferencedKit => "unreferenced-kit",
+            Lint::OrphanedLockEntry => "orphaned-lock-entry",
+            Lint::MutableReference => "mutable-reference",
+        }
+    }
+
+    fn default_level(&self) -> LintLevel {
+        match self {
+            Lint::UnusedVendor | Lint::DuplicateVendorRegistry | Lint::OrphanedLockEntry => {
+                LintLevel::Warn
+            }
+            Lint::ConflictingKitVersions => LintLevel::Deny,
+            // Projects often build kits only to publish them, so this is opt-in.
+            Lint::UnreferencedKit => LintLevel::Allow,
+            // Requirements are a supported way to follow a kit's releases, so this is opt-in.
+            Lint::MutableReference => LintLevel::Allow,
+        }
+    }
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum LintLevel {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl Display for LintLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LintLevel::Allow => write!(f, "allow"),
+            LintLevel::Warn => write!(f, "warning"),
+            LintLevel::Deny => write!(f, "error"),
+        }
+    }
+}
+
+/// The `lint` section of `Twoliter.toml`, which sets the level of individual lints.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct LintConfig {
+    levels: BTreeMap<Lint, LintLevel>,
+}
+
+impl LintConfig {
+    /// Overrides the level of `lint`, e.g. from the command line.
+    pub(crate) fn set(&mut self, lint: Lint, level: LintLevel) {
+        self.levels.insert(lint, level);
+    }
+
+    pub(crate) fn level(&self, lint: Lint) -> LintLevel {
+        self.levels
+            .get(&lint)
+            .copied()
+            .unwrap_or_else(|| lint.default_level())
+    }
+}
+
+/// A problem found by a lint.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+pub(crate) struct Finding {
+    pub(crate) level: LintLevel,
+    pub(crate) lint: Lint,
+    pub(crate) message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}[{}]: {}", self.level, self.lint, self.message)
+    }
+}
+
+/// The findings of a `twoliter lint` run, most severe first.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize)]
+pub(crate) struct LintReport {
+    pub(crate) findings: Vec<Finding>,
+}
+
+impl LintReport {
+    /// The number of findings at the deny level.
+    pub(crate) fn errors(&self) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.level == LintLevel::Deny)
+            .count()
+    }
+}
+
+impl Display for LintReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let lines = self.findings.iter().map(ToString::to_string);
+        write!(f, "{}", lines.collect::<Vec<_>>().join("\n"))
+    }
+}
+
+impl Output for LintReport {
+    const SCHEMA: OutputSchema = LINT_SCHEMA;
+}
+
+/// Runs every lint that is not allowed by `config`, which starts from the project's own settings.
+pub(crate) async fn lint<L: ProjectLock>(
+    project: &Project<L>,
+    config: &LintConfig,
+) -> Result<LintReport> {
+    let mut findings = Vec::new();
+    for lint in Lint::iter() {
+        let level = config.level(lint);
+        if level == LintLevel::Allow {
+            continue;
+        }
+        let messages = match lint {
+            Lint::UnusedVendor => unused_vendors(project),
+            Lint::DuplicateVendorRegistry => duplicate_vendor_registries(project),
+            Lint::ConflictingKitVersions => conflicting_kit_versions(project),
+            Lint::UnreferencedKit => unreferenced_kits(&project.project_dir).await?,
+            Lint::OrphanedLockEntry => orphaned_lock_entries(project).await?,
+            Lint::MutableReference => mutable_references(project)
+                .into_iter()
+                .map(|(kit, requirement, version)| {
+                    format!(
+                        "kit '{kit}' is referenced by the requirement '{requirement}', which can \
+                        resolve to a different release on every update; pin it to '{version}', \
+                        which `twoliter lint --fix` does"
+                    )
+                })
+                .collect(),
+        };
+        findings.extend(messages.into_iter().map(|message| Finding {
+            level,
+            lint,
+            message,
+        }));
+    }
+    findings.sort();
+    findings.reverse();
+    Ok(LintReport { findings })
+}
+
+fn unused_vendors<L: ProjectLock>(project: &Project<L>) -> Vec<String> {
+    let used = project
+        .sdk
+        .iter()
+        .chain(project.kit.iter())
+        .map(|image| &image.vendor)
+        .collect::<BTreeSet<_>>();
+    project
+        .vendor
+        .keys()
+        .filter(|vendor| !used.contains(vendor))
+        .map(|vendor| format!("vendor '{vendor}' is not used by the sdk or any kit"))
+        .collect()
+}
+
+fn duplicate_vendor_registries<L: ProjectLock>(project: &Project<L>) -> Vec<String> {
+    let mut by_registry = BTreeMap::<_, Vec<_>>::new();
+    for (name, vendor) in &project.vendor {
+        by_registry
+            .entry(vendor.registry.trim_end_matches('/'))
+            .or_default()
+            .push(name.to_string());
+    }
+    by_registry
+        .into_iter()
+        .filter(|(_, vendors)| vendors.len() > 1)
+        .map(|(registry, vendors)| {
+            format!(
+                "vendors {} all point at registry '{registry}'",
+                vendors.join(", ")
+            )
+        })
+        .collect()
+}
+
+fn conflicting_kit_versions<L: ProjectLock>(project: &Project<L>) -> Vec<String> {
+    let mut versions = BTreeMap::<_, BTreeSet<_>>::new();
+    for kit in &project.kit {
+        versions
+            .entry((&kit.name, &kit.vendor))
+            .or_default()
+            .insert(kit.version.to_string());
+    }
+    versions
+        .into_iter()
+        .filter(|(_, versions)| versions.len() > 1)
+        .map(|((name, vendor), versions)| {
+            format!(
+                "kit '{name}' from vendor '{vendor}' is required at versions {}, which can never \
+                all be satisfied",
+                versions.into_iter().collect::<Vec<_>>().join(", ")
+            )
+        })
+        .collect()
+}
+
+async fn orphaned_lock_entries<L: ProjectLock>(project: &Project<L>) -> Result<Vec<String>> {
+    Ok(project
+        .orphaned_lock_entries()
+        .await?
+        .into_iter()
+        .map(|image| {
+            format!(
+                "Twoliter.lock entry {image} no longer corresponds to Twoliter.toml; run \
+                `twoliter update` to prune it"
+            )
+        })
+        .collect())
+}
+
+/// The kits referenced by version requirements, with the requirement and the version it resolves
+/// to now.
+fn mutable_references<L: ProjectLock>(
+    project: &Project<L>,
+) -> Vec<(String, String, semver::Version)> {
+    // The pinned kits are in the order of the kit dependencies they were pinned from.
+    project
+        .kit_dependencies
+        .iter()
+        .zip(&project.kit)
+        .filter_map(|(dependency, pinned)| match &dependency.version {
+            KitVersion::Requirement(requirement) => Some((
+                dependency.name.to_string(),
+                requirement.to_string(),
+                pinned.version.clone(),
+            )),
+            KitVersion::Exact(_) => None,
+        })
+        .collect()
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// Rewrites the kits in Twoliter.toml which are referenced by version requirements to the
+    /// versions they resolve to now, returning a description of each change.
+    pub(crate) async fn fix_mutable_references(&self) -> Result<Vec<String>> {
+        let mut fixes = Vec::new();
+        for (kit, requirement, version) in mutable_references(self) {
+            set_kit_dependency(&self.filepath, &kit, &version).await?;
+            fixes.push(format!(
+                "pinned kit '{kit}' from '{requirement}' to '{version}'"
+            ));
+        }
+        Ok(fixes)
+    }
+}
+
+/// Finds the project's kits which no variant depends on. Projects without variants are skipped,
+/// since their kits can only be meant for publishing.
+async fn unreferenced_kits(project_dir: &Path) -> Result<Vec<String>> {
+    let kits = read_build_dependencies(&project_dir.join("kits")).await?;
+    let variants = read_build_dependencies(&project_dir.join("variants")).await?;
+    if variants.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let referenced = reachable_dependencies(variants.values().flatten(), &kits);
+    Ok(kits
+        .keys()
+        .filter(|kit| !referenced.contains(kit))
+        .map(|kit| format!("kit '{kit}' is not used by any variant"))
+        .collect())
+}
+
+/// Finds everything reached from `roots` by following the build dependencies of the project's
+/// kits, as read by [`read_build_dependencies`].
+pub(super) fn reachable_dependencies<'a>(
+    roots: impl IntoIterator<Item = &'a String>,
+    kits: &'a BTreeMap<String, Vec<String>>,
+) -> BTreeSet<&'a String> {
+    let mut reached = BTreeSet::new();
+    let mut pending = roots.into_iter().collect::<Vec<_>>();
+    while let Some(dependency) = pending.pop() {
+        if reached.insert(dependency) {
+            pending.extend(kits.get(dependency).into_iter().flatten());
+        }
+    }
+    reached
+}
+
+/// Reads the build dependencies of each Cargo package found directly within `dir`, keyed by the
+/// package's name.
+pub(super) async fn read_build_dependencies(dir: &Path) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut packages = BTreeMap::new();
+    if !dir.is_dir() {
+        return Ok(packages);
+    }
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .context(format!("failed to read directory '{}'", dir.display()))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context(format!("failed to read directory '{}'", dir.display()))?
+    {
+        let manifest_path = entry.path().join("Cargo.toml");
+        if !manifest_path.is_file() {
+            continue;
+        }
+        let manifest: Table =
+            toml::from_str(&crate::common::fs::read_to_string(&manifest_path).await?)
+                .context(format!("failed to parse '{}'", manifest_path.display()))?;
+        let Some(name) = manifest
+            .get("package")
+            .and_then(|package| package.get("name"))
+            .and_then(|name| name.as_str())
+        else {
+            continue;
+        };
+        let dependencies = manifest
+            .get("build-dependencies")
+            .and_then(|deps| deps.as_table())
+            .map(|deps| deps.keys().cloned().collect())
+            .unwrap_or_default();
+        packages.insert(name.to_string(), dependencies);
+    }
+    Ok(packages)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::projects_dir;
+
+    #[tokio::test]
+    async fn test_unreferenced_kits() {
+        let project_dir = projects_dir().join("local-kit");
+        // Every kit in the local-kit project is reached from the hello-ootb variant.
+        assert!(unreferenced_kits(&project_dir).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_lint_config_levels() {
+        let mut config: LintConfig =
+            toml::from_str("unused-vendor = \"deny\"\nunreferenced-kit = \"warn\"").unwrap();
+        assert_eq!(config.level(Lint::UnusedVendor), LintLevel::Deny);
+        assert_eq!(config.level(Lint::UnreferencedKit), LintLevel::Warn);
+        assert_eq!(config.level(Lint::ConflictingKitVersions), LintLevel::Deny);
+        assert_eq!(config.level(Lint::MutableReference), LintLevel::Allow);
+        config.set(Lint::UnusedVendor, LintLevel::Allow);
+        assert_eq!(config.level(Lint::UnusedVendor), LintLevel::Allow);
+    }
+}
@@ -1,11 +1,177 @@
-use super::views::{IndexView, ManifestLayoutView};
-use crate::common::fs::{create_dir_all, read, read_to_string, remove_dir_all, write};
-use anyhow::{Context, Result};
+use super::integrity::{hash_files, hash_tree, verify_archive, CacheKey};
+use super::inventory::Inventory;
+use super::layer::{read_layer, LayerDigestMismatch};
+use super::path_check::PathCheck;
+use super::transfer::Transfer;
+use super::views::{ImageConfigView, IndexView, ManifestLayoutView};
+use crate::api::TransferStage;
+use crate::common::fs::{create_dir_all, read, read_to_string, remove_dir_all, rename, write};
+use crate::project::cache::{dir_size, CacheMiss, CacheStatus, EXTRACTED_DIGEST_FILE, STALE_FILE};
+use crate::project::shared_cache::SharedCache;
+use crate::project::store::SystemStore;
+use crate::warnings;
+use anyhow::{bail, ensure, Context, Result};
 use oci_cli_wrapper::ImageTool;
-use std::fs::File;
-use std::path::{Path, PathBuf};
-use tar::Archive as TarArchive;
-use tracing::{debug, instrument, trace};
+use std::fmt::{Display, Formatter};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tar::{Archive as TarArchive, Entry as TarEntry, EntryType};
+use tracing::{debug, info, instrument, trace};
+
+/// How often the size of a pull in progress is measured.
+const PULL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The file in an extracted kit directory which records the [`Attributes`] it was extracted with.
+pub(super) const ATTRIBUTES_FILE: &str = ".attributes";
+
+/// How the ownership, permissions and extended attributes that a kit's layers record are applied
+/// to the files extracted from them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Attributes {
+    /// Files belong to whoever extracts them, directories and executables get mode `0755` and
+    /// other files `0644`, and extended attributes such as file capabilities are dropped, so that
+    /// extractions are identical whether or not they run as root and whatever the filesystem.
+    #[default]
+    Normalize,
+    /// Files keep the ownership, permissions and extended attributes the layers record. Keeping
+    /// ownership requires running as root.
+    Preserve,
+    /// As with [`Attributes::Normalize`], but files belong to the given user and group, which
+    /// requires running as root unless they are the user's own.
+    Map { uid: u32, gid: u32 },
+}
+
+impl Attributes {
+    /// Sets `archive` up to unpack what this keeps of each entry.
+    fn configure<R: Read>(&self, archive: &mut TarArchive<R>) {
+        let preserve = *self == Attributes::Preserve;
+        archive.set_preserve_permissions(preserve);
+        archive.set_preserve_ownerships(preserve);
+        archive.set_unpack_xattrs(preserve);
+        archive.set_preserve_mtime(true);
+    }
+
+    /// Unpacks `entry` into `dir` and applies this to the file it wrote. Returns whether the entry
+    /// was unpacked, which it is not if its path would leave `dir`.
+    fn unpack<R: Read>(&self, entry: &mut TarEntry<R>, dir: &Path) -> Result<bool> {
+        let entry_path = entry
+            .path()
+            .context("failed to read path in layer of oci image")?
+            .into_owned();
+        let unpacked = entry
+            .unpack_in(dir)
+            .context(if *self == Attributes::Preserve {
+                "failed to unpack layer to disk, keeping the ownership it records"
+            } else {
+                "failed to unpack layer to disk"
+            })?;
+        if !unpacked || *self == Attributes::Preserve {
+            return Ok(unpacked);
+        }
+
+        let path = entry_path
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .fold(dir.to_path_buf(), |path, component| path.join(component));
+        let mode = match entry.header().entry_type() {
+            EntryType::Directory => Some(0o755),
+            EntryType::Regular | EntryType::Continuous => {
+                let mode = entry.header().mode().unwrap_or(0o644);
+                Some(if mode & 0o111 != 0 { 0o755 } else { 0o644 })
+            }
+            _ => None,
+        };
+        // Directories the entry's path needed were created with the umask's permissions.
+        let parents = path
+            .ancestors()
+            .skip(1)
+            .take_while(|parent| parent.starts_with(dir) && *parent != dir)
+            .map(|parent| (parent, Some(0o755)));
+        for (path, mode) in std::iter::once((path.as_path(), mode)).chain(parents) {
+            self.apply(path, mode)?;
+        }
+        Ok(true)
+    }
+
+    /// Gives `path` the permissions `mode`, if any, and the owner that files are mapped to.
+    #[cfg(unix)]
+    fn apply(&self, path: &Path, mode: Option<u32>) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        if let Some(mode) = mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .context(format!("failed to set permissions of '{}'", path.display()))?;
+        }
+        if let Attributes::Map { uid, gid } = self {
+            std::os::unix::fs::lchown(path, Some(*uid), Some(*gid)).context(format!(
+                "failed to change the owner of '{}' to {uid}:{gid}",
+                path.display()
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Other platforms have no unix permissions to normalize, nor owners to map files to.
+    #[cfg(not(unix))]
+    fn apply(&self, path: &Path, _mode: Option<u32>) -> Result<()> {
+        ensure!(
+            !matches!(self, Attributes::Map { .. }),
+            "cannot change the owner of '{}' on this platform",
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+/// How a kit is extracted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExtractOptions {
+    pub attributes: Attributes,
+}
+
+impl Display for Attributes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Attributes::Normalize => f.write_str("normalize"),
+            Attributes::Preserve => f.write_str("preserve"),
+            Attributes::Map { uid, gid } => write!(f, "map:{uid}:{gid}"),
+        }
+    }
+}
+
+impl FromStr for Attributes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "normalize" => Ok(Attributes::Normalize),
+            "preserve" => Ok(Attributes::Preserve),
+            _ => {
+                let (uid, gid) = s
+                    .strip_prefix("map:")
+                    .and_then(|ids| ids.split_once(':'))
+                    .context(format!(
+                        "'{s}' is not 'normalize', 'preserve' or 'map:<uid>:<gid>'"
+                    ))?;
+                Ok(Attributes::Map {
+                    uid: uid.parse().context(format!("invalid uid '{uid}'"))?,
+                    gid: gid.parse().context(format!("invalid gid '{gid}'"))?,
+                })
+            }
+        }
+    }
+}
+
+/// Reads the [`Attributes`] that the kit in `kit_dir` was extracted with, if it records them.
+async fn extracted_attributes(kit_dir: &Path) -> Option<Attributes> {
+    read_to_string(kit_dir.join(ATTRIBUTES_FILE))
+        .await
+        .ok()?
+        .parse()
+        .ok()
+}
 
 #[derive(Debug)]
 pub(crate) struct OCIArchive {
@@ -13,6 +179,9 @@ pub(crate) struct OCIArchive {
     repository: String,
     digest: String,
     cache_dir: PathBuf,
+    store_dir: Option<PathBuf>,
+    shared_cache: Option<SharedCache>,
+    transfer: Option<Arc<Transfer>>,
 }
 
 impl OCIArchive {
@@ -25,11 +194,47 @@ impl OCIArchive {
             repository: repository.into(),
             digest: digest.into(),
             cache_dir: cache_dir.as_ref().to_path_buf(),
+            store_dir: None,
+            shared_cache: None,
+            transfer: None,
         })
     }
 
+    /// Reuses the archive from the system-wide store when it is there.
+    pub fn with_store(mut self, store: Option<&SystemStore>) -> Self {
+        self.store_dir = store.map(SystemStore::kit_archives_dir);
+        self
+    }
+
+    /// Assembles the archive from the shared blob cache when it holds the whole image, and shares
+    /// the blobs of archives that are pulled.
+    pub fn with_shared_cache(mut self, shared_cache: Option<SharedCache>) -> Self {
+        self.shared_cache = shared_cache;
+        self
+    }
+
+    /// Counts the bytes pulled and extracted in `transfer`, so that they can be reported.
+    pub fn with_transfer(mut self, transfer: Option<Arc<Transfer>>) -> Self {
+        self.transfer = transfer;
+        self
+    }
+
+    /// The path of the archive, which is in the system-wide store if it holds the archive, or else
+    /// in the cache.
     pub fn archive_path(&self) -> PathBuf {
-        self.cache_dir.join(self.digest.replace(':', "-"))
+        self.store_archive_path()
+            .unwrap_or_else(|| self.cache_dir.join(self.archive_name()))
+    }
+
+    fn archive_name(&self) -> String {
+        self.digest.replace(':', "-")
+    }
+
+    fn store_archive_path(&self) -> Option<PathBuf> {
+        self.store_dir
+            .as_ref()
+            .map(|dir| dir.join(self.archive_name()))
+            .filter(|path| path.exists())
     }
 
     pub fn uri(&self) -> String {
@@ -37,22 +242,128 @@ impl OCIArchive {
     }
 
     #[instrument(level = "trace", skip_all, fields(registry = %self.registry, repository = %self.repository, digest = %self.digest))]
-    pub async fn pull_image(&self, image_tool: &ImageTool) -> Result<()> {
+    pub async fn pull_image(
+        &self,
+        image_tool: &ImageTool,
+        key: Option<&CacheKey>,
+    ) -> Result<CacheStatus> {
         let digest_uri = self.uri();
+        if let Some(store_path) = self.store_archive_path() {
+            // The store is read-only, so a damaged entry can only be reported.
+            if key.is_some() && !verify_archive(&store_path, &self.digest).await? {
+                bail!(
+                    "the archive of '{}' in the system store at '{}' failed its integrity check; \
+                    it must be repopulated with `twoliter store populate`",
+                    digest_uri,
+                    store_path.display()
+                );
+            }
+            debug!("Image from '{}' found in the system store.", digest_uri);
+            return Ok(CacheStatus::Hit);
+        }
         debug!("Pulling image '{}'", digest_uri);
         let oci_archive_path = self.archive_path();
-        if !oci_archive_path.exists() {
-            create_dir_all(&oci_archive_path).await?;
-            image_tool
-                .pull_oci_image(oci_archive_path.as_path(), digest_uri.as_str())
-                .await?;
-        } else {
-            debug!(
-                "Image from '{}' already present -- no need to pull.",
+        let mut status = CacheStatus::Miss(CacheMiss::Missing);
+        if oci_archive_path.exists() {
+            if key.is_none() || verify_archive(&oci_archive_path, &self.digest).await? {
+                debug!(
+                    "Image from '{}' already present -- no need to pull.",
+                    digest_uri
+                );
+                return Ok(CacheStatus::Hit);
+            }
+            warnings::warn(format!(
+                "Cached archive of '{}' failed its integrity check, pulling it again",
                 digest_uri
-            );
+            ));
+            remove_dir_all(&oci_archive_path).await?;
+            status = CacheStatus::Miss(CacheMiss::Tampered);
+        }
+        // Pull next to the final location and move the archive into place once it is complete, so
+        // that a pull which is interrupted or cancelled is never mistaken for a cached archive. The
+        // blobs of an interrupted pull are kept, so that pulling again only downloads the rest.
+        let archives_dir = oci_archive_path
+            .parent()
+            .context("kit archive has no parent directory")?;
+        let partial = archives_dir.join(format!(".{}.partial", self.archive_name()));
+        if partial.exists() {
+            let kept = resume_pull(&partial)?;
+            info!("Resuming the pull of '{digest_uri}' with {kept} blob(s) already downloaded");
+        }
+        create_dir_all(&partial).await?;
+        let assembled = match &self.shared_cache {
+            Some(shared_cache) => shared_cache
+                .assemble(&self.digest, &partial)
+                .unwrap_or_else(|e| {
+                    warnings::warn(format!(
+                        "Failed to assemble '{}' from the shared cache: {:#}",
+                        digest_uri, e
+                    ));
+                    false
+                }),
+            None => false,
+        };
+        if assembled {
+            debug!("Assembled image '{}' from the shared cache", digest_uri);
+        } else {
+            match &self.transfer {
+                Some(transfer) => self.watch_pull(image_tool, &partial, transfer).await?,
+                None => image_tool.pull_oci_image(&partial, &digest_uri).await?,
+            }
+            if let Some(shared_cache) = &self.shared_cache {
+                // The archive is complete without the shared cache, which only saves space.
+                if let Err(e) = shared_cache.adopt(&partial) {
+                    warnings::warn(format!(
+                        "Failed to share the blobs of '{}': {:#}",
+                        digest_uri, e
+                    ));
+                }
+            }
+        }
+        rename(&partial, &oci_archive_path).await?;
+        Ok(status)
+    }
+
+    /// Pulls the image into `partial`, counting the bytes written so far in `transfer`.
+    async fn watch_pull(
+        &self,
+        image_tool: &ImageTool,
+        partial: &Path,
+        transfer: &Transfer,
+    ) -> Result<()> {
+        // krane reports no progress of its own, so the image is sized from its manifest.
+        let digest_uri = self.uri();
+        let total = image_tool
+            .get_manifest(&digest_uri)
+            .await
+            .ok()
+            .and_then(|manifest| serde_json::from_slice::<ManifestLayoutView>(&manifest).ok())
+            .map_or(0, |manifest| {
+                manifest
+                    .config
+                    .iter()
+                    .chain(&manifest.layers)
+                    .map(|blob| blob.size)
+                    .sum()
+            });
+        transfer.start(TransferStage::Pull, total);
+        let pull = image_tool.pull_oci_image(partial, &digest_uri);
+        tokio::pin!(pull);
+        let mut ticker = tokio::time::interval(PULL_POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                result = &mut pull => {
+                    result?;
+                    transfer.set(total);
+                    return Ok(());
+                }
+                _ = ticker.tick() => {
+                    if let Ok(size) = dir_size(partial).await {
+                        transfer.set(size);
+                    }
+                }
+            }
         }
-        Ok(())
     }
 
     #[instrument(
@@ -60,67 +371,442 @@ impl OCIArchive {
         skip_all,
         fields(registry = %self.registry, repository = %self.repository, digest = %self.digest, out_dir = %out_dir.as_ref().display()),
     )]
-    pub async fn unpack_layers<P>(&self, out_dir: P) -> Result<()>
+    pub async fn unpack_layers<P>(
+        &self,
+        out_dir: P,
+        options: ExtractOptions,
+        key: Option<&CacheKey>,
+    ) -> Result<CacheStatus>
     where
         P: AsRef<Path>,
     {
+        let ExtractOptions { attributes } = options;
         let path = out_dir.as_ref();
-        let digest_file = path.join("digest");
+        let digest_file = path.join(EXTRACTED_DIGEST_FILE);
         let digest_uri = self.uri();
-        if digest_file.exists() {
+        let mut status = CacheStatus::Miss(CacheMiss::Missing);
+        if let Ok(changed) = read_to_string(path.join(STALE_FILE)).await {
+            info!(
+                "Extracted kit at '{}' was changed after it was extracted, extracting it again",
+                path.display()
+            );
+            status = CacheStatus::Miss(CacheMiss::Modified { path: changed });
+        } else if digest_file.exists() {
             let digest = read_to_string(&digest_file).await.context(format!(
                 "failed to read digest file at {}",
                 digest_file.display()
             ))?;
-            if digest == self.digest {
+            let extracted = extracted_attributes(path).await;
+            if digest == self.digest && extracted == Some(attributes) {
                 trace!(
                     "Found existing digest file for image from '{}' at '{}'",
                     digest_uri,
                     digest_file.display()
                 );
-                return Ok(());
+                let Some(key) = key else {
+                    return Ok(CacheStatus::Hit);
+                };
+                if key.verify_dir(path, &self.digest)? {
+                    return Ok(CacheStatus::Hit);
+                }
+                warnings::warn(format!(
+                    "Extracted kit at '{}' failed its integrity check, extracting it again",
+                    path.display()
+                ));
+                status = CacheStatus::Miss(CacheMiss::Tampered);
+            } else {
+                let cached_attributes =
+                    extracted.map_or("unknown attributes".to_string(), |a| a.to_string());
+                status = CacheStatus::Miss(CacheMiss::Changed {
+                    cached: format!("{digest} ({cached_attributes})"),
+                    wanted: format!("{} ({attributes})", self.digest),
+                });
             }
         }
 
         debug!("Unpacking layers for image from '{}'", digest_uri);
         remove_dir_all(path).await?;
         create_dir_all(path).await?;
-        let index_bytes = read(self.archive_path().join("index.json")).await?;
-        let index: IndexView = serde_json::from_slice(index_bytes.as_slice())
-            .context("failed to deserialize oci image index")?;
-
-        // Read the manifest so we can get the layer digests
-        trace!(from = %digest_uri, "Extracting layer digests from image manifest");
-        let digest = index
-            .manifests
-            .first()
-            .context("empty oci image")?
-            .digest
-            .replace(':', "/");
-        let manifest_bytes = read(self.archive_path().join(format!("blobs/{digest}")))
-            .await
-            .context("failed to read manifest blob")?;
-        let manifest_layout: ManifestLayoutView = serde_json::from_slice(manifest_bytes.as_slice())
-            .context("failed to deserialize oci manifest")?;
 
         // Extract each layer into the target directory
         trace!(from = %digest_uri, "Extracting image layers");
-        for layer in manifest_layout.layers {
-            let digest = layer.digest.to_string().replace(':', "/");
-            let layer_blob = File::open(self.archive_path().join(format!("blobs/{digest}")))
-                .context("failed to read layer of oci image")?;
-            let mut layer_archive = TarArchive::new(layer_blob);
-            layer_archive
-                .unpack(path)
-                .context("failed to unpack layer to disk")?;
+        let layers = self
+            .layer_digests()
+            .await?
+            .into_iter()
+            .map(|layer| {
+                let blob = self.blob_path(&layer);
+                (layer, blob)
+            })
+            .collect::<Vec<_>>();
+        if let Some(transfer) = &self.transfer {
+            let total = layers
+                .iter()
+                .filter_map(|(_, blob)| blob.metadata().ok())
+                .map(|metadata| metadata.len())
+                .sum();
+            transfer.start(TransferStage::Extract, total);
         }
+        let (dir, kit, transfer) = (
+            path.to_path_buf(),
+            digest_uri.clone(),
+            self.transfer.clone(),
+        );
+        let unpacked = tokio::task::spawn_blocking(move || {
+            unpack_entries(&layers, &dir, attributes, &kit, transfer.as_deref())
+        })
+        .await
+        .context("failed to join the task unpacking the kit")?;
+        match unpacked {
+            Err(e) if e.downcast_ref::<LayerDigestMismatch>().is_some() => {
+                // As with a kit that fails validation, a damaged archive in the cache is pulled
+                // again on the next run.
+                if self.store_archive_path().is_none() {
+                    remove_dir_all(self.archive_path()).await?;
+                }
+                return Err(e.context(format!(
+                    "the archive of kit '{}' is damaged; run `twoliter fetch` to pull it again",
+                    digest_uri
+                )));
+            }
+            unpacked => unpacked?,
+        }
+        if let Some(inventory) = self.inventory().await? {
+            if let Err(e) = inventory.check(&hash_tree(path)?) {
+                // A damaged archive in the cache is pulled again on the next run. The store is
+                // read-only, so an archive there can only be reported.
+                if self.store_archive_path().is_none() {
+                    remove_dir_all(self.archive_path()).await?;
+                }
+                return Err(e.context(format!(
+                    "kit '{}' extracted to '{}' failed validation",
+                    digest_uri,
+                    path.display()
+                )));
+            }
+        } else {
+            debug!("Image from '{}' has no inventory to validate", digest_uri);
+        }
+        let attributes_file = path.join(ATTRIBUTES_FILE);
+        write(&attributes_file, attributes.to_string())
+            .await
+            .context(format!(
+                "failed to record attributes to {}",
+                attributes_file.display()
+            ))?;
         write(&digest_file, self.digest.as_str())
             .await
             .context(format!(
                 "failed to record digest to {}",
                 digest_file.display()
             ))?;
+        if let Some(key) = key {
+            key.seal_dir(path, &self.digest)?;
+        }
 
-        Ok(())
+        Ok(status)
+    }
+
+    /// Reads the digests of the image's layers from its manifest, in the order they apply.
+    async fn layer_digests(&self) -> Result<Vec<String>> {
+        // Read the manifest so we can get the layer digests
+        trace!(from = %self.uri(), "Extracting layer digests from image manifest");
+        Ok(self
+            .manifest()
+            .await?
+            .layers
+            .into_iter()
+            .map(|layer| layer.digest.to_string())
+            .collect())
+    }
+
+    /// Reads the inventory of the kit's files from the image config, if the kit has one.
+    async fn inventory(&self) -> Result<Option<Inventory>> {
+        let Some(config) = self.manifest().await?.config else {
+            return Ok(None);
+        };
+        let config_bytes = read(self.blob_path(&config.digest.to_string()))
+            .await
+            .context("failed to read config blob")?;
+        let config: ImageConfigView = serde_json::from_slice(config_bytes.as_slice())
+            .context("failed to deserialize oci image config")?;
+        Inventory::from_labels(config.config.labels.iter().flatten())
+    }
+
+    async fn manifest(&self) -> Result<ManifestLayoutView> {
+        let index_bytes = read(self.archive_path().join("index.json")).await?;
+        let index: IndexView = serde_json::from_slice(index_bytes.as_slice())
+            .context("failed to deserialize oci image index")?;
+        let digest = &index.manifests.first().context("empty oci image")?.digest;
+        let manifest_bytes = read(self.blob_path(digest))
+            .await
+            .context("failed to read manifest blob")?;
+        serde_json::from_slice(manifest_bytes.as_slice())
+            .context("failed to deserialize oci manifest")
+    }
+
+    fn blob_path(&self, digest: &str) -> PathBuf {
+        blob_path(&self.archive_path(), digest)
+    }
+}
+
+pub(super) fn blob_path(archive_path: &Path, digest: &str) -> PathBuf {
+    archive_path.join(format!("blobs/{}", digest.replace(':', "/")))
+}
+
+/// Prepares the layout of an interrupted pull at `partial` to be pulled into again, which only
+/// downloads the blobs it lacks. Blobs which were cut off or do not match their digests are
+/// removed, as is the index, which is only written once every blob is. Returns how many blobs are
+/// kept.
+fn resume_pull(partial: &Path) -> Result<usize> {
+    let index = partial.join("index.json");
+    if index.exists() {
+        std::fs::remove_file(&index).context(format!("failed to remove '{}'", index.display()))?;
+    }
+    let blobs_dir = partial.join("blobs/sha256");
+    let Ok(entries) = std::fs::read_dir(&blobs_dir) else {
+        return Ok(0);
+    };
+    let mut blobs = Vec::new();
+    for entry in entries {
+        let path = entry
+            .context(format!("failed to read '{}'", blobs_dir.display()))?
+            .path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let is_digest = name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit());
+        if is_digest && path.is_file() {
+            blobs.push((name.to_string(), path));
+        } else if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+                .context(format!("failed to remove '{}'", path.display()))?;
+        } else {
+            std::fs::remove_file(&path)
+                .context(format!("failed to remove '{}'", path.display()))?;
+        }
+    }
+    let paths = blobs
+        .iter()
+        .map(|(_, path)| path.as_path())
+        .collect::<Vec<_>>();
+    let mut kept = 0;
+    for ((encoded, path), hash) in blobs.iter().zip(hash_files(&paths)?) {
+        if *encoded == hash {
+            kept += 1;
+        } else {
+            debug!(
+                "Removing '{}', which was not fully downloaded",
+                path.display()
+            );
+            std::fs::remove_file(path).context(format!("failed to remove '{}'", path.display()))?;
+        }
+    }
+    Ok(kept)
+}
+
+/// Unpacks `layers`, as their digests and blobs in the order they apply, into `dir`, which must
+/// exist. The layers are read synchronously, so this runs on a blocking thread and kits are
+/// unpacked in parallel. Each layer's size is added to `transfer` once it is unpacked.
+fn unpack_entries(
+    layers: &[(String, PathBuf)],
+    dir: &Path,
+    attributes: Attributes,
+    kit: &str,
+    transfer: Option<&Transfer>,
+) -> Result<()> {
+    let mut path_check = PathCheck::new(dir)?;
+    for (layer, blob) in layers {
+        read_layer(blob, layer, |layer_archive| {
+            attributes.configure(layer_archive);
+            for entry in layer_archive
+                .entries()
+                .context("failed to read layer of oci image")?
+            {
+                let mut entry = entry.context("failed to read layer of oci image")?;
+                let entry_path = entry
+                    .path()
+                    .context("failed to read path in layer of oci image")?
+                    .into_owned();
+                if path_check.check(&entry_path) {
+                    attributes.unpack(&mut entry, dir)?;
+                }
+            }
+            Ok(())
+        })?;
+        if let (Some(transfer), Ok(metadata)) = (transfer, blob.metadata()) {
+            transfer.add(metadata.len());
+        }
+    }
+    path_check.finish(kit)
+}
+
+/// Unpacks the layers of the image in the OCI layout at `layout`, such as one pulled with
+/// [`ImageTool::pull_oci_image`], into `out_dir`. Layers may be plain or gzipped tarballs.
+pub(crate) async fn unpack_layout(layout: &Path, out_dir: &Path) -> Result<()> {
+    let index: IndexView = serde_json::from_slice(&read(layout.join("index.json")).await?)
+        .context("failed to deserialize oci image index")?;
+    let digest = &index.manifests.first().context("empty oci image")?.digest;
+    let manifest: ManifestLayoutView = serde_json::from_slice(
+        &read(blob_path(layout, digest))
+            .await
+            .context("failed to read manifest blob")?,
+    )
+    .context("failed to deserialize oci manifest")?;
+
+    create_dir_all(out_dir).await?;
+    for layer in manifest.layers {
+        let digest = layer.digest.to_string();
+        read_layer(&blob_path(layout, &digest), &digest, |layer_archive| {
+            layer_archive
+                .unpack(out_dir)
+                .context("failed to unpack layer to disk")
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Builds a layer holding a setuid executable and a group-writable file, owned by another user.
+    fn layer() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, mode) in [("bin/tool", 0o4775), ("share/data", 0o664)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(4);
+            header.set_mode(mode);
+            header.set_uid(4242);
+            header.set_gid(4242);
+            header.set_mtime(1_700_000_000);
+            builder
+                .append_data(&mut header, path, "data".as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn unpack(attributes: Attributes, dir: &Path) {
+        let layer = layer();
+        let mut archive = TarArchive::new(layer.as_slice());
+        attributes.configure(&mut archive);
+        for entry in archive.entries().unwrap() {
+            assert!(attributes.unpack(&mut entry.unwrap(), dir).unwrap());
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_normalized_attributes() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let owner = std::fs::metadata(dir.path()).unwrap();
+        for attributes in [
+            Attributes::Normalize,
+            Attributes::Map {
+                uid: owner.uid(),
+                gid: owner.gid(),
+            },
+        ] {
+            let kit_dir = dir.path().join(attributes.to_string());
+            unpack(attributes, &kit_dir);
+            let metadata = |path: &str| std::fs::metadata(kit_dir.join(path)).unwrap();
+            assert_eq!(metadata("bin/tool").mode() & 0o7777, 0o755);
+            assert_eq!(metadata("share/data").mode() & 0o7777, 0o644);
+            assert_eq!(metadata("bin").mode() & 0o7777, 0o755);
+            // Files belong to whoever extracts them rather than the owner the layer records, but
+            // keep the times it records.
+            assert_eq!(metadata("bin/tool").uid(), owner.uid());
+            assert_eq!(metadata("bin/tool").mtime(), 1_700_000_000);
+        }
+    }
+
+    #[test]
+    fn test_unpack_entries() {
+        use flate2::write::GzEncoder;
+        use sha2::{Digest, Sha256};
+        use std::io::Write;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let tar = |files: &[(&str, &str)]| {
+            let mut builder = tar::Builder::new(Vec::new());
+            for (path, contents) in files {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                builder
+                    .append_data(&mut header, path, contents.as_bytes())
+                    .unwrap();
+            }
+            builder.into_inner().unwrap()
+        };
+        // The first layer is gzipped and the second is not, and the second replaces a file of the
+        // first, so the layers must be applied in order.
+        let mut gzipped = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzipped
+            .write_all(&tar(&[("repodata/repomd.xml", "old")]))
+            .unwrap();
+        let layers = [
+            gzipped.finish().unwrap(),
+            tar(&[("repodata/repomd.xml", "new"), ("Packages/a.rpm", "rpm")]),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, blob)| {
+            let path = dir.path().join(format!("layer-{i}"));
+            std::fs::write(&path, &blob).unwrap();
+            (format!("sha256:{:x}", Sha256::digest(&blob)), path)
+        })
+        .collect::<Vec<_>>();
+
+        let kit_dir = dir.path().join("kit");
+        std::fs::create_dir(&kit_dir).unwrap();
+        unpack_entries(&layers, &kit_dir, Attributes::Normalize, "core-kit", None).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(kit_dir.join("repodata/repomd.xml")).unwrap(),
+            "new"
+        );
+        assert_eq!(
+            std::fs::read_to_string(kit_dir.join("Packages/a.rpm")).unwrap(),
+            "rpm"
+        );
+    }
+
+    #[test]
+    fn test_attributes_round_trip() {
+        for attributes in [
+            Attributes::Normalize,
+            Attributes::Preserve,
+            Attributes::Map { uid: 0, gid: 1000 },
+        ] {
+            assert_eq!(
+                attributes.to_string().parse::<Attributes>().unwrap(),
+                attributes
+            );
+        }
+        assert!("map:1000".parse::<Attributes>().is_err());
+        assert!("keep".parse::<Attributes>().is_err());
+    }
+
+    #[test]
+    fn test_resume_pull() {
+        use sha2::{Digest, Sha256};
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let blobs = dir.path().join("blobs/sha256");
+        std::fs::create_dir_all(&blobs).unwrap();
+        let complete = format!("{:x}", Sha256::digest("layer"));
+        let cut_off = format!("{:x}", Sha256::digest("another layer"));
+        std::fs::write(blobs.join(&complete), "layer").unwrap();
+        std::fs::write(blobs.join(&cut_off), "another").unwrap();
+        std::fs::write(blobs.join(format!("{cut_off}123456")), "another").unwrap();
+        std::fs::write(dir.path().join("index.json"), "{}").unwrap();
+
+        assert_eq!(resume_pull(dir.path()).unwrap(), 1);
+        assert!(blobs.join(&complete).is_file());
+        assert_eq!(std::fs::read_dir(&blobs).unwrap().count(), 1);
+        assert!(!dir.path().join("index.json").exists());
+        assert_eq!(resume_pull(&dir.path().join("missing")).unwrap(), 0);
     }
 }
@@ -0,0 +1,289 @@
+//! Combines per-architecture kit images into the kit's manifest list, run by `twoliter kit
+//! assemble`. This lets CI build each architecture of a kit natively on its own machine, push the
+//! images separately, and then publish them together as the kit.
+//!
+//! Each image must be a single-architecture image, such as one of the `<version>-<build id>-<arch>`
+//! images which `twoliter publish kit` pushes, and no two may be for the same architecture. Every
+//! image must carry the same kit metadata, so that builds of different kits or versions are never
+//! combined. The manifest list references the images by the digests which were checked, so an
+//! image retagged in the meantime is not picked up. `twoliter build kit --multi-arch` checks the
+//! kit archives it collects from its native builders in the same way.
+use super::image::{EncodedKitMetadata, ImageMetadata};
+use super::local::{archive_labels, split_archive_name, LocalKit};
+use super::views::ManifestKindView;
+use crate::output::{Output, OutputSchema, KIT_ASSEMBLE_SCHEMA};
+use anyhow::{bail, ensure, Context, Result};
+use oci_cli_wrapper::{ConfigView, DockerArchitecture, ImageTool};
+use semver::Version;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct AssembleReport {
+    pub(crate) kit: String,
+    pub(crate) version: Version,
+    /// Where the manifest list is pushed.
+    pub(crate) target: String,
+    /// The digest of the pushed manifest list, absent for a dry run.
+    pub(crate) digest: Option<String>,
+    pub(crate) images: Vec<AssembledImage>,
+}
+
+/// A per-architecture image in the manifest list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct AssembledImage {
+    pub(crate) uri: String,
+    pub(crate) architecture: String,
+    pub(crate) digest: String,
+}
+
+/// Checks the per-architecture kit images at `uris` and, unless this is a `dry_run`, pushes a
+/// manifest list of them to `target`.
+pub(crate) async fn assemble_kit(
+    uris: &[String],
+    target: &str,
+    dry_run: bool,
+) -> Result<AssembleReport> {
+    ensure!(!uris.is_empty(), "no kit images to assemble");
+    let image_tool = ImageTool::registry();
+    let mut images = Vec::with_capacity(uris.len());
+    for uri in uris {
+        images.push(read_image(&image_tool, uri).await?);
+    }
+    check_images(&images)?;
+    let (_, metadata) = images.first().context("no kit images to assemble")?;
+    let metadata: ImageMetadata = metadata
+        .clone()
+        .try_into()
+        .context("Failed to decode and parse kit metadata")?;
+
+    let mut report = AssembleReport {
+        kit: metadata.name,
+        version: metadata.version,
+        target: target.to_string(),
+        digest: None,
+        images: images.into_iter().map(|(image, _)| image).collect(),
+    };
+    if dry_run {
+        return Ok(report);
+    }
+
+    let platform_images = report
+        .images
+        .iter()
+        .map(|image| {
+            Ok((
+                DockerArchitecture::try_from(image.architecture.as_str())?,
+                format!("{}@{}", repository(&image.uri), image.digest),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    info!(
+        "Pushing the manifest list of kit '{}' to {target}",
+        report.kit
+    );
+    image_tool
+        .push_multi_platform_manifest(platform_images, target)
+        .await
+        .context(format!("failed to push the manifest list to '{target}'"))?;
+    report.digest = Some(image_tool.get_digest(target).await?);
+    Ok(report)
+}
+
+/// Checks that the latest local builds of the kit in `dir`, one for each of `arches`, are builds
+/// of the same kit from the same commit, so that `twoliter publish kit` publishes them together
+/// as one manifest list. Returns the archives in the order of `arches`.
+pub(crate) async fn check_kit_archives(dir: &Path, arches: &[&str]) -> Result<Vec<PathBuf>> {
+    let archives = LocalKit::new(dir).archives()?;
+    let mut images = Vec::with_capacity(arches.len());
+    let mut selected = Vec::with_capacity(arches.len());
+    for arch in arches {
+        let archive = archives.get(*arch).context(format!(
+            "found no build of the kit for architecture '{arch}' in '{}'",
+            dir.display()
+        ))?;
+        let config = ConfigView {
+            labels: archive_labels(archive).await?,
+            architecture: None,
+        };
+        let metadata = EncodedKitMetadata::from_config(&config).context(format!(
+            "failed to read the kit metadata of '{}'",
+            archive.display()
+        ))?;
+        images.push((
+            AssembledImage {
+                uri: archive.display().to_string(),
+                architecture: DockerArchitecture::try_from(*arch)?.to_string(),
+                digest: String::new(),
+            },
+            metadata,
+        ));
+        selected.push(archive.clone());
+    }
+    check_images(&images)?;
+
+    let mut builds = selected
+        .iter()
+        .filter_map(|archive| split_archive_name(archive))
+        .map(|(build, _)| build);
+    if let Some(first) = builds.next() {
+        if let Some(other) = builds.find(|build| *build != first) {
+            bail!(
+                "the kit archives are from different builds, '{first}' and '{other}'; build every \
+                architecture from the same commit"
+            );
+        }
+    }
+    Ok(selected)
+}
+
+/// Reads the architecture, digest and kit metadata of the single-architecture image at `uri`.
+async fn read_image(
+    image_tool: &ImageTool,
+    uri: &str,
+) -> Result<(AssembledImage, EncodedKitMetadata)> {
+    let manifest = image_tool.get_manifest(uri).await?;
+    let kind: ManifestKindView = serde_json::from_slice(&manifest)
+        .context(format!("failed to deserialize manifest of '{uri}'"))?;
+    ensure!(
+        kind.is_image_manifest(),
+        "'{uri}' is not a single-architecture image; pass the images built for each \
+        architecture rather than a manifest list"
+    );
+    let config = image_tool.get_config(uri).await?;
+    let architecture = config.architecture.clone().context(format!(
+        "the config of '{uri}' does not name its architecture"
+    ))?;
+    let architecture = DockerArchitecture::try_from(architecture.as_str())?.to_string();
+    let metadata = EncodedKitMetadata::from_config(&config)
+        .context(format!("failed to read the kit metadata of '{uri}'"))?;
+    let digest = image_tool.get_digest(uri).await?;
+    info!("Found the {architecture} image of the kit at {uri}");
+    Ok((
+        AssembledImage {
+            uri: uri.to_string(),
+            architecture,
+            digest,
+        },
+        metadata,
+    ))
+}
+
+/// Checks that the images are each for a different architecture, and are builds of the same kit.
+fn check_images(images: &[(AssembledImage, EncodedKitMetadata)]) -> Result<()> {
+    let Some((first, metadata)) = images.first() else {
+        return Ok(());
+    };
+    for (i, (image, other)) in images.iter().enumerate().skip(1) {
+        ensure!(
+            other == metadata,
+            "the kit metadata of '{}' differs from that of '{}', so they are not builds of the \
+            same kit",
+            image.uri,
+            first.uri
+        );
+        if let Some((duplicate, _)) = images[..i]
+            .iter()
+            .find(|(earlier, _)| earlier.architecture == image.architecture)
+        {
+            bail!(
+                "'{}' and '{}' are both {} images",
+                duplicate.uri,
+                image.uri,
+                image.architecture
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The repository of the image `uri`, without its tag or digest.
+fn repository(uri: &str) -> &str {
+    if let Some((repository, _)) = uri.split_once('@') {
+        return repository;
+    }
+    match uri.rsplit_once(':') {
+        // A colon before the last slash separates a registry's port, not a tag.
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => uri,
+    }
+}
+
+impl Display for AssembleReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.digest {
+            Some(digest) => write!(
+                f,
+                "Assembled kit {}-{} at {} ({digest})",
+                self.kit, self.version, self.target
+            )?,
+            None => write!(
+                f,
+                "Would assemble kit {}-{} at {}",
+                self.kit, self.version, self.target
+            )?,
+        }
+        for image in &self.images {
+            write!(
+                f,
+                "\n  {}: {} ({})",
+                image.architecture, image.uri, image.digest
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Output for AssembleReport {
+    const SCHEMA: OutputSchema = KIT_ASSEMBLE_SCHEMA;
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::image::supported_kit_metadata_label;
+    use super::*;
+    use oci_cli_wrapper::ConfigView;
+    use std::collections::HashMap;
+
+    fn image(
+        uri: &str,
+        architecture: &str,
+        metadata: &str,
+    ) -> (AssembledImage, EncodedKitMetadata) {
+        let config = ConfigView {
+            labels: HashMap::from([(supported_kit_metadata_label(), metadata.to_string())]),
+            architecture: Some(architecture.to_string()),
+        };
+        (
+            AssembledImage {
+                uri: uri.to_string(),
+                architecture: architecture.to_string(),
+                digest: "sha256:0".to_string(),
+            },
+            EncodedKitMetadata::from_config(&config).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_check_images() {
+        let amd64 = image("example.com/kit:v1-abc-x86_64", "amd64", "a");
+        let arm64 = image("example.com/kit:v1-abc-aarch64", "arm64", "a");
+        check_images(&[amd64.clone(), arm64.clone()]).unwrap();
+
+        let other_kit = image("example.com/kit:v2-def-aarch64", "arm64", "b");
+        assert!(check_images(&[amd64.clone(), other_kit]).is_err());
+        assert!(check_images(&[amd64.clone(), arm64, amd64]).is_err());
+    }
+
+    #[test]
+    fn test_repository() {
+        assert_eq!(repository("example.com/kit:v1"), "example.com/kit");
+        assert_eq!(repository("localhost:5000/kit:v1"), "localhost:5000/kit");
+        assert_eq!(repository("localhost:5000/kit"), "localhost:5000/kit");
+        assert_eq!(repository("example.com/kit@sha256:0"), "example.com/kit");
+    }
+}
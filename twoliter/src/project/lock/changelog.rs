@@ -0,0 +1,344 @@
+//! Drafts the changelog of a kit release, run by `twoliter changelog`.
+//!
+//! The packages in the local build of the kit are compared with those in a previously published
+//! version of it, pulled from the kit's vendor. Packages are identified by the names and versions
+//! in their RPM file names. For each package added or updated, the draft quotes the latest entry of
+//! the changelog kept with the package, either a `CHANGELOG.md` in its directory or the `%changelog`
+//! section of its spec, where there is one. The draft is meant to be edited, and can then be
+//! attached to the published kit with `twoliter publish kit --release-notes`.
+use super::image::ImageResolver;
+use super::ExtractOptions;
+use crate::common::fs::read_to_string;
+use crate::project::{Image, Project, ProjectLock, ValidIdentifier};
+use anyhow::{ensure, Context, Result};
+use oci_cli_wrapper::ImageTool;
+use semver::Version;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// The manifest annotation which holds a kit's release notes.
+pub(crate) const RELEASE_NOTES_ANNOTATION: &str = "dev.bottlerocket.kit.release-notes";
+
+/// The prefix given to the names of the packages built by the SDK.
+const PACKAGE_PREFIX: &str = "bottlerocket-";
+
+/// The most lines quoted from a package's changelog.
+const MAX_SNIPPET_LINES: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Changelog {
+    pub(crate) kit: String,
+    pub(crate) previous: Version,
+    pub(crate) version: String,
+    pub(crate) changes: Vec<PackageChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PackageChange {
+    pub(crate) package: String,
+    /// The package's `version-release` in the previous kit, or `None` if it was added.
+    pub(crate) old: Option<String>,
+    /// The package's `version-release` in this kit, or `None` if it was removed.
+    pub(crate) new: Option<String>,
+    /// The latest entry of the package's changelog.
+    pub(crate) snippet: Option<String>,
+}
+
+impl Display for PackageChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (&self.old, &self.new) {
+            (None, Some(new)) => write!(f, "- Added `{}` {new}", self.package)?,
+            (Some(old), None) => write!(f, "- Removed `{}` {old}", self.package)?,
+            (Some(old), Some(new)) => write!(f, "- Updated `{}` {old} to {new}", self.package)?,
+            (None, None) => write!(f, "- `{}`", self.package)?,
+        }
+        if let Some(snippet) = &self.snippet {
+            for line in snippet.lines() {
+                write!(f, "\n  > {line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Changelog {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "# {} {}", self.kit, self.version)?;
+        writeln!(f)?;
+        writeln!(f, "Changes since {} {}:", self.kit, self.previous)?;
+        writeln!(f)?;
+        if self.changes.is_empty() {
+            writeln!(f, "- No package changes")?;
+        }
+        for change in &self.changes {
+            writeln!(f, "{change}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// Compares the local build of `kit` for `arch` with its `previous` version published by
+    /// `vendor`.
+    pub(crate) async fn changelog(
+        &self,
+        kit: &str,
+        vendor: &str,
+        arch: &str,
+        previous: &Version,
+    ) -> Result<Changelog> {
+        let packages_dir = self
+            .project_dir
+            .join("build/kits")
+            .join(kit)
+            .join(arch)
+            .join("Packages");
+        ensure!(
+            packages_dir.is_dir(),
+            "no build of kit '{kit}' for '{arch}' at '{}', build it first",
+            packages_dir.display()
+        );
+        let current = read_packages(&packages_dir)?;
+
+        let image = Image {
+            name: ValidIdentifier(kit.to_string()),
+            version: previous.clone(),
+            vendor: ValidIdentifier(vendor.to_string()),
+            digest: None,
+        };
+        let resolver = ImageResolver::from_image(&self.as_project_image(&image)?)?;
+        let extract_dir = tempfile::tempdir().context("failed to create a temporary directory")?;
+        resolver
+            .extract(
+                &ImageTool::from_env(),
+                extract_dir.path(),
+                arch,
+                ExtractOptions::default(),
+                None,
+                None,
+            )
+            .await?;
+        let previous_packages = read_packages(extract_dir.path())?;
+
+        let snippets = self.package_changelogs().await?;
+        let changes = package_changes(&previous_packages, &current)
+            .into_iter()
+            .map(|mut change| {
+                if change.new.is_some() {
+                    change.snippet = snippets.get(&change.package).cloned();
+                }
+                change
+            })
+            .collect();
+        Ok(Changelog {
+            kit: kit.to_string(),
+            previous: previous.clone(),
+            version: self.release_version.clone(),
+            changes,
+        })
+    }
+
+    /// Reads the latest changelog entry of each package in the project, keyed by package name.
+    async fn package_changelogs(&self) -> Result<BTreeMap<String, String>> {
+        let mut snippets = BTreeMap::new();
+        let packages_dir = self.project_dir.join("packages");
+        let Ok(entries) = std::fs::read_dir(&packages_dir) else {
+            return Ok(snippets);
+        };
+        for entry in entries.flatten() {
+            let dir = entry.path();
+            let Some(spec_path) = spec_file(&dir) else {
+                continue;
+            };
+            let spec = read_to_string(&spec_path).await?;
+            let Some(name) = spec_name(&spec) else {
+                continue;
+            };
+            let changelog_md = dir.join("CHANGELOG.md");
+            let snippet = if changelog_md.is_file() {
+                latest_markdown_entry(&read_to_string(&changelog_md).await?)
+            } else {
+                latest_spec_entry(&spec)
+            };
+            if let Some(snippet) = snippet {
+                snippets.insert(name, snippet);
+            }
+        }
+        Ok(snippets)
+    }
+}
+
+/// Finds the RPMs within `dir`, returning the `version-release` of each package by name.
+fn read_packages(dir: &Path) -> Result<BTreeMap<String, String>> {
+    let mut packages = BTreeMap::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .context(format!("failed to read directory '{}'", dir.display()))?;
+        for entry in entries {
+            let path = entry
+                .context(format!("failed to read directory '{}'", dir.display()))?
+                .path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if let Some((name, version)) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(parse_rpm_name)
+            {
+                packages.insert(name, version);
+            }
+        }
+    }
+    Ok(packages)
+}
+
+/// Splits an RPM file name, `name-version-release.arch.rpm`, into the package's name without the
+/// SDK's prefix and its `version-release`.
+pub(crate) fn parse_rpm_name(file_name: &str) -> Option<(String, String)> {
+    let (nvr, _arch) = file_name.strip_suffix(".rpm")?.rsplit_once('.')?;
+    let (name_version, release) = nvr.rsplit_once('-')?;
+    let (name, version) = name_version.rsplit_once('-')?;
+    let name = name.strip_prefix(PACKAGE_PREFIX).unwrap_or(name);
+    Some((name.to_string(), format!("{version}-{release}")))
+}
+
+/// Lists the packages added, removed or updated between `previous` and `current`.
+fn package_changes(
+    previous: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+) -> Vec<PackageChange> {
+    let mut names = previous.keys().chain(current.keys()).collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|name| previous.get(*name) != current.get(*name))
+        .map(|name| PackageChange {
+            package: name.clone(),
+            old: previous.get(name).cloned(),
+            new: current.get(name).cloned(),
+            snippet: None,
+        })
+        .collect()
+}
+
+/// The spec file of the package in `dir`, if it is a package directory.
+fn spec_file(dir: &Path) -> Option<PathBuf> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "spec"))
+}
+
+/// The name of the package that a spec builds, without the SDK's prefix.
+fn spec_name(spec: &str) -> Option<String> {
+    let name = spec
+        .lines()
+        .find_map(|line| line.strip_prefix("Name:"))?
+        .trim()
+        .replace("%{_cross_os}", "");
+    Some(name)
+}
+
+/// The latest entry of a `CHANGELOG.md`: the first section under a second-level heading, or under
+/// a first-level heading if there are none, without the heading.
+fn latest_markdown_entry(changelog: &str) -> Option<String> {
+    let entry_heading = if changelog.lines().any(|line| line.starts_with("## ")) {
+        "## "
+    } else {
+        "# "
+    };
+    let is_heading = |line: &str| line.starts_with(entry_heading) || line.starts_with("# ");
+    let mut lines = changelog
+        .lines()
+        .skip_while(|line| !line.starts_with(entry_heading));
+    lines.next()?;
+    snippet(lines.take_while(|line| !is_heading(line)))
+}
+
+/// The latest entry of a spec's `%changelog` section.
+fn latest_spec_entry(spec: &str) -> Option<String> {
+    let mut lines = spec
+        .lines()
+        .skip_while(|line| line.trim() != "%changelog")
+        .skip(1)
+        .skip_while(|line| !line.starts_with('*'));
+    let header = lines.next()?;
+    let entry = std::iter::once(header).chain(lines.take_while(|line| !line.starts_with('*')));
+    snippet(entry)
+}
+
+/// Joins the non-blank `lines`, up to the most that are quoted.
+fn snippet<'a>(lines: impl Iterator<Item = &'a str>) -> Option<String> {
+    let lines = lines
+        .map(str::trim_end)
+        .filter(|line| !line.trim().is_empty())
+        .take(MAX_SNIPPET_LINES)
+        .collect::<Vec<_>>();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_rpm_name() {
+        assert_eq!(
+            parse_rpm_name("bottlerocket-pkg-a-0.1-1.x86_64.rpm"),
+            Some(("pkg-a".to_string(), "0.1-1".to_string()))
+        );
+        assert_eq!(
+            parse_rpm_name("bottlerocket-kernel-6.1-devel-6.1.90-1.1718.aarch64.rpm"),
+            Some(("kernel-6.1-devel".to_string(), "6.1.90-1.1718".to_string()))
+        );
+        assert_eq!(parse_rpm_name("repomd.xml"), None);
+    }
+
+    #[test]
+    fn test_package_changes() {
+        let packages = |entries: &[(&str, &str)]| {
+            entries
+                .iter()
+                .map(|(name, version)| (name.to_string(), version.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let previous = packages(&[("pkg-a", "1.0-1"), ("pkg-b", "2.0-1"), ("pkg-c", "1.0-1")]);
+        let current = packages(&[("pkg-a", "1.0-1"), ("pkg-b", "2.1-1"), ("pkg-d", "0.1-1")]);
+        let changes = package_changes(&previous, &current);
+        assert_eq!(
+            changes
+                .iter()
+                .map(|change| change.to_string())
+                .collect::<Vec<_>>(),
+            [
+                "- Updated `pkg-b` 2.0-1 to 2.1-1",
+                "- Removed `pkg-c` 1.0-1",
+                "- Added `pkg-d` 0.1-1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_changelog_entries() {
+        let spec = "Name: %{_cross_os}pkg-a\nVersion: 1.1\n\n%changelog\n\
+            * Tue Jan 2 2024 Someone <a@example.com> - 1.1-1\n- Fix a crash\n\n\
+            * Mon Jan 1 2024 Someone <a@example.com> - 1.0-1\n- Initial release\n";
+        assert_eq!(spec_name(spec).as_deref(), Some("pkg-a"));
+        assert_eq!(
+            latest_spec_entry(spec).as_deref(),
+            Some("* Tue Jan 2 2024 Someone <a@example.com> - 1.1-1\n- Fix a crash")
+        );
+
+        let markdown =
+            "# Changelog\n\n## 1.1\n\n### Fixed\n- A crash\n\n## 1.0\n\n- Initial release\n";
+        assert_eq!(
+            latest_markdown_entry(markdown).as_deref(),
+            Some("### Fixed\n- A crash")
+        );
+        assert_eq!(latest_markdown_entry("No entries yet\n"), None);
+    }
+}
@@ -0,0 +1,157 @@
+//! Publishers can declare which versions of the SDK and of other kits a kit works with, in the
+//! `publish` section of the kit project's `Twoliter.toml`:
+//!
+//! ```toml
+//! [publish.compatibility]
+//! sdk = ">=0.50, <0.60"
+//!
+//! [publish.compatibility.kits]
+//! core-kit = "^2"
+//! ```
+//!
+//! `twoliter publish kit` attaches the matrix to the kit image as JSON, in the
+//! `dev.bottlerocket.kit.compatibility` annotation. Projects which depend on the kit check it while
+//! resolving their dependencies, so that an SDK or kit which the kit does not work with is reported
+//! before anything is built. Kits without the annotation are taken to work with anything.
+use crate::diagnostic::Code;
+use crate::messages::msg;
+use anyhow::{ensure, Context, Result};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// Holds the compatibility matrix of a kit, as JSON.
+pub(crate) const COMPATIBILITY_ANNOTATION: &str = "dev.bottlerocket.kit.compatibility";
+
+/// The versions of the SDK and of other kits which a kit works with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct CompatibilityMatrix {
+    /// The versions of the SDK the kit works with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sdk: Option<Requirement>,
+
+    /// The versions of other kits, by name, which the kit works with.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    kits: BTreeMap<String, Requirement>,
+}
+
+/// A semver requirement such as `>=0.50, <0.60`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+struct Requirement(VersionReq);
+
+impl Ord for Requirement {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.to_string().cmp(&other.0.to_string())
+    }
+}
+
+impl PartialOrd for Requirement {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl CompatibilityMatrix {
+    /// Reads the compatibility matrix of `kit` from its manifest annotations, if it has one.
+    pub(crate) fn from_annotations(
+        kit: &str,
+        annotations: &BTreeMap<String, String>,
+    ) -> Result<Option<Self>> {
+        annotations
+            .get(COMPATIBILITY_ANNOTATION)
+            .map(|matrix| {
+                serde_json::from_str(matrix).context(format!(
+                    "the {COMPATIBILITY_ANNOTATION} annotation of {kit} is not a compatibility \
+                    matrix"
+                ))
+            })
+            .transpose()
+    }
+
+    /// Checks that `kit` works with the version of the SDK, and with the versions of the other
+    /// kits by name, which the project resolves alongside it.
+    pub(crate) fn check(
+        &self,
+        kit: &str,
+        sdk: &Version,
+        kits: &BTreeMap<String, Version>,
+    ) -> Result<()> {
+        let sdk = self
+            .sdk
+            .as_ref()
+            .map(|requirement| ("the sdk", requirement, sdk));
+        let kits = self.kits.iter().filter_map(|(name, requirement)| {
+            kits.get(name)
+                .map(|version| (name.as_str(), requirement, version))
+        });
+        for (dependency, requirement, version) in sdk.into_iter().chain(kits) {
+            ensure!(
+                requirement.0.matches(version),
+                Code::IncompatibleKit.error(msg!(
+                    "error.incompatible-kit",
+                    kit = kit,
+                    dependency = dependency,
+                    requirement = requirement.0,
+                    version = version,
+                ))
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let matrix: CompatibilityMatrix = toml::from_str(
+            r#"
+            sdk = ">=0.50, <0.60"
+            kits = { core-kit = "^2" }
+            "#,
+        )
+        .unwrap();
+        let version = |version| Version::parse(version).unwrap();
+        let kits = BTreeMap::from([("core-kit".to_string(), version("2.3.0"))]);
+        matrix
+            .check("extra-kit-1.0.0", &version("0.52.0"), &kits)
+            .unwrap();
+        matrix
+            .check("extra-kit-1.0.0", &version("0.52.0"), &BTreeMap::new())
+            .unwrap();
+
+        let err = matrix
+            .check("extra-kit-1.0.0", &version("0.60.0"), &kits)
+            .unwrap_err();
+        assert!(err.to_string().contains("the sdk >=0.50, <0.60"), "{err}");
+        let kits = BTreeMap::from([("core-kit".to_string(), version("3.0.0"))]);
+        assert!(matrix
+            .check("extra-kit-1.0.0", &version("0.52.0"), &kits)
+            .is_err());
+    }
+
+    #[test]
+    fn test_from_annotations() {
+        let matrix: CompatibilityMatrix = toml::from_str("sdk = \"^0.50\"").unwrap();
+        let mut annotations = BTreeMap::from([(
+            COMPATIBILITY_ANNOTATION.to_string(),
+            serde_json::to_string(&matrix).unwrap(),
+        )]);
+        assert_eq!(
+            CompatibilityMatrix::from_annotations("extra-kit-1.0.0", &annotations).unwrap(),
+            Some(matrix)
+        );
+
+        annotations.insert(
+            COMPATIBILITY_ANNOTATION.into(),
+            "{\"sdk\": \"latest\"}".into(),
+        );
+        assert!(CompatibilityMatrix::from_annotations("extra-kit-1.0.0", &annotations).is_err());
+        assert!(toml::from_str::<CompatibilityMatrix>("kit = { core-kit = \"^2\" }").is_err());
+    }
+}
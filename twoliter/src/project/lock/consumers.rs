@@ -0,0 +1,205 @@
+//! Finds the kits and projects which depend on a kit, run by `twoliter kit consumers`. This is
+//! worth checking before yanking a kit version or making a breaking change to it.
+//!
+//! Kits are found by reading the metadata embedded in every tag of the given repositories, or of
+//! every repository under a registry namespace for registries which support listing them.
+//! Projects are found through the kits recorded in their `Twoliter.lock`, which include the kits
+//! they depend on indirectly.
+use super::image::read_kit_metadata;
+use super::migrate::parse_lock;
+use super::{Lock, TWOLITER_LOCK};
+use crate::common::fs::read_to_string;
+use crate::output::{Output, OutputSchema, KIT_CONSUMERS_SCHEMA};
+use anyhow::{Context, Result};
+use oci_cli_wrapper::ImageTool;
+use semver::Version;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Where to look for consumers of a kit.
+#[derive(Debug, Default)]
+pub(crate) struct ConsumerSources {
+    /// Registry namespaces to scan, e.g. `public.ecr.aws/bottlerocket`.
+    pub(crate) namespaces: Vec<String>,
+    /// Repositories to scan, e.g. `public.ecr.aws/bottlerocket/bottlerocket-core-kit`.
+    pub(crate) repositories: Vec<String>,
+    /// Project files, or the directories holding them.
+    pub(crate) projects: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct ConsumersReport {
+    pub(crate) kit: String,
+    pub(crate) version: Option<Version>,
+    pub(crate) consumers: Vec<Consumer>,
+}
+
+/// A kit or project which depends on the kit being queried.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Consumer {
+    /// The consuming kit's image, or the consuming project's file.
+    pub(crate) consumer: String,
+    /// The version of the queried kit it depends on.
+    pub(crate) depends_on: Version,
+}
+
+impl Display for ConsumersReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kit = match &self.version {
+            Some(version) => format!("{}-{version}", self.kit),
+            None => self.kit.clone(),
+        };
+        if self.consumers.is_empty() {
+            return write!(f, "Nothing found depends on '{kit}'");
+        }
+        write!(f, "{} consumer(s) of '{kit}':", self.consumers.len())?;
+        for consumer in &self.consumers {
+            write!(
+                f,
+                "\n  {} (depends on {})",
+                consumer.consumer, consumer.depends_on
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Output for ConsumersReport {
+    const SCHEMA: OutputSchema = KIT_CONSUMERS_SCHEMA;
+}
+
+/// Finds the kits and projects in `sources` which depend on `kit`, at `version` if given.
+pub(crate) async fn kit_consumers(
+    kit: &str,
+    version: Option<&Version>,
+    sources: &ConsumerSources,
+) -> Result<ConsumersReport> {
+    let image_tool = ImageTool::registry();
+    let mut repositories = sources.repositories.clone();
+    for namespace in &sources.namespaces {
+        repositories.extend(namespace_repositories(&image_tool, namespace).await?);
+    }
+
+    let mut consumers = Vec::new();
+    for repository in &repositories {
+        info!("Reading kit metadata from '{repository}'");
+        for tag in image_tool.list_tags(repository).await? {
+            let Some(metadata) = read_kit_metadata(&image_tool, repository, &tag).await? else {
+                continue;
+            };
+            consumers.extend(
+                metadata
+                    .kits
+                    .iter()
+                    .filter(|dep| is_match(kit, version, dep.name.as_ref(), &dep.version))
+                    .map(|dep| Consumer {
+                        consumer: format!(
+                            "{repository}:{tag} ({} {})",
+                            metadata.name, metadata.version
+                        ),
+                        depends_on: dep.version.clone(),
+                    }),
+            );
+        }
+    }
+
+    for project in &sources.projects {
+        let (project_file, lock) = read_project_lock(project).await?;
+        consumers.extend(
+            lock.kit
+                .iter()
+                .filter(|locked| is_match(kit, version, locked.name.as_ref(), &locked.version))
+                .map(|locked| Consumer {
+                    consumer: project_file.display().to_string(),
+                    depends_on: locked.version.clone(),
+                }),
+        );
+    }
+
+    Ok(ConsumersReport {
+        kit: kit.to_string(),
+        version: version.cloned(),
+        consumers,
+    })
+}
+
+/// Lists the repositories under `namespace`, which starts with the registry's host.
+async fn namespace_repositories(image_tool: &ImageTool, namespace: &str) -> Result<Vec<String>> {
+    let namespace = namespace.trim_end_matches('/');
+    let (registry, prefix) = namespace.split_once('/').unwrap_or((namespace, ""));
+    let repositories = image_tool
+        .list_repositories(registry)
+        .await
+        .context(format!(
+            "failed to list the repositories in '{registry}'; name them with --repository \
+            if the registry does not support listing them"
+        ))?;
+    Ok(repositories
+        .into_iter()
+        .filter(|repository| in_namespace(repository, prefix))
+        .map(|repository| format!("{registry}/{repository}"))
+        .collect())
+}
+
+/// Whether the dependency `name` at `dependency_version` is on `kit`, at `version` if given.
+fn is_match(
+    kit: &str,
+    version: Option<&Version>,
+    name: &str,
+    dependency_version: &Version,
+) -> bool {
+    name == kit && version.map_or(true, |version| version == dependency_version)
+}
+
+/// Whether `repository` lies within the namespace `prefix`, relative to its registry.
+fn in_namespace(repository: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || repository
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Reads the lock of the project at `path`, which names its project file or directory.
+async fn read_project_lock(path: &Path) -> Result<(PathBuf, Lock)> {
+    let (project_file, project_dir) = if path.is_dir() {
+        (path.join("Twoliter.toml"), path.to_path_buf())
+    } else {
+        let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        (path.to_path_buf(), dir)
+    };
+    let lock_file = project_dir.join(TWOLITER_LOCK);
+    let lock = parse_lock(&read_to_string(&lock_file).await?)
+        .context(format!("failed to parse '{}'", lock_file.display()))?
+        .lock;
+    Ok((project_file, lock))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_in_namespace() {
+        assert!(in_namespace("bottlerocket/core-kit", "bottlerocket"));
+        assert!(in_namespace("bottlerocket/team/core-kit", "bottlerocket"));
+        assert!(!in_namespace("bottlerocket-other/core-kit", "bottlerocket"));
+        assert!(in_namespace("anything", ""));
+    }
+
+    #[test]
+    fn test_is_match() {
+        let version = Version::new(2, 1, 0);
+        assert!(is_match("core-kit", None, "core-kit", &version));
+        assert!(is_match("core-kit", Some(&version), "core-kit", &version));
+        assert!(!is_match(
+            "core-kit",
+            Some(&Version::new(2, 0, 0)),
+            "core-kit",
+            &version
+        ));
+        assert!(!is_match("core-kit", None, "extra-kit", &version));
+    }
+}
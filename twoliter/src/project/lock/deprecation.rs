@@ -0,0 +1,209 @@
+//! Publishers can mark a kit or SDK as deprecated by annotating its manifest list, which is read
+//! while resolving it anyway. `twoliter publish kit` adds the `publish.annotations` section of the
+//! `Twoliter.toml` to the manifest list as well as to each image manifest:
+//!
+//! ```toml
+//! [publish.annotations]
+//! "dev.bottlerocket.kit.deprecated" = "core-kit 1.x is no longer patched"
+//! "dev.bottlerocket.kit.replacement" = "core-kit 2.0.0"
+//! ```
+//!
+//! When a whole vendor is moving, e.g. to a new registry, `dev.bottlerocket.kit.vendor-deprecated`
+//! marks every image that the vendor publishes from then on. Deprecations are reported as warnings
+//! while resolving dependencies, and fail the run with `--deny deprecations`.
+//!
+//! Publishers can also announce ahead of time when a version stops being supported, with
+//! `"dev.bottlerocket.kit.end-of-life" = "2027-06-30"`. Building against it warns within
+//! 90 days of that date, and once it has passed the image is reported as deprecated.
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+/// Marks this version of the image as deprecated. The value explains why.
+pub(crate) const DEPRECATED_ANNOTATION: &str = "dev.bottlerocket.kit.deprecated";
+
+/// Marks the vendor publishing the image as deprecated. The value explains why.
+pub(crate) const VENDOR_DEPRECATED_ANNOTATION: &str = "dev.bottlerocket.kit.vendor-deprecated";
+
+/// Suggests what to depend on instead of a deprecated image or vendor.
+pub(crate) const REPLACEMENT_ANNOTATION: &str = "dev.bottlerocket.kit.replacement";
+
+/// The date, as `YYYY-MM-DD`, after which this version of the image is no longer supported.
+pub(crate) const END_OF_LIFE_ANNOTATION: &str = "dev.bottlerocket.kit.end-of-life";
+
+/// How many days ahead of its end of life building against an image starts to warn.
+const END_OF_LIFE_NOTICE_DAYS: i64 = 90;
+
+/// What a deprecation notice applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DeprecationScope {
+    Version,
+    Vendor,
+}
+
+/// A deprecation notice found on an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Deprecation {
+    pub(crate) image: String,
+    pub(crate) scope: DeprecationScope,
+    pub(crate) reason: String,
+    pub(crate) replacement: Option<String>,
+}
+
+impl Deprecation {
+    /// Reads the deprecation notice for `image` from its manifest annotations, if it has one. A
+    /// deprecated vendor takes precedence over a deprecated version.
+    pub(crate) fn from_annotations(
+        image: impl Into<String>,
+        annotations: &BTreeMap<String, String>,
+    ) -> Option<Self> {
+        let (scope, reason) = match (
+            annotations.get(VENDOR_DEPRECATED_ANNOTATION),
+            annotations.get(DEPRECATED_ANNOTATION),
+        ) {
+            (Some(reason), _) => (DeprecationScope::Vendor, reason),
+            (None, Some(reason)) => (DeprecationScope::Version, reason),
+            (None, None) => return None,
+        };
+        Some(Self {
+            image: image.into(),
+            scope,
+            reason: reason.clone(),
+            replacement: annotations.get(REPLACEMENT_ANNOTATION).cloned(),
+        })
+    }
+}
+
+impl Display for Deprecation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.scope {
+            DeprecationScope::Version => write!(f, "{} is deprecated", self.image)?,
+            DeprecationScope::Vendor => write!(f, "the vendor of {} is deprecated", self.image)?,
+        }
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        if let Some(replacement) = &self.replacement {
+            write!(f, " (use {replacement} instead)")?;
+        }
+        Ok(())
+    }
+}
+
+/// When an image stops being supported, as announced by its publisher.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EndOfLife {
+    pub(crate) image: String,
+    pub(crate) date: NaiveDate,
+}
+
+/// How close an image is to its end of life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EndOfLifeStatus {
+    Supported,
+    Near,
+    Past,
+}
+
+impl EndOfLife {
+    /// Reads the end of life of `image` from its manifest annotations, if it has one.
+    pub(crate) fn from_annotations(
+        image: impl Into<String>,
+        annotations: &BTreeMap<String, String>,
+    ) -> Result<Option<Self>> {
+        let image = image.into();
+        let Some(date) = annotations.get(END_OF_LIFE_ANNOTATION) else {
+            return Ok(None);
+        };
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").context(format!(
+            "the {END_OF_LIFE_ANNOTATION} annotation of {image} is not a date such as \
+            '2027-06-30': '{date}'"
+        ))?;
+        Ok(Some(Self { image, date }))
+    }
+
+    pub(crate) fn status(&self, today: NaiveDate) -> EndOfLifeStatus {
+        let days_left = (self.date - today).num_days();
+        if days_left < 0 {
+            EndOfLifeStatus::Past
+        } else if days_left <= END_OF_LIFE_NOTICE_DAYS {
+            EndOfLifeStatus::Near
+        } else {
+            EndOfLifeStatus::Supported
+        }
+    }
+}
+
+impl Display for EndOfLife {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is supported until {}",
+            self.image,
+            self.date.format("%Y-%m-%d")
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_annotations() {
+        let mut annotations =
+            BTreeMap::from([("com.example.retention".to_string(), "90d".to_string())]);
+        assert_eq!(
+            Deprecation::from_annotations("core-kit", &annotations),
+            None
+        );
+
+        annotations.insert(DEPRECATED_ANNOTATION.into(), "no longer patched".into());
+        annotations.insert(REPLACEMENT_ANNOTATION.into(), "core-kit 2.0.0".into());
+        let deprecation = Deprecation::from_annotations("core-kit-1.0.0", &annotations).unwrap();
+        assert_eq!(deprecation.scope, DeprecationScope::Version);
+        assert_eq!(
+            deprecation.to_string(),
+            "core-kit-1.0.0 is deprecated: no longer patched (use core-kit 2.0.0 instead)"
+        );
+
+        annotations.insert(VENDOR_DEPRECATED_ANNOTATION.into(), "moved".into());
+        let deprecation = Deprecation::from_annotations("core-kit-1.0.0", &annotations).unwrap();
+        assert_eq!(deprecation.scope, DeprecationScope::Vendor);
+    }
+
+    #[test]
+    fn test_end_of_life() {
+        let mut annotations = BTreeMap::new();
+        assert_eq!(
+            EndOfLife::from_annotations("core-kit-1.0.0", &annotations).unwrap(),
+            None
+        );
+
+        annotations.insert(END_OF_LIFE_ANNOTATION.into(), "2027-06-30".into());
+        let end_of_life = EndOfLife::from_annotations("core-kit-1.0.0", &annotations)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            end_of_life.to_string(),
+            "core-kit-1.0.0 is supported until 2027-06-30"
+        );
+        let date = |date| NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap();
+        assert_eq!(
+            end_of_life.status(date("2027-01-01")),
+            EndOfLifeStatus::Supported
+        );
+        assert_eq!(
+            end_of_life.status(date("2027-06-30")),
+            EndOfLifeStatus::Near
+        );
+        assert_eq!(
+            end_of_life.status(date("2027-07-01")),
+            EndOfLifeStatus::Past
+        );
+
+        annotations.insert(END_OF_LIFE_ANNOTATION.into(), "June 2027".into());
+        assert!(EndOfLife::from_annotations("core-kit-1.0.0", &annotations).is_err());
+    }
+}
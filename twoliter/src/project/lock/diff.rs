@@ -0,0 +1,352 @@
+//! Computes the changes between two states of a project's lockfile, such as before and after
+//! running `twoliter update`.
+use super::changelog::RELEASE_NOTES_ANNOTATION;
+use super::image::LockedImage;
+use super::views::ManifestListView;
+use super::Lock;
+use crate::output::{Output, OutputSchema, LOCK_DIFF_SCHEMA};
+use crate::project::ValidIdentifier;
+use oci_cli_wrapper::ImageTool;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use tracing::debug;
+
+/// A change to a single locked image between two lock states.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LockChange {
+    Added(LockedImage),
+    Removed(LockedImage),
+    Updated { old: LockedImage, new: LockedImage },
+}
+
+impl Display for LockChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockChange::Added(new) => write!(f, "add {} v{}", new.name, new.version),
+            LockChange::Removed(old) => write!(f, "remove {} v{}", old.name, old.version),
+            LockChange::Updated { old, new } if old.version != new.version => write!(
+                f,
+                "update {} from v{} to v{}",
+                new.name, old.version, new.version
+            ),
+            LockChange::Updated { new, .. } => {
+                write!(f, "update {} v{} digest", new.name, new.version)
+            }
+        }
+    }
+}
+
+impl Serialize for LockChange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Change<'a> {
+            change: &'static str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            old: Option<&'a LockedImage>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            new: Option<&'a LockedImage>,
+        }
+
+        let (change, old, new) = match self {
+            LockChange::Added(new) => ("added", None, Some(new)),
+            LockChange::Removed(old) => ("removed", Some(old), None),
+            LockChange::Updated { old, new } => ("updated", Some(old), Some(new)),
+        };
+        Change { change, old, new }.serialize(serializer)
+    }
+}
+
+impl LockChange {
+    /// The image the lock records after the change, unless it was removed.
+    fn new_image(&self) -> Option<&LockedImage> {
+        match self {
+            LockChange::Added(new) | LockChange::Updated { new, .. } => Some(new),
+            LockChange::Removed(_) => None,
+        }
+    }
+
+    /// Describes the change including sources and digests, suitable for a commit message body.
+    fn detailed(&self) -> String {
+        match self {
+            LockChange::Added(new) => format!("{self}\n  {}", pulled_as(new)),
+            LockChange::Removed(old) => format!("{self}\n  {}", pulled_as(old)),
+            LockChange::Updated { old, new } => {
+                format!("{self}\n  {}\n  -> {}", pulled_as(old), pulled_as(new))
+            }
+        }
+    }
+}
+
+/// Describes where `image` comes from: the uri that pulls exactly it, when the lock records its
+/// manifest digest, or else its source and lock digest.
+fn pulled_as(image: &LockedImage) -> String {
+    image
+        .pinned_uri()
+        .unwrap_or_else(|| format!("{} ({})", image.source, image.digest))
+}
+
+/// The set of changes between a previous lock state (if any) and a new lock state.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct LockDiff {
+    changes: Vec<LockChange>,
+}
+
+impl LockDiff {
+    /// Compares two lock states. A missing `old` lock is treated as empty, so every image in the
+    /// `new` lock is reported as added.
+    pub(crate) fn between(old: Option<&Lock>, new: &Lock) -> Self {
+        let mut old_images = old.map(Self::images_by_key).unwrap_or_default();
+        let mut changes = Vec::new();
+
+        for (key, new_image) in Self::images_by_key(new) {
+            match old_images.remove(&key) {
+                None => changes.push(LockChange::Added(new_image.clone())),
+                Some(old_image) if old_image != new_image => changes.push(LockChange::Updated {
+                    old: old_image.clone(),
+                    new: new_image.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+        changes.extend(
+            old_images
+                .into_values()
+                .map(|old_image| LockChange::Removed(old_image.clone())),
+        );
+
+        Self { changes }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub(crate) fn changes(&self) -> &[LockChange] {
+        &self.changes
+    }
+
+    /// Describes each change including sources and digests, one after another.
+    pub(crate) fn detailed(&self) -> String {
+        self.changes
+            .iter()
+            .map(|change| format!("{}\n", change.detailed()))
+            .collect()
+    }
+
+    /// Reads the release notes which `twoliter publish kit --release-notes` attached to each image
+    /// added or updated, keyed by the image. Images without release notes, or whose manifest list
+    /// cannot be read, are left out, since the notes only add to the commit message.
+    pub(crate) async fn release_notes(&self) -> BTreeMap<String, String> {
+        let image_tool = ImageTool::from_env();
+        let mut release_notes = BTreeMap::new();
+        for image in self.changes.iter().filter_map(LockChange::new_image) {
+            let uri = image.pinned_uri().unwrap_or_else(|| image.source.clone());
+            let manifest_list = match image_tool.get_manifest(&uri).await {
+                Ok(manifest) => serde_json::from_slice::<ManifestListView>(&manifest).ok(),
+                Err(e) => {
+                    debug!("Unable to read the release notes of '{uri}': {e}");
+                    None
+                }
+            };
+            if let Some(notes) = manifest_list
+                .and_then(|list| list.annotations.get(RELEASE_NOTES_ANNOTATION).cloned())
+            {
+                release_notes.insert(image.to_string(), notes);
+            }
+        }
+        release_notes
+    }
+
+    /// Renders a conventional commit message summarizing the changes, with the `release_notes` of
+    /// the images added or updated, keyed by the image, beneath each change.
+    pub(crate) fn commit_message(&self, release_notes: &BTreeMap<String, String>) -> String {
+        let subject = match self.changes.as_slice() {
+            [change] => format!("chore(deps): {change}"),
+            changes => format!("chore(deps): update {} locked dependencies", changes.len()),
+        };
+        let body = self
+            .changes
+            .iter()
+            .map(|change| {
+                let notes = change
+                    .new_image()
+                    .and_then(|image| release_notes.get(&image.to_string()));
+                match notes {
+                    Some(notes) => format!("- {}\n\n{}", change.detailed(), indent(notes.trim())),
+                    None => format!("- {}", change.detailed()),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!("{subject}\n\n{body}\n")
+    }
+
+    fn images_by_key(lock: &Lock) -> BTreeMap<(ValidIdentifier, ValidIdentifier), &LockedImage> {
+        std::iter::once(&lock.sdk)
+            .chain(lock.kit.iter())
+            .map(|image| ((image.vendor.clone(), image.name.clone()), image))
+            .collect()
+    }
+}
+
+/// Indents each line of `text` to sit beneath an entry in a commit message body.
+fn indent(text: &str) -> String {
+    text.lines()
+        .map(|line| match line {
+            "" => String::new(),
+            line => format!("  {line}"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl Display for LockDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for change in self.changes() {
+            writeln!(f, "{change}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Output for LockDiff {
+    const SCHEMA: OutputSchema = LOCK_DIFF_SCHEMA;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::project::lock::test::{lock, locked_image};
+
+    #[test]
+    fn test_no_changes() {
+        let old = lock(
+            locked_image("sdk", "0.50.0", "a"),
+            vec![locked_image("core-kit", "2.0.0", "b")],
+        );
+        let diff = LockDiff::between(Some(&old), &old.clone());
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn test_missing_old_lock_adds_everything() {
+        let new = lock(
+            locked_image("sdk", "0.50.0", "a"),
+            vec![locked_image("core-kit", "2.0.0", "b")],
+        );
+        let diff = LockDiff::between(None, &new);
+        assert_eq!(diff.changes().len(), 2);
+        assert!(diff
+            .changes()
+            .iter()
+            .all(|change| matches!(change, LockChange::Added(_))));
+    }
+
+    #[test]
+    fn test_version_and_digest_changes() {
+        let old = lock(
+            locked_image("sdk", "0.50.0", "a"),
+            vec![
+                locked_image("core-kit", "2.0.0", "b"),
+                locked_image("old-kit", "1.0.0", "c"),
+            ],
+        );
+        let new = lock(
+            locked_image("sdk", "0.50.0", "z"),
+            vec![
+                locked_image("core-kit", "2.1.0", "d"),
+                locked_image("new-kit", "1.0.0", "e"),
+            ],
+        );
+        let diff = LockDiff::between(Some(&old), &new);
+        let rendered = diff.to_string();
+        assert_eq!(diff.changes().len(), 4);
+        assert!(rendered.contains("update core-kit from v2.0.0 to v2.1.0"));
+        assert!(rendered.contains("update sdk v0.50.0 digest"));
+        assert!(rendered.contains("add new-kit v1.0.0"));
+        assert!(rendered.contains("remove old-kit v1.0.0"));
+    }
+
+    #[test]
+    fn test_commit_message_single_change() {
+        let old = lock(
+            locked_image("sdk", "0.50.0", "a"),
+            vec![locked_image("core-kit", "2.0.0", "b")],
+        );
+        let new = lock(
+            locked_image("sdk", "0.50.0", "a"),
+            vec![locked_image("core-kit", "2.1.0", "c")],
+        );
+        let message = LockDiff::between(Some(&old), &new).commit_message(&BTreeMap::new());
+        let subject = message.lines().next().unwrap();
+        assert_eq!(
+            subject,
+            "chore(deps): update core-kit from v2.0.0 to v2.1.0"
+        );
+        assert!(message.contains("public.ecr.aws/bottlerocket/core-kit:v2.1.0 (c)"));
+    }
+
+    #[test]
+    fn test_detailed() {
+        let old = lock(
+            locked_image("sdk", "0.50.0", "a"),
+            vec![locked_image("core-kit", "2.0.0", "b")],
+        );
+        let new = lock(
+            locked_image("sdk", "0.50.0", "a"),
+            vec![locked_image("core-kit", "2.0.0", "c")],
+        );
+        assert_eq!(
+            LockDiff::between(Some(&old), &new).detailed(),
+            "update core-kit v2.0.0 digest\n  public.ecr.aws/bottlerocket/core-kit:v2.0.0 (b)\n  \
+            -> public.ecr.aws/bottlerocket/core-kit:v2.0.0 (c)\n"
+        );
+    }
+
+    #[test]
+    fn test_commit_message_multiple_changes() {
+        let old = lock(
+            locked_image("sdk", "0.50.0", "a"),
+            vec![locked_image("core-kit", "2.0.0", "b")],
+        );
+        let new = lock(
+            locked_image("sdk", "0.51.0", "c"),
+            vec![locked_image("core-kit", "2.1.0", "d")],
+        );
+        let message = LockDiff::between(Some(&old), &new).commit_message(&BTreeMap::new());
+        assert!(message.starts_with("chore(deps): update 2 locked dependencies\n\n"));
+        assert!(message.contains("- update sdk from v0.50.0 to v0.51.0"));
+        assert!(message.contains("- update core-kit from v2.0.0 to v2.1.0"));
+    }
+
+    #[test]
+    fn test_commit_message_release_notes() {
+        let old = lock(
+            locked_image("sdk", "0.50.0", "a"),
+            vec![locked_image("core-kit", "2.0.0", "b")],
+        );
+        let new_kit = locked_image("core-kit", "2.1.0", "c");
+        let new = lock(locked_image("sdk", "0.50.0", "a"), vec![new_kit.clone()]);
+        let release_notes =
+            BTreeMap::from([(new_kit.to_string(), "Fixes:\n\n* a fix\n".to_string())]);
+        let message = LockDiff::between(Some(&old), &new).commit_message(&release_notes);
+        assert!(
+            message.ends_with("(c)\n\n  Fixes:\n\n  * a fix\n"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_serialize_changes() {
+        let old = lock(locked_image("sdk", "0.50.0", "a"), vec![]);
+        let new = lock(
+            locked_image("sdk", "0.50.0", "a"),
+            vec![locked_image("core-kit", "2.0.0", "b")],
+        );
+        let json = serde_json::to_value(LockDiff::between(Some(&old), &new)).unwrap();
+        assert_eq!(json["changes"][0]["change"], "added");
+        assert_eq!(json["changes"][0]["new"]["digest"], "b");
+        assert!(json["changes"][0].get("old").is_none());
+    }
+}
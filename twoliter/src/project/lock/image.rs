@@ -1,18 +1,36 @@
-use super::archive::OCIArchive;
-use super::views::ManifestListView;
-use crate::common::fs::create_dir_all;
+use super::archive::{ExtractOptions, OCIArchive};
+use super::compatibility::CompatibilityMatrix;
+use super::deprecation::{Deprecation, EndOfLife, EndOfLifeStatus};
+use super::integrity::CacheKey;
+use super::local::{LocalKit, LOCAL_SOURCE_PREFIX};
+use super::transfer::Transfer;
+use super::views::{ManifestKindView, ManifestListView, ManifestView, Platform};
+use crate::common::fs::{create_dir_all, rename};
 use crate::compatibility::SUPPORTED_KIT_METADATA_VERSION;
+use crate::diagnostic::Code;
+use crate::docker::ImageUri;
+use crate::messages::msg;
+use crate::project::cache::{
+    CacheMiss, CacheReport, CacheStage, CacheStatus, KIT_ARCHIVE_CACHE_DIR,
+};
+use crate::project::shared_cache::SharedCache;
+use crate::project::store::SystemStore;
 use crate::project::{Image, ProjectImage, ValidIdentifier, VendedArtifact};
-use anyhow::{bail, Context, Result};
+use crate::warnings;
+use anyhow::{bail, ensure, Context, Result};
 use base64::Engine;
+use chrono::Utc;
 use futures::{pin_mut, stream, StreamExt, TryStreamExt};
 use log::trace;
 use oci_cli_wrapper::{ConfigView, DockerArchitecture, ImageTool};
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Display, Formatter};
 use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
 use tracing::{debug, error, info, instrument};
 
 /// The OCI config label prefix to which the supported kit metadata version is appended.
@@ -37,11 +55,32 @@ pub(crate) struct LockedImage {
     pub source: String,
     /// The digest of the image
     pub digest: String,
+    /// The registry digest of the image's manifest list, as `sha256:<hex>`, which other tools can
+    /// pull it by. Absent from locks written before it was recorded, and for kits locked from
+    /// local builds.
+    #[serde(
+        default,
+        rename = "manifest-digest",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub manifest_digest: Option<String>,
+    /// The mirror the image was resolved from, when its vendor's registry could not be reached
+    #[serde(
+        default,
+        rename = "resolved-from",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub resolved_from: Option<String>,
 }
 
 impl PartialEq for LockedImage {
     fn eq(&self, other: &Self) -> bool {
-        self.source == other.source && self.digest == other.digest
+        // Entries locked before manifest digests were recorded match on the other digest alone.
+        let manifest_digests_match = match (&self.manifest_digest, &other.manifest_digest) {
+            (Some(digest), Some(other)) => digest == other,
+            _ => true,
+        };
+        self.source == other.source && self.digest == other.digest && manifest_digests_match
     }
 }
 
@@ -66,22 +105,53 @@ impl VendedArtifact for LockedImage {
     fn version(&self) -> &Version {
         &self.version
     }
+
+    /// Kits pinned to a digest are locked with a source which references it.
+    fn digest(&self) -> Option<&str> {
+        if self.is_local() {
+            return None;
+        }
+        self.source.split_once('@').map(|(_, digest)| digest)
+    }
+}
+
+impl LockedImage {
+    /// Whether the image was locked from local builds rather than from a registry.
+    pub(crate) fn is_local(&self) -> bool {
+        self.source.starts_with(LOCAL_SOURCE_PREFIX)
+    }
+
+    /// The uri which pulls exactly the locked image, as `<repository>@sha256:<hex>`, when the
+    /// lock records its manifest digest.
+    pub(crate) fn pinned_uri(&self) -> Option<String> {
+        let digest = self.manifest_digest.as_ref()?;
+        let repository = match self.source.split_once('@') {
+            Some((repository, _)) => repository,
+            // A colon before the last slash separates a registry's port, not a tag.
+            None => match self.source.rsplit_once(':') {
+                Some((repository, tag)) if !tag.contains('/') => repository,
+                _ => self.source.as_str(),
+            },
+        };
+        Some(format!("{repository}@{digest}"))
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct ImageMetadata {
     /// The name of the kit
-    #[expect(dead_code)]
     pub name: String,
     /// The version of the kit
-    #[expect(dead_code)]
     pub version: Version,
     /// The required sdk of the kit,
     pub sdk: Image,
     /// Any dependent kits
     #[serde(rename = "kit")]
     pub kits: Vec<Image>,
+    /// The versions of the SDK and other kits the kit works with, read from its annotations
+    #[serde(skip)]
+    pub compatibility: Option<CompatibilityMatrix>,
 }
 
 impl TryFrom<EncodedKitMetadata> for ImageMetadata {
@@ -116,6 +186,21 @@ impl EncodedKitMetadata {
         Ok(kit_metadata)
     }
 
+    /// Reads the kit metadata from the config of the kit's local builds.
+    async fn try_from_local(local: &LocalKit<'_>) -> Result<Self> {
+        Self::from_config(&ConfigView {
+            labels: local.labels().await?,
+            architecture: None,
+        })
+    }
+
+    /// Reads the kit metadata from an image config which has already been fetched.
+    pub(super) fn from_config(config: &ConfigView) -> Result<Self> {
+        Ok(EncodedKitMetadata(Self::extract_encoded_kit_metadata(
+            config,
+        )?))
+    }
+
     fn extract_encoded_kit_metadata(oci_config: &ConfigView) -> Result<String> {
         let encoded_metadata = oci_config
             .labels
@@ -133,14 +218,14 @@ impl EncodedKitMetadata {
                     let meta_relation =
                         Self::compare_version_strs(kit_version, SUPPORTED_KIT_METADATA_VERSION);
 
-                    bail!(
-                        "kit appears to be built with metadata version '{kit_version}', possibly by \
-                        {meta_relation} version of twoliter with unsupported incompatibilities. \
-                        This version of twoliter supports metadata version \
-                        '{SUPPORTED_KIT_METADATA_VERSION}'.",
-                    )
+                    bail!(Code::UnsupportedKitMetadata.error(msg!(
+                        "error.unsupported-kit-metadata",
+                        kit_version = kit_version,
+                        relation = meta_relation,
+                        supported_version = SUPPORTED_KIT_METADATA_VERSION,
+                    )))
                 } else {
-                    bail!("no metadata stored on image, this image appears not to be a kit")
+                    bail!(Code::MissingKitMetadata.error(msg!("error.missing-kit-metadata")))
                 }
             }
         }
@@ -192,6 +277,15 @@ impl Debug for EncodedKitMetadata {
 pub struct ImageResolver {
     image: ProjectImage,
     skip_metadata_retrieval: bool,
+    skip_signature_verification: bool,
+    /// Where the image is fetched from, which is the image itself or one of its mirrors. Chosen
+    /// when the image is first fetched.
+    source: OnceCell<ProjectImage>,
+    /// The manifest list from the source and its registry digest, fetched together once and
+    /// shared by everything that reads them.
+    manifest: OnceCell<(Vec<u8>, String)>,
+    /// Counts the bytes of the image pulled and extracted, when they are reported.
+    transfer: Option<Arc<Transfer>>,
 }
 
 impl ImageResolver {
@@ -199,9 +293,56 @@ impl ImageResolver {
         Ok(Self {
             image: image.clone(),
             skip_metadata_retrieval: false,
+            skip_signature_verification: false,
+            source: OnceCell::new(),
+            manifest: OnceCell::new(),
+            transfer: None,
         })
     }
 
+    /// Where the image is fetched from, choosing it on first use.
+    async fn source(&self, image_tool: &ImageTool) -> Result<&ProjectImage> {
+        self.source
+            .get_or_try_init(|| async {
+                let (source, manifest) = select_source(&self.image, image_tool).await?;
+                if let Some(manifest) = manifest {
+                    // Choosing among mirrors fetches the manifest list and its digest, which need
+                    // not be fetched again.
+                    let _ = self.manifest.set(manifest);
+                }
+                Ok(source)
+            })
+            .await
+    }
+
+    /// The image's manifest list and its registry digest, fetching them from the source on first
+    /// use.
+    async fn manifest(&self, image_tool: &ImageTool) -> Result<&(Vec<u8>, String)> {
+        let uri = self
+            .source(image_tool)
+            .await?
+            .project_image_uri()
+            .to_string();
+        self.manifest
+            .get_or_try_init(|| async {
+                debug!(image=%self.image, uri, "Fetching image manifest.");
+                Ok(image_tool.get_manifest_with_digest(uri.as_str()).await?)
+            })
+            .await
+    }
+
+    /// The bytes of the image's manifest list, fetching them from the source on first use.
+    async fn manifest_bytes(&self, image_tool: &ImageTool) -> Result<&[u8]> {
+        Ok(self.manifest(image_tool).await?.0.as_slice())
+    }
+
+    /// The registry digest of the image's manifest list, as `sha256:<hex>`. This is the digest of
+    /// the bytes the registry serves, which the canonicalized manifest list need not match. It
+    /// comes from the same fetch as the manifest list itself.
+    async fn manifest_digest(&self, image_tool: &ImageTool) -> Result<&str> {
+        Ok(self.manifest(image_tool).await?.1.as_str())
+    }
+
     /// Skip metadata retrieval when resolving images.
     ///
     /// This is useful for SDKs, which don't store image metadata (no deps.)
@@ -210,17 +351,29 @@ impl ImageResolver {
         self
     }
 
+    /// Skip verifying the image's signature when resolving it.
+    ///
+    /// This is only safe when the image is checked against a digest which was verified when it
+    /// was locked, such as when reading images offline, where signatures cannot be checked.
+    pub(crate) fn skip_signature_verification(mut self, skip: bool) -> Self {
+        self.skip_signature_verification = skip;
+        self
+    }
+
+    /// Count the bytes pulled and extracted in `transfer`, so that they can be reported.
+    pub(crate) fn with_transfer(mut self, transfer: &Arc<Transfer>) -> Self {
+        self.transfer = Some(transfer.clone());
+        self
+    }
+
     #[instrument(
         level = "trace",
         fields(image = %self.image, uri = %self.image.project_image_uri())
     )]
     /// Calculate the digest of the locked image
     async fn calculate_digest(&self, image_tool: &ImageTool) -> Result<String> {
-        let image_uri = self.image.project_image_uri();
-        let image_uri_str = image_uri.to_string();
-        let manifest_bytes = image_tool.get_manifest(image_uri_str.as_str()).await?;
-        let digest = sha2::Sha256::digest(manifest_bytes.as_slice());
-        let digest = base64::engine::general_purpose::STANDARD.encode(digest.as_slice());
+        let image_uri = self.source(image_tool).await?.project_image_uri();
+        let digest = lock_digest(self.manifest_bytes(image_tool).await?);
         debug!(
             "Calculated digest for locked image '{}': '{}'",
             image_uri, digest,
@@ -233,11 +386,34 @@ impl ImageResolver {
         fields(image = %self.image, uri = %self.image.project_image_uri())
     )]
     async fn get_manifest(&self, image_tool: &ImageTool) -> Result<ManifestListView> {
-        let uri = self.image.project_image_uri().to_string();
-        debug!(image=%self.image, uri, "Fetching image manifest.");
-        let manifest_bytes = image_tool.get_manifest(uri.as_str()).await?;
-        serde_json::from_slice(manifest_bytes.as_slice())
-            .context("failed to deserialize manifest list")
+        let uri = self.source_uri(image_tool).await?;
+        read_manifest_list(image_tool, &uri, self.manifest_bytes(image_tool).await?).await
+    }
+
+    /// Checks that the registry has the manifest list `pinned` names, which it is fetched by.
+    async fn check_pinned_digest(&self, image_tool: &ImageTool, pinned: &str) -> Result<()> {
+        let digest = self.manifest_digest(image_tool).await.context(format!(
+            "failed to fetch {} by the digest it is pinned to",
+            self.image
+        ))?;
+        ensure!(
+            digest == pinned,
+            "the registry served {} with digest '{digest}' rather than '{pinned}'",
+            self.image
+        );
+        debug!(image=%self.image, pinned, "Verified the pinned digest.");
+        Ok(())
+    }
+
+    /// When the image stops being supported, if its publisher announced it.
+    pub(crate) async fn end_of_life(&self, image_tool: &ImageTool) -> Result<Option<EndOfLife>> {
+        if self.image.local_path().is_some() {
+            return Ok(None);
+        }
+        // `twoliter publish kit` annotates the manifest list as well as each image manifest, so
+        // the list which is fetched anyway is enough.
+        let manifest_list = self.get_manifest(image_tool).await?;
+        EndOfLife::from_annotations(self.image.to_string(), &manifest_list.annotations)
     }
 
     #[instrument(
@@ -248,8 +424,16 @@ impl ImageResolver {
         &self,
         image_tool: &ImageTool,
     ) -> Result<(LockedImage, Option<ImageMetadata>)> {
+        if let Some(dir) = self.image.local_path() {
+            return self.resolve_local(dir).await;
+        }
+        if let Some(pinned) = self.image.digest() {
+            self.check_pinned_digest(image_tool, pinned).await?;
+        }
+
         // First get the manifest list
-        let uri = self.image.project_image_uri();
+        let source = self.source(image_tool).await?;
+        let uri = source.project_image_uri();
         info!("Resolving dependency image dependency '{}'.", self.image);
 
         let manifest_list = self.get_manifest(image_tool).await?;
@@ -258,6 +442,32 @@ impl ImageResolver {
             .as_ref()
             .context("no registry found for image")?;
 
+        let annotations = &manifest_list.annotations;
+        let image = self.image.to_string();
+        if let Some(deprecation) = Deprecation::from_annotations(&image, annotations) {
+            warnings::deprecated(deprecation.to_string());
+        }
+        match EndOfLife::from_annotations(&image, annotations) {
+            Ok(Some(end_of_life)) => warn_end_of_life(&end_of_life),
+            Ok(None) => {}
+            Err(e) => warnings::warn(format!("{e:#}")),
+        }
+
+        if let Some(policy) = self
+            .image
+            .signature_policy()
+            .filter(|_| !self.skip_signature_verification)
+        {
+            let digest = self.manifest_digest(image_tool).await?;
+            policy
+                .verify(
+                    self.image.vendor_name().as_ref(),
+                    &self.image.to_string(),
+                    &format!("{registry}/{}@{digest}", uri.repo),
+                )
+                .await?;
+        }
+
         let locked_image = LockedImage {
             name: self.image.name().to_owned(),
             version: self.image.version().to_owned(),
@@ -265,6 +475,8 @@ impl ImageResolver {
             // The source is the image uri without the tag, which is the digest
             source: self.image.original_source_uri().to_string(),
             digest: self.calculate_digest(image_tool).await?,
+            manifest_digest: Some(self.manifest_digest(image_tool).await?.to_string()),
+            resolved_from: (*source != self.image).then(|| uri.to_string()),
         };
 
         if self.skip_metadata_retrieval {
@@ -298,43 +510,142 @@ impl ImageResolver {
                 bail!("Metadata does not match between images in manifest list");
             }
         }
-        let metadata = canonical_metadata
+        let mut metadata: ImageMetadata = canonical_metadata
             .try_into()
             .context("Failed to decode and parse kit metadata")?;
+        metadata.compatibility = CompatibilityMatrix::from_annotations(&image, annotations)?;
+        // A digest is not tied to a version the way a tag is, so check that it is the version
+        // the project declares.
+        ensure!(
+            self.image.digest().is_none() || metadata.version == *self.image.version(),
+            "kit {} is pinned to a digest of version {} rather than {}",
+            self.image,
+            metadata.version,
+            self.image.version()
+        );
 
         Ok((locked_image, Some(metadata)))
     }
 
-    #[instrument(
-        level = "trace",
-        fields(uri = %self.image.project_image_uri(), path = %path.as_ref().display())
-    )]
-    pub(crate) async fn extract<P>(&self, image_tool: &ImageTool, path: P, arch: &str) -> Result<()>
-    where
-        P: AsRef<Path>,
-    {
+    /// Locks a kit which is replaced with local builds. There is no manifest list, signature or
+    /// lifecycle annotations to check, so the kit is locked with the digest of its metadata.
+    async fn resolve_local(&self, dir: &Path) -> Result<(LockedImage, Option<ImageMetadata>)> {
+        ensure!(
+            !self.skip_metadata_retrieval,
+            "only kits can be overridden with local builds, not {}",
+            self.image
+        );
         info!(
-            "Extracting kit '{}' to '{}'",
-            self.image.name(),
-            path.as_ref().display()
+            "Resolving dependency image '{}' from local builds in '{}'.",
+            self.image,
+            dir.display()
         );
-        let target_path = path.as_ref().join(format!(
-            "{}/{}/{arch}",
-            self.image.vendor_name(),
-            self.image.name()
+        let local = LocalKit::new(dir);
+        let encoded = EncodedKitMetadata::try_from_local(&local).await?;
+        let locked_image = LockedImage {
+            name: self.image.name().to_owned(),
+            version: self.image.version().to_owned(),
+            vendor: self.image.vendor_name().to_owned(),
+            source: local.source(),
+            digest: lock_digest(encoded.0.as_bytes()),
+            manifest_digest: None,
+            resolved_from: None,
+        };
+        let metadata: ImageMetadata = encoded
+            .try_into()
+            .context("Failed to decode and parse kit metadata")?;
+        warnings::warn(format!(
+            "{} is overridden with the local builds in '{}', so Twoliter.lock is only valid on \
+            this machine and should not be committed",
+            self.image,
+            dir.display()
         ));
-        let cache_path = path.as_ref().join("cache");
-        create_dir_all(&target_path).await?;
-        create_dir_all(&cache_path).await?;
+        if metadata.version != *self.image.version() {
+            warnings::warn(format!(
+                "the local builds of {} are of version {}",
+                self.image, metadata.version
+            ));
+        }
+        Ok((locked_image, Some(metadata)))
+    }
+
+    /// Reads the kit metadata embedded in the image without pulling it. Every manifest in a kit's
+    /// manifest list carries the same metadata, so only the first is read.
+    #[instrument(
+        level = "trace",
+        fields(image = %self.image, uri = %self.image.project_image_uri())
+    )]
+    pub(crate) async fn kit_metadata(&self, image_tool: &ImageTool) -> Result<ImageMetadata> {
+        if let Some(dir) = self.image.local_path() {
+            return EncodedKitMetadata::try_from_local(&LocalKit::new(dir))
+                .await?
+                .try_into()
+                .context("Failed to decode and parse kit metadata");
+        }
+        let uri = self.source(image_tool).await?.project_image_uri();
+        let registry = uri
+            .registry
+            .as_ref()
+            .context("no registry found for image")?;
+        let manifest_list = self.get_manifest(image_tool).await?;
+        let manifest = manifest_list
+            .manifests
+            .first()
+            .context(format!("could not find metadata for kit {}", uri))?;
+        let image_uri = format!("{registry}/{}@{}", uri.repo, manifest.digest);
+        EncodedKitMetadata::try_from_image(&image_uri, image_tool)
+            .await?
+            .try_into()
+            .context("Failed to decode and parse kit metadata")
+    }
 
+    /// Where the image is fetched from, which is the image itself or one of its mirrors, or the
+    /// local builds which replace it.
+    pub(crate) async fn source_uri(&self, image_tool: &ImageTool) -> Result<String> {
+        if let Some(dir) = self.image.local_path() {
+            return Ok(LocalKit::new(dir).source());
+        }
+        Ok(self
+            .source(image_tool)
+            .await?
+            .project_image_uri()
+            .to_string())
+    }
+
+    /// The architectures of the images in the manifest list, without pulling any of them.
+    pub(crate) async fn architectures(&self, image_tool: &ImageTool) -> Result<Vec<String>> {
+        if let Some(dir) = self.image.local_path() {
+            return LocalKit::new(dir).architectures();
+        }
+        Ok(self
+            .get_manifest(image_tool)
+            .await?
+            .manifests
+            .iter()
+            .filter_map(|manifest| manifest.platform.as_ref())
+            .map(|platform| platform.architecture.to_string())
+            .collect())
+    }
+
+    /// Locates the archive of the image for `arch`, to be kept in `cache_path`.
+    async fn archive(
+        &self,
+        image_tool: &ImageTool,
+        cache_path: &Path,
+        arch: &str,
+    ) -> Result<OCIArchive> {
         // First get the manifest for the specific requested architecture
-        let uri = self.image.project_image_uri();
+        let uri = self.source(image_tool).await?.project_image_uri();
         let manifest_list = self.get_manifest(image_tool).await?;
         let docker_arch = DockerArchitecture::try_from(arch)?;
         let manifest = manifest_list
             .manifests
             .iter()
-            .find(|x| x.platform.as_ref().unwrap().architecture == docker_arch)
+            .find(|x| {
+                x.platform
+                    .as_ref()
+                    .is_some_and(|platform| platform.architecture == docker_arch)
+            })
             .cloned()
             .context(format!(
                 "could not find image for architecture '{}' at {}",
@@ -342,21 +653,293 @@ impl ImageResolver {
             ))?;
 
         let registry = uri.registry.context("failed to resolve image registry")?;
-        let oci_archive = OCIArchive::new(
+        OCIArchive::new(
             registry.as_str(),
             uri.repo.as_str(),
             manifest.digest.as_str(),
-            &cache_path,
-        )?;
+            cache_path,
+        )
+    }
+
+    /// Where the kit for `arch` is extracted, relative to the external kits directory.
+    fn kit_path(&self, arch: &str) -> String {
+        format!("{}/{}/{arch}", self.image.vendor_name(), self.image.name())
+    }
+
+    /// Pulls the archive of the image for `arch` into the kit archive cache within `path`, unless
+    /// it is cached already or found in the system-wide `store`.
+    pub(crate) async fn pull<P>(
+        &self,
+        image_tool: &ImageTool,
+        path: P,
+        arch: &str,
+        key: Option<&CacheKey>,
+        store: Option<&SystemStore>,
+    ) -> Result<(OCIArchive, CacheReport)>
+    where
+        P: AsRef<Path>,
+    {
+        let cache_path = path.as_ref().join(KIT_ARCHIVE_CACHE_DIR);
+        create_dir_all(&cache_path).await?;
+        let oci_archive = self
+            .archive(image_tool, &cache_path, arch)
+            .await?
+            .with_store(store)
+            .with_shared_cache(SharedCache::configured())
+            .with_transfer(self.transfer.clone());
+
+        let mut report = CacheReport::default();
 
         // Checks for the saved image locally, or else pulls and saves it
-        oci_archive.pull_image(image_tool).await?;
+        let status = oci_archive.pull_image(image_tool, key).await?;
+        report.record(self.kit_path(arch), CacheStage::KitArchive, status);
+        Ok((oci_archive, report))
+    }
+
+    /// Pulls the archive of the image for `arch` into the system-wide store, unless it is there.
+    pub(crate) async fn store(
+        &self,
+        image_tool: &ImageTool,
+        store: &SystemStore,
+        arch: &str,
+    ) -> Result<CacheReport> {
+        let kit_path = self.kit_path(arch);
+        let archives_dir = store.kit_archives_dir();
+        create_dir_all(&archives_dir).await?;
+        let archive_path = self
+            .archive(image_tool, &archives_dir, arch)
+            .await?
+            .archive_path();
+        let mut report = CacheReport::default();
+        if archive_path.exists() {
+            report.record(&kit_path, CacheStage::KitArchive, CacheStatus::Hit);
+            return Ok(report);
+        }
+        // Pull into a temporary directory next to the final location, as the SDK archive is, so
+        // that builds sharing the store never see a partial archive, and a failed pull leaves
+        // nothing behind in it.
+        let temp_dir = tempfile::Builder::new()
+            .prefix(".kit-")
+            .tempdir_in(&archives_dir)?;
+        let oci_archive = self.archive(image_tool, temp_dir.path(), arch).await?;
+        let status = oci_archive.pull_image(image_tool, None).await?;
+        rename(oci_archive.archive_path(), &archive_path).await?;
+        report.record(&kit_path, CacheStage::KitArchive, status);
+        Ok(report)
+    }
+
+    #[instrument(
+        level = "trace",
+        fields(uri = %self.image.project_image_uri(), path = %path.as_ref().display())
+    )]
+    pub(crate) async fn extract<P>(
+        &self,
+        image_tool: &ImageTool,
+        path: P,
+        arch: &str,
+        options: ExtractOptions,
+        key: Option<&CacheKey>,
+        store: Option<&SystemStore>,
+    ) -> Result<CacheReport>
+    where
+        P: AsRef<Path>,
+    {
+        info!(
+            "Extracting kit '{}' to '{}'",
+            self.image.name(),
+            path.as_ref().display()
+        );
+        let kit_path = self.kit_path(arch);
+        let target_path = path.as_ref().join(&kit_path);
+        if let Some(dir) = self.image.local_path() {
+            // Local builds change without their version changing, so they are never cached.
+            LocalKit::new(dir).extract(&target_path, arch).await?;
+            let mut report = CacheReport::default();
+            report.record(
+                &kit_path,
+                CacheStage::KitExtraction,
+                CacheStatus::Miss(CacheMiss::Missing),
+            );
+            return Ok(report);
+        }
+        create_dir_all(&target_path).await?;
+
+        let (oci_archive, mut report) = self.pull(image_tool, &path, arch, key, store).await?;
 
         // Checks if this archive has already been extracted by checking a digest file
         // otherwise cleans up the path and unpacks the archive
-        oci_archive.unpack_layers(&target_path).await?;
+        let status = oci_archive
+            .unpack_layers(&target_path, options, key)
+            .await?;
+        report.record(&kit_path, CacheStage::KitExtraction, status);
 
-        Ok(())
+        Ok(report)
+    }
+}
+
+/// Writes the image for `arch` from the manifest list at `uri` to an OCI layout at `path`.
+pub(crate) async fn pull_platform_image(
+    image_tool: &ImageTool,
+    uri: &ImageUri,
+    arch: &str,
+    path: &Path,
+) -> Result<()> {
+    let manifest = image_tool.get_manifest(&uri.uri()).await?;
+    let manifest_list = read_manifest_list(image_tool, &uri.uri(), &manifest).await?;
+    let docker_arch = DockerArchitecture::try_from(arch)?;
+    let manifest = manifest_list
+        .manifests
+        .iter()
+        .find(|manifest| {
+            manifest
+                .platform
+                .as_ref()
+                .is_some_and(|platform| platform.architecture == docker_arch)
+        })
+        .context(format!(
+            "could not find image for architecture '{docker_arch}' at {uri}"
+        ))?;
+    let registry = uri
+        .registry
+        .as_ref()
+        .context("no registry found for image")?;
+    let digest_uri = format!("{registry}/{}@{}", uri.repo, manifest.digest);
+    image_tool.pull_oci_image(path, &digest_uri).await?;
+    Ok(())
+}
+
+/// Warns when an image is near its end of life, and reports it as deprecated once that has passed,
+/// so that `--deny deprecations` fails builds against it.
+fn warn_end_of_life(end_of_life: &EndOfLife) {
+    let date = end_of_life.date.format("%Y-%m-%d");
+    match end_of_life.status(Utc::now().date_naive()) {
+        EndOfLifeStatus::Supported => debug!("{end_of_life}"),
+        EndOfLifeStatus::Near => warnings::warn(format!(
+            "{} reaches its end of life on {date}",
+            end_of_life.image
+        )),
+        EndOfLifeStatus::Past => warnings::deprecated(format!(
+            "{} reached its end of life on {date}",
+            end_of_life.image
+        )),
+    }
+}
+
+/// Reads `manifest`, which `uri` serves, as a manifest list. Some registries only hold
+/// single-architecture images, whose plain image manifest is read as a list of itself alone, for
+/// the architecture named in the image's config.
+async fn read_manifest_list(
+    image_tool: &ImageTool,
+    uri: &str,
+    manifest: &[u8],
+) -> Result<ManifestListView> {
+    let kind: ManifestKindView = serde_json::from_slice(manifest)
+        .context(format!("failed to deserialize manifest of '{uri}'"))?;
+    if kind.is_manifest_list() {
+        return serde_json::from_slice(manifest)
+            .context(format!("failed to deserialize manifest list of '{uri}'"));
+    }
+    ensure!(
+        kind.is_image_manifest(),
+        "'{uri}' is neither an image nor a manifest list, but has media type '{}'",
+        kind.media_type.unwrap_or_default()
+    );
+
+    debug!(
+        uri,
+        "Image has a single image manifest rather than a manifest list."
+    );
+    let architecture = image_tool
+        .get_config(uri)
+        .await?
+        .architecture
+        .context(format!(
+            "the config of '{uri}' does not name its architecture"
+        ))?;
+    // The manifest was canonicalized when it was fetched, so its digest is the registry's, not a
+    // hash of the bytes read here.
+    Ok(ManifestListView {
+        manifests: vec![ManifestView {
+            digest: image_tool.get_digest(uri).await?,
+            platform: Some(Platform {
+                architecture: DockerArchitecture::try_from(architecture.as_str())?,
+            }),
+        }],
+        annotations: kind.annotations,
+    })
+}
+
+/// The digest recorded in Twoliter.lock for an image with the manifest list `manifest`.
+pub(super) fn lock_digest(manifest: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(sha2::Sha256::digest(manifest).as_slice())
+}
+
+/// Chooses where to fetch `image` from: the first of its vendor's mirrors which can be reached, or
+/// else the vendor's registry. Sources after the first reachable one are not contacted, since they
+/// may be blocked from where twoliter runs; a mirror which serves a different image is caught by
+/// the digest recorded in Twoliter.lock and by signature verification. The manifest list of the
+/// chosen source and its digest are returned too, if they were fetched.
+async fn select_source(
+    image: &ProjectImage,
+    image_tool: &ImageTool,
+) -> Result<(ProjectImage, Option<(Vec<u8>, String)>)> {
+    let sources = image.sources();
+    if sources.len() == 1 {
+        return Ok((image.clone(), None));
+    }
+    let mut failures = Vec::new();
+    for source in sources {
+        let uri = source.project_image_uri().to_string();
+        let manifest = match image_tool.get_manifest_with_digest(&uri).await {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                // Falling back to the next source is what mirrors are for, so it is not a warning.
+                info!("Unable to reach '{uri}', trying the next source: {e}");
+                failures.push(format!("'{uri}': {e}"));
+                continue;
+            }
+        };
+        if source != *image {
+            info!(
+                "Resolving '{image}' from mirror '{}'",
+                source.project_image_uri()
+            );
+        }
+        return Ok((source, Some(manifest)));
+    }
+    bail!(
+        "none of the sources of '{image}' could be reached: {}",
+        failures.join("; ")
+    )
+}
+
+/// Reads the metadata embedded in the image `repository:tag`, or returns `None` if it is not a kit
+/// this version of twoliter can read.
+pub(crate) async fn read_kit_metadata(
+    image_tool: &ImageTool,
+    repository: &str,
+    tag: &str,
+) -> Result<Option<ImageMetadata>> {
+    let uri = format!("{repository}:{tag}");
+    let manifest_bytes = image_tool.get_manifest(&uri).await?;
+    let Ok(manifest_list) = read_manifest_list(image_tool, &uri, &manifest_bytes).await else {
+        debug!(uri, "Image has no readable manifest, so it is not a kit.");
+        return Ok(None);
+    };
+    let Some(manifest) = manifest_list.manifests.first() else {
+        return Ok(None);
+    };
+    let image_uri = format!("{repository}@{}", manifest.digest);
+    let metadata = match EncodedKitMetadata::try_from_image(&image_uri, image_tool).await {
+        Ok(encoded) => encoded.try_into(),
+        Err(e) => Err(e),
+    };
+    match metadata {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) => {
+            debug!(uri, error = %e, "Image has no readable kit metadata.");
+            Ok(None)
+        }
     }
 }
 
@@ -393,6 +976,7 @@ mod test {
     fn test_extract_encoded_kit_metadata_fails_no_label() {
         EncodedKitMetadata::extract_encoded_kit_metadata(&ConfigView {
             labels: HashMap::from([("foo".to_string(), "bar".to_string())]),
+            architecture: None,
         })
         .expect_err("no label");
     }
@@ -401,6 +985,7 @@ mod test {
     fn test_extract_encoded_kit_metadata_fails_older_metadata() {
         let err = EncodedKitMetadata::extract_encoded_kit_metadata(&ConfigView {
             labels: HashMap::from([(format!("{KIT_METADATA_LABEL_PREFIX}v0"), "bar".to_string())]),
+            architecture: None,
         })
         .expect_err("too old")
         .to_string();
@@ -415,6 +1000,7 @@ mod test {
                 format!("{KIT_METADATA_LABEL_PREFIX}v9999"),
                 "bar".to_string(),
             )]),
+            architecture: None,
         })
         .expect_err("too new")
         .to_string();
@@ -429,6 +1015,7 @@ mod test {
                 format!("{KIT_METADATA_LABEL_PREFIX}notaversion"),
                 "foo".to_string(),
             )]),
+            architecture: None,
         })
         .expect_err("not a version")
         .to_string();
@@ -444,9 +1031,166 @@ mod test {
                     format!("{KIT_METADATA_LABEL_PREFIX}{SUPPORTED_KIT_METADATA_VERSION}"),
                     "bar".to_string(),
                 )]),
+                architecture: None,
             })
             .unwrap(),
             "bar".to_string()
         );
     }
+
+    #[test]
+    fn test_locked_image_manifest_digest() {
+        let old: LockedImage = toml::from_str(
+            r#"
+            name = "core-kit"
+            version = "2.0.0"
+            vendor = "bottlerocket"
+            source = "public.ecr.aws/bottlerocket/core-kit:v2.0.0"
+            digest = "a2l0"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(old.manifest_digest, None);
+        assert_eq!(old.pinned_uri(), None);
+
+        // Entries from older locks match the same image locked with its manifest digest.
+        let digest = format!("sha256:{}", "0".repeat(64));
+        let new = LockedImage {
+            manifest_digest: Some(digest.clone()),
+            ..old.clone()
+        };
+        assert_eq!(old, new);
+        assert_ne!(
+            new,
+            LockedImage {
+                manifest_digest: Some(format!("sha256:{}", "1".repeat(64))),
+                ..new.clone()
+            }
+        );
+        assert_eq!(
+            new.pinned_uri().unwrap(),
+            format!("public.ecr.aws/bottlerocket/core-kit@{digest}")
+        );
+        assert!(toml::to_string(&new)
+            .unwrap()
+            .contains(&format!("manifest-digest = \"{digest}\"")));
+    }
+
+    /// Writes a blob named by `digest` into the OCI layout at `layout`.
+    fn write_blob(layout: &Path, digest: &str, contents: &str) {
+        let (algorithm, encoded) = digest.split_once(':').unwrap();
+        let dir = layout.join("blobs").join(algorithm);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(encoded), contents).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pull_platform_image_offline() {
+        let oci_dir = tempfile::TempDir::new().unwrap();
+        let layout = oci_dir.path().join("example.com/bottlerocket-sdk");
+        let (list, manifest, config, layer) = ("sha256:aa", "sha256:bb", "sha256:cc", "sha256:dd");
+        std::fs::create_dir_all(&layout).unwrap();
+        std::fs::write(
+            layout.join("index.json"),
+            format!(
+                r#"{{"manifests":[{{"digest":"{list}","annotations":{{"org.opencontainers.image.ref.name":"v0.50.0"}}}}]}}"#
+            ),
+        )
+        .unwrap();
+        write_blob(
+            &layout,
+            list,
+            &format!(
+                r#"{{"manifests":[{{"digest":"{manifest}","platform":{{"architecture":"amd64"}}}}]}}"#
+            ),
+        );
+        write_blob(
+            &layout,
+            manifest,
+            &format!(r#"{{"config":{{"digest":"{config}"}},"layers":[{{"digest":"{layer}"}}]}}"#),
+        );
+        write_blob(&layout, config, "{}");
+        write_blob(&layout, layer, "layer");
+
+        let image_tool = ImageTool::oci_layout_dir(oci_dir.path());
+        let out = tempfile::TempDir::new().unwrap();
+        let uri = ImageUri::new(
+            Some("example.com".to_string()),
+            "bottlerocket-sdk",
+            "v0.50.0",
+        );
+        let pulled = out.path().join("sdk");
+        pull_platform_image(&image_tool, &uri, "x86_64", &pulled)
+            .await
+            .unwrap();
+        let index = std::fs::read_to_string(pulled.join("index.json")).unwrap();
+        assert!(index.contains(manifest), "{index}");
+        assert_eq!(
+            std::fs::read_to_string(pulled.join("blobs/sha256/dd")).unwrap(),
+            "layer"
+        );
+
+        let missing = ImageUri::new(
+            Some("example.com".to_string()),
+            "bottlerocket-sdk",
+            "v0.51.0",
+        );
+        let err = pull_platform_image(&image_tool, &missing, "x86_64", &out.path().join("missing"))
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("is not in the OCI layout directory"),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pull_platform_image_single_manifest() {
+        let oci_dir = tempfile::TempDir::new().unwrap();
+        let layout = oci_dir.path().join("example.com/bottlerocket-sdk");
+        let (config, layer) = ("sha256:cc", "sha256:dd");
+        let manifest = format!(
+            r#"{{"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"digest":"{config}"}},"layers":[{{"digest":"{layer}"}}]}}"#
+        );
+        // The manifest is not in canonical form, so its digest is not that of the manifest which
+        // is read.
+        let digest = format!("sha256:{}", hex::encode(sha2::Sha256::digest(&manifest)));
+        std::fs::create_dir_all(&layout).unwrap();
+        std::fs::write(
+            layout.join("index.json"),
+            format!(
+                r#"{{"manifests":[{{"digest":"{digest}","annotations":{{"org.opencontainers.image.ref.name":"v0.50.0"}}}}]}}"#
+            ),
+        )
+        .unwrap();
+        write_blob(&layout, &digest, &manifest);
+        write_blob(
+            &layout,
+            config,
+            r#"{"architecture":"amd64","config":{"Labels":{}}}"#,
+        );
+        write_blob(&layout, layer, "layer");
+
+        // The image manifest is read as a manifest list of itself, for the config's architecture.
+        let image_tool = ImageTool::oci_layout_dir(oci_dir.path());
+        let out = tempfile::TempDir::new().unwrap();
+        let uri = ImageUri::new(
+            Some("example.com".to_string()),
+            "bottlerocket-sdk",
+            "v0.50.0",
+        );
+        pull_platform_image(&image_tool, &uri, "x86_64", &out.path().join("sdk"))
+            .await
+            .unwrap();
+        let index = std::fs::read_to_string(out.path().join("sdk/index.json")).unwrap();
+        assert!(index.contains(&digest), "{index}");
+
+        let err = pull_platform_image(&image_tool, &uri, "aarch64", &out.path().join("arm"))
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("could not find image for architecture 'arm64'"),
+            "{err:#}"
+        );
+    }
 }
@@ -0,0 +1,368 @@
+//! Detects kit cache entries which were tampered with or corrupted after they were written, e.g.
+//! on a cache volume shared between CI jobs.
+//!
+//! Protection is enabled by pointing `TWOLITER_CACHE_KEY_FILE` at a file holding a secret key.
+//! Each extracted kit is then sealed with an HMAC-SHA256 over the digest it was extracted from,
+//! the type and mode of every file and directory in it, and the contents of its files, and the seal
+//! is checked whenever the kit would be reused. Pulled
+//! archives need no key since they are content addressed: their index must point at the wanted
+//! digest, and every blob must hash to its name. Entries that fail these checks are fetched again.
+//!
+//! Files are hashed on as many threads as there are CPUs, or as `TWOLITER_HASH_JOBS` names.
+use super::archive::blob_path;
+use super::default_extract_jobs;
+use super::views::{IndexView, ManifestLayoutView};
+use crate::common::fs::read;
+use crate::warnings;
+use anyhow::{ensure, Context, Result};
+use hmac::{Hmac, Mac};
+use krane_static::settings;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::debug;
+
+/// The environment variable naming the file which holds the cache key.
+pub(crate) const CACHE_KEY_FILE_ENV: &str = "TWOLITER_CACHE_KEY_FILE";
+
+/// The file in an extracted kit directory which holds its seal.
+pub(crate) const SEAL_FILE: &str = ".integrity";
+
+/// The environment variable naming how many files are hashed at once.
+pub(crate) const HASH_JOBS_ENV: &str = "TWOLITER_HASH_JOBS";
+
+/// The secret key used to seal extracted kits.
+pub(crate) struct CacheKey(Vec<u8>);
+
+impl CacheKey {
+    /// Loads the key named by `TWOLITER_CACHE_KEY_FILE`, or returns `None` if it is unset, in
+    /// which case cache entries are trusted as they are.
+    pub(crate) async fn from_env() -> Result<Option<Self>> {
+        let Some(path) = settings::var_os(CACHE_KEY_FILE_ENV) else {
+            return Ok(None);
+        };
+        let key = read(&path).await.context(format!(
+            "failed to read the cache key named by {CACHE_KEY_FILE_ENV}"
+        ))?;
+        ensure!(
+            key.len() >= 16,
+            "the cache key in '{}' must be at least 16 bytes long",
+            Path::new(&path).display()
+        );
+        Ok(Some(Self(key)))
+    }
+
+    /// Seals the kit extracted into `dir` from the image with `digest`.
+    pub(crate) fn seal_dir(&self, dir: &Path, digest: &str) -> Result<()> {
+        let seal = self.seal(digest, &tree_modes(dir)?, &hash_tree(dir)?);
+        let seal_file = dir.join(SEAL_FILE);
+        std::fs::write(&seal_file, hex::encode(seal))
+            .context(format!("failed to write seal to '{}'", seal_file.display()))
+    }
+
+    /// Checks that the kit extracted into `dir` is unchanged since it was sealed.
+    pub(crate) fn verify_dir(&self, dir: &Path, digest: &str) -> Result<bool> {
+        let Ok(seal) = std::fs::read_to_string(dir.join(SEAL_FILE)) else {
+            debug!(dir = %dir.display(), "Extracted kit has no seal");
+            return Ok(false);
+        };
+        let Ok(seal) = hex::decode(seal.trim()) else {
+            return Ok(false);
+        };
+        Ok(self
+            .mac(digest, &tree_modes(dir)?, &hash_tree(dir)?)
+            .verify_slice(&seal)
+            .is_ok())
+    }
+
+    fn seal(
+        &self,
+        digest: &str,
+        modes: &BTreeMap<PathBuf, String>,
+        files: &BTreeMap<PathBuf, String>,
+    ) -> Vec<u8> {
+        self.mac(digest, modes, files)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+
+    /// Covers every entry by its type and mode, and files and symlinks by their contents too.
+    fn mac(
+        &self,
+        digest: &str,
+        modes: &BTreeMap<PathBuf, String>,
+        files: &BTreeMap<PathBuf, String>,
+    ) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC accepts keys of any length");
+        mac.update(digest.as_bytes());
+        for (path, mode) in modes {
+            mac.update(b"\n");
+            mac.update(path.to_string_lossy().as_bytes());
+            mac.update(b"\0");
+            mac.update(mode.as_bytes());
+            mac.update(b"\0");
+            if let Some(hash) = files.get(path) {
+                mac.update(hash.as_bytes());
+            }
+        }
+        mac
+    }
+}
+
+/// The type and mode of `dir` and of each entry within it, keyed by path relative to `dir`, so that
+/// changed permissions and added directories are caught as well as changed contents.
+fn tree_modes(dir: &Path) -> Result<BTreeMap<PathBuf, String>> {
+    let mut modes = BTreeMap::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let relative = path.strip_prefix(dir).unwrap_or(&path).to_path_buf();
+        if relative == Path::new(SEAL_FILE) {
+            continue;
+        }
+        let metadata = std::fs::symlink_metadata(&path)
+            .context(format!("failed to read metadata of '{}'", path.display()))?;
+        let kind = if metadata.is_dir() {
+            let entries = std::fs::read_dir(&path)
+                .context(format!("failed to read directory '{}'", path.display()))?;
+            for entry in entries {
+                pending.push(
+                    entry
+                        .context(format!("failed to read directory '{}'", path.display()))?
+                        .path(),
+                );
+            }
+            "dir"
+        } else if metadata.is_symlink() {
+            "symlink"
+        } else if metadata.is_file() {
+            "file"
+        } else {
+            "other"
+        };
+        modes.insert(relative, format!("{kind} {}", mode(&metadata)));
+    }
+    Ok(modes)
+}
+
+/// The permission bits of an entry, including the setuid, setgid and sticky bits.
+#[cfg(unix)]
+fn mode(metadata: &std::fs::Metadata) -> String {
+    use std::os::unix::fs::PermissionsExt;
+    format!("{:o}", metadata.permissions().mode() & 0o7777)
+}
+
+/// Whether an entry is read-only, which is all of its mode that is portable.
+#[cfg(not(unix))]
+fn mode(metadata: &std::fs::Metadata) -> String {
+    if metadata.permissions().readonly() {
+        "ro".to_string()
+    } else {
+        "rw".to_string()
+    }
+}
+
+/// Hashes each file within `dir`, keyed by its path relative to `dir`. Symlinks are hashed by
+/// their target.
+pub(crate) fn hash_tree(dir: &Path) -> Result<BTreeMap<PathBuf, String>> {
+    let mut hashes = BTreeMap::new();
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let entries = std::fs::read_dir(&current)
+            .context(format!("failed to read directory '{}'", current.display()))?;
+        for entry in entries {
+            let path = entry
+                .context(format!("failed to read directory '{}'", current.display()))?
+                .path();
+            let relative = path.strip_prefix(dir).unwrap_or(&path).to_path_buf();
+            if relative == Path::new(SEAL_FILE) {
+                continue;
+            }
+            let metadata = std::fs::symlink_metadata(&path)
+                .context(format!("failed to read metadata of '{}'", path.display()))?;
+            if metadata.is_dir() {
+                pending.push(path);
+            } else if metadata.is_symlink() {
+                let target = std::fs::read_link(&path)
+                    .context(format!("failed to read link '{}'", path.display()))?;
+                let hash = hex::encode(Sha256::digest(target.to_string_lossy().as_bytes()));
+                hashes.insert(relative, hash);
+            } else {
+                files.push((relative, path));
+            }
+        }
+    }
+    let paths = files
+        .iter()
+        .map(|(_, path)| path.as_path())
+        .collect::<Vec<_>>();
+    for ((relative, _), hash) in files.iter().zip(hash_files(&paths)?) {
+        hashes.insert(relative.clone(), hash);
+    }
+    Ok(hashes)
+}
+
+pub(crate) fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).context(format!("failed to open '{}'", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).context(format!("failed to read '{}'", path.display()))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Hashes each of `paths` on up to [`hash_jobs`] threads, returning the hashes in the same order.
+pub(crate) fn hash_files(paths: &[&Path]) -> Result<Vec<String>> {
+    let jobs = hash_jobs().min(paths.len());
+    if jobs <= 1 {
+        return paths.iter().map(|path| hash_file(path)).collect();
+    }
+    // Each thread takes the next file not yet taken, so a few large files don't hold up the rest.
+    let next = AtomicUsize::new(0);
+    let mut hashes = std::thread::scope(|scope| {
+        let workers = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut hashed = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(path) = paths.get(index) else {
+                            return hashed;
+                        };
+                        hashed.push((index, hash_file(path)));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("hashing thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    hashes.sort_by_key(|(index, _)| *index);
+    hashes.into_iter().map(|(_, hash)| hash).collect()
+}
+
+/// How many files are hashed at once: the number in `TWOLITER_HASH_JOBS`, or one per CPU.
+pub(crate) fn hash_jobs() -> usize {
+    let Ok(jobs) = settings::var(HASH_JOBS_ENV) else {
+        return default_extract_jobs();
+    };
+    match jobs.parse::<usize>() {
+        Ok(jobs) if jobs > 0 => jobs,
+        _ => {
+            warnings::warn(format!(
+                "Ignoring {HASH_JOBS_ENV}='{jobs}', which is not a positive number"
+            ));
+            default_extract_jobs()
+        }
+    }
+}
+
+/// Checks that the OCI archive at `archive_path` holds the image with `digest`, and that none of
+/// its blobs have changed.
+pub(crate) async fn verify_archive(archive_path: &Path, digest: &str) -> Result<bool> {
+    let Ok(index_bytes) = read(archive_path.join("index.json")).await else {
+        return Ok(false);
+    };
+    let Ok(index) = serde_json::from_slice::<IndexView>(&index_bytes) else {
+        return Ok(false);
+    };
+    let Some(manifest) = index.manifests.first() else {
+        return Ok(false);
+    };
+    if manifest.digest != digest || !blob_matches(archive_path, &manifest.digest)? {
+        return Ok(false);
+    }
+    let manifest_bytes = read(blob_path(archive_path, &manifest.digest)).await?;
+    let Ok(layout) = serde_json::from_slice::<ManifestLayoutView>(&manifest_bytes) else {
+        return Ok(false);
+    };
+    let layers = layout
+        .layers
+        .iter()
+        .map(|layer| blob_path(archive_path, &layer.digest.to_string()))
+        .collect::<Vec<_>>();
+    if !layers.iter().all(|path| path.is_file()) {
+        return Ok(false);
+    }
+    let paths = layers.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+    let hashes = hash_files(&paths)?;
+    Ok(layout
+        .layers
+        .iter()
+        .zip(hashes)
+        .all(|(layer, hash)| layer.digest.to_string().trim_start_matches("sha256:") == hash))
+}
+
+fn blob_matches(archive_path: &Path, digest: &str) -> Result<bool> {
+    let path = blob_path(archive_path, digest);
+    if !path.is_file() {
+        return Ok(false);
+    }
+    let expected = digest.trim_start_matches("sha256:");
+    Ok(hash_file(&path)? == expected)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seal_detects_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("Packages")).unwrap();
+        std::fs::write(dir.path().join("Packages/a.rpm"), "a").unwrap();
+        let key = CacheKey(b"0123456789abcdef".to_vec());
+
+        key.seal_dir(dir.path(), "sha256:1").unwrap();
+        assert!(key.verify_dir(dir.path(), "sha256:1").unwrap());
+        assert!(!key.verify_dir(dir.path(), "sha256:2").unwrap());
+
+        std::fs::write(dir.path().join("Packages/a.rpm"), "b").unwrap();
+        assert!(!key.verify_dir(dir.path(), "sha256:1").unwrap());
+
+        key.seal_dir(dir.path(), "sha256:1").unwrap();
+        std::fs::create_dir(dir.path().join("empty")).unwrap();
+        assert!(!key.verify_dir(dir.path(), "sha256:1").unwrap());
+        std::fs::remove_dir(dir.path().join("empty")).unwrap();
+        assert!(key.verify_dir(dir.path(), "sha256:1").unwrap());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let rpm = dir.path().join("Packages/a.rpm");
+            let mode = std::fs::metadata(&rpm).unwrap().permissions().mode();
+            std::fs::set_permissions(&rpm, std::fs::Permissions::from_mode(mode | 0o4755)).unwrap();
+            assert!(!key.verify_dir(dir.path(), "sha256:1").unwrap());
+            std::fs::set_permissions(&rpm, std::fs::Permissions::from_mode(mode)).unwrap();
+            assert!(key.verify_dir(dir.path(), "sha256:1").unwrap());
+        }
+
+        let other = CacheKey(b"fedcba9876543210".to_vec());
+        key.seal_dir(dir.path(), "sha256:1").unwrap();
+        assert!(!other.verify_dir(dir.path(), "sha256:1").unwrap());
+    }
+
+    #[test]
+    fn test_hash_files_keeps_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = (0..64)
+            .map(|i| {
+                let path = dir.path().join(i.to_string());
+                std::fs::write(&path, "x".repeat(i)).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+        let paths = paths.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+        let sequential = paths
+            .iter()
+            .map(|path| hash_file(path).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(hash_files(&paths).unwrap(), sequential);
+        assert!(hash_files(&[&dir.path().join("missing")]).is_err());
+    }
+}
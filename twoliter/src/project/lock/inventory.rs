@@ -0,0 +1,155 @@
+//! Checks that a kit was extracted intact, against the inventory of its files that `rpm2kit`
+//! embeds in each kit image's config.
+//!
+//! The inventory maps the path of every file in the kit to its sha256 hash. A layer which was
+//! truncated or corrupted in the registry or on its way from it can still unpack without error,
+//! leaving a kit whose packages fail to install much later in a build. Comparing the extracted
+//! files to the inventory catches this as soon as the kit is extracted. Kits built before the
+//! inventory was added have none, and are extracted without the check.
+use anyhow::{ensure, Context, Result};
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The OCI config label which holds the kit's inventory, as base64-encoded JSON.
+pub(crate) const KIT_INVENTORY_LABEL: &str = "dev.bottlerocket.kit.inventory.v1";
+
+/// How many problems of each kind an error lists before summarizing the rest.
+const MAX_LISTED: usize = 5;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub(crate) struct Inventory {
+    /// The sha256 hash of each file in the kit, keyed by its path within the kit.
+    files: BTreeMap<PathBuf, String>,
+}
+
+impl Inventory {
+    /// Reads the inventory from the labels of a kit image's config, or returns `None` if the kit
+    /// has none.
+    pub(crate) fn from_labels<'a>(
+        mut labels: impl Iterator<Item = (&'a String, &'a String)>,
+    ) -> Result<Option<Self>> {
+        let Some((_, encoded)) = labels.find(|(label, _)| *label == KIT_INVENTORY_LABEL) else {
+            return Ok(None);
+        };
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .context("failed to decode kit inventory as base64")?;
+        serde_json::from_slice(&bytes)
+            .map(Some)
+            .context("failed to parse kit inventory json")
+    }
+
+    /// Checks the `extracted` files, keyed by path and mapped to their sha256 hashes, against the
+    /// inventory.
+    pub(crate) fn check(&self, extracted: &BTreeMap<PathBuf, String>) -> Result<()> {
+        let mut missing = Vec::new();
+        let mut changed = Vec::new();
+        for (path, hash) in &self.files {
+            match extracted.get(path) {
+                Some(extracted) if extracted != hash => changed.push(path),
+                None => missing.push(path),
+                _ => {}
+            }
+        }
+        let unexpected = extracted
+            .keys()
+            .filter(|path| !self.files.contains_key(*path))
+            .collect::<Vec<_>>();
+
+        let problems = [
+            ("missing", missing),
+            ("with the wrong contents", changed),
+            ("not in its inventory", unexpected),
+        ]
+        .into_iter()
+        .filter(|(_, paths)| !paths.is_empty())
+        .map(|(kind, paths)| format!("{} file(s) {kind}: {}", paths.len(), list(&paths)))
+        .collect::<Vec<_>>();
+        ensure!(
+            problems.is_empty(),
+            "the extracted kit does not match its inventory of {} file(s), so the kit archive is \
+            likely corrupt or truncated; {}",
+            self.files.len(),
+            problems.join("; ")
+        );
+        Ok(())
+    }
+}
+
+fn list(paths: &[&PathBuf]) -> String {
+    let mut listed = paths
+        .iter()
+        .take(MAX_LISTED)
+        .map(|path| format!("'{}'", path.display()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if paths.len() > MAX_LISTED {
+        listed.push_str(&format!(" and {} more", paths.len() - MAX_LISTED));
+    }
+    listed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn files(files: &[(&str, &str)]) -> BTreeMap<PathBuf, String> {
+        files
+            .iter()
+            .map(|(path, hash)| (PathBuf::from(path), hash.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_labels() {
+        let json = r#"{"files":{"Packages/a/a.rpm":"aa","repodata/repomd.xml":"bb"}}"#;
+        let labels = [
+            (
+                "dev.bottlerocket.kit.v2".to_string(),
+                "metadata".to_string(),
+            ),
+            (
+                KIT_INVENTORY_LABEL.to_string(),
+                base64::engine::general_purpose::STANDARD.encode(json),
+            ),
+        ];
+        let inventory = Inventory::from_labels(labels.iter().map(|(k, v)| (k, v)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            inventory.files,
+            files(&[("Packages/a/a.rpm", "aa"), ("repodata/repomd.xml", "bb")])
+        );
+        assert!(
+            Inventory::from_labels(labels[..1].iter().map(|(k, v)| (k, v)))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let inventory = Inventory {
+            files: files(&[
+                ("Packages/a/a.rpm", "aa"),
+                ("Packages/b/b.rpm", "bb"),
+                ("repodata/repomd.xml", "cc"),
+            ]),
+        };
+        inventory.check(&inventory.files).unwrap();
+
+        let err = inventory
+            .check(&files(&[
+                ("Packages/a/a.rpm", "aa"),
+                ("Packages/b/b.rpm", "truncated"),
+                ("Packages/c/c.rpm", "dd"),
+            ]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("1 file(s) missing: 'repodata/repomd.xml'"));
+        assert!(err.contains("1 file(s) with the wrong contents: 'Packages/b/b.rpm'"));
+        assert!(err.contains("1 file(s) not in its inventory: 'Packages/c/c.rpm'"));
+    }
+}
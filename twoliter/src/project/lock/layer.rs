@@ -0,0 +1,198 @@
+//! Reads the layer blobs of a kit's OCI archive as tarballs, checking each against the digest its
+//! manifest records as it is read, so that a damaged cache entry, or one altered by a proxy, fails
+//! the extraction rather than producing a bad build root. The check costs no extra pass over the
+//! blob: it is hashed as the tarball is read, and whatever the tarball leaves unread is hashed
+//! when the layer is finished.
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use tar::Archive as TarArchive;
+
+/// The first bytes of a gzipped layer.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The first bytes of a layer compressed with zstd.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// A layer blob whose contents do not match the digest it is stored under.
+#[derive(Debug)]
+pub(super) struct LayerDigestMismatch {
+    path: PathBuf,
+    digest: String,
+    actual: String,
+}
+
+impl Display for LayerDigestMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "layer '{}' does not match its digest {}, but has sha256:{}; it was damaged or altered \
+             after it was pulled",
+            self.path.display(),
+            self.digest,
+            self.actual
+        )
+    }
+}
+
+impl std::error::Error for LayerDigestMismatch {}
+
+/// Hashes a layer blob as it is read.
+pub(super) struct VerifyingReader {
+    file: File,
+    hasher: Sha256,
+    path: PathBuf,
+    digest: String,
+}
+
+impl Read for VerifyingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl VerifyingReader {
+    /// Reads the rest of the blob and checks it against its digest.
+    fn verify(mut self) -> Result<()> {
+        io::copy(&mut self, &mut io::sink())
+            .context(format!("failed to read '{}'", self.path.display()))?;
+        let actual = hex::encode(self.hasher.finalize());
+        if self.digest.strip_prefix("sha256:") != Some(actual.as_str()) {
+            return Err(LayerDigestMismatch {
+                path: self.path,
+                digest: self.digest,
+                actual,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// A layer blob, decompressed as it is read if it is compressed.
+pub(super) enum LayerReader {
+    Plain(VerifyingReader),
+    Gzipped(GzDecoder<BufReader<VerifyingReader>>),
+    Zstd(zstd::Decoder<'static, BufReader<VerifyingReader>>),
+}
+
+impl Read for LayerReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            LayerReader::Plain(reader) => reader.read(buf),
+            LayerReader::Gzipped(reader) => reader.read(buf),
+            LayerReader::Zstd(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// Opens the layer blob at `path`, which is stored under `digest`, as a tarball.
+pub(super) fn open_layer(path: &Path, digest: &str) -> Result<TarArchive<LayerReader>> {
+    if !digest.starts_with("sha256:") {
+        bail!(
+            "cannot verify layer '{}' with digest {digest}",
+            path.display()
+        );
+    }
+    // A blob which cannot be opened fails below.
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    let _ = File::open(path)
+        .and_then(|blob| blob.take(ZSTD_MAGIC.len() as u64).read_to_end(&mut magic));
+    let reader = VerifyingReader {
+        file: File::open(path).context(format!(
+            "failed to read layer of oci image at '{}'",
+            path.display()
+        ))?,
+        hasher: Sha256::new(),
+        path: path.to_path_buf(),
+        digest: digest.to_string(),
+    };
+    Ok(TarArchive::new(if magic.starts_with(&GZIP_MAGIC) {
+        LayerReader::Gzipped(GzDecoder::new(BufReader::new(reader)))
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        LayerReader::Zstd(zstd::Decoder::new(reader).context(format!(
+            "failed to read layer of oci image at '{}'",
+            path.display()
+        ))?)
+    } else {
+        LayerReader::Plain(reader)
+    }))
+}
+
+/// Finishes reading a layer opened with [`open_layer`], checking it against its digest.
+pub(super) fn finish_layer(archive: TarArchive<LayerReader>) -> Result<()> {
+    match archive.into_inner() {
+        LayerReader::Plain(reader) => reader.verify(),
+        LayerReader::Gzipped(reader) => reader.into_inner().into_inner().verify(),
+        LayerReader::Zstd(reader) => reader.finish().into_inner().verify(),
+    }
+}
+
+/// Reads the layer blob at `path` with `read`, and then checks it against `digest`. A layer which
+/// does not match its digest fails with [`LayerDigestMismatch`] even if `read` failed first, since
+/// a damaged tarball is the likelier cause.
+pub(super) fn read_layer<T>(
+    path: &Path,
+    digest: &str,
+    read: impl FnOnce(&mut TarArchive<LayerReader>) -> Result<T>,
+) -> Result<T> {
+    let mut archive = open_layer(path, digest)?;
+    let result = read(&mut archive);
+    finish_layer(archive)?;
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn layer() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "repodata/repomd.xml", "data".as_bytes())
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn entries(path: &Path, digest: &str) -> Result<usize> {
+        read_layer(path, digest, |archive| Ok(archive.entries()?.count()))
+    }
+
+    #[test]
+    fn test_read_layer() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut gzipped = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzipped.write_all(&layer()).unwrap();
+        let zstd = zstd::encode_all(layer().as_slice(), 3).unwrap();
+        for blob in [layer(), gzipped.finish().unwrap(), zstd] {
+            let path = temp_dir.path().join("layer");
+            std::fs::write(&path, &blob).unwrap();
+            let digest = format!("sha256:{:x}", Sha256::digest(&blob));
+            assert_eq!(entries(&path, &digest).unwrap(), 1);
+
+            // A blob that no longer matches its digest is caught, even if it still reads.
+            let wrong = format!("sha256:{:x}", Sha256::digest("other"));
+            let err = entries(&path, &wrong).unwrap_err();
+            assert!(err.downcast_ref::<LayerDigestMismatch>().is_some());
+
+            // Garbage appended after the tarball is caught too, though the tarball never reads it.
+            let mut extended = blob.clone();
+            extended.extend_from_slice(&[0; 4096]);
+            std::fs::write(&path, &extended).unwrap();
+            let err = entries(&path, &digest).unwrap_err();
+            assert!(err.downcast_ref::<LayerDigestMismatch>().is_some());
+        }
+    }
+}
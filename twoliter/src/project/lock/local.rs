@@ -0,0 +1,188 @@
+//! Kits which `Twoliter.override` replaces with local builds, so that a kit can be tested against
+//! a project before it is published:
+//!
+//! ```toml
+//! [my-vendor.my-kit]
+//! path = "../my-kit/build/kits/my-kit"
+//! ```
+//!
+//! The path is the directory which `twoliter build kit` writes the kit's archives to, one per
+//! architecture, and the latest archive for each architecture is used. Such a kit is locked with a
+//! `local:` source naming the directory and a digest of its metadata, so that rebuilding the kit
+//! does not make the lock stale unless its metadata changes. The lock then only works where the
+//! directory exists, so twoliter warns whenever it locks a local kit.
+use super::archive::{blob_path, unpack_layout};
+use super::views::{ImageConfigView, IndexView, ManifestLayoutView};
+use crate::common::fs::{read, remove_dir_all};
+use anyhow::{ensure, Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tempfile::TempDir;
+
+/// The prefix of the source of a kit locked from local builds.
+pub(crate) const LOCAL_SOURCE_PREFIX: &str = "local:";
+
+/// The local builds of a kit, in the directory `Twoliter.override` names.
+#[derive(Debug)]
+pub(super) struct LocalKit<'a> {
+    dir: &'a Path,
+}
+
+impl<'a> LocalKit<'a> {
+    pub(super) fn new(dir: &'a Path) -> Self {
+        Self { dir }
+    }
+
+    /// The source the kit is locked with.
+    pub(super) fn source(&self) -> String {
+        format!("{LOCAL_SOURCE_PREFIX}{}", self.dir.display())
+    }
+
+    /// The latest archive of the kit for each architecture it was built for.
+    pub(super) fn archives(&self) -> Result<BTreeMap<String, PathBuf>> {
+        let entries = std::fs::read_dir(self.dir).context(format!(
+            "failed to read the local builds of the kit in '{}'",
+            self.dir.display()
+        ))?;
+        let mut latest: BTreeMap<String, (SystemTime, PathBuf)> = BTreeMap::new();
+        for entry in entries {
+            let path = entry
+                .context(format!("failed to read '{}'", self.dir.display()))?
+                .path();
+            let Some((_, arch)) = split_archive_name(&path) else {
+                continue;
+            };
+            let modified = path
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .context(format!("failed to read '{}'", path.display()))?;
+            if !latest
+                .get(arch)
+                .is_some_and(|(newest, _)| *newest >= modified)
+            {
+                latest.insert(arch.to_string(), (modified, path.clone()));
+            }
+        }
+        ensure!(
+            !latest.is_empty(),
+            "found no kit archives in '{}'; build the kit with `twoliter build kit` first",
+            self.dir.display()
+        );
+        Ok(latest
+            .into_iter()
+            .map(|(arch, (_, path))| (arch, path))
+            .collect())
+    }
+
+    /// The architectures the kit was built for.
+    pub(super) fn architectures(&self) -> Result<Vec<String>> {
+        Ok(self.archives()?.into_keys().collect())
+    }
+
+    /// Reads the labels of the image config of the kit's latest build. Every architecture carries
+    /// the same kit metadata, so only the first is read.
+    pub(super) async fn labels(&self) -> Result<HashMap<String, String>> {
+        let archives = self.archives()?;
+        let (_, archive) = archives
+            .first_key_value()
+            .context("found no kit archives")?;
+        archive_labels(archive).await
+    }
+
+    /// Unpacks the kit's latest build for `arch` into `target`, replacing whatever was there.
+    pub(super) async fn extract(&self, target: &Path, arch: &str) -> Result<()> {
+        let archives = self.archives()?;
+        let archive = archives.get(arch).context(format!(
+            "found no build of the kit for architecture '{arch}' in '{}'",
+            self.dir.display()
+        ))?;
+        let layout = unpack_archive(archive).await?;
+        if target.exists() {
+            remove_dir_all(target).await?;
+        }
+        unpack_layout(layout.path(), target).await
+    }
+}
+
+/// Reads the labels of the image config of the kit archive at `archive`.
+pub(super) async fn archive_labels(archive: &Path) -> Result<HashMap<String, String>> {
+    let layout = unpack_archive(archive).await?;
+    let index: IndexView = serde_json::from_slice(&read(layout.path().join("index.json")).await?)
+        .context("failed to deserialize oci image index")?;
+    let digest = &index.manifests.first().context("empty oci image")?.digest;
+    let manifest: ManifestLayoutView =
+        serde_json::from_slice(&read(blob_path(layout.path(), digest)).await?)
+            .context("failed to deserialize oci manifest")?;
+    let config = manifest.config.context(format!(
+        "the kit archive '{}' has no image config",
+        archive.display()
+    ))?;
+    let config: ImageConfigView =
+        serde_json::from_slice(&read(blob_path(layout.path(), &config.digest.to_string())).await?)
+            .context("failed to deserialize oci image config")?;
+    Ok(config
+        .config
+        .labels
+        .unwrap_or_default()
+        .into_iter()
+        .collect())
+}
+
+/// Splits the name of a kit archive like `{kit}-{version}-{build_id}-{arch}.tar` into the build,
+/// `{kit}-{version}-{build_id}`, and the architecture.
+pub(super) fn split_archive_name(path: &Path) -> Option<(&str, &str)> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".tar")?;
+    name.rsplit_once('-')
+        .filter(|(build, arch)| !build.is_empty() && !arch.is_empty())
+}
+
+/// Unpacks the OCI archive at `archive` into a temporary directory.
+async fn unpack_archive(archive: &Path) -> Result<TempDir> {
+    let archive = archive.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let dir = TempDir::new().context("failed to create a temporary directory")?;
+        let file = std::fs::File::open(&archive)
+            .context(format!("failed to open '{}'", archive.display()))?;
+        tar::Archive::new(file)
+            .unpack(dir.path())
+            .context(format!("failed to unpack '{}'", archive.display()))?;
+        Ok(dir)
+    })
+    .await
+    .context("failed to join the task unpacking the kit archive")?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use filetime::FileTime;
+
+    #[test]
+    fn test_archives() {
+        let dir = TempDir::new().unwrap();
+        let archive = |name: &str, mtime: i64| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, "").unwrap();
+            filetime::set_file_mtime(&path, FileTime::from_unix_time(mtime, 0)).unwrap();
+            path
+        };
+        archive("my-kit-1.0.0-abc-x86_64.tar", 100);
+        let latest = archive("my-kit-1.0.0-def-x86_64.tar", 200);
+        let aarch64 = archive("my-kit-1.0.0-abc-aarch64.tar", 100);
+        archive("notes.txt", 300);
+
+        let kit = LocalKit::new(dir.path());
+        assert_eq!(
+            kit.archives().unwrap(),
+            BTreeMap::from([
+                ("aarch64".to_string(), aarch64),
+                ("x86_64".to_string(), latest),
+            ])
+        );
+        assert_eq!(kit.source(), format!("local:{}", dir.path().display()));
+
+        let empty = TempDir::new().unwrap();
+        assert!(LocalKit::new(empty.path()).archives().is_err());
+    }
+}
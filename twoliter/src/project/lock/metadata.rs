@@ -0,0 +1,235 @@
+//! Reads the versions, architectures, end-of-life dates and dependencies of a project's kits and
+//! SDK from their registries without locking them, for dashboards which poll many projects. Only
+//! manifests and kit metadata are fetched: digests are not computed, signatures are not verified,
+//! and neither Twoliter.lock nor the kit cache is read or written.
+use super::image::{ImageMetadata, ImageResolver};
+use crate::diagnostic::Code;
+use crate::messages::msg;
+use crate::output::{Output, OutputSchema, RESOLVE_METADATA_SCHEMA};
+use crate::project::{Project, ProjectImage, Unlocked, ValidIdentifier};
+use anyhow::{ensure, Context, Result};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use oci_cli_wrapper::ImageTool;
+use semver::Version;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::mem::take;
+use tracing::{debug, info};
+
+/// What a registry serves for one of the project's images.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ImageSummary {
+    name: String,
+    version: String,
+    vendor: String,
+    /// Where the image was found, which is the image itself or one of its mirrors.
+    uri: String,
+    architectures: Vec<String>,
+    /// The date after which the image is no longer supported, if its publisher announced one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_of_life: Option<String>,
+    /// The SDK a kit was built with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sdk: Option<String>,
+    /// The kits a kit depends on.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    kits: Vec<String>,
+}
+
+/// The project's SDK and every kit it depends on, directly or through other kits, as their
+/// registries serve them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct MetadataReport {
+    sdk: ImageSummary,
+    kits: Vec<ImageSummary>,
+}
+
+impl MetadataReport {
+    /// Reads the metadata of the project's kits, and of the kits they depend on, with up to `jobs`
+    /// kits read at once, then the manifest list of the one SDK they share.
+    pub(crate) async fn resolve(project: &Project<Unlocked>, jobs: usize) -> Result<Self> {
+        info!("Reading the metadata of project references without locking them");
+        let image_tool = ImageTool::from_env();
+        let mut known: HashMap<(ValidIdentifier, ValidIdentifier), Version> = HashMap::new();
+        let mut kits = Vec::new();
+        let mut remaining = project.direct_kit_deps()?;
+
+        let mut sdk_set = HashSet::new();
+        if let Some(sdk) = project.direct_sdk_image_dep() {
+            sdk_set.insert(sdk?);
+        }
+        while !remaining.is_empty() {
+            let mut unread = Vec::new();
+            for image in take(&mut remaining) {
+                let key = (image.name().clone(), image.vendor_name().clone());
+                if let Some(version) = known.get(&key) {
+                    ensure!(
+                        image.version() == version,
+                        Code::MultipleKitVersions.error(msg!(
+                            "error.multiple-kit-versions",
+                            name = image.name().clone(),
+                            left_version = image.version().clone(),
+                            version = version,
+                            vendor = image.vendor_name().clone(),
+                        ))
+                    );
+                    continue;
+                }
+                known.insert(key, image.version().clone());
+                unread.push(image);
+            }
+
+            let read: Vec<_> = stream::iter(unread)
+                .map(|image| {
+                    let image_tool = &image_tool;
+                    async move { read_kit(image_tool, &image).await }
+                })
+                .buffered(jobs.max(1))
+                .try_collect()
+                .await?;
+            for (summary, metadata) in read {
+                sdk_set.insert(project.as_project_image(&metadata.sdk)?);
+                for dep in &metadata.kits {
+                    remaining.push(project.as_project_image(dep)?);
+                }
+                kits.push(summary);
+            }
+        }
+        ensure!(
+            sdk_set.len() <= 1,
+            Code::MultipleSdks.error(msg!(
+                "error.multiple-sdks",
+                sdks = sdk_set
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ))
+        );
+        let sdk = sdk_set
+            .into_iter()
+            .next()
+            .ok_or_else(|| Code::NoSdk.error(msg!("error.no-sdk")))?;
+        let sdk = summarize(&image_tool, &ImageResolver::from_image(&sdk)?, &sdk).await?;
+
+        Ok(Self { sdk, kits })
+    }
+}
+
+/// Reads the manifest list and embedded metadata of the kit `image`.
+async fn read_kit(
+    image_tool: &ImageTool,
+    image: &ProjectImage,
+) -> Result<(ImageSummary, ImageMetadata)> {
+    debug!(%image, "Reading the metadata of kit '{}'", image.name());
+    let resolver = ImageResolver::from_image(image)?;
+    let mut summary = summarize(image_tool, &resolver, image).await?;
+    let metadata = resolver
+        .kit_metadata(image_tool)
+        .await
+        .context(format!("failed to read the metadata of kit {image}"))?;
+    summary.sdk = Some(metadata.sdk.to_string());
+    summary.kits = metadata.kits.iter().map(ToString::to_string).collect();
+    Ok((summary, metadata))
+}
+
+/// Reads where `image` is served from and the architectures it is served for.
+async fn summarize(
+    image_tool: &ImageTool,
+    resolver: &ImageResolver,
+    image: &ProjectImage,
+) -> Result<ImageSummary> {
+    Ok(ImageSummary {
+        name: image.name().to_string(),
+        version: image.version().to_string(),
+        vendor: image.vendor_name().to_string(),
+        uri: resolver.source_uri(image_tool).await?,
+        architectures: resolver.architectures(image_tool).await?,
+        end_of_life: resolver
+            .end_of_life(image_tool)
+            .await?
+            .map(|end_of_life| end_of_life.date.format("%Y-%m-%d").to_string()),
+        sdk: None,
+        kits: Vec::new(),
+    })
+}
+
+impl Display for ImageSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}@{} ({}) [{}]",
+            self.name,
+            self.version,
+            self.vendor,
+            self.uri,
+            self.architectures.join(", ")
+        )?;
+        if let Some(end_of_life) = &self.end_of_life {
+            write!(f, "\n  end of life: {end_of_life}")?;
+        }
+        if let Some(sdk) = &self.sdk {
+            write!(f, "\n  sdk: {sdk}")?;
+        }
+        for kit in &self.kits {
+            write!(f, "\n  kit: {kit}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for MetadataReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sdk {}", self.sdk)?;
+        for kit in &self.kits {
+            write!(f, "\nkit {kit}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Output for MetadataReport {
+    const SCHEMA: OutputSchema = RESOLVE_METADATA_SCHEMA;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn summary(name: &str) -> ImageSummary {
+        ImageSummary {
+            name: name.into(),
+            version: "1.0.0".into(),
+            vendor: "bottlerocket".into(),
+            uri: format!("public.ecr.aws/bottlerocket/{name}:v1.0.0"),
+            architectures: vec!["amd64".into(), "arm64".into()],
+            end_of_life: None,
+            sdk: None,
+            kits: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_report_json() {
+        let report = MetadataReport {
+            sdk: summary("bottlerocket-sdk"),
+            kits: vec![ImageSummary {
+                end_of_life: Some("2027-06-30".into()),
+                sdk: Some("bottlerocket-sdk-1.0.0@bottlerocket".into()),
+                ..summary("core-kit")
+            }],
+        };
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["sdk"]["architectures"][1], "arm64");
+        assert!(json["sdk"].get("sdk").is_none());
+        assert!(json["sdk"].get("end-of-life").is_none());
+        assert_eq!(json["kits"][0]["end-of-life"], "2027-06-30");
+        assert!(json["kits"][0].get("kits").is_none());
+        assert_eq!(
+            json["kits"][0]["sdk"],
+            "bottlerocket-sdk-1.0.0@bottlerocket"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Reads Twoliter.lock files in the format of any earlier version of Twoliter, so that changing the
+//! format of the lock does not break older checkouts.
+//!
+//! Every lock records the `schema-version` of its format. A lock in an older format is migrated
+//! one version at a time as it is read, in memory only. It is written in the current format only
+//! by `twoliter update`, or by `twoliter lock migrate` once it has been checked against the
+//! project. A lock in a newer format than this Twoliter knows is rejected rather than misread, or
+//! overwritten by `twoliter update`.
+//!
+//! | version | format                                                             |
+//! |---------|--------------------------------------------------------------------|
+//! | 1       | The original format.                                               |
+//! | 2       | Each image may record the `manifest-digest` of its manifest list.  |
+//!
+//! A version only needs a migration if a lock in the version before it cannot be read as it is.
+//! The manifest digest is optional and cannot be derived from the digest version 1 records, so
+//! version 1 needs none; the digests are recorded when the lock is next written.
+use super::Lock;
+use crate::diagnostic::{Code, Diagnostic};
+use crate::messages::msg;
+use anyhow::{bail, ensure, Context, Result};
+use toml::{Table, Value};
+use tracing::debug;
+
+/// The version of the lock format which this Twoliter reads and writes.
+pub(crate) const LOCK_SCHEMA_VERSION: u32 = 2;
+
+/// Migrates a lock from one version of the format to the next.
+type Migration = fn(&mut Table) -> Result<()>;
+
+/// The migrations from each version of the lock format to the next, by the version they migrate
+/// from, in order. Versions which a lock in the version before can be read as are left out.
+const MIGRATIONS: &[(u32, Migration)] = &[];
+
+/// A lock read from Twoliter.lock, along with the version of the format it was written in.
+#[derive(Debug, Clone)]
+pub(super) struct ReadLock {
+    pub(super) lock: Lock,
+    pub(super) schema_version: u32,
+}
+
+impl ReadLock {
+    /// Whether the lock was written in an older format, which `twoliter lock migrate` rewrites.
+    pub(super) fn is_migrated(&self) -> bool {
+        self.schema_version < LOCK_SCHEMA_VERSION
+    }
+}
+
+/// Parses the contents of a Twoliter.lock, migrating it to the current format.
+pub(super) fn parse_lock(contents: &str) -> Result<ReadLock> {
+    let mut table: Table = toml::from_str(contents).context("failed to deserialize lockfile")?;
+    let schema_version = schema_version(&table)?;
+    ensure!(
+        schema_version <= LOCK_SCHEMA_VERSION,
+        Code::NewerLock.error(msg!(
+            "error.newer-lock",
+            version = schema_version,
+            supported = LOCK_SCHEMA_VERSION,
+        ))
+    );
+    for (from, migrate) in MIGRATIONS
+        .iter()
+        .filter(|(from, _)| *from >= schema_version)
+    {
+        debug!(
+            "Migrating Twoliter.lock from schema version {from} to {}",
+            from + 1
+        );
+        migrate(&mut table).context(format!(
+            "failed to migrate Twoliter.lock from schema version {from} to {}",
+            from + 1
+        ))?;
+    }
+    table.insert(
+        "schema-version".to_string(),
+        Value::Integer(LOCK_SCHEMA_VERSION.into()),
+    );
+    let lock = Value::Table(table)
+        .try_into()
+        .context("failed to deserialize lockfile")?;
+    Ok(ReadLock {
+        lock,
+        schema_version,
+    })
+}
+
+/// Whether `error` is from reading a lock written by a newer Twoliter, which must not be treated
+/// as if there were no lock.
+pub(super) fn is_newer_lock(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<Diagnostic>())
+        .any(|diagnostic| diagnostic.code() == Code::NewerLock)
+}
+
+fn schema_version(table: &Table) -> Result<u32> {
+    match table.get("schema-version") {
+        Some(Value::Integer(version)) if *version >= 1 => {
+            // Versions too large for a u32 are certainly newer than this Twoliter.
+            Ok(u32::try_from(*version).unwrap_or(u32::MAX))
+        }
+        Some(version) => bail!("Twoliter.lock has an invalid schema-version '{version}'"),
+        None => bail!("Twoliter.lock has no schema-version"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const V1_LOCK: &str = r#"
+schema-version = 1
+
+[sdk]
+name = "bottlerocket-sdk"
+version = "0.50.0"
+vendor = "bottlerocket"
+source = "public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0"
+digest = "c2Rr"
+
+[[kit]]
+name = "core-kit"
+version = "2.0.0"
+vendor = "bottlerocket"
+source = "public.ecr.aws/bottlerocket/core-kit:v2.0.0"
+digest = "a2l0"
+"#;
+
+    #[test]
+    fn test_migrate_v1() {
+        let read = parse_lock(V1_LOCK).unwrap();
+        assert_eq!(read.schema_version, 1);
+        assert!(read.is_migrated());
+        assert_eq!(read.lock.schema_version.get(), LOCK_SCHEMA_VERSION);
+        assert_eq!(read.lock.kit[0].digest, "a2l0");
+        assert_eq!(read.lock.kit[0].manifest_digest, None);
+
+        let rewritten = toml::to_string(&read.lock).unwrap();
+        let read = parse_lock(&rewritten).unwrap();
+        assert_eq!(read.schema_version, LOCK_SCHEMA_VERSION);
+        assert!(!read.is_migrated());
+    }
+
+    #[test]
+    fn test_newer_lock() {
+        let newer = V1_LOCK.replace("schema-version = 1", "schema-version = 99");
+        let error = parse_lock(&newer).unwrap_err();
+        assert!(is_newer_lock(&error));
+        assert!(error.to_string().contains("E0209"), "{error}");
+
+        let error = parse_lock(&V1_LOCK.replace("schema-version = 1", "")).unwrap_err();
+        assert!(!is_newer_lock(&error));
+        assert!(parse_lock(&V1_LOCK.replace("schema-version = 1", "schema-version = 0")).is_err());
+    }
+}
@@ -5,35 +5,116 @@
 
 /// Contains operations for working with an OCI Archive
 mod archive;
+/// Combines per-architecture kit images into a manifest list
+mod assemble;
+/// Drafts the changelog of a kit release
+mod changelog;
+/// Checks kits against the SDK and kit versions their publishers declare they work with
+mod compatibility;
+/// Finds the kits and projects which depend on a kit
+mod consumers;
+/// Reads deprecation notices that publishers attach to images
+mod deprecation;
+/// Computes the changes between two lock states
+mod diff;
 /// Covers resolution and validation of a single image dependency in a lock file
 mod image;
+/// Detects cached kits which were changed after they were written
+mod integrity;
+/// Checks extracted kits against the inventory of files embedded in them
+mod inventory;
+/// Reads kit layers, checking them against their digests
+mod layer;
+/// Reads kits which Twoliter.override replaces with local builds
+mod local;
+/// Reads the metadata of the project's dependencies without locking them
+mod metadata;
+/// Reads lock files in older formats, migrating them to the current one
+mod migrate;
+/// Finds lock entries that no longer correspond to the project
+mod orphan;
+/// Finds paths in kits that the filesystem they are extracted onto cannot hold
+mod path_check;
+/// Checks that registries still serve the images the lock records
+mod registry_check;
+/// Limits an update to some of the project's dependencies
+mod scope;
+/// Copies the locked images into a directory of OCI layouts for offline builds
+mod snapshot;
+/// Selects the kits needed to build a single variant
+mod sparse;
+/// Counts the bytes of each kit pulled and extracted, to report their progress
+mod transfer;
 /// Provides tools for marking artifacts as having been verified against the Twoliter lockfile
 mod verification;
 /// Implements view models of common OCI manifest and configuration types
 mod views;
-
+/// Marks extracted kits that are changed after extraction as stale
+mod watch;
+
+pub(crate) use self::archive::unpack_layout;
+pub use self::archive::{Attributes, ExtractOptions};
+pub(crate) use self::assemble::{assemble_kit, check_kit_archives};
+pub(crate) use self::changelog::{parse_rpm_name, RELEASE_NOTES_ANNOTATION};
+pub(crate) use self::compatibility::{CompatibilityMatrix, COMPATIBILITY_ANNOTATION};
+pub(crate) use self::consumers::{kit_consumers, ConsumerSources};
+pub(crate) use self::diff::LockDiff;
+pub(crate) use self::integrity::{hash_file, hash_tree};
+pub(crate) use self::metadata::MetadataReport;
+pub(crate) use self::migrate::LOCK_SCHEMA_VERSION;
+pub(crate) use self::registry_check::LockCheck;
+pub(crate) use self::scope::UpdateScope;
+pub(crate) use self::snapshot::VendorReport;
 pub(crate) use self::verification::VerificationTagger;
-pub(crate) use image::LockedImage;
+pub(crate) use self::watch::watch_kits;
+pub(crate) use image::{pull_platform_image, LockedImage};
 
+use crate::api::{Progress, ProgressFn};
 use crate::common::fs::{create_dir_all, read, write};
-use crate::project::{Project, ValidIdentifier};
+use crate::diagnostic::Code;
+use crate::messages::msg;
+use crate::project::cache::CacheReport;
+use crate::project::store::SystemStore;
+use crate::project::{Project, ProjectImage, ValidIdentifier};
 use crate::schema_version::SchemaVersion;
 use anyhow::{bail, ensure, Context, Result};
-use image::ImageResolver;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use image::{ImageMetadata, ImageResolver};
+use integrity::CacheKey;
+use migrate::{is_newer_lock, parse_lock, ReadLock};
+use oci_cli_wrapper::layout::oci_dir_from_env;
 use oci_cli_wrapper::ImageTool;
 use olpc_cjson::CanonicalFormatter as CanonicalJsonFormatter;
+use orphan::Declared;
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Debug;
 use std::mem::take;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use tokio::fs::read_to_string;
 use tracing::{debug, error, info, instrument};
 
 use super::{Locked, ProjectLock, Unlocked};
 
-const TWOLITER_LOCK: &str = "Twoliter.lock";
+pub(super) const TWOLITER_LOCK: &str = "Twoliter.lock";
+
+/// Whether images are read from a directory of OCI layouts rather than registries. Signatures
+/// cannot be verified offline, so locks are then only checked against the digests they record,
+/// which were verified when they were locked.
+fn offline() -> bool {
+    oci_dir_from_env().is_some()
+}
+
+/// How many kits are resolved against their registries at once by default.
+pub(crate) const DEFAULT_RESOLVE_JOBS: usize = 8;
+
+/// How many kits are extracted at once by default, which is one per CPU.
+pub(crate) fn default_extract_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
 
 #[derive(Serialize, Debug)]
 struct ExternalKitMetadata {
@@ -47,6 +128,9 @@ struct ExternalKitMetadata {
 pub(crate) struct Override {
     pub name: Option<String>,
     pub registry: Option<String>,
+    /// A directory of local builds of the kit, which are used instead of its published image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
 }
 
 /// A resolved and locked project SDK, typically from the Twoliter.lock file for a project.
@@ -68,7 +152,7 @@ impl LockedSDK {
         info!("Resolving SDK project reference to check against lock file");
 
         let current_lock = Lock::current_lock_state(project).await?;
-        let resolved_lock = Self::resolve_sdk(project)
+        let resolved_lock = Self::resolve_sdk(project, &[&current_lock.sdk])
             .await?
             .context("Project does not have explicit SDK image.")?;
 
@@ -83,17 +167,21 @@ impl LockedSDK {
                 resolved_sdk=?resolved_lock,
                 "Locked SDK does not match resolved SDK",
             );
-            bail!("Changes have occured to Twoliter.toml or the remote SDK image that require an update to Twoliter.lock");
+            bail!(Code::StaleLock.error(msg!("error.stale-lock-sdk")));
         }
 
         Ok(resolved_lock)
     }
 
-    /// Creates a project lock referring to only the resolved SDK image from the project.
+    /// Creates a project lock referring to only the resolved SDK image from the project. The SDK
+    /// is kept as `kept` locks it where it locks the same image, as in `Lock::resolve`.
     ///
     /// Returns `None` if the project does not have an explicit SDK image.
     #[instrument(level = "trace", skip(project))]
-    async fn resolve_sdk(project: &Project<Unlocked>) -> Result<Option<Self>> {
+    async fn resolve_sdk(
+        project: &Project<Unlocked>,
+        kept: &[&LockedImage],
+    ) -> Result<Option<Self>> {
         debug!("Attempting to resolve workspace SDK");
         let sdk = match project.direct_sdk_image_dep() {
             Some(sdk) => sdk?,
@@ -104,11 +192,9 @@ impl LockedSDK {
         };
 
         debug!(?sdk, "Resolving workspace SDK");
-        ImageResolver::from_image(&sdk)?
-            .skip_metadata_retrieval() // SDKs don't have metadata
-            .resolve(&ImageTool::krane())
-            .await
-            .map(|(sdk, _)| Some(Self(sdk)))
+        let (sdk, _metadata) =
+            resolve_image(&sdk, kept, offline(), true, &ImageTool::from_env()).await?;
+        Ok(Some(Self(sdk)))
     }
 }
 
@@ -116,8 +202,8 @@ impl LockedSDK {
 #[derive(Debug, Clone, Eq, Ord, PartialOrd, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) struct Lock {
-    /// The version of the Twoliter.toml this was generated from
-    pub schema_version: SchemaVersion<1>,
+    /// The version of the lock's format
+    pub schema_version: SchemaVersion<LOCK_SCHEMA_VERSION>,
     /// The resolved bottlerocket sdk
     pub sdk: LockedImage,
     /// Resolved kit dependencies
@@ -135,30 +221,94 @@ impl PartialEq for Lock {
 #[allow(dead_code)]
 impl Lock {
     #[instrument(level = "trace", skip(project))]
-    pub(super) async fn create(project: &Project<Unlocked>) -> Result<Self> {
-        let lock_file_path = project.project_dir().join(TWOLITER_LOCK);
-
+    pub(super) async fn create(project: &Project<Unlocked>, jobs: usize) -> Result<Self> {
         info!("Resolving project references to create lock file");
-        let lock_state = Self::resolve(project).await?;
-        let lock_str = toml::to_string(&lock_state).context("failed to serialize lock file")?;
+        let lock_state = Self::resolve(project, jobs, false, &[]).await?;
+        lock_state.write(project).await?;
+        Ok(lock_state)
+    }
+
+    async fn write(&self, project: &Project<Unlocked>) -> Result<()> {
+        let lock_file_path = project.project_dir().join(TWOLITER_LOCK);
+        let lock_str = toml::to_string(self).context("failed to serialize lock file")?;
 
         debug!("Writing new lock file to '{}'", lock_file_path.display());
         write(&lock_file_path, lock_str)
             .await
-            .context("failed to write lock file")?;
-        Ok(lock_state)
+            .context("failed to write lock file")
+    }
+
+    /// Resolves the project's dependencies and writes a new lock file, returning the changes made
+    /// relative to the existing lock file.
+    ///
+    /// A missing or unreadable existing lock file is treated as empty. Up to `jobs` kits are
+    /// resolved at once. Entries outside the `scope` are kept from the existing lock file where
+    /// they still lock the same version, and only the images in the scope are resolved afresh.
+    #[instrument(level = "trace", skip(project))]
+    pub(super) async fn update(
+        project: &Project<Unlocked>,
+        jobs: usize,
+        scope: &UpdateScope,
+    ) -> Result<(Self, LockDiff)> {
+        let previous_lock = Self::previous_lock_state(project).await?;
+        info!("Resolving project references to update lock file");
+        let lock = Self::resolve(project, jobs, false, &scope.kept(previous_lock.as_ref())).await?;
+        scope.check(&lock)?;
+        lock.write(project).await?;
+        let diff = LockDiff::between(previous_lock.as_ref(), &lock);
+        Ok((lock, diff))
+    }
+
+    /// Resolves the project's dependencies and returns the changes that updating would make to
+    /// the lock file, without writing it.
+    #[instrument(level = "trace", skip(project))]
+    pub(super) async fn preview_update(
+        project: &Project<Unlocked>,
+        jobs: usize,
+        scope: &UpdateScope,
+    ) -> Result<LockDiff> {
+        let previous_lock = Self::previous_lock_state(project).await?;
+        info!("Resolving project references to preview changes to the lock file");
+        let lock = Self::resolve(project, jobs, false, &scope.kept(previous_lock.as_ref())).await?;
+        scope.check(&lock)?;
+        Ok(LockDiff::between(previous_lock.as_ref(), &lock))
+    }
+
+    /// Returns the state of the existing lockfile, or `None` if it is missing or unreadable. A
+    /// lockfile written by a newer Twoliter is an error, so that it is not overwritten.
+    async fn previous_lock_state(project: &Project<Unlocked>) -> Result<Option<Self>> {
+        match Self::current_lock_state(project).await {
+            Ok(lock) => Ok(Some(lock)),
+            Err(e) if is_newer_lock(&e) => Err(e),
+            Err(e) => {
+                debug!("Unable to load existing lock file, treating it as empty: {e:?}");
+                Ok(None)
+            }
+        }
     }
 
     /// Loads the lockfile for the given project.
     ///
     /// Re-resolves the project's dependencies to ensure that the lockfile matches the state of the
-    /// world.
+    /// world. The images the lockfile already locks are read by the registry digests they were
+    /// locked with, so an entry whose tag has since moved, such as one a scoped update kept, still
+    /// matches. A lockfile in an older format is migrated in memory only, and is left as it is on
+    /// disk until `twoliter update` or `twoliter lock migrate` writes it.
     #[instrument(level = "trace", skip(project))]
     pub(super) async fn load(project: &Project<Unlocked>) -> Result<Self> {
         info!("Resolving project references to check against lock file");
 
-        let current_lock = Self::current_lock_state(project).await?;
-        let resolved_lock = Self::resolve(project).await?;
+        let ReadLock {
+            lock: current_lock,
+            schema_version,
+        } = Self::read_lock_state(project).await?;
+        let resolved_lock = Self::resolve(
+            project,
+            DEFAULT_RESOLVE_JOBS,
+            offline(),
+            &current_lock.entries(),
+        )
+        .await?;
 
         debug!(
             current_lock=?current_lock,
@@ -171,26 +321,88 @@ impl Lock {
                 resolved_lock=?resolved_lock,
                 "Locked dependencies do not match resolved dependencies"
             );
-            bail!("changes have occured to Twoliter.toml or the remote kit images that require an update to Twoliter.lock");
+            bail!(Code::StaleLock.error(msg!("error.stale-lock-kits")));
+        }
+        if schema_version < LOCK_SCHEMA_VERSION {
+            info!(
+                "Twoliter.lock is in schema version {schema_version}; run `twoliter lock migrate` \
+                to rewrite it in schema version {LOCK_SCHEMA_VERSION}"
+            );
         }
 
         Ok(resolved_lock)
     }
 
+    /// Rewrites a lockfile in an older format in the current one, once it has been checked
+    /// against the project's dependencies, resolving up to `jobs` kits at once. Returns the
+    /// version of the format it was written in, or `None` if it was already current and has been
+    /// left as it is.
+    #[instrument(level = "trace", skip(project))]
+    pub(super) async fn migrate(project: &Project<Unlocked>, jobs: usize) -> Result<Option<u32>> {
+        let read = Self::read_lock_state(project).await?;
+        if !read.is_migrated() {
+            return Ok(None);
+        }
+        info!("Resolving project references to migrate lock file");
+        let resolved_lock = Self::resolve(project, jobs, offline(), &read.lock.entries()).await?;
+        ensure!(
+            read.lock == resolved_lock,
+            Code::StaleLock.error(msg!("error.stale-lock-kits"))
+        );
+        resolved_lock.write(project).await?;
+        Ok(Some(read.schema_version))
+    }
+
+    /// Checks that the registries of each image in the project's lock file still serve the image
+    /// it records. Up to `jobs` images are fetched at once.
+    pub(super) async fn check_registries<L: ProjectLock>(
+        project: &Project<L>,
+        jobs: usize,
+    ) -> Result<LockCheck> {
+        let lock = Self::current_lock_state(project).await?;
+        info!("Checking the images in the lock file against their registries");
+        Ok(LockCheck::run(&lock, jobs).await)
+    }
+
     /// Returns the state of the lockfile for the given `Project`
-    async fn current_lock_state<L: ProjectLock>(project: &Project<L>) -> Result<Self> {
+    pub(super) async fn current_lock_state<L: ProjectLock>(project: &Project<L>) -> Result<Self> {
+        Ok(Self::read_lock_state(project).await?.lock)
+    }
+
+    /// Returns the state of the lockfile for the given `Project`, migrated to the current format,
+    /// along with the version of the format it was written in.
+    async fn read_lock_state<L: ProjectLock>(project: &Project<L>) -> Result<ReadLock> {
         let lock_file_path = project.project_dir().join(TWOLITER_LOCK);
         ensure!(
             lock_file_path.exists(),
-            "Twoliter.lock does not exist, please run `twoliter update` first"
+            Code::MissingLock.error(msg!("error.missing-lock"))
         );
         debug!("Loading existing lockfile '{}'", lock_file_path.display());
         let lock_str = read_to_string(&lock_file_path)
             .await
             .context("failed to read lockfile")?;
-        let lock: Self =
-            toml::from_str(lock_str.as_str()).context("failed to deserialize lockfile")?;
-        Ok(lock)
+        parse_lock(lock_str.as_str())
+    }
+
+    /// Returns the entries in the project's existing lock file that nothing in Twoliter.toml can
+    /// lead to. A project without a lock file has none.
+    pub(super) async fn orphaned_entries<L: ProjectLock>(
+        project: &Project<L>,
+    ) -> Result<Vec<LockedImage>> {
+        if !project.lock_file_path().exists() {
+            return Ok(Vec::new());
+        }
+        let lock = Self::current_lock_state(project).await?;
+        Ok(lock.orphans(&Declared {
+            vendors: project.vendor.keys().collect(),
+            sdk: project.sdk.as_ref(),
+            kits: &project.kit,
+        }))
+    }
+
+    /// Every entry of the lock, the SDK first.
+    fn entries(&self) -> Vec<&LockedImage> {
+        std::iter::once(&self.sdk).chain(&self.kit).collect()
     }
 
     fn external_kit_metadata(&self) -> ExternalKitMetadata {
@@ -200,28 +412,148 @@ impl Lock {
         }
     }
 
-    /// Fetches all external kits defined in a Twoliter.lock to the build directory
+    /// Fetches the external kits defined in a Twoliter.lock to the build directory, reporting
+    /// which of them could be reused from an earlier fetch. When `variant` is given, only the kits
+    /// it needs are fetched. Up to `jobs` kits are pulled and extracted at once.
     #[instrument(level = "trace", skip_all)]
-    pub(crate) async fn fetch(&self, project: &Project<Locked>, arch: &str) -> Result<()> {
+    pub(crate) async fn fetch(
+        &self,
+        project: &Project<Locked>,
+        arch: &str,
+        variant: Option<&str>,
+        options: ExtractOptions,
+        jobs: usize,
+        progress: &ProgressFn,
+    ) -> Result<CacheReport> {
+        let kits = self.kits_to_fetch(project, variant, progress).await?;
+        info!(
+            dependencies = ?kits.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "Extracting kit dependencies."
+        );
+        let key = CacheKey::from_env().await?;
+        let store = SystemStore::find();
+        let image_tool = ImageTool::from_env();
+        let kits_dir = project.external_kits_dir();
+        let images = kits
+            .iter()
+            .map(|image| locked_project_image(project, image))
+            .collect::<Result<Vec<_>>>()?;
+        let transfers = transfer::transfers(&images);
+        // Kits are independent of each other, so they are extracted side by side. The reports are
+        // still collected in the order of the kits, so that they read the same on every run.
+        let mut extracted = stream::iter(images.iter().zip(&transfers))
+            .map(|(image, (_, transfer))| {
+                let (image_tool, kits_dir, key, store) =
+                    (&image_tool, &kits_dir, key.as_ref(), store.as_ref());
+                async move {
+                    let report = ImageResolver::from_image(image)?
+                        .with_transfer(transfer)
+                        .extract(image_tool, kits_dir, arch, options, key, store)
+                        .await?;
+                    Ok::<_, anyhow::Error>((image, report))
+                }
+            })
+            .buffered(jobs.max(1));
+        let mut report = CacheReport::default();
+        let mut done = 0;
+        while let Some((image, kit_report)) =
+            transfer::reporting(extracted.try_next(), &transfers, progress).await?
+        {
+            report.extend(kit_report);
+            done += 1;
+            progress(&Progress::KitReady {
+                kit: image.to_string(),
+                done,
+                total: kits.len(),
+            });
+        }
+
+        self.synchronize_metadata(project).await?;
+        Ok(report)
+    }
+
+    /// Pulls the archives of the external kits into the kit archive cache without extracting them,
+    /// so that a later fetch can run offline. When `variant` is given, only the kits it needs are
+    /// pulled.
+    pub(crate) async fn pull(
+        &self,
+        project: &Project<Locked>,
+        arch: &str,
+        variant: Option<&str>,
+        progress: &ProgressFn,
+    ) -> Result<CacheReport> {
+        let kits = self.kits_to_fetch(project, variant, progress).await?;
+        let key = CacheKey::from_env().await?;
+        let store = SystemStore::find();
+        let mut report = CacheReport::default();
+        let images = kits
+            .iter()
+            .map(|image| locked_project_image(project, image))
+            .collect::<Result<Vec<_>>>()?;
+        let transfers = transfer::transfers(&images);
+        for (done, (image, (_, transfer))) in images.iter().zip(&transfers).enumerate() {
+            // Kits replaced with local builds have nothing to pull.
+            if image.local_path().is_none() {
+                let resolver = ImageResolver::from_image(image)?.with_transfer(transfer);
+                let pull = resolver.pull(
+                    &ImageTool::from_env(),
+                    &project.external_kits_dir(),
+                    arch,
+                    key.as_ref(),
+                    store.as_ref(),
+                );
+                let (_, kit_report) = transfer::reporting(pull, &transfers, progress).await?;
+                report.extend(kit_report);
+            }
+            progress(&Progress::KitReady {
+                kit: image.to_string(),
+                done: done + 1,
+                total: kits.len(),
+            });
+        }
+        Ok(report)
+    }
+
+    async fn kits_to_fetch(
+        &self,
+        project: &Project<Locked>,
+        variant: Option<&str>,
+        progress: &ProgressFn,
+    ) -> Result<Vec<LockedImage>> {
         let target_dir = project.external_kits_dir();
         create_dir_all(&target_dir).await.context(format!(
             "failed to create external-kits directory at {}",
             target_dir.display()
         ))?;
 
-        info!(
-            dependencies = ?self.kit.iter().map(ToString::to_string).collect::<Vec<_>>(),
-            "Extracting kit dependencies."
-        );
+        let kits = match variant {
+            Some(variant) => self.kits_for_variant(project, variant).await?,
+            None => self.kit.clone(),
+        };
+        progress(&Progress::KitsResolved {
+            kits: kits.iter().map(ToString::to_string).collect(),
+        });
+        Ok(kits)
+    }
+
+    /// Pulls the archives of every kit in the lock for `arch` into the system-wide store.
+    pub(crate) async fn store_kits(
+        &self,
+        project: &Project<Locked>,
+        store: &SystemStore,
+        arch: &str,
+    ) -> Result<CacheReport> {
+        let mut report = CacheReport::default();
         for image in self.kit.iter() {
-            let image = project.as_project_image(image)?;
+            let image = locked_project_image(project, image)?;
+            if image.local_path().is_some() {
+                debug!("Not storing {image}, which is replaced with local builds");
+                continue;
+            }
             let resolver = ImageResolver::from_image(&image)?;
-            resolver
-                .extract(&ImageTool::krane(), &project.external_kits_dir(), arch)
-                .await?;
+            report.extend(resolver.store(&ImageTool::from_env(), store, arch).await?);
         }
-
-        self.synchronize_metadata(project).await
+        Ok(report)
     }
 
     pub(crate) async fn synchronize_metadata(&self, project: &Project<Locked>) -> Result<()> {
@@ -252,11 +584,28 @@ impl Lock {
         Ok(())
     }
 
-    #[instrument(level = "trace", skip(project))]
-    async fn resolve(project: &Project<Unlocked>) -> Result<Self> {
+    /// Resolves the kits the project depends on, directly or through other kits, and its SDK.
+    ///
+    /// Kits are resolved a level of the dependency graph at a time, with up to `jobs` of the kits
+    /// in a level fetched from their registries at once. The results are kept in the order the
+    /// kits were found, so the lock is the same however many jobs are used. Signatures are not
+    /// verified if `skip_signatures` is set, which is only safe when the result is checked against
+    /// an existing lock.
+    ///
+    /// Images which an entry of `kept` locks at the same version are kept as that entry locks
+    /// them. They are read by the registry digest they were locked with rather than by their tag,
+    /// only to find the kits they depend on.
+    #[instrument(level = "trace", skip(project, kept))]
+    async fn resolve(
+        project: &Project<Unlocked>,
+        jobs: usize,
+        skip_signatures: bool,
+        kept: &[&LockedImage],
+    ) -> Result<Self> {
         let mut known: HashMap<(ValidIdentifier, ValidIdentifier), Version> = HashMap::new();
         let mut locked: Vec<LockedImage> = Vec::new();
         let mut remaining = project.direct_kit_deps()?;
+        let mut matrices = Vec::new();
 
         let mut sdk_set = HashSet::new();
         if let Some(sdk) = project.direct_sdk_image_dep() {
@@ -265,6 +614,7 @@ impl Lock {
         }
         while !remaining.is_empty() {
             let working_set: Vec<_> = take(&mut remaining);
+            let mut unresolved = Vec::new();
             for image in working_set.iter() {
                 debug!(%image, "Resolving kit '{}'", image.name());
                 if let Some(version) =
@@ -275,8 +625,13 @@ impl Lock {
                     let vendor = image.vendor_name().clone();
                     ensure!(
                         image.version() == version,
-                        "cannot have multiple versions of the same kit ({name}-{left_version}@{vendor} \
-                        != {name}-{version}@{vendor}",
+                        Code::MultipleKitVersions.error(msg!(
+                            "error.multiple-kit-versions",
+                            name = name,
+                            left_version = left_version,
+                            version = version,
+                            vendor = vendor,
+                        ))
                     );
                     debug!(
                         ?image,
@@ -289,12 +644,24 @@ impl Lock {
                     (image.name().clone(), image.vendor_name().clone()),
                     image.version().clone(),
                 );
-                let image_resolver = ImageResolver::from_image(image)?;
-                let (locked_image, metadata) = image_resolver.resolve(&ImageTool::krane()).await?;
+                unresolved.push(image);
+            }
+
+            let image_tool = ImageTool::from_env();
+            let resolved: Vec<_> = stream::iter(unresolved)
+                .map(|image| resolve_image(image, kept, skip_signatures, false, &image_tool))
+                .buffered(jobs.max(1))
+                .try_collect()
+                .await?;
+            for (locked_image, metadata) in resolved {
                 let metadata = metadata.context(format!(
                     "failed to validate kit image with name {} from vendor {}",
                     locked_image.name, locked_image.vendor
                 ))?;
+                if let Some(matrix) = metadata.compatibility {
+                    let kit = format!("{}-{}", locked_image.name, locked_image.version);
+                    matrices.push((kit, matrix));
+                }
                 locked.push(locked_image);
                 sdk_set.insert(project.as_project_image(&metadata.sdk)?);
                 for dep in metadata.kits {
@@ -305,28 +672,114 @@ impl Lock {
         debug!(?sdk_set, "Resolving workspace SDK");
         ensure!(
             sdk_set.len() <= 1,
-            "cannot use multiple sdks (found sdk: {})",
-            sdk_set
-                .iter()
-                .map(ToString::to_string)
-                .collect::<Vec<_>>()
-                .join(", ")
+            Code::MultipleSdks.error(msg!(
+                "error.multiple-sdks",
+                sdks = sdk_set
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ))
         );
         let sdk = sdk_set
             .iter()
             .next()
-            .context("no sdk was found for use, please specify a sdk in Twoliter.toml")?;
+            .ok_or_else(|| Code::NoSdk.error(msg!("error.no-sdk")))?;
+
+        // Check the kits against what their publishers declare they work with before the SDK is
+        // fetched, so that an incompatible dependency is reported rather than failing the build.
+        let kit_versions = known
+            .into_iter()
+            .map(|((name, _), version)| (name.to_string(), version))
+            .collect::<BTreeMap<_, _>>();
+        for (kit, matrix) in &matrices {
+            matrix.check(kit, sdk.version(), &kit_versions)?;
+        }
 
         debug!(?sdk, "Resolving workspace SDK");
-        let (sdk, _metadata) = ImageResolver::from_image(sdk)?
-            .skip_metadata_retrieval() // SDKs don't have metadata
-            .resolve(&ImageTool::krane())
-            .await?;
+        let (sdk, _metadata) =
+            resolve_image(sdk, kept, skip_signatures, true, &ImageTool::from_env()).await?;
 
         Ok(Self {
-            schema_version: project.schema_version(),
+            schema_version: SchemaVersion::default(),
             kit: locked,
             sdk,
         })
     }
 }
+
+/// The image `locked` locks, pinned to the registry digest it was locked with where the lock
+/// records one, so that what is fetched is what was locked however the image's tag has moved.
+fn locked_project_image(project: &Project<Locked>, locked: &LockedImage) -> Result<ProjectImage> {
+    let image = project.as_project_image(locked)?;
+    Ok(match &locked.manifest_digest {
+        Some(digest) if image.local_path().is_none() => image.pinned(digest),
+        _ => image,
+    })
+}
+
+/// Resolves `image`, or else keeps the entry of `kept` which locks it. A kept image is read by the
+/// registry digest it was locked with, only for its metadata, and its signature is not checked
+/// again since it was verified when the image was locked. SDKs have no metadata to read.
+async fn resolve_image(
+    image: &ProjectImage,
+    kept: &[&LockedImage],
+    skip_signatures: bool,
+    is_sdk: bool,
+    image_tool: &ImageTool,
+) -> Result<(LockedImage, Option<ImageMetadata>)> {
+    let entry = scope::kept_entry(kept, image);
+    let mut resolver = match entry.and_then(|entry| entry.manifest_digest.as_deref()) {
+        Some(digest) => {
+            debug!(%image, digest, "Keeping the locked entry of '{}'", image.name());
+            ImageResolver::from_image(&image.pinned(digest))?.skip_signature_verification(true)
+        }
+        None => ImageResolver::from_image(image)?.skip_signature_verification(skip_signatures),
+    };
+    if is_sdk {
+        resolver = resolver.skip_metadata_retrieval();
+    }
+    let (locked_image, metadata) = resolver.resolve(image_tool).await?;
+    Ok((entry.cloned().unwrap_or(locked_image), metadata))
+}
+
+/// Builds locks and their entries for the tests of the modules which read them.
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{Lock, LockedImage};
+    use crate::project::ValidIdentifier;
+    use crate::schema_version::SchemaVersion;
+    use semver::Version;
+
+    /// The lock entry for `name` at `version` from the bottlerocket vendor, with lock `digest`.
+    pub(crate) fn locked_image(name: &str, version: &str, digest: &str) -> LockedImage {
+        vendor_locked_image("bottlerocket", name, version, digest)
+    }
+
+    /// The lock entry for `name` at `version` from `vendor`, with lock `digest`.
+    pub(crate) fn vendor_locked_image(
+        vendor: &str,
+        name: &str,
+        version: &str,
+        digest: &str,
+    ) -> LockedImage {
+        LockedImage {
+            name: ValidIdentifier(name.into()),
+            version: Version::parse(version).unwrap(),
+            vendor: ValidIdentifier(vendor.into()),
+            source: format!("public.ecr.aws/{vendor}/{name}:v{version}"),
+            digest: digest.into(),
+            manifest_digest: None,
+            resolved_from: None,
+        }
+    }
+
+    /// A lock in the current format of `sdk` and `kit`.
+    pub(crate) fn lock(sdk: LockedImage, kit: Vec<LockedImage>) -> Lock {
+        Lock {
+            schema_version: SchemaVersion::default(),
+            sdk,
+            kit,
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Finds entries in a lock file that nothing in `Twoliter.toml` can lead to, such as a kit left
+//! behind after its vendor was removed from the project.
+use super::image::LockedImage;
+use super::Lock;
+use crate::project::{Image, ValidIdentifier};
+use std::collections::BTreeSet;
+
+/// The parts of `Twoliter.toml` that lock entries are resolved from.
+pub(super) struct Declared<'a> {
+    pub(super) vendors: BTreeSet<&'a ValidIdentifier>,
+    pub(super) sdk: Option<&'a Image>,
+    pub(super) kits: &'a [Image],
+}
+
+impl Lock {
+    /// Returns the entries which cannot have been resolved from `declared`.
+    ///
+    /// Transitive dependencies are only known from the metadata of remote kit images, so this
+    /// conservatively reports entries which contradict the project file: images from undeclared
+    /// vendors, an SDK other than the declared one, and kits which the project depends on directly
+    /// but at a different version.
+    pub(super) fn orphans(&self, declared: &Declared<'_>) -> Vec<LockedImage> {
+        let sdk_orphaned = !declared.vendors.contains(&self.sdk.vendor)
+            || declared
+                .sdk
+                .is_some_and(|sdk| sdk.name != self.sdk.name || sdk.vendor != self.sdk.vendor);
+
+        let kit_orphaned = |kit: &LockedImage| {
+            if !declared.vendors.contains(&kit.vendor) {
+                return true;
+            }
+            let mut direct = declared
+                .kits
+                .iter()
+                .filter(|image| image.name == kit.name && image.vendor == kit.vendor)
+                .peekable();
+            direct.peek().is_some() && !direct.any(|image| image.version == kit.version)
+        };
+
+        sdk_orphaned
+            .then(|| self.sdk.clone())
+            .into_iter()
+            .chain(self.kit.iter().filter(|kit| kit_orphaned(kit)).cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::project::lock::test::{lock, vendor_locked_image};
+    use semver::Version;
+
+    fn id(s: &str) -> ValidIdentifier {
+        ValidIdentifier(s.into())
+    }
+
+    fn image(name: &str, version: &str, vendor: &str) -> Image {
+        Image {
+            name: id(name),
+            version: Version::parse(version).unwrap(),
+            vendor: id(vendor),
+            digest: None,
+        }
+    }
+
+    #[test]
+    fn test_orphans() {
+        let lock = lock(
+            vendor_locked_image("bottlerocket", "sdk", "0.50.0", "sha256:0"),
+            vec![
+                vendor_locked_image("bottlerocket", "core-kit", "2.0.0", "sha256:0"),
+                // Left behind after core-kit was bumped by hand.
+                vendor_locked_image("bottlerocket", "core-kit", "1.0.0", "sha256:0"),
+                // A transitive dependency of core-kit.
+                vendor_locked_image("bottlerocket", "base-kit", "1.0.0", "sha256:0"),
+                // Its vendor has been removed from the project.
+                vendor_locked_image("removed", "extra-kit", "1.0.0", "sha256:0"),
+            ],
+        );
+        let bottlerocket = id("bottlerocket");
+        let kits = [image("core-kit", "2.0.0", "bottlerocket")];
+        let declared = Declared {
+            vendors: BTreeSet::from([&bottlerocket]),
+            sdk: None,
+            kits: &kits,
+        };
+
+        let orphans = lock
+            .orphans(&declared)
+            .into_iter()
+            .map(|image| image.source)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            orphans,
+            vec![
+                "public.ecr.aws/bottlerocket/core-kit:v1.0.0",
+                "public.ecr.aws/removed/extra-kit:v1.0.0",
+            ]
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Checks the paths in a kit's layers against the limits of the filesystem they are extracted onto,
+//! so that a path which is too long, or which differs from another only in case on a filesystem
+//! that ignores case, fails the extraction instead of silently dropping or overwriting files.
+use crate::diagnostic::Code;
+use crate::messages::msg;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use tracing::debug;
+
+/// The longest file name, in bytes, that common filesystems allow.
+const MAX_NAME_LEN: usize = 255;
+
+/// The longest path, in bytes, that Linux allows, leaving room for the terminating NUL.
+const MAX_PATH_LEN: usize = 4095;
+
+/// How many problems an error lists before summarizing the rest.
+const LISTED: usize = 10;
+
+#[derive(Debug)]
+pub(super) struct PathCheck {
+    dir: PathBuf,
+    case_insensitive: bool,
+    /// The paths and parent directories seen so far, keyed by their case-folded form when the
+    /// filesystem ignores case.
+    seen: HashMap<PathBuf, PathBuf>,
+    problems: Vec<String>,
+}
+
+impl PathCheck {
+    /// Checks paths to be extracted into `dir`, which must exist, probing whether its filesystem
+    /// ignores case.
+    pub(super) fn new(dir: &Path) -> Result<Self> {
+        let probe = tempfile::Builder::new()
+            .prefix(".case-probe-")
+            .tempfile_in(dir)
+            .context(format!("failed to create a file in '{}'", dir.display()))?;
+        let name = probe
+            .path()
+            .file_name()
+            .context("case probe has no file name")?
+            .to_string_lossy()
+            .to_uppercase();
+        let case_insensitive = dir.join(name).exists();
+        if case_insensitive {
+            debug!("The filesystem of '{}' ignores case", dir.display());
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            case_insensitive,
+            seen: HashMap::new(),
+            problems: Vec::new(),
+        })
+    }
+
+    /// Records the problems with `path`, relative to the directory being extracted into. Returns
+    /// whether the path can be extracted as it is.
+    pub(super) fn check(&mut self, path: &Path) -> bool {
+        let path = path
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect::<PathBuf>();
+        let problems = self.problems.len();
+        // The length of an `OsStr` is its length in bytes on unix.
+        if let Some(name) = path.iter().find(|name| name.len() > MAX_NAME_LEN) {
+            self.problems.push(format!(
+                "'{}' has a name of {} bytes, more than {MAX_NAME_LEN}",
+                path.display(),
+                name.len()
+            ));
+        }
+        let full_len = self.dir.join(&path).as_os_str().len();
+        if full_len > MAX_PATH_LEN {
+            self.problems.push(format!(
+                "'{}' would be {full_len} bytes long within '{}', more than {MAX_PATH_LEN}",
+                path.display(),
+                self.dir.display()
+            ));
+        }
+
+        // Each parent directory is checked too, since files in `Packages` and `packages` land in
+        // the same directory on a filesystem which ignores case.
+        for prefix in path
+            .ancestors()
+            .filter(|prefix| !prefix.as_os_str().is_empty())
+        {
+            let key = self.key(prefix);
+            match self.seen.get(&key) {
+                Some(seen) if seen != prefix => {
+                    self.problems.push(format!(
+                        "'{}' and '{}' differ only in case",
+                        seen.display(),
+                        prefix.display()
+                    ));
+                    break;
+                }
+                Some(_) => {}
+                None => {
+                    self.seen.insert(key, prefix.to_path_buf());
+                }
+            }
+        }
+        self.problems.len() == problems
+    }
+
+    fn key(&self, path: &Path) -> PathBuf {
+        if self.case_insensitive {
+            PathBuf::from(path.to_string_lossy().to_lowercase())
+        } else {
+            path.to_path_buf()
+        }
+    }
+
+    /// Fails with every problem found in the kit `kit`, if there were any.
+    pub(super) fn finish(self, kit: &str) -> Result<()> {
+        if self.problems.is_empty() {
+            return Ok(());
+        }
+        let mut paths = self
+            .problems
+            .iter()
+            .take(LISTED)
+            .cloned()
+            .collect::<Vec<_>>();
+        if self.problems.len() > LISTED {
+            paths.push(format!("and {} more", self.problems.len() - LISTED));
+        }
+        Err(Code::UnrepresentablePath
+            .error(msg!(
+                "error.unrepresentable-path",
+                kit = kit,
+                dir = self.dir.display(),
+                count = self.problems.len(),
+                paths = paths.join("; "),
+            ))
+            .into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn check(case_insensitive: bool, paths: &[&str]) -> Vec<String> {
+        let dir = TempDir::new().unwrap();
+        let mut check = PathCheck::new(dir.path()).unwrap();
+        check.case_insensitive = case_insensitive;
+        for path in paths {
+            check.check(Path::new(path));
+        }
+        check.problems
+    }
+
+    #[test]
+    fn test_case_collisions() {
+        let paths = [
+            "./Packages/a.rpm",
+            "Packages/a.rpm",
+            "packages/b.rpm",
+            "repodata/README",
+            "repodata/readme",
+        ];
+        assert!(check(false, &paths).is_empty());
+        let problems = check(true, &paths);
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].contains("'Packages' and 'packages'"));
+        assert!(problems[1].contains("'repodata/README' and 'repodata/readme'"));
+    }
+
+    #[test]
+    fn test_long_paths() {
+        let long_name = "a".repeat(MAX_NAME_LEN + 1);
+        let deep = "dir/".repeat(MAX_PATH_LEN / 4 + 1);
+        let problems = check(false, &[&long_name, &deep, "Packages/a.rpm"]);
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].contains("more than 255"));
+        assert!(problems[1].contains("more than 4095"));
+    }
+
+    #[test]
+    fn test_finish() {
+        let dir = TempDir::new().unwrap();
+        let mut check = PathCheck::new(dir.path()).unwrap();
+        assert!(check.check(Path::new("Packages/a.rpm")));
+        assert!(!check.check(Path::new(&"a".repeat(MAX_NAME_LEN + 1))));
+        let err = check.finish("core-kit").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<crate::diagnostic::Diagnostic>()
+                .map(|diagnostic| diagnostic.code()),
+            Some(Code::UnrepresentablePath)
+        );
+    }
+}
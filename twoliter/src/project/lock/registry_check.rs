@@ -0,0 +1,259 @@
+//! Checks that registries still serve the images recorded in Twoliter.lock, so that CI can catch a
+//! tag which was moved or a registry which was tampered with without running a build.
+use super::image::{lock_digest, LockedImage};
+use super::Lock;
+use crate::diagnostic::Code;
+use crate::messages::msg;
+use crate::output::{Output, OutputSchema, LOCK_VERIFY_SCHEMA};
+use anyhow::{ensure, Result};
+use futures::stream::{self, StreamExt};
+use oci_cli_wrapper::ImageTool;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use tracing::debug;
+
+/// What a registry serves for a locked image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum CheckStatus {
+    /// The registry serves the locked manifest list.
+    Match,
+    /// The registry serves a different manifest list.
+    Mismatch,
+    /// The registry could not be asked.
+    Unreachable,
+}
+
+/// The result of asking one registry for a locked image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ImageCheck {
+    /// The locked image, as its name and version.
+    image: String,
+    /// Where the image was fetched from.
+    uri: String,
+    status: CheckStatus,
+    /// The digest recorded in the lock.
+    locked: String,
+    /// The digest of the manifest list the registry serves, if it could be fetched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    served: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The results of checking every image in a lock against its registries.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct LockCheck {
+    checks: Vec<ImageCheck>,
+}
+
+impl LockCheck {
+    /// Fetches the manifest list of every image in `lock` from its source, and from the mirror it
+    /// was resolved from if there was one, with up to `jobs` fetches at once.
+    pub(crate) async fn run(lock: &Lock, jobs: usize) -> Self {
+        let image_tool = ImageTool::registry();
+        let locations = std::iter::once(&lock.sdk)
+            .chain(lock.kit.iter())
+            // Kits locked from local builds are not in any registry.
+            .filter(|image| !image.is_local())
+            .flat_map(|image| {
+                std::iter::once(image.source.clone())
+                    .chain(image.resolved_from.clone())
+                    .map(move |uri| (image, uri))
+            });
+        let checks = stream::iter(locations)
+            .map(|(image, uri)| {
+                let image_tool = &image_tool;
+                async move { check(image_tool, image, uri).await }
+            })
+            .buffered(jobs.max(1))
+            .collect()
+            .await;
+        Self { checks }
+    }
+
+    /// Fails if any registry serves a different image than the lock records, or if an image
+    /// could not be fetched from anywhere.
+    pub(crate) fn ensure_verified(&self) -> Result<()> {
+        let mismatched = self
+            .checks
+            .iter()
+            .filter(|check| check.status == CheckStatus::Mismatch)
+            .map(|check| format!("'{}' from '{}'", check.image, check.uri))
+            .collect::<Vec<_>>();
+        ensure!(
+            mismatched.is_empty(),
+            Code::LockDigestMismatch.error(msg!(
+                "error.lock-digest-mismatch",
+                images = mismatched.join(", "),
+            ))
+        );
+        let unverified = self
+            .checks
+            .iter()
+            .filter(|check| !self.verified(&check.image))
+            .map(|check| format!("'{}'", check.image))
+            .collect::<BTreeSet<_>>();
+        ensure!(
+            unverified.is_empty(),
+            "could not verify {} against any registry",
+            unverified.into_iter().collect::<Vec<_>>().join(", ")
+        );
+        Ok(())
+    }
+
+    /// Whether any registry served the locked `image`.
+    fn verified(&self, image: &str) -> bool {
+        self.checks
+            .iter()
+            .any(|check| check.image == image && check.status == CheckStatus::Match)
+    }
+}
+
+async fn check(image_tool: &ImageTool, image: &LockedImage, uri: String) -> ImageCheck {
+    debug!("Checking '{uri}' against Twoliter.lock");
+    // Entries which record the registry digest are checked against it, and older entries against
+    // the digest of the canonicalized manifest list.
+    let (locked, served) = match &image.manifest_digest {
+        Some(locked) => (locked.clone(), image_tool.get_digest(&uri).await),
+        None => (
+            image.digest.clone(),
+            image_tool
+                .get_manifest(&uri)
+                .await
+                .map(|manifest| lock_digest(&manifest)),
+        ),
+    };
+    let (status, served, error) = match served {
+        Ok(served) => {
+            let status = if served == locked {
+                CheckStatus::Match
+            } else {
+                CheckStatus::Mismatch
+            };
+            (status, Some(served), None)
+        }
+        Err(e) => (CheckStatus::Unreachable, None, Some(e.to_string())),
+    };
+    ImageCheck {
+        image: format!("{} v{}", image.name, image.version),
+        uri,
+        status,
+        locked,
+        served,
+        error,
+    }
+}
+
+impl Display for LockCheck {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for check in &self.checks {
+            match (check.status, &check.served, &check.error) {
+                (CheckStatus::Match, _, _) => writeln!(f, "ok {} ({})", check.image, check.uri)?,
+                (CheckStatus::Mismatch, Some(served), _) => writeln!(
+                    f,
+                    "MISMATCH {} ({})\n  locked: {}\n  served: {}",
+                    check.image, check.uri, check.locked, served
+                )?,
+                (_, _, error) => writeln!(
+                    f,
+                    "unreachable {} ({}): {}",
+                    check.image,
+                    check.uri,
+                    error.as_deref().unwrap_or("unknown error")
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Output for LockCheck {
+    const SCHEMA: OutputSchema = LOCK_VERIFY_SCHEMA;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image_check(image: &str, uri: &str, status: CheckStatus) -> ImageCheck {
+        ImageCheck {
+            image: image.to_string(),
+            uri: uri.to_string(),
+            status,
+            locked: "a".to_string(),
+            served: (status != CheckStatus::Unreachable).then(|| "b".to_string()),
+            error: (status == CheckStatus::Unreachable).then(|| "timed out".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_verified() {
+        let check = LockCheck {
+            checks: vec![
+                image_check("sdk v0.50.0", "example.com/sdk:v0.50.0", CheckStatus::Match),
+                image_check(
+                    "core-kit v2.0.0",
+                    "example.com/core-kit:v2.0.0",
+                    CheckStatus::Unreachable,
+                ),
+                image_check(
+                    "core-kit v2.0.0",
+                    "mirror.example.com/core-kit:v2.0.0",
+                    CheckStatus::Match,
+                ),
+            ],
+        };
+        assert!(check.ensure_verified().is_ok());
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let check = LockCheck {
+            checks: vec![
+                image_check("sdk v0.50.0", "example.com/sdk:v0.50.0", CheckStatus::Match),
+                image_check(
+                    "core-kit v2.0.0",
+                    "example.com/core-kit:v2.0.0",
+                    CheckStatus::Mismatch,
+                ),
+            ],
+        };
+        let err = check.ensure_verified().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<crate::diagnostic::Diagnostic>()
+                .map(|diagnostic| diagnostic.code()),
+            Some(Code::LockDigestMismatch)
+        );
+        assert!(check
+            .to_string()
+            .contains("MISMATCH core-kit v2.0.0 (example.com/core-kit:v2.0.0)"));
+    }
+
+    #[test]
+    fn test_unverified() {
+        let check = LockCheck {
+            checks: vec![image_check(
+                "sdk v0.50.0",
+                "example.com/sdk:v0.50.0",
+                CheckStatus::Unreachable,
+            )],
+        };
+        let err = check.ensure_verified().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "could not verify 'sdk v0.50.0' against any registry"
+        );
+    }
+
+    #[test]
+    fn test_lock_digest() {
+        // The digest twoliter has always recorded: the base64 of the manifest list's sha256.
+        assert_eq!(
+            lock_digest(b"{}"),
+            "RBNvo1WzZ4oRRq0W9+hknpT7T8If536DEMBg9hyq/4o="
+        );
+    }
+}
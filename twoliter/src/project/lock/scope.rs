@@ -0,0 +1,167 @@
+//! Limits `twoliter update` to some of the project's dependencies, so that a targeted bump only
+//! changes the entries of Twoliter.lock it is about.
+//!
+//! Only the selected images are resolved against their tags. The other entries of Twoliter.lock
+//! are kept as they are, and their images are read by the registry digest they were locked with,
+//! so that the kits they depend on can still be found however their tags have moved since.
+use super::image::LockedImage;
+use super::Lock;
+use crate::project::ProjectImage;
+use anyhow::{ensure, Result};
+
+/// The dependencies an update locks afresh. Everything is locked afresh when no kits are named
+/// and the SDK is not selected.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct UpdateScope {
+    /// The names of the kits to lock afresh.
+    pub(crate) kits: Vec<String>,
+    /// Whether to lock the SDK afresh.
+    pub(crate) sdk: bool,
+}
+
+impl UpdateScope {
+    pub(crate) fn is_everything(&self) -> bool {
+        self.kits.is_empty() && !self.sdk
+    }
+
+    /// The entries of the `previous` lock which an update in this scope keeps rather than
+    /// resolving them afresh: those outside the scope.
+    pub(super) fn kept<'a>(&self, previous: Option<&'a Lock>) -> Vec<&'a LockedImage> {
+        let Some(previous) = previous.filter(|_| !self.is_everything()) else {
+            return Vec::new();
+        };
+        let sdk = (!self.sdk).then_some(&previous.sdk);
+        sdk.into_iter()
+            .chain(previous.kit.iter().filter(|image| !self.includes(image)))
+            .collect()
+    }
+
+    /// Checks that each kit the scope names is one the newly resolved `lock` locks.
+    pub(super) fn check(&self, lock: &Lock) -> Result<()> {
+        for name in &self.kits {
+            ensure!(
+                lock.kit.iter().any(|image| image.name.as_ref() == name),
+                "kit '{name}' is not a dependency of the project"
+            );
+        }
+        Ok(())
+    }
+
+    fn includes(&self, image: &LockedImage) -> bool {
+        self.kits.iter().any(|name| image.name.as_ref() == name)
+    }
+}
+
+/// The entry among `kept` which locks `image` at the same version, if it can be kept as it is.
+///
+/// Entries without a registry digest to read the image by are resolved afresh, as are images which
+/// the project pins to a digest itself or replaces with local builds. So is an image whose version
+/// has changed: a kit which a bump starts to depend on must be locked afresh.
+pub(super) fn kept_entry<'a>(
+    kept: &[&'a LockedImage],
+    image: &ProjectImage,
+) -> Option<&'a LockedImage> {
+    if image.digest().is_some() || image.local_path().is_some() {
+        return None;
+    }
+    kept.iter().copied().find(|old| {
+        old.manifest_digest.is_some()
+            && old.vendor == *image.vendor_name()
+            && old.name == *image.name()
+            && old.version == *image.version()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::project::lock::test::{lock, locked_image};
+    use crate::project::{ArtifactVendor, Image, ValidIdentifier, Vendor};
+    use semver::Version;
+
+    fn previous() -> Lock {
+        lock(
+            locked_image("sdk", "0.50.0", "a"),
+            vec![
+                locked_image("core-kit", "2.0.0", "b"),
+                locked_image("extra-kit", "1.0.0", "c"),
+            ],
+        )
+    }
+
+    fn digests(kept: Vec<&LockedImage>) -> Vec<&str> {
+        kept.into_iter()
+            .map(|image| image.digest.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_everything() {
+        let previous = previous();
+        assert!(UpdateScope::default().kept(Some(&previous)).is_empty());
+    }
+
+    #[test]
+    fn test_single_kit() {
+        let scope = UpdateScope {
+            kits: vec!["core-kit".to_string()],
+            sdk: false,
+        };
+        let previous = previous();
+        assert_eq!(digests(scope.kept(Some(&previous))), ["a", "c"]);
+        assert!(scope.kept(None).is_empty());
+    }
+
+    #[test]
+    fn test_sdk() {
+        let scope = UpdateScope {
+            kits: vec![],
+            sdk: true,
+        };
+        let previous = previous();
+        assert_eq!(digests(scope.kept(Some(&previous))), ["b", "c"]);
+    }
+
+    #[test]
+    fn test_unknown_kit() {
+        let scope = UpdateScope {
+            kits: vec!["missing-kit".to_string()],
+            sdk: false,
+        };
+        assert!(scope.check(&previous()).is_err());
+        let scope = UpdateScope {
+            kits: vec!["extra-kit".to_string()],
+            sdk: false,
+        };
+        assert!(scope.check(&previous()).is_ok());
+    }
+
+    #[test]
+    fn test_kept_entry() {
+        let image = |version: &str| ProjectImage {
+            image: Image {
+                name: ValidIdentifier("core-kit".into()),
+                version: Version::parse(version).unwrap(),
+                vendor: ValidIdentifier("bottlerocket".into()),
+                digest: None,
+            },
+            vendor: ArtifactVendor::verbatim(
+                ValidIdentifier("bottlerocket".into()),
+                Vendor {
+                    registry: "public.ecr.aws/bottlerocket".into(),
+                    mirrors: vec![],
+                    signature: None,
+                },
+            ),
+        };
+        let mut old = locked_image("core-kit", "2.0.0", "b");
+        // Without a registry digest, there is nothing to read the image by.
+        assert!(kept_entry(&[&old], &image("2.0.0")).is_none());
+        old.manifest_digest = Some("sha256:b".to_string());
+        assert_eq!(kept_entry(&[&old], &image("2.0.0")), Some(&old));
+        // A bump to another version is locked afresh.
+        assert!(kept_entry(&[&old], &image("2.1.0")).is_none());
+        // So is an image the project pins itself.
+        assert!(kept_entry(&[&old], &image("2.0.0").pinned("sha256:c")).is_none());
+    }
+}
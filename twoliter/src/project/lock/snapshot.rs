@@ -0,0 +1,296 @@
+//! Copies every image in Twoliter.lock, for all architectures, into a directory of OCI layouts, run
+//! by `twoliter vendor`. The directory can then be handed to `twoliter fetch --offline --oci-dir`
+//! to build without reaching any registry, or archived alongside a release.
+//!
+//! Each image is stored at `<registry>/<repository>` under the tag it is locked at, the layout that
+//! offline builds read. Running it again only pulls images which are missing, no longer match the
+//! lock, or have blobs which changed since they were written.
+use super::image::{lock_digest, LockedImage};
+use super::Lock;
+use crate::diagnostic::Code;
+use crate::messages::msg;
+use crate::output::{Output, OutputSchema, VENDOR_SCHEMA};
+use crate::project::{Project, ProjectLock};
+use anyhow::{ensure, Context, Result};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use oci_cli_wrapper::error::Error as ImageToolError;
+use oci_cli_wrapper::layout::OciLayoutDir;
+use oci_cli_wrapper::ImageTool;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tracing::{debug, info};
+
+/// What was done to bring an image in the vendor directory up to date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum VendorStatus {
+    /// The image was already vendored and intact.
+    UpToDate,
+    /// The image was not vendored yet.
+    Pulled,
+    /// The vendored image differed from the lock or was damaged, and was pulled again.
+    Replaced,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct VendoredImage {
+    /// The locked image, as its name and version.
+    image: String,
+    /// Where the image was pulled from, which is also where it is stored in the vendor directory.
+    uri: String,
+    status: VendorStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct VendorReport {
+    dir: PathBuf,
+    images: Vec<VendoredImage>,
+}
+
+impl Lock {
+    /// Copies the SDK and every kit in the project's lock file into the OCI layouts in `dir`,
+    /// pulling up to `jobs` images at once.
+    pub(super) async fn vendor<L: ProjectLock>(
+        project: &Project<L>,
+        dir: &Path,
+        jobs: usize,
+    ) -> Result<VendorReport> {
+        let lock = Self::current_lock_state(project).await?;
+        std::fs::create_dir_all(dir)
+            .context(format!("failed to create directory '{}'", dir.display()))?;
+        info!(
+            "Vendoring the images in the lock file into '{}'",
+            dir.display()
+        );
+
+        let image_tool = ImageTool::registry();
+        let images = std::iter::once(&lock.sdk)
+            .chain(lock.kit.iter())
+            .map(|image| {
+                let uri = project.as_project_image(image)?.project_image_uri();
+                let repository = match &uri.registry {
+                    Some(registry) => format!("{registry}/{}", uri.repo),
+                    None => uri.repo.clone(),
+                };
+                Ok((image, repository, uri.tag))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let images = stream::iter(images)
+            .map(|(image, repository, tag)| {
+                let image_tool = &image_tool;
+                async move { vendor_image(image_tool, dir, image, &repository, &tag).await }
+            })
+            .buffered(jobs.max(1))
+            .try_collect()
+            .await?;
+        Ok(VendorReport {
+            dir: dir.to_path_buf(),
+            images,
+        })
+    }
+}
+
+/// Brings `image` in the vendor directory `dir` up to date with the lock, pulling it with
+/// `image_tool` from `repository` if needed.
+async fn vendor_image(
+    image_tool: &ImageTool,
+    dir: &Path,
+    image: &LockedImage,
+    repository: &str,
+    tag: &str,
+) -> Result<VendoredImage> {
+    let uri = format!("{repository}:{tag}");
+    let name = format!("{} v{}", image.name, image.version);
+    let status = match verify(dir, &uri).await? {
+        Ok(manifest) if lock_digest(&manifest) == image.digest => {
+            debug!("'{uri}' is already vendored");
+            return Ok(VendoredImage {
+                image: name,
+                uri,
+                status: VendorStatus::UpToDate,
+            });
+        }
+        Ok(_) => {
+            debug!("The vendored '{uri}' is not the image Twoliter.lock records");
+            VendorStatus::Replaced
+        }
+        Err(ImageToolError::LayoutMissing { .. }) => VendorStatus::Pulled,
+        Err(e) => {
+            debug!("The vendored '{uri}' is damaged: {e}");
+            VendorStatus::Replaced
+        }
+    };
+
+    // The image is pulled by digest, so that what is checked against the lock is what is stored
+    // even if the tag moves in between. Locks which record the manifest digest name it directly.
+    info!("Pulling '{uri}' into the vendor directory");
+    let digest = match &image.manifest_digest {
+        Some(digest) => digest.clone(),
+        None => image_tool
+            .get_digest(&uri)
+            .await
+            .context(format!("failed to fetch the digest of '{uri}'"))?,
+    };
+    let pinned = format!("{repository}@{digest}");
+    let work_dir = TempDir::with_prefix_in(".twoliter-vendor-", dir).context(format!(
+        "failed to create a directory in '{}'",
+        dir.display()
+    ))?;
+    image_tool
+        .pull_oci_image(&work_dir.path().join(repository), &pinned)
+        .await
+        .context(format!("failed to pull '{pinned}'"))?;
+    let manifest = verify(work_dir.path(), &pinned)
+        .await?
+        .context(format!("the image pulled from '{pinned}' is incomplete"))?;
+    ensure!(
+        lock_digest(&manifest) == image.digest,
+        Code::LockDigestMismatch.error(msg!(
+            "error.lock-digest-mismatch",
+            images = format!("'{name}' from '{uri}'"),
+        ))
+    );
+    let (pulled, vendor_dir, stored_uri) = (
+        OciLayoutDir::new(work_dir.path()),
+        OciLayoutDir::new(dir),
+        uri.clone(),
+    );
+    tokio::task::spawn_blocking(move || vendor_dir.store(&pulled, &stored_uri, &digest))
+        .await
+        .context("failed to join the task storing the image")?
+        .context(format!("failed to store '{uri}' in '{}'", dir.display()))?;
+    Ok(VendoredImage {
+        image: name,
+        uri,
+        status,
+    })
+}
+
+/// Checks the image at `uri` in the OCI layouts in `dir`. Every blob is hashed, so this runs off
+/// the async runtime's threads.
+async fn verify(dir: &Path, uri: &str) -> Result<oci_cli_wrapper::Result<Vec<u8>>> {
+    let (dir, uri) = (dir.to_path_buf(), uri.to_string());
+    tokio::task::spawn_blocking(move || OciLayoutDir::new(dir).verify(&uri))
+        .await
+        .context("failed to join the task verifying the image")
+}
+
+impl Display for VendorReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for image in &self.images {
+            let status = match image.status {
+                VendorStatus::UpToDate => "up to date",
+                VendorStatus::Pulled => "pulled",
+                VendorStatus::Replaced => "replaced",
+            };
+            writeln!(f, "{status} {} ({})", image.image, image.uri)?;
+        }
+        write!(
+            f,
+            "Vendored {} images into '{}'; build offline with `twoliter fetch --offline --oci-dir {}`",
+            self.images.len(),
+            self.dir.display(),
+            self.dir.display()
+        )
+    }
+}
+
+impl Output for VendorReport {
+    const SCHEMA: OutputSchema = VENDOR_SCHEMA;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::project::lock::test::locked_image;
+    use sha2::{Digest, Sha256};
+
+    /// Writes `contents` into the OCI layout at `layout` and returns its digest.
+    fn write_blob(layout: &Path, contents: &str) -> String {
+        let encoded = format!("{:x}", Sha256::digest(contents));
+        let dir = layout.join("blobs/sha256");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(&encoded), contents).unwrap();
+        format!("sha256:{encoded}")
+    }
+
+    /// Writes an image with a single platform into `oci_dir` at `repository:tag`, and returns the
+    /// contents of its manifest list.
+    fn write_image(oci_dir: &Path, repository: &str, tag: &str, layer: &str) -> String {
+        let layout = oci_dir.join(repository);
+        let layer = write_blob(&layout, layer);
+        let config = write_blob(&layout, "{}");
+        let manifest = write_blob(
+            &layout,
+            &format!(r#"{{"config":{{"digest":"{config}"}},"layers":[{{"digest":"{layer}"}}]}}"#),
+        );
+        let list = format!(
+            r#"{{"manifests":[{{"digest":"{manifest}","platform":{{"architecture":"amd64"}}}}]}}"#
+        );
+        let list_digest = write_blob(&layout, &list);
+        std::fs::write(
+            layout.join("index.json"),
+            format!(
+                r#"{{"manifests":[{{"digest":"{list_digest}","annotations":{{"org.opencontainers.image.ref.name":"{tag}"}}}}]}}"#
+            ),
+        )
+        .unwrap();
+        list
+    }
+
+    async fn vendor(registry: &Path, dir: &Path, image: &LockedImage) -> Result<VendoredImage> {
+        let image_tool = ImageTool::oci_layout_dir(registry);
+        vendor_image(&image_tool, dir, image, "example.com/core-kit", "v2.0.0").await
+    }
+
+    async fn status(registry: &Path, dir: &Path, image: &LockedImage) -> VendorStatus {
+        vendor(registry, dir, image).await.unwrap().status
+    }
+
+    #[tokio::test]
+    async fn test_vendor_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let (registry, dir) = (
+            &temp_dir.path().join("registry"),
+            &temp_dir.path().join("vendor"),
+        );
+        std::fs::create_dir_all(dir).unwrap();
+        let list = write_image(registry, "example.com/core-kit", "v2.0.0", "layer");
+        let image = locked_image("core-kit", "2.0.0", &lock_digest(list.as_bytes()));
+        assert_eq!(status(registry, dir, &image).await, VendorStatus::Pulled);
+        assert_eq!(status(registry, dir, &image).await, VendorStatus::UpToDate);
+
+        // A damaged blob is replaced with the one the registry serves.
+        let blobs = dir.join("example.com/core-kit/blobs/sha256");
+        let layer = format!("{:x}", Sha256::digest("layer"));
+        std::fs::write(blobs.join(&layer), "damaged").unwrap();
+        assert_eq!(status(registry, dir, &image).await, VendorStatus::Replaced);
+        assert_eq!(status(registry, dir, &image).await, VendorStatus::UpToDate);
+
+        // When the lock moves to a new image, it replaces the old one and the old blobs are
+        // removed.
+        let moved = write_image(registry, "example.com/core-kit", "v2.0.0", "moved");
+        let moved_image = locked_image("core-kit", "2.0.0", &lock_digest(moved.as_bytes()));
+        assert_eq!(
+            status(registry, dir, &moved_image).await,
+            VendorStatus::Replaced
+        );
+        assert!(!blobs.join(&layer).exists());
+        assert_eq!(
+            status(registry, dir, &moved_image).await,
+            VendorStatus::UpToDate
+        );
+
+        // The registry no longer serves the image the old lock records.
+        let err = vendor(registry, dir, &image).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<crate::diagnostic::Diagnostic>()
+                .map(|diagnostic| diagnostic.code()),
+            Some(Code::LockDigestMismatch)
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Selects the kits that a single variant needs, so that `twoliter fetch --variant` can skip
+//! pulling and extracting the rest of the lock.
+use super::image::{ImageResolver, LockedImage};
+use super::Lock;
+use crate::project::lint::{reachable_dependencies, read_build_dependencies};
+use crate::project::{Locked, Project};
+use anyhow::{ensure, Result};
+use oci_cli_wrapper::ImageTool;
+use std::collections::BTreeSet;
+use std::path::Path;
+use tracing::{debug, info};
+
+impl Lock {
+    /// Returns the locked kits that `variant` depends on, directly or through other kits.
+    ///
+    /// The variant's kits are found by following the build dependencies of the project's variants
+    /// and kits. The dependencies of external kits are then read from their image metadata. If the
+    /// variant names none of the locked kits, every kit is returned, since it may depend on them
+    /// some other way.
+    pub(super) async fn kits_for_variant(
+        &self,
+        project: &Project<Locked>,
+        variant: &str,
+    ) -> Result<Vec<LockedImage>> {
+        let names = variant_dependencies(&project.project_dir, variant).await?;
+        let mut pending = self
+            .kit
+            .iter()
+            .filter(|kit| names.contains(kit.name.to_string().as_str()))
+            .collect::<Vec<_>>();
+        if pending.is_empty() {
+            debug!(
+                variant,
+                "Variant does not name any locked kits; selecting all of them"
+            );
+            return Ok(self.kit.clone());
+        }
+
+        let mut required = BTreeSet::new();
+        while let Some(kit) = pending.pop() {
+            if !required.insert(kit) {
+                continue;
+            }
+            let image = project.as_project_image(kit)?;
+            let metadata = ImageResolver::from_image(&image)?
+                .kit_metadata(&ImageTool::from_env())
+                .await?;
+            pending.extend(self.kit.iter().filter(|locked| {
+                metadata
+                    .kits
+                    .iter()
+                    .any(|dep| dep.name == locked.name && dep.vendor == locked.vendor)
+            }));
+        }
+
+        let skipped = self.kit.len() - required.len();
+        if skipped > 0 {
+            info!("Skipping {skipped} kit(s) which variant '{variant}' does not need");
+        }
+        Ok(self
+            .kit
+            .iter()
+            .filter(|kit| required.contains(kit))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Finds the names of everything `variant` depends on through the project's variants and kits.
+async fn variant_dependencies(project_dir: &Path, variant: &str) -> Result<BTreeSet<String>> {
+    let variants = read_build_dependencies(&project_dir.join("variants")).await?;
+    let kits = read_build_dependencies(&project_dir.join("kits")).await?;
+    let roots = variants.get(variant);
+    ensure!(
+        roots.is_some(),
+        "variant '{variant}' was not found in '{}'",
+        project_dir.join("variants").display()
+    );
+    Ok(reachable_dependencies(roots.into_iter().flatten(), &kits)
+        .into_iter()
+        .cloned()
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::projects_dir;
+
+    #[tokio::test]
+    async fn test_variant_dependencies() {
+        let project_dir = projects_dir().join("local-kit");
+        let names = variant_dependencies(&project_dir, "hello-ootb")
+            .await
+            .unwrap();
+        for kit in ["core-kit", "extra-1-kit", "extra-2-kit", "extra-3-kit"] {
+            assert!(names.contains(kit), "expected {kit} in {names:?}");
+        }
+        assert!(variant_dependencies(&project_dir, "missing").await.is_err());
+    }
+}
@@ -0,0 +1,129 @@
+//! Tracks how many bytes of each kit have been pulled and extracted. Pulls run in krane and
+//! extraction on blocking threads, so they only update counters, which are reported as progress
+//! from the task waiting on them.
+use crate::api::{Progress, ProgressFn, TransferStage};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the progress of transfers is reported.
+const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The progress of one kit through its current stage.
+#[derive(Debug, Default)]
+pub(crate) struct Transfer {
+    /// The stage, as 0 before the first one starts, or else one more than its index in
+    /// [`STAGES`].
+    stage: AtomicU8,
+    bytes: AtomicU64,
+    total: AtomicU64,
+    /// Whether the transfer changed since it was last reported.
+    changed: AtomicBool,
+}
+
+const STAGES: [TransferStage; 2] = [TransferStage::Pull, TransferStage::Extract];
+
+impl Transfer {
+    /// Starts `stage`, which will transfer `total` bytes.
+    pub(crate) fn start(&self, stage: TransferStage, total: u64) {
+        let index = STAGES.iter().position(|s| *s == stage).unwrap_or_default();
+        self.stage.store(index as u8 + 1, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+        self.set(0);
+    }
+
+    /// Records that `bytes` of the stage have been transferred so far.
+    pub(crate) fn set(&self, bytes: u64) {
+        self.bytes.store(bytes, Ordering::Relaxed);
+        self.changed.store(true, Ordering::Release);
+    }
+
+    /// Records that `bytes` more of the stage have been transferred.
+    pub(crate) fn add(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.changed.store(true, Ordering::Release);
+    }
+
+    /// The stage and its progress, if it changed since this was last called.
+    fn take_update(&self) -> Option<(TransferStage, u64, u64)> {
+        if !self.changed.swap(false, Ordering::Acquire) {
+            return None;
+        }
+        let stage = *STAGES.get(usize::from(self.stage.load(Ordering::Relaxed)).checked_sub(1)?)?;
+        let total = self.total.load(Ordering::Relaxed);
+        let bytes = self.bytes.load(Ordering::Relaxed).min(total);
+        Some((stage, bytes, total))
+    }
+}
+
+/// A transfer for each of `kits`, keyed by the kit.
+pub(super) fn transfers(kits: &[impl Display]) -> Vec<(String, Arc<Transfer>)> {
+    kits.iter()
+        .map(|kit| (kit.to_string(), Arc::default()))
+        .collect()
+}
+
+/// Runs `work`, meanwhile reporting the progress of `transfers`, which are keyed by kit.
+pub(super) async fn reporting<T>(
+    work: impl Future<Output = T>,
+    transfers: &[(String, Arc<Transfer>)],
+    progress: &ProgressFn,
+) -> T {
+    tokio::pin!(work);
+    let mut ticker = tokio::time::interval(REPORT_INTERVAL);
+    loop {
+        tokio::select! {
+            result = &mut work => {
+                report(transfers, progress);
+                return result;
+            }
+            _ = ticker.tick() => report(transfers, progress),
+        }
+    }
+}
+
+fn report(transfers: &[(String, Arc<Transfer>)], progress: &ProgressFn) {
+    for (kit, transfer) in transfers {
+        if let Some((stage, bytes, total)) = transfer.take_update() {
+            progress(&Progress::KitTransfer {
+                kit: kit.clone(),
+                stage,
+                bytes,
+                total,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_reporting() {
+        let transfer = Arc::new(Transfer::default());
+        let transfers = [("core-kit".to_string(), transfer.clone())];
+        let events = Mutex::new(Vec::new());
+        let record = |progress: &Progress| events.lock().unwrap().push(progress.clone());
+
+        // Nothing is reported before a stage starts, or when nothing changed.
+        reporting(async {}, &transfers, &record).await;
+        transfer.start(TransferStage::Pull, 10);
+        transfer.add(4);
+        transfer.add(8);
+        reporting(async {}, &transfers, &record).await;
+        reporting(async {}, &transfers, &record).await;
+        assert_eq!(
+            *events.lock().unwrap(),
+            [Progress::KitTransfer {
+                kit: "core-kit".to_string(),
+                stage: TransferStage::Pull,
+                bytes: 10,
+                total: 10,
+            }]
+        );
+    }
+}
@@ -1,11 +1,120 @@
 use oci_cli_wrapper::DockerArchitecture;
-use serde::de::Error;
+use serde::de::{Error, IgnoredAny};
 use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
 
+/// A manifest list, which is either an OCI image index or a Docker manifest list.
 #[derive(Deserialize, Debug)]
 pub(crate) struct ManifestListView {
+    /// The images in the list which can be run. Attestation manifests, such as those which buildx
+    /// adds for the `unknown/unknown` platform, and images for platforms other than Linux on the
+    /// architectures Twoliter supports are skipped.
+    #[serde(deserialize_with = "runnable_manifests")]
     pub manifests: Vec<ManifestView>,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+}
+
+/// The media types of manifest lists.
+pub(crate) const MANIFEST_LIST_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// The annotation with which buildx marks the attestation manifests it adds to a manifest list.
+const REFERENCE_TYPE_ANNOTATION: &str = "vnd.docker.reference.type";
+
+/// An entry of a manifest list, before it is known to be an image which can be run.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ManifestEntryView {
+    media_type: Option<String>,
+    digest: String,
+    platform: Option<PlatformEntryView>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PlatformEntryView {
+    architecture: String,
+    os: Option<String>,
+}
+
+impl ManifestEntryView {
+    /// The entry as an image, unless it is an attestation, a nested list, or an image for a
+    /// platform which cannot be run.
+    fn runnable(self) -> Option<ManifestView> {
+        if self.annotations.contains_key(REFERENCE_TYPE_ANNOTATION) {
+            return None;
+        }
+        if let Some(media_type) = &self.media_type {
+            if !IMAGE_MANIFEST_MEDIA_TYPES.contains(&media_type.as_str()) {
+                return None;
+            }
+        }
+        let platform = match self.platform {
+            Some(platform) => {
+                if platform.os.as_deref().is_some_and(|os| os != "linux") {
+                    return None;
+                }
+                let architecture = DockerArchitecture::try_from(platform.architecture.as_str());
+                Some(Platform {
+                    architecture: architecture.ok()?,
+                })
+            }
+            None => None,
+        };
+        Some(ManifestView {
+            digest: self.digest,
+            platform,
+        })
+    }
+}
+
+fn runnable_manifests<'de, D>(deserializer: D) -> Result<Vec<ManifestView>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Vec::<ManifestEntryView>::deserialize(deserializer)?
+        .into_iter()
+        .filter_map(ManifestEntryView::runnable)
+        .collect())
+}
+
+/// The media types of single-platform image manifests, which some registries hold in place of a
+/// manifest list.
+pub(crate) const IMAGE_MANIFEST_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+/// The fields which tell a single-platform image manifest from a manifest list.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ManifestKindView {
+    pub media_type: Option<String>,
+    /// Only image manifests have a config, which tells them apart when the media type is missing.
+    pub config: Option<IgnoredAny>,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl ManifestKindView {
+    pub(crate) fn is_image_manifest(&self) -> bool {
+        match self.media_type.as_deref() {
+            Some(media_type) => IMAGE_MANIFEST_MEDIA_TYPES.contains(&media_type),
+            None => self.config.is_some(),
+        }
+    }
+
+    pub(crate) fn is_manifest_list(&self) -> bool {
+        match self.media_type.as_deref() {
+            Some(media_type) => MANIFEST_LIST_MEDIA_TYPES.contains(&media_type),
+            None => self.config.is_none(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -26,12 +135,29 @@ pub(crate) struct IndexView {
 
 #[derive(Deserialize, Debug)]
 pub(crate) struct ManifestLayoutView {
+    #[serde(default)]
+    pub config: Option<Layer>,
     pub layers: Vec<Layer>,
 }
 
+/// The labels of an image, from its config blob.
+#[derive(Deserialize, Debug)]
+pub(crate) struct ImageConfigView {
+    #[serde(default)]
+    pub config: ImageConfigLabelsView,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct ImageConfigLabelsView {
+    #[serde(default, rename = "Labels")]
+    pub labels: Option<BTreeMap<String, String>>,
+}
+
 #[derive(Deserialize, Debug)]
 pub(crate) struct Layer {
     pub digest: ContainerDigest,
+    #[serde(default)]
+    pub size: u64,
 }
 
 #[derive(Debug)]
@@ -58,3 +184,69 @@ impl Display for ContainerDigest {
         f.write_str(self.0.as_str())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_manifest_list_skips_attestations() {
+        let manifest_list: ManifestListView = serde_json::from_str(
+            r#"{
+              "schemaVersion": 2,
+              "mediaType": "application/vnd.oci.image.index.v1+json",
+              "manifests": [
+                {
+                  "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                  "digest": "sha256:aaaa",
+                  "platform": {"architecture": "amd64", "os": "linux"}
+                },
+                {
+                  "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                  "digest": "sha256:bbbb",
+                  "platform": {"architecture": "unknown", "os": "unknown"},
+                  "annotations": {
+                    "vnd.docker.reference.digest": "sha256:aaaa",
+                    "vnd.docker.reference.type": "attestation-manifest"
+                  }
+                },
+                {
+                  "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                  "digest": "sha256:cccc",
+                  "platform": {"architecture": "arm64", "os": "linux", "variant": "v8"}
+                },
+                {
+                  "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                  "digest": "sha256:dddd",
+                  "platform": {"architecture": "s390x", "os": "linux"}
+                },
+                {
+                  "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                  "digest": "sha256:eeee",
+                  "platform": {"architecture": "amd64", "os": "windows"}
+                }
+              ]
+            }"#,
+        )
+        .unwrap();
+        let digests = manifest_list
+            .manifests
+            .iter()
+            .map(|manifest| manifest.digest.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(digests, ["sha256:aaaa", "sha256:cccc"]);
+    }
+
+    #[test]
+    fn test_manifest_kind() {
+        let kind = |json| serde_json::from_str::<ManifestKindView>(json).unwrap();
+        let docker_list =
+            kind(r#"{"mediaType": "application/vnd.docker.distribution.manifest.list.v2+json"}"#);
+        assert!(docker_list.is_manifest_list());
+        assert!(!docker_list.is_image_manifest());
+        assert!(kind(r#"{"manifests": []}"#).is_manifest_list());
+        assert!(kind(r#"{"config": {}}"#).is_image_manifest());
+        let artifact = kind(r#"{"mediaType": "application/vnd.cncf.helm.config.v1+json"}"#);
+        assert!(!artifact.is_manifest_list() && !artifact.is_image_manifest());
+    }
+}
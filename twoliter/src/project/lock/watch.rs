@@ -0,0 +1,209 @@
+//! Watches the kits extracted into the external kits directory for changes, run by
+//! `twoliter cache watch`. A kit whose extracted files are edited, added to or have their
+//! permissions changed is marked as stale, so that the next fetch or build extracts it again rather
+//! than building with files that no longer match the kit.
+//!
+//! Twoliter's own writes are told apart from a developer's: a kit that is being extracted has no
+//! digest file yet, and the files Twoliter keeps alongside a kit's contents are ignored.
+//! Deletions are ignored too, since extracting a kit again starts by removing it.
+use super::archive::ATTRIBUTES_FILE;
+use super::integrity::SEAL_FILE;
+use crate::project::cache::{EXTRACTED_DIGEST_FILE, KIT_ARCHIVE_CACHE_DIR, STALE_FILE};
+use crate::warnings;
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use tracing::info;
+
+/// The files Twoliter writes into an extracted kit's directory besides the kit's contents.
+const MARKER_FILES: [&str; 4] = [
+    EXTRACTED_DIGEST_FILE,
+    SEAL_FILE,
+    ATTRIBUTES_FILE,
+    STALE_FILE,
+];
+
+/// The events that change an extracted file.
+fn watch_mask() -> WatchMask {
+    WatchMask::CREATE
+        | WatchMask::MODIFY
+        | WatchMask::ATTRIB
+        | WatchMask::CLOSE_WRITE
+        | WatchMask::MOVED_TO
+}
+
+/// Watches the kits extracted into `kits_dir` until interrupted, marking those that change as
+/// stale.
+pub(crate) async fn watch_kits(kits_dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(kits_dir)
+        .context(format!("failed to create '{}'", kits_dir.display()))?;
+    let inotify = Inotify::init().context("failed to initialize inotify")?;
+    let mut watcher = KitWatcher {
+        kits_dir: kits_dir.to_path_buf(),
+        watches: inotify.watches(),
+        dirs: HashMap::new(),
+    };
+    watcher.watch_tree(kits_dir)?;
+    info!(
+        "Watching {} directories of the kits in '{}' for changes",
+        watcher.dirs.len(),
+        kits_dir.display()
+    );
+
+    let mut buffer = [0; 4096];
+    let mut events = inotify
+        .into_event_stream(&mut buffer)
+        .context("failed to read inotify events")?;
+    while let Some(event) = events.next().await {
+        let event = event.context("failed to read inotify event")?;
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            warnings::warn(
+                "Too many changes at once to keep track of; some may not mark kits as stale",
+            );
+            continue;
+        }
+        if event.mask.contains(EventMask::IGNORED) {
+            watcher.dirs.remove(&event.wd);
+            continue;
+        }
+        let (Some(dir), Some(name)) = (watcher.dirs.get(&event.wd), event.name) else {
+            continue;
+        };
+        let path = dir.join(name);
+        if event.mask.contains(EventMask::ISDIR) {
+            // Directories are only watched, since a kit's contents are its files.
+            if event
+                .mask
+                .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+            {
+                watcher.watch_tree(&path)?;
+            }
+            continue;
+        }
+        watcher.changed(&path)?;
+    }
+    Ok(())
+}
+
+struct KitWatcher {
+    kits_dir: PathBuf,
+    watches: Watches,
+    /// The directory each watch is on.
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl KitWatcher {
+    /// Watches `dir` and every directory below it, except for the kit archive cache.
+    fn watch_tree(&mut self, dir: &Path) -> Result<()> {
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            if dir == self.kits_dir.join(KIT_ARCHIVE_CACHE_DIR) {
+                continue;
+            }
+            let wd = self.watches.add(&dir, watch_mask()).context(format!(
+                "failed to watch '{}'; if there are too many directories, raise \
+                 fs.inotify.max_user_watches",
+                dir.display()
+            ))?;
+            self.dirs.insert(wd, dir.clone());
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                    pending.push(entry.path());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the kit that `path` belongs to as stale, unless the change was Twoliter's own.
+    fn changed(&self, path: &Path) -> Result<()> {
+        let Some((kit_dir, relative)) = extracted_kit(&self.kits_dir, path) else {
+            return Ok(());
+        };
+        if relative
+            .to_str()
+            .is_some_and(|name| MARKER_FILES.contains(&name))
+        {
+            return Ok(());
+        }
+        mark_stale(&kit_dir, &relative)
+    }
+}
+
+/// Finds the extracted kit that `path` belongs to, and the path relative to the kit. Kits that are
+/// not extracted, or are being extracted, have no digest file.
+fn extracted_kit(kits_dir: &Path, path: &Path) -> Option<(PathBuf, PathBuf)> {
+    let relative = path.strip_prefix(kits_dir).ok()?;
+    if relative.components().next() == Some(Component::Normal(KIT_ARCHIVE_CACHE_DIR.as_ref())) {
+        return None;
+    }
+    path.ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(kits_dir) && *dir != kits_dir)
+        .find(|dir| dir.join(EXTRACTED_DIGEST_FILE).is_file())
+        .map(|kit_dir| {
+            let relative = path.strip_prefix(kit_dir).unwrap_or(path).to_path_buf();
+            (kit_dir.to_path_buf(), relative)
+        })
+}
+
+/// Marks the kit in `kit_dir` as stale because `changed`, relative to it, was changed.
+fn mark_stale(kit_dir: &Path, changed: &Path) -> Result<()> {
+    let stale_file = kit_dir.join(STALE_FILE);
+    if stale_file.exists() {
+        return Ok(());
+    }
+    info!(
+        "'{}' was changed in the kit extracted at '{}'; it will be extracted again on the next \
+         fetch or build",
+        changed.display(),
+        kit_dir.display()
+    );
+    std::fs::write(&stale_file, changed.to_string_lossy().as_bytes())
+        .context(format!("failed to write '{}'", stale_file.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_changed() {
+        let temp_dir = TempDir::new().unwrap();
+        let kits_dir = temp_dir.path();
+        let kit_dir = kits_dir.join("bottlerocket/core-kit/x86_64");
+        std::fs::create_dir_all(kit_dir.join("Packages")).unwrap();
+        let watcher = KitWatcher {
+            kits_dir: kits_dir.to_path_buf(),
+            watches: Inotify::init().unwrap().watches(),
+            dirs: HashMap::new(),
+        };
+
+        // A kit that is being extracted has no digest file yet.
+        let rpm = kit_dir.join("Packages/a.rpm");
+        watcher.changed(&rpm).unwrap();
+        assert!(!kit_dir.join(STALE_FILE).exists());
+
+        // Twoliter's own files leave the kit alone.
+        std::fs::write(kit_dir.join(EXTRACTED_DIGEST_FILE), "sha256:aaa").unwrap();
+        watcher
+            .changed(&kit_dir.join(EXTRACTED_DIGEST_FILE))
+            .unwrap();
+        watcher
+            .changed(&kits_dir.join("cache/sha256-aaa/index.json"))
+            .unwrap();
+        assert!(!kit_dir.join(STALE_FILE).exists());
+
+        watcher.changed(&rpm).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(kit_dir.join(STALE_FILE)).unwrap(),
+            "Packages/a.rpm"
+        );
+    }
+}
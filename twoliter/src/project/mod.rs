@@ -1,31 +1,93 @@
+mod approval;
+mod budget;
+mod build_info;
+pub(crate) mod cache;
+mod checkout;
+mod compression;
+pub(crate) mod drift;
+mod effective;
 mod image;
+mod kit_version;
+mod layers;
+pub(crate) mod lint;
 mod lock;
+mod module_proxy;
+mod native_builder;
+mod network;
+mod plan;
+mod priority;
+mod profile;
+mod provenance;
+mod publish;
+mod registry_auth;
+mod release;
+mod runner;
+mod secret;
+pub(crate) mod shared_cache;
+mod signature;
+mod step;
+pub(crate) mod store;
 pub(crate) mod tasks;
+mod timings;
 pub(crate) mod vendor;
 
+pub(crate) use self::approval::{ApprovalPolicy, ApprovalRequest, PROMOTION_TASKS};
+pub(crate) use self::budget::ByteSize;
+pub(crate) use self::build_info::{BuildInfo, BuildOptions, BuildTarget};
+pub(crate) use self::compression::LayerCompression;
+pub(crate) use self::effective::{Setting, SettingSource};
 pub(crate) use self::image::{Image, ProjectImage, ValidIdentifier, VendedArtifact, Vendor};
+pub(crate) use self::native_builder::KIT_ARCHES;
+pub(crate) use self::step::STEPS_KEY_ENV;
 pub(crate) use self::vendor::ArtifactVendor;
 use lock::LockedImage;
-pub(crate) use lock::VerificationTagger;
+pub(crate) use lock::{
+    assemble_kit, check_kit_archives, default_extract_jobs, kit_consumers, unpack_layout,
+    watch_kits, ConsumerSources, LockCheck, LockDiff, MetadataReport, UpdateScope, VendorReport,
+    VerificationTagger, COMPATIBILITY_ANNOTATION, DEFAULT_RESOLVE_JOBS, RELEASE_NOTES_ANNOTATION,
+};
+pub use lock::{Attributes, ExtractOptions};
 use path_absolutize::Absolutize;
-
+pub(crate) use plan::{BuildPlan, PlanRequest};
+pub(crate) use priority::{CpuQuota, PriorityConfig};
+pub(crate) use profile::PROFILE_ENV;
+pub(crate) use publish::PublishMetadata;
+pub(crate) use release::BumpLevel;
+
+use self::budget::BudgetConfig;
+use self::cache::CacheReport;
+use self::kit_version::KitDependency;
+use self::lint::LintConfig;
 use self::lock::{Lock, LockedSDK, Override};
+use self::module_proxy::ModuleProxy;
+use self::native_builder::NativeBuilder;
+use self::network::NetworkConfig;
+use self::profile::Profile;
+use self::publish::PublishConfig;
+use self::registry_auth::RegistryConfig;
+use self::secret::Secret;
+use self::step::Step;
+use crate::api::ProgressFn;
 use crate::common::fs::{self, read_to_string};
 use crate::compatibility::SUPPORTED_TWOLITER_PROJECT_SCHEMA_VERSION;
+use crate::diagnostic::Code;
+use crate::messages::msg;
 use crate::schema_version::SchemaVersion;
+use crate::warnings;
 use anyhow::{ensure, Context, Result};
 use async_recursion::async_recursion;
 use async_trait::async_trait;
 use async_walkdir::WalkDir;
 use buildsys_config::{EXTERNAL_KIT_DIRECTORY, EXTERNAL_KIT_METADATA};
 use futures::stream::StreamExt;
+use krane_static::settings;
 use serde::Deserialize;
 use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::path::{Path, PathBuf};
 use toml::Table;
-use tracing::{debug, info, instrument, trace, warn};
+use tracing::{debug, info, instrument, trace};
 
 const TWOLITER_OVERRIDES: &str = "Twoliter.override";
 
@@ -63,11 +125,44 @@ pub(crate) struct Project<L: ProjectLock> {
     /// Set of vendors
     vendor: BTreeMap<ValidIdentifier, Vendor>,
 
-    /// Set of kit dependencies
+    /// The kit dependencies as declared, whose versions may be requirements.
+    kit_dependencies: Vec<KitDependency>,
+
+    /// Set of kit dependencies, pinned to exact versions
     kit: Vec<Image>,
 
     overrides: BTreeMap<String, BTreeMap<String, Override>>,
 
+    /// Settings for publishing artifacts.
+    publish: PublishConfig,
+
+    /// The levels of lints run by `twoliter lint`.
+    lint: LintConfig,
+
+    /// Custom build steps run by `twoliter step run`.
+    steps: BTreeMap<ValidIdentifier, Step>,
+
+    /// The size budgets of variants and kits.
+    budget: BudgetConfig,
+
+    /// Credentials passed to the builds of the packages which need them.
+    secrets: BTreeMap<ValidIdentifier, Secret>,
+
+    /// Mirrors of the registries that Rust, Go and Python dependencies are fetched from.
+    module_proxy: ModuleProxy,
+
+    /// How much of a shared host builds may take.
+    priority: PriorityConfig,
+
+    /// Credentials for the private registries which host the SDK or kits, by registry host.
+    registry: BTreeMap<String, RegistryConfig>,
+
+    /// The proxies and CA bundle through which registries are reached.
+    network: NetworkConfig,
+
+    /// The hosts which build each architecture of a kit with `--multi-arch`, by architecture.
+    native_builders: BTreeMap<String, NativeBuilder>,
+
     /// The resolved and locked dependencies of the project.
     lock: L,
 }
@@ -79,11 +174,20 @@ impl Project<Unlocked> {
         let data = fs::read_to_string(&path)
             .await
             .context(format!("Unable to read project file '{}'", path.display()))?;
-        let unvalidated: UnvalidatedProject = toml::from_str(&data).context(format!(
+        let mut table: Table = toml::from_str(&data).context(format!(
             "Unable to deserialize project file '{}'",
             path.display()
         ))?;
-        let project = unvalidated.validate(path).await?;
+        let profile = Profile::apply(&mut table, settings::var(PROFILE_ENV).ok().as_deref())?;
+        let unvalidated: UnvalidatedProject = toml::Value::Table(table).try_into().context(
+            format!("Unable to deserialize project file '{}'", path.display()),
+        )?;
+        let mut project = unvalidated.validate(path).await?;
+        profile.apply_env();
+        project.apply_registry_auth()?;
+        project.apply_network_env();
+        // Listing a kit's releases needs the registry credentials and network settings.
+        project.pin_kits(None).await?;
 
         // When projects are resolved, tags are written indicating which artifacts have been checked
         // against the lockfile.
@@ -123,9 +227,55 @@ impl Project<Unlocked> {
         Self::find_and_load(parent).await
     }
 
-    pub(crate) async fn create_lock(self) -> Result<Project<Locked>> {
-        let lock = Lock::create(&self).await?;
-        Ok(self.with_new_lock(lock))
+    /// Resolves the project's dependencies and writes Twoliter.lock, returning the changes made
+    /// relative to the previous lock file. Up to `jobs` kits are resolved at once, and only the
+    /// entries within `scope` are changed where the previous lock file still applies.
+    pub(crate) async fn update_lock(
+        self,
+        jobs: usize,
+        scope: &UpdateScope,
+    ) -> Result<(Project<Locked>, LockDiff)> {
+        let mut project = self;
+        project.pin_kits(Some(scope)).await?;
+        let (lock, diff) = Lock::update(&project, jobs, scope).await?;
+        Ok((project.with_new_lock(lock), diff))
+    }
+
+    /// Resolves the project's dependencies and returns the changes that `update_lock` would make
+    /// to Twoliter.lock, without writing it.
+    pub(crate) async fn preview_lock_update(
+        &self,
+        jobs: usize,
+        scope: &UpdateScope,
+    ) -> Result<LockDiff> {
+        let mut project = self.clone();
+        project.pin_kits(Some(scope)).await?;
+        Lock::preview_update(&project, jobs, scope).await
+    }
+
+    /// Reads the versions, architectures and dependencies of the project's SDK and kits from their
+    /// registries, reading up to `jobs` kits at once, without locking them or pulling any image.
+    pub(crate) async fn resolve_metadata(&self, jobs: usize) -> Result<MetadataReport> {
+        MetadataReport::resolve(self, jobs).await
+    }
+
+    /// Rewrites Twoliter.lock in the current format if it was written in an older one, resolving
+    /// up to `jobs` kits at once to check it first. Returns the version it was written in, if it
+    /// was rewritten.
+    pub(crate) async fn migrate_lock(&self, jobs: usize) -> Result<Option<u32>> {
+        Lock::migrate(self, jobs).await
+    }
+
+    /// Checks that registries still serve the images recorded in Twoliter.lock, fetching up to
+    /// `jobs` of them at once.
+    pub(crate) async fn check_lock(&self, jobs: usize) -> Result<LockCheck> {
+        Lock::check_registries(self, jobs).await
+    }
+
+    /// Copies every image in Twoliter.lock into the OCI layouts in `dir`, pulling up to `jobs` of
+    /// them at once, so that the project can be built offline.
+    pub(crate) async fn vendor_images(&self, dir: &Path, jobs: usize) -> Result<VendorReport> {
+        Lock::vendor(self, dir, jobs).await
     }
 
     pub(crate) async fn load_lock<NL: ProjectLock>(&self) -> Result<Project<NL>> {
@@ -152,8 +302,19 @@ impl<L: ProjectLock> Project<L> {
             release_version: self.release_version.clone(),
             sdk: self.sdk.clone(),
             vendor: self.vendor.clone(),
+            kit_dependencies: self.kit_dependencies.clone(),
             kit: self.kit.clone(),
             overrides: self.overrides.clone(),
+            publish: self.publish.clone(),
+            lint: self.lint.clone(),
+            steps: self.steps.clone(),
+            budget: self.budget.clone(),
+            secrets: self.secrets.clone(),
+            module_proxy: self.module_proxy.clone(),
+            priority: self.priority.clone(),
+            registry: self.registry.clone(),
+            network: self.network.clone(),
+            native_builders: self.native_builders.clone(),
             lock: new_lock.into(),
         }
     }
@@ -166,6 +327,10 @@ impl<L: ProjectLock> Project<L> {
         self.project_dir.clone()
     }
 
+    pub(crate) fn lock_file_path(&self) -> PathBuf {
+        self.project_dir.join(lock::TWOLITER_LOCK)
+    }
+
     pub(crate) fn external_kits_dir(&self) -> PathBuf {
         self.project_dir.join(EXTERNAL_KIT_DIRECTORY)
     }
@@ -178,14 +343,31 @@ impl<L: ProjectLock> Project<L> {
         self.project_dir.join("build/external-sdk-archives")
     }
 
-    pub(crate) fn schema_version(&self) -> SchemaVersion<1> {
-        self.schema_version
-    }
-
     pub(crate) fn release_version(&self) -> &str {
         self.release_version.as_str()
     }
 
+    /// Returns the entries in Twoliter.lock that nothing in Twoliter.toml can lead to any more.
+    /// These are dropped the next time the lock is updated.
+    pub(crate) async fn orphaned_lock_entries(&self) -> Result<Vec<LockedImage>> {
+        Lock::orphaned_entries(self).await
+    }
+
+    /// Returns the lint levels configured in the `lint` section of `Twoliter.toml`.
+    pub(crate) fn lint_config(&self) -> &LintConfig {
+        &self.lint
+    }
+
+    /// Returns the metadata to attach to images published to the given vendor.
+    pub(crate) fn publish_metadata_for(&self, vendor: &str) -> PublishMetadata {
+        self.publish.metadata_for(vendor)
+    }
+
+    /// Returns who must approve promoting a variant, if anyone.
+    pub(crate) fn promotion_approval(&self) -> Option<&ApprovalPolicy> {
+        self.publish.promotion_approval()
+    }
+
     pub(crate) fn direct_kit_deps(&self) -> Result<Vec<ProjectImage>> {
         self.kit
             .iter()
@@ -286,10 +468,31 @@ impl<T: LockedSDKProvider> Project<T> {
 }
 
 impl Project<Locked> {
-    /// Fetches all external kits defined in a Twoliter.lock to the build directory
-    pub(crate) async fn fetch_kits(&self, arch: &str) -> Result<()> {
+    /// Fetches the external kits defined in a Twoliter.lock to the build directory, or only those
+    /// needed by `variant` when it is given.
+    pub(crate) async fn fetch_kits(
+        &self,
+        arch: &str,
+        variant: Option<&str>,
+        options: ExtractOptions,
+        jobs: usize,
+        progress: &ProgressFn,
+    ) -> Result<CacheReport> {
         let Locked(lock) = &self.lock;
-        lock.fetch(self, arch).await
+        lock.fetch(self, arch, variant, options, jobs, progress)
+            .await
+    }
+
+    /// Pulls the archives of the external kits defined in a Twoliter.lock without extracting them,
+    /// or only those needed by `variant` when it is given.
+    pub(crate) async fn pull_kits(
+        &self,
+        arch: &str,
+        variant: Option<&str>,
+        progress: &ProgressFn,
+    ) -> Result<CacheReport> {
+        let Locked(lock) = &self.lock;
+        lock.pull(self, arch, variant, progress).await
     }
 
     #[expect(dead_code)]
@@ -314,7 +517,17 @@ struct UnvalidatedProject {
     release_version: String,
     sdk: Option<Image>,
     vendor: Option<BTreeMap<ValidIdentifier, Vendor>>,
-    kit: Option<Vec<Image>>,
+    kit: Option<Vec<KitDependency>>,
+    publish: Option<PublishConfig>,
+    lint: Option<LintConfig>,
+    step: Option<BTreeMap<ValidIdentifier, Step>>,
+    budget: Option<BudgetConfig>,
+    secret: Option<BTreeMap<ValidIdentifier, Secret>>,
+    module_proxy: Option<ModuleProxy>,
+    priority: Option<PriorityConfig>,
+    registry: Option<BTreeMap<String, RegistryConfig>>,
+    network: Option<NetworkConfig>,
+    native_builder: Option<BTreeMap<String, NativeBuilder>>,
 }
 
 impl UnvalidatedProject {
@@ -332,6 +545,34 @@ impl UnvalidatedProject {
         self.check_vendor_availability().await?;
         self.check_release_toml(&project_dir).await?;
         let overrides = self.check_and_load_overrides(&project_dir).await?;
+        let mut vendors = self.vendor.unwrap_or_default();
+        for (name, vendor) in vendors.iter_mut() {
+            if let Some(signature) = vendor.signature.take() {
+                signature.validate(name.as_ref())?;
+                vendor.signature = Some(signature.relative_to(&project_dir));
+            }
+        }
+        let steps = self.step.unwrap_or_default();
+        for (name, step) in &steps {
+            step.validate(name)?;
+        }
+        let secrets = self.secret.unwrap_or_default();
+        for (name, secret) in &secrets {
+            secret.validate(name)?;
+        }
+        let module_proxy = self.module_proxy.unwrap_or_default();
+        module_proxy.validate()?;
+        let priority = self.priority.unwrap_or_default();
+        priority.validate()?;
+        let registry = self.registry.unwrap_or_default();
+        for (host, config) in &registry {
+            config.validate(host)?;
+        }
+        let network = self.network.unwrap_or_default().validate(&project_dir)?;
+        let native_builders = self.native_builder.unwrap_or_default();
+        for (arch, builder) in &native_builders {
+            builder.validate(arch)?;
+        }
 
         Ok(Project {
             filepath,
@@ -339,9 +580,21 @@ impl UnvalidatedProject {
             schema_version: self.schema_version,
             release_version: self.release_version,
             sdk: self.sdk,
-            vendor: self.vendor.unwrap_or_default(),
-            kit: self.kit.unwrap_or_default(),
+            vendor: vendors,
+            kit_dependencies: self.kit.unwrap_or_default(),
+            // Pinned once the project is loaded.
+            kit: Vec::new(),
             overrides,
+            publish: self.publish.unwrap_or_default(),
+            lint: self.lint.unwrap_or_default(),
+            steps,
+            budget: self.budget.unwrap_or_default(),
+            secrets,
+            module_proxy,
+            priority,
+            registry,
+            network,
+            native_builders,
             lock: Unlocked,
         })
     }
@@ -359,28 +612,34 @@ impl UnvalidatedProject {
         let overrides_str = read_to_string(&overrides_file_path)
             .await
             .context("failed to read overrides file")?;
-        let overrides: BTreeMap<String, BTreeMap<String, Override>> =
+        let mut overrides: BTreeMap<String, BTreeMap<String, Override>> =
             toml::from_str(overrides_str.as_str())
                 .context("failed to deserialize overrides file")?;
+        for (vendor, images) in overrides.iter_mut() {
+            for (name, override_) in images.iter_mut() {
+                let Some(local_path) = override_.path.take() else {
+                    continue;
+                };
+                ensure!(
+                    override_.name.is_none() && override_.registry.is_none(),
+                    "the override of '{vendor}.{name}' has a path, so it cannot also have a name \
+                    or registry"
+                );
+                override_.path = Some(path.as_ref().join(local_path));
+            }
+        }
         Ok(overrides)
     }
 
     /// Errors if the user has defined a sdk and/or kit dependency without specifying the associated
     /// vendor
     async fn check_vendor_availability(&self) -> Result<()> {
-        let mut dependency_list = self.kit.clone().unwrap_or_default();
-        if let Some(sdk) = self.sdk.as_ref() {
-            dependency_list.push(sdk.clone());
-        }
-        for dependency in dependency_list.iter() {
+        let kit_vendors = self.kit.iter().flatten().map(|kit| &kit.vendor);
+        let sdk_vendor = self.sdk.iter().map(|sdk| &sdk.vendor);
+        for vendor in kit_vendors.chain(sdk_vendor) {
             ensure!(
-                self.vendor.is_some()
-                    && self
-                        .vendor
-                        .as_ref()
-                        .unwrap()
-                        .contains_key(&dependency.vendor),
-                "cannot define a dependency on a vendor that is not specified in Twoliter.toml"
+                self.vendor.is_some() && self.vendor.as_ref().unwrap().contains_key(vendor),
+                Code::NoRegistryForImage.error(msg!("error.unknown-vendor", vendor = vendor,))
             );
         }
         Ok(())
@@ -395,9 +654,9 @@ impl UnvalidatedProject {
             trace!("This project does not have a Release.toml file (this is not a problem)");
             return Ok(());
         }
-        warn!(
+        warnings::warn(
             "A Release.toml file was found. Release.toml is deprecated. Please remove it from \
-             your project."
+             your project.",
         );
         let content = fs::read_to_string(&path).await.context(format!(
             "Error while checking Release.toml file at '{}'",
@@ -406,10 +665,10 @@ impl UnvalidatedProject {
         let toml: Table = match toml::from_str(&content) {
             Ok(toml) => toml,
             Err(e) => {
-                warn!(
+                warnings::warn(format!(
                     "Unable to parse Release.toml to ensure that its version matches the \
                      release-version in Twoliter.toml: {e}",
-                );
+                ));
                 return Ok(());
             }
         };
@@ -424,9 +683,11 @@ impl UnvalidatedProject {
         .context("The version in Release.toml is not a string")?;
         ensure!(
             version == self.release_version,
-            "The version found in Release.toml, '{version}', does not match the release-version \
-            found in Twoliter.toml '{}'",
-            self.release_version
+            Code::ReleaseVersionMismatch.error(msg!(
+                "error.release-version-mismatch",
+                version = version,
+                release_version = self.release_version,
+            ))
         );
         Ok(())
     }
@@ -527,6 +788,7 @@ mod private {
 
 #[cfg(test)]
 mod test {
+    use super::kit_version::KitVersion;
     use super::*;
     use crate::common::fs;
     use crate::docker::ImageUri;
@@ -630,6 +892,84 @@ mod test {
         assert!(matches!(vendor, ArtifactVendor::Verbatim(_)));
     }
 
+    #[test]
+    fn test_vendor_mirrors() {
+        let name = ValidIdentifier("bottlerocket".into());
+        let vendor = Vendor {
+            registry: "a.com/b".into(),
+            mirrors: vec!["m1.com/b".into(), "m2.com/b".into()],
+            signature: None,
+        };
+        let registries = |vendor: &ArtifactVendor| {
+            vendor
+                .mirrors()
+                .iter()
+                .map(|mirror| mirror.registry().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let verbatim = ArtifactVendor::verbatim(name.clone(), vendor.clone());
+        assert_eq!(registries(&verbatim), ["m1.com/b", "m2.com/b"]);
+
+        let renamed = ArtifactVendor::overridden(
+            name.clone(),
+            vendor.clone(),
+            Override {
+                name: Some("my-sdk".into()),
+                registry: None,
+                path: None,
+            },
+        );
+        assert_eq!(registries(&renamed), ["m1.com/b", "m2.com/b"]);
+
+        let moved = ArtifactVendor::overridden(
+            name,
+            vendor,
+            Override {
+                name: None,
+                registry: Some("c.com/d".into()),
+                path: None,
+            },
+        );
+        assert!(registries(&moved).is_empty());
+    }
+
+    #[test]
+    fn test_image_sources() {
+        let image = ProjectImage {
+            image: Image {
+                name: ValidIdentifier("core-kit".into()),
+                version: Version::new(2, 0, 0),
+                vendor: ValidIdentifier("bottlerocket".into()),
+                digest: None,
+            },
+            vendor: ArtifactVendor::verbatim(
+                ValidIdentifier("bottlerocket".into()),
+                Vendor {
+                    registry: "public.ecr.aws/bottlerocket".into(),
+                    mirrors: vec!["mirror.corp/bottlerocket".into()],
+                    signature: None,
+                },
+            ),
+        };
+        let sources = image
+            .sources()
+            .iter()
+            .map(|source| source.project_image_uri().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sources,
+            [
+                "mirror.corp/bottlerocket/core-kit:v2.0.0",
+                "public.ecr.aws/bottlerocket/core-kit:v2.0.0",
+            ]
+        );
+        assert_eq!(
+            image.original_source_uri().to_string(),
+            "public.ecr.aws/bottlerocket/core-kit:v2.0.0"
+        );
+    }
+
     #[tokio::test]
     async fn test_overridden_sdk() {
         let path = data_dir().join("override/Twoliter-override-1.toml");
@@ -643,10 +983,13 @@ mod test {
                 sdk.vendor_name().clone(),
                 Vendor {
                     registry: "a.com/b".parse().unwrap(),
+                    mirrors: Vec::new(),
+                    signature: None,
                 },
                 Override {
                     name: Some("my-overridden-sdk".parse().unwrap()),
                     registry: Some("c.com/d".parse().unwrap()),
+                    path: None,
                 },
             )
         );
@@ -670,18 +1013,32 @@ mod test {
                 name: ValidIdentifier("bottlerocket-sdk".into()),
                 version: Version::new(1, 41, 1),
                 vendor: ValidIdentifier("bottlerocket".into()),
+                digest: None,
             }),
             vendor: Some(BTreeMap::from([(
                 ValidIdentifier("not-bottlerocket".into()),
                 Vendor {
                     registry: "public.ecr.aws/not-bottlerocket".into(),
+                    mirrors: Vec::new(),
+                    signature: None,
                 },
             )])),
-            kit: Some(vec![Image {
+            kit: Some(vec![KitDependency {
                 name: ValidIdentifier("bottlerocket-core-kit".into()),
-                version: Version::new(1, 20, 0),
+                version: KitVersion::Exact(Version::new(1, 20, 0)),
                 vendor: ValidIdentifier("not-bottlerocket".into()),
+                digest: None,
             }]),
+            publish: None,
+            lint: None,
+            step: None,
+            budget: None,
+            secret: None,
+            module_proxy: None,
+            priority: None,
+            registry: None,
+            network: None,
+            native_builder: None,
         };
         assert!(project.check_vendor_availability().await.is_err());
     }
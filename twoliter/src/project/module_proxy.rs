@@ -0,0 +1,244 @@
+//! Mirrors of the registries that Rust, Go and Python dependencies are fetched from, declared in
+//! the `module-proxy` section of `Twoliter.toml`, so that projects build behind firewalls which
+//! block the public registries.
+//!
+//! ```toml
+//! [module-proxy.cargo]
+//! # A mirror of crates.io, which replaces it for every cargo command of the build.
+//! registry = "sparse+https://artifacts.example.com/api/cargo/crates-io/index/"
+//!
+//! [module-proxy.go]
+//! proxy = "https://goproxy.example.com"
+//! sumdb = "sum.golang.org https://goproxy.example.com/sumdb/sum.golang.org"
+//! private = "git.example.com/*"
+//!
+//! [module-proxy.pip]
+//! index-url = "https://pypi.example.com/simple"
+//! ```
+//!
+//! The Go and pip settings are passed to the build as the environment variables those tools read,
+//! which the SDK containers that fetch modules receive. Variables already set in the environment
+//! take precedence, so that a developer can point at a different proxy for a single build. Cargo
+//! does not read source replacement from the environment, so the crates.io mirror is written to
+//! the `config.toml` of the build's `CARGO_HOME`, which the SDK containers and package builds
+//! mount. That file is owned by Twoliter, which refuses to overwrite one it did not write.
+use super::{Project, ProjectLock};
+use anyhow::{ensure, Context, Result};
+use krane_static::settings;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// The first line of the cargo configuration Twoliter writes, which marks it as Twoliter's own.
+const CARGO_CONFIG_HEADER: &str =
+    "# Written by twoliter from the module-proxy section of Twoliter.toml. Changes are overwritten.";
+
+/// The name of the source which replaces crates.io.
+const CARGO_SOURCE: &str = "twoliter-module-proxy";
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct ModuleProxy {
+    cargo: Option<CargoProxy>,
+    go: Option<GoProxy>,
+    pip: Option<PipProxy>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct CargoProxy {
+    /// The index of a mirror of crates.io, e.g. `sparse+https://...` or the URL of a git index.
+    registry: String,
+}
+
+/// The settings of the Go module proxy, named after the `GO*` environment variables they set.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct GoProxy {
+    proxy: Option<String>,
+    noproxy: Option<String>,
+    private: Option<String>,
+    sumdb: Option<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct PipProxy {
+    index_url: String,
+    trusted_host: Option<String>,
+}
+
+impl ModuleProxy {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Some(cargo) = &self.cargo {
+            ensure!(
+                !cargo.registry.trim().is_empty(),
+                "module-proxy.cargo.registry must not be empty"
+            );
+        }
+        if let Some(pip) = &self.pip {
+            ensure!(
+                !pip.index_url.trim().is_empty(),
+                "module-proxy.pip.index-url must not be empty"
+            );
+        }
+        Ok(())
+    }
+
+    /// The environment variables which point Go and pip at their proxies, leaving out those which
+    /// are already set in the environment.
+    fn envs(&self) -> Vec<(&'static str, String)> {
+        let go = self.go.clone().unwrap_or_default();
+        let pip = self.pip.clone();
+        [
+            ("GOPROXY", go.proxy),
+            ("GONOPROXY", go.noproxy),
+            ("GOPRIVATE", go.private),
+            ("GOSUMDB", go.sumdb),
+            (
+                "PIP_INDEX_URL",
+                pip.as_ref().map(|pip| pip.index_url.clone()),
+            ),
+            ("PIP_TRUSTED_HOST", pip.and_then(|pip| pip.trusted_host)),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value?)))
+        .filter(|(key, _)| settings::var_os(key).is_none())
+        .collect()
+    }
+
+    /// The cargo configuration which replaces crates.io with the mirror, if there is one.
+    fn cargo_config(&self) -> Option<String> {
+        let cargo = self.cargo.as_ref()?;
+        // Formatted as a TOML value, so that the registry is quoted and escaped.
+        let registry = toml::Value::String(cargo.registry.clone());
+        Some(format!(
+            "{CARGO_CONFIG_HEADER}\n\
+             [source.crates-io]\n\
+             replace-with = \"{CARGO_SOURCE}\"\n\
+             \n\
+             [source.{CARGO_SOURCE}]\n\
+             registry = {registry}\n"
+        ))
+    }
+
+    /// Writes the cargo configuration to `cargo_home`, or removes the one Twoliter wrote before if
+    /// there is no longer a crates.io mirror.
+    fn write_cargo_config(&self, cargo_home: &Path) -> Result<()> {
+        let path = cargo_home.join("config.toml");
+        let existing = std::fs::read_to_string(&path).ok();
+        let owned = existing
+            .as_deref()
+            .map_or(true, |existing| existing.starts_with(CARGO_CONFIG_HEADER));
+        match self.cargo_config() {
+            Some(config) => {
+                ensure!(
+                    owned,
+                    "cannot configure the crates.io mirror in module-proxy.cargo, since '{}' was \
+                     not written by twoliter; move its settings to Twoliter.toml or remove it",
+                    path.display()
+                );
+                if existing.as_deref() != Some(config.as_str()) {
+                    debug!("Writing the crates.io mirror to '{}'", path.display());
+                    std::fs::create_dir_all(cargo_home)
+                        .context(format!("failed to create '{}'", cargo_home.display()))?;
+                    std::fs::write(&path, config)
+                        .context(format!("failed to write '{}'", path.display()))?;
+                }
+            }
+            None if existing.is_some() && owned => {
+                debug!("Removing the crates.io mirror from '{}'", path.display());
+                std::fs::remove_file(&path)
+                    .context(format!("failed to remove '{}'", path.display()))?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// Points the cargo commands which use `cargo_home` at the project's crates.io mirror, and
+    /// returns the environment variables which point Go and pip at their proxies.
+    pub(crate) fn module_proxy_env(
+        &self,
+        cargo_home: &Path,
+    ) -> Result<Vec<(&'static str, String)>> {
+        self.module_proxy.write_cargo_config(cargo_home)?;
+        Ok(self.module_proxy.envs())
+    }
+
+    /// The `CARGO_HOME` of builds which are not given one, as set in Makefile.toml.
+    pub(crate) fn default_cargo_home(&self) -> PathBuf {
+        self.project_dir.join(".cargo")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn module_proxy(toml: &str) -> ModuleProxy {
+        let proxy: ModuleProxy = toml::from_str(toml).unwrap();
+        proxy.validate().unwrap();
+        proxy
+    }
+
+    #[test]
+    fn test_envs() {
+        let proxy = module_proxy(
+            r#"
+            go = { proxy = "https://goproxy.example.com", sumdb = "off" }
+            pip = { index-url = "https://pypi.example.com/simple" }
+            "#,
+        );
+        let envs = proxy.envs();
+        for (key, value) in [
+            ("GOPROXY", "https://goproxy.example.com"),
+            ("GOSUMDB", "off"),
+            ("PIP_INDEX_URL", "https://pypi.example.com/simple"),
+        ] {
+            if settings::var_os(key).is_none() {
+                assert!(envs.contains(&(key, value.to_string())), "{envs:?}");
+            }
+        }
+        assert!(!envs.iter().any(|(key, _)| *key == "GOPRIVATE"));
+        assert!(toml::from_str::<ModuleProxy>("npm = { registry = \"x\" }").is_err());
+    }
+
+    #[test]
+    fn test_write_cargo_config() {
+        let cargo_home = TempDir::new().unwrap();
+        let path = cargo_home.path().join("config.toml");
+        let proxy =
+            module_proxy(r#"cargo = { registry = "sparse+https://cargo.example.com/index/" }"#);
+        proxy.write_cargo_config(cargo_home.path()).unwrap();
+        let config: toml::Table = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            config["source"]["crates-io"]["replace-with"].as_str(),
+            Some(CARGO_SOURCE)
+        );
+        assert_eq!(
+            config["source"][CARGO_SOURCE]["registry"].as_str(),
+            Some("sparse+https://cargo.example.com/index/")
+        );
+
+        // Without a mirror, the configuration Twoliter wrote is removed.
+        ModuleProxy::default()
+            .write_cargo_config(cargo_home.path())
+            .unwrap();
+        assert!(!path.exists());
+
+        // A configuration Twoliter did not write is left alone, and not overwritten.
+        std::fs::write(&path, "[net]\noffline = true\n").unwrap();
+        ModuleProxy::default()
+            .write_cargo_config(cargo_home.path())
+            .unwrap();
+        assert!(proxy.write_cargo_config(cargo_home.path()).is_err());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "[net]\noffline = true\n"
+        );
+    }
+}
@@ -0,0 +1,71 @@
+//! The hosts which `twoliter build kit --multi-arch` builds each architecture on, declared in the
+//! `native-builder` section of `Twoliter.toml` by architecture:
+//!
+//! ```toml
+//! [native-builder.aarch64]
+//! remote = "builder@arm64.example.com"
+//! ```
+//!
+//! Each `remote` is an ssh destination, which the architecture is built on as with `twoliter
+//! --remote`. Architectures without a native builder are built on this host.
+use super::{Project, ProjectLock};
+use anyhow::{ensure, Result};
+use serde::Deserialize;
+
+/// The architectures a kit is built for with `--multi-arch`.
+pub(crate) const KIT_ARCHES: &[&str] = &["aarch64", "x86_64"];
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct NativeBuilder {
+    /// The ssh destination of the host which builds the architecture.
+    pub(crate) remote: String,
+}
+
+impl NativeBuilder {
+    /// Checks that `arch` is an architecture kits are built for, and that the builder names a
+    /// host.
+    pub(crate) fn validate(&self, arch: &str) -> Result<()> {
+        ensure!(
+            KIT_ARCHES.contains(&arch),
+            "native-builder.{arch} must be one of the architectures {}",
+            KIT_ARCHES.join(", ")
+        );
+        ensure!(
+            !self.remote.trim().is_empty() && !self.remote.starts_with('-'),
+            "native-builder.{arch}.remote must be an ssh destination, e.g. \
+            'builder@arm64.example.com', not '{}'",
+            self.remote
+        );
+        Ok(())
+    }
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// The ssh destination of the native builder for `arch`, or `None` to build it on this host.
+    pub(crate) fn native_builder(&self, arch: &str) -> Option<&str> {
+        self.native_builders
+            .get(arch)
+            .map(|builder| builder.remote.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let builder = |remote: &str| NativeBuilder {
+            remote: remote.to_string(),
+        };
+        builder("builder@arm64.example.com")
+            .validate("aarch64")
+            .unwrap();
+        assert!(builder("builder@arm64.example.com")
+            .validate("arm64")
+            .is_err());
+        assert!(builder("").validate("x86_64").is_err());
+        assert!(builder("-oProxyCommand=x").validate("x86_64").is_err());
+    }
+}
@@ -0,0 +1,131 @@
+//! How registries are reached from networks which only allow traffic through a proxy, declared in
+//! the `network` section of `Twoliter.toml`. A proxy which intercepts TLS presents certificates
+//! signed by its own CA, which registries are trusted to present when it is in the CA bundle.
+//!
+//! ```toml
+//! [network]
+//! # PEM certificates trusted in addition to the system's CAs, relative to the project directory.
+//! ca-bundle = "certs/proxy-ca.pem"
+//! https-proxy = "http://proxy.example.com:3128"
+//! http-proxy = "http://proxy.example.com:3128"
+//! no-proxy = "localhost,.internal.example.com"
+//! ```
+//!
+//! The settings are passed to twoliter's registry clients, and to the tools it runs, as the
+//! environment variables `TWOLITER_CA_BUNDLE`, `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY`.
+//! Variables already set in the environment take precedence, in either case for the proxies.
+use super::{Project, ProjectLock};
+use anyhow::{ensure, Result};
+use krane_static::settings;
+use oci_cli_wrapper::registry::CA_BUNDLE_ENV;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct NetworkConfig {
+    ca_bundle: Option<PathBuf>,
+    https_proxy: Option<String>,
+    http_proxy: Option<String>,
+    no_proxy: Option<String>,
+}
+
+impl NetworkConfig {
+    /// Resolves the CA bundle against `project_dir`, and checks that it exists.
+    pub(crate) fn validate(mut self, project_dir: &Path) -> Result<Self> {
+        if let Some(ca_bundle) = self.ca_bundle.take() {
+            let ca_bundle = project_dir.join(ca_bundle);
+            ensure!(
+                ca_bundle.is_file(),
+                "network.ca-bundle '{}' does not exist",
+                ca_bundle.display()
+            );
+            self.ca_bundle = Some(ca_bundle);
+        }
+        for (key, value) in [
+            ("https-proxy", &self.https_proxy),
+            ("http-proxy", &self.http_proxy),
+        ] {
+            if let Some(value) = value {
+                ensure!(
+                    value.contains("://"),
+                    "network.{key} must be a URL such as 'http://proxy.example.com:3128', not \
+                     '{value}'"
+                );
+            }
+        }
+        Ok(self)
+    }
+
+    /// The environment variables which carry the settings, leaving out those which are already
+    /// set, in the environment or by the selected profile.
+    fn envs(&self) -> Vec<(&'static str, String)> {
+        let ca_bundle = self
+            .ca_bundle
+            .as_ref()
+            .map(|path| path.display().to_string());
+        [
+            (CA_BUNDLE_ENV, ca_bundle),
+            ("HTTPS_PROXY", self.https_proxy.clone()),
+            ("HTTP_PROXY", self.http_proxy.clone()),
+            ("NO_PROXY", self.no_proxy.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value?)))
+        .filter(|(key, _)| {
+            settings::var_os(key).is_none() && settings::var_os(key.to_lowercase()).is_none()
+        })
+        .collect()
+    }
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// Sets the variables which point twoliter's registry clients, and the tools it runs, at the
+    /// project's proxies and CA bundle.
+    pub(crate) fn apply_network_env(&self) {
+        for (key, value) in self.network.envs() {
+            debug!("Setting {key} from the network section of Twoliter.toml");
+            settings::set(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_validate() {
+        let project_dir = TempDir::new().unwrap();
+        std::fs::create_dir(project_dir.path().join("certs")).unwrap();
+        std::fs::write(project_dir.path().join("certs/proxy-ca.pem"), "").unwrap();
+
+        let network: NetworkConfig = toml::from_str(
+            r#"
+            ca-bundle = "certs/proxy-ca.pem"
+            https-proxy = "http://proxy.example.com:3128"
+            no-proxy = "localhost"
+            "#,
+        )
+        .unwrap();
+        let network = network.validate(project_dir.path()).unwrap();
+        assert_eq!(
+            network.ca_bundle,
+            Some(project_dir.path().join("certs/proxy-ca.pem"))
+        );
+        let envs = network.envs();
+        if settings::var_os("HTTPS_PROXY").is_none() && settings::var_os("https_proxy").is_none() {
+            assert!(envs.contains(&("HTTPS_PROXY", "http://proxy.example.com:3128".into())));
+        }
+        assert!(!envs.iter().any(|(key, _)| *key == "HTTP_PROXY"));
+
+        let missing: NetworkConfig = toml::from_str("ca-bundle = \"certs/missing.pem\"").unwrap();
+        assert!(missing.validate(project_dir.path()).is_err());
+        let host_only: NetworkConfig =
+            toml::from_str("https-proxy = \"proxy.example.com:3128\"").unwrap();
+        assert!(host_only.validate(project_dir.path()).is_err());
+        assert!(toml::from_str::<NetworkConfig>("socks-proxy = \"x\"").is_err());
+    }
+}
@@ -0,0 +1,387 @@
+//! Lays out a project's build as a graph of steps, so that an external scheduler such as a CI
+//! system's job matrix can run the steps as separate, parallel jobs.
+//!
+//! `twoliter plan` prints the plan and `twoliter exec-step` runs one of its steps. Each step is a
+//! twoliter command, run from the project directory. The outputs a step leaves in the project must
+//! be carried to the jobs of the steps that depend on it. A plan is tied to the Twoliter.lock it
+//! was made from, and its steps refuse to run once the lock has changed.
+//!
+//! Steps which ran before on this host carry an estimate of how long they take, from the times
+//! kept in `build/state/timings.json`, and the plan an estimate of its longest chain of steps.
+use super::lint::{reachable_dependencies, read_build_dependencies};
+use super::timings::format_duration;
+use super::{BuildTarget, Locked, Project};
+use crate::common::fs::read;
+use crate::output::{Output, OutputSchema, PLAN_SCHEMA};
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BuildPlan {
+    /// The sha256 digest of the Twoliter.lock that the plan was made from.
+    pub(crate) lock_digest: String,
+    /// The steps, each after the steps it depends on.
+    pub(crate) steps: Vec<PlanStep>,
+    /// How long the longest chain of steps should take, in seconds, if any step ran before.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) estimate_seconds: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PlanStep {
+    pub(crate) id: String,
+    /// The arguments to run twoliter with for the step.
+    pub(crate) command: Vec<String>,
+    /// The steps which must finish before this one starts.
+    pub(crate) depends_on: Vec<String>,
+    /// The paths the step leaves in the project for the steps that depend on it.
+    pub(crate) outputs: Vec<String>,
+    /// How long the step should take, in seconds, if it ran before.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) estimate_seconds: Option<u64>,
+}
+
+/// What a plan should build.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PlanRequest {
+    pub(crate) arches: Vec<String>,
+    /// The project's kits to build. With no kits or variants, every kit and variant is built.
+    pub(crate) kits: Vec<String>,
+    pub(crate) variants: Vec<String>,
+    /// The vendor to publish the built kits to, if they should be published.
+    pub(crate) publish_vendor: Option<String>,
+}
+
+impl BuildPlan {
+    pub(crate) fn step(&self, id: &str) -> Option<&PlanStep> {
+        self.steps.iter().find(|step| step.id == id)
+    }
+}
+
+impl Display for BuildPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} step(s)", self.steps.len())?;
+        if let Some(seconds) = self.estimate_seconds {
+            write!(
+                f,
+                ", about {} along the longest chain",
+                format_duration(Duration::from_secs(seconds))
+            )?;
+        }
+        write!(f, ":")?;
+        for step in &self.steps {
+            write!(f, "\n  {}: twoliter {}", step.id, step.command.join(" "))?;
+            if !step.depends_on.is_empty() {
+                write!(f, " (after {})", step.depends_on.join(", "))?;
+            }
+            if let Some(seconds) = step.estimate_seconds {
+                write!(f, " ~{}", format_duration(Duration::from_secs(seconds)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Output for BuildPlan {
+    const SCHEMA: OutputSchema = PLAN_SCHEMA;
+}
+
+impl BuildTarget {
+    /// The id of the plan step which builds the target for `arch`.
+    pub(crate) fn step_id(&self, arch: &str) -> String {
+        match self {
+            BuildTarget::Kit(kit) => format!("build-kit:{kit}:{arch}"),
+            BuildTarget::Variant(variant) => format!("build-variant:{variant}:{arch}"),
+        }
+    }
+}
+
+impl Project<Locked> {
+    /// Plans the build described by `request`.
+    pub(crate) async fn build_plan(&self, request: &PlanRequest) -> Result<BuildPlan> {
+        let kits = read_build_dependencies(&self.project_dir.join("kits")).await?;
+        let variants = read_build_dependencies(&self.project_dir.join("variants")).await?;
+        let timings = self.timings().await;
+        let mut steps = plan_steps(request, &kits, &variants)?;
+        for step in &mut steps {
+            step.estimate_seconds = timings.estimate(&step.id).map(|took| took.as_secs());
+        }
+        Ok(BuildPlan {
+            lock_digest: self.lock_digest().await?,
+            estimate_seconds: longest_chain(&steps),
+            steps,
+        })
+    }
+
+    /// The sha256 digest of the project's Twoliter.lock.
+    pub(crate) async fn lock_digest(&self) -> Result<String> {
+        Ok(hex::encode(Sha256::digest(
+            read(self.lock_file_path()).await?,
+        )))
+    }
+}
+
+/// Plans the steps of `request` for a project with the build dependencies of its `kits` and
+/// `variants`, keyed by name.
+fn plan_steps(
+    request: &PlanRequest,
+    kits: &BTreeMap<String, Vec<String>>,
+    variants: &BTreeMap<String, Vec<String>>,
+) -> Result<Vec<PlanStep>> {
+    for kit in &request.kits {
+        ensure!(kits.contains_key(kit), "the project has no kit '{kit}'");
+    }
+    for variant in &request.variants {
+        ensure!(
+            variants.contains_key(variant),
+            "the project has no variant '{variant}'"
+        );
+    }
+    let (wanted_kits, wanted_variants) = if request.kits.is_empty() && request.variants.is_empty() {
+        (
+            kits.keys().cloned().collect::<Vec<_>>(),
+            variants.keys().cloned().collect::<Vec<_>>(),
+        )
+    } else {
+        (request.kits.clone(), request.variants.clone())
+    };
+
+    // Build the kits that were asked for, and the project's kits that they or the variants
+    // depend on. Kits from other projects are fetched rather than built.
+    let local_kits = |roots: &[String]| -> BTreeSet<String> {
+        reachable_dependencies(roots, kits)
+            .into_iter()
+            .filter(|name| kits.contains_key(*name))
+            .cloned()
+            .collect()
+    };
+    let mut built_kits = local_kits(&wanted_kits);
+    for variant in &wanted_variants {
+        built_kits.extend(local_kits(&variants[variant]));
+    }
+
+    let mut steps = Vec::new();
+    for arch in &request.arches {
+        let fetch = format!("fetch:{arch}");
+        steps.push(PlanStep {
+            id: fetch.clone(),
+            command: args(&["fetch", "--arch", arch]),
+            depends_on: Vec::new(),
+            outputs: vec!["build/external-kits".to_string()],
+            estimate_seconds: None,
+        });
+        let kit_steps = |roots: &[String]| {
+            local_kits(roots)
+                .into_iter()
+                .map(|kit| BuildTarget::Kit(kit).step_id(arch))
+                .collect::<Vec<_>>()
+        };
+        // Kits are built in dependency order, so each comes after the kits it depends on.
+        for kit in dependency_order(&built_kits, kits) {
+            let mut depends_on = vec![fetch.clone()];
+            depends_on.extend(kit_steps(&kits[&kit]));
+            steps.push(PlanStep {
+                id: BuildTarget::Kit(kit.clone()).step_id(arch),
+                command: args(&["build", "kit", &kit, "--arch", arch]),
+                depends_on,
+                outputs: vec![format!("build/kits/{kit}/{arch}")],
+                estimate_seconds: None,
+            });
+        }
+        for variant in &wanted_variants {
+            let mut depends_on = vec![fetch.clone()];
+            depends_on.extend(kit_steps(&variants[variant]));
+            steps.push(PlanStep {
+                id: BuildTarget::Variant(variant.clone()).step_id(arch),
+                command: args(&["build", "variant", variant, "--arch", arch]),
+                depends_on,
+                outputs: vec![format!("build/images/{arch}-{variant}")],
+                estimate_seconds: None,
+            });
+        }
+    }
+
+    if let Some(vendor) = &request.publish_vendor {
+        for kit in dependency_order(&built_kits, kits) {
+            steps.push(PlanStep {
+                id: format!("publish-kit:{kit}"),
+                command: args(&["publish", "kit", &kit, vendor]),
+                depends_on: request
+                    .arches
+                    .iter()
+                    .map(|arch| BuildTarget::Kit(kit.clone()).step_id(arch))
+                    .collect(),
+                outputs: Vec::new(),
+                estimate_seconds: None,
+            });
+        }
+    }
+    Ok(steps)
+}
+
+/// How long the longest chain of `steps` should take, counting the steps which never ran as
+/// taking no time, or `None` if none of them ran.
+fn longest_chain(steps: &[PlanStep]) -> Option<u64> {
+    if steps.iter().all(|step| step.estimate_seconds.is_none()) {
+        return None;
+    }
+    // Each step comes after the steps it depends on, so they finish in order.
+    let mut finished = BTreeMap::<&str, u64>::new();
+    for step in steps {
+        let start = step
+            .depends_on
+            .iter()
+            .filter_map(|id| finished.get(id.as_str()))
+            .max()
+            .copied()
+            .unwrap_or_default();
+        finished.insert(&step.id, start + step.estimate_seconds.unwrap_or_default());
+    }
+    finished.into_values().max()
+}
+
+/// Orders `names` so that each kit comes after the kits it depends on.
+fn dependency_order(names: &BTreeSet<String>, kits: &BTreeMap<String, Vec<String>>) -> Vec<String> {
+    let mut ordered = Vec::new();
+    let mut placed = BTreeSet::new();
+    while ordered.len() < names.len() {
+        let before = ordered.len();
+        for name in names.iter().filter(|name| !placed.contains(*name)) {
+            let ready = kits[name]
+                .iter()
+                .all(|dep| !names.contains(dep) || placed.contains(dep));
+            if ready {
+                ordered.push(name.clone());
+            }
+        }
+        placed.extend(ordered[before..].iter().cloned());
+        // Kits which depend on each other cannot be ordered; cargo reports the cycle when they
+        // are built.
+        if ordered.len() == before {
+            ordered.extend(names.iter().filter(|name| !placed.contains(*name)).cloned());
+        }
+    }
+    ordered
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn deps(entries: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        entries
+            .iter()
+            .map(|(name, deps)| (name.to_string(), args(deps)))
+            .collect()
+    }
+
+    fn ids(steps: &[PlanStep]) -> Vec<&str> {
+        steps.iter().map(|step| step.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_plan_steps_for_variant() {
+        let kits = deps(&[
+            ("extra-kit", &["core-kit"]),
+            ("core-kit", &["bottlerocket-core-kit"]),
+            ("unused-kit", &[]),
+        ]);
+        let variants = deps(&[("aws-dev", &["extra-kit"]), ("metal-dev", &["core-kit"])]);
+        let request = PlanRequest {
+            arches: args(&["x86_64", "aarch64"]),
+            variants: args(&["aws-dev"]),
+            publish_vendor: Some("custom".to_string()),
+            ..Default::default()
+        };
+        let steps = plan_steps(&request, &kits, &variants).unwrap();
+        assert_eq!(
+            ids(&steps),
+            [
+                "fetch:x86_64",
+                "build-kit:core-kit:x86_64",
+                "build-kit:extra-kit:x86_64",
+                "build-variant:aws-dev:x86_64",
+                "fetch:aarch64",
+                "build-kit:core-kit:aarch64",
+                "build-kit:extra-kit:aarch64",
+                "build-variant:aws-dev:aarch64",
+                "publish-kit:core-kit",
+                "publish-kit:extra-kit",
+            ]
+        );
+        assert_eq!(
+            steps[2].depends_on,
+            ["fetch:x86_64", "build-kit:core-kit:x86_64"]
+        );
+        assert_eq!(
+            steps[3].depends_on,
+            [
+                "fetch:x86_64",
+                "build-kit:core-kit:x86_64",
+                "build-kit:extra-kit:x86_64"
+            ]
+        );
+        assert_eq!(steps[9].command, ["publish", "kit", "extra-kit", "custom"]);
+        assert_eq!(
+            steps[9].depends_on,
+            ["build-kit:extra-kit:x86_64", "build-kit:extra-kit:aarch64"]
+        );
+    }
+
+    #[test]
+    fn test_plan_steps_defaults_to_everything() {
+        let kits = deps(&[("core-kit", &[])]);
+        let variants = deps(&[("aws-dev", &["core-kit"])]);
+        let request = PlanRequest {
+            arches: args(&["x86_64"]),
+            ..Default::default()
+        };
+        let steps = plan_steps(&request, &kits, &variants).unwrap();
+        assert_eq!(
+            ids(&steps),
+            [
+                "fetch:x86_64",
+                "build-kit:core-kit:x86_64",
+                "build-variant:aws-dev:x86_64"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_longest_chain() {
+        let kits = deps(&[("core-kit", &[]), ("extra-kit", &["core-kit"])]);
+        let variants = deps(&[("aws-dev", &["core-kit"])]);
+        let request = PlanRequest {
+            arches: args(&["x86_64"]),
+            ..Default::default()
+        };
+        let mut steps = plan_steps(&request, &kits, &variants).unwrap();
+        assert_eq!(longest_chain(&steps), None);
+        for (step, seconds) in steps.iter_mut().zip([60, 3600, 1800, 600]) {
+            step.estimate_seconds = Some(seconds);
+        }
+        // fetch, then core-kit, then the longer of extra-kit and aws-dev.
+        assert_eq!(ids(&steps)[2], "build-kit:extra-kit:x86_64");
+        assert_eq!(longest_chain(&steps), Some(60 + 3600 + 1800));
+    }
+
+    #[test]
+    fn test_plan_steps_unknown_target() {
+        let request = PlanRequest {
+            arches: args(&["x86_64"]),
+            kits: args(&["missing-kit"]),
+            ..Default::default()
+        };
+        assert!(plan_steps(&request, &BTreeMap::new(), &BTreeMap::new()).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+//! How much of a shared host builds may take, declared in the `priority` section of
+//! `Twoliter.toml`, so that background builds on a shared development server do not starve
+//! interactive sessions. The `--nice`, `--cpu-quota` and `--cgroup-parent` options of the build
+//! commands override these defaults.
+//!
+//! ```toml
+//! [priority]
+//! # Run builds at a lower priority, from 0, the default, to 19, the lowest.
+//! nice = 10
+//! # Keep at most this many CPUs busy.
+//! cpu-quota = 4.5
+//! # Run build containers under this cgroup, such as a systemd slice with limits of its own.
+//! cgroup-parent = "builds.slice"
+//! ```
+//!
+//! The niceness applies to twoliter and every process it starts, and to the commands which build
+//! packages and images in the SDK containers. I/O is scheduled by the niceness too, in the
+//! best-effort class at the level the kernel derives from it. The CPU quota bounds how many
+//! packages build at once and how many jobs each runs, which is how much the build asks for rather
+//! than a limit the host enforces. For a hard limit, give a cgroup whose limits the host enforces,
+//! e.g. a systemd slice with `CPUQuota` and `IOWeight` set.
+use super::{Project, ProjectLock};
+use anyhow::{ensure, Context, Result};
+use krane_static::settings;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use tracing::{debug, info};
+
+/// The lowest priority a process can be given.
+const MAX_NICE: u8 = 19;
+
+/// The arguments of `ioprio_set`, from `linux/ioprio.h`.
+const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+const IOPRIO_CLASS_BE: libc::c_long = 2;
+const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct PriorityConfig {
+    pub(crate) nice: Option<u8>,
+    pub(crate) cpu_quota: Option<CpuQuota>,
+    pub(crate) cgroup_parent: Option<String>,
+}
+
+/// A number of CPUs, which may be a fraction, kept in thousandths of a CPU.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(try_from = "f64")]
+pub(crate) struct CpuQuota(u32);
+
+impl CpuQuota {
+    /// The quota rounded up to whole CPUs, which is how many jobs may run at once.
+    fn jobs(&self) -> u32 {
+        self.0.div_ceil(1000).max(1)
+    }
+}
+
+impl TryFrom<f64> for CpuQuota {
+    type Error = anyhow::Error;
+
+    fn try_from(cpus: f64) -> Result<Self> {
+        ensure!(
+            cpus.is_finite() && cpus >= 0.001 && cpus <= f64::from(u32::MAX / 1000),
+            "the CPU quota must be a positive number of CPUs, not '{cpus}'"
+        );
+        Ok(Self((cpus * 1000.0).round() as u32))
+    }
+}
+
+impl FromStr for CpuQuota {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.trim()
+            .parse::<f64>()
+            .context(format!("'{s}' is not a number of CPUs"))?
+            .try_into()
+    }
+}
+
+impl Display for CpuQuota {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", f64::from(self.0) / 1000.0)
+    }
+}
+
+impl PriorityConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Some(nice) = self.nice {
+            ensure!(
+                nice <= MAX_NICE,
+                "priority.nice must be from 0 to {MAX_NICE}, not {nice}"
+            );
+        }
+        if let Some(cgroup_parent) = &self.cgroup_parent {
+            ensure!(
+                !cgroup_parent.trim().is_empty(),
+                "priority.cgroup-parent must not be empty"
+            );
+        }
+        Ok(())
+    }
+
+    /// These settings, with those of `overrides` taking precedence.
+    fn merge(&self, overrides: &PriorityConfig) -> PriorityConfig {
+        PriorityConfig {
+            nice: overrides.nice.or(self.nice),
+            cpu_quota: overrides.cpu_quota.or(self.cpu_quota),
+            cgroup_parent: overrides
+                .cgroup_parent
+                .clone()
+                .or_else(|| self.cgroup_parent.clone()),
+        }
+    }
+
+    /// Adds the environment variables which pass the priority to buildsys to `envs`, and caps how
+    /// many packages cargo builds at once. A cap already in `envs` is only lowered.
+    fn add_envs(&self, envs: &mut Vec<(&'static str, String)>) {
+        if let Some(nice) = self.nice {
+            envs.push(("BUILDSYS_NICE", nice.to_string()));
+        }
+        if let Some(cpu_quota) = self.cpu_quota {
+            envs.push(("BUILDSYS_CPU_QUOTA", cpu_quota.to_string()));
+            let jobs = envs
+                .iter()
+                .find(|(key, _)| *key == "CARGO_BUILD_JOBS")
+                .map(|(_, value)| value.clone())
+                .or_else(|| settings::var("CARGO_BUILD_JOBS").ok())
+                .and_then(|jobs| jobs.parse::<u32>().ok())
+                .map_or(cpu_quota.jobs(), |jobs| jobs.min(cpu_quota.jobs()));
+            envs.retain(|(key, _)| *key != "CARGO_BUILD_JOBS");
+            envs.push(("CARGO_BUILD_JOBS", jobs.to_string()));
+        }
+        if let Some(cgroup_parent) = &self.cgroup_parent {
+            envs.push(("BUILDSYS_CGROUP_PARENT", cgroup_parent.clone()));
+        }
+    }
+}
+
+/// Lowers the CPU and I/O priority of every thread of twoliter to `nice`, so that the processes it
+/// starts from then on run at that priority too.
+fn renice(nice: u8) -> Result<()> {
+    // Threads are scheduled on their own, so each is given the priority; threads started later
+    // inherit it from the thread which starts them.
+    let tasks =
+        std::fs::read_dir("/proc/self/task").context("failed to list twoliter's threads")?;
+    for task in tasks {
+        let task = task.context("failed to list twoliter's threads")?;
+        let Some(tid) = task
+            .file_name()
+            .to_str()
+            .and_then(|tid| tid.parse::<libc::id_t>().ok())
+        else {
+            continue;
+        };
+        // SAFETY: setpriority only reads its arguments.
+        let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, libc::c_int::from(nice)) };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("failed to lower the priority of thread {tid}"));
+        }
+        // The best-effort I/O class, at the level the kernel derives from the niceness for threads
+        // with no I/O priority of their own.
+        let ioprio = (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | libc::c_long::from((nice + 20) / 5);
+        // SAFETY: ioprio_set only reads its arguments.
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                tid as libc::c_long,
+                ioprio,
+            )
+        };
+        if result != 0 {
+            debug!(
+                "Failed to lower the I/O priority of thread {tid}: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    Ok(())
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// Applies the project's build priority, with `overrides` from the command line, to twoliter
+    /// and adds the environment variables which apply it to the build to `envs`.
+    pub(crate) fn apply_priority(
+        &self,
+        overrides: &PriorityConfig,
+        envs: &mut Vec<(&'static str, String)>,
+    ) -> Result<()> {
+        let priority = self.priority.merge(overrides);
+        priority.validate()?;
+        if let Some(nice) = priority.nice.filter(|nice| *nice > 0) {
+            info!("Running the build at niceness {nice}");
+            renice(nice)?;
+        }
+        priority.add_envs(envs);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cpu_quota() {
+        let config: PriorityConfig = toml::from_str("cpu-quota = 2.5").unwrap();
+        let quota = config.cpu_quota.unwrap();
+        assert_eq!(quota, "2.5".parse().unwrap());
+        assert_eq!(quota.to_string(), "2.5");
+        assert_eq!(quota.jobs(), 3);
+
+        let config: PriorityConfig = toml::from_str("cpu-quota = 4").unwrap();
+        assert_eq!(config.cpu_quota.unwrap().jobs(), 4);
+        assert_eq!("0.2".parse::<CpuQuota>().unwrap().jobs(), 1);
+
+        for invalid in ["0", "-1", "NaN", "four"] {
+            assert!(invalid.parse::<CpuQuota>().is_err(), "{invalid}");
+        }
+        assert!(toml::from_str::<PriorityConfig>("cpu-quota = 0").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let config: PriorityConfig = toml::from_str("nice = 20").unwrap();
+        assert!(config.validate().is_err());
+        let config: PriorityConfig = toml::from_str(r#"cgroup-parent = " ""#).unwrap();
+        assert!(config.validate().is_err());
+        assert!(toml::from_str::<PriorityConfig>("niceness = 5").is_err());
+    }
+
+    #[test]
+    fn test_envs() {
+        let config: PriorityConfig = toml::from_str(
+            r#"
+            nice = 10
+            cpu-quota = 8
+            cgroup-parent = "builds.slice"
+            "#,
+        )
+        .unwrap();
+        let overrides = PriorityConfig {
+            cpu_quota: Some("1.5".parse().unwrap()),
+            ..Default::default()
+        };
+        let priority = config.merge(&overrides);
+        assert_eq!(priority.nice, Some(10));
+
+        // A container's lower cap on jobs is kept.
+        let mut envs = vec![("CARGO_BUILD_JOBS", "1".to_string())];
+        priority.add_envs(&mut envs);
+        assert_eq!(
+            envs,
+            [
+                ("BUILDSYS_NICE", "10".to_string()),
+                ("BUILDSYS_CPU_QUOTA", "1.5".to_string()),
+                ("CARGO_BUILD_JOBS", "1".to_string()),
+                ("BUILDSYS_CGROUP_PARENT", "builds.slice".to_string()),
+            ]
+        );
+        let mut envs = vec![("CARGO_BUILD_JOBS", "16".to_string())];
+        priority.add_envs(&mut envs);
+        let jobs: Vec<_> = envs
+            .iter()
+            .filter(|(key, _)| *key == "CARGO_BUILD_JOBS")
+            .collect();
+        assert_eq!(jobs, [&("CARGO_BUILD_JOBS", "2".to_string())]);
+    }
+}
@@ -0,0 +1,201 @@
+//! Named sets of settings in `Twoliter.toml`, selected with `--profile` or `TWOLITER_PROFILE`, so
+//! that switching between everyday development and locked-down release builds is one flag.
+//!
+//! A profile is a table under `profile` which is merged over the rest of `Twoliter.toml` when it
+//! is selected: tables are merged key by key, and any other value replaces the one it overrides.
+//! A profile's `env` table sets environment variables for the run, for settings twoliter reads
+//! from the environment, such as the audit log or the proxy cache. They are seen by twoliter and
+//! passed to the tools it runs, but are not set in twoliter's own environment. For example:
+//!
+//! ```toml
+//! [vendor.my-vendor]
+//! registry = "public.ecr.aws/my-vendor"
+//!
+//! [profile.airgap.vendor.my-vendor]
+//! mirrors = ["registry.internal:5000/my-vendor"]
+//!
+//! [profile.release.publish.approval]
+//! required = 2
+//! allowed-signers = "approvers/allowed_signers"
+//!
+//! [profile.release.env]
+//! TWOLITER_AUDIT_LOG = "/var/log/twoliter/audit.jsonl"
+//! ```
+//!
+//! Mirrors are tried before the vendor's registry, but Twoliter.lock records each kit as coming
+//! from the vendor's registry, so profiles which are to share a lock should add mirrors rather
+//! than change a vendor's registry.
+use anyhow::{bail, ensure, Context, Result};
+use krane_static::settings;
+use std::collections::BTreeMap;
+use toml::{Table, Value};
+
+/// The environment variable naming the selected profile, which `--profile` sets.
+pub(crate) const PROFILE_ENV: &str = "TWOLITER_PROFILE";
+
+/// The settings of a profile which are not merged into `Twoliter.toml`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Profile {
+    /// The environment variables to set for the run.
+    pub(crate) env: BTreeMap<String, String>,
+}
+
+impl Profile {
+    /// Removes the profiles from the contents of `Twoliter.toml`, and merges the `selected` one
+    /// over the rest.
+    pub(crate) fn apply(project: &mut Table, selected: Option<&str>) -> Result<Self> {
+        let profiles = match project.remove("profile") {
+            Some(Value::Table(profiles)) => profiles,
+            Some(_) => bail!("'profile' in Twoliter.toml must be a table of profiles"),
+            None => Table::new(),
+        };
+        let Some(selected) = selected.filter(|name| !name.is_empty()) else {
+            return Ok(Self::default());
+        };
+        let mut profile = match profiles.get(selected) {
+            Some(Value::Table(profile)) => profile.clone(),
+            Some(_) => bail!("profile '{selected}' in Twoliter.toml must be a table"),
+            None => bail!(
+                "profile '{selected}' is not defined in Twoliter.toml, which defines {}",
+                match profiles
+                    .keys()
+                    .map(|name| format!("'{name}'"))
+                    .collect::<Vec<_>>()
+                {
+                    names if names.is_empty() => "no profiles".to_string(),
+                    names => names.join(", "),
+                }
+            ),
+        };
+        for key in ["schema-version", "profile"] {
+            ensure!(
+                !profile.contains_key(key),
+                "profile '{selected}' cannot set '{key}'"
+            );
+        }
+        let env = match profile.remove("env") {
+            Some(env) => env.try_into().context(format!(
+                "the env of profile '{selected}' must be a table of strings"
+            ))?,
+            None => BTreeMap::new(),
+        };
+        merge(project, profile);
+        Ok(Self { env })
+    }
+
+    /// Sets the profile's environment variables for the run.
+    pub(crate) fn apply_env(&self) {
+        for (key, value) in &self.env {
+            settings::set(key, value);
+        }
+    }
+}
+
+/// Merges `overrides` into `base`, table by table.
+fn merge(base: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overrides)) => merge(base, overrides),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PROJECT: &str = r#"
+schema-version = 1
+release-version = "1.0.0"
+
+[vendor.my-vendor]
+registry = "dev.example.com/kits"
+
+[[kit]]
+name = "core-kit"
+version = "1.0.0"
+vendor = "my-vendor"
+
+[publish.labels]
+"quay.expires-after" = "2w"
+
+[profile.release.publish.labels]
+"com.example.channel" = "stable"
+
+[profile.release.env]
+TWOLITER_AUDIT_LOG = "/var/log/twoliter/audit.jsonl"
+
+[profile.airgap.vendor.my-vendor]
+mirrors = ["localhost:5000/dev.example.com/kits"]
+"#;
+
+    fn project() -> Table {
+        toml::from_str(PROJECT).unwrap()
+    }
+
+    #[test]
+    fn test_no_profile() {
+        let mut project = project();
+        assert_eq!(
+            Profile::apply(&mut project, None).unwrap(),
+            Profile::default()
+        );
+        assert!(!project.contains_key("profile"));
+        assert_eq!(
+            project["vendor"]["my-vendor"]["registry"].as_str(),
+            Some("dev.example.com/kits")
+        );
+    }
+
+    #[test]
+    fn test_apply_profile() {
+        let mut project = project();
+        let profile = Profile::apply(&mut project, Some("release")).unwrap();
+        assert_eq!(
+            profile.env["TWOLITER_AUDIT_LOG"],
+            "/var/log/twoliter/audit.jsonl"
+        );
+        assert!(!project.contains_key("profile"));
+        assert!(!project.contains_key("env"));
+        let labels = project["publish"]["labels"].as_table().unwrap();
+        assert_eq!(labels.len(), 2);
+        assert_eq!(project["kit"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_profile_merges_tables() {
+        let mut project = project();
+        Profile::apply(&mut project, Some("airgap")).unwrap();
+        let vendor = project["vendor"]["my-vendor"].as_table().unwrap();
+        assert_eq!(vendor["registry"].as_str(), Some("dev.example.com/kits"));
+        assert_eq!(vendor["mirrors"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_unknown_profile() {
+        let err = Profile::apply(&mut project(), Some("prod")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "profile 'prod' is not defined in Twoliter.toml, which defines 'airgap', 'release'"
+        );
+        let mut project: Table = toml::from_str("schema-version = 1").unwrap();
+        assert!(Profile::apply(&mut project, Some("dev")).is_err());
+    }
+
+    #[test]
+    fn test_profile_cannot_set_schema_version() {
+        let mut project: Table = toml::from_str(
+            r#"
+schema-version = 1
+
+[profile.dev]
+schema-version = 2
+"#,
+        )
+        .unwrap();
+        assert!(Profile::apply(&mut project, Some("dev")).is_err());
+    }
+}
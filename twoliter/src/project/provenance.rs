@@ -0,0 +1,236 @@
+//! Traces a file in a Bottlerocket image back to the package, sources and patches it was built
+//! from, run by `twoliter provenance`.
+//!
+//! `rpm2kit` writes a record for each package in a kit to the kit's `provenance` directory,
+//! listing the sha512 hashes of the package's sources and patches and the files installed by
+//! each of its RPMs. The query covers the kits built by the project and the external kits it
+//! fetched. Kits built before the records were added have none, and are listed as such so that an
+//! empty answer is not mistaken for a complete one.
+use super::layers::kit_dirs;
+use super::{Project, ProjectLock};
+use crate::output::{Output, OutputSchema, PROVENANCE_SCHEMA};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// The directory of a kit which holds a provenance record for each of its packages.
+const PROVENANCE_DIR: &str = "provenance";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ProvenanceReport {
+    /// The path that was queried, as installed in the image.
+    pub(crate) path: String,
+    pub(crate) arch: String,
+    /// The RPMs which install the path. More than one kit may provide the same file.
+    pub(crate) providers: Vec<Provider>,
+    /// Kits which have no provenance records, so may provide the path unbeknownst to the report.
+    pub(crate) unrecorded: Vec<String>,
+}
+
+/// An RPM which installs the queried path, and what it was built from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Provider {
+    /// The kit's name, prefixed with its vendor for external kits.
+    pub(crate) kit: String,
+    pub(crate) package: String,
+    pub(crate) rpm: String,
+    pub(crate) sources: Vec<SourceFile>,
+    pub(crate) patches: Vec<SourceFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct SourceFile {
+    pub(crate) name: String,
+    pub(crate) sha512: String,
+}
+
+/// The record `rpm2kit` writes for each package in a kit.
+#[derive(Debug, Deserialize)]
+struct PackageRecord {
+    package: String,
+    #[serde(default)]
+    sources: Vec<SourceFile>,
+    #[serde(default)]
+    patches: Vec<SourceFile>,
+    /// The files installed by each of the package's RPMs, keyed by the RPM's file name.
+    #[serde(default)]
+    rpms: BTreeMap<String, Vec<String>>,
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// Finds the RPMs in the kits built or fetched for `arch` which install `path`.
+    pub(crate) fn provenance(&self, arch: &str, path: &str) -> Result<ProvenanceReport> {
+        let path = normalize(path);
+        let mut kits = kit_dirs(&self.project_dir.join("build/kits"), 1);
+        kits.extend(kit_dirs(&self.external_kits_dir(), 2));
+
+        let mut providers = Vec::new();
+        let mut unrecorded = Vec::new();
+        for (kit, dir) in kits {
+            let dir = dir.join(arch);
+            if !dir.is_dir() {
+                continue;
+            }
+            match kit_providers(&kit, &dir.join(PROVENANCE_DIR), &path)? {
+                Some(found) => providers.extend(found),
+                None => unrecorded.push(kit),
+            }
+        }
+        Ok(ProvenanceReport {
+            path,
+            arch: arch.to_string(),
+            providers,
+            unrecorded,
+        })
+    }
+}
+
+/// Paths are recorded as RPM installs them, absolute and without a trailing slash.
+fn normalize(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+/// The RPMs of one kit which install `path`, or `None` if the kit has no provenance records.
+fn kit_providers(kit: &str, dir: &Path, path: &str) -> Result<Option<Vec<Provider>>> {
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    let mut records = std::fs::read_dir(dir)
+        .context(format!("failed to read directory '{}'", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .context(format!("failed to read directory '{}'", dir.display()))?;
+    records.retain(|record| record.extension().is_some_and(|ext| ext == "json"));
+    records.sort();
+
+    let mut providers = Vec::new();
+    for record in records {
+        let contents =
+            std::fs::read(&record).context(format!("failed to read '{}'", record.display()))?;
+        let record: PackageRecord = serde_json::from_slice(&contents).context(format!(
+            "failed to parse provenance record '{}'",
+            record.display()
+        ))?;
+        for (rpm, files) in &record.rpms {
+            if files.iter().any(|file| file == path) {
+                providers.push(Provider {
+                    kit: kit.to_string(),
+                    package: record.package.clone(),
+                    rpm: rpm.clone(),
+                    sources: record.sources.clone(),
+                    patches: record.patches.clone(),
+                });
+            }
+        }
+    }
+    Ok(Some(providers))
+}
+
+impl Display for ProvenanceReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.providers.is_empty() {
+            write!(
+                f,
+                "No kit built or fetched for {} installs '{}'",
+                self.arch, self.path
+            )?;
+        }
+        for (i, provider) in self.providers.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "'{}' is installed by {} from package '{}' in kit '{}'",
+                self.path, provider.rpm, provider.package, provider.kit
+            )?;
+            for (kind, files) in [
+                ("Sources", &provider.sources),
+                ("Patches", &provider.patches),
+            ] {
+                if files.is_empty() {
+                    continue;
+                }
+                write!(f, "\n  {kind}:")?;
+                for file in files {
+                    write!(f, "\n    {} (sha512:{})", file.name, file.sha512)?;
+                }
+            }
+        }
+        if !self.unrecorded.is_empty() {
+            write!(
+                f,
+                "\nThese kits were built without provenance records and were not searched: {}",
+                self.unrecorded.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Output for ProvenanceReport {
+    const SCHEMA: OutputSchema = PROVENANCE_SCHEMA;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("usr/bin/apiclient"), "/usr/bin/apiclient");
+        assert_eq!(normalize("/usr/share/licenses/"), "/usr/share/licenses");
+    }
+
+    #[test]
+    fn test_kit_providers() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = &temp_dir.path().join(PROVENANCE_DIR);
+        assert!(kit_providers("my-kit", dir, "/usr/bin/a")
+            .unwrap()
+            .is_none());
+
+        let record = r#"{
+            "package": "app",
+            "sources": [{"name": "app-1.0.tar.gz", "sha512": "aa"}],
+            "patches": [{"name": "0001-fix.patch", "sha512": "bb"}],
+            "rpms": {
+                "bottlerocket-app-1.0-1.x86_64.rpm": ["/usr/bin/app", "/usr/lib/app"],
+                "bottlerocket-app-extra-1.0-1.x86_64.rpm": ["/usr/bin/app-extra"]
+            }
+        }"#;
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("app.json"), record).unwrap();
+        std::fs::write(dir.join("README"), "not a record").unwrap();
+
+        let providers = kit_providers("my-kit", dir, "/usr/bin/app")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            providers,
+            [Provider {
+                kit: "my-kit".to_string(),
+                package: "app".to_string(),
+                rpm: "bottlerocket-app-1.0-1.x86_64.rpm".to_string(),
+                sources: vec![SourceFile {
+                    name: "app-1.0.tar.gz".to_string(),
+                    sha512: "aa".to_string(),
+                }],
+                patches: vec![SourceFile {
+                    name: "0001-fix.patch".to_string(),
+                    sha512: "bb".to_string(),
+                }],
+            }]
+        );
+        assert!(kit_providers("my-kit", dir, "/usr/bin/other")
+            .unwrap()
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,293 @@
+//! Settings from the `publish` section of `Twoliter.toml`, which control how artifacts are
+//! published.
+//!
+//! Annotations and labels listed directly under `publish` apply whenever a kit is published.
+//! Those listed under `publish.vendor.<name>` apply only when publishing to that vendor, and take
+//! precedence over the shared ones. For example:
+//!
+//! ```toml
+//! [publish.annotations]
+//! "com.example.retention" = "90d"
+//!
+//! [publish.vendor.my-dev-vendor.labels]
+//! "quay.expires-after" = "2w"
+//! ```
+//!
+//! Kits are always tagged with their version, e.g. `v1.2.3`. Additional tags, such as floating
+//! version tags or channels, point at the same manifest list. `{major}`, `{minor}`, `{patch}` and
+//! `{version}` in a tag are replaced with the parts of the release version. Tags are immutable
+//! unless marked otherwise, so publishing fails rather than move them once they are set:
+//!
+//! ```toml
+//! [[publish.tags]]
+//! tag = "v{major}.{minor}"
+//! mutable = true
+//!
+//! [[publish.vendor.my-dev-vendor.tags]]
+//! tag = "latest"
+//! mutable = true
+//! ```
+//!
+//! An `approval` policy, described in [`super::approval`], requires publishes to be approved by
+//! several people. The shared policy also covers promoting variants. A vendor's policy replaces
+//! the shared one, as does a vendor's `layer-compression`, described in [`super::compression`],
+//! and a vendor's `compatibility` matrix of the SDK and kit versions that the kit works with.
+use super::approval::ApprovalPolicy;
+use super::compression::LayerCompression;
+use super::lock::CompatibilityMatrix;
+use anyhow::{ensure, Context, Result};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default, Clone, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PublishConfig {
+    #[serde(flatten)]
+    shared: PublishMetadata,
+
+    /// Settings which only apply when publishing to a given vendor.
+    #[serde(default)]
+    vendor: BTreeMap<String, PublishMetadata>,
+}
+
+impl PublishConfig {
+    /// Returns the metadata to attach to images published to `vendor`.
+    pub(crate) fn metadata_for(&self, vendor: &str) -> PublishMetadata {
+        let mut metadata = self.shared.clone();
+        if let Some(vendor_metadata) = self.vendor.get(vendor) {
+            metadata
+                .annotations
+                .extend(vendor_metadata.annotations.clone());
+            metadata.labels.extend(vendor_metadata.labels.clone());
+            metadata.tags.retain(|tag| {
+                !vendor_metadata
+                    .tags
+                    .iter()
+                    .any(|vendor_tag| vendor_tag.tag == tag.tag)
+            });
+            metadata.tags.extend(vendor_metadata.tags.clone());
+            if vendor_metadata.approval.is_some() {
+                metadata.approval = vendor_metadata.approval.clone();
+            }
+            if vendor_metadata.layer_compression.is_some() {
+                metadata.layer_compression = vendor_metadata.layer_compression;
+            }
+            if vendor_metadata.compatibility.is_some() {
+                metadata.compatibility = vendor_metadata.compatibility.clone();
+            }
+        }
+        metadata
+    }
+
+    /// Returns who must approve promoting a variant, which is the shared approval policy, since
+    /// variants are not published to a vendor.
+    pub(crate) fn promotion_approval(&self) -> Option<&ApprovalPolicy> {
+        self.shared.approval.as_ref()
+    }
+}
+
+/// Metadata to attach to published images, for example registry lifecycle hints such as
+/// `quay.expires-after`.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PublishMetadata {
+    /// Annotations to add to the image manifest.
+    #[serde(default)]
+    pub(crate) annotations: BTreeMap<String, String>,
+
+    /// Labels to add to the image config.
+    #[serde(default)]
+    pub(crate) labels: BTreeMap<String, String>,
+
+    /// Tags to point at the kit besides its version.
+    #[serde(default)]
+    pub(crate) tags: Vec<PublishTag>,
+
+    /// Who must approve a publish before it proceeds.
+    #[serde(default)]
+    pub(crate) approval: Option<ApprovalPolicy>,
+
+    /// How to compress the kit's layers.
+    #[serde(default)]
+    pub(crate) layer_compression: Option<LayerCompression>,
+
+    /// The versions of the SDK and other kits that the kit works with.
+    #[serde(default)]
+    pub(crate) compatibility: Option<CompatibilityMatrix>,
+}
+
+/// A tag to point at a published kit.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct PublishTag {
+    /// The tag, which may refer to parts of the release version.
+    pub(crate) tag: String,
+
+    /// Whether the tag may be moved to a newer release once set.
+    #[serde(default)]
+    pub(crate) mutable: bool,
+}
+
+impl PublishTag {
+    /// Replaces the references to the release version in the tag with the parts of `version`.
+    pub(crate) fn expand(&self, version: &str) -> Result<Self> {
+        let parsed = Version::parse(version).context(format!(
+            "release-version '{version}' is not a semantic version"
+        ))?;
+        let tag = self
+            .tag
+            .replace("{major}", &parsed.major.to_string())
+            .replace("{minor}", &parsed.minor.to_string())
+            .replace("{patch}", &parsed.patch.to_string())
+            .replace("{version}", version);
+        ensure!(
+            is_valid_tag(&tag),
+            "publish tag '{}' expands to '{tag}', which is not a valid image tag",
+            self.tag
+        );
+        Ok(Self {
+            tag,
+            mutable: self.mutable,
+        })
+    }
+}
+
+/// Whether `tag` is a valid OCI image tag.
+fn is_valid_tag(tag: &str) -> bool {
+    let mut chars = tag.chars();
+    tag.len() <= 128
+        && chars
+            .next()
+            .is_some_and(|first| first.is_ascii_alphanumeric() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PUBLISH: &str = r#"
+[annotations]
+"com.example.retention" = "90d"
+"com.example.owner" = "team-a"
+
+[vendor.dev.annotations]
+"com.example.retention" = "7d"
+
+[vendor.dev.labels]
+"quay.expires-after" = "2w"
+"#;
+
+    #[test]
+    fn test_metadata_for_vendor_overrides_shared() {
+        let config: PublishConfig = toml::from_str(PUBLISH).unwrap();
+        let metadata = config.metadata_for("dev");
+        assert_eq!(metadata.annotations["com.example.retention"], "7d");
+        assert_eq!(metadata.annotations["com.example.owner"], "team-a");
+        assert_eq!(metadata.labels["quay.expires-after"], "2w");
+    }
+
+    #[test]
+    fn test_vendor_tags_override_shared() {
+        let config: PublishConfig = toml::from_str(
+            r#"
+[[tags]]
+tag = "v{major}"
+mutable = true
+
+[[tags]]
+tag = "latest"
+
+[[vendor.dev.tags]]
+tag = "latest"
+mutable = true
+"#,
+        )
+        .unwrap();
+        let tags = config.metadata_for("dev").tags;
+        assert_eq!(tags.len(), 2);
+        assert!(tags.iter().all(|tag| tag.mutable));
+        assert!(!config.metadata_for("prod").tags[1].mutable);
+    }
+
+    #[test]
+    fn test_expand_tag() {
+        let tag = |tag: &str| PublishTag {
+            tag: tag.to_string(),
+            mutable: true,
+        };
+        assert_eq!(tag("v{major}.{minor}").expand("2.1.3").unwrap().tag, "v2.1");
+        assert_eq!(
+            tag("{version}-stable").expand("2.1.3").unwrap().tag,
+            "2.1.3-stable"
+        );
+        assert!(tag("v{major}/latest").expand("2.1.3").is_err());
+        assert!(tag("v{major}").expand("not-a-version").is_err());
+    }
+
+    #[test]
+    fn test_vendor_approval_overrides_shared() {
+        let config: PublishConfig = toml::from_str(
+            r#"
+[approval]
+required = 1
+url = "https://approvals.example.com/v1/check"
+
+[vendor.prod.approval]
+required = 2
+allowed-signers = "approvers/allowed_signers"
+"#,
+        )
+        .unwrap();
+        let prod = config.metadata_for("prod").approval.unwrap();
+        let dev = config.metadata_for("dev").approval.unwrap();
+        assert_ne!(prod, dev);
+        assert_eq!(config.shared.approval, Some(dev));
+    }
+
+    #[test]
+    fn test_vendor_layer_compression_overrides_shared() {
+        let config: PublishConfig = toml::from_str(
+            r#"
+layer-compression = "gzip:9"
+
+[vendor.dev]
+layer-compression = "zstd"
+"#,
+        )
+        .unwrap();
+        let compression = |vendor: &str| config.metadata_for(vendor).layer_compression.unwrap();
+        assert_eq!(compression("dev").to_string(), "zstd:3");
+        assert_eq!(compression("prod").to_string(), "gzip:9");
+        assert!(toml::from_str::<PublishConfig>(r#"layer-compression = "xz""#).is_err());
+    }
+
+    #[test]
+    fn test_vendor_compatibility_overrides_shared() {
+        let config: PublishConfig = toml::from_str(
+            r#"
+[compatibility]
+sdk = "^0.50"
+
+[vendor.dev.compatibility]
+sdk = ">=0.50"
+kits = { core-kit = "^2" }
+"#,
+        )
+        .unwrap();
+        assert_ne!(
+            config.metadata_for("dev").compatibility,
+            config.metadata_for("prod").compatibility
+        );
+        assert!(toml::from_str::<PublishConfig>("compatibility = { sdk = \"latest\" }").is_err());
+    }
+
+    #[test]
+    fn test_metadata_for_other_vendor_is_shared() {
+        let config: PublishConfig = toml::from_str(PUBLISH).unwrap();
+        let metadata = config.metadata_for("prod");
+        assert_eq!(metadata.annotations["com.example.retention"], "90d");
+        assert!(metadata.labels.is_empty());
+    }
+}
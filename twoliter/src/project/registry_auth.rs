@@ -0,0 +1,138 @@
+//! Credentials for private registries which host the SDK or kits, such as ECR, Artifact Registry
+//! or ACR, declared in the `registry` section of `Twoliter.toml`, so that `twoliter update` and
+//! `twoliter fetch` reach them without a prior `docker login`.
+//!
+//! Each registry's credentials come from a docker credential helper, or from environment variables
+//! holding a username and a password or token, and are never written in `Twoliter.toml`:
+//!
+//! ```toml
+//! [registry."123456789012.dkr.ecr.us-west-2.amazonaws.com".auth]
+//! credential-helper = "ecr-login"
+//!
+//! [registry."registry.example.com".auth]
+//! username-env = "EXAMPLE_REGISTRY_USER"
+//! password-env = "EXAMPLE_REGISTRY_TOKEN"
+//! ```
+//!
+//! The configured credentials are used ahead of those the host has, when resolving and pulling
+//! images and when publishing. Registries which are not configured are reached with the host's
+//! credentials, as before.
+use super::{Project, ProjectLock};
+use crate::warnings;
+use anyhow::{bail, ensure, Context, Result};
+use krane_static::settings;
+use oci_cli_wrapper::registry_auth::{self, RegistryAuth, REGISTRY_AUTH_ENV};
+use serde::Deserialize;
+use tracing::debug;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct RegistryConfig {
+    pub(crate) auth: Option<RegistryAuth>,
+}
+
+impl RegistryConfig {
+    /// Checks that `host` names a registry, and that its credentials come from exactly one source.
+    pub(crate) fn validate(&self, host: &str) -> Result<()> {
+        ensure!(
+            !host.is_empty() && !host.contains(['/', ' ']),
+            "registry '{host}' must be a host with an optional port, e.g. \
+            'registry.example.com:5000'"
+        );
+        let Some(auth) = &self.auth else {
+            return Ok(());
+        };
+        match (
+            &auth.credential_helper,
+            &auth.username_env,
+            &auth.password_env,
+        ) {
+            (Some(helper), None, None) => ensure!(
+                !helper.is_empty() && !helper.contains(['/', ' ']),
+                "registry.\"{host}\".auth.credential-helper must name a docker credential helper, \
+                e.g. 'ecr-login' for docker-credential-ecr-login, not '{helper}'"
+            ),
+            (None, Some(username_env), Some(password_env)) => ensure!(
+                !username_env.is_empty() && !password_env.is_empty(),
+                "registry.\"{host}\".auth must name the environment variables holding its \
+                credentials"
+            ),
+            _ => bail!(
+                "registry.\"{host}\".auth must set either credential-helper, or both username-env \
+                and password-env"
+            ),
+        }
+        Ok(())
+    }
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// Makes the credentials configured for registries available to every registry access by
+    /// twoliter and the tools it runs, ahead of those the host has. Credentials already configured
+    /// in the environment are kept for registries which the project does not configure.
+    pub(crate) fn apply_registry_auth(&self) -> Result<()> {
+        let mut configured = registry_auth::configured();
+        for (host, registry) in &self.registry {
+            let Some(auth) = &registry.auth else {
+                continue;
+            };
+            for var in [&auth.username_env, &auth.password_env]
+                .into_iter()
+                .flatten()
+            {
+                if settings::var_os(var).is_none() {
+                    warnings::warn(format!(
+                        "The credentials for registry '{host}' are read from {var}, which is not set"
+                    ));
+                }
+            }
+            configured.insert(host.clone(), auth.clone());
+        }
+        if configured.is_empty() {
+            return Ok(());
+        }
+        debug!(
+            "Using the credentials configured for {}",
+            configured.keys().cloned().collect::<Vec<_>>().join(", ")
+        );
+        let configured = serde_json::to_string(&configured)
+            .context("failed to serialize registry credentials")?;
+        settings::set(REGISTRY_AUTH_ENV, configured);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let config: RegistryConfig =
+            toml::from_str("auth = { credential-helper = \"ecr-login\" }").unwrap();
+        config
+            .validate("123456789012.dkr.ecr.us-west-2.amazonaws.com")
+            .unwrap();
+
+        let config: RegistryConfig = toml::from_str(
+            "auth = { username-env = \"REGISTRY_USER\", password-env = \"REGISTRY_TOKEN\" }",
+        )
+        .unwrap();
+        config.validate("registry.example.com:5000").unwrap();
+        assert!(config.validate("https://registry.example.com").is_err());
+
+        for invalid in [
+            "auth = { username-env = \"REGISTRY_USER\" }",
+            "auth = { credential-helper = \"ecr-login\", password-env = \"REGISTRY_TOKEN\" }",
+            "auth = { credential-helper = \"/usr/bin/helper\" }",
+            "auth = {}",
+        ] {
+            let config: RegistryConfig = toml::from_str(invalid).unwrap();
+            assert!(
+                config.validate("registry.example.com").is_err(),
+                "{invalid}"
+            );
+        }
+        assert!(toml::from_str::<RegistryConfig>("auth = { password = \"hunter2\" }").is_err());
+    }
+}
@@ -0,0 +1,317 @@
+//! Release bookkeeping for kits, run by `twoliter version bump`.
+//!
+//! Kits built by a project are published at the project's `release-version`, so bumping one of
+//! them bumps the `release-version` in `Twoliter.toml`, and in `Release.toml` if the project still
+//! has one. Bumping a kit the project depends on bumps its `[[kit]]` entry instead. Either way, the
+//! `[[kit]]` entries of the consuming projects given are moved to the new version, so that their
+//! next `twoliter update` picks it up rather than failing against a version that was never
+//! published.
+//!
+//! Files are edited in place, leaving their formatting and comments as they were.
+use super::lint::read_build_dependencies;
+use super::{Project, ProjectLock};
+use crate::common::exec_log;
+use crate::common::fs::{read_to_string, write};
+use anyhow::{ensure, Context, Result};
+use semver::Version;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use toml::Table;
+use tracing::info;
+
+/// The part of a version to increment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BumpLevel {
+    Major,
+    Minor,
+    Patch,
+}
+
+impl BumpLevel {
+    /// Increments `version` at this level, resetting the parts below it.
+    fn apply(self, version: &Version) -> Version {
+        match self {
+            BumpLevel::Major => Version::new(version.major + 1, 0, 0),
+            BumpLevel::Minor => Version::new(version.major, version.minor + 1, 0),
+            BumpLevel::Patch => Version::new(version.major, version.minor, version.patch + 1),
+        }
+    }
+}
+
+/// The changes made by a version bump.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct VersionBump {
+    pub(crate) kit: String,
+    pub(crate) old: Version,
+    pub(crate) new: Version,
+    /// The other kits built by the project, which share its release version.
+    pub(crate) also_bumped: Vec<String>,
+    pub(crate) files: Vec<PathBuf>,
+    pub(crate) tag: Option<String>,
+}
+
+impl Display for VersionBump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Bumped '{}' from {} to {}", self.kit, self.old, self.new)?;
+        if !self.also_bumped.is_empty() {
+            write!(
+                f,
+                ", along with {} which share the project's release-version",
+                self.also_bumped.join(", ")
+            )?;
+        }
+        for file in &self.files {
+            write!(f, "\n  updated {}", file.display())?;
+        }
+        if let Some(tag) = &self.tag {
+            write!(f, "\n  tagged {tag}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// Bumps `kit` at `level`, updating the references to it in this project and in the projects
+    /// `consumers`. With `tag`, commits the changed files and tags the commit.
+    pub(crate) async fn bump_version(
+        &self,
+        kit: &str,
+        level: BumpLevel,
+        consumers: &[PathBuf],
+        tag: bool,
+    ) -> Result<VersionBump> {
+        let local_kits = read_build_dependencies(&self.project_dir.join("kits")).await?;
+        let mut files = Vec::new();
+        let (old, new, also_bumped) = if local_kits.contains_key(kit) {
+            let old = Version::parse(&self.release_version).context(format!(
+                "release-version '{}' is not a semantic version",
+                self.release_version
+            ))?;
+            let new = level.apply(&old);
+            let new_version = new.to_string();
+            edit_toml(&self.filepath, |content| {
+                set_string(content, "release-version", &new_version, |header, _| {
+                    header.is_empty()
+                })
+            })
+            .await?;
+            files.push(self.filepath.clone());
+
+            let release_toml = self.project_dir.join("Release.toml");
+            if release_toml.is_file() {
+                edit_toml(&release_toml, |content| {
+                    set_string(content, "version", &new_version, |header, _| {
+                        header.is_empty()
+                    })
+                })
+                .await?;
+                files.push(release_toml);
+            }
+            let also_bumped = local_kits.into_keys().filter(|name| name != kit).collect();
+            (old, new, also_bumped)
+        } else {
+            let dependency = self
+                .kit
+                .iter()
+                .find(|dependency| dependency.name.as_ref() == kit)
+                .context(format!(
+                    "'{kit}' is neither built by this project nor one of its kit dependencies"
+                ))?;
+            let old = dependency.version.clone();
+            let new = level.apply(&old);
+            set_kit_dependency(&self.filepath, kit, &new).await?;
+            files.push(self.filepath.clone());
+            (old, new, Vec::new())
+        };
+
+        for consumer in consumers {
+            let project_file = if consumer.is_dir() {
+                consumer.join("Twoliter.toml")
+            } else {
+                consumer.clone()
+            };
+            set_kit_dependency(&project_file, kit, &new).await?;
+            info!(
+                "Run 'twoliter update' in '{}' to lock {kit} {new}",
+                project_file.parent().unwrap_or(Path::new(".")).display()
+            );
+            files.push(project_file);
+        }
+
+        let tag = if tag {
+            Some(self.commit_and_tag(kit, &new, &files).await?)
+        } else {
+            None
+        };
+        Ok(VersionBump {
+            kit: kit.to_string(),
+            old,
+            new,
+            also_bumped,
+            files,
+            tag,
+        })
+    }
+
+    /// Commits `files` and tags the commit as the release of `kit` at `version`.
+    async fn commit_and_tag(
+        &self,
+        kit: &str,
+        version: &Version,
+        files: &[PathBuf],
+    ) -> Result<String> {
+        let tag = format!("{kit}-v{version}");
+        let message = format!("Release {kit} {version}");
+        exec_log(
+            Command::new("git")
+                .arg("-C")
+                .arg(&self.project_dir)
+                .args(["commit", "--message", &message, "--"])
+                .args(files),
+        )
+        .await
+        .context("failed to commit the version bump")?;
+        exec_log(Command::new("git").arg("-C").arg(&self.project_dir).args([
+            "tag",
+            "--annotate",
+            &tag,
+            "--message",
+            &message,
+        ]))
+        .await
+        .context(format!("failed to create tag '{tag}'"))?;
+        Ok(tag)
+    }
+}
+
+/// Sets the version of every `[[kit]]` entry named `kit` in the project file `path`.
+pub(super) async fn set_kit_dependency(path: &Path, kit: &str, version: &Version) -> Result<()> {
+    let version = version.to_string();
+    edit_toml(path, |content| {
+        set_string(content, "version", &version, |header, body| {
+            header == "[[kit]]"
+                && body
+                    .iter()
+                    .any(|line| parse_line(line) == Some(("name", kit)))
+        })
+    })
+    .await
+    .context(format!("no kit named '{kit}' in '{}'", path.display()))
+}
+
+/// Rewrites the TOML file at `path` with `edit`, which returns the new content and the number of
+/// values it changed, and checks that the file is still valid TOML.
+async fn edit_toml(path: &Path, edit: impl FnOnce(&str) -> (String, usize)) -> Result<()> {
+    let content = read_to_string(path).await?;
+    let (edited, changes) = edit(&content);
+    ensure!(changes > 0, "nothing to update in '{}'", path.display());
+    toml::from_str::<Table>(&edited).context(format!(
+        "editing '{}' would leave it invalid",
+        path.display()
+    ))?;
+    write(path, edited).await
+}
+
+/// Sets each `key` found in the sections of the TOML document `content` selected by `select` to
+/// the string `value`, keeping everything else as it is. `select` sees each section's header, which
+/// is empty for the lines before the first table, and its lines. Returns the new content, and the
+/// number of values set.
+fn set_string(
+    content: &str,
+    key: &str,
+    value: &str,
+    select: impl Fn(&str, &[&str]) -> bool,
+) -> (String, usize) {
+    let mut sections: Vec<(&str, Vec<&str>)> = vec![("", Vec::new())];
+    for line in content.split_inclusive('\n') {
+        if line.trim_start().starts_with('[') {
+            sections.push((line.trim(), Vec::new()));
+        }
+        if let Some((_, lines)) = sections.last_mut() {
+            lines.push(line);
+        }
+    }
+
+    let mut edited = String::with_capacity(content.len());
+    let mut changes = 0;
+    for (header, lines) in &sections {
+        let selected = select(header, lines);
+        for line in lines {
+            match (selected, parse_line(line), quoted_range(line)) {
+                (true, Some((line_key, _)), Some((start, end))) if line_key == key => {
+                    edited.push_str(&line[..start]);
+                    edited.push_str(value);
+                    edited.push_str(&line[end..]);
+                    changes += 1;
+                }
+                _ => edited.push_str(line),
+            }
+        }
+    }
+    (edited, changes)
+}
+
+/// Splits a `key = "value"` line into its key and string value.
+fn parse_line(line: &str) -> Option<(&str, &str)> {
+    let (key, _) = line.split_once('=')?;
+    let (start, end) = quoted_range(line)?;
+    Some((key.trim().trim_matches('"'), &line[start..end]))
+}
+
+/// The byte range of the string value of a `key = "value"` line, within its quotes.
+fn quoted_range(line: &str) -> Option<(usize, usize)> {
+    let equals = line.find('=')?;
+    let rest = &line[equals + 1..];
+    let value = rest.trim_start().strip_prefix('"')?;
+    let start = line.len() - value.len();
+    let end = start + value.find('"')?;
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bump_level() {
+        let version = Version::parse("1.4.2-rc.1").unwrap();
+        assert_eq!(BumpLevel::Major.apply(&version), Version::new(2, 0, 0));
+        assert_eq!(BumpLevel::Minor.apply(&version), Version::new(1, 5, 0));
+        assert_eq!(BumpLevel::Patch.apply(&version), Version::new(1, 4, 3));
+    }
+
+    #[test]
+    fn test_set_string() {
+        let content = r#"schema-version = 1
+release-version = "1.0.0" # bumped by releases
+
+[[kit]]
+name = "core-kit"
+version = "2.0.0"
+vendor = "bottlerocket"
+
+[[kit]]
+name = "extra-kit"
+version = "2.0.0"
+vendor = "bottlerocket"
+"#;
+        let (edited, changes) = set_string(content, "release-version", "1.1.0", |header, _| {
+            header.is_empty()
+        });
+        assert_eq!(changes, 1);
+        assert!(edited.contains("release-version = \"1.1.0\" # bumped by releases\n"));
+
+        let (edited, changes) = set_string(content, "version", "2.1.0", |header, body| {
+            header == "[[kit]]"
+                && body
+                    .iter()
+                    .any(|l| parse_line(l) == Some(("name", "core-kit")))
+        });
+        assert_eq!(changes, 1);
+        let table: Table = toml::from_str(&edited).unwrap();
+        let kits = table["kit"].as_array().unwrap();
+        assert_eq!(kits[0]["version"].as_str(), Some("2.1.0"));
+        assert_eq!(kits[1]["version"].as_str(), Some("2.0.0"));
+    }
+}
@@ -0,0 +1,116 @@
+//! Builds a container image which CI jobs can run a project's builds from, created by
+//! `twoliter bundle runner`.
+//!
+//! The image is based on the project's SDK and holds this twoliter binary along with the tools
+//! embedded in it, the docker CLI, and the project's `Twoliter.toml` and `Twoliter.lock`. Images
+//! are pinned to the digests they had when the runner was built. Its entrypoint runs twoliter, and
+//! warns when the mounted project's lock differs from the one the image was built for. Jobs still
+//! need to give the runner access to a docker daemon, e.g. by mounting its socket.
+use super::{Locked, Project};
+use crate::common::exec_log;
+use crate::common::fs::{copy, write};
+use crate::docker::Docker;
+use anyhow::{ensure, Context, Result};
+use oci_cli_wrapper::ImageTool;
+use std::path::Path;
+use tracing::info;
+
+/// The image the docker CLI is copied from.
+const DOCKER_CLI_IMAGE: &str = "public.ecr.aws/docker/library/docker:27-cli";
+
+/// Where the runner image keeps the project files it was built for.
+const RUNNER_PROJECT_DIR: &str = "/etc/twoliter";
+
+const ENTRYPOINT: &str = r#"#!/bin/sh
+if [ -f Twoliter.lock ] && ! cmp -s Twoliter.lock /etc/twoliter/Twoliter.lock; then
+  echo "warning: Twoliter.lock differs from the one this runner image was built for" >&2
+fi
+exec twoliter "$@"
+"#;
+
+/// Renders the runner's Dockerfile, based on the pinned `base` image.
+fn dockerfile(base: &str, docker_cli: &str, release_version: &str) -> String {
+    format!(
+        r#"FROM {docker_cli} AS docker
+FROM {base}
+LABEL dev.bottlerocket.twoliter.version="{twoliter_version}" \
+      dev.bottlerocket.twoliter.release-version="{release_version}"
+USER root
+COPY --from=docker /usr/local/bin/docker /usr/local/bin/
+COPY --from=docker /usr/local/libexec/docker/cli-plugins/ /usr/local/libexec/docker/cli-plugins/
+COPY twoliter entrypoint /usr/local/bin/
+COPY Twoliter.toml Twoliter.lock {RUNNER_PROJECT_DIR}/
+RUN chmod 0755 /usr/local/bin/twoliter /usr/local/bin/entrypoint
+ENTRYPOINT ["/usr/local/bin/entrypoint"]
+"#,
+        twoliter_version = env!("CARGO_PKG_VERSION"),
+    )
+}
+
+impl Project<Locked> {
+    /// Builds the runner image for this project and tags it as `tag`. When `output` is given, the
+    /// image is written there as an OCI archive instead of being loaded into the docker daemon.
+    pub(crate) async fn bundle_runner(&self, tag: &str, output: Option<&Path>) -> Result<()> {
+        let context_dir = tempfile::tempdir().context("failed to create runner build context")?;
+        let context = context_dir.path();
+
+        let twoliter = std::env::current_exe().context("failed to find the twoliter binary")?;
+        copy(&twoliter, context.join("twoliter")).await?;
+        copy(self.filepath(), context.join("Twoliter.toml")).await?;
+        copy(self.lock_file_path(), context.join("Twoliter.lock")).await?;
+        write(context.join("entrypoint"), ENTRYPOINT).await?;
+
+        let base = pinned_uri(&self.sdk_image().project_image_uri().uri()).await?;
+        let docker_cli = pinned_uri(DOCKER_CLI_IMAGE).await?;
+        write(
+            context.join("Dockerfile"),
+            dockerfile(&base, &docker_cli, self.release_version()),
+        )
+        .await?;
+
+        let tool = Docker::tool()?;
+        ensure!(
+            output.is_none() || tool.has_buildx(),
+            "Writing the runner image to an archive needs docker's buildx, which {tool} does not have"
+        );
+        info!("Building runner image '{tag}' from '{base}'");
+        let mut command = Docker::command()?;
+        match output {
+            Some(output) => command
+                .args(["buildx", "build", "--output"])
+                .arg(format!("type=oci,dest={}", output.display())),
+            None => command.arg("build"),
+        };
+        exec_log(command.args(["--tag", tag]).arg(context)).await
+    }
+}
+
+/// Pins `uri` to the digest its tag currently points at.
+async fn pinned_uri(uri: &str) -> Result<String> {
+    let digest = ImageTool::registry().get_digest(uri).await?;
+    // Drop the tag, taking care not to mistake a registry port for one.
+    let name_start = uri.rfind('/').map_or(0, |slash| slash + 1);
+    let repo = match uri[name_start..].rfind(':') {
+        Some(colon) => &uri[..name_start + colon],
+        None => uri,
+    };
+    Ok(format!("{repo}@{digest}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dockerfile() {
+        let dockerfile = dockerfile(
+            "example.com/sdk@sha256:abc",
+            "example.com/docker@sha256:def",
+            "1.2.3",
+        );
+        assert!(dockerfile.starts_with("FROM example.com/docker@sha256:def AS docker\n"));
+        assert!(dockerfile.contains("\nFROM example.com/sdk@sha256:abc\n"));
+        assert!(dockerfile.contains("release-version=\"1.2.3\""));
+        assert!(dockerfile.contains("COPY Twoliter.toml Twoliter.lock /etc/twoliter/"));
+    }
+}
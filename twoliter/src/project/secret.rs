@@ -0,0 +1,183 @@
+//! Credentials that package builds need, such as tokens for private crate registries or Go module
+//! proxies, declared in the `secret` section of `Twoliter.toml`.
+//!
+//! Each secret is read from one source: an environment variable, a file, or the output of a
+//! command, which can fetch it from an external secret store or decrypt an encrypted file. For
+//! example:
+//!
+//! ```toml
+//! [secret.cargo-registry-token]
+//! env = "CARGO_REGISTRY_TOKEN"
+//!
+//! [secret.goproxy-netrc]
+//! file = "/home/builder/.netrc"
+//!
+//! [secret.npm-token]
+//! command = ["sops", "--decrypt", "--extract", "[\"npm\"]", "secrets.enc.yaml"]
+//! ```
+//!
+//! A package receives only the secrets it names in `package.metadata.build-package.secrets`.
+//! Before a build, the secrets are written to a private temporary directory outside the project,
+//! which buildsys reads through `BUILDSYS_SECRETS_DIR` and which is removed when the build ends.
+//! Secret values are never logged, not even when a command that fetches one fails.
+use super::{Project, ProjectLock, ValidIdentifier};
+use anyhow::{ensure, Context, Result};
+use krane_static::settings;
+use serde::Deserialize;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Stdio;
+use tempfile::TempDir;
+use tokio::process::Command;
+use tracing::debug;
+
+/// The environment variable through which buildsys finds the resolved secrets.
+const SECRETS_DIR_ENV: &str = "BUILDSYS_SECRETS_DIR";
+
+/// Where to read a secret from.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) enum Secret {
+    /// An environment variable of the twoliter process.
+    Env(String),
+    /// A file, relative to the project directory unless absolute.
+    File(PathBuf),
+    /// A command and its arguments, run from the project directory, whose output is the secret.
+    /// One trailing newline is removed from the output.
+    Command(Vec<String>),
+}
+
+impl Secret {
+    pub(crate) fn validate(&self, name: &ValidIdentifier) -> Result<()> {
+        if let Secret::Command(command) = self {
+            ensure!(!command.is_empty(), "secret '{name}' has an empty command");
+        }
+        Ok(())
+    }
+}
+
+/// The project's secrets, written to a private directory for the duration of a build.
+#[derive(Debug)]
+pub(crate) struct ResolvedSecrets {
+    dir: TempDir,
+}
+
+impl ResolvedSecrets {
+    /// The environment variable which tells buildsys where to find the secrets.
+    pub(crate) fn env(&self) -> (&'static str, String) {
+        (SECRETS_DIR_ENV, self.dir.path().display().to_string())
+    }
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// Reads every secret declared in Twoliter.toml into a private directory, or returns `None` if
+    /// the project declares no secrets. The directory is removed when the result is dropped.
+    pub(crate) async fn resolve_secrets(&self) -> Result<Option<ResolvedSecrets>> {
+        if self.secrets.is_empty() {
+            return Ok(None);
+        }
+        let dir = TempDir::with_prefix("twoliter-secrets-")
+            .context("failed to create a directory for the project's secrets")?;
+        for (name, secret) in &self.secrets {
+            debug!("Resolving secret '{name}'");
+            let value = self
+                .read_secret(secret)
+                .await
+                .context(format!("failed to read secret '{name}'"))?;
+            let path = dir.path().join(name.as_ref());
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            options
+                .open(&path)
+                .and_then(|mut file| file.write_all(&value))
+                .context(format!("failed to write secret '{name}'"))?;
+        }
+        Ok(Some(ResolvedSecrets { dir }))
+    }
+
+    async fn read_secret(&self, secret: &Secret) -> Result<Vec<u8>> {
+        match secret {
+            Secret::Env(var) => settings::var(var)
+                .map(String::into_bytes)
+                .context(format!("environment variable '{var}' is not set")),
+            Secret::File(path) => {
+                let path = self.project_dir.join(path);
+                std::fs::read(&path).context(format!("failed to read '{}'", path.display()))
+            }
+            Secret::Command(command) => {
+                // The command's output is the secret, so it is captured and never shown. Its
+                // errors are shown as they are written.
+                let mut cmd = Command::new(&command[0]);
+                settings::apply(cmd.as_std_mut());
+                let output = cmd
+                    .args(&command[1..])
+                    .current_dir(&self.project_dir)
+                    .stdin(Stdio::null())
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .await
+                    .context(format!("failed to run '{}'", command[0]))?;
+                ensure!(
+                    output.status.success(),
+                    "'{}' failed with exit code {}",
+                    command[0],
+                    output.status.code().unwrap_or(1)
+                );
+                let mut value = output.stdout;
+                if value.last() == Some(&b'\n') {
+                    value.pop();
+                }
+                Ok(value)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Secrets {
+        secret: std::collections::BTreeMap<ValidIdentifier, Secret>,
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let secrets: Secrets = toml::from_str(
+            r#"
+            [secret.token]
+            env = "TOKEN"
+
+            [secret.netrc]
+            file = "secrets/netrc"
+
+            [secret.npm]
+            command = ["sops", "--decrypt", "npm.enc"]
+            "#,
+        )
+        .unwrap();
+        let secret = |name: &str| &secrets.secret[&ValidIdentifier(name.to_string())];
+        assert_eq!(secret("token"), &Secret::Env("TOKEN".to_string()));
+        assert_eq!(secret("netrc"), &Secret::File("secrets/netrc".into()));
+        assert_eq!(
+            secret("npm"),
+            &Secret::Command(vec![
+                "sops".to_string(),
+                "--decrypt".to_string(),
+                "npm.enc".to_string()
+            ])
+        );
+
+        assert!(toml::from_str::<Secrets>("[secret.both]\nenv = \"A\"\nfile = \"b\"").is_err());
+        let empty = Secret::Command(Vec::new());
+        assert!(empty
+            .validate(&ValidIdentifier("empty".to_string()))
+            .is_err());
+    }
+}
@@ -0,0 +1,499 @@
+//! A content-addressed cache of image blobs shared by all of a user's projects, so that layers which
+//! several projects use, such as those of a kit they all depend on, are downloaded and stored once.
+//!
+//! Kit archives are still kept in each project's kit archive cache, but their blobs are hard links
+//! to those in the shared cache, and an archive whose blobs are all in the shared cache is
+//! assembled from them instead of being pulled. The cache is found at `$TWOLITER_CACHE_DIR`, or else
+//! at `$XDG_CACHE_HOME/twoliter` or `~/.cache/twoliter`, and setting `TWOLITER_CACHE_DIR` to an
+//! empty value turns it off. It is laid out as the blobs of an OCI layout:
+//!
+//! ```text
+//! blobs/sha256/<digest>
+//! ```
+//!
+//! Nothing is removed from the cache until `twoliter cache gc`, which removes the blobs used least
+//! recently. A blob that a project's archive still links to only frees its space once the archive
+//! is removed as well.
+use super::budget::ByteSize;
+use super::lock::hash_file;
+use crate::output::{Output, OutputSchema, CACHE_GC_SCHEMA};
+use crate::warnings;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use filetime::{set_file_mtime, FileTime};
+use krane_static::settings;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt::{Display, Formatter};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+/// The environment variable naming the shared cache, which turns it off when empty.
+pub(crate) const SHARED_CACHE_ENV: &str = "TWOLITER_CACHE_DIR";
+
+const OCI_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SharedCache {
+    root: PathBuf,
+}
+
+/// The parts of an image manifest needed to find its blobs.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestBlobs {
+    media_type: Option<String>,
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+    digest: String,
+}
+
+/// When `twoliter cache gc` removes a blob from the shared cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct GcPolicy {
+    /// The most the blobs may take up, after which those used least recently are removed.
+    pub(crate) max_size: Option<u64>,
+    /// How long ago a blob may have been last used before it is removed.
+    pub(crate) max_age: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct GcReport {
+    root: PathBuf,
+    removed: usize,
+    removed_size: u64,
+    /// The space freed, which leaves out blobs that a project's kit archive still links to.
+    freed: u64,
+    kept: usize,
+    kept_size: u64,
+}
+
+/// A blob in the shared cache, as found by `twoliter cache gc`.
+#[derive(Debug)]
+struct CachedBlob {
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+    links: Option<u64>,
+}
+
+impl SharedCache {
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// The cache at the configured location, unless it has been turned off.
+    pub(crate) fn configured() -> Option<Self> {
+        match settings::var_os(SHARED_CACHE_ENV) {
+            Some(dir) if dir.is_empty() => None,
+            Some(dir) => Some(Self::new(dir)),
+            None => settings::var_os("XDG_CACHE_HOME")
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .or_else(|| settings::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+                .map(|dir| Self::new(dir.join("twoliter"))),
+        }
+    }
+
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn blobs_dir(&self) -> PathBuf {
+        self.root.join("blobs")
+    }
+
+    fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        let (algorithm, encoded) = digest
+            .split_once(':')
+            .context(format!("invalid digest '{digest}'"))?;
+        ensure!(
+            [algorithm, encoded]
+                .iter()
+                .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric())),
+            "invalid digest '{digest}'"
+        );
+        Ok(self.blobs_dir().join(algorithm).join(encoded))
+    }
+
+    /// Whether the cache holds the blob with `digest` unchanged. A blob that has changed is removed,
+    /// so that it is replaced by the next pull which needs it.
+    fn holds(&self, digest: &str) -> Result<bool> {
+        let path = self.blob_path(digest)?;
+        let Some(expected) = digest.strip_prefix("sha256:") else {
+            return Ok(false);
+        };
+        if !path.is_file() {
+            return Ok(false);
+        }
+        if hash_file(&path)? == expected {
+            return Ok(true);
+        }
+        warnings::warn(format!(
+            "Removing '{}' from the shared cache, since it no longer matches its digest",
+            path.display()
+        ));
+        std::fs::remove_file(&path).context(format!("failed to remove '{}'", path.display()))?;
+        Ok(false)
+    }
+
+    /// Writes the OCI layout of the image manifest with `digest` into the empty directory `layout`
+    /// from the cached blobs. Returns whether the cache held all of the image's blobs, and so
+    /// whether the layout was written.
+    pub(crate) fn assemble(&self, digest: &str, layout: &Path) -> Result<bool> {
+        let Ok(manifest) = std::fs::read(self.blob_path(digest)?) else {
+            return Ok(false);
+        };
+        let Ok(blobs) = serde_json::from_slice::<ManifestBlobs>(&manifest) else {
+            return Ok(false);
+        };
+        let digests = std::iter::once(digest)
+            .chain(blobs.config.iter().map(|config| config.digest.as_str()))
+            .chain(blobs.layers.iter().map(|layer| layer.digest.as_str()))
+            .collect::<Vec<_>>();
+        for digest in &digests {
+            if !self.holds(digest)? {
+                debug!("The shared cache lacks '{digest}'");
+                return Ok(false);
+            }
+        }
+
+        for digest in &digests {
+            let cached = self.blob_path(digest)?;
+            link_or_copy(
+                &cached,
+                &layout.join("blobs").join(digest.replace(':', "/")),
+            )?;
+            touch(&cached)?;
+        }
+        let media_type = blobs
+            .media_type
+            .unwrap_or_else(|| "application/vnd.oci.image.manifest.v1+json".to_string());
+        let index = json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_INDEX_MEDIA_TYPE,
+            "manifests": [{ "mediaType": media_type, "digest": digest, "size": manifest.len() }],
+        });
+        write(&layout.join("index.json"), index.to_string().as_bytes())?;
+        write(
+            &layout.join("oci-layout"),
+            br#"{"imageLayoutVersion":"1.0.0"}"#,
+        )?;
+        Ok(true)
+    }
+
+    /// Moves the blobs of the OCI layout at `layout` into the cache, leaving hard links to them in
+    /// their place. Blobs the cache already holds replace those in the layout instead.
+    pub(crate) fn adopt(&self, layout: &Path) -> Result<()> {
+        for (digest, blob) in layout_blobs(&layout.join("blobs"))? {
+            let cached = self.blob_path(&digest)?;
+            if self.holds(&digest)? {
+                link_or_copy(&cached, &blob)?;
+            } else {
+                // Only blobs which match their digest are shared with other projects.
+                let expected = digest.strip_prefix("sha256:");
+                if expected.map_or(true, |expected| {
+                    hash_file(&blob).ok().as_deref() != Some(expected)
+                }) {
+                    debug!(
+                        "Not sharing '{}', which does not match its digest",
+                        blob.display()
+                    );
+                    continue;
+                }
+                link_or_copy(&blob, &cached)?;
+            }
+            touch(&cached)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the blobs which `policy` no longer allows, those used least recently first.
+    pub(crate) fn gc(&self, policy: &GcPolicy, now: SystemTime) -> Result<GcReport> {
+        let mut blobs = layout_blobs(&self.blobs_dir())?
+            .into_iter()
+            .map(|(_, path)| {
+                let metadata = std::fs::metadata(&path)
+                    .context(format!("failed to read metadata of '{}'", path.display()))?;
+                Ok(CachedBlob {
+                    size: metadata.len(),
+                    last_used: metadata.modified()?,
+                    links: link_count(&metadata),
+                    path,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        blobs.sort_by_key(|blob| blob.last_used);
+
+        let mut total: u64 = blobs.iter().map(|blob| blob.size).sum();
+        let mut report = GcReport {
+            root: self.root.clone(),
+            ..GcReport::default()
+        };
+        for blob in blobs {
+            let expired = policy.max_age.is_some_and(|max_age| {
+                now.duration_since(blob.last_used).unwrap_or_default() > max_age
+            });
+            let over_size = policy.max_size.is_some_and(|max_size| total > max_size);
+            if expired || over_size {
+                debug!("Removing '{}' from the shared cache", blob.path.display());
+                std::fs::remove_file(&blob.path)
+                    .context(format!("failed to remove '{}'", blob.path.display()))?;
+                total -= blob.size;
+                report.removed += 1;
+                report.removed_size += blob.size;
+                if blob.links.is_some_and(|links| links <= 1) {
+                    report.freed += blob.size;
+                }
+            } else {
+                report.kept += 1;
+                report.kept_size += blob.size;
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// How many hard links there are to a file, where the platform tells.
+fn link_count(metadata: &Metadata) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(metadata.nlink())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// The digests and paths of the blobs in the `blobs` directory of an OCI layout.
+fn layout_blobs(blobs_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut blobs = Vec::new();
+    let Ok(algorithms) = std::fs::read_dir(blobs_dir) else {
+        return Ok(blobs);
+    };
+    for algorithm in algorithms {
+        let algorithm = algorithm.context(format!("failed to read '{}'", blobs_dir.display()))?;
+        if !algorithm.file_type()?.is_dir() {
+            continue;
+        }
+        let algorithm_dir = algorithm.path();
+        for blob in std::fs::read_dir(&algorithm_dir)
+            .context(format!("failed to read '{}'", algorithm_dir.display()))?
+        {
+            let blob = blob.context(format!("failed to read '{}'", algorithm_dir.display()))?;
+            if !blob.file_type()?.is_file() {
+                continue;
+            }
+            blobs.push((
+                format!(
+                    "{}:{}",
+                    algorithm.file_name().to_string_lossy(),
+                    blob.file_name().to_string_lossy()
+                ),
+                blob.path(),
+            ));
+        }
+    }
+    Ok(blobs)
+}
+
+/// Replaces `dst` with a hard link to `src`, or with a copy of it when they are on different
+/// filesystems.
+fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    let dir = dst
+        .parent()
+        .context(format!("'{}' has no parent", dst.display()))?;
+    std::fs::create_dir_all(dir).context(format!("failed to create '{}'", dir.display()))?;
+    let temp = tempfile::Builder::new()
+        .prefix(".link-")
+        .tempdir_in(dir)
+        .context(format!(
+            "failed to create a directory in '{}'",
+            dir.display()
+        ))?;
+    let staged = temp.path().join("blob");
+    if std::fs::hard_link(src, &staged).is_err() {
+        std::fs::copy(src, &staged).context(format!(
+            "failed to copy '{}' to '{}'",
+            src.display(),
+            staged.display()
+        ))?;
+    }
+    std::fs::rename(&staged, dst).context(format!(
+        "failed to move '{}' to '{}'",
+        staged.display(),
+        dst.display()
+    ))
+}
+
+/// Marks a cached blob as just used, which `twoliter cache gc` goes by.
+fn touch(path: &Path) -> Result<()> {
+    set_file_mtime(path, FileTime::now())
+        .context(format!("failed to update the time of '{}'", path.display()))
+}
+
+fn write(path: &Path, contents: &[u8]) -> Result<()> {
+    std::fs::write(path, contents).context(format!("failed to write '{}'", path.display()))
+}
+
+/// Parses an age such as `30d`, in seconds (`s`), minutes (`m`), hours (`h`), days (`d`) or weeks
+/// (`w`).
+pub(crate) fn parse_age(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("'{s}' is not an age, such as '30d'"))?;
+    let seconds = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        unit => bail!("unknown unit '{unit}' in age '{s}'"),
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+impl Display for GcReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Removed {} blobs ({}) from the shared cache at '{}', freeing {}; kept {} blobs ({})",
+            self.removed,
+            ByteSize(self.removed_size),
+            self.root.display(),
+            ByteSize(self.freed),
+            self.kept,
+            ByteSize(self.kept_size)
+        )
+    }
+}
+
+impl Output for GcReport {
+    const SCHEMA: OutputSchema = CACHE_GC_SCHEMA;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sha2::{Digest, Sha256};
+    use tempfile::TempDir;
+
+    /// Writes `contents` into the OCI layout at `layout` and returns its digest.
+    fn write_blob(layout: &Path, contents: &str) -> String {
+        let encoded = format!("{:x}", Sha256::digest(contents));
+        let dir = layout.join("blobs/sha256");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(&encoded), contents).unwrap();
+        format!("sha256:{encoded}")
+    }
+
+    /// Writes an image into the OCI layout at `layout` and returns the digest of its manifest.
+    fn write_image(layout: &Path, layer: &str) -> String {
+        let layer = write_blob(layout, layer);
+        let config = write_blob(layout, "{}");
+        write_blob(
+            layout,
+            &format!(r#"{{"config":{{"digest":"{config}"}},"layers":[{{"digest":"{layer}"}}]}}"#),
+        )
+    }
+
+    #[test]
+    fn test_adopt_and_assemble() {
+        let temp_dir = TempDir::new().unwrap();
+        let cache = SharedCache::new(temp_dir.path().join("cache"));
+        let pulled = temp_dir.path().join("pulled");
+        let digest = write_image(&pulled, "layer");
+        assert!(!cache
+            .assemble(&digest, &temp_dir.path().join("empty"))
+            .unwrap());
+
+        cache.adopt(&pulled).unwrap();
+        let layer = format!("blobs/sha256/{:x}", Sha256::digest("layer"));
+        #[cfg(unix)]
+        assert_eq!(
+            link_count(&std::fs::metadata(pulled.join(&layer)).unwrap()),
+            Some(2)
+        );
+
+        let assembled = temp_dir.path().join("assembled");
+        assert!(cache.assemble(&digest, &assembled).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(assembled.join(&layer)).unwrap(),
+            "layer"
+        );
+        let index: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(assembled.join("index.json")).unwrap()).unwrap();
+        assert_eq!(index["manifests"][0]["digest"], digest.as_str());
+
+        // A blob that changed is dropped from the cache rather than handed out.
+        std::fs::remove_file(pulled.join(&layer)).unwrap();
+        std::fs::remove_file(assembled.join(&layer)).unwrap();
+        std::fs::write(cache.root().join(&layer), "changed").unwrap();
+        assert!(!cache
+            .assemble(&digest, &temp_dir.path().join("again"))
+            .unwrap());
+        assert!(!cache.root().join(&layer).exists());
+    }
+
+    #[test]
+    fn test_gc() {
+        let temp_dir = TempDir::new().unwrap();
+        let cache = SharedCache::new(temp_dir.path().join("cache"));
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        for (contents, age) in [("old", 10), ("older", 20), ("new", 0)] {
+            let digest = write_blob(cache.root(), contents);
+            let path = cache.blob_path(&digest).unwrap();
+            set_file_mtime(&path, FileTime::from_system_time(now - day * age)).unwrap();
+        }
+
+        // Nothing is removed without a policy.
+        let report = cache.gc(&GcPolicy::default(), now).unwrap();
+        assert_eq!((report.removed, report.kept), (0, 3));
+
+        // The oldest blobs go first, until what is left fits.
+        let policy = GcPolicy {
+            max_size: Some(6),
+            max_age: None,
+        };
+        let report = cache.gc(&policy, now).unwrap();
+        assert_eq!(
+            (report.removed, report.removed_size, report.freed),
+            (1, 5, 5)
+        );
+        let old = format!("sha256:{:x}", Sha256::digest("old"));
+        assert!(cache.blob_path(&old).unwrap().exists());
+
+        let policy = GcPolicy {
+            max_size: None,
+            max_age: Some(day * 5),
+        };
+        let report = cache.gc(&policy, now).unwrap();
+        assert_eq!((report.removed, report.kept, report.kept_size), (1, 1, 3));
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(
+            parse_age("30d").unwrap(),
+            Duration::from_secs(30 * 24 * 60 * 60)
+        );
+        assert_eq!(parse_age("12h").unwrap(), Duration::from_secs(12 * 60 * 60));
+        assert!(parse_age("30").is_err());
+        assert!(parse_age("1y").is_err());
+        assert!(parse_age("d").is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! Verifies the cosign signatures of the images of vendors which sign them, so that a kit or SDK is
+//! only locked if its publisher signed it.
+//!
+//! A vendor's images are verified by giving the vendor a `signature` policy in `Twoliter.toml`,
+//! either with a public key, which is a file relative to the project or anything else cosign
+//! accepts as a key, such as a KMS URI:
+//!
+//! ```toml
+//! [vendor.my-vendor.signature]
+//! key = "keys/my-vendor.pub"
+//! ```
+//!
+//! or keylessly, with the identity that signs the images and the issuer of its certificate:
+//!
+//! ```toml
+//! [vendor.my-vendor.signature]
+//! certificate-identity-regexp = "^https://github.com/my-org/kits/.github/workflows/publish.yml@"
+//! certificate-oidc-issuer = "https://token.actions.githubusercontent.com"
+//! ```
+//!
+//! The signature is checked with `cosign verify` against the digest of the image's manifest list,
+//! before the image is accepted into `Twoliter.lock`. `cosign` must be installed to resolve the
+//! images of vendors with a policy.
+use crate::common::exec;
+use crate::diagnostic::Code;
+use crate::messages::msg;
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::process::Command;
+use tracing::{debug, info};
+
+/// How the images of a vendor must be signed.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct SignaturePolicy {
+    /// The public key the images are signed with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+
+    /// The identity in the signing certificate of keyless signatures.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    certificate_identity: Option<String>,
+
+    /// A regular expression matching the identity in the signing certificate of keyless
+    /// signatures.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    certificate_identity_regexp: Option<String>,
+
+    /// The OIDC issuer of the signing certificate of keyless signatures.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    certificate_oidc_issuer: Option<String>,
+}
+
+impl SignaturePolicy {
+    /// Checks that the policy of `vendor` verifies signatures in exactly one way.
+    pub(crate) fn validate(&self, vendor: &str) -> Result<()> {
+        let keyless = self.certificate_identity.is_some()
+            || self.certificate_identity_regexp.is_some()
+            || self.certificate_oidc_issuer.is_some();
+        ensure!(
+            self.key.is_some() != keyless,
+            "the signature policy of vendor '{vendor}' needs either a key, or a certificate \
+            identity and OIDC issuer for keyless signatures, but not both"
+        );
+        if keyless {
+            ensure!(
+                self.certificate_identity.is_some() != self.certificate_identity_regexp.is_some(),
+                "the signature policy of vendor '{vendor}' needs one of certificate-identity or \
+                certificate-identity-regexp"
+            );
+            ensure!(
+                self.certificate_oidc_issuer.is_some(),
+                "the signature policy of vendor '{vendor}' needs a certificate-oidc-issuer"
+            );
+        }
+        Ok(())
+    }
+
+    /// Resolves a key file relative to the project in `project_dir`. Keys which cosign fetches
+    /// itself, such as from a KMS, are left as they are.
+    pub(crate) fn relative_to(mut self, project_dir: &Path) -> Self {
+        if let Some(key) = self.key.as_mut().filter(|key| !key.contains("://")) {
+            *key = project_dir.join(&*key).display().to_string();
+        }
+        self
+    }
+
+    /// The arguments of `cosign verify` which apply the policy.
+    fn args(&self) -> Vec<String> {
+        [
+            ("--key", &self.key),
+            ("--certificate-identity", &self.certificate_identity),
+            (
+                "--certificate-identity-regexp",
+                &self.certificate_identity_regexp,
+            ),
+            ("--certificate-oidc-issuer", &self.certificate_oidc_issuer),
+        ]
+        .into_iter()
+        .filter_map(|(flag, value)| value.as_ref().map(|value| format!("{flag}={value}")))
+        .collect()
+    }
+
+    /// Verifies that `image`, a kit or SDK of `vendor` served at `uri` by digest, is signed as the
+    /// policy requires.
+    pub(crate) async fn verify(&self, vendor: &str, image: &str, uri: &str) -> Result<()> {
+        debug!("Verifying the signature of '{uri}'");
+        exec(
+            Command::new("cosign")
+                .arg("verify")
+                .args(self.args())
+                .args(["--output", "json"])
+                .arg(uri),
+            true,
+        )
+        .await
+        .map_err(|e| {
+            Code::UnverifiedSignature.error(msg!(
+                "error.unverified-signature",
+                image = image,
+                vendor = vendor,
+                reason = e.to_string().trim(),
+            ))
+        })?;
+        info!("Verified the signature of '{image}'");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy(toml: &str) -> SignaturePolicy {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn test_key_policy() {
+        let policy = policy(r#"key = "keys/vendor.pub""#);
+        policy.validate("vendor").unwrap();
+        assert_eq!(
+            policy.relative_to(Path::new("/project")).args(),
+            ["--key=/project/keys/vendor.pub"]
+        );
+        let kms = self::policy(r#"key = "awskms:///alias/kit-signing""#);
+        assert_eq!(
+            kms.relative_to(Path::new("/project")).args(),
+            ["--key=awskms:///alias/kit-signing"]
+        );
+    }
+
+    #[test]
+    fn test_keyless_policy() {
+        let policy = policy(
+            r#"
+certificate-identity-regexp = "^https://github.com/my-org/"
+certificate-oidc-issuer = "https://token.actions.githubusercontent.com"
+"#,
+        );
+        policy.validate("vendor").unwrap();
+        assert_eq!(
+            policy.args(),
+            [
+                "--certificate-identity-regexp=^https://github.com/my-org/",
+                "--certificate-oidc-issuer=https://token.actions.githubusercontent.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_policies() {
+        for invalid in [
+            "",
+            "key = \"a.pub\"\ncertificate-oidc-issuer = \"https://issuer\"",
+            "certificate-identity = \"me\"",
+            "certificate-oidc-issuer = \"https://issuer\"",
+            "certificate-identity = \"me\"\ncertificate-identity-regexp = \"me\"\n\
+            certificate-oidc-issuer = \"https://issuer\"",
+        ] {
+            assert!(
+                policy(invalid).validate("vendor").is_err(),
+                "{invalid} should be invalid"
+            );
+        }
+        assert!(toml::from_str::<SignaturePolicy>("keys = \"a.pub\"").is_err());
+    }
+}
@@ -0,0 +1,294 @@
+//! Custom build steps declared in the `step` section of `Twoliter.toml`, run by
+//! `twoliter step run`.
+//!
+//! A step declares the files it reads and the directories it writes, and runs in the project's SDK
+//! with only its inputs mounted, read-only, its outputs writable, and no network access, so a
+//! script the step runs must be one of its inputs. Because everything a step depends on is
+//! declared, a step is skipped when its command, inputs and SDK are unchanged since it last
+//! succeeded and its outputs are still present. Builds run the steps which are out of date before
+//! they start, and take the steps' inputs and outputs into their cache keys, so that packages are
+//! rebuilt when a step's results change. For example:
+//!
+//! ```toml
+//! [step.motd]
+//! command = ["scripts/render-motd", "build/motd"]
+//! inputs = ["scripts/render-motd", "motd"]
+//! outputs = ["build/motd"]
+//! ```
+//!
+//! Paths are relative to the project directory, and inputs may name files or directories. A path
+//! which leads out of the project directory through a symlink is rejected when the step runs.
+use super::lock::{hash_file, hash_tree};
+use super::{LockedSDKProvider, Project, ValidIdentifier};
+use crate::common::exec_log;
+use crate::common::fs::{create_dir_all, read_to_string, write};
+use crate::docker::Docker;
+use anyhow::{ensure, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::{Component, Path, PathBuf};
+use tracing::info;
+
+/// Where the project is mounted within a step's container.
+const STEP_PROJECT_DIR: &str = "/project";
+
+/// Keeps builds of unchanged inputs reproducible, since steps cannot see the time they last ran.
+const SOURCE_DATE_EPOCH: &str = "0";
+
+/// The variable which carries the key of the project's steps into builds, which rebuild whenever
+/// it changes.
+pub(crate) const STEPS_KEY_ENV: &str = "BUILDSYS_STEPS_KEY";
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Step {
+    /// The command to run and its arguments, run from the project directory.
+    command: Vec<String>,
+
+    /// The files and directories the step reads.
+    #[serde(default)]
+    inputs: Vec<PathBuf>,
+
+    /// The directories the step writes.
+    #[serde(default)]
+    outputs: Vec<PathBuf>,
+}
+
+impl Step {
+    /// Checks that the step has a command, and that its paths stay within the project.
+    pub(crate) fn validate(&self, name: &ValidIdentifier) -> Result<()> {
+        ensure!(!self.command.is_empty(), "step '{name}' has no command");
+        for path in self.inputs.iter().chain(&self.outputs) {
+            ensure!(
+                is_contained(path),
+                "path '{}' of step '{name}' must be relative to the project directory, and stay \
+                within it",
+                path.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Whether `path` is relative and never leaves the directory it is relative to.
+fn is_contained(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Where `path` in `project_dir` leads once symlinks are followed, which must be within the project
+/// directory. The parts of `path` which do not exist yet are taken as they are, since they cannot
+/// be symlinks.
+fn resolve_path(project_dir: &Path, path: &Path) -> Result<PathBuf> {
+    let root = std::fs::canonicalize(project_dir).context(format!(
+        "failed to resolve the project directory '{}'",
+        project_dir.display()
+    ))?;
+    let mut existing = root.join(path);
+    let mut missing = Vec::new();
+    while existing.symlink_metadata().is_err() {
+        let Some(name) = existing.file_name().map(ToOwned::to_owned) else {
+            break;
+        };
+        missing.push(name);
+        existing.pop();
+    }
+    let mut resolved = std::fs::canonicalize(&existing)
+        .context(format!("failed to resolve '{}'", path.display()))?;
+    resolved.extend(missing.into_iter().rev());
+    ensure!(
+        resolved.starts_with(&root),
+        "path '{}' leads outside the project directory, to '{}'",
+        path.display(),
+        resolved.display()
+    );
+    Ok(resolved)
+}
+
+impl<T: LockedSDKProvider> Project<T> {
+    /// Runs the step called `name` in the project's SDK, unless nothing it depends on has changed
+    /// since it last succeeded. Returns whether the step ran.
+    pub(crate) async fn run_step(&self, step_name: &str, force: bool) -> Result<bool> {
+        let (name, step) = self
+            .steps
+            .iter()
+            .find(|(name, _)| name.as_ref() == step_name)
+            .context(format!("no step named '{step_name}' in Twoliter.toml"))?;
+        self.run_declared_step(name, step, force).await
+    }
+
+    /// Runs each of the project's steps which is out of date, in the order of their names, and
+    /// returns a key of what they read and wrote for builds to take into their cache keys. Returns
+    /// `None` when the project declares no steps.
+    pub(crate) async fn run_steps(&self) -> Result<Option<String>> {
+        if self.steps.is_empty() {
+            return Ok(None);
+        }
+        let mut hasher = Sha256::new();
+        for (name, step) in &self.steps {
+            self.run_declared_step(name, step, false).await?;
+            let key = read_to_string(self.step_key_file(name)).await?;
+            hasher.update(format!("{name}\0{key}\0"));
+            for output in &step.outputs {
+                hasher.update(format!("\n>{}\0", output.display()));
+                for (file, hash) in hash_tree(&resolve_path(&self.project_dir, output)?)? {
+                    hasher.update(format!("{}\0{hash}\0", file.display()));
+                }
+            }
+        }
+        Ok(Some(hex::encode(hasher.finalize())))
+    }
+
+    async fn run_declared_step(
+        &self,
+        name: &ValidIdentifier,
+        step: &Step,
+        force: bool,
+    ) -> Result<bool> {
+        let sdk = self.sdk_image().project_image_uri().to_string();
+        let key = self.step_key(name, step, &sdk)?;
+        let key_file = self.step_key_file(name);
+        let outputs = step
+            .outputs
+            .iter()
+            .map(|output| resolve_path(&self.project_dir, output))
+            .collect::<Result<Vec<_>>>()
+            .context(format!("step '{name}' has an output outside the project"))?;
+        let outputs_present = outputs.iter().all(|output| output.exists());
+        if !force && outputs_present && read_to_string(&key_file).await.ok() == Some(key.clone()) {
+            info!("Step '{name}' is up to date");
+            return Ok(false);
+        }
+
+        for output in &outputs {
+            create_dir_all(output).await?;
+        }
+        info!("Running step '{name}'");
+        let tool = Docker::tool()?;
+        let mut command = Docker::command()?;
+        command.args(["run", "--rm", "--network", "none"]);
+        command.args(tool.run_args());
+        // Run the SDK for the platform of the machine which runs containers, which on a Mac is a
+        // Linux VM, rather than whichever the tool would pick for the host.
+        command.args(["--platform", &Docker::host_platform().await?]);
+        // Run as the project's owner, so that the step's outputs belong to them.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let owner = std::fs::metadata(&self.project_dir).context(format!(
+                "failed to read metadata of '{}'",
+                self.project_dir.display()
+            ))?;
+            command.args(["--user", &format!("{}:{}", owner.uid(), owner.gid())]);
+        }
+        command
+            .args(["--env", &format!("SOURCE_DATE_EPOCH={SOURCE_DATE_EPOCH}")])
+            .args(["--workdir", STEP_PROJECT_DIR]);
+        for input in &step.inputs {
+            command.arg("--volume").arg(format!(
+                "{}:{STEP_PROJECT_DIR}/{}:ro",
+                resolve_path(&self.project_dir, input)?.display(),
+                input.display()
+            ));
+        }
+        for (output, path) in step.outputs.iter().zip(&outputs) {
+            command.arg("--volume").arg(format!(
+                "{}:{STEP_PROJECT_DIR}/{}",
+                path.display(),
+                output.display()
+            ));
+        }
+        exec_log(command.arg(&sdk).args(&step.command))
+            .await
+            .context(format!("step '{name}' failed"))?;
+
+        if let Some(state_dir) = key_file.parent() {
+            create_dir_all(state_dir).await?;
+        }
+        write(&key_file, key).await?;
+        Ok(true)
+    }
+
+    fn step_key_file(&self, name: &ValidIdentifier) -> PathBuf {
+        self.project_dir
+            .join("build/state/steps")
+            .join(format!("{name}.key"))
+    }
+
+    /// Hashes everything the step's results depend on.
+    fn step_key(&self, name: &ValidIdentifier, step: &Step, sdk: &str) -> Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{name}\0{sdk}\0"));
+        for arg in &step.command {
+            hasher.update(format!("{arg}\0"));
+        }
+        for input in &step.inputs {
+            let path = resolve_path(&self.project_dir, input)
+                .context(format!("step '{name}' has an input outside the project"))?;
+            ensure!(
+                path.exists(),
+                "input '{}' of step '{name}' does not exist",
+                input.display()
+            );
+            hasher.update(format!("\n{}\0", input.display()));
+            if path.is_dir() {
+                for (file, hash) in hash_tree(&path)? {
+                    hasher.update(format!("{}\0{hash}\0", file.display()));
+                }
+            } else {
+                hasher.update(hash_file(&path)?);
+            }
+        }
+        for output in &step.outputs {
+            hasher.update(format!("\n>{}", output.display()));
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate_paths() {
+        let name = ValidIdentifier("motd".to_string());
+        let step = |outputs: &[&str]| Step {
+            command: vec!["true".to_string()],
+            inputs: vec![PathBuf::from("scripts/render-motd")],
+            outputs: outputs.iter().map(PathBuf::from).collect(),
+        };
+        assert!(step(&["build/motd"]).validate(&name).is_ok());
+        assert!(step(&["../elsewhere"]).validate(&name).is_err());
+        assert!(step(&["/etc"]).validate(&name).is_err());
+
+        let no_command = Step {
+            command: Vec::new(),
+            ..step(&[])
+        };
+        assert!(no_command.validate(&name).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_path() {
+        let project_dir = tempfile::TempDir::new().unwrap();
+        let outside = tempfile::TempDir::new().unwrap();
+        let project = project_dir.path();
+        std::fs::create_dir(project.join("scripts")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), project.join("escape")).unwrap();
+        std::os::unix::fs::symlink("scripts", project.join("linked")).unwrap();
+
+        let root = std::fs::canonicalize(project).unwrap();
+        assert_eq!(
+            resolve_path(project, Path::new("linked/render-motd")).unwrap(),
+            root.join("scripts/render-motd")
+        );
+        assert_eq!(
+            resolve_path(project, Path::new("build/motd")).unwrap(),
+            root.join("build/motd")
+        );
+        assert!(resolve_path(project, Path::new("escape")).is_err());
+        assert!(resolve_path(project, Path::new("escape/motd")).is_err());
+    }
+}
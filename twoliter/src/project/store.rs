@@ -0,0 +1,158 @@
+//! A read-only store of kit archives and SDK images for build hosts shared by several users.
+//!
+//! An administrator populates the store with `twoliter store populate`, typically as root. Builds
+//! then reuse what they find there instead of pulling their own copies, and never write to it. The
+//! store is found at `$TWOLITER_SYSTEM_STORE`, or else at `/var/lib/twoliter/store`, and is laid
+//! out as:
+//!
+//! ```text
+//! kits/<manifest digest>/               OCI archives, as in the project's kit archive cache
+//! sdk/<image uri>_<platform>.tar        SDK images, ready for `docker load`
+//! ```
+use super::cache::{CacheMiss, CacheReport, CacheStage, CacheStatus};
+use super::{Locked, LockedSDKProvider, Project};
+use crate::common::fs::create_dir_all;
+use crate::docker::{Docker, ImageUri};
+use anyhow::{Context, Result};
+use krane_static::{call_krane_inherited_io, settings};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// The environment variable naming the system-wide store.
+pub(crate) const SYSTEM_STORE_ENV: &str = "TWOLITER_SYSTEM_STORE";
+
+/// Where the system-wide store is kept when `TWOLITER_SYSTEM_STORE` is unset.
+pub(crate) const DEFAULT_SYSTEM_STORE: &str = "/var/lib/twoliter/store";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SystemStore {
+    root: PathBuf,
+}
+
+impl SystemStore {
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// The store at the configured location, whether or not it exists yet.
+    pub(crate) fn configured() -> Self {
+        Self::new(
+            settings::var_os(SYSTEM_STORE_ENV)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_SYSTEM_STORE)),
+        )
+    }
+
+    /// The store at the configured location, if it has been populated.
+    pub(crate) fn find() -> Option<Self> {
+        Some(Self::configured()).filter(|store| store.root.is_dir())
+    }
+
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The directory holding kit OCI archives, named like those in the kit archive cache.
+    pub(crate) fn kit_archives_dir(&self) -> PathBuf {
+        self.root.join("kits")
+    }
+
+    /// The path of the archive of `sdk` for `platform`, e.g. `linux/amd64`.
+    pub(crate) fn sdk_archive(&self, sdk: &ImageUri, platform: &str) -> PathBuf {
+        let name = format!("{}_{platform}", sdk.uri()).replace(['/', ':'], "-");
+        self.root.join("sdk").join(format!("{name}.tar"))
+    }
+}
+
+impl Project<Locked> {
+    /// Pulls the project's kits for each of `arches`, and its SDK for `platform`, into `store`.
+    /// Anything already in the store is left alone.
+    pub(crate) async fn populate_store(
+        &self,
+        store: &SystemStore,
+        arches: &[String],
+        platform: &str,
+    ) -> Result<CacheReport> {
+        let Locked(lock) = &self.lock;
+        let mut report = CacheReport::default();
+        for arch in arches {
+            report.extend(lock.store_kits(self, store, arch).await?);
+        }
+
+        let sdk_uri = self.sdk_image().project_image_uri();
+        let sdk_archive = store.sdk_archive(&sdk_uri, platform);
+        if sdk_archive.exists() {
+            report.record(sdk_uri.to_string(), CacheStage::SdkImage, CacheStatus::Hit);
+            return Ok(report);
+        }
+        let sdk_dir = sdk_archive.parent().unwrap_or(store.root());
+        create_dir_all(sdk_dir).await?;
+        // Pull next to the final location, so that builds never see a partial archive.
+        let temp_path = tempfile::Builder::new()
+            .prefix(".sdk-")
+            .suffix(".tar")
+            .tempfile_in(sdk_dir)?
+            .into_temp_path();
+        info!("Pulling '{sdk_uri}' for platform '{platform}' into the system store");
+        call_krane_inherited_io(&[
+            "pull",
+            &sdk_uri.uri(),
+            &temp_path.to_string_lossy(),
+            "--platform",
+            platform,
+        ])
+        .context("Failed to pull SDK image")?;
+        temp_path.persist(&sdk_archive).context(format!(
+            "failed to move SDK archive to '{}'",
+            sdk_archive.display()
+        ))?;
+        report.record(
+            sdk_uri.to_string(),
+            CacheStage::SdkImage,
+            CacheStatus::Miss(CacheMiss::Missing),
+        );
+        Ok(report)
+    }
+}
+
+impl<T: LockedSDKProvider> Project<T> {
+    /// Loads the project's SDK into the docker daemon from the system-wide store, returning whether
+    /// the store held it.
+    pub(crate) async fn load_sdk_from_store(&self, platform: &str) -> Result<bool> {
+        let Some(store) = SystemStore::find() else {
+            return Ok(false);
+        };
+        let sdk_uri = self.sdk_image().project_image_uri();
+        let sdk_archive = store.sdk_archive(&sdk_uri, platform);
+        if !sdk_archive.is_file() {
+            return Ok(false);
+        }
+        info!(
+            "Loading SDK image '{sdk_uri}' from the system store at '{}'",
+            store.root().display()
+        );
+        Docker::load(&sdk_archive).await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sdk_archive_name() {
+        let store = SystemStore::new("/store");
+        let sdk = ImageUri::new(
+            Some("public.ecr.aws/bottlerocket".to_string()),
+            "bottlerocket-sdk",
+            "v0.50.0",
+        );
+        assert_eq!(
+            store.sdk_archive(&sdk, "linux/amd64"),
+            PathBuf::from(
+                "/store/sdk/public.ecr.aws-bottlerocket-bottlerocket-sdk-v0.50.0_linux-amd64.tar"
+            )
+        );
+    }
+}
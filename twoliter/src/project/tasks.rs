@@ -1,22 +1,44 @@
 //! This module defines common atomic build tasks that can be performed with a fully loaded project.
+use super::cache::{CacheMiss, CacheReport, CacheStage, CacheStatus};
+use super::lock::pull_platform_image;
 use super::{LockedSDKProvider, Project};
 use crate::cleanup::JANITOR;
-use crate::docker::Docker;
+use crate::docker::{Docker, ImageUri};
 use anyhow::{Context, Result};
 use krane_static::call_krane_inherited_io;
+use oci_cli_wrapper::layout::oci_dir_from_env;
+use oci_cli_wrapper::ImageTool;
+use std::path::Path;
 use tracing::instrument;
 
 impl<T: LockedSDKProvider> Project<T> {
     /// Caches the project's SDK into the docker daemon if an image with the same name/tag is not
-    /// already cached.
+    /// already cached, reporting whether it was.
     #[instrument(level = "trace")]
-    pub(crate) async fn fetch_sdk(&self) -> Result<()> {
+    pub(crate) async fn fetch_sdk(&self) -> Result<CacheReport> {
         let sdk_uri = self.sdk_image().project_image_uri();
         tracing::info!("Ensuring project SDK '{sdk_uri}' is cached locally.");
 
+        let mut report = CacheReport::default();
         if Docker::image_is_cached(&sdk_uri).await? {
             tracing::debug!("SDK '{sdk_uri}' is cached.");
-            return Ok(());
+            report.record(sdk_uri.to_string(), CacheStage::SdkImage, CacheStatus::Hit);
+            return Ok(report);
+        }
+
+        report.record(
+            sdk_uri.to_string(),
+            CacheStage::SdkImage,
+            CacheStatus::Miss(CacheMiss::Missing),
+        );
+
+        let host_platform = Docker::host_platform().await?;
+        if self.load_sdk_from_store(&host_platform).await? {
+            return Ok(report);
+        }
+        if let Some(oci_dir) = oci_dir_from_env() {
+            self.load_sdk_offline(&oci_dir, &host_platform).await?;
+            return Ok(report);
         }
 
         let sdk_archive_dir = self.external_sdk_archive_dir();
@@ -28,8 +50,6 @@ impl<T: LockedSDKProvider> Project<T> {
             .tempfile_in(&sdk_archive_dir)?
             .into_temp_path();
 
-        let host_platform = Docker::host_platform().await?;
-
         JANITOR
             .with_tempfile(temp_path, |temp_path| async move {
                 let path_str = temp_path.to_string_lossy().to_string();
@@ -51,6 +71,62 @@ impl<T: LockedSDKProvider> Project<T> {
             })
             .await??;
 
-        Ok(())
+        Ok(report)
+    }
+
+    /// Loads the SDK into the docker daemon from the OCI layouts in `oci_dir`, for hosts which
+    /// cannot reach its registry.
+    async fn load_sdk_offline(&self, oci_dir: &Path, platform: &str) -> Result<()> {
+        let sdk_uri = self.sdk_image().project_image_uri();
+        let arch = platform.rsplit('/').next().unwrap_or(platform);
+        let sdk_archive_dir = self.external_sdk_archive_dir();
+        tokio::fs::create_dir_all(&sdk_archive_dir).await?;
+        let temp_dir = tempfile::Builder::new()
+            .prefix("bottlerocket-sdk-tmp-layout-")
+            .tempdir_in(&sdk_archive_dir)?;
+        let layout = temp_dir.path().join("layout");
+
+        tracing::info!(
+            "Reading '{sdk_uri}' for platform '{platform}' from '{}'",
+            oci_dir.display()
+        );
+        let image_tool = ImageTool::oci_layout_dir(oci_dir);
+        pull_platform_image(&image_tool, &sdk_uri, arch, &layout).await?;
+        name_layout_image(&layout, &sdk_uri)?;
+
+        let archive = temp_dir.path().join("sdk.tar");
+        let archive_path = archive.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let file = std::fs::File::create(&archive_path)?;
+            let mut builder = tar::Builder::new(file);
+            builder.append_dir_all(".", &layout)?;
+            builder.finish()?;
+            Ok(())
+        })
+        .await?
+        .context("Failed to archive SDK image")?;
+
+        tracing::info!("Loading SDK image '{sdk_uri}' into docker daemon");
+        Docker::load(&archive).await
+    }
+}
+
+/// Names the image in the OCI layout at `layout` as `uri`, which is the name docker gives it when
+/// the layout is loaded.
+fn name_layout_image(layout: &Path, uri: &ImageUri) -> Result<()> {
+    let index_path = layout.join("index.json");
+    let mut index: serde_json::Value = serde_json::from_slice(&std::fs::read(&index_path)?)
+        .context("failed to deserialize OCI layout index")?;
+    let manifests = index
+        .get_mut("manifests")
+        .and_then(serde_json::Value::as_array_mut)
+        .context("OCI layout index has no manifests")?;
+    for manifest in manifests {
+        manifest["annotations"] = serde_json::json!({
+            "io.containerd.image.name": uri.uri(),
+            "org.opencontainers.image.ref.name": uri.tag,
+        });
     }
+    std::fs::write(&index_path, index.to_string())?;
+    Ok(())
 }
@@ -0,0 +1,337 @@
+//! Estimates how long the phases of a build will take from how long they took before, so that
+//! multi-hour kit builds report how much longer they should run, and `twoliter plan` can say how
+//! long each of its steps should take.
+//!
+//! Each phase is timed under the id of the plan step which runs it, such as
+//! `build-kit:core-kit:x86_64`. buildsys records how long each package took to build in
+//! `build/state/package-timings-<arch>.jsonl`, and once a phase ends, those times and the phase's
+//! own are folded into `build/state/timings.json`. Each is kept as an average which weighs recent
+//! runs more, so that estimates follow a project as it grows.
+//!
+//! While a phase runs, the packages built so far are weighed against those built the last time
+//! the phase ran, so a phase that rebuilds few of its packages is not expected to take as long as
+//! one that rebuilds them all. Packages build in parallel, so their times only say how much of a
+//! phase's work is left, and the phase's own time says how long that work takes.
+use super::{Project, ProjectLock};
+use crate::common::fs::{create_dir_all, read_to_string, remove_file, write};
+use crate::warnings;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Where the times of earlier runs are kept, relative to the project directory.
+const TIMINGS_FILE: &str = "build/state/timings.json";
+
+/// The prefix of the files in the state directory where buildsys records how long each package
+/// took to build, one file per architecture.
+const TIMING_LOG_PREFIX: &str = "package-timings";
+
+/// How much the newest run counts towards an average.
+const NEWEST_WEIGHT: f64 = 0.3;
+
+/// How often a running phase reports how much longer it should take.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The times of earlier runs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Timings {
+    /// The phases, by the id of the plan step which runs them.
+    #[serde(default)]
+    phases: BTreeMap<String, PhaseTiming>,
+    /// The package builds, by architecture and then by package.
+    #[serde(default)]
+    packages: BTreeMap<String, BTreeMap<String, Average>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct PhaseTiming {
+    #[serde(flatten)]
+    average: Average,
+    /// The packages built the last time the phase ran.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    packages: BTreeSet<String>,
+}
+
+/// A duration in seconds, averaged over runs with the newest weighed the most.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Average {
+    seconds: f64,
+    runs: u32,
+}
+
+impl Average {
+    fn record(&mut self, seconds: f64) {
+        self.seconds = if self.runs == 0 {
+            seconds
+        } else {
+            NEWEST_WEIGHT * seconds + (1.0 - NEWEST_WEIGHT) * self.seconds
+        };
+        self.runs += 1;
+    }
+}
+
+impl Timings {
+    /// Reads the times of earlier runs from `path`. Missing or unreadable times only mean there
+    /// are no estimates.
+    async fn load(path: &Path) -> Self {
+        if !path.exists() {
+            return Self::default();
+        }
+        let timings = read_to_string(path).await.and_then(|timings| {
+            serde_json::from_str(&timings).context(format!("failed to parse '{}'", path.display()))
+        });
+        timings.unwrap_or_else(|e| {
+            warnings::warn(format!("Ignoring the times of earlier builds: {e:#}"));
+            Self::default()
+        })
+    }
+
+    async fn save(&self, path: &Path) -> Result<()> {
+        let timings = serde_json::to_string_pretty(self).context("failed to serialize timings")?;
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).await?;
+        }
+        write(path, timings).await
+    }
+
+    /// How long the phase `phase` usually takes.
+    pub(crate) fn estimate(&self, phase: &str) -> Option<Duration> {
+        self.phases
+            .get(phase)
+            .map(|timing| Duration::from_secs_f64(timing.average.seconds))
+    }
+
+    /// How much longer the phase `phase` for `arch` should take, now that `built` have been built
+    /// `elapsed` into it.
+    fn remaining(
+        &self,
+        phase: &str,
+        arch: &str,
+        built: &BTreeMap<String, f64>,
+        elapsed: Duration,
+    ) -> Option<Duration> {
+        let timing = self.phases.get(phase)?;
+        let packages = self.packages.get(arch);
+        let seconds = |name: &String| {
+            packages
+                .and_then(|packages| packages.get(name))
+                .map_or(0.0, |average| average.seconds)
+        };
+        let total: f64 = timing.packages.iter().map(seconds).sum();
+        let seconds = if total > 0.0 {
+            let left: f64 = timing
+                .packages
+                .iter()
+                .filter(|name| !built.contains_key(*name))
+                .map(seconds)
+                .sum();
+            timing.average.seconds * left / total
+        } else {
+            timing.average.seconds - elapsed.as_secs_f64()
+        };
+        Some(Duration::from_secs_f64(seconds.max(0.0)))
+    }
+
+    fn record_packages(&mut self, arch: &str, built: &BTreeMap<String, f64>) {
+        let packages = self.packages.entry(arch.to_string()).or_default();
+        for (package, seconds) in built {
+            packages
+                .entry(package.clone())
+                .or_default()
+                .record(*seconds);
+        }
+    }
+
+    fn record_phase(&mut self, phase: &str, took: Duration, built: &BTreeMap<String, f64>) {
+        let timing = self.phases.entry(phase.to_string()).or_default();
+        timing.average.record(took.as_secs_f64());
+        timing.packages = built.keys().cloned().collect();
+    }
+}
+
+/// Reads the package build times that buildsys recorded in `log`, by package.
+fn read_package_log(log: &str) -> BTreeMap<String, f64> {
+    #[derive(Deserialize)]
+    struct PackageTiming {
+        package: String,
+        seconds: f64,
+    }
+    log.lines()
+        .filter_map(|line| serde_json::from_str::<PackageTiming>(line).ok())
+        .map(|timing| (timing.package, timing.seconds))
+        .collect()
+}
+
+/// Formats `duration` to the minute, or to the second when it is shorter than a minute.
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds / 60 % 60) {
+        (0, 0) => format!("{seconds}s"),
+        (0, minutes) => format!("{minutes}m"),
+        (hours, minutes) => format!("{hours}h {minutes:02}m"),
+    }
+}
+
+impl<L: ProjectLock> Project<L> {
+    /// The times of the project's earlier runs.
+    pub(crate) async fn timings(&self) -> Timings {
+        Timings::load(&self.project_dir.join(TIMINGS_FILE)).await
+    }
+
+    /// Runs `work`, the phase `phase` of a build for `arch`, reporting how much longer it should
+    /// take, and then records how long it and its package builds took.
+    pub(crate) async fn timed<T>(
+        &self,
+        phase: &str,
+        arch: &str,
+        work: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let timings_file = self.project_dir.join(TIMINGS_FILE);
+        let package_log = timings_file.with_file_name(format!("{TIMING_LOG_PREFIX}-{arch}.jsonl"));
+        if package_log.exists() {
+            remove_file(&package_log).await?;
+        }
+        let built =
+            || async { read_package_log(&read_to_string(&package_log).await.unwrap_or_default()) };
+        let timings = Timings::load(&timings_file).await;
+        let estimate = timings.estimate(phase);
+        if let Some(estimate) = estimate {
+            info!(
+                "'{phase}' took about {} in earlier runs",
+                format_duration(estimate)
+            );
+        }
+
+        let started = Instant::now();
+        tokio::pin!(work);
+        let mut ticker = tokio::time::interval_at(
+            tokio::time::Instant::now() + REPORT_INTERVAL,
+            REPORT_INTERVAL,
+        );
+        let result = loop {
+            tokio::select! {
+                result = &mut work => break result,
+                _ = ticker.tick() => {
+                    let (elapsed, built) = (started.elapsed(), built().await);
+                    let so_far = format!(
+                        "'{phase}' has run for {} and built {} package(s)",
+                        format_duration(elapsed),
+                        built.len()
+                    );
+                    match (timings.remaining(phase, arch, &built, elapsed), estimate) {
+                        (Some(remaining), _) if !remaining.is_zero() => {
+                            info!("{so_far}; about {} to go", format_duration(remaining));
+                        }
+                        (_, Some(estimate)) => info!(
+                            "{so_far}; it is taking longer than the {} it took before",
+                            format_duration(estimate)
+                        ),
+                        _ => info!("{so_far}"),
+                    }
+                }
+            }
+        };
+
+        // Other phases may have finished meanwhile, so their times are read again before adding
+        // this one's.
+        let built = built().await;
+        let mut timings = Timings::load(&timings_file).await;
+        timings.record_packages(arch, &built);
+        if result.is_ok() {
+            timings.record_phase(phase, started.elapsed(), &built);
+        }
+        if let Err(e) = timings.save(&timings_file).await {
+            warnings::warn(format!("Failed to record how long '{phase}' took: {e:#}"));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn built(packages: &[(&str, f64)]) -> BTreeMap<String, f64> {
+        packages
+            .iter()
+            .map(|(package, seconds)| (package.to_string(), *seconds))
+            .collect()
+    }
+
+    #[test]
+    fn test_average() {
+        let mut average = Average::default();
+        average.record(100.0);
+        assert_eq!(average.seconds, 100.0);
+        average.record(200.0);
+        assert!((average.seconds - 130.0).abs() < 1e-9, "{average:?}");
+        assert_eq!(average.runs, 2);
+    }
+
+    #[test]
+    fn test_remaining() {
+        let phase = "build-kit:core-kit:x86_64";
+        let mut timings = Timings::default();
+        assert_eq!(
+            timings.remaining(phase, "x86_64", &BTreeMap::new(), Duration::ZERO),
+            None
+        );
+
+        // Without package times, the phase's own time is counted down.
+        timings.record_phase(phase, Duration::from_secs(600), &BTreeMap::new());
+        assert_eq!(
+            timings.remaining(phase, "x86_64", &BTreeMap::new(), Duration::from_secs(200)),
+            Some(Duration::from_secs(400))
+        );
+        assert_eq!(
+            timings.remaining(phase, "x86_64", &BTreeMap::new(), Duration::from_secs(900)),
+            Some(Duration::ZERO)
+        );
+
+        // With them, the phase's time is shared out by how much of its work is left.
+        let last_run = built(&[("kernel-6.1", 300.0), ("glibc", 100.0)]);
+        timings.record_packages("x86_64", &last_run);
+        timings.record_phase(phase, Duration::from_secs(600), &last_run);
+        assert_eq!(
+            timings.remaining(
+                phase,
+                "x86_64",
+                &built(&[("glibc", 90.0)]),
+                Duration::from_secs(500)
+            ),
+            Some(Duration::from_secs(450))
+        );
+    }
+
+    #[test]
+    fn test_read_package_log() {
+        let log = concat!(
+            r#"{"package":"glibc","arch":"x86_64","seconds":12.5}"#,
+            "\n",
+            "not json\n",
+            r#"{"package":"kernel-6.1","arch":"x86_64","seconds":300}"#,
+            "\n",
+        );
+        assert_eq!(
+            read_package_log(log),
+            built(&[("glibc", 12.5), ("kernel-6.1", 300.0)])
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(12 * 60 + 5)), "12m");
+        assert_eq!(
+            format_duration(Duration::from_secs(2 * 3600 + 5 * 60)),
+            "2h 05m"
+        );
+    }
+}
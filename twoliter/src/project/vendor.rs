@@ -2,9 +2,11 @@
 //!
 //! Most users of this module will need [`ArtifactVendor`], which represents a vendor which may have
 //! been overridden in a `Twoliter.override` file.
+use super::signature::SignaturePolicy;
 use super::{Override, ValidIdentifier, VendedArtifact, Vendor};
 use crate::docker::ImageUri;
 use std::fmt::Debug;
+use std::path::Path;
 
 /// `ArtifactVendor` represents a vendor associated with an image artifact used in a project.
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -34,10 +36,41 @@ impl ArtifactVendor {
         ImageUri {
             registry: Some(self.registry().to_string()),
             repo: self.repo_for(image).to_string(),
-            tag: format!("v{}", image.version()),
+            tag: image
+                .digest()
+                .map_or_else(|| format!("v{}", image.version()), str::to_string),
         }
     }
 
+    /// Returns the vendor as served by each of its mirrors. A vendor whose registry is overridden
+    /// has no mirrors, since the override replaces the registry they mirror.
+    pub(crate) fn mirrors(&self) -> Vec<ArtifactVendor> {
+        let (vendor_name, vendor, name) = match self {
+            ArtifactVendor::Verbatim(vendor) => (&vendor.vendor_name, &vendor.vendor, None),
+            ArtifactVendor::Overridden(vendor) if vendor.override_.registry.is_none() => (
+                &vendor.original_vendor_name,
+                &vendor.original_vendor,
+                vendor.override_.name.clone(),
+            ),
+            ArtifactVendor::Overridden(_) => return Vec::new(),
+        };
+        vendor
+            .mirrors
+            .iter()
+            .map(|mirror| {
+                Self::overridden(
+                    vendor_name.clone(),
+                    vendor.clone(),
+                    Override {
+                        name: name.clone(),
+                        registry: Some(mirror.clone()),
+                        path: None,
+                    },
+                )
+            })
+            .collect()
+    }
+
     pub(crate) fn vendor_name(&self) -> &ValidIdentifier {
         match self {
             ArtifactVendor::Verbatim(vendor) => &vendor.vendor_name,
@@ -45,6 +78,23 @@ impl ArtifactVendor {
         }
     }
 
+    /// How the vendor's images must be signed. An override serves the vendor's images from
+    /// elsewhere, so they must be signed the same way.
+    pub(crate) fn signature_policy(&self) -> Option<&SignaturePolicy> {
+        match self {
+            ArtifactVendor::Verbatim(vendor) => vendor.vendor.signature.as_ref(),
+            ArtifactVendor::Overridden(vendor) => vendor.original_vendor.signature.as_ref(),
+        }
+    }
+
+    /// The directory of local builds which the vendor's image is overridden with, if any.
+    pub(crate) fn local_path(&self) -> Option<&Path> {
+        match self {
+            ArtifactVendor::Verbatim(_) => None,
+            ArtifactVendor::Overridden(vendor) => vendor.override_.path.as_deref(),
+        }
+    }
+
     pub(crate) fn overridden(
         original_vendor_name: ValidIdentifier,
         original_vendor: Vendor,
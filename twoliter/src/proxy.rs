@@ -0,0 +1,568 @@
+//! A caching, pull-through proxy of container registries, so that a team can share one egress
+//! point and one copy of its kits and SDKs.
+//!
+//! `twoliter proxy serve` speaks the read-only part of the OCI distribution API. The first part of
+//! each repository name is the upstream registry, so a vendor whose registry is `public.ecr.aws`
+//! is pulled through the proxy by setting its registry to `<proxy host>/public.ecr.aws` in
+//! Twoliter.toml. Only the upstream registries given to the proxy with `--upstream` are proxied.
+//! Upstream registries are reached with krane, which authenticates with the docker config of the
+//! user running the proxy.
+//!
+//! Clients must authenticate with the proxy's token, which is given to the proxy in
+//! `TWOLITER_PROXY_TOKEN`, as the password of a `docker login` to the proxy or as a bearer token.
+//! Errors only tell clients what went wrong in general terms; the details are logged by the proxy.
+//!
+//! Pulled content is kept in a content-addressed cache:
+//!
+//! ```text
+//! blobs/sha256/<hex>                  manifests, configs and layers, named by their digest
+//! tags/<registry>/<repository>/<tag>  the digest of the manifest each tag last pointed to
+//! ```
+//!
+//! Content named by a digest never changes, so it is served from the cache whenever it is there.
+//! Tags are looked up upstream on every request, and the cached digest is only served when the
+//! upstream registry cannot be reached. Blobs are pulled into the cache one at a time, when a
+//! client first asks for them. Layers are also served from the system-wide store when it holds
+//! them. The proxy speaks plain HTTP, which clients only use for registries on `localhost`;
+//! elsewhere it should sit behind a proxy which terminates TLS.
+use crate::common::fs::{create_dir_all, read, read_to_string, rename, write};
+use crate::project::store::SystemStore;
+use crate::warnings;
+use anyhow::{ensure, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use krane_static::call_krane;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, info};
+
+/// The environment variable naming the proxy's cache directory.
+pub(crate) const PROXY_CACHE_ENV: &str = "TWOLITER_PROXY_CACHE";
+
+/// Where the proxy's cache is kept when `TWOLITER_PROXY_CACHE` is unset.
+pub(crate) const DEFAULT_PROXY_CACHE: &str = "/var/cache/twoliter/proxy";
+
+/// The environment variable holding the token that clients must present to the proxy.
+pub(crate) const PROXY_TOKEN_ENV: &str = "TWOLITER_PROXY_TOKEN";
+
+const DIGEST_HEADER: &str = "Docker-Content-Digest";
+const API_VERSION_HEADER: &str = "Docker-Distribution-API-Version";
+const DEFAULT_MANIFEST_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// A request the proxy understands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Route {
+    /// The API version check, `/v2/`.
+    Base,
+    Manifest {
+        registry: String,
+        repository: String,
+        reference: String,
+    },
+    Blob {
+        registry: String,
+        repository: String,
+        digest: String,
+    },
+}
+
+impl Route {
+    /// Parses the path of a request, or returns `None` if the proxy does not serve it.
+    fn parse(path: &str) -> Option<Self> {
+        let path = path.strip_prefix("/v2/")?;
+        if path.is_empty() {
+            return Some(Route::Base);
+        }
+        let (name, kind, reference) = ["manifests", "blobs"].into_iter().find_map(|kind| {
+            let (name, reference) = path.rsplit_once(&format!("/{kind}/"))?;
+            Some((name, kind, reference))
+        })?;
+        let (registry, repository) = name.split_once('/')?;
+        let is_host = registry.contains(['.', ':']) || registry == "localhost";
+        let valid_repository = repository.split('/').all(|component| {
+            !component.is_empty()
+                && !component.starts_with('.')
+                && component
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c))
+        });
+        if !is_host || registry.contains(['/', '\\']) || registry.starts_with('.') {
+            return None;
+        }
+        if !valid_repository {
+            return None;
+        }
+        let (registry, repository) = (registry.to_string(), repository.to_string());
+        match kind {
+            "manifests" if is_digest(reference) || is_tag(reference) => Some(Route::Manifest {
+                registry,
+                repository,
+                reference: reference.to_string(),
+            }),
+            "blobs" if is_digest(reference) => Some(Route::Blob {
+                registry,
+                repository,
+                digest: reference.to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn is_digest(reference: &str) -> bool {
+    reference.strip_prefix("sha256:").is_some_and(|hex| {
+        hex.len() == 64
+            && hex
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    })
+}
+
+fn is_tag(reference: &str) -> bool {
+    !reference.is_empty()
+        && reference.len() <= 128
+        && !reference.starts_with(['.', '-'])
+        && reference
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+/// The part of a manifest the proxy reads.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestView {
+    media_type: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RegistryProxy {
+    cache_dir: PathBuf,
+    store: Option<SystemStore>,
+    /// The upstream registries which are proxied.
+    upstreams: BTreeSet<String>,
+    /// The token clients must present.
+    token: String,
+}
+
+impl RegistryProxy {
+    pub(crate) fn new(
+        cache_dir: impl Into<PathBuf>,
+        store: Option<SystemStore>,
+        upstreams: impl IntoIterator<Item = String>,
+        token: impl Into<String>,
+    ) -> Self {
+        Self {
+            cache_dir: cache_dir.into(),
+            store,
+            upstreams: upstreams.into_iter().collect(),
+            token: token.into(),
+        }
+    }
+
+    /// Serves the proxy on `address` until it fails.
+    pub(crate) async fn serve(self, address: SocketAddr) -> Result<()> {
+        ensure!(
+            !self.upstreams.is_empty(),
+            "give the upstream registries to proxy with --upstream"
+        );
+        ensure!(
+            !self.token.trim().is_empty(),
+            "set {PROXY_TOKEN_ENV} to the token that clients must present"
+        );
+        create_dir_all(self.cache_dir.join("blobs/sha256")).await?;
+        let cache_dir = self.cache_dir.clone();
+        let upstreams = self.upstreams.clone();
+        let proxy = Arc::new(self);
+        let make_service = make_service_fn(move |_| {
+            let proxy = Arc::clone(&proxy);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let proxy = Arc::clone(&proxy);
+                    async move { Ok::<_, Infallible>(proxy.handle(request).await) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&address)
+            .context(format!("failed to listen on '{address}'"))?
+            .serve(make_service);
+        info!(
+            "Serving the registry proxy on 'http://{address}', caching in '{}'",
+            cache_dir.display()
+        );
+        info!(
+            "Proxying {}",
+            upstreams.into_iter().collect::<Vec<_>>().join(", ")
+        );
+        server.await.context("the registry proxy failed")
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        debug!("{} {}", request.method(), request.uri());
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "UNSUPPORTED",
+                "the proxy is read-only",
+            );
+        }
+        if !self.is_authorized(&request) {
+            let mut response = error(
+                StatusCode::UNAUTHORIZED,
+                "UNAUTHORIZED",
+                "authentication required",
+            );
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"twoliter proxy\""),
+            );
+            return response;
+        }
+        let route = Route::parse(request.uri().path());
+        if let Some(Route::Manifest { registry, .. } | Route::Blob { registry, .. }) = &route {
+            if !self.upstreams.contains(registry) {
+                return error(
+                    StatusCode::NOT_FOUND,
+                    "NAME_UNKNOWN",
+                    "the registry is not proxied",
+                );
+            }
+        }
+        let result = match route {
+            Some(Route::Base) => Response::builder()
+                .header(API_VERSION_HEADER, "registry/2.0")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from("{}"))
+                .context("failed to build response"),
+            Some(Route::Manifest {
+                registry,
+                repository,
+                reference,
+            }) => self.manifest(&registry, &repository, &reference).await,
+            Some(Route::Blob {
+                registry,
+                repository,
+                digest,
+            }) => self.blob(&registry, &repository, &digest).await,
+            None => {
+                return error(
+                    StatusCode::NOT_FOUND,
+                    "NAME_UNKNOWN",
+                    "expected /v2/<registry>/<repository>/manifests/<reference> or \
+                    /v2/<registry>/<repository>/blobs/<digest>",
+                )
+            }
+        };
+        result.unwrap_or_else(|e| {
+            warnings::warn(format!("Failed to serve '{}': {e:?}", request.uri()));
+            error(
+                StatusCode::BAD_GATEWAY,
+                "UNKNOWN",
+                "failed to fetch the content from the upstream registry",
+            )
+        })
+    }
+
+    /// Whether the request presents the proxy's token, as the password of basic authentication or
+    /// as a bearer token.
+    fn is_authorized(&self, request: &Request<Body>) -> bool {
+        let Some(authorization) = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return false;
+        };
+        let token = if let Some(token) = authorization.strip_prefix("Bearer ") {
+            token.trim().to_string()
+        } else if let Some(credentials) = authorization.strip_prefix("Basic ") {
+            let Some(credentials) = STANDARD
+                .decode(credentials.trim())
+                .ok()
+                .and_then(|credentials| String::from_utf8(credentials).ok())
+            else {
+                return false;
+            };
+            match credentials.split_once(':') {
+                Some((_, password)) => password.to_string(),
+                None => return false,
+            }
+        } else {
+            return false;
+        };
+        constant_time_eq(token.as_bytes(), self.token.as_bytes())
+    }
+
+    /// Serves a manifest. The blobs it refers to are pulled when they are asked for.
+    async fn manifest(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+    ) -> Result<Response<Body>> {
+        let upstream = if is_digest(reference) {
+            format!("{registry}/{repository}@{reference}")
+        } else {
+            format!("{registry}/{repository}:{reference}")
+        };
+        let cached = if is_digest(reference) {
+            Some(reference.to_string())
+        } else {
+            read_to_string(self.tag_path(registry, repository, reference))
+                .await
+                .ok()
+                .map(|digest| digest.trim().to_string())
+        };
+        let cached = cached.filter(|digest| self.blob_path(digest).is_file());
+
+        let (digest, manifest) = match cached {
+            Some(digest) if is_digest(reference) => {
+                let manifest = read(self.blob_path(&digest)).await?;
+                (digest, manifest)
+            }
+            cached => match krane(&["manifest", &upstream]).await {
+                Ok(manifest) => {
+                    let digest = sha256_digest(&manifest);
+                    ensure!(
+                        !is_digest(reference) || digest == reference,
+                        "'{upstream}' served a manifest with digest '{digest}'"
+                    );
+                    self.write_blob(&digest, &manifest).await?;
+                    if !is_digest(reference) {
+                        let tag_path = self.tag_path(registry, repository, reference);
+                        create_dir_all(tag_path.parent().context("tag has no parent")?).await?;
+                        write(&tag_path, &digest).await?;
+                    }
+                    (digest, manifest)
+                }
+                Err(e) => {
+                    let Some(digest) = cached else {
+                        return Err(e);
+                    };
+                    warnings::warn(format!(
+                        "Unable to reach '{upstream}', serving the cached '{digest}': {e:#}"
+                    ));
+                    let manifest = read(self.blob_path(&digest)).await?;
+                    (digest, manifest)
+                }
+            },
+        };
+
+        let view: ManifestView = serde_json::from_slice(&manifest)
+            .context(format!("failed to parse the manifest of '{upstream}'"))?;
+        Response::builder()
+            .header(
+                CONTENT_TYPE,
+                view.media_type.as_deref().unwrap_or(DEFAULT_MANIFEST_TYPE),
+            )
+            .header(CONTENT_LENGTH, manifest.len())
+            .header(DIGEST_HEADER, &digest)
+            .body(Body::from(manifest))
+            .context("failed to build response")
+    }
+
+    /// Serves a blob from the cache or the system store, or else pulls it into the cache first.
+    async fn blob(&self, registry: &str, repository: &str, digest: &str) -> Result<Response<Body>> {
+        let path = match self.find_blob(digest) {
+            Some(path) => path,
+            None => {
+                let upstream = format!("{registry}/{repository}@{digest}");
+                info!("Pulling '{upstream}' into the cache");
+                let blob = krane(&["blob", &upstream]).await?;
+                ensure!(
+                    sha256_digest(&blob) == digest,
+                    "'{upstream}' served a blob with the wrong digest"
+                );
+                self.write_blob(digest, &blob).await?;
+                self.blob_path(digest)
+            }
+        };
+        let file = tokio::fs::File::open(&path)
+            .await
+            .context(format!("failed to open '{}'", path.display()))?;
+        let length = file
+            .metadata()
+            .await
+            .context(format!("failed to read metadata of '{}'", path.display()))?
+            .len();
+        Response::builder()
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header(CONTENT_LENGTH, length)
+            .header(DIGEST_HEADER, digest)
+            .body(Body::wrap_stream(ReaderStream::new(file)))
+            .context("failed to build response")
+    }
+
+    /// Where the blob with `digest` is, in the cache or else in one of the system store's kit
+    /// archives.
+    fn find_blob(&self, digest: &str) -> Option<PathBuf> {
+        let cached = self.blob_path(digest);
+        if cached.is_file() {
+            return Some(cached);
+        }
+        let archives = std::fs::read_dir(self.store.as_ref()?.kit_archives_dir()).ok()?;
+        archives
+            .flatten()
+            .map(|archive| blob_path(&archive.path(), digest))
+            .find(|path| path.is_file())
+    }
+
+    fn blob_path(&self, digest: &str) -> PathBuf {
+        blob_path(&self.cache_dir, digest)
+    }
+
+    fn tag_path(&self, registry: &str, repository: &str, tag: &str) -> PathBuf {
+        self.cache_dir
+            .join("tags")
+            .join(registry)
+            .join(repository)
+            .join(tag)
+    }
+
+    /// Writes a blob into the cache, so that it appears complete or not at all.
+    async fn write_blob(&self, digest: &str, contents: &[u8]) -> Result<()> {
+        let path = self.blob_path(digest);
+        if path.is_file() {
+            return Ok(());
+        }
+        let partial = path.with_extension(format!("partial-{}", uuid::Uuid::new_v4()));
+        write(&partial, contents).await?;
+        rename(&partial, &path).await
+    }
+}
+
+fn blob_path(root: &Path, digest: &str) -> PathBuf {
+    root.join("blobs").join(digest.replace(':', "/"))
+}
+
+/// Compares secrets without returning early, so that how long it takes doesn't tell how much of
+/// the secret was guessed.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn sha256_digest(bytes: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(bytes)))
+}
+
+/// An error in the format of the distribution API.
+fn error(status: StatusCode, code: &str, message: &str) -> Response<Body> {
+    let body = serde_json::json!({ "errors": [{ "code": code, "message": message }] });
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+/// Runs krane, which blocks, and returns what it printed.
+async fn krane(args: &[&str]) -> Result<Vec<u8>> {
+    let owned = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    let output = tokio::task::spawn_blocking(move || call_krane(&owned))
+        .await
+        .context("krane did not finish")??;
+    ensure!(
+        output.status.success(),
+        "krane {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_route_parse() {
+        assert_eq!(Route::parse("/v2/"), Some(Route::Base));
+        assert_eq!(
+            Route::parse("/v2/public.ecr.aws/bottlerocket/core-kit/manifests/v2.0.0"),
+            Some(Route::Manifest {
+                registry: "public.ecr.aws".to_string(),
+                repository: "bottlerocket/core-kit".to_string(),
+                reference: "v2.0.0".to_string(),
+            })
+        );
+        assert_eq!(
+            Route::parse(&format!("/v2/localhost:5000/kits/blobs/{DIGEST}")),
+            Some(Route::Blob {
+                registry: "localhost:5000".to_string(),
+                repository: "kits".to_string(),
+                digest: DIGEST.to_string(),
+            })
+        );
+        // The first component must name a registry.
+        assert_eq!(Route::parse("/v2/bottlerocket/core-kit/manifests/v2"), None);
+        // Names and references must not escape the cache.
+        assert_eq!(
+            Route::parse("/v2/public.ecr.aws/../core-kit/manifests/v2"),
+            None
+        );
+        assert_eq!(
+            Route::parse("/v2/public.ecr.aws/core-kit/manifests/..%2Fv2"),
+            None
+        );
+        assert_eq!(
+            Route::parse("/v2/public.ecr.aws/core-kit/blobs/sha256:abc"),
+            None
+        );
+        assert_eq!(Route::parse("/v2/public.ecr.aws/core-kit/tags/list"), None);
+    }
+
+    fn get(path: &str, authorization: Option<&str>) -> Request<Body> {
+        let mut request = Request::get(path);
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_authorization() {
+        let dir = tempfile::tempdir().unwrap();
+        let proxy = RegistryProxy::new(dir.path(), None, ["public.ecr.aws".to_string()], "secret");
+        let basic = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+
+        let response = proxy.handle(get("/v2/", None)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key(WWW_AUTHENTICATE));
+        let response = proxy.handle(get("/v2/", Some(&basic("user:wrong")))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = proxy.handle(get("/v2/", Some(&basic("user:secret")))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = proxy.handle(get("/v2/", Some("Bearer secret"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_unproxied_registry() {
+        let dir = tempfile::tempdir().unwrap();
+        let proxy = RegistryProxy::new(dir.path(), None, ["public.ecr.aws".to_string()], "secret");
+        let response = proxy
+            .handle(get(
+                "/v2/registry.example.com/core-kit/manifests/v2",
+                Some("Bearer secret"),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_sha256_digest() {
+        assert_eq!(
+            sha256_digest(b""),
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert!(is_digest(&sha256_digest(b"kit")));
+    }
+}
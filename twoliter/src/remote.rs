@@ -0,0 +1,302 @@
+//! Runs twoliter commands on a remote Linux build host, for developers whose own machine is too
+//! small to build on, or cannot build at all.
+//!
+//! `twoliter --remote <destination>` syncs the project to the host at the ssh `destination` with
+//! `rsync`, runs the same command there with the `twoliter` on the host's `PATH`, streaming its
+//! output, and then copies the build outputs and Twoliter.lock back. The project is kept on the host
+//! between runs in `twoliter-remote/<project directory name>` under the remote user's home
+//! directory, so that later runs only transfer what changed and reuse the host's build directory.
+//! Files ignored by the project's `.gitignore` are not synced. A `--project-path` given to the
+//! command is rewritten to name the project file in the synced directory.
+use crate::cargo_make::build_system_env;
+use crate::common::exec;
+use crate::emulation::shell_quote;
+use crate::project::{self, PROFILE_ENV};
+use crate::warnings;
+use anyhow::{ensure, Context, Result};
+use krane_static::settings;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::info;
+
+/// The directory under the remote user's home directory which holds the synced projects.
+const REMOTE_ROOT: &str = "twoliter-remote";
+
+/// What a run leaves in the project that is copied back from the remote host.
+const OUTPUTS: &[&str] = &[
+    "Twoliter.lock",
+    "build/build-info",
+    "build/images",
+    "build/kits",
+    "build/logs",
+];
+
+/// Runs this invocation of twoliter on the remote host at `destination` instead.
+pub(crate) async fn run(destination: &str) -> Result<()> {
+    let args = forwarded_args(std::env::args().skip(1));
+    let project = project::load_or_find_project(project_path(&args)).await?;
+    run_args(&project.filepath(), destination, &args).await
+}
+
+/// Runs twoliter with `args` on the remote host at `destination`, against the project whose
+/// Twoliter.toml is `project_file`, and copies its build outputs back.
+pub(crate) async fn run_args(
+    project_file: &Path,
+    destination: &str,
+    args: &[String],
+) -> Result<()> {
+    // ssh and rsync would take a destination starting with '-' as an option.
+    ensure!(
+        !destination.is_empty() && !destination.starts_with('-'),
+        "invalid remote destination '{destination}'"
+    );
+    let project_dir = project_file.parent().context(format!(
+        "cannot find the project directory of '{}'",
+        project_file.display()
+    ))?;
+    let remote_dir = remote_dir(project_dir)?;
+    let file_name = project_file.file_name().context(format!(
+        "cannot find the file name of '{}'",
+        project_file.display()
+    ))?;
+    let args = with_project_path(args, &file_name.to_string_lossy());
+
+    info!(
+        "Syncing '{}' to '{destination}:{remote_dir}'",
+        project_dir.display()
+    );
+    exec(
+        Command::new("ssh")
+            .arg(destination)
+            .arg("--")
+            .arg(format!("mkdir -p {}", shell_quote(&remote_dir))),
+        true,
+    )
+    .await
+    .context(format!("failed to reach the remote host '{destination}'"))?;
+    exec(
+        Command::new("rsync")
+            .args(["--archive", "--compress", "--delete"])
+            // Excluded files are also kept from deletion, which preserves the remote build
+            // directory and its caches.
+            .args(["--exclude=/build/", "--filter=:- .gitignore"])
+            .arg(format!("{}/", project_dir.display()))
+            .arg(format!("{destination}:{remote_dir}/")),
+        true,
+    )
+    .await
+    .context(format!("failed to sync the project to '{destination}'"))?;
+
+    info!("Running 'twoliter {}' on '{destination}'", args.join(" "));
+    let mut command = vec![
+        "cd".to_string(),
+        shell_quote(&remote_dir),
+        "&&".to_string(),
+        "env".to_string(),
+    ];
+    command.extend(
+        build_system_env()?
+            .into_iter()
+            .chain(
+                settings::var(PROFILE_ENV)
+                    .ok()
+                    .map(|profile| (PROFILE_ENV.to_string(), profile)),
+            )
+            .map(|(key, value)| shell_quote(&format!("{key}={value}"))),
+    );
+    command.push("twoliter".to_string());
+    command.extend(args.iter().map(|arg| shell_quote(arg)));
+    let result = exec(
+        Command::new("ssh").arg(destination).arg("--").args(command),
+        false,
+    )
+    .await
+    .context(format!(
+        "twoliter failed on the remote host '{destination}'"
+    ));
+
+    // Copy the outputs back even when the command failed, since its logs explain why.
+    info!("Copying build outputs back from '{destination}'");
+    let outputs = exec(
+        Command::new("rsync")
+            .args([
+                "--archive",
+                "--compress",
+                "--relative",
+                "--ignore-missing-args",
+            ])
+            .args(
+                OUTPUTS
+                    .iter()
+                    .map(|output| format!("{destination}:{remote_dir}/./{output}")),
+            )
+            .arg(format!("{}/", project_dir.display())),
+        true,
+    )
+    .await;
+    match (result, outputs) {
+        (Err(e), Err(outputs)) => {
+            warnings::warn(format!(
+                "Failed to copy the build outputs back: {outputs:?}"
+            ));
+            Err(e)
+        }
+        (result, outputs) => {
+            outputs.context(format!(
+                "failed to copy the build outputs back from '{destination}'"
+            ))?;
+            result.map(|_| ())
+        }
+    }
+}
+
+/// Where the project in `project_dir` is synced to, relative to the remote user's home directory.
+fn remote_dir(project_dir: &Path) -> Result<String> {
+    let name = project_dir
+        .file_name()
+        .context(format!(
+            "cannot name a remote directory for the project in '{}'",
+            project_dir.display()
+        ))?
+        .to_string_lossy();
+    Ok(format!("{REMOTE_ROOT}/{name}"))
+}
+
+/// The value of `--project-path` among the arguments twoliter was run with, if it was given.
+fn project_path(args: &[String]) -> Option<PathBuf> {
+    let mut args = args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == "--project-path" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--project-path=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// `args` with the value of `--project-path`, if it is given, replaced with `path`, which is
+/// relative to the directory that the command runs in on the remote host.
+fn with_project_path(args: &[String], path: &str) -> Vec<String> {
+    let mut rewritten = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            rewritten.push(arg.clone());
+            rewritten.extend(args.by_ref().cloned());
+        } else if arg == "--project-path" {
+            rewritten.push(arg.clone());
+            if args.next().is_some() {
+                rewritten.push(path.to_string());
+            }
+        } else if arg.starts_with("--project-path=") {
+            rewritten.push(format!("--project-path={path}"));
+        } else {
+            rewritten.push(arg.clone());
+        }
+    }
+    rewritten
+}
+
+/// The arguments to run twoliter with on the remote host, which are the ones it was run with here
+/// except for `--remote`.
+pub(crate) fn forwarded_args(mut args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut forwarded = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            forwarded.push(arg);
+            forwarded.extend(args.by_ref());
+        } else if arg == "--remote" {
+            args.next();
+        } else if !arg.starts_with("--remote=") {
+            forwarded.push(arg);
+        }
+    }
+    forwarded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_forwarded_args() {
+        assert_eq!(
+            forwarded_args(
+                args(&["--remote", "builder", "build", "variant", "aws-dev"]).into_iter()
+            ),
+            args(&["build", "variant", "aws-dev"])
+        );
+        assert_eq!(
+            forwarded_args(args(&["fetch", "--remote=builder", "--arch", "aarch64"]).into_iter()),
+            args(&["fetch", "--arch", "aarch64"])
+        );
+        assert_eq!(
+            forwarded_args(args(&["make", "testsys", "--", "--remote", "x"]).into_iter()),
+            args(&["make", "testsys", "--", "--remote", "x"])
+        );
+    }
+
+    #[test]
+    fn test_project_path() {
+        let given = args(&[
+            "build",
+            "variant",
+            "--project-path",
+            "/home/me/variants/Twoliter.toml",
+            "--",
+            "--project-path=other",
+        ]);
+        assert_eq!(
+            project_path(&given),
+            Some(PathBuf::from("/home/me/variants/Twoliter.toml"))
+        );
+        assert_eq!(
+            with_project_path(&given, "Twoliter.toml"),
+            args(&[
+                "build",
+                "variant",
+                "--project-path",
+                "Twoliter.toml",
+                "--",
+                "--project-path=other",
+            ])
+        );
+        let given = args(&["fetch", "--project-path=../variants/Twoliter.toml"]);
+        assert_eq!(
+            project_path(&given),
+            Some(PathBuf::from("../variants/Twoliter.toml"))
+        );
+        assert_eq!(
+            with_project_path(&given, "Twoliter.toml"),
+            args(&["fetch", "--project-path=Twoliter.toml"])
+        );
+        assert_eq!(project_path(&args(&["build", "variant"])), None);
+    }
+
+    #[tokio::test]
+    async fn test_option_destination() {
+        let err = run_args(
+            Path::new("/home/me/variants/Twoliter.toml"),
+            "-oProxyCommand=true",
+            &[],
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid remote destination"));
+    }
+
+    #[test]
+    fn test_remote_dir() {
+        assert_eq!(
+            remote_dir(Path::new("/home/me/my-variants")).unwrap(),
+            "twoliter-remote/my-variants"
+        );
+        assert!(remote_dir(Path::new("/")).is_err());
+    }
+}
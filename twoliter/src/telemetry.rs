@@ -0,0 +1,213 @@
+//! Opt-in, anonymous usage metrics, which help maintainers and platform teams see which commands
+//! are used and how they fail.
+//!
+//! Telemetry is off unless `TWOLITER_TELEMETRY_ENDPOINT` is set to an `http` or `https` URL, and
+//! `TWOLITER_TELEMETRY=off` turns it off again, e.g. for one user of a machine whose environment
+//! sets an endpoint for everyone. When it is on, each run posts one JSON event to the endpoint
+//! with the subcommand that ran, how long it took, and whether it succeeded or else the class of
+//! its failure, along with the twoliter version, the host's OS and architecture, and the name of
+//! the CI system running it. Arguments, paths, project, kit and image names, and anything which
+//! identifies the user or machine are never sent. A failure to send is ignored.
+use crate::cmd::Args;
+use crate::container::ci_name;
+use crate::diagnostic::Diagnostic;
+use anyhow::Error;
+use clap::{ArgMatches, CommandFactory};
+use krane_static::settings;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// The environment variable naming the endpoint that events are posted to.
+pub(crate) const TELEMETRY_ENDPOINT_ENV: &str = "TWOLITER_TELEMETRY_ENDPOINT";
+
+/// The environment variable which turns telemetry off when set to `off`, `false` or `0`.
+pub(crate) const TELEMETRY_ENV: &str = "TWOLITER_TELEMETRY";
+
+/// Identifies the format of the events, which changes only compatibly within a version.
+const EVENT_SCHEMA: &str = "twoliter.telemetry.v1";
+
+/// How long to wait for the endpoint before giving up on an event.
+const SEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Event {
+    schema: &'static str,
+    /// The subcommand that ran, e.g. `build variant`.
+    command: String,
+    twoliter_version: &'static str,
+    os: &'static str,
+    arch: &'static str,
+    ci: Option<String>,
+    duration_ms: u128,
+    success: bool,
+    /// The class of the failure, e.g. the diagnostic code of the error.
+    failure_class: Option<String>,
+}
+
+/// Measures one run of twoliter, for telemetry.
+#[derive(Debug)]
+pub(crate) struct Telemetry {
+    endpoint: String,
+    command: String,
+    started: Instant,
+}
+
+impl Telemetry {
+    /// Starts measuring this run, if telemetry is on.
+    pub(crate) fn start() -> Option<Self> {
+        let endpoint = endpoint(|key| settings::var(key).ok())?;
+        Some(Self {
+            endpoint,
+            command: command_name(std::env::args_os()),
+            started: Instant::now(),
+        })
+    }
+
+    /// Reports the `result` of the run.
+    pub(crate) async fn finish(self, result: &anyhow::Result<()>) {
+        let event = Event {
+            schema: EVENT_SCHEMA,
+            command: self.command,
+            twoliter_version: env!("CARGO_PKG_VERSION"),
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            ci: ci_name(|key| settings::var(key).ok()),
+            duration_ms: self.started.elapsed().as_millis(),
+            success: result.is_ok(),
+            failure_class: result.as_ref().err().map(failure_class),
+        };
+        let sent = match reqwest::Client::builder().timeout(SEND_TIMEOUT).build() {
+            Ok(client) => client
+                .post(&self.endpoint)
+                .json(&event)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            debug!("Unable to send telemetry to '{}': {e}", self.endpoint);
+        }
+    }
+}
+
+/// Where to send events, found through `var`, or `None` if telemetry is off.
+pub(crate) fn endpoint(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let disabled = var(TELEMETRY_ENV)
+        .is_some_and(|value| ["off", "false", "0"].contains(&value.trim().to_lowercase().as_str()));
+    let endpoint = var(TELEMETRY_ENDPOINT_ENV)?;
+    let endpoint = endpoint.trim();
+    let valid = endpoint.starts_with("https://") || endpoint.starts_with("http://");
+    if !valid {
+        debug!("Ignoring {TELEMETRY_ENDPOINT_ENV}, which is not an http or https URL");
+    }
+    (valid && !disabled).then(|| endpoint.to_string())
+}
+
+/// The names of the subcommands in `args`, without any of their arguments.
+fn command_name(args: impl IntoIterator<Item = std::ffi::OsString>) -> String {
+    let Ok(matches) = Args::command().try_get_matches_from(args) else {
+        return "unknown".to_string();
+    };
+    let mut names = Vec::new();
+    let mut current: &ArgMatches = &matches;
+    while let Some((name, sub)) = current.subcommand() {
+        names.push(name.to_string());
+        current = sub;
+    }
+    names.join(" ")
+}
+
+/// Classifies a failure without revealing its message, which may name the user's project.
+fn failure_class(error: &Error) -> String {
+    if let Some(diagnostic) = error.chain().find_map(|e| e.downcast_ref::<Diagnostic>()) {
+        return diagnostic.code().to_string();
+    }
+    if let Some(io) = error
+        .chain()
+        .find_map(|e| e.downcast_ref::<std::io::Error>())
+    {
+        return format!("io: {:?}", io.kind());
+    }
+    "other".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::diagnostic::Code;
+    use anyhow::Context;
+    use std::collections::HashMap;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[test]
+    fn test_endpoint() {
+        assert_eq!(endpoint(vars(&[])), None);
+        assert_eq!(
+            endpoint(vars(&[(
+                TELEMETRY_ENDPOINT_ENV,
+                "https://metrics.example.com/v1"
+            )])),
+            Some("https://metrics.example.com/v1".to_string())
+        );
+        assert_eq!(
+            endpoint(vars(&[
+                (TELEMETRY_ENDPOINT_ENV, "https://metrics.example.com/v1"),
+                (TELEMETRY_ENV, "off"),
+            ])),
+            None
+        );
+        assert_eq!(
+            endpoint(vars(&[(TELEMETRY_ENDPOINT_ENV, "metrics.example.com")])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_command_name() {
+        let args = |args: &[&str]| {
+            args.iter()
+                .map(std::ffi::OsString::from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            command_name(args(&["twoliter", "build", "variant", "secret-variant"])),
+            "build variant"
+        );
+        assert_eq!(
+            command_name(args(&["twoliter", "--log-level", "debug", "fetch"])),
+            "fetch"
+        );
+        assert_eq!(
+            command_name(args(&["twoliter", "no-such-command"])),
+            "unknown"
+        );
+    }
+
+    #[test]
+    fn test_failure_class() {
+        let diagnostic: Error = Code::StaleLock.error("lock is stale").into();
+        assert_eq!(
+            failure_class(&diagnostic.context("failed to build")),
+            "E0202 stale-lock"
+        );
+        let io: Error = std::io::Error::from(std::io::ErrorKind::NotFound).into();
+        assert_eq!(
+            failure_class(&io.context("/home/me/project/Twoliter.toml")),
+            "io: NotFound"
+        );
+        assert_eq!(
+            failure_class(&anyhow::anyhow!("my-project failed")),
+            "other"
+        );
+    }
+}
@@ -0,0 +1,555 @@
+//! Creates projects from templates, so that organizations can standardize how new projects and
+//! kits are laid out.
+//!
+//! A template is a directory tree, fetched from one of:
+//!
+//! - `oci://<image>`: an OCI artifact whose layers are tarballs of the tree, pulled with krane.
+//! - `git+<url>[#<rev>]`: a git repository, at the branch or tag `rev` if one is given.
+//! - a local directory.
+//!
+//! Templates can also be given by name, from a template registry: a TOML file naming each
+//! template and where it comes from, read from `TWOLITER_TEMPLATE_REGISTRY` or else from
+//! `twoliter/templates.toml` in the user's configuration directory:
+//!
+//! ```toml
+//! [template.kit]
+//! source = "oci://registry.example.com/templates/kit:v3"
+//! description = "A kit with the organization's CI and publishing settings"
+//! ```
+//!
+//! A template may declare its variables in a `twoliter-template.toml` at its root, which is not
+//! copied into the project:
+//!
+//! ```toml
+//! [variables.vendor]
+//! description = "The vendor the kit is published as"
+//!
+//! [variables.kit-name]
+//! default = "my-kit"
+//! ```
+//!
+//! `vendor`, `kit-name` and `registry` are always variables. Each `{{name}}` of a variable in the
+//! template's files and paths is replaced with its value; anything else in braces, such as the
+//! expressions of CI workflows, is left as it is. Files which are not UTF-8 are copied unchanged.
+use crate::common::exec_log;
+use crate::common::fs::{copy, create_dir_all, read, read_to_string, remove_dir_all, write};
+use crate::project::unpack_layout;
+use anyhow::{bail, ensure, Context, Result};
+use async_recursion::async_recursion;
+use krane_static::settings;
+use oci_cli_wrapper::ImageTool;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::{debug, info};
+
+/// The environment variable naming the template registry.
+pub(crate) const TEMPLATE_REGISTRY_ENV: &str = "TWOLITER_TEMPLATE_REGISTRY";
+
+/// The file at the root of a template which declares its variables.
+const TEMPLATE_MANIFEST: &str = "twoliter-template.toml";
+
+/// The variables every template has, whether or not it declares them.
+const BUILTIN_VARIABLES: &[&str] = &["vendor", "kit-name", "registry"];
+
+/// Where a template is fetched from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplateSource {
+    Oci(String),
+    Git { url: String, rev: Option<String> },
+    Dir(PathBuf),
+}
+
+impl TemplateSource {
+    fn parse(source: &str) -> Self {
+        if let Some(uri) = source.strip_prefix("oci://") {
+            return Self::Oci(uri.to_string());
+        }
+        if let Some(url) = source.strip_prefix("git+") {
+            let (url, rev) = match url.rsplit_once('#') {
+                Some((url, rev)) if !rev.is_empty() => (url, Some(rev.to_string())),
+                _ => (url.trim_end_matches('#'), None),
+            };
+            return Self::Git {
+                url: url.to_string(),
+                rev,
+            };
+        }
+        Self::Dir(PathBuf::from(source))
+    }
+
+    /// Fetches the template's tree into the empty directory `dir`.
+    async fn fetch(&self, dir: &Path) -> Result<()> {
+        match self {
+            Self::Oci(uri) => {
+                info!("Pulling template '{uri}'");
+                let layout = tempfile::tempdir()
+                    .context("failed to create a directory to pull the template into")?;
+                ImageTool::krane()
+                    .pull_oci_image(layout.path(), uri)
+                    .await
+                    .context(format!("failed to pull template '{uri}'"))?;
+                unpack_layout(layout.path(), dir).await
+            }
+            Self::Git { url, rev } => {
+                info!("Cloning template '{url}'");
+                let mut clone = Command::new("git");
+                clone.args(["clone", "--depth", "1"]);
+                if let Some(rev) = rev {
+                    clone.args(["--branch", rev]);
+                }
+                exec_log(clone.arg(url).arg(dir))
+                    .await
+                    .context(format!("failed to clone template '{url}'"))?;
+                remove_dir_all(dir.join(".git")).await
+            }
+            Self::Dir(path) => {
+                ensure!(
+                    path.is_dir(),
+                    "template '{}' is not in the template registry, and is not a directory",
+                    path.display()
+                );
+                copy_tree(path, dir).await
+            }
+        }
+    }
+}
+
+/// The named templates of an organization.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct TemplateRegistry {
+    #[serde(default)]
+    template: BTreeMap<String, RegisteredTemplate>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct RegisteredTemplate {
+    source: String,
+    #[serde(default)]
+    description: Option<String>,
+}
+
+impl TemplateRegistry {
+    /// Loads the registry named by `TWOLITER_TEMPLATE_REGISTRY`, or else the user's registry if
+    /// they have one.
+    async fn load() -> Result<Self> {
+        let path = match settings::var_os(TEMPLATE_REGISTRY_ENV).filter(|path| !path.is_empty()) {
+            Some(path) => PathBuf::from(path),
+            None => match user_registry() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        debug!("Reading template registry '{}'", path.display());
+        toml::from_str(&read_to_string(&path).await?).context(format!(
+            "failed to parse template registry '{}'",
+            path.display()
+        ))
+    }
+
+    /// Where `template` comes from, looking it up by name before taking it as a source.
+    fn source(&self, template: &str) -> TemplateSource {
+        match self.template.get(template) {
+            Some(registered) => {
+                if let Some(description) = &registered.description {
+                    info!("Using template '{template}': {description}");
+                }
+                TemplateSource::parse(&registered.source)
+            }
+            None => TemplateSource::parse(template),
+        }
+    }
+}
+
+/// The template registry in the user's configuration directory.
+pub(crate) fn user_registry() -> Option<PathBuf> {
+    settings::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| settings::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("twoliter").join("templates.toml"))
+}
+
+/// The variables a template declares.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct TemplateManifest {
+    #[serde(default)]
+    variables: BTreeMap<String, Variable>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Variable {
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    default: Option<String>,
+}
+
+/// How to create a project from a template.
+#[derive(Debug)]
+pub(crate) struct NewProject {
+    /// The name of a template in the registry, or its source.
+    pub(crate) template: String,
+    /// The directory to create the project in, which must not exist or be empty.
+    pub(crate) output: PathBuf,
+    /// The values of the template's variables.
+    pub(crate) values: BTreeMap<String, String>,
+}
+
+impl NewProject {
+    /// Creates the project, returning how many files were written.
+    pub(crate) async fn run(&self) -> Result<usize> {
+        let occupied = std::fs::read_dir(&self.output)
+            .map(|mut entries| entries.next().is_some())
+            .unwrap_or(false);
+        ensure!(
+            !occupied,
+            "'{}' already exists and is not empty",
+            self.output.display()
+        );
+
+        let source = TemplateRegistry::load().await?.source(&self.template);
+        let fetched = tempfile::tempdir()
+            .context("failed to create a directory to fetch the template into")?;
+        source.fetch(fetched.path()).await?;
+
+        let manifest_path = fetched.path().join(TEMPLATE_MANIFEST);
+        let manifest: TemplateManifest = if manifest_path.exists() {
+            toml::from_str(&read_to_string(&manifest_path).await?).context(format!(
+                "failed to parse the manifest of template '{}'",
+                self.template
+            ))?
+        } else {
+            TemplateManifest::default()
+        };
+        let values = manifest.values(&self.values)?;
+        render(fetched.path(), &self.output, &values, true).await
+    }
+}
+
+impl TemplateManifest {
+    /// The value of each variable, from `given` or else the variable's default.
+    fn values(&self, given: &BTreeMap<String, String>) -> Result<BTreeMap<String, String>> {
+        if let Some(unknown) = given.keys().find(|name| {
+            !BUILTIN_VARIABLES.contains(&name.as_str()) && !self.variables.contains_key(*name)
+        }) {
+            bail!(
+                "the template has no variable '{unknown}'; its variables are {}",
+                self.names().join(", ")
+            );
+        }
+        let mut values = BTreeMap::new();
+        let mut missing = Vec::new();
+        for name in self.names() {
+            let variable = self.variables.get(&name);
+            match given
+                .get(&name)
+                .or_else(|| variable.and_then(|v| v.default.as_ref()))
+            {
+                Some(value) => {
+                    values.insert(name, value.clone());
+                }
+                // Builtin variables which the template does not declare are optional.
+                None if variable.is_none() => {}
+                None => missing.push(match variable.and_then(|v| v.description.as_ref()) {
+                    Some(description) => format!("'{name}' ({description})"),
+                    None => format!("'{name}'"),
+                }),
+            }
+        }
+        ensure!(
+            missing.is_empty(),
+            "the template needs values for {}, which can be given with --set <name>=<value>",
+            missing.join(", ")
+        );
+        Ok(values)
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names = BUILTIN_VARIABLES
+            .iter()
+            .map(ToString::to_string)
+            .chain(self.variables.keys().cloned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+/// Copies the template in `from` to `to`, substituting `values` into the paths and contents of
+/// its files. Returns how many files were written.
+#[async_recursion]
+async fn render(
+    from: &Path,
+    to: &Path,
+    values: &BTreeMap<String, String>,
+    top: bool,
+) -> Result<usize> {
+    create_dir_all(to).await?;
+    let mut written = 0;
+    let mut entries = tokio::fs::read_dir(from)
+        .await
+        .context(format!("failed to read directory '{}'", from.display()))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context(format!("failed to read directory '{}'", from.display()))?
+    {
+        let name = entry.file_name();
+        if top && name == TEMPLATE_MANIFEST {
+            continue;
+        }
+        let Some(target) = name.to_str().map(|name| to.join(substitute(name, values))) else {
+            bail!("template path '{}' is not UTF-8", entry.path().display());
+        };
+        let file_type = entry
+            .file_type()
+            .await
+            .context(format!("failed to inspect '{}'", entry.path().display()))?;
+        if file_type.is_dir() {
+            written += render(&entry.path(), &target, values, false).await?;
+            continue;
+        }
+        if file_type.is_symlink() {
+            let link = tokio::fs::read_link(entry.path())
+                .await
+                .context(format!("failed to read link '{}'", entry.path().display()))?;
+            tokio::fs::symlink(&link, &target)
+                .await
+                .context(format!("failed to create link '{}'", target.display()))?;
+            continue;
+        }
+        match String::from_utf8(read(entry.path()).await?) {
+            Ok(contents) => {
+                write(&target, substitute(&contents, values)).await?;
+                // Keep scripts executable.
+                let permissions = entry
+                    .metadata()
+                    .await
+                    .context(format!("failed to inspect '{}'", entry.path().display()))?
+                    .permissions();
+                tokio::fs::set_permissions(&target, permissions)
+                    .await
+                    .context(format!(
+                        "failed to set permissions of '{}'",
+                        target.display()
+                    ))?;
+            }
+            Err(_) => {
+                copy(entry.path(), &target).await?;
+            }
+        }
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Replaces each `{{name}}` of a variable in `text` with its value.
+fn substitute(text: &str, values: &BTreeMap<String, String>) -> String {
+    let mut substituted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        substituted.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after
+            .find("}}")
+            .and_then(|end| values.get(after[..end].trim()).map(|value| (end, value)))
+        {
+            Some((end, value)) => {
+                substituted.push_str(value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                substituted.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    substituted.push_str(rest);
+    substituted
+}
+
+/// Copies the directory `from` to `to`, leaving out its git repository.
+#[async_recursion]
+async fn copy_tree(from: &Path, to: &Path) -> Result<()> {
+    create_dir_all(to).await?;
+    let mut entries = tokio::fs::read_dir(from)
+        .await
+        .context(format!("failed to read directory '{}'", from.display()))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context(format!("failed to read directory '{}'", from.display()))?
+    {
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        let file_type = entry
+            .file_type()
+            .await
+            .context(format!("failed to inspect '{}'", entry.path().display()))?;
+        let target = to.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_tree(&entry.path(), &target).await?;
+        } else if file_type.is_symlink() {
+            let link = tokio::fs::read_link(entry.path())
+                .await
+                .context(format!("failed to read link '{}'", entry.path().display()))?;
+            tokio::fs::symlink(&link, &target)
+                .await
+                .context(format!("failed to create link '{}'", target.display()))?;
+        } else {
+            copy(entry.path(), &target).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(
+            TemplateSource::parse("oci://registry.example.com/templates/kit:v3"),
+            TemplateSource::Oci("registry.example.com/templates/kit:v3".to_string())
+        );
+        assert_eq!(
+            TemplateSource::parse("git+https://example.com/templates.git#v1"),
+            TemplateSource::Git {
+                url: "https://example.com/templates.git".to_string(),
+                rev: Some("v1".to_string()),
+            }
+        );
+        assert_eq!(
+            TemplateSource::parse("git+ssh://git@example.com/templates.git"),
+            TemplateSource::Git {
+                url: "ssh://git@example.com/templates.git".to_string(),
+                rev: None,
+            }
+        );
+        assert_eq!(
+            TemplateSource::parse("../templates/kit"),
+            TemplateSource::Dir(PathBuf::from("../templates/kit"))
+        );
+    }
+
+    #[test]
+    fn test_registry_source() {
+        let registry: TemplateRegistry = toml::from_str(
+            r#"
+[template.kit]
+source = "oci://registry.example.com/templates/kit:v3"
+description = "A kit"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            registry.source("kit"),
+            TemplateSource::Oci("registry.example.com/templates/kit:v3".to_string())
+        );
+        assert_eq!(
+            registry.source("templates/kit"),
+            TemplateSource::Dir(PathBuf::from("templates/kit"))
+        );
+    }
+
+    #[test]
+    fn test_substitute() {
+        let values = values(&[("vendor", "acme"), ("kit-name", "acme-kit")]);
+        assert_eq!(
+            substitute(
+                "vendor = \"{{vendor}}\"\nname = \"{{ kit-name }}\"\n",
+                &values
+            ),
+            "vendor = \"acme\"\nname = \"acme-kit\"\n"
+        );
+        assert_eq!(
+            substitute("token: ${{ secrets.TOKEN }} {{registry}} {{", &values),
+            "token: ${{ secrets.TOKEN }} {{registry}} {{"
+        );
+    }
+
+    #[test]
+    fn test_values() {
+        let manifest: TemplateManifest = toml::from_str(
+            r#"
+[variables.vendor]
+description = "The vendor the kit is published as"
+
+[variables.team]
+default = "core"
+"#,
+        )
+        .unwrap();
+        let given = values(&[("vendor", "acme"), ("kit-name", "acme-kit")]);
+        assert_eq!(
+            manifest.values(&given).unwrap(),
+            values(&[
+                ("vendor", "acme"),
+                ("kit-name", "acme-kit"),
+                ("team", "core")
+            ])
+        );
+        let err = manifest.values(&BTreeMap::new()).unwrap_err();
+        assert!(err.to_string().contains("'vendor' (The vendor"));
+        assert!(manifest.values(&values(&[("colour", "red")])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_new_project() {
+        let template = tempfile::tempdir().unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let kit_dir = template.path().join("kits").join("{{kit-name}}");
+        std::fs::create_dir_all(&kit_dir).unwrap();
+        std::fs::write(
+            kit_dir.join("Cargo.toml"),
+            "[package]\nname = \"{{kit-name}}\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            template.path().join("Twoliter.toml"),
+            "[vendor.{{vendor}}]\nregistry = \"{{registry}}\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            template.path().join(TEMPLATE_MANIFEST),
+            "[variables.kit-name]\ndefault = \"my-kit\"\n",
+        )
+        .unwrap();
+
+        let written = NewProject {
+            template: template.path().display().to_string(),
+            output: output.path().join("project"),
+            values: values(&[("vendor", "acme"), ("registry", "registry.example.com")]),
+        }
+        .run()
+        .await
+        .unwrap();
+        assert_eq!(written, 2);
+        let project = output.path().join("project");
+        assert!(!project.join(TEMPLATE_MANIFEST).exists());
+        assert_eq!(
+            std::fs::read_to_string(project.join("kits/my-kit/Cargo.toml")).unwrap(),
+            "[package]\nname = \"my-kit\"\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(project.join("Twoliter.toml")).unwrap(),
+            "[vendor.acme]\nregistry = \"registry.example.com\"\n"
+        );
+    }
+}
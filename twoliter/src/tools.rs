@@ -1,4 +1,5 @@
 use crate::common::fs;
+use crate::host;
 use anyhow::{Context, Result};
 use filetime::{set_file_handle_times, set_file_mtime, FileTime};
 use flate2::read::ZlibDecoder;
@@ -24,6 +25,7 @@ const UNPLUG: &[u8] = include_bytes!(env!("CARGO_BIN_FILE_UNPLUG"));
 /// auto delete when it goes out of scope).
 pub(crate) async fn install_tools(tools_dir: impl AsRef<Path>) -> Result<()> {
     let dir = tools_dir.as_ref();
+    host::ensure_builds_supported(dir).await?;
     debug!("Installing tools to '{}'", dir.display());
     fs::remove_dir_all(dir)
         .await
@@ -60,12 +62,11 @@ pub(crate) async fn install_tools(tools_dir: impl AsRef<Path>) -> Result<()> {
 
 async fn write_bin(name: &str, data: &[u8], dir: impl AsRef<Path>, mtime: FileTime) -> Result<()> {
     let path = dir.as_ref().join(name);
-    let mut f = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .read(false)
-        .write(true)
-        .mode(0o755)
+    let mut options = OpenOptions::new();
+    options.create(true).truncate(true).read(false).write(true);
+    #[cfg(unix)]
+    options.mode(0o755);
+    let mut f = options
         .open(&path)
         .await
         .context(format!("Unable to create file '{}'", path.display()))?;
@@ -113,6 +114,7 @@ async fn test_install_tools() {
     assert!(toolsdir.join("docker-go").is_file());
     assert!(toolsdir.join("img2img").is_file());
     assert!(toolsdir.join("imghelper").is_file());
+    assert!(toolsdir.join("kitabi").is_file());
     assert!(toolsdir.join("metadata.spec").is_file());
     assert!(toolsdir.join("partyplanner").is_file());
     assert!(toolsdir.join("rpm2img").is_file());
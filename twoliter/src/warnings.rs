@@ -0,0 +1,116 @@
+//! Collects the warnings raised during a run so that a warning which repeats, e.g. once for each
+//! kit or layer, is only logged the first time, and so that the warnings can be summarized or
+//! treated as errors with `--deny warnings` or `--deny deprecations` when the run ends.
+//!
+//! Every warning shown to the user is raised here rather than logged with `tracing::warn!`, so
+//! that `--deny warnings` sees all of them. The only exception is the summary of the warnings
+//! themselves, which is logged when the run ends.
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use tracing::warn;
+
+lazy_static! {
+    static ref WARNINGS: Mutex<Warnings> = Mutex::new(Warnings::default());
+}
+
+/// Logs `message` as a warning the first time it is raised during this run. Repeats are counted
+/// and included in the summary instead of being logged again.
+pub(crate) fn warn(message: impl Into<String>) {
+    raise(message.into(), false)
+}
+
+/// Warns that something the project depends on is deprecated. Deprecations are counted separately
+/// so that they alone can be denied with `--deny deprecations`.
+pub(crate) fn deprecated(message: impl Into<String>) {
+    raise(message.into(), true)
+}
+
+fn raise(message: String, deprecation: bool) {
+    let first = WARNINGS
+        .lock()
+        .map(|mut warnings| warnings.raise(&message, deprecation))
+        .unwrap_or(true);
+    if first {
+        warn!("{message}");
+    }
+}
+
+/// The warnings raised so far during this run.
+pub(crate) fn summary() -> WarningSummary {
+    WARNINGS
+        .lock()
+        .map(|warnings| warnings.summary())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Default)]
+struct Warnings {
+    counts: HashMap<String, usize>,
+    deprecations: HashSet<String>,
+}
+
+impl Warnings {
+    /// Records `message`, returning whether this is the first time it was raised.
+    fn raise(&mut self, message: &str, deprecation: bool) -> bool {
+        if deprecation {
+            self.deprecations.insert(message.to_string());
+        }
+        let count = self.counts.entry(message.to_string()).or_default();
+        *count += 1;
+        *count == 1
+    }
+
+    fn summary(&self) -> WarningSummary {
+        WarningSummary {
+            distinct: self.counts.len(),
+            total: self.counts.values().sum(),
+            deprecations: self.deprecations.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct WarningSummary {
+    /// The number of different warnings raised.
+    pub(crate) distinct: usize,
+    /// The number of warnings raised, including repeats.
+    pub(crate) total: usize,
+    /// The number of different deprecation warnings raised.
+    pub(crate) deprecations: usize,
+}
+
+impl Display for WarningSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} warning(s)", self.distinct)?;
+        let repeats = self.total - self.distinct;
+        if repeats > 0 {
+            write!(f, " ({repeats} repeat(s) not shown)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_repeats_are_counted() {
+        let mut warnings = Warnings::default();
+        assert!(warnings.raise("mirror unavailable", false));
+        assert!(!warnings.raise("mirror unavailable", false));
+        assert!(warnings.raise("core-kit-1.0.0 is deprecated", true));
+        let summary = warnings.summary();
+        assert_eq!(
+            summary,
+            WarningSummary {
+                distinct: 2,
+                total: 3,
+                deprecations: 1,
+            }
+        );
+        assert_eq!(summary.to_string(), "2 warning(s) (1 repeat(s) not shown)");
+    }
+}